@@ -1,5 +1,5 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     routing::{get, post},
     Json, Router, http::StatusCode, response::{IntoResponse, Response},
 };
@@ -7,23 +7,32 @@ use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use tower_http::cors::{Any, CorsLayer};
+use tokio::sync::{watch, RwLock};
 use tokio::task::JoinHandle;
 
-use crate::blockchain::{Blockchain, Block};
+use crate::blockchain::{parse_hash, verify_merkle_proof, Blockchain, Block, BlockHeader, FamilyTree, MempoolAcceptResult, MerkleProof, RejectReason, TrianglePath, TriangleRecord};
+use crate::config::Config;
+use crate::error::ChainError;
 use crate::persistence::Database;
-use crate::transaction::Transaction;
+use crate::transaction::{AddressRole, Transaction};
 use crate::crypto::KeyPair;
-use crate::miner;
-use crate::network::Node;
+use crate::miner::{MinerStats, MiningConfig, MiningCoordinator};
+use crate::network::{PeerState, PeerStatus, SyncState};
+use crate::render::{Bounds, ColorBy, SvgOptions, TileCoord};
 
 /// Mining state that tracks the current mining operation
 #[derive(Clone)]
 struct MiningState {
     is_mining: Arc<AtomicBool>,
+    /// Set to interrupt the block being mined
+    stop: Arc<AtomicBool>,
     blocks_mined: Arc<AtomicU64>,
-    last_block_time: Arc<Mutex<Option<Instant>>>,
+    /// Hashrate figures published by the running mining task
+    stats: Arc<Mutex<Option<watch::Receiver<MinerStats>>>>,
+    /// Threads and duty cycle of the running mining task
+    config: Arc<Mutex<MiningConfig>>,
     mining_task: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
@@ -31,36 +40,50 @@ impl Default for MiningState {
     fn default() -> Self {
         Self {
             is_mining: Arc::new(AtomicBool::new(false)),
+            stop: Arc::new(AtomicBool::new(false)),
             blocks_mined: Arc::new(AtomicU64::new(0)),
-            last_block_time: Arc::new(Mutex::new(None)),
+            stats: Arc::new(Mutex::new(None)),
+            config: Arc::new(Mutex::new(MiningConfig::default())),
             mining_task: Arc::new(Mutex::new(None)),
         }
     }
 }
 
-/// Network state that tracks peers and node information
+/// Node information. Peers are read from the database, where the node
+/// process that manages them saves their state.
 #[derive(Clone, Default)]
 struct NetworkState {
-    peers: Arc<Mutex<Vec<Node>>>,
     node_id: Arc<Mutex<String>>,
     listening_port: Arc<Mutex<u16>>,
 }
 
 #[derive(Clone)]
 struct AppState {
-    blockchain: Arc<Mutex<Blockchain>>,
+    /// Shared with the node and miner when running inside `siertrid`
+    blockchain: Arc<RwLock<Blockchain>>,
     db: Arc<Mutex<Database>>,
     mining: MiningState,
     network: NetworkState,
 }
 
-pub async fn run_api_server() {
-    let db = Database::open("siertrichain.db").unwrap();
+/// Port the API listens on, on localhost, and where the CLIs look for it
+pub const DEFAULT_API_PORT: u16 = 3000;
+
+pub async fn run_api_server(config: &Config) {
+    let db = Database::open_in(config).unwrap();
     let blockchain = db.load_blockchain().unwrap();
 
+    let addr = SocketAddr::from(([127, 0, 0, 1], DEFAULT_API_PORT));
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    serve(listener, Arc::new(RwLock::new(blockchain)), Arc::new(Mutex::new(db))).await.unwrap();
+}
+
+/// Serve the API on `listener` over a chain and database shared with the rest
+/// of the process, as `siertrid` shares them with its node and miner
+pub async fn serve(listener: tokio::net::TcpListener, blockchain: Arc<RwLock<Blockchain>>, db: Arc<Mutex<Database>>) -> Result<(), ChainError> {
     let app_state = AppState {
-        blockchain: Arc::new(Mutex::new(blockchain)),
-        db: Arc::new(Mutex::new(db)),
+        blockchain,
+        db,
         mining: MiningState::default(),
         network: NetworkState::default(),
     };
@@ -84,12 +107,22 @@ pub async fn run_api_server() {
         .route("/blockchain/stats", get(get_blockchain_stats))
         .route("/blockchain/blocks", get(get_recent_blocks))
         .route("/blockchain/block/:hash", get(get_block_by_hash))
+        .route("/blockchain/block/:hash/proof/:txid", get(get_merkle_proof))
         .route("/blockchain/block/by-height/:height", get(get_block_by_height))
         .route("/blockchain/reward/:height", get(get_block_reward_info))
         // Address & Balance
         .route("/address/:addr/balance", get(get_address_balance))
         .route("/address/:addr/triangles", get(get_address_triangles))
         .route("/address/:addr/history", get(get_address_history))
+        .route("/address/:addr/nonce", get(get_next_nonce))
+        // Triangle endpoints
+        .route("/triangle/:hash", get(get_triangle))
+        .route("/triangle/:hash/inscriptions", get(get_triangle_inscriptions))
+        .route("/triangle/path/:path", get(get_triangle_by_path))
+        .route("/utxo/region", get(get_utxo_region))
+        .route("/triangle/:hash/ancestry", get(get_triangle_ancestry))
+        .route("/triangle/:hash/descendants", get(get_triangle_descendants))
+        .route("/tiles/:z/:x/:y", get(get_tile))
         // Transactions
         .route("/transaction", post(submit_transaction))
         .route("/transaction/:hash", get(get_transaction_status))
@@ -105,34 +138,71 @@ pub async fn run_api_server() {
         // Network
         .route("/network/peers", get(get_peers))
         .route("/network/info", get(get_network_info))
+        .route("/network/sync", get(get_sync_state))
         .with_state(app_state)
         .layer(cors);
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app).await
+        .map_err(|e| ChainError::NetworkError(format!("API server failed: {}", e)))
 }
 
 async fn get_blockchain_height(State(state): State<AppState>) -> Json<u64> {
-    let blockchain = state.blockchain.lock().unwrap();
+    let blockchain = state.blockchain.read().await;
     Json(blockchain.blocks.len() as u64)
 }
 
-async fn get_block_by_hash(State(state): State<AppState>, Path(hash): Path<String>) -> Result<Json<Option<Block>>, Response> {
-    let blockchain = state.blockchain.lock().unwrap();
-    let hash_bytes = match hex::decode(hash) {
-        Ok(bytes) => bytes,
-        Err(_) => return Err((StatusCode::BAD_REQUEST, "Invalid hash format").into_response()),
-    };
-    let mut hash_arr = [0u8; 32];
-    if hash_bytes.len() != 32 {
-        return Err((StatusCode::BAD_REQUEST, "Invalid hash length").into_response());
-    }
-    hash_arr.copy_from_slice(&hash_bytes);
-    let block = blockchain.block_index.get(&hash_arr).cloned();
+async fn get_block_by_hash(State(state): State<AppState>, Path(hash): Path<String>) -> Result<Json<Option<Block>>, ApiError> {
+    let blockchain = state.blockchain.read().await;
+    let hash_arr = parse_hash(&hash)?;
+    let block = blockchain.get_block(&hash_arr).cloned();
     Ok(Json(block))
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct MerkleProofResponse {
+    pub block_hash: String,
+    pub header: BlockHeader,
+    pub txid: String,
+    /// Position of the transaction in the block
+    pub index: usize,
+    /// Sibling hashes from the transaction up to the merkle root
+    pub branch: Vec<String>,
+}
+
+impl MerkleProofResponse {
+    /// Decode the proof and check it against the header. The caller still has
+    /// to check that the header belongs to the chain it follows.
+    pub fn verify(&self) -> Result<bool, ChainError> {
+        let proof = MerkleProof {
+            txid: parse_hash(&self.txid)?,
+            index: self.index,
+            branch: self.branch.iter().map(|hash| parse_hash(hash)).collect::<Result<_, _>>()?,
+        };
+        Ok(verify_merkle_proof(&self.header, &proof))
+    }
+}
+
+/// Merkle branch proving a transaction is in a block, with the block's header,
+/// for light clients that keep headers only
+async fn get_merkle_proof(State(state): State<AppState>, Path((hash, txid)): Path<(String, String)>) -> Result<Json<MerkleProofResponse>, ApiError> {
+    let blockchain = state.blockchain.read().await;
+    let (hash, txid) = (parse_hash(&hash)?, parse_hash(&txid)?);
+    let block = blockchain.get_block(&hash)
+        .ok_or_else(|| ApiError::not_found("block-not-found", format!("No block {}", hex::encode(hash))))?;
+    if blockchain.is_pruned(block) {
+        return Err(ChainError::PrunedData(format!("Transactions of block {} have been pruned", block.header.height)).into());
+    }
+    let proof = block.merkle_proof(&txid)?
+        .ok_or_else(|| ApiError::not_found("transaction-not-found", format!("Transaction {} is not in block {}", hex::encode(txid), hex::encode(hash))))?;
+    Ok(Json(MerkleProofResponse {
+        block_hash: hex::encode(hash),
+        header: block.header.clone(),
+        txid: hex::encode(proof.txid),
+        index: proof.index,
+        branch: proof.branch.iter().map(hex::encode).collect(),
+    }))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct BalanceResponse {
     pub triangles: Vec<String>,
@@ -148,14 +218,15 @@ pub struct RecentBlock {
 #[derive(Serialize, Deserialize)]
 pub struct StatsResponse {
     pub height: u64,
-    pub difficulty: u64,
+    pub difficulty: f64,
+    pub bits: u32,
     pub utxo_count: usize,
     pub mempool_size: usize,
     pub recent_blocks: Vec<RecentBlock>,
 }
 
 async fn get_blockchain_stats(State(state): State<AppState>) -> Json<StatsResponse> {
-    let blockchain = state.blockchain.lock().unwrap();
+    let blockchain = state.blockchain.read().await;
     let recent_blocks = blockchain.blocks.iter().rev().take(6).map(|b| RecentBlock {
         height: b.header.height,
         hash: hex::encode(b.hash),
@@ -163,7 +234,8 @@ async fn get_blockchain_stats(State(state): State<AppState>) -> Json<StatsRespon
 
     Json(StatsResponse {
         height: blockchain.blocks.len() as u64,
-        difficulty: blockchain.difficulty,
+        difficulty: blockchain.difficulty(),
+        bits: blockchain.bits,
         utxo_count: blockchain.state.utxo_set.len(),
         mempool_size: blockchain.mempool.len(),
         recent_blocks,
@@ -171,15 +243,13 @@ async fn get_blockchain_stats(State(state): State<AppState>) -> Json<StatsRespon
 }
 
 async fn get_address_balance(State(state): State<AppState>, Path(addr): Path<String>) -> Json<BalanceResponse> {
-    let blockchain = state.blockchain.lock().unwrap();
+    let blockchain = state.blockchain.read().await;
     let mut triangles = Vec::new();
     let mut total_area = 0.0;
 
-    for (hash, triangle) in &blockchain.state.utxo_set {
-        if triangle.owner == addr {
-            triangles.push(hex::encode(hash));
-            total_area += triangle.area();
-        }
+    for (hash, triangle) in blockchain.state.by_owner(&addr) {
+        triangles.push(hex::encode(hash));
+        total_area += triangle.area();
     }
 
     Json(BalanceResponse {
@@ -188,30 +258,148 @@ async fn get_address_balance(State(state): State<AppState>, Path(addr): Path<Str
     })
 }
 
-async fn submit_transaction(State(state): State<AppState>, Json(tx): Json<Transaction>) -> Json<String> {
-    let mut blockchain = state.blockchain.lock().unwrap();
-    let tx_hash = tx.hash_str();
-    blockchain.mempool.add_transaction(tx).unwrap();
-    Json(tx_hash)
+#[derive(Serialize, Deserialize)]
+pub struct SubmitTransactionResponse {
+    pub tx_hash: String,
+    /// `accepted`, `replaced`, `orphaned` or `rejected`
+    pub status: String,
+    /// Machine-readable rejection reason, e.g. `insufficient-fee`
+    pub reason: Option<String>,
+    pub error: Option<String>,
+    /// Fee per serialized byte of an accepted transaction
+    pub fee_rate: Option<f64>,
+    /// Hash of the pending transaction this one replaced by paying a higher fee
+    pub replaced: Option<String>,
+    /// Pending transactions a rejected transaction conflicts with
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    /// Inputs of an orphaned transaction that aren't known yet
+    #[serde(default)]
+    pub missing_inputs: Vec<String>,
 }
 
-async fn get_transaction_status(State(state): State<AppState>, Path(hash): Path<String>) -> Result<Json<Option<Transaction>>, Response> {
-    let blockchain = state.blockchain.lock().unwrap();
-    let hash_bytes = match hex::decode(hash) {
-        Ok(bytes) => bytes,
-        Err(_) => return Err((StatusCode::BAD_REQUEST, "Invalid hash format").into_response()),
-    };
-    let mut hash_arr = [0u8; 32];
-    if hash_bytes.len() != 32 {
-        return Err((StatusCode::BAD_REQUEST, "Invalid hash length").into_response());
+impl From<MempoolAcceptResult> for SubmitTransactionResponse {
+    fn from(result: MempoolAcceptResult) -> Self {
+        let mut response = SubmitTransactionResponse {
+            tx_hash: hex::encode(result.tx_hash()),
+            status: String::new(),
+            reason: None,
+            error: None,
+            fee_rate: None,
+            replaced: None,
+            conflicts: Vec::new(),
+            missing_inputs: Vec::new(),
+        };
+        match result {
+            MempoolAcceptResult::Accepted { fee_rate, .. } => {
+                response.status = "accepted".to_string();
+                response.fee_rate = Some(fee_rate.per_byte());
+            }
+            MempoolAcceptResult::Replaced { fee_rate, replaced, .. } => {
+                response.status = "replaced".to_string();
+                response.fee_rate = Some(fee_rate.per_byte());
+                response.replaced = Some(hex::encode(replaced));
+            }
+            MempoolAcceptResult::Orphaned { missing_inputs, .. } => {
+                response.status = "orphaned".to_string();
+                response.missing_inputs = missing_inputs.iter().map(hex::encode).collect();
+            }
+            MempoolAcceptResult::Rejected { reason, error, conflicts, .. } => {
+                response.status = "rejected".to_string();
+                response.reason = Some(reason.code().to_string());
+                response.error = Some(error.to_string());
+                response.conflicts = conflicts.iter().map(hex::encode).collect();
+            }
+        }
+        response
+    }
+}
+
+/// HTTP status for a chain error: missing data is 404, conflicts with existing
+/// state 409, internal failures 500, and anything else the caller's fault 400
+pub fn error_status(error: &ChainError) -> StatusCode {
+    match error {
+        ChainError::UtxoMissing { .. }
+        | ChainError::TriangleNotFound(_)
+        | ChainError::HeaderNotFound(_)
+        | ChainError::PrunedData(_) => StatusCode::NOT_FOUND,
+        ChainError::DuplicateTransaction { .. }
+        | ChainError::InsufficientFee { .. }
+        | ChainError::StaleNonce { .. } => StatusCode::CONFLICT,
+        ChainError::AuthenticationError(_) => StatusCode::UNAUTHORIZED,
+        ChainError::DatabaseError(_) | ChainError::WalletError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        ChainError::NetworkError(_) | ChainError::IncompatiblePeer(_) => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Body of every error response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    /// Machine-readable error code, e.g. `triangle-not-found`
+    pub error: String,
+    pub message: String,
+}
+
+/// An error answered as JSON with its HTTP status and code. Chain errors
+/// convert with `?`, taking their status from `error_status`.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError { status, code, message: message.into() }
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, code, message)
     }
-    hash_arr.copy_from_slice(&hash_bytes);
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+}
+
+impl From<ChainError> for ApiError {
+    fn from(error: ChainError) -> Self {
+        ApiError::new(error_status(&error), error.code(), error.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse { error: self.code.to_string(), message: self.message };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// Accepted and replacing transactions answer 200; orphaned and rejected ones
+/// answer with the status of their error and the same body, so callers can act
+/// on the reason code. A sender over its pending transaction limit gets 429.
+async fn submit_transaction(State(state): State<AppState>, Json(tx): Json<Transaction>) -> Response {
+    let mut blockchain = state.blockchain.write().await;
+    let result = blockchain.accept_transaction(tx);
+    let status = match result.clone().into_result() {
+        Ok(_) => StatusCode::OK,
+        Err(_) if result.reason() == Some(RejectReason::AddressLimit) => StatusCode::TOO_MANY_REQUESTS,
+        Err(e) => error_status(&e),
+    };
+    (status, Json(SubmitTransactionResponse::from(result))).into_response()
+}
+
+async fn get_transaction_status(State(state): State<AppState>, Path(hash): Path<String>) -> Result<Json<Option<Transaction>>, ApiError> {
+    let blockchain = state.blockchain.read().await;
+    let hash_arr = parse_hash(&hash)?;
     if let Some(tx) = blockchain.mempool.get_transaction(&hash_arr).cloned() {
         return Ok(Json(Some(tx)));
     }
 
     for block in &blockchain.blocks {
-        if let Some(tx) = block.transactions.iter().find(|tx| tx.hash() == hash_arr) {
+        if let Some(tx) = block.transactions.get()?.iter().find(|tx| tx.hash() == hash_arr) {
             return Ok(Json(Some(tx.clone())));
         }
     }
@@ -222,7 +410,7 @@ async fn get_transaction_status(State(state): State<AppState>, Path(hash): Path<
 // New endpoints
 
 async fn get_recent_blocks(State(state): State<AppState>) -> Json<Vec<RecentBlock>> {
-    let blockchain = state.blockchain.lock().unwrap();
+    let blockchain = state.blockchain.read().await;
     let blocks = blockchain.blocks.iter().rev().take(20).map(|b| RecentBlock {
         height: b.header.height,
         hash: hex::encode(b.hash),
@@ -230,8 +418,8 @@ async fn get_recent_blocks(State(state): State<AppState>) -> Json<Vec<RecentBloc
     Json(blocks)
 }
 
-async fn get_block_by_height(State(state): State<AppState>, Path(height): Path<u64>) -> Result<Json<Option<Block>>, Response> {
-    let blockchain = state.blockchain.lock().unwrap();
+async fn get_block_by_height(State(state): State<AppState>, Path(height): Path<u64>) -> Result<Json<Option<Block>>, ApiError> {
+    let blockchain = state.blockchain.read().await;
     let block = blockchain.blocks.iter().find(|b| b.header.height == height).cloned();
     Ok(Json(block))
 }
@@ -244,9 +432,8 @@ pub struct TriangleInfo {
 }
 
 async fn get_address_triangles(State(state): State<AppState>, Path(addr): Path<String>) -> Json<Vec<TriangleInfo>> {
-    let blockchain = state.blockchain.lock().unwrap();
-    let triangles: Vec<TriangleInfo> = blockchain.state.utxo_set.iter()
-        .filter(|(_, triangle)| triangle.owner == addr)
+    let blockchain = state.blockchain.read().await;
+    let triangles: Vec<TriangleInfo> = blockchain.state.by_owner(&addr)
         .map(|(hash, triangle)| TriangleInfo {
             hash: hex::encode(hash),
             area: triangle.area(),
@@ -266,40 +453,279 @@ pub struct TransactionHistory {
     pub block_height: u64,
     pub timestamp: i64,
     pub tx_type: String,
+    /// The parts the address plays in the transaction
+    #[serde(default)]
+    pub roles: Vec<AddressRole>,
 }
 
-async fn get_address_history(State(state): State<AppState>, Path(addr): Path<String>) -> Json<Vec<TransactionHistory>> {
-    let blockchain = state.blockchain.lock().unwrap();
+/// Transactions naming `addr`, oldest first: from the database's address
+/// index if it keeps one, or else by scanning the chain
+async fn get_address_history(State(state): State<AppState>, Path(addr): Path<String>) -> Result<Json<Vec<TransactionHistory>>, ApiError> {
+    {
+        let db = state.db.lock().unwrap();
+        if db.has_address_index() {
+            return Ok(Json(db.load_address_history(&addr)?.into_iter().map(|entry| TransactionHistory {
+                tx_hash: hex::encode(entry.txid),
+                block_height: entry.height,
+                timestamp: entry.timestamp,
+                tx_type: tx_type_name(&entry.transaction).to_string(),
+                roles: entry.roles,
+            }).collect()));
+        }
+    }
+
+    let blockchain = state.blockchain.read().await;
     let mut history = Vec::new();
 
     for block in &blockchain.blocks {
-        for tx in &block.transactions {
-            let involves_address = match tx {
-                Transaction::Subdivision(tx) => tx.owner_address == addr,
-                Transaction::Transfer(tx) => tx.sender == addr || tx.new_owner == addr,
-                Transaction::Coinbase(tx) => tx.beneficiary_address == addr,
-            };
-
-            if involves_address {
+        for tx in block.transactions.get()? {
+            let roles: Vec<AddressRole> = tx.addresses().into_iter()
+                .filter(|(address, _)| **address == addr)
+                .map(|(_, role)| role)
+                .collect();
+
+            if !roles.is_empty() {
                 history.push(TransactionHistory {
                     tx_hash: tx.hash_str(),
                     block_height: block.header.height,
                     timestamp: block.header.timestamp,
-                    tx_type: match tx {
-                        Transaction::Subdivision(_) => "Subdivision".to_string(),
-                        Transaction::Transfer(_) => "Transfer".to_string(),
-                        Transaction::Coinbase(_) => "Coinbase".to_string(),
-                    },
+                    tx_type: tx_type_name(tx).to_string(),
+                    roles,
                 });
             }
         }
     }
 
-    Json(history)
+    Ok(Json(history))
+}
+
+fn tx_type_name(tx: &Transaction) -> &'static str {
+    match tx {
+        Transaction::Subdivision(_) => "Subdivision",
+        Transaction::DeepSubdivision(_) => "DeepSubdivision",
+        Transaction::SplitTransfer(_) => "SplitTransfer",
+        Transaction::Swap(_) => "Swap",
+        Transaction::Lease(_) => "Lease",
+        Transaction::Approve(_) => "Approve",
+        Transaction::Transfer(_) => "Transfer",
+        Transaction::BatchTransfer(_) => "BatchTransfer",
+        Transaction::HtlcLock(_) => "HtlcLock",
+        Transaction::HtlcRedeem(_) => "HtlcRedeem",
+        Transaction::EscrowLock(_) => "EscrowLock",
+        Transaction::EscrowSettle(_) => "EscrowSettle",
+        Transaction::Offer(_) => "Offer",
+        Transaction::Accept(_) => "Accept",
+        Transaction::Inscription(_) => "Inscription",
+        Transaction::Coinbase(_) => "Coinbase",
+    }
+}
+
+/// The nonce `addr`'s next transaction should use, after its confirmed and
+/// pending ones
+async fn get_next_nonce(State(state): State<AppState>, Path(addr): Path<String>) -> Json<u64> {
+    Json(state.blockchain.read().await.next_nonce(&addr))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TrianglePathInfo {
+    pub hash: String,
+    /// Canonical path address, e.g. `Δ/0/2/1`
+    pub path: String,
+    pub owner: String,
+    pub area: f64,
+    pub depth: u32,
+}
+
+/// Look up an unspent triangle by path. Use `.` between the parts, e.g.
+/// `/triangle/path/Δ.0.2.1`, since `/` would split the URL.
+async fn get_triangle_by_path(State(state): State<AppState>, Path(path): Path<String>) -> Result<Json<TrianglePathInfo>, ApiError> {
+    let blockchain = state.blockchain.read().await;
+    let path: TrianglePath = path.parse()?;
+    let (hash, triangle) = blockchain.state.find_by_path(&path)
+        .ok_or_else(|| ApiError::not_found("triangle-not-found", format!("No unspent triangle at {}", path)))?;
+    Ok(Json(TrianglePathInfo {
+        hash: hex::encode(hash),
+        path: path.to_string(),
+        owner: triangle.owner.clone(),
+        area: triangle.area(),
+        depth: triangle.depth,
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TriangleDetails {
+    pub hash: String,
+    pub vertices: Vec<(f64, f64)>,
+    pub owner: String,
+    pub area: f64,
+    pub depth: u32,
+    /// Path from its root, e.g. `Δ/0/2`
+    pub path: String,
+    /// Parent, grandparent and so on up to the root, or to the pruning point
+    pub ancestors: Vec<String>,
+    /// `unspent` or `spent`
+    pub status: String,
+    pub created_height: u64,
+    /// Transaction that created it, unknown for triangles from before the pruning point
+    pub created_by: Option<String>,
+    pub spent_height: Option<u64>,
+    pub spent_by: Option<String>,
+}
+
+/// Look up a triangle by hash, spent or not, with where it came from
+async fn get_triangle(State(state): State<AppState>, Path(hash): Path<String>) -> Result<Json<TriangleDetails>, ApiError> {
+    let blockchain = state.blockchain.read().await;
+    let hash = parse_hash(&hash)?;
+    let mut ancestry = blockchain.triangle_ancestry(&hash)?.into_iter();
+    let record = ancestry.next().ok_or_else(|| ChainError::TriangleNotFound(hex::encode(hash)))?;
+    let triangle = &record.triangle;
+    Ok(Json(TriangleDetails {
+        hash: hex::encode(record.id),
+        vertices: vec![
+            (triangle.a.x, triangle.a.y),
+            (triangle.b.x, triangle.b.y),
+            (triangle.c.x, triangle.c.y),
+        ],
+        owner: triangle.owner.clone(),
+        area: triangle.area(),
+        depth: triangle.depth,
+        path: TrianglePath::of(triangle).to_string(),
+        ancestors: ancestry.map(|ancestor| hex::encode(ancestor.id)).collect(),
+        status: if record.spent.is_some() { "spent" } else { "unspent" }.to_string(),
+        created_height: record.created_height,
+        created_by: record.created_by.map(hex::encode),
+        spent_height: record.spent.map(|(height, _)| height),
+        spent_by: record.spent.map(|(_, tx)| hex::encode(tx)),
+    }))
+}
+
+/// Most triangles `/utxo/region` answers with
+pub const MAX_REGION_TRIANGLES: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct RegionQuery {
+    /// `min_x,min_y,max_x,max_y`
+    pub bbox: Option<String>,
+    /// At most `MAX_REGION_TRIANGLES`, which is also the default
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegionTriangle {
+    pub hash: String,
+    pub owner: String,
+    pub area: f64,
+    pub depth: u32,
+    pub vertices: Vec<(f64, f64)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegionResponse {
+    /// In id order
+    pub triangles: Vec<RegionTriangle>,
+    /// Whether more triangles touch the box than were returned
+    pub truncated: bool,
+}
+
+/// Unspent triangles whose bounding boxes touch `bbox`, e.g.
+/// `/utxo/region?bbox=0,0,0.5,0.5`
+async fn get_utxo_region(State(state): State<AppState>, Query(query): Query<RegionQuery>) -> Result<Json<RegionResponse>, ApiError> {
+    let bounds: Bounds = query.bbox
+        .ok_or_else(|| ApiError::bad_request("missing-bbox", "Missing bbox=min_x,min_y,max_x,max_y"))?
+        .parse()?;
+    let limit = query.limit.unwrap_or(MAX_REGION_TRIANGLES).min(MAX_REGION_TRIANGLES);
+
+    let blockchain = state.blockchain.read().await;
+    let found = blockchain.state.query_region(&bounds);
+    Ok(Json(RegionResponse {
+        truncated: found.len() > limit,
+        triangles: found.into_iter().take(limit).map(|(hash, triangle)| RegionTriangle {
+            hash: hex::encode(hash),
+            owner: triangle.owner.clone(),
+            area: triangle.area(),
+            depth: triangle.depth,
+            vertices: vec![
+                (triangle.a.x, triangle.a.y),
+                (triangle.b.x, triangle.b.y),
+                (triangle.c.x, triangle.c.y),
+            ],
+        }).collect(),
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InscriptionInfo {
+    pub tx_hash: String,
+    pub block_height: u64,
+    pub owner: String,
+    /// Hex-encoded data
+    pub data: String,
+    /// The data as text, when it is valid UTF-8
+    pub text: Option<String>,
+}
+
+async fn get_triangle_inscriptions(State(state): State<AppState>, Path(hash): Path<String>) -> Result<Json<Vec<InscriptionInfo>>, ApiError> {
+    let blockchain = state.blockchain.read().await;
+    let hash_arr = parse_hash(&hash)?;
+
+    let inscriptions = blockchain.inscriptions(&hash_arr)?.into_iter()
+        .map(|(block_height, tx)| InscriptionInfo {
+            tx_hash: hex::encode(tx.hash()),
+            block_height,
+            owner: tx.owner.clone(),
+            data: hex::encode(&tx.data),
+            text: String::from_utf8(tx.data.clone()).ok(),
+        })
+        .collect();
+    Ok(Json(inscriptions))
+}
+
+/// The triangle followed by its ancestors up to its root, spent ones included
+async fn get_triangle_ancestry(State(state): State<AppState>, Path(hash): Path<String>) -> Result<Json<Vec<TriangleRecord>>, ApiError> {
+    let blockchain = state.blockchain.read().await;
+    let hash = parse_hash(&hash)?;
+    Ok(Json(blockchain.triangle_ancestry(&hash)?))
+}
+
+/// Everything subdivided out of the triangle, as a tree
+async fn get_triangle_descendants(State(state): State<AppState>, Path(hash): Path<String>) -> Result<Json<FamilyTree>, ApiError> {
+    let blockchain = state.blockchain.read().await;
+    let hash = parse_hash(&hash)?;
+    Ok(Json(blockchain.triangle_descendants(&hash)?))
+}
+
+#[derive(Deserialize)]
+pub struct TileQuery {
+    /// `owner`, `depth` or `age`; anything else draws one color
+    pub color: Option<String>,
+}
+
+/// Serve a PNG map tile of the unspent triangles, e.g. `/tiles/3/2/5.png`
+async fn get_tile(
+    State(state): State<AppState>,
+    Path((z, x, y)): Path<(u8, u64, String)>,
+    Query(query): Query<TileQuery>,
+) -> Result<Response, ApiError> {
+    let y: u64 = y.strip_suffix(".png").unwrap_or(&y).parse()
+        .map_err(|_| ApiError::bad_request("invalid-tile", format!("Invalid tile row '{}'", y)))?;
+    let tile = TileCoord::new(z, x, y)?;
+
+    let blockchain = state.blockchain.read().await;
+    let color_by = match query.color.as_deref() {
+        Some("owner") => ColorBy::Owner,
+        Some("depth") => ColorBy::Depth,
+        Some("age") => ColorBy::Age {
+            heights: blockchain.creation_heights(),
+            tip: blockchain.blocks.last().map_or(0, |b| b.header.height),
+        },
+        _ => ColorBy::Uniform,
+    };
+    let png = blockchain.state.render_tile(tile, &SvgOptions { color_by, ..SvgOptions::default() })?;
+    Ok(([(axum::http::header::CONTENT_TYPE, "image/png")], png).into_response())
 }
 
 async fn get_pending_transactions(State(state): State<AppState>) -> Json<Vec<Transaction>> {
-    let blockchain = state.blockchain.lock().unwrap();
+    let blockchain = state.blockchain.read().await;
     Json(blockchain.mempool.get_all_transactions())
 }
 
@@ -310,7 +736,7 @@ pub struct WalletResponse {
     pub private_key: String,
 }
 
-async fn create_wallet() -> Result<Json<WalletResponse>, Response> {
+async fn create_wallet() -> Result<Json<WalletResponse>, ApiError> {
     match KeyPair::generate() {
         Ok(keypair) => {
             let address = keypair.address();
@@ -323,7 +749,7 @@ async fn create_wallet() -> Result<Json<WalletResponse>, Response> {
                 private_key,
             }))
         }
-        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate keypair: {}", e)).into_response()),
+        Err(e) => Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.code(), format!("Failed to generate keypair: {}", e))),
     }
 }
 
@@ -332,11 +758,9 @@ pub struct ImportWalletRequest {
     pub private_key: String,
 }
 
-async fn import_wallet(Json(req): Json<ImportWalletRequest>) -> Result<Json<WalletResponse>, Response> {
-    let private_key_bytes = match hex::decode(&req.private_key) {
-        Ok(bytes) => bytes,
-        Err(_) => return Err((StatusCode::BAD_REQUEST, "Invalid private key format").into_response()),
-    };
+async fn import_wallet(Json(req): Json<ImportWalletRequest>) -> Result<Json<WalletResponse>, ApiError> {
+    let private_key_bytes = hex::decode(&req.private_key)
+        .map_err(|_| ApiError::bad_request("invalid-private-key", "Invalid private key format"))?;
 
     match KeyPair::from_secret_bytes(&private_key_bytes) {
         Ok(keypair) => {
@@ -349,7 +773,7 @@ async fn import_wallet(Json(req): Json<ImportWalletRequest>) -> Result<Json<Wall
                 private_key: req.private_key,
             }))
         }
-        Err(e) => Err((StatusCode::BAD_REQUEST, format!("Invalid private key: {}", e)).into_response()),
+        Err(e) => Err(ApiError::bad_request("invalid-private-key", format!("Invalid private key: {}", e))),
     }
 }
 
@@ -357,139 +781,94 @@ async fn import_wallet(Json(req): Json<ImportWalletRequest>) -> Result<Json<Wall
 pub struct MiningStatus {
     pub is_mining: bool,
     pub blocks_mined: u64,
+    /// Hashes per second over the last `miner::HASHRATE_WINDOW`
     pub hashrate: f64,
+    /// Hashes tried by the current mining task
+    pub hashes: u64,
+    pub thread_hashrates: Vec<f64>,
+    pub threads: usize,
+    /// Percentage of the time spent hashing
+    pub duty_cycle: u8,
 }
 
 async fn get_mining_status(State(state): State<AppState>) -> Json<MiningStatus> {
     let is_mining = state.mining.is_mining.load(Ordering::Relaxed);
     let blocks_mined = state.mining.blocks_mined.load(Ordering::Relaxed);
 
-    // Calculate approximate hashrate based on last block time
-    let hashrate = if is_mining {
-        let last_time = state.mining.last_block_time.lock().unwrap();
-        if let Some(instant) = *last_time {
-            let elapsed = instant.elapsed().as_secs_f64();
-            if elapsed > 0.0 {
-                // Estimate based on difficulty and time
-                let blockchain = state.blockchain.lock().unwrap();
-                let difficulty = blockchain.difficulty;
-                let expected_hashes = 16_u64.pow(difficulty as u32) as f64;
-                expected_hashes / elapsed
-            } else {
-                0.0
-            }
-        } else {
-            0.0
-        }
-    } else {
-        0.0
+    let stats = match state.mining.stats.lock().unwrap().as_ref() {
+        Some(stats) if is_mining => stats.borrow().clone(),
+        _ => MinerStats::default(),
     };
+    let config = *state.mining.config.lock().unwrap();
 
     Json(MiningStatus {
         is_mining,
         blocks_mined,
-        hashrate,
+        hashrate: stats.hashrate,
+        hashes: stats.hashes,
+        thread_hashrates: stats.thread_hashrates,
+        threads: config.threads,
+        duty_cycle: config.duty_cycle,
     })
 }
 
-async fn start_mining(State(state): State<AppState>) -> impl IntoResponse {
+/// Optional `/mining/start` parameters; mines on one thread flat out by default
+#[derive(Deserialize)]
+pub struct StartMiningQuery {
+    pub threads: Option<usize>,
+    /// Percentage of the time to spend hashing, from 1 to 100
+    pub duty_cycle: Option<u8>,
+}
+
+async fn start_mining(
+    State(state): State<AppState>,
+    Query(query): Query<StartMiningQuery>,
+) -> Result<Json<String>, ApiError> {
     // Check if already mining
     if state.mining.is_mining.load(Ordering::Relaxed) {
-        return (StatusCode::BAD_REQUEST, "Mining already in progress").into_response();
+        return Err(ApiError::new(StatusCode::CONFLICT, "mining-active", "Mining already in progress"));
     }
 
+    let defaults = MiningConfig::default();
+    let config = MiningConfig::new(
+        query.threads.unwrap_or(defaults.threads),
+        query.duty_cycle.unwrap_or(defaults.duty_cycle),
+    )?;
+
     // Get a wallet address for mining rewards
     let wallet_path = std::env::var("HOME").unwrap_or_else(|_| ".".to_string()) + "/.siertrichain/wallet.json";
-    let wallet_data = match std::fs::read_to_string(&wallet_path) {
-        Ok(data) => data,
-        Err(_) => return (StatusCode::BAD_REQUEST, "No wallet found. Create a wallet first using siertri-wallet-new").into_response(),
-    };
+    let wallet_data = std::fs::read_to_string(&wallet_path)
+        .map_err(|_| ApiError::bad_request("no-wallet", "No wallet found. Create a wallet first using siertri-wallet-new"))?;
 
-    let wallet: serde_json::Value = match serde_json::from_str(&wallet_data) {
-        Ok(w) => w,
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Invalid wallet format").into_response(),
-    };
+    let wallet: serde_json::Value = serde_json::from_str(&wallet_data)
+        .map_err(|_| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "wallet-error", "Invalid wallet format"))?;
 
-    let miner_address = match wallet.get("address").and_then(|a| a.as_str()) {
-        Some(addr) => addr.to_string(),
-        None => return (StatusCode::INTERNAL_SERVER_ERROR, "Wallet missing address").into_response(),
-    };
+    let miner_address = wallet.get("address").and_then(|a| a.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "wallet-error", "Wallet missing address"))?;
 
     // Set mining flag
+    state.mining.stop.store(false, Ordering::Relaxed);
     state.mining.is_mining.store(true, Ordering::Relaxed);
 
     // Spawn mining task
-    let blockchain_clone = state.blockchain.clone();
-    let db_clone = state.db.clone();
+    let blockchain = state.blockchain.clone();
+    let db = state.db.clone();
     let mining_state = state.mining.clone();
-
-    let task = tokio::spawn(async move {
-        loop {
-            // Check if we should stop
-            if !mining_state.is_mining.load(Ordering::Relaxed) {
-                break;
-            }
-
-            // Get pending transactions
-            let block = {
-                let blockchain = blockchain_clone.lock().unwrap();
-                let transactions = blockchain.mempool.get_all_transactions();
-
-                // Create coinbase transaction
-                let reward_area = 100u64;
-                let coinbase = Transaction::Coinbase(crate::transaction::CoinbaseTx {
-                    reward_area,
-                    beneficiary_address: miner_address.clone(),
-                });
-
-                let mut all_txs = vec![coinbase];
-                all_txs.extend(transactions);
-
-                let height = blockchain.blocks.len() as u64;
-                let previous_hash = blockchain.blocks.last().unwrap().hash;
-                let difficulty = blockchain.difficulty;
-
-                Block::new(height, previous_hash, difficulty, all_txs)
-            };
-
-            // Mine the block (this is CPU intensive)
-            let start = Instant::now();
-            match miner::mine_block(block) {
-                Ok(mined_block) => {
-                    // Update last block time
-                    {
-                        let mut last_time = mining_state.last_block_time.lock().unwrap();
-                        *last_time = Some(start);
-                    }
-
-                    // Add block to blockchain
-                    {
-                        let mut blockchain = blockchain_clone.lock().unwrap();
-                        if let Err(e) = blockchain.apply_block(mined_block.clone()) {
-                            eprintln!("Failed to apply mined block: {}", e);
-                            continue;
-                        }
-
-                        // Save to database
-                        let db = db_clone.lock().unwrap();
-                        if let Err(e) = db.save_block(&mined_block) {
-                            eprintln!("Failed to save block: {}", e);
-                        }
-                        if let Err(e) = db.save_utxo_set(&blockchain.state) {
-                            eprintln!("Failed to save UTXO set: {}", e);
-                        }
-                    }
-
-                    // Increment blocks mined counter
-                    mining_state.blocks_mined.fetch_add(1, Ordering::Relaxed);
-
-                    println!("✅ Mined block at height {}", mined_block.header.height);
-                }
-                Err(e) => {
-                    eprintln!("Mining error: {}", e);
-                    break;
-                }
-            }
+    *state.mining.config.lock().unwrap() = config;
+
+    // Hashing and the coordinator's chain locks block, so mine off the async runtime
+    let task = tokio::task::spawn_blocking(move || {
+        // Rebuilds the template whenever the tip moves or the mempool changes
+        let mut coordinator = MiningCoordinator::new(blockchain, miner_address).with_config(config);
+        *mining_state.stats.lock().unwrap() = Some(coordinator.subscribe());
+
+        let result = coordinator.run(Some(&db), &mining_state.stop, |block| {
+            mining_state.blocks_mined.fetch_add(1, Ordering::Relaxed);
+            println!("✅ Mined block at height {}", block.header.height);
+        });
+        if let Err(e) = result {
+            eprintln!("Mining error: {}", e);
         }
 
         println!("Mining stopped");
@@ -501,17 +880,18 @@ async fn start_mining(State(state): State<AppState>) -> impl IntoResponse {
         *task_handle = Some(task);
     }
 
-    Json("Mining started successfully".to_string()).into_response()
+    Ok(Json("Mining started successfully".to_string()))
 }
 
-async fn stop_mining(State(state): State<AppState>) -> impl IntoResponse {
+async fn stop_mining(State(state): State<AppState>) -> Result<Json<String>, ApiError> {
     // Check if mining is active
     if !state.mining.is_mining.load(Ordering::Relaxed) {
-        return (StatusCode::BAD_REQUEST, "Mining is not active").into_response();
+        return Err(ApiError::new(StatusCode::CONFLICT, "mining-inactive", "Mining is not active"));
     }
 
-    // Signal the mining task to stop
+    // Signal the mining task to stop, interrupting the block in progress
     state.mining.is_mining.store(false, Ordering::Relaxed);
+    state.mining.stop.store(true, Ordering::Relaxed);
 
     // Wait for the task to complete (with timeout)
     let task_handle = state.mining.mining_task.lock().unwrap().take();
@@ -525,22 +905,11 @@ async fn stop_mining(State(state): State<AppState>) -> impl IntoResponse {
         }
     }
 
-    Json("Mining stopped successfully".to_string()).into_response()
+    Ok(Json("Mining stopped successfully".to_string()))
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct PeerInfo {
-    pub address: String,
-    pub last_seen: i64,
-}
-
-async fn get_peers(State(state): State<AppState>) -> Json<Vec<PeerInfo>> {
-    let peers = state.network.peers.lock().unwrap();
-    let peer_info: Vec<PeerInfo> = peers.iter().map(|peer| PeerInfo {
-        address: peer.addr(),
-        last_seen: chrono::Utc::now().timestamp(), // In a real implementation, track actual last seen time
-    }).collect();
-    Json(peer_info)
+async fn get_peers(State(state): State<AppState>) -> Result<Json<Vec<PeerStatus>>, ApiError> {
+    Ok(Json(state.db.lock().unwrap().load_peer_statuses()?))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -548,20 +917,38 @@ pub struct NetworkInfo {
     pub peers_count: usize,
     pub node_id: String,
     pub listening_port: u16,
+    /// SOCKS5 proxy the node dials peers through, if any
+    pub socks5_proxy: Option<String>,
 }
 
 async fn get_network_info(State(state): State<AppState>) -> Json<NetworkInfo> {
-    let peers = state.network.peers.lock().unwrap();
+    let (peers, socks5_proxy) = {
+        let db = state.db.lock().unwrap();
+        (db.load_peer_statuses().unwrap_or_default(), db.load_socks5_proxy().unwrap_or_default())
+    };
     let node_id = state.network.node_id.lock().unwrap();
     let listening_port = state.network.listening_port.lock().unwrap();
 
     Json(NetworkInfo {
-        peers_count: peers.len(),
+        peers_count: peers.iter().filter(|peer| peer.state == PeerState::Connected).count(),
         node_id: node_id.clone(),
         listening_port: *listening_port,
+        socks5_proxy,
     })
 }
 
+/// Sync progress last saved by the node, or the API's own chain at rest if
+/// no node has saved any
+async fn get_sync_state(State(state): State<AppState>) -> Result<Json<SyncState>, ApiError> {
+    let saved = state.db.lock().unwrap().load_sync_state()?;
+    if let Some(sync) = saved {
+        return Ok(Json(sync));
+    }
+    let blockchain = state.blockchain.read().await;
+    let height = blockchain.blocks.last().map_or(0, |block| block.header.height);
+    Ok(Json(SyncState::new(blockchain.header_chain.best_height(), height, height, 0.0)))
+}
+
 // New endpoints for enhanced block explorer functionality
 
 #[derive(Serialize)]
@@ -574,7 +961,7 @@ struct MempoolStatsResponse {
 }
 
 async fn get_mempool_stats(State(state): State<AppState>) -> Json<MempoolStatsResponse> {
-    let blockchain = state.blockchain.lock().unwrap();
+    let blockchain = state.blockchain.read().await;
     let txs = blockchain.mempool.get_all_transactions();
 
     let fees: Vec<u64> = txs.iter().map(|tx| tx.fee()).collect();
@@ -606,7 +993,7 @@ struct RewardInfoResponse {
 }
 
 async fn get_block_reward_info(State(state): State<AppState>, Path(height): Path<u64>) -> Json<RewardInfoResponse> {
-    let blockchain = state.blockchain.lock().unwrap();
+    let blockchain = state.blockchain.read().await;
     let current_height = blockchain.blocks.len() as u64;
     let query_height = if height == 0 { current_height } else { height };
 
@@ -632,11 +1019,16 @@ mod tests {
     use axum_test::TestServer;
 
     fn test_app() -> Router {
-        let blockchain = Blockchain::new();
-        let db = Database::open(":memory:").unwrap();
+        test_app_with(Blockchain::regtest())
+    }
+
+    fn test_app_with(blockchain: Blockchain) -> Router {
+        test_app_with_db(blockchain, Database::open(":memory:").unwrap())
+    }
 
+    fn test_app_with_db(blockchain: Blockchain, db: Database) -> Router {
         let app_state = AppState {
-            blockchain: Arc::new(Mutex::new(blockchain)),
+            blockchain: Arc::new(RwLock::new(blockchain)),
             db: Arc::new(Mutex::new(db)),
             mining: MiningState::default(),
             network: NetworkState::default(),
@@ -645,12 +1037,80 @@ mod tests {
         Router::new()
             .route("/blockchain/height", get(get_blockchain_height))
             .route("/blockchain/block/:hash", get(get_block_by_hash))
+            .route("/blockchain/block/:hash/proof/:txid", get(get_merkle_proof))
             .route("/address/:addr/balance", get(get_address_balance))
             .route("/transaction", post(submit_transaction))
             .route("/transaction/:hash", get(get_transaction_status))
+            .route("/triangle/:hash", get(get_triangle))
+            .route("/triangle/:hash/inscriptions", get(get_triangle_inscriptions))
+            .route("/triangle/path/:path", get(get_triangle_by_path))
+            .route("/utxo/region", get(get_utxo_region))
+            .route("/triangle/:hash/ancestry", get(get_triangle_ancestry))
+            .route("/triangle/:hash/descendants", get(get_triangle_descendants))
+            .route("/tiles/:z/:x/:y", get(get_tile))
+            .route("/network/peers", get(get_peers))
+            .route("/network/info", get(get_network_info))
+            .route("/network/sync", get(get_sync_state))
             .with_state(app_state)
     }
 
+    #[tokio::test]
+    async fn test_network_peers_come_from_the_node() {
+        let server = TestServer::new(test_app()).unwrap();
+        assert!(server.get("/network/peers").await.json::<Vec<PeerStatus>>().is_empty());
+
+        let db = Database::open(":memory:").unwrap();
+        let peers = vec![
+            PeerStatus {
+                address: "10.0.0.1:8333".to_string(),
+                state: PeerState::Connected,
+                direction: crate::network::Direction::Outbound,
+                last_seen: Some(1_700_000_000),
+                latency_ms: Some(12),
+                failures: 0,
+                retry_at: None,
+                encrypted: true,
+            },
+            PeerStatus {
+                address: "10.0.0.2:8333".to_string(),
+                state: PeerState::Backoff,
+                direction: crate::network::Direction::Outbound,
+                last_seen: None,
+                latency_ms: None,
+                failures: 3,
+                retry_at: Some(1_700_000_100),
+                encrypted: false,
+            },
+        ];
+        db.save_peer_statuses(&peers).unwrap();
+        db.save_socks5_proxy(Some("127.0.0.1:9050")).unwrap();
+        let server = TestServer::new(test_app_with_db(Blockchain::regtest(), db)).unwrap();
+
+        assert_eq!(server.get("/network/peers").await.json::<Vec<PeerStatus>>(), peers);
+        let info = server.get("/network/info").await.json::<serde_json::Value>();
+        assert_eq!(info["peers_count"], 1);
+        assert_eq!(info["socks5_proxy"], "127.0.0.1:9050");
+    }
+
+    #[tokio::test]
+    async fn test_network_sync_comes_from_the_node() {
+        let server = TestServer::new(test_app()).unwrap();
+        let idle = server.get("/network/sync").await.json::<SyncState>();
+        assert_eq!(idle.blocks_height, 0);
+        assert_eq!(idle.percentage, 100.0);
+        assert!(!idle.syncing);
+
+        let db = Database::open(":memory:").unwrap();
+        let state = SyncState::new(84, 42, 84, 3.5);
+        db.save_sync_state(&state).unwrap();
+        let server = TestServer::new(test_app_with_db(Blockchain::regtest(), db)).unwrap();
+
+        let sync = server.get("/network/sync").await.json::<SyncState>();
+        assert_eq!(sync, state);
+        assert_eq!(sync.percentage, 50.0);
+        assert!(sync.syncing);
+    }
+
     #[tokio::test]
     async fn test_get_blockchain_height() {
         let server = TestServer::new(test_app()).unwrap();
@@ -686,12 +1146,13 @@ mod tests {
 
     #[tokio::test]
     async fn test_submit_and_get_transaction() {
-        let server = TestServer::new(test_app()).unwrap();
-        let blockchain = Blockchain::new();
+        let mut blockchain = Blockchain::regtest();
         let _genesis = blockchain.blocks[0].clone();
         let keypair = KeyPair::generate().unwrap();
         let address = keypair.address();
         let parent_hash = *blockchain.state.utxo_set.keys().next().unwrap();
+        blockchain.state.utxo_set.get_mut(&parent_hash).unwrap().owner = address.clone();
+        let server = TestServer::new(test_app_with(blockchain.clone())).unwrap();
         let children = blockchain.state.utxo_set.values().next().unwrap().subdivide();
         let mut tx = SubdivisionTx::new(parent_hash, children.to_vec(), address, 0, 1);
         let message = tx.signable_message();
@@ -702,12 +1163,174 @@ mod tests {
 
         let response = server.post("/transaction").json(&transaction).await;
         assert_eq!(response.status_code(), StatusCode::OK);
-        let tx_hash: String = response.json();
+        let submitted: SubmitTransactionResponse = response.json();
+        let tx_hash = submitted.tx_hash;
         assert!(!tx_hash.is_empty());
+        assert!(submitted.replaced.is_none());
+        assert_eq!(submitted.status, "accepted");
+        assert!(submitted.fee_rate.is_some());
+
+        // Resubmitting is rejected with a reason code
+        let response = server.post("/transaction").json(&transaction).await;
+        assert_eq!(response.status_code(), StatusCode::CONFLICT);
+        let rejected: SubmitTransactionResponse = response.json();
+        assert_eq!(rejected.status, "rejected");
+        assert_eq!(rejected.reason.as_deref(), Some("duplicate"));
 
         let response = server.get(&format!("/transaction/{}", tx_hash)).await;
         assert_eq!(response.status_code(), StatusCode::OK);
         let tx_status: Option<Transaction> = response.json();
         assert!(tx_status.is_some());
     }
+
+    #[tokio::test]
+    async fn test_get_triangle_inscriptions() {
+        let server = TestServer::new(test_app()).unwrap();
+        let genesis_hash = hex::encode(crate::blockchain::genesis_triangle().hash());
+        let response = server.get(&format!("/triangle/{}/inscriptions", genesis_hash)).await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert!(response.json::<Vec<InscriptionInfo>>().is_empty());
+
+        let response = server.get("/triangle/not-hex/inscriptions").await;
+        assert_eq!(response.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.json::<ErrorResponse>().error, "codec-error");
+    }
+
+    #[tokio::test]
+    async fn test_get_triangle_by_path() {
+        let mut blockchain = Blockchain::regtest();
+        let genesis = crate::blockchain::genesis_triangle();
+        blockchain.state.utxo_set.remove(&genesis.hash());
+        for child in genesis.subdivide() {
+            blockchain.state.utxo_set.insert(child.hash(), child);
+        }
+        let server = TestServer::new(test_app_with(blockchain)).unwrap();
+
+        let response = server.get("/triangle/path/Δ.2").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let info: TrianglePathInfo = response.json();
+        assert_eq!(info.path, "Δ/2");
+        assert_eq!(info.hash, hex::encode(genesis.subdivide()[2].hash()));
+        assert_eq!(info.depth, 1);
+
+        // Genesis itself has been subdivided
+        assert_eq!(server.get("/triangle/path/Δ").await.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(server.get("/triangle/path/Δ.3").await.status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_get_tile() {
+        let server = TestServer::new(test_app()).unwrap();
+
+        let response = server.get("/tiles/0/0/0.png").add_query_param("color", "owner").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.header("content-type"), "image/png");
+        assert!(response.as_bytes().starts_with(b"\x89PNG"));
+
+        assert_eq!(server.get("/tiles/1/1/1").await.status_code(), StatusCode::OK);
+        assert_eq!(server.get("/tiles/1/2/0.png").await.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(server.get("/tiles/40/0/0.png").await.status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_get_triangle_genealogy() {
+        let server = TestServer::new(test_app()).unwrap();
+        let genesis = crate::blockchain::genesis_triangle().hash_str();
+
+        let response = server.get(&format!("/triangle/{}/ancestry", genesis)).await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let ancestry: Vec<TriangleRecord> = response.json();
+        assert_eq!(ancestry.len(), 1);
+        assert_eq!(ancestry[0].owners[0].1, "genesis_owner");
+
+        let response = server.get(&format!("/triangle/{}/descendants", genesis)).await;
+        assert_eq!(response.json::<FamilyTree>().size(), 1);
+
+        let unknown = hex::encode([7u8; 32]);
+        let response = server.get(&format!("/triangle/{}/ancestry", unknown)).await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        let error: ErrorResponse = response.json();
+        assert_eq!(error.error, "triangle-not-found");
+        assert!(error.message.contains(&unknown));
+    }
+
+    #[tokio::test]
+    async fn test_get_triangle() {
+        let mut blockchain = Blockchain::regtest();
+        let genesis = crate::blockchain::genesis_triangle();
+        blockchain.prune_base = Some(blockchain.state.clone());
+        let server = TestServer::new(test_app_with(blockchain)).unwrap();
+
+        let response = server.get(&format!("/triangle/{}", genesis.hash_str())).await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let details: TriangleDetails = response.json();
+        assert_eq!(details.owner, "genesis_owner");
+        assert_eq!(details.status, "unspent");
+        assert_eq!(details.depth, 0);
+        assert_eq!(details.path, TrianglePath::of(&genesis).to_string());
+        assert!(details.ancestors.is_empty());
+        assert!(details.created_by.is_none());
+
+        let response = server.get(&format!("/triangle/{}", hex::encode([7u8; 32]))).await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(response.json::<ErrorResponse>().error, "triangle-not-found");
+    }
+
+    #[tokio::test]
+    async fn test_get_utxo_region() {
+        let mut blockchain = Blockchain::regtest();
+        let genesis = crate::blockchain::genesis_triangle();
+        blockchain.state.remove_triangle(&genesis.hash());
+        for child in genesis.subdivide() {
+            blockchain.state.insert_triangle(child.hash(), child);
+        }
+        let server = TestServer::new(test_app_with(blockchain)).unwrap();
+
+        let everything = Bounds::of([&genesis]).unwrap();
+        let bbox = format!("{},{},{},{}", everything.min_x, everything.min_y, everything.max_x, everything.max_y);
+        let response = server.get("/utxo/region").add_query_param("bbox", &bbox).await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let region: RegionResponse = response.json();
+        assert_eq!(region.triangles.len(), 3);
+        assert!(!region.truncated);
+
+        let response = server.get("/utxo/region").add_query_param("bbox", &bbox).add_query_param("limit", 2).await;
+        let region: RegionResponse = response.json();
+        assert_eq!(region.triangles.len(), 2);
+        assert!(region.truncated);
+
+        let far = server.get("/utxo/region").add_query_param("bbox", "100,100,101,101").await;
+        assert!(far.json::<RegionResponse>().triangles.is_empty());
+
+        let response = server.get("/utxo/region").await;
+        assert_eq!(response.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.json::<ErrorResponse>().error, "missing-bbox");
+        let response = server.get("/utxo/region").add_query_param("bbox", "1,1,0,0").await;
+        assert_eq!(response.json::<ErrorResponse>().error, "codec-error");
+    }
+
+    #[tokio::test]
+    async fn test_get_merkle_proof() {
+        let mut blockchain = Blockchain::regtest();
+        let txs: Vec<Transaction> = (0..3)
+            .map(|i| Transaction::Coinbase(crate::transaction::CoinbaseTx::new(1000 + i, "miner".to_string())))
+            .collect();
+        let mut block = Block::new(1, blockchain.blocks[0].hash, blockchain.bits, txs.clone());
+        block.hash = block.calculate_hash();
+        blockchain.forks.insert(block.hash, block.clone());
+        let server = TestServer::new(test_app_with(blockchain)).unwrap();
+
+        let url = format!("/blockchain/block/{}/proof/{}", hex::encode(block.hash), txs[2].hash_str());
+        let response = server.get(&url).await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let proof: MerkleProofResponse = response.json();
+        assert_eq!(proof.index, 2);
+        assert_eq!(proof.header.merkle_root, block.header.merkle_root);
+        assert!(proof.verify().unwrap());
+
+        let url = format!("/blockchain/block/{}/proof/{}", hex::encode(block.hash), hex::encode([7u8; 32]));
+        let response = server.get(&url).await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(response.json::<ErrorResponse>().error, "transaction-not-found");
+    }
 }
@@ -1,15 +1,18 @@
 use siertrichain::api::run_api_server;
+use siertrichain::config::Config;
 use siertrichain::persistence::Database;
 use siertrichain::blockchain::Blockchain;
 
 #[tokio::main]
 async fn main() {
-    let db = Database::open("siertrichain.db").unwrap();
+    let config = Config::from_args(&mut std::env::args().collect()).unwrap();
+    let db = Database::open_in(&config).unwrap();
     if db.load_blockchain().is_err() {
-        let chain = Blockchain::new();
-        db.save_blockchain_state(&chain.blocks[0], &chain.state, chain.difficulty).unwrap();
+        let mut chain = Blockchain::with_params(&config.params);
+        let genesis = chain.blocks[0].clone();
+        db.save_blockchain_state(&genesis, &mut chain.state, chain.bits).unwrap();
     }
 
     println!("Starting the siertrichain API server...");
-    run_api_server().await;
+    run_api_server(&config).await;
 }
@@ -1,6 +1,9 @@
 //! Check wallet balance - Beautiful edition!
+//!
+//! Asks the node (see `siertrid`) at `SIERTRI_RPC_URL`, by default
+//! http://127.0.0.1:3000, rather than reading the database itself.
 
-use siertrichain::persistence::Database;
+use siertrichain::rpc::RpcClient;
 use colored::*;
 use comfy_table::{Table, Cell, ContentArrangement, Attribute};
 use comfy_table::presets::UTF8_FULL;
@@ -18,7 +21,8 @@ const LOGO: &str = r#"
 ╚═══════════════════════════════════════════════════════════════╝
 "#;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("{}", LOGO.bright_cyan());
 
     let home = std::env::var("HOME")?;
@@ -40,10 +44,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let my_address = wallet_data["address"].as_str()
         .ok_or("Wallet address not found in wallet file")?;
 
-    let db = Database::open("siertrichain.db")
-        .map_err(|e| format!("Failed to open database: {}", e))?;
-    let chain = db.load_blockchain()
-        .map_err(|e| format!("Failed to load blockchain: {}", e))?;
+    let rpc = RpcClient::from_env();
+    let sync = rpc.sync_state().await?;
+    let triangles = rpc.triangles(&my_address.to_string()).await?;
 
     println!("{}", "┌─────────────────────────────────────────────────────────────┐".bright_green());
     println!("{}", "│                    💰 WALLET BALANCE                        │".bright_green().bold());
@@ -58,10 +61,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("{}", format!("📍 Address: {}", addr_display).cyan());
 
-    let height = chain.blocks.last()
-        .map(|b| b.header.height)
-        .unwrap_or(0);
-    println!("{}", format!("📊 Chain Height: {}", height).bright_blue());
+    println!("{}", format!("📊 Chain Height: {}", sync.blocks_height).bright_blue());
     println!("{}", format!("⛓️  Network: {}", "Mainnet".bright_magenta()).bright_blue());
     println!();
 
@@ -69,11 +69,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut total_area = 0.0;
     let mut triangle_list = Vec::new();
 
-    for (hash, triangle) in &chain.state.utxo_set {
+    for triangle in triangles {
         my_triangles += 1;
-        total_area += triangle.area();
-        let hash_hex = hex::encode(hash);
-        triangle_list.push((hash_hex, triangle.area()));
+        total_area += triangle.area;
+        triangle_list.push((triangle.hash, triangle.area));
     }
 
     if my_triangles == 0 {
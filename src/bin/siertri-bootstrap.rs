@@ -0,0 +1,41 @@
+//! Export the chain to a bootstrap file, or import one to bring up a new
+//! node without syncing every block from peers
+
+use siertrichain::config::{Config, DATADIR_ENV};
+use siertrichain::persistence::Database;
+use colored::*;
+use std::env;
+
+fn print_usage() {
+    println!("{}", "Usage:".bright_yellow().bold());
+    println!("  siertri-bootstrap export <file> [--datadir <dir>]");
+    println!("  siertri-bootstrap import <file> [--datadir <dir>]");
+    println!();
+    println!("  export writes the stored main chain to <file>; import validates the");
+    println!("  blocks in <file> and adds those the database doesn't have. The database");
+    println!("  is in --datadir, else ${}, else ~/.siertrichain/data.", DATADIR_ENV);
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args: Vec<String> = env::args().collect();
+    let config = Config::from_args(&mut args)?;
+
+    match (args.get(1).map(String::as_str), args.len()) {
+        (Some("export"), 3) => {
+            let db = Database::open_read_only_in(&config)?;
+            let count = db.export_chain(&args[2])?;
+            println!("{}", format!("📦 Exported {} blocks to {}", count, args[2]).bright_green().bold());
+        }
+        (Some("import"), 3) => {
+            let db = Database::open_in(&config)?;
+            println!("{}", format!("📥 Importing {}...", args[2]).bright_cyan());
+            let count = db.import_chain(&args[2])?;
+            println!("{}", format!("✅ Imported {} new blocks", count).bright_green().bold());
+        }
+        _ => {
+            print_usage();
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
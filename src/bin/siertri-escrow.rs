@@ -0,0 +1,109 @@
+//! Escrow CLI for siertrichain: lock a triangle for a buyer with an arbiter,
+//! then release or refund it once two of the three parties have signed
+
+use siertrichain::blockchain::Blockchain;
+use siertrichain::codec;
+use siertrichain::config::Config;
+use siertrichain::network::NetworkNode;
+use siertrichain::persistence::Database;
+use siertrichain::security::SecurityManager;
+use siertrichain::transaction::{EscrowOutcome, EscrowSettleTx, EscrowSignature, Transaction};
+use siertrichain::wallet;
+use colored::*;
+use std::env;
+
+fn print_usage() {
+    println!("{}", "Usage:".bright_yellow().bold());
+    println!("  siertri-escrow lock <triangle> <buyer> <arbiter>");
+    println!("  siertri-escrow sign <triangle> <release|refund>");
+    println!("  siertri-escrow settle <triangle> <release|refund> <cosignature>");
+    println!("  (each takes --datadir <dir> to read the chain from another data directory)");
+    println!();
+    println!("  <triangle> is a hash prefix or a path such as Δ/0/2/1");
+    println!();
+    println!("{}", "Flow:".bright_yellow().bold());
+    println!("  1. The seller locks the triangle in escrow");
+    println!("  2. One party runs `sign` and hands the printed cosignature to another");
+    println!("  3. That party runs `settle` with it to submit the settlement");
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args: Vec<String> = env::args().collect();
+    let config = Config::from_args(&mut args)?;
+
+    match (args.get(1).map(String::as_str), args.len()) {
+        (Some("lock"), 5) => lock(&config, &args[2], &args[3], &args[4]).await,
+        (Some("sign"), 4) => sign(&config, &args[2], &args[3]),
+        (Some("settle"), 5) => settle(&config, &args[2], &args[3], &args[4]).await,
+        _ => {
+            print_usage();
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_outcome(outcome: &str) -> Result<EscrowOutcome, String> {
+    match outcome {
+        "release" => Ok(EscrowOutcome::Release),
+        "refund" => Ok(EscrowOutcome::Refund),
+        other => Err(format!("Unknown outcome '{}', expected release or refund", other)),
+    }
+}
+
+/// The unsigned settlement every party signs for `outcome`
+fn settlement(chain: &Blockchain, triangle: &str, outcome: &str) -> Result<EscrowSettleTx, Box<dyn std::error::Error>> {
+    let input_hash = chain.state.resolve(triangle)?;
+    let contract = chain.state.escrows.get(&input_hash)
+        .ok_or_else(|| format!("Triangle {} is not in escrow", hex::encode(input_hash)))?;
+    Ok(EscrowSettleTx::new(contract, input_hash, parse_outcome(outcome)?, 0))
+}
+
+async fn submit(config: &Config, mut chain: Blockchain, tx: Transaction) -> Result<(), Box<dyn std::error::Error>> {
+    chain.submit_transaction(tx.clone())?;
+    let network_node = NetworkNode::new(chain, config.db_path().display().to_string())
+        .with_security(SecurityManager::from_env()?);
+    network_node.broadcast_transaction(&tx).await?;
+    println!("{}", format!("✅ Submitted {}", tx.hash_str()).bright_green().bold());
+    Ok(())
+}
+
+async fn lock(config: &Config, triangle: &str, buyer: &str, arbiter: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let wallet = wallet::load_default_wallet()?;
+    let chain = Database::open_read_only_in(config)?.load_blockchain()?;
+
+    let input_hash = chain.state.resolve(triangle)?;
+    let nonce = chain.next_nonce(&wallet.address);
+    let tx = wallet.create_escrow_lock(input_hash, buyer.to_string(), arbiter.to_string(), 0, nonce)?;
+
+    println!("{}", "🔒 Locking triangle in escrow".bright_cyan().bold());
+    println!("  Triangle: {}", hex::encode(input_hash));
+    println!("  Buyer:    {}", buyer);
+    println!("  Arbiter:  {}", arbiter);
+    submit(config, chain, tx).await
+}
+
+fn sign(config: &Config, triangle: &str, outcome: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let wallet = wallet::load_default_wallet()?;
+    let chain = Database::open_read_only_in(config)?.load_blockchain()?;
+
+    let tx = settlement(&chain, triangle, outcome)?;
+    let signature = wallet.sign_escrow_settlement(&tx)?;
+
+    println!("{}", "✍️  Cosignature (give this to another party):".bright_cyan().bold());
+    println!("{}", hex::encode(codec::encode(&signature)));
+    Ok(())
+}
+
+async fn settle(config: &Config, triangle: &str, outcome: &str, cosignature: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let wallet = wallet::load_default_wallet()?;
+    let chain = Database::open_read_only_in(config)?.load_blockchain()?;
+
+    let mut tx = settlement(&chain, triangle, outcome)?;
+    let cosignature: EscrowSignature = codec::decode(&hex::decode(cosignature)?)?;
+    tx.add_signature(cosignature);
+    tx.add_signature(wallet.sign_escrow_settlement(&tx)?);
+
+    println!("{}", format!("🔓 Settling escrow: {}", outcome).bright_cyan().bold());
+    submit(config, chain, Transaction::EscrowSettle(tx)).await
+}
@@ -1,7 +1,9 @@
 //! View transaction history for your wallet - Beautiful edition!
 
+use siertrichain::config::Config;
 use siertrichain::persistence::Database;
-use siertrichain::transaction::Transaction;
+use siertrichain::error::ChainError;
+use siertrichain::transaction::{EscrowOutcome, HtlcRedeemPath, Transaction};
 use colored::*;
 use comfy_table::{Table, Cell, ContentArrangement, Attribute};
 use comfy_table::presets::UTF8_FULL;
@@ -41,10 +43,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let my_address = wallet_data["address"].as_str()
         .ok_or("Wallet address not found in wallet file")?;
 
-    let db = Database::open("siertrichain.db")
+    let config = Config::from_args(&mut std::env::args().collect())?;
+    let db = Database::open_read_only_in(&config)
         .map_err(|e| format!("Failed to open database: {}", e))?;
-    let chain = db.load_blockchain()
-        .map_err(|e| format!("Failed to load blockchain: {}", e))?;
+
+    // Height, time and transactions of each block to look through: just the
+    // ones naming the address if the database keeps an address index
+    let mut blocks: Vec<(u64, i64, Vec<Transaction>)> = Vec::new();
+    if db.has_address_index() {
+        let entries = db.load_address_history(my_address)
+            .map_err(|e| format!("Failed to load address history: {}", e))?;
+        for entry in entries {
+            match blocks.last_mut() {
+                Some((height, _, transactions)) if *height == entry.height => transactions.push(entry.transaction),
+                _ => blocks.push((entry.height, entry.timestamp, vec![entry.transaction])),
+            }
+        }
+    } else {
+        let chain = db.load_blockchain()
+            .map_err(|e| format!("Failed to load blockchain: {}", e))?;
+        blocks = chain.blocks.iter()
+            .map(|block| Ok((block.header.height, block.header.timestamp, block.transactions.get()?.clone())))
+            .collect::<Result<_, ChainError>>()
+            .map_err(|e| format!("Failed to load blockchain: {}", e))?;
+    }
 
     let addr_display = if my_address.len() > 40 {
         format!("{}...{}", &my_address[..20], &my_address[my_address.len()-16..])
@@ -74,10 +96,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let mut transactions: Vec<TxRecord> = Vec::new();
+    // Sellers of offers seen so far, so an acceptance can be matched to its seller
+    let mut offer_sellers = std::collections::HashMap::new();
 
-    // Iterate through all blocks
-    for block in &chain.blocks {
-        for tx in &block.transactions {
+    for (height, timestamp, block_transactions) in &blocks {
+        for tx in block_transactions {
             match tx {
                 Transaction::Transfer(transfer_tx) => {
                     let is_sender = transfer_tx.sender == my_address;
@@ -130,27 +153,247 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         };
 
                         transactions.push(TxRecord {
-                            block_height: block.header.height,
+                            block_height: *height,
                             tx_type: "Transfer".to_string(),
                             direction,
                             details: format!("{} | {}{}", hash_short, other_party, memo_str),
-                            timestamp: block.header.timestamp,
+                            timestamp: *timestamp,
+                            color,
+                        });
+                    }
+                }
+                Transaction::BatchTransfer(batch_tx) => {
+                    let is_sender = batch_tx.sender == my_address;
+                    let received = batch_tx.transfers.iter().filter(|t| t.new_owner == my_address).count();
+
+                    if is_sender || received > 0 {
+                        tx_count += 1;
+
+                        let (direction, color, details) = if is_sender {
+                            sent_count += 1;
+                            ("📤 Sent".to_string(), TableColor::Red,
+                             format!("{} triangles in one batch", batch_tx.transfers.len()))
+                        } else {
+                            received_count += 1;
+                            let addr = &batch_tx.sender;
+                            let from = if addr.len() > 20 {
+                                format!("From: {}...{}", &addr[..8], &addr[addr.len()-8..])
+                            } else {
+                                format!("From: {}", addr)
+                            };
+                            ("📥 Received".to_string(), TableColor::Green,
+                             format!("{} triangles | {}", received, from))
+                        };
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "Batch Transfer".to_string(),
+                            direction,
+                            details,
+                            timestamp: *timestamp,
                             color,
                         });
                     }
                 }
+                Transaction::HtlcLock(lock_tx) => {
+                    if lock_tx.sender == my_address || lock_tx.recipient == my_address {
+                        tx_count += 1;
+
+                        let (direction, other) = if lock_tx.sender == my_address {
+                            ("🔒 Locked".to_string(), format!("For: {}", lock_tx.recipient))
+                        } else {
+                            ("🔒 Incoming".to_string(), format!("From: {}", lock_tx.sender))
+                        };
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "HTLC Lock".to_string(),
+                            direction,
+                            details: format!("{} | timeout at height {}", other, lock_tx.timeout_height),
+                            timestamp: *timestamp,
+                            color: TableColor::Yellow,
+                        });
+                    }
+                }
+                Transaction::Lease(lease_tx) => {
+                    if lease_tx.owner == my_address || lease_tx.tenant == my_address {
+                        tx_count += 1;
+
+                        let (direction, other) = if lease_tx.owner == my_address {
+                            ("🔑 Leased out".to_string(), format!("To: {}", lease_tx.tenant))
+                        } else {
+                            ("🔑 Leased".to_string(), format!("From: {}", lease_tx.owner))
+                        };
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "Lease".to_string(),
+                            direction,
+                            details: format!("{} | expires at height {}", other, lease_tx.expires_at),
+                            timestamp: *timestamp,
+                            color: TableColor::Yellow,
+                        });
+                    }
+                }
+                Transaction::Approve(approve_tx) => {
+                    let is_delegate = approve_tx.delegate.as_deref() == Some(my_address);
+                    if approve_tx.owner == my_address || is_delegate {
+                        tx_count += 1;
+
+                        let (direction, details) = match (&approve_tx.delegate, is_delegate) {
+                            (Some(_), true) => ("✅ Approved".to_string(), format!("By: {}", approve_tx.owner)),
+                            (Some(delegate), false) => ("✅ Approved".to_string(), format!("Delegate: {}", delegate)),
+                            (None, _) => ("🚫 Revoked".to_string(), hex::encode(&approve_tx.input_hash[..8])),
+                        };
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "Approve".to_string(),
+                            direction,
+                            details,
+                            timestamp: *timestamp,
+                            color: TableColor::Cyan,
+                        });
+                    }
+                }
+                Transaction::HtlcRedeem(redeem_tx) => {
+                    if redeem_tx.redeemer == my_address {
+                        tx_count += 1;
+                        received_count += 1;
+
+                        let kind = match redeem_tx.path {
+                            HtlcRedeemPath::Claim { .. } => "Claimed",
+                            HtlcRedeemPath::Refund => "Refunded",
+                        };
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "HTLC Redeem".to_string(),
+                            direction: format!("🔓 {}", kind),
+                            details: hex::encode(&redeem_tx.input_hash[..8]),
+                            timestamp: *timestamp,
+                            color: TableColor::Green,
+                        });
+                    }
+                }
+                Transaction::EscrowLock(lock_tx) => {
+                    let role = if lock_tx.seller == my_address {
+                        Some(("🔒 Escrowed", format!("Buyer: {}", lock_tx.buyer)))
+                    } else if lock_tx.buyer == my_address {
+                        Some(("🔒 Incoming", format!("Seller: {}", lock_tx.seller)))
+                    } else if lock_tx.arbiter == my_address {
+                        Some(("⚖️  Arbiter", format!("Seller: {}", lock_tx.seller)))
+                    } else {
+                        None
+                    };
+
+                    if let Some((direction, details)) = role {
+                        tx_count += 1;
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "Escrow Lock".to_string(),
+                            direction: direction.to_string(),
+                            details,
+                            timestamp: *timestamp,
+                            color: TableColor::Yellow,
+                        });
+                    }
+                }
+                Transaction::EscrowSettle(settle_tx) => {
+                    if settle_tx.signatures.iter().any(|s| s.signer == my_address) {
+                        tx_count += 1;
+
+                        let kind = match settle_tx.outcome {
+                            EscrowOutcome::Release => "Released",
+                            EscrowOutcome::Refund => "Refunded",
+                        };
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "Escrow Settle".to_string(),
+                            direction: format!("🔓 {}", kind),
+                            details: hex::encode(&settle_tx.input_hash[..8]),
+                            timestamp: *timestamp,
+                            color: TableColor::Green,
+                        });
+                    }
+                }
+                Transaction::Offer(offer_tx) => {
+                    offer_sellers.insert(offer_tx.hash(), offer_tx.seller.clone());
+
+                    if offer_tx.seller == my_address {
+                        tx_count += 1;
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "Offer".to_string(),
+                            direction: "🏷️  Listed".to_string(),
+                            details: format!("{} | Price: {:.6}", hex::encode(&offer_tx.input_hash[..8]), offer_tx.price_area),
+                            timestamp: *timestamp,
+                            color: TableColor::Yellow,
+                        });
+                    }
+                }
+                Transaction::Accept(accept_tx) => {
+                    let is_seller = offer_sellers.get(&accept_tx.offer_id).is_some_and(|seller| *seller == my_address);
+
+                    if accept_tx.buyer == my_address || is_seller {
+                        tx_count += 1;
+
+                        let (direction, color) = if is_seller {
+                            sent_count += 1;
+                            ("📤 Sold".to_string(), TableColor::Red)
+                        } else {
+                            received_count += 1;
+                            ("📥 Bought".to_string(), TableColor::Green)
+                        };
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "Accept".to_string(),
+                            direction,
+                            details: format!(
+                                "{} | {} payment triangles",
+                                hex::encode(&accept_tx.input_hash[..8]),
+                                accept_tx.payment.len()
+                            ),
+                            timestamp: *timestamp,
+                            color,
+                        });
+                    }
+                }
+                Transaction::Inscription(inscription_tx) => {
+                    if inscription_tx.owner == my_address {
+                        tx_count += 1;
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "Inscription".to_string(),
+                            direction: "✏️  Inscribed".to_string(),
+                            details: format!(
+                                "{} | {} bytes",
+                                hex::encode(&inscription_tx.input_hash[..8]),
+                                inscription_tx.data.len()
+                            ),
+                            timestamp: *timestamp,
+                            color: TableColor::Magenta,
+                        });
+                    }
+                }
                 Transaction::Coinbase(coinbase_tx) => {
-                    if coinbase_tx.beneficiary_address == my_address {
+                    let reward = coinbase_tx.reward_to(my_address);
+                    if reward > 0 {
                         tx_count += 1;
                         received_count += 1;
                         mining_count += 1;
 
                         transactions.push(TxRecord {
-                            block_height: block.header.height,
+                            block_height: *height,
                             tx_type: "Mining".to_string(),
                             direction: "⛏️  Reward".to_string(),
-                            details: format!("Area: {}", coinbase_tx.reward_area),
-                            timestamp: block.header.timestamp,
+                            details: format!("Area: {}", reward),
+                            timestamp: *timestamp,
                             color: TableColor::Cyan,
                         });
                     }
@@ -167,11 +410,76 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         };
 
                         transactions.push(TxRecord {
-                            block_height: block.header.height,
+                            block_height: *height,
                             tx_type: "Subdivision".to_string(),
                             direction: "✂️  Split".to_string(),
                             details: format!("{} → {} children", hash_short, sub_tx.children.len()),
-                            timestamp: block.header.timestamp,
+                            timestamp: *timestamp,
+                            color: TableColor::Magenta,
+                        });
+                    }
+                }
+                Transaction::Swap(swap_tx) => {
+                    let gave = swap_tx.inputs.iter().filter(|i| i.owner == my_address).count();
+                    let got = swap_tx.outputs.iter().filter(|o| o.new_owner == my_address).count();
+
+                    if gave > 0 || got > 0 {
+                        tx_count += 1;
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "Swap".to_string(),
+                            direction: "🔄 Swapped".to_string(),
+                            details: format!("Gave {} | Got {} | {} parties", gave, got, swap_tx.inputs.len()),
+                            timestamp: *timestamp,
+                            color: TableColor::Yellow,
+                        });
+                    }
+                }
+                Transaction::SplitTransfer(split_tx) => {
+                    let is_sender = split_tx.sender == my_address;
+                    let is_receiver = split_tx.recipient == my_address;
+
+                    if is_sender || is_receiver {
+                        tx_count += 1;
+
+                        let leaves = format!(
+                            "{} of {} leaves",
+                            split_tx.recipient_leaves.len(),
+                            3usize.pow(split_tx.depth as u32)
+                        );
+                        let (direction, color, details) = if is_sender {
+                            sent_count += 1;
+                            ("📤 Sent".to_string(), TableColor::Red, format!("{} | To: {}", leaves, split_tx.recipient))
+                        } else {
+                            received_count += 1;
+                            ("📥 Received".to_string(), TableColor::Green, format!("{} | From: {}", leaves, split_tx.sender))
+                        };
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "Split Transfer".to_string(),
+                            direction,
+                            details,
+                            timestamp: *timestamp,
+                            color,
+                        });
+                    }
+                }
+                Transaction::DeepSubdivision(deep_tx) => {
+                    if deep_tx.owner_address == my_address {
+                        tx_count += 1;
+
+                        transactions.push(TxRecord {
+                            block_height: *height,
+                            tx_type: "Deep Subdivision".to_string(),
+                            direction: "✂️  Split".to_string(),
+                            details: format!(
+                                "{}... → {} leaves",
+                                &hex::encode(deep_tx.parent_hash)[..13],
+                                3usize.pow(deep_tx.depth as u32)
+                            ),
+                            timestamp: *timestamp,
                             color: TableColor::Magenta,
                         });
                     }
@@ -1,15 +1,19 @@
 //! Mine a new block by subdividing a triangle
 
+use siertrichain::blockassembler::BlockTemplate;
+use siertrichain::config::Config;
 use siertrichain::persistence::Database;
-use siertrichain::transaction::{Transaction, SubdivisionTx, CoinbaseTx};
+use siertrichain::transaction::{Transaction, SubdivisionTx};
 use siertrichain::crypto::KeyPair;
 use siertrichain::miner::mine_block;
+use std::sync::atomic::AtomicBool;
 use secp256k1::SecretKey;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("⛏️  Mining Block...\n");
 
-    let db = Database::open("siertrichain.db")?;
+    let config = Config::from_args(&mut std::env::args().collect())?;
+    let db = Database::open_in(&config)?;
     let mut chain = db.load_blockchain()?;
 
     let current_height = chain.blocks.last()
@@ -43,31 +47,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("🔺 Subdividing triangle {}...", hash_prefix);
     let children = parent_triangle.subdivide();
 
-    let mut tx = SubdivisionTx::new(parent_hash, children.to_vec(), address.clone(), 0, chain.blocks.len() as u64);
+    let mut tx = SubdivisionTx::new(parent_hash, children.to_vec(), address.clone(), 0, chain.next_nonce(&address));
     let message = tx.signable_message();
     let signature = keypair.sign(&message)?;
     let public_key = keypair.public_key.serialize().to_vec();
     tx.sign(signature, public_key);
 
-    let coinbase = CoinbaseTx { reward_area: 1000, beneficiary_address: address };
+    // Through the mempool, so the assembler checks it against everything else
+    // pending and pays its fee into the coinbase
+    chain.submit_transaction(Transaction::Subdivision(tx))?;
 
-    let transactions = vec![
-        Transaction::Coinbase(coinbase),
-        Transaction::Subdivision(tx),
-    ];
+    println!("⛏️  Mining block (difficulty {:.2})...", chain.difficulty());
 
-    println!("⛏️  Mining block (difficulty {})...", chain.difficulty);
-
-    let last_block = chain.blocks.last()
-        .ok_or("Blockchain is empty")?;
-    let mut new_block = siertrichain::blockchain::Block::new(
-        last_block.header.height + 1,
-        last_block.hash,
-        chain.difficulty,
-        transactions,
-    );
-
-    new_block = mine_block(new_block)?;
+    let template = BlockTemplate::build(&chain, &address);
+    let new_block = mine_block(template.block, &AtomicBool::new(false))?;
 
     let new_hash_hex = hex::encode(new_block.hash);
     let new_hash_prefix = &new_hash_hex[..16];
@@ -75,8 +68,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     chain.apply_block(new_block.clone())?;
 
-    db.save_block(&new_block)?;
-    db.save_utxo_set(&chain.state)?;
+    db.save_blockchain_state(&new_block, &mut chain.state, chain.bits)?;
 
     println!("\n🎉 Block {} mined successfully!", chain.blocks.len() - 1);
     println!("   UTXOs: {}", chain.state.count());
@@ -1,10 +1,16 @@
 //! Miner CLI for siertrichain - Beautiful edition!
 
-use siertrichain::blockchain::{Blockchain, Block};
+use siertrichain::blockchain::Blockchain;
+use siertrichain::blockassembler::BlockTemplate;
+use siertrichain::config::Config;
 use siertrichain::persistence::Database;
-use siertrichain::network::NetworkNode;
-use siertrichain::transaction::{Transaction, CoinbaseTx};
+use siertrichain::error::ChainError;
+use siertrichain::miner::{mine_block_threaded, HashrateTracker, MiningConfig};
+use siertrichain::network::{NetworkNode, Node};
+use siertrichain::security::SecurityManager;
 use std::env;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 use tokio::time::sleep;
 use colored::*;
@@ -29,7 +35,7 @@ fn format_number(num: u64) -> String {
     let chars: Vec<char> = num_str.chars().collect();
 
     for (i, &ch) in chars.iter().enumerate() {
-        if i > 0 && (chars.len() - i) % 3 == 0 {
+        if i > 0 && (chars.len() - i).is_multiple_of(3) {
             result.push(',');
         }
         result.push(ch);
@@ -48,26 +54,54 @@ async fn main() {
         println!("{}", "╠══════════════════════════════════════════════════════════╣".bright_yellow());
         println!("{}", "║  Usage:                                                  ║".bright_yellow());
         println!("{}", "║    miner <beneficiary_address> [--peer <host:port>]      ║".white());
+        println!("{}", "║          [--threads <n>] [--duty-cycle <percent>]        ║".white());
+        println!("{}", "║          [--datadir <dir>]                               ║".white());
+        println!("{}", "║                                                          ║".bright_yellow());
+        println!("{}", "║  --duty-cycle hashes for that share of each second and   ║".bright_yellow());
+        println!("{}", "║  sleeps for the rest, e.g. 20 to spare a laptop          ║".bright_yellow());
         println!("{}", "║                                                          ║".bright_yellow());
         println!("{}", "║  Example:                                                ║".bright_yellow());
         println!("{}", "║    miner abc123...                                       ║".white());
-        println!("{}", "║    miner abc123... --peer 192.168.1.10:8333             ║".white());
+        println!("{}", "║    miner abc123... --peer 192.168.1.10:8333              ║".white());
+        println!("{}", "║    miner abc123... --threads 2 --duty-cycle 20           ║".white());
         println!("{}", "╚══════════════════════════════════════════════════════════╝".bright_yellow());
         println!();
         return;
     }
     let beneficiary_address = args[1].clone();
 
+    let mut peer = None;
+    let mut datadir = Config::from_env();
+    let defaults = MiningConfig::default();
+    let (mut threads, mut duty_cycle) = (defaults.threads, defaults.duty_cycle);
+    let mut flags = args[2..].iter();
+    while let Some(flag) = flags.next() {
+        match (flag.as_str(), flags.next()) {
+            ("--peer", Some(addr)) => peer = Some(addr.clone()),
+            ("--threads", Some(n)) => threads = n.parse().expect("Invalid thread count"),
+            ("--duty-cycle", Some(percent)) => duty_cycle = percent.parse().expect("Invalid duty cycle"),
+            ("--datadir", Some(dir)) => datadir = datadir.with_datadir(dir),
+            _ => {
+                eprintln!("{}", format!("❌ Unknown or incomplete option: {}", flag).red());
+                std::process::exit(1);
+            }
+        }
+    }
+    let config = MiningConfig::new(threads, duty_cycle).unwrap_or_else(|e| {
+        eprintln!("{}", format!("❌ {}", e).red());
+        std::process::exit(1);
+    });
+
     println!("{}", LOGO.bright_yellow());
     println!("{}", "┌─────────────────────────────────────────────────────────────┐".bright_green());
     println!("{}", "│                   ⛏️  STARTING MINER                        │".bright_green().bold());
     println!("{}", "└─────────────────────────────────────────────────────────────┘".bright_green());
     println!();
     
-    let db = Database::open("siertrichain.db").expect("Failed to open database");
-    let mut chain = db.load_blockchain().unwrap_or_else(|_| {
+    let db = Database::open_in(&datadir).expect("Failed to open database");
+    let chain = db.load_blockchain().unwrap_or_else(|_| {
         println!("{}", "⚠️  No blockchain found, creating genesis...".yellow());
-        Blockchain::new()
+        Blockchain::with_params(&datadir.params)
     });
 
     let beneficiary_display = if beneficiary_address.len() > 20 {
@@ -81,29 +115,44 @@ async fn main() {
     println!("{}", "╠══════════════════════════════════════════════════════════╣".cyan());
     println!("{}", format!("║  👤 Beneficiary: {:<40} ║", beneficiary_display).cyan());
     println!("{}", format!("║  💰 Reward: {:<45} ║", "1000 area").cyan());
+    println!("{}", format!("║  🧵 Threads: {:<44} ║", config.threads).cyan());
+    println!("{}", format!("║  🔋 Duty Cycle: {:<41} ║", format!("{}%", config.duty_cycle)).cyan());
     println!("{}", "╚══════════════════════════════════════════════════════════╝".cyan());
     println!();
 
-    let network_node = NetworkNode::new(chain.clone(), "siertrichain.db".to_string());
+    // Blocks from peers arrive on the node's chain, which saves them to the database
+    let network_node = NetworkNode::new(chain.clone(), datadir.db_path().display().to_string())
+        .with_database(Arc::new(Mutex::new(Database::open_in(&datadir).expect("Failed to open database"))))
+        .with_security(SecurityManager::from_env().expect("Failed to create node key"));
+
+    if let Some(peer_addr) = &peer {
+        let peer = Node::parse(peer_addr).expect("Invalid peer address");
+        println!("{}", format!("🔗 Connecting to peer {}...", peer.addr()).bright_blue());
+        if let Err(e) = network_node.connect_peer(peer.host, peer.port).await {
+            eprintln!("{}", format!("❌ Failed to connect to peer: {}", e).red());
+        } else {
+            println!("{}", "✅ Connected to peer successfully!".green());
+        }
+        println!();
+    }
 
-    if args.len() >= 4 && args[2] == "--peer" {
-        let peer_addr = &args[3];
-        let parts: Vec<&str> = peer_addr.split(':').collect();
-        if parts.len() == 2 {
-            let peer_host = parts[0].to_string();
-            let peer_port: u16 = parts[1].parse().expect("Invalid peer port");
+    // The node says when a peer relays a block, so the miner never rereads the chain
+    let mut tips = network_node.subscribe_tip().await;
 
-            println!("{}", format!("🔗 Connecting to peer {}:{}...", peer_host, peer_port).bright_blue());
-            if let Err(e) = network_node.connect_peer(peer_host, peer_port).await {
-                eprintln!("{}", format!("❌ Failed to connect to peer: {}", e).red());
-            } else {
-                println!("{}", "✅ Connected to peer successfully!".green());
+    // Ctrl-C interrupts the block being hashed rather than killing the process mid-save
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                stop.store(true, Ordering::Relaxed);
             }
-            println!();
-        }
+        });
     }
 
     let mut blocks_mined = 0;
+    // Hashes across every template, including ones abandoned for a new tip
+    let tracker = Arc::new(Mutex::new(HashrateTracker::new(config.threads)));
     let start_time = Instant::now();
 
     println!("{}", "╔══════════════════════════════════════════════════════════╗".bright_green());
@@ -111,36 +160,19 @@ async fn main() {
     println!("{}", "╚══════════════════════════════════════════════════════════╝".bright_green());
     println!();
 
-    loop {
-        // Reload blockchain from database before each mining round
-        // This ensures we're mining on the latest chain, including blocks from peers
-        chain = db.load_blockchain().unwrap_or_else(|_| {
-            eprintln!("⚠️  Failed to reload blockchain, using current chain");
-            chain
-        });
+    'mining: loop {
+        // Mine on the node's chain, which includes blocks relayed by peers.
+        // Tips up to here are in it; any later one abandons the template.
+        tips.borrow_and_update();
+        let mut chain = network_node.blockchain().read().await.clone();
 
-        let last_block = chain.blocks.last().unwrap();
-        let new_height = last_block.header.height + 1;
-        let difficulty = chain.difficulty;
+        let difficulty = chain.difficulty();
 
-        let coinbase_tx = Transaction::Coinbase(CoinbaseTx {
-            reward_area: 1000,
-            beneficiary_address: beneficiary_address.clone(),
-        });
-
-        let mut new_block = Block::new(
-            new_height,
-            last_block.hash,
-            difficulty,
-            vec![coinbase_tx],
-        );
+        let template = BlockTemplate::build(&chain, &beneficiary_address).block;
+        let new_height = template.header.height;
+        let parent_hash = template.header.previous_hash;
 
-        // Ensure timestamp is greater than parent to avoid validation errors
-        if new_block.header.timestamp <= last_block.header.timestamp {
-            new_block.header.timestamp = last_block.header.timestamp + 1;
-        }
-
-        println!("{}", format!("⛏️  Mining block #{} (difficulty: {})...", new_height, difficulty).bright_yellow());
+        println!("{}", format!("⛏️  Mining block #{} (difficulty: {:.2})...", new_height, difficulty).bright_yellow());
 
         let pb = ProgressBar::new_spinner();
         pb.set_style(
@@ -152,36 +184,67 @@ async fn main() {
         pb.enable_steady_tick(Duration::from_millis(100));
 
         let mine_start = Instant::now();
-        let mut hash_count = 0u64;
-
-        loop {
-            new_block.hash = new_block.calculate_hash();
-            hash_count += 1;
-
-            if hash_count % 10000 == 0 {
-                let elapsed = mine_start.elapsed().as_secs_f64();
-                let hashrate = if elapsed > 0.0 { hash_count as f64 / elapsed } else { 0.0 };
-                pb.set_message(format!("Hashing... {} attempts ({:.0} H/s)", hash_count, hashrate));
+        let hashes_before = tracker.lock().unwrap().stats().hashes;
+        // Set to give up on this template for a newer tip
+        let abandon = Arc::new(AtomicBool::new(false));
+        let mut mining = {
+            let (tracker, stop, abandon, pow) = (tracker.clone(), stop.clone(), abandon.clone(), chain.pow.clone());
+            let reported = Mutex::new(vec![0u64; config.threads]);
+            tokio::task::spawn_blocking(move || mine_block_threaded(template, &config, &*pow, |thread, hashes| {
+                let mut reported = reported.lock().unwrap();
+                tracker.lock().unwrap().record(thread, hashes - reported[thread]);
+                reported[thread] = hashes;
+                stop.load(Ordering::Relaxed) || abandon.load(Ordering::Relaxed)
+            }))
+        };
+        let mut progress = tokio::time::interval(Duration::from_millis(500));
+
+        let new_block = loop {
+            tokio::select! {
+                result = &mut mining => match result.expect("mining task panicked") {
+                    Ok(block) => break block,
+                    Err(ChainError::MiningCancelled) if stop.load(Ordering::Relaxed) => {
+                        pb.finish_and_clear();
+                        break 'mining;
+                    }
+                    Err(ChainError::MiningCancelled) => {
+                        pb.finish_and_clear();
+                        println!("{}", "🔄 New chain tip, rebuilding block template".bright_blue());
+                        continue 'mining;
+                    }
+                    Err(e) => {
+                        pb.finish_and_clear();
+                        eprintln!("{}", format!("❌ Mining failed: {}", e).red());
+                        sleep(Duration::from_secs(10)).await;
+                        continue 'mining;
+                    }
+                },
+                Ok(()) = tips.changed() => {
+                    if tips.borrow_and_update().hash != parent_hash {
+                        abandon.store(true, Ordering::Relaxed);
+                    }
+                }
+                _ = progress.tick() => {
+                    let stats = tracker.lock().unwrap().stats();
+                    pb.set_message(format!("Hashing... {} attempts ({:.0} H/s)", stats.hashes - hashes_before, stats.hashrate));
+                }
             }
-
-            if new_block.verify_proof_of_work() {
-                pb.finish_and_clear();
-                let mine_duration = mine_start.elapsed();
-                let hash_hex = hex::encode(new_block.hash);
-                let hash_display = format!("{}...{}", &hash_hex[..10], &hash_hex[hash_hex.len()-10..]);
-
-                println!("{}", "┌─────────────────────────────────────────────────────────────┐".green());
-                println!("{}", format!("│ ✨ BLOCK FOUND! #{:<45} │", new_height).green().bold());
-                println!("{}", "├─────────────────────────────────────────────────────────────┤".green());
-                println!("{}", format!("│ Hash: {:<52} │", hash_display).green());
-                println!("{}", format!("│ Attempts: {:<48} │", hash_count).green());
-                println!("{}", format!("│ Time: {:.2}s{:<47} │", mine_duration.as_secs_f64(), "").green());
-                println!("{}", format!("│ Avg Hashrate: {:.0} H/s{:<36} │", hash_count as f64 / mine_duration.as_secs_f64(), "").green());
-                println!("{}", "└─────────────────────────────────────────────────────────────┘".green());
-                break;
-            }
-            new_block.header.nonce += 1;
-        }
+        };
+
+        pb.finish_and_clear();
+        let mine_duration = mine_start.elapsed();
+        let hash_count = tracker.lock().unwrap().stats().hashes - hashes_before;
+        let hash_hex = hex::encode(new_block.hash);
+        let hash_display = format!("{}...{}", &hash_hex[..10], &hash_hex[hash_hex.len()-10..]);
+
+        println!("{}", "┌─────────────────────────────────────────────────────────────┐".green());
+        println!("{}", format!("│ ✨ BLOCK FOUND! #{:<45} │", new_height).green().bold());
+        println!("{}", "├─────────────────────────────────────────────────────────────┤".green());
+        println!("{}", format!("│ Hash: {:<52} │", hash_display).green());
+        println!("{}", format!("│ Attempts: {:<48} │", hash_count).green());
+        println!("{}", format!("│ Time: {:.2}s{:<47} │", mine_duration.as_secs_f64(), "").green());
+        println!("{}", format!("│ Avg Hashrate: {:.0} H/s{:<36} │", hash_count as f64 / mine_duration.as_secs_f64(), "").green());
+        println!("{}", "└─────────────────────────────────────────────────────────────┘".green());
 
         if let Err(e) = chain.apply_block(new_block.clone()) {
             eprintln!("{}", format!("❌ Failed to apply new block: {}", e).red());
@@ -190,9 +253,15 @@ async fn main() {
         }
 
         // Use atomic save to ensure database consistency
-        db.save_blockchain_state(&new_block, &chain.state, chain.difficulty)
+        db.save_blockchain_state(&new_block, &mut chain.state, chain.bits)
             .expect("Failed to save blockchain state");
+        db.save_prune_state(&chain)
+            .expect("Failed to save prune state");
 
+        // Connecting it on the node announces it to connected peers
+        if let Err(e) = network_node.blockchain().write().await.apply_block(new_block.clone()) {
+            eprintln!("{}", format!("⚠️  Node could not connect the block: {}", e).yellow());
+        }
         if let Err(e) = network_node.broadcast_block(&new_block).await {
             eprintln!("{}", format!("⚠️  Failed to broadcast block: {}", e).yellow());
         } else {
@@ -205,8 +274,8 @@ async fn main() {
 
         // Calculate supply statistics
         let current_height = chain.blocks.last().unwrap().header.height;
-        let current_supply = Blockchain::calculate_current_supply(current_height);
-        let supply_pct = (current_supply as f64 / siertrichain::blockchain::MAX_SUPPLY as f64) * 100.0;
+        let current_supply = chain.supply_at_tip;
+        let supply_pct = chain.supply_percentage();
         let current_reward = Blockchain::calculate_block_reward(current_height);
         let halving_era = current_height / 210_000;
         let blocks_to_halving = ((halving_era + 1) * 210_000).saturating_sub(current_height);
@@ -219,7 +288,10 @@ async fn main() {
         println!("{}", format!("║ 🏔️  Chain Height: {:<39} ║", current_height).cyan());
         println!("{}", format!("║ ⏱️  Uptime: {:.0}m {:.0}s{:<38} ║", elapsed.as_secs() / 60, elapsed.as_secs() % 60, "").cyan());
         println!("{}", format!("║ ⚡ Avg Block Time: {:.1}s{:<34} ║", avg_block_time, "").cyan());
-        println!("{}", format!("║ 🎯 Difficulty: {:<41} ║", chain.difficulty).cyan());
+        let stats = tracker.lock().unwrap().stats();
+        println!("{}", format!("║ 🔢 Hashrate: {:.0} H/s{:<38} ║", stats.hashrate, "").cyan());
+        println!("{}", format!("║ #️⃣  Total Hashes: {:<38} ║", format_number(stats.hashes)).cyan());
+        println!("{}", format!("║ 🎯 Difficulty: {:<41.2} ║", chain.difficulty()).cyan());
         println!("{}", format!("║ 💎 Current Reward: {:<35} ║", current_reward).cyan());
         println!("{}", format!("║ 🪙  Total Earned: {:<37.1} ║", blocks_mined as f64 * 1000.0).cyan());
         println!("{}", format!("║ 📈 Total Supply: {:>10} / {} ({:.3}%){:<6} ║",
@@ -231,4 +303,6 @@ async fn main() {
         println!("{}", "╚══════════════════════════════════════════════════════════╝".bright_cyan());
         println!();
     }
-}
\ No newline at end of file
+
+    println!("{}", format!("🛑 Mining stopped after {} blocks", blocks_mined).bright_yellow());
+}
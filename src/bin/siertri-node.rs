@@ -1,59 +1,180 @@
 //! Network node for siertrichain
 
 use siertrichain::blockchain::Blockchain;
-use siertrichain::persistence::Database;
-use siertrichain::network::NetworkNode;
+use siertrichain::config::{Config, DATADIR_ENV};
+use siertrichain::persistence::{BackupSchedule, Database, DEFAULT_BACKUP_RETENTION};
+use siertrichain::connmgr::{ConnectionManager, DEFAULT_OUTBOUND_PEERS};
+use siertrichain::network::{NetworkNode, Node, DEFAULT_MAX_INBOUND_PEERS};
+use siertrichain::peerstore::get_peers_path;
+use siertrichain::security::SecurityManager;
 use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let config = match Config::from_args(&mut args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            return;
+        }
+    };
     
     if args.len() < 2 {
         print_usage();
         return;
     }
+    if args[1] == "backup" {
+        if let Err(e) = backup(&config, args.get(2)) {
+            eprintln!("❌ Backup failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     
     let port: u16 = args[1].parse().expect("Invalid port number");
-    let db_path = "siertrichain.db".to_string();
+    let (mut peer, mut listen, mut external) = (None, Vec::new(), Vec::new());
+    let mut prune = None;
+    let mut backups = BackupSchedule::new(config.backup_dir());
+    let mut back_up = false;
+    let mut flags = args[2..].iter();
+    while let Some(flag) = flags.next() {
+        match (flag.as_str(), flags.next()) {
+            ("--peer", Some(addr)) => peer = Some(Node::parse(addr).expect("Invalid peer address")),
+            ("--listen", Some(addr)) => listen.push(addr.parse::<SocketAddr>().expect("Invalid listen address")),
+            ("--external", Some(addr)) => external.push(Node::parse(addr).expect("Invalid external address")),
+            ("--prune", Some(depth)) => prune = Some(depth.parse::<u64>().expect("Invalid prune depth")),
+            ("--backup-interval", Some(hours)) => {
+                let hours: f64 = hours.parse().expect("Invalid backup interval");
+                backups = backups.with_interval(Duration::from_secs_f64(hours * 3600.0));
+                back_up = true;
+            }
+            ("--backup-retention", Some(count)) => {
+                backups = backups.with_retention(count.parse().expect("Invalid backup retention"));
+            }
+            _ => {
+                print_usage();
+                return;
+            }
+        }
+    }
+    let db_path = config.db_path().display().to_string();
     
     println!("🔺 siertri-node v0.1.0");
     println!("   Starting on port {}...\n", port);
     
-    let db = Database::open(&db_path).expect("Failed to open database");
+    let db = Database::open_in(&config).expect("Failed to open database");
+    if let Some(depth) = prune {
+        let pruned = db.prune(depth).expect("Failed to prune the database");
+        println!("✂️  Keeping transactions of the last {} blocks; pruned {} more", depth, pruned);
+    }
     let blockchain = db.load_blockchain().unwrap_or_else(|_| {
         println!("⚠️  No blockchain found, creating genesis...");
-        Blockchain::new()
+        Blockchain::with_params(&config.params)
     });
     
     println!("📊 Current height: {}", blockchain.blocks.last().unwrap().header.height);
     println!("💾 UTXO count: {}\n", blockchain.state.count());
     
-    let node = NetworkNode::new(blockchain, db_path);
-    
-    if args.len() >= 4 && args[2] == "--peer" {
-        let peer_addr = &args[3];
-        let parts: Vec<&str> = peer_addr.split(':').collect();
-        if parts.len() == 2 {
-            let peer_host = parts[0].to_string();
-            let peer_port: u16 = parts[1].parse().expect("Invalid peer port");
-            
-            println!("🔗 Connecting to peer {}:{}...", peer_host, peer_port);
-            if let Err(e) = node.connect_peer(peer_host, peer_port).await {
-                eprintln!("❌ Failed to connect to peer: {}", e);
-            }
+    let max_inbound = env_limit("SIERTRI_MAX_INBOUND", DEFAULT_MAX_INBOUND_PEERS);
+    let max_outbound = env_limit("SIERTRI_MAX_OUTBOUND", DEFAULT_OUTBOUND_PEERS);
+    println!("🔌 Peer limits: {} inbound, {} outbound", max_inbound, max_outbound);
+
+    let mut node = NetworkNode::new(blockchain, db_path)
+        .with_max_inbound(max_inbound)
+        .with_params(config.params.clone())
+        .with_database(Arc::new(Mutex::new(db)))
+        .with_peers_file(get_peers_path())
+        .expect("Failed to load peers file")
+        .with_security(SecurityManager::from_env().expect("Failed to create node key"));
+    for addr in external {
+        node = node.with_external_address(addr);
+    }
+    println!("🔐 Peer authentication: {}", if node.requires_auth() { "ENABLED" } else { "DISABLED" });
+    if let Some(key) = node.public_key() {
+        println!("🔑 Node key: {}", hex::encode(key));
+    }
+    if let Some(proxy) = node.socks5_proxy() {
+        println!("🧅 Dialing peers through SOCKS5 proxy {}", proxy);
+    }
+    if let Err(e) = node.save_proxy_status() {
+        eprintln!("❌ Failed to save proxy status: {}", e);
+    }
+    
+    if let Some(peer) = peer {
+        println!("🔗 Connecting to peer {}...", peer.addr());
+        node.add_peer(peer.clone()).await;
+        if let Err(e) = node.connect_peer(peer.host, peer.port).await {
+            eprintln!("❌ Failed to connect to peer, will keep retrying: {}", e);
         }
     }
+
+    if back_up {
+        println!("💾 Backing up the database to {} every {:.1} hours, keeping {}",
+                 backups.dir.display(), backups.interval.as_secs_f64() / 3600.0, backups.retention);
+        tokio::spawn(backups.run(config.db_path()));
+    }
+
+    // Keep connected to peers from previous runs, those they tell us about,
+    // or the seed nodes, reconnecting as peers drop
+    tokio::spawn(ConnectionManager::new(node.clone(), config.params.clone(), max_outbound).run());
     
     println!("🌐 Ready to accept connections!\n");
-    if let Err(e) = node.start_server(port).await {
+    let served = if listen.is_empty() { node.start_server(port).await } else { node.listen(&listen).await };
+    if let Err(e) = served {
         eprintln!("❌ Server error: {}", e);
     }
 }
 
+/// A connection limit from the environment variable `name`, or `default`
+fn env_limit(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("Invalid {}: {}", name, value)),
+        Err(_) => default,
+    }
+}
+
+/// Back up the database to `file`, or into the backup directory, keeping the
+/// newest backups there
+fn backup(config: &Config, file: Option<&String>) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open_read_only_in(config)?;
+    let path = match file {
+        Some(file) => {
+            db.backup_to(file)?;
+            file.into()
+        }
+        None => BackupSchedule::new(config.backup_dir()).run_once(&db)?,
+    };
+    println!("💾 Backed up the database to {}", path.display());
+    Ok(())
+}
+
 fn print_usage() {
-    println!("Usage: siertri-node <port> [--peer <host:port>]");
+    println!("Usage: siertri-node <port> [--peer <host:port>] [--listen <ip:port>]... [--external <host:port>]...");
+    println!("                    [--datadir <dir>] [--regtest] [--prune <depth>]");
+    println!("                    [--backup-interval <hours>] [--backup-retention <count>]");
+    println!("       siertri-node backup [<file>] [--datadir <dir>] [--regtest]");
+    println!("\nListens on <port> over IPv4 and IPv6, or on each --listen address instead;");
+    println!("IPv6 addresses go in brackets, e.g. [::]:8333. Peers are told this node can");
+    println!("be reached at each --external address and each routable address it listens on.");
+    println!("With --prune, only the last <depth> blocks keep their transactions, from then on.");
+    println!("With --backup-interval, the database is backed up that often into the backups");
+    println!("directory beside it, keeping the newest {} unless --backup-retention says.", DEFAULT_BACKUP_RETENTION);
+    println!("`backup` takes one backup now, into <file> or the backups directory.");
+    println!("\nStays connected to up to {} peers: the one given, peers saved from", DEFAULT_OUTBOUND_PEERS);
+    println!("earlier runs, the peers they know of, or the seed nodes. Accepts up to");
+    println!("{} inbound peers, evicting the least valuable to make room for new ones.", DEFAULT_MAX_INBOUND_PEERS);
+    println!("\nEnvironment:");
+    println!("  SIERTRI_MAX_INBOUND   inbound connection limit");
+    println!("  SIERTRI_MAX_OUTBOUND  outbound connections to keep");
+    println!("  SIERTRI_NODE_KEY      hex secret key peers know this node by");
+    println!("  SIERTRI_TRUSTED_PEERS comma-separated hex keys of peers that may authenticate");
+    println!("  {}       data directory, if --datadir isn't given", DATADIR_ENV);
     println!("\nExamples:");
     println!("  siertri-node 8333");
     println!("  siertri-node 8334 --peer 192.168.1.100:8333");
+    println!("  siertri-node 8333 --listen 0.0.0.0:8333 --listen [2001:db8::1]:8333");
 }
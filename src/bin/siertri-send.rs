@@ -1,9 +1,11 @@
 //! Send triangles to another address - Beautiful edition!
+//!
+//! Submits the transfer to the node (see `siertrid`) at `SIERTRI_RPC_URL`, by
+//! default http://127.0.0.1:3000, which relays it to its peers.
 
-use siertrichain::persistence::Database;
 use siertrichain::transaction::{Transaction, TransferTx};
 use siertrichain::crypto::KeyPair;
-use siertrichain::network::NetworkNode;
+use siertrichain::rpc::RpcClient;
 use secp256k1::SecretKey;
 use std::env;
 use colored::*;
@@ -33,11 +35,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("{}", "╠══════════════════════════════════════════════════════════╣".bright_yellow());
         println!("{}", "║                                                          ║".bright_yellow());
         println!("{}", "║  Usage:                                                  ║".bright_yellow());
-        println!("{}", "║    send <to_address> <triangle_hash> [memo]              ║".white());
+        println!("{}", "║    send <to_address> <triangle_hash|path> [memo]         ║".white());
         println!("{}", "║                                                          ║".bright_yellow());
         println!("{}", "║  Examples:                                               ║".bright_yellow());
         println!("{}", "║    send abc123... def456...                              ║".white());
         println!("{}", "║    send abc123... def456... \"Payment for services\"      ║".white());
+        println!("{}", "║    send abc123... Δ/0/2/1                                ║".white());
         println!("{}", "║                                                          ║".bright_yellow());
         println!("{}", "╚══════════════════════════════════════════════════════════╝".bright_yellow());
         println!();
@@ -85,20 +88,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let secret_key = SecretKey::from_slice(&secret_bytes)?;
     let keypair = KeyPair::from_secret_key(secret_key);
 
-    pb.set_message("Loading blockchain...");
-
-    let db = Database::open("siertrichain.db")?;
-    let mut chain = db.load_blockchain()?;
-
     pb.set_message("Looking up triangle...");
 
-    let full_hash = *chain.state.utxo_set.keys()
-        .find(|h| hex::encode(h).starts_with(triangle_hash))
-        .ok_or_else(|| format!("Triangle with hash prefix {} not found", triangle_hash))?;
-
-    let triangle = chain.state.utxo_set.get(&full_hash)
-        .ok_or("Triangle not found in UTXO set")?
-        .clone();
+    let rpc = RpcClient::from_env();
+    let (full_hash, area) = rpc.resolve(&from_address, triangle_hash).await?;
 
     pb.finish_and_clear();
 
@@ -123,7 +116,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("{}", "║              🔍 TRANSACTION DETAILS                      ║".bright_cyan().bold());
     println!("{}", "╠══════════════════════════════════════════════════════════╣".bright_cyan());
     println!("{}", format!("║  🔺 Triangle: {:<42} ║", full_hash_display).cyan());
-    println!("{}", format!("║  📐 Area: {:<47.6} ║", triangle.area()).cyan());
+    println!("{}", format!("║  📐 Area: {:<47.6} ║", area).cyan());
     println!("{}", format!("║  👤 From: {:<47} ║", from_display).cyan());
     println!("{}", format!("║  🎯 To: {:<49} ║", to_display).cyan());
     if let Some(ref m) = memo {
@@ -148,7 +141,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     pb.set_message("Creating transaction...");
 
-    let mut tx = TransferTx::new(full_hash, to_address.to_string(), from_address.clone(), 0, chain.blocks.len() as u64);
+    let nonce = rpc.next_nonce(&from_address).await?;
+    let mut tx = TransferTx::new(full_hash, to_address.to_string(), from_address.clone(), 0, nonce);
 
     if let Some(m) = memo {
         tx = tx.with_memo(m)?;
@@ -162,12 +156,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tx.sign(signature, public_key);
 
     let transaction = Transaction::Transfer(tx);
-    chain.mempool.add_transaction(transaction.clone())?;
 
-    pb.set_message("Broadcasting to network...");
+    pb.set_message("Submitting to the node...");
 
-    let network_node = NetworkNode::new(chain, "siertrichain.db".to_string());
-    network_node.broadcast_transaction(&transaction).await?;
+    let response = rpc.submit_transaction(&transaction).await?;
+    if response.status != "accepted" && response.status != "replaced" {
+        pb.finish_and_clear();
+        let reason = response.error.or(response.reason).unwrap_or(response.status);
+        return Err(format!("Node rejected the transaction: {}", reason).into());
+    }
 
     pb.finish_and_clear();
 
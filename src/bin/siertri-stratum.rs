@@ -0,0 +1,83 @@
+//! Remote mining for siertrichain: serve stratum jobs from this node's chain,
+//! or work for a pool running elsewhere
+
+use siertrichain::blockchain::Blockchain;
+use siertrichain::config::Config;
+use siertrichain::persistence::Database;
+use siertrichain::stratum::{self, Response, StratumServer};
+use colored::*;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+fn print_usage() {
+    println!("{}", "Usage:".bright_yellow().bold());
+    println!("  siertri-stratum serve <port> <beneficiary_address> [--datadir <dir>]");
+    println!("  siertri-stratum work <host:port> <worker_name> [payout_address]");
+    println!();
+    println!("  serve hands out block templates to workers and splits block rewards");
+    println!("  between the payout addresses behind recent shares, or pays the");
+    println!("  beneficiary while there are none; work mines a pool's jobs until Ctrl-C");
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args: Vec<String> = env::args().collect();
+    let config = Config::from_args(&mut args)?;
+
+    match (args.get(1).map(String::as_str), args.len()) {
+        (Some("serve"), 4) => serve(&config, args[2].parse()?, &args[3]).await,
+        (Some("work"), 4 | 5) => work(&args[2], &args[3], args.get(4).map(String::as_str)).await,
+        _ => {
+            print_usage();
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn serve(config: &Config, port: u16, beneficiary: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open_in(config)?;
+    let chain = db.load_blockchain().unwrap_or_else(|_| {
+        println!("{}", "⚠️  No blockchain found, creating genesis...".yellow());
+        Blockchain::with_params(&config.params)
+    });
+    println!("{}", format!("📊 Serving jobs on height {}", chain.blocks.len()).bright_blue());
+
+    let server = StratumServer::new(Arc::new(Mutex::new(chain)), beneficiary.to_string(), stratum::DEFAULT_SHARE_BITS)
+        .with_database(Arc::new(Mutex::new(db)))?;
+    Arc::new(server).run(port).await?;
+    Ok(())
+}
+
+async fn work(pool: &str, worker: &str, address: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                stop.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+
+    println!("{}", format!("👷 Working for {} as {}", pool, worker).bright_cyan().bold());
+    if let Some(address) = address {
+        println!("{}", format!("💰 Shares credited to {}", address).bright_blue());
+    }
+    let (mut accepted, mut rejected) = (0u64, 0u64);
+    stratum::run_worker(pool, worker, address, stop, |response| match response {
+        Response::ShareAccepted { block: Some(hash) } => {
+            accepted += 1;
+            println!("{}", format!("✨ Share completed block {}", hash).bright_green().bold());
+        }
+        Response::ShareAccepted { block: None } => accepted += 1,
+        Response::ShareRejected { reason } => {
+            rejected += 1;
+            eprintln!("{}", format!("⚠️  Share rejected: {}", reason).yellow());
+        }
+        _ => {}
+    }).await?;
+
+    println!("{}", format!("🛑 Stopped: {} shares accepted, {} rejected", accepted, rejected).bright_yellow());
+    Ok(())
+}
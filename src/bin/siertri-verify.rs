@@ -0,0 +1,85 @@
+//! Re-validate the most recent blocks and check the stored UTXO set, or with
+//! --full check the whole database, rebuilding its derived tables on --repair
+
+use siertrichain::blockchain::ChainVerificationReport;
+use siertrichain::config::Config;
+use siertrichain::persistence::Database;
+use std::env;
+
+/// Number of blocks checked when no depth is given
+const DEFAULT_DEPTH: usize = 288;
+
+const USAGE: &str = "Usage: siertri-verify [depth | --full | --repair] [--datadir <dir>]";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args: Vec<String> = env::args().collect();
+    let config = Config::from_args(&mut args)?;
+
+    match args.get(1).map(String::as_str) {
+        Some("--full") => full(&config, false),
+        Some("--repair") => full(&config, true),
+        Some(arg) => recent(&config, arg.parse().map_err(|_| USAGE)?),
+        None => recent(&config, DEFAULT_DEPTH),
+    }
+}
+
+fn recent(config: &Config, depth: usize) -> Result<(), Box<dyn std::error::Error>> {
+    println!("🔍 Verifying the last {} blocks...\n", depth);
+
+    let db = Database::open_read_only_in(config)?;
+    let chain = db.load_blockchain()?;
+    let report = chain.verify_chain(depth);
+    print_chain_report(&report);
+
+    if report.is_ok() {
+        println!("✅ Chain is consistent");
+        Ok(())
+    } else {
+        Err("Chain verification failed".into())
+    }
+}
+
+/// Check every block and table, rebuilding the derived tables first if asked
+fn full(config: &Config, repair: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let db = if repair {
+        let db = Database::open_in(config)?;
+        println!("🔧 Rebuilding the UTXO set and indexes from the blocks...");
+        db.repair()?;
+        db
+    } else {
+        Database::open_read_only_in(config)?
+    };
+
+    println!("🔍 Verifying the whole database...\n");
+    let report = db.verify()?;
+    for problem in &report.problems {
+        println!("❌ {}", problem);
+    }
+    if let Some(chain) = &report.chain {
+        print_chain_report(chain);
+    }
+
+    if report.is_ok() {
+        println!("✅ Database is consistent");
+        Ok(())
+    } else {
+        if !repair {
+            println!("\n💡 Run siertri-verify --repair to rebuild what is derived from the blocks");
+        }
+        Err("Database verification failed".into())
+    }
+}
+
+fn print_chain_report(report: &ChainVerificationReport) {
+    println!("📊 Checked {} blocks (heights {}..={})",
+             report.blocks_checked, report.start_height, report.tip_height);
+
+    for problem in &report.problems {
+        println!("❌ Block {} ({}): {}",
+                 problem.height, hex::encode(problem.hash), problem.description);
+    }
+
+    if !report.state_matches {
+        println!("❌ Replayed UTXO set does not match the stored UTXO set");
+    }
+}
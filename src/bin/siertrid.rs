@@ -0,0 +1,182 @@
+//! Node daemon for siertrichain: networking, the mempool, mining and the HTTP
+//! API in one process over one shared chain, so none of them wait on another
+//! to save the database. The wallet CLIs talk to it through the API.
+
+use siertrichain::api::{self, DEFAULT_API_PORT};
+use siertrichain::blockchain::Blockchain;
+use siertrichain::config::{Config, DATADIR_ENV};
+use siertrichain::connmgr::{ConnectionManager, DEFAULT_OUTBOUND_PEERS};
+use siertrichain::miner::{MiningConfig, MiningCoordinator};
+use siertrichain::network::{NetworkNode, Node, DEFAULT_MAX_INBOUND_PEERS};
+use siertrichain::params::ChainParams;
+use siertrichain::peerstore::get_peers_path;
+use siertrichain::persistence::Database;
+use siertrichain::security::SecurityManager;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const DEFAULT_PORT: u16 = 8333;
+
+fn print_usage() {
+    println!("Usage: siertrid [--port <port>] [--listen <ip:port>]... [--external <host:port>]...");
+    println!("                [--api-port <port>] [--peer <host:port>]");
+    println!("                [--mine <address>] [--threads <n>] [--duty-cycle <percent>]");
+    println!("                [--address-index <on|off>] [--datadir <dir>] [--regtest]");
+    println!("\nListens for peers on port {} over IPv4 and IPv6 and serves the API on", DEFAULT_PORT);
+    println!("127.0.0.1:{} unless told otherwise. --listen replaces the former with the", DEFAULT_API_PORT);
+    println!("addresses given; IPv6 ones go in brackets, e.g. [::]:8333. --external tells");
+    println!("peers another address to reach this node at. With --mine, also mines");
+    println!("blocks paying <address>. --address-index turns the database's index of");
+    println!("each address's transactions on or off, for this and later runs.");
+    println!("The database is kept in --datadir, else ${}, else ~/.siertrichain/data.", DATADIR_ENV);
+    println!("--regtest runs a private test network, kept in the regtest subdirectory.");
+    println!("\nEnvironment:");
+    println!("  SIERTRI_MAX_INBOUND   inbound connection limit");
+    println!("  SIERTRI_MAX_OUTBOUND  outbound connections to keep");
+    println!("  SIERTRI_NODE_KEY      hex secret key peers know this node by");
+    println!("  SIERTRI_TRUSTED_PEERS comma-separated hex keys of peers that may authenticate");
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let datadir = Config::from_args(&mut args).unwrap_or_else(|e| {
+        eprintln!("❌ {}", e);
+        std::process::exit(1);
+    });
+    let (mut port, mut api_port) = (DEFAULT_PORT, DEFAULT_API_PORT);
+    let (mut peer, mut beneficiary) = (None, None);
+    let (mut listen, mut external) = (Vec::new(), Vec::new());
+    let mut address_index = None;
+    let defaults = MiningConfig::default();
+    let (mut threads, mut duty_cycle) = (defaults.threads, defaults.duty_cycle);
+    let mut flags = args[1..].iter();
+    while let Some(flag) = flags.next() {
+        match (flag.as_str(), flags.next()) {
+            ("--port", Some(p)) => port = p.parse().expect("Invalid port number"),
+            ("--listen", Some(addr)) => listen.push(addr.parse::<SocketAddr>().expect("Invalid listen address")),
+            ("--external", Some(addr)) => external.push(Node::parse(addr).expect("Invalid external address")),
+            ("--api-port", Some(p)) => api_port = p.parse().expect("Invalid API port number"),
+            ("--peer", Some(addr)) => peer = Some(Node::parse(addr).expect("Invalid peer address")),
+            ("--mine", Some(address)) => beneficiary = Some(address.clone()),
+            ("--threads", Some(n)) => threads = n.parse().expect("Invalid thread count"),
+            ("--duty-cycle", Some(percent)) => duty_cycle = percent.parse().expect("Invalid duty cycle"),
+            ("--address-index", Some(setting)) if setting == "on" || setting == "off" => {
+                address_index = Some(setting == "on");
+            }
+            _ => {
+                print_usage();
+                std::process::exit(1);
+            }
+        }
+    }
+    let config = MiningConfig::new(threads, duty_cycle).unwrap_or_else(|e| {
+        eprintln!("❌ {}", e);
+        std::process::exit(1);
+    });
+
+    println!("🔺 siertrid v0.1.0\n");
+
+    println!("📁 Data directory: {}", datadir.network_dir().display());
+    if datadir.params != ChainParams::MAINNET {
+        println!("🧪 Network: {}", datadir.params.network);
+    }
+    let mut db = Database::open_in(&datadir).expect("Failed to open database");
+    if let Some(enabled) = address_index {
+        db.set_address_index(enabled).expect("Failed to set up the address index");
+    }
+    println!("📇 Address index: {}", if db.has_address_index() { "ON" } else { "OFF" });
+    let blockchain = db.load_blockchain().unwrap_or_else(|_| {
+        println!("⚠️  No blockchain found, creating genesis...");
+        let mut chain = Blockchain::with_params(&datadir.params);
+        let genesis = chain.blocks[0].clone();
+        db.save_blockchain_state(&genesis, &mut chain.state, chain.bits).expect("Failed to save genesis");
+        chain
+    });
+    println!("📊 Current height: {}", blockchain.blocks.last().unwrap().header.height);
+    println!("💾 UTXO count: {}\n", blockchain.state.count());
+    let db = Arc::new(Mutex::new(db));
+
+    let max_inbound = env_limit("SIERTRI_MAX_INBOUND", DEFAULT_MAX_INBOUND_PEERS);
+    let max_outbound = env_limit("SIERTRI_MAX_OUTBOUND", DEFAULT_OUTBOUND_PEERS);
+    println!("🔌 Peer limits: {} inbound, {} outbound", max_inbound, max_outbound);
+
+    // The node owns the chain; the API and miner share it
+    let mut node = NetworkNode::new(blockchain, datadir.db_path().display().to_string())
+        .with_max_inbound(max_inbound)
+        .with_params(datadir.params.clone())
+        .with_database(db.clone())
+        .with_peers_file(get_peers_path())
+        .expect("Failed to load peers file")
+        .with_security(SecurityManager::from_env().expect("Failed to create node key"));
+    for addr in external {
+        node = node.with_external_address(addr);
+    }
+    println!("🔐 Peer authentication: {}", if node.requires_auth() { "ENABLED" } else { "DISABLED" });
+    if let Some(key) = node.public_key() {
+        println!("🔑 Node key: {}", hex::encode(key));
+    }
+    if let Some(proxy) = node.socks5_proxy() {
+        println!("🧅 Dialing peers through SOCKS5 proxy {}", proxy);
+    }
+    if let Err(e) = node.save_proxy_status() {
+        eprintln!("❌ Failed to save proxy status: {}", e);
+    }
+
+    let api_addr = SocketAddr::from(([127, 0, 0, 1], api_port));
+    let listener = tokio::net::TcpListener::bind(api_addr).await.expect("Failed to bind the API port");
+    println!("🌍 API listening on http://{}", api_addr);
+    tokio::spawn(api::serve(listener, node.blockchain(), db.clone()));
+
+    if let Some(peer) = peer {
+        println!("🔗 Connecting to peer {}...", peer.addr());
+        node.add_peer(peer.clone()).await;
+        if let Err(e) = node.connect_peer(peer.host, peer.port).await {
+            eprintln!("❌ Failed to connect to peer, will keep retrying: {}", e);
+        }
+    }
+    tokio::spawn(ConnectionManager::new(node.clone(), datadir.params.clone(), max_outbound).run());
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let miner = beneficiary.map(|beneficiary| {
+        println!("⛏️  Mining to {} on {} thread(s) at {}%", beneficiary, config.threads, config.duty_cycle);
+        let (chain, db, stop) = (node.blockchain(), db.clone(), stop.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut coordinator = MiningCoordinator::new(chain, beneficiary).with_config(config);
+            let result = coordinator.run(Some(&db), &stop, |block| {
+                println!("✅ Mined block {} at height {}", hex::encode(block.hash), block.header.height);
+            });
+            if let Err(e) = result {
+                eprintln!("❌ Mining stopped: {}", e);
+            }
+        })
+    });
+
+    println!("🌐 Ready to accept connections!\n");
+    tokio::select! {
+        result = async {
+            if listen.is_empty() { node.start_server(port).await } else { node.listen(&listen).await }
+        } => {
+            if let Err(e) = result {
+                eprintln!("❌ Server error: {}", e);
+            }
+        }
+        _ = tokio::signal::ctrl_c() => println!("\n👋 Shutting down..."),
+    }
+
+    // Let the miner finish saving any block it found before exiting
+    stop.store(true, Ordering::Relaxed);
+    if let Some(miner) = miner {
+        let _ = miner.await;
+    }
+}
+
+/// A connection limit from the environment variable `name`, or `default`
+fn env_limit(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("Invalid {}: {}", name, value)),
+        Err(_) => default,
+    }
+}
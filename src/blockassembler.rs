@@ -0,0 +1,371 @@
+//! Block template construction for siertrichain miners
+//!
+//! Both the API mining task and `siertri-miner` build their candidate blocks
+//! here, so they agree on transaction selection, the coinbase reward, and a
+//! timestamp the chain will accept.
+
+use crate::blockchain::{Block, Blockchain, Sha256Hash, MAX_BLOCK_SIZE, MAX_BLOCK_TRANSACTIONS};
+use crate::transaction::{Address, CoinbaseOutput, CoinbaseTx, FeeRate, Transaction};
+use chrono::Utc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// An unmined block ready for proof-of-work, plus the figures used to build it
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pub block: Block,
+    /// Subsidy for this height, before fees
+    pub subsidy: u64,
+    /// Sum of fees from the selected transactions
+    pub total_fees: u64,
+}
+
+impl BlockTemplate {
+    /// Build a template on top of the current tip, paying the reward to `miner_address`.
+    /// Mempool transactions are taken as packages with their unconfirmed ancestors,
+    /// highest package fee rate (fee per serialized byte) first, so a high-fee child
+    /// can pay for its parent.
+    /// Packages whose inputs are missing or already spent by an earlier selection, or
+    /// that would put a sender's nonces out of order, are skipped.
+    pub fn build(chain: &Blockchain, miner_address: &str) -> Self {
+        Self::build_with(chain, |reward| coinbase(reward, miner_address))
+    }
+
+    /// Like `build`, but split the reward between several addresses in
+    /// proportion to their weights (see `split_reward`), as a pool pays its
+    /// members. `weights` must contain at least one positive weight.
+    pub fn build_split(chain: &Blockchain, weights: &[(Address, f64)]) -> Self {
+        Self::build_with(chain, |reward| {
+            Transaction::Coinbase(CoinbaseTx::with_outputs(split_reward(reward, weights)))
+        })
+    }
+
+    fn build_with(chain: &Blockchain, coinbase: impl Fn(u64) -> Transaction) -> Self {
+        let tip = chain.blocks.last().unwrap();
+        let height = tip.header.height + 1;
+        let subsidy = Blockchain::calculate_block_reward(height);
+
+        // Rewards are fixed-width, so measure the block with the largest possible
+        // reward, which pays every output, before the fees are known
+        let largest_reward = chain.mempool.get_all_transactions().iter()
+            .fold(subsidy, |reward, tx| reward.saturating_add(tx.fee()));
+        let placeholder = Block::new(height, tip.hash, chain.bits, vec![coinbase(largest_reward)]);
+        let mut remaining_bytes = MAX_BLOCK_SIZE.saturating_sub(placeholder.serialized_size());
+
+        let mempool = &chain.mempool;
+        let median_time_past = chain.median_time_past(&tip.hash);
+        let mut candidates: Vec<(Sha256Hash, Vec<Sha256Hash>, FeeRate)> = mempool
+            .get_all_transactions()
+            .iter()
+            .map(|tx| {
+                let hash = tx.hash();
+                let mut package = mempool.ancestors(&hash);
+                package.push(hash);
+                let (package_fee, package_size) = package
+                    .iter()
+                    .filter_map(|h| mempool.get_transaction(h))
+                    .fold((0u64, 0usize), |(fee, size), tx| {
+                        (fee.saturating_add(tx.fee()), size + tx.size())
+                    });
+                (hash, package, FeeRate::new(package_fee, package_size))
+            })
+            .collect();
+        // Highest package fee rate first
+        candidates.sort_by(|(hash_a, _, rate_a), (hash_b, _, rate_b)| {
+            rate_b.cmp(rate_a).then_with(|| hash_a.cmp(hash_b))
+        });
+
+        let mut selected: Vec<Transaction> = Vec::new();
+        let mut included: HashSet<Sha256Hash> = HashSet::new();
+        let mut spent: HashSet<Sha256Hash> = HashSet::new();
+        let mut created: HashSet<Sha256Hash> = HashSet::new();
+        let mut nonces: HashMap<&str, u64> = HashMap::new();
+        let mut total_fees = 0u64;
+
+        for (_, package, _) in candidates {
+            let package: Vec<&Transaction> = package
+                .iter()
+                .filter(|h| !included.contains(*h))
+                .filter_map(|h| mempool.get_transaction(h))
+                .collect();
+            if package.is_empty() {
+                continue;
+            }
+            if selected.len() + package.len() >= MAX_BLOCK_TRANSACTIONS {
+                continue;
+            }
+
+            let mut package_spent = HashSet::new();
+            let mut package_created = HashSet::new();
+            let mut package_nonces: HashMap<&str, u64> = HashMap::new();
+            let mut package_size = 0usize;
+            let mut fits = true;
+            for tx in &package {
+                let inputs = tx.inputs();
+                if inputs.is_empty() || !tx.is_final(height, median_time_past) {
+                    fits = false;
+                    break;
+                }
+                for input in inputs {
+                    let exists = chain.state.utxo_set.contains_key(&input)
+                        || created.contains(&input)
+                        || package_created.contains(&input);
+                    if !exists || spent.contains(&input) || !package_spent.insert(input) {
+                        fits = false;
+                    }
+                }
+                if !fits {
+                    break;
+                }
+                // Each sender's nonces must increase through the block
+                if let (Some(sender), Some(nonce)) = (tx.sender(), tx.nonce()) {
+                    let last = package_nonces.get(sender.as_str())
+                        .or_else(|| nonces.get(sender.as_str()))
+                        .copied()
+                        .or_else(|| chain.state.nonces.get(sender).copied());
+                    if last.is_some_and(|last| nonce <= last) {
+                        fits = false;
+                        break;
+                    }
+                    package_nonces.insert(sender, nonce);
+                }
+                if let Transaction::Subdivision(sub_tx) = tx {
+                    package_created.extend(sub_tx.children.iter().map(|c| c.hash()));
+                }
+                package_size += tx.size();
+            }
+            if !fits || package_size > remaining_bytes {
+                continue;
+            }
+
+            remaining_bytes -= package_size;
+            spent.extend(package_spent);
+            created.extend(package_created);
+            nonces.extend(package_nonces);
+            for tx in package {
+                included.insert(tx.hash());
+                total_fees = total_fees.saturating_add(tx.fee());
+                selected.push(tx.clone());
+            }
+        }
+
+        let mut transactions = vec![coinbase(subsidy.saturating_add(total_fees))];
+        transactions.extend(selected);
+
+        let mut block = Block::new(height, tip.hash, chain.bits, transactions);
+
+        // The timestamp must be past the median of recent blocks even if the local clock lags
+        let min_timestamp = median_time_past.saturating_add(1);
+        block.header.timestamp = Utc::now().timestamp().max(min_timestamp);
+        block.hash = block.calculate_hash();
+
+        BlockTemplate {
+            block,
+            subsidy,
+            total_fees,
+        }
+    }
+
+    /// Reward claimed by the template's coinbase
+    pub fn reward(&self) -> u64 {
+        match self.block.transactions.get().map(|transactions| transactions.first()) {
+            Ok(Some(Transaction::Coinbase(cb_tx))) => cb_tx.reward_area(),
+            _ => 0,
+        }
+    }
+}
+
+fn coinbase(reward_area: u64, miner_address: &str) -> Transaction {
+    Transaction::Coinbase(CoinbaseTx::new(reward_area, miner_address.to_string()))
+}
+
+/// Divide `reward` between addresses in proportion to their weights, one
+/// coinbase output each. Weights for the same address are added together.
+/// Only the `CoinbaseTx::MAX_OUTPUTS` heaviest addresses are paid; the
+/// rounding remainder goes to the heaviest first, and addresses whose share
+/// rounds to nothing are left out.
+pub fn split_reward(reward: u64, weights: &[(Address, f64)]) -> Vec<CoinbaseOutput> {
+    let mut totals: BTreeMap<&str, f64> = BTreeMap::new();
+    for (address, weight) in weights {
+        if weight.is_finite() && *weight > 0.0 {
+            *totals.entry(address.as_str()).or_default() += weight;
+        }
+    }
+    let mut totals: Vec<(&str, f64)> = totals.into_iter().collect();
+    totals.sort_by(|(address_a, weight_a), (address_b, weight_b)| {
+        weight_b.total_cmp(weight_a).then_with(|| address_a.cmp(address_b))
+    });
+    totals.truncate(CoinbaseTx::MAX_OUTPUTS);
+    if totals.is_empty() {
+        return Vec::new();
+    }
+    let total_weight: f64 = totals.iter().map(|(_, weight)| weight).sum();
+
+    let mut outputs: Vec<CoinbaseOutput> = totals.iter().map(|(address, weight)| CoinbaseOutput {
+        beneficiary_address: address.to_string(),
+        reward_area: (reward as f64 * weight / total_weight).floor() as u64,
+    }).collect();
+    let paid = outputs.iter().fold(0u64, |total, output| total.saturating_add(output.reward_area));
+    let count = outputs.len();
+    for index in 0..reward.saturating_sub(paid) as usize {
+        outputs[index % count].reward_area += 1;
+    }
+    outputs.retain(|output| output.reward_area > 0);
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis_triangle;
+    use crate::crypto::KeyPair;
+    use crate::miner::mine_block;
+    use std::sync::atomic::AtomicBool;
+    use crate::transaction::SubdivisionTx;
+
+    fn signed_subdivision(keypair: &KeyPair, fee: u64, nonce: u64) -> Transaction {
+        let mut genesis = genesis_triangle();
+        genesis.owner = keypair.address();
+        let mut tx = SubdivisionTx::new(genesis.hash(), genesis.subdivide().to_vec(), keypair.address(), fee, nonce);
+        let signature = keypair.sign(&tx.signable_message()).unwrap();
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        Transaction::Subdivision(tx)
+    }
+
+    #[test]
+    fn test_template_is_valid_and_pays_subsidy() {
+        let mut chain = Blockchain::regtest();
+        let template = BlockTemplate::build(&chain, "miner");
+
+        assert_eq!(template.block.header.height, 1);
+        assert_eq!(template.reward(), Blockchain::calculate_block_reward(1));
+
+        let block = mine_block(template.block, &AtomicBool::new(false)).unwrap();
+        chain.apply_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_split_reward() {
+        let weights = vec![
+            ("alice".to_string(), 2.0),
+            ("bob".to_string(), 1.0),
+            ("alice".to_string(), 1.0),
+            ("carol".to_string(), 0.0),
+        ];
+        let outputs = split_reward(1000, &weights);
+        let paid: Vec<(&str, u64)> = outputs.iter()
+            .map(|output| (output.beneficiary_address.as_str(), output.reward_area))
+            .collect();
+        assert_eq!(paid, vec![("alice", 750), ("bob", 250)]);
+
+        // The remainder goes to the heaviest, and dust shares are dropped
+        let outputs = split_reward(10, &[("alice".to_string(), 2.0), ("bob".to_string(), 1.0), ("dust".to_string(), 0.01)]);
+        let paid: Vec<(&str, u64)> = outputs.iter()
+            .map(|output| (output.beneficiary_address.as_str(), output.reward_area))
+            .collect();
+        assert_eq!(paid, vec![("alice", 7), ("bob", 3)]);
+
+        let crowd: Vec<(Address, f64)> = (0..CoinbaseTx::MAX_OUTPUTS + 10)
+            .map(|i| (format!("member{}", i), 1.0 + i as f64))
+            .collect();
+        let outputs = split_reward(1000, &crowd);
+        assert_eq!(outputs.len(), CoinbaseTx::MAX_OUTPUTS);
+        assert_eq!(outputs.iter().map(|output| output.reward_area).sum::<u64>(), 1000);
+        assert!(outputs.iter().all(|output| output.beneficiary_address != "member0"));
+    }
+
+    #[test]
+    fn test_split_template_pays_each_member() {
+        let mut chain = Blockchain::regtest();
+        let weights = vec![("alice".to_string(), 3.0), ("bob".to_string(), 1.0)];
+        let template = BlockTemplate::build_split(&chain, &weights);
+
+        let Some(Transaction::Coinbase(coinbase)) = template.block.transactions.get().unwrap().first() else {
+            panic!("expected a coinbase");
+        };
+        let reward = Blockchain::calculate_block_reward(1);
+        assert_eq!(template.reward(), reward);
+        assert_eq!(coinbase.reward_to("alice"), reward * 3 / 4);
+        assert_eq!(coinbase.reward_to("bob"), reward / 4);
+
+        let block = mine_block(template.block, &AtomicBool::new(false)).unwrap();
+        chain.apply_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_split_template_shares_the_fees() {
+        let mut chain = Blockchain::regtest();
+        let keypair = KeyPair::generate().unwrap();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+        chain.mempool.add_transaction(signed_subdivision(&keypair, 200, 1), &chain.state).unwrap();
+
+        let weights = vec![("alice".to_string(), 3.0), ("bob".to_string(), 1.0)];
+        let template = BlockTemplate::build_split(&chain, &weights);
+        let Some(Transaction::Coinbase(coinbase)) = template.block.transactions.get().unwrap().first() else {
+            panic!("expected a coinbase");
+        };
+        let reward = Blockchain::calculate_block_reward(1) + 200;
+        assert_eq!(template.reward(), reward);
+        assert_eq!(coinbase.reward_to("alice"), reward * 3 / 4);
+        assert_eq!(coinbase.reward_to("bob"), reward / 4);
+
+        let block = mine_block(template.block, &AtomicBool::new(false)).unwrap();
+        chain.apply_block(block).unwrap();
+        assert!(chain.audit_supply().problems.is_empty());
+    }
+
+    #[test]
+    fn test_template_skips_conflicting_transactions() {
+        let mut chain = Blockchain::regtest();
+        let keypair = KeyPair::generate().unwrap();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+
+        // Two transactions spending the genesis triangle; only the higher fee one fits
+        let low = signed_subdivision(&keypair, 1, 1);
+        let high = signed_subdivision(&keypair, 5, 2);
+        chain.mempool.add_transaction(low, &chain.state).unwrap();
+        chain.mempool.add_transaction(high.clone(), &chain.state).unwrap();
+
+        let template = BlockTemplate::build(&chain, "miner");
+        assert_eq!(template.block.transactions.get().unwrap().len(), 2);
+        assert_eq!(template.block.transactions.get().unwrap()[1].hash(), high.hash());
+        assert_eq!(template.total_fees, 5);
+        // The fees are paid on top of the subsidy, not burned
+        assert_eq!(template.reward(), template.subsidy + 5);
+
+        let block = mine_block(template.block, &AtomicBool::new(false)).unwrap();
+        chain.apply_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_template_includes_child_pays_for_parent_package() {
+        let mut chain = Blockchain::regtest();
+        let keypair = KeyPair::generate().unwrap();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+
+        // A zero-fee parent and a high-fee child spending one of its outputs
+        let parent = signed_subdivision(&keypair, 0, 1);
+        let child_input = match &parent {
+            Transaction::Subdivision(tx) => tx.children[0].clone(),
+            _ => unreachable!(),
+        };
+        let mut child = SubdivisionTx::new(child_input.hash(), child_input.subdivide().to_vec(), keypair.address(), 9, 2);
+        let signature = keypair.sign(&child.signable_message()).unwrap();
+        child.sign(signature, keypair.public_key.serialize().to_vec());
+        let child = Transaction::Subdivision(child);
+
+        chain.submit_transaction(parent.clone()).unwrap();
+        chain.submit_transaction(child.clone()).unwrap();
+
+        let template = BlockTemplate::build(&chain, "miner");
+        assert_eq!(template.block.transactions.get().unwrap().len(), 3);
+        // The parent must come before the child that spends its output
+        assert_eq!(template.block.transactions.get().unwrap()[1].hash(), parent.hash());
+        assert_eq!(template.block.transactions.get().unwrap()[2].hash(), child.hash());
+        assert_eq!(template.total_fees, 9);
+
+        let block = mine_block(template.block, &AtomicBool::new(false)).unwrap();
+        chain.apply_block(block).unwrap();
+        assert!(chain.state.utxo_set.contains_key(&child_input.subdivide()[0].hash()));
+        assert!(chain.mempool.is_empty());
+    }
+}
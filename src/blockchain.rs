@@ -1,1332 +1,5909 @@
 //! Core blockchain implementation for siertrichain
 
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
-use crate::geometry::{Triangle, Point};
-use crate::transaction::{Transaction, SubdivisionTx, CoinbaseTx};
+use std::collections::{HashMap, HashSet};
+use crate::geometry::{Contact, Triangle, TriangleId};
+use crate::render::{Bounds, SpatialIndex};
+use crate::transaction::{
+    FeeRate, Transaction, SubdivisionTx, CoinbaseTx, AcceptTx, DeepSubdivisionTx, EscrowContract, HtlcContract, InscriptionTx,
+    Approval, LeaseContract, MarketOffer, SplitTransferTx,
+};
 use crate::error::ChainError;
+use crate::params::ChainParams;
+use crate::crypto::{Address, SignatureCache};
+use crate::codec;
+use crate::difficulty::{self, add_work, block_work, Work};
+use crate::events::{ChainEvent, EventBus};
+use crate::pow::{self, PowEngine, Sha256Pow};
 use chrono::Utc;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::broadcast;
 
 pub type Sha256Hash = [u8; 32];
 pub type BlockHeight = u64;
 
-/// The genesis triangle - the root of all fractals
+/// Parse a 64-character hex string into a hash
+pub fn parse_hash(hex_str: &str) -> Result<Sha256Hash, ChainError> {
+    let bytes = hex::decode(hex_str)
+        .map_err(|e| ChainError::CodecError(format!("Invalid hash {}: {}", hex_str, e)))?;
+    bytes.try_into().map_err(|bytes: Vec<u8>| {
+        ChainError::CodecError(format!("Hash must be 32 bytes, got {}", bytes.len()))
+    })
+}
+
+/// The genesis triangle - the root of all fractals. Shorthand for
+/// `ChainParams::MAINNET.genesis_triangle()`.
 pub fn genesis_triangle() -> Triangle {
-    Triangle::new(
-        Point { x: 0.0, y: 0.0 },
-        Point { x: 1.0, y: 0.0 },
-        Point { x: 0.5, y: 0.866025403784 },
-        None,
-        "genesis_owner".to_string(),
-    )
+    ChainParams::MAINNET.genesis_triangle()
+}
+
+/// Human-readable triangle address: a root followed by the child index taken
+/// at each subdivision, e.g. `Δ/0/2/1`. Descendants of the genesis triangle use
+/// `Δ` as their root; other roots (coinbase rewards) are written as their hex id.
+/// Parsing also accepts `.` as the separator, which is safe in URLs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrianglePath {
+    pub root: TriangleId,
+    pub indices: Vec<u8>,
+}
+
+impl TrianglePath {
+    /// Marker for the genesis root
+    pub const GENESIS_ROOT: &'static str = "Δ";
+
+    pub fn of(triangle: &Triangle) -> Self {
+        TrianglePath { root: triangle.root_id(), indices: triangle.path() }
+    }
+
+    /// The id of the triangle this path names, if it descends from genesis.
+    /// Other roots need the chain to resolve; see `TriangleState::find_by_path`.
+    pub fn genesis_descendant_id(&self) -> Option<TriangleId> {
+        let mut triangle = genesis_triangle();
+        if self.root != triangle.hash() {
+            return None;
+        }
+        for &index in &self.indices {
+            triangle = triangle.subdivide()[index as usize].clone();
+        }
+        Some(triangle.hash())
+    }
+}
+
+impl std::fmt::Display for TrianglePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.root == genesis_triangle().hash() {
+            write!(f, "{}", Self::GENESIS_ROOT)?;
+        } else {
+            write!(f, "{}", hex::encode(self.root))?;
+        }
+        for index in &self.indices {
+            write!(f, "/{}", index)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for TrianglePath {
+    type Err = ChainError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(['/', '.']);
+        let root = match parts.next().unwrap_or_default() {
+            Self::GENESIS_ROOT => genesis_triangle().hash(),
+            other => parse_hash(other)?,
+        };
+        let indices = parts
+            .map(|part| match part.parse::<u8>() {
+                Ok(index) if index < 3 => Ok(index),
+                _ => Err(ChainError::CodecError(format!(
+                    "Invalid child index '{}' in triangle path {}", part, s
+                ))),
+            })
+            .collect::<Result<Vec<u8>, ChainError>>()?;
+        if indices.len() > crate::geometry::MAX_DEPTH as usize {
+            return Err(ChainError::CodecError(format!("Triangle path {} is too deep", s)));
+        }
+        Ok(TrianglePath { root, indices })
+    }
+}
+
+/// Most changed triangles a state tracks before it settles for being saved whole
+const MAX_TRACKED_UTXO_CHANGES: usize = 100_000;
+
+/// Unspent triangles changed since a state was last saved, so saving it can
+/// write just those
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum UtxoChanges {
+    /// Ids of the triangles added, spent or handed over
+    Touched(HashSet<TriangleId>),
+    /// The state was built or replaced wholesale, or too much changed to
+    /// track: all of it has to be saved
+    #[default]
+    All,
 }
 
 /// Manages the canonical set of all currently valid (unspent) triangles (UTXO set).
 #[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
 pub struct TriangleState {
-    pub utxo_set: HashMap<Sha256Hash, Triangle>,
+    pub utxo_set: HashMap<TriangleId, Triangle>,
+    /// Highest nonce each address has used in a confirmed transaction
+    #[serde(default)]
+    pub nonces: HashMap<Address, u64>,
+    /// Open hash time-locked contracts, keyed by the locked triangle
+    #[serde(default)]
+    pub htlcs: HashMap<Sha256Hash, HtlcContract>,
+    /// Open escrows, keyed by the escrowed triangle
+    #[serde(default)]
+    pub escrows: HashMap<Sha256Hash, EscrowContract>,
+    /// Open marketplace offers, keyed by the listed triangle
+    #[serde(default)]
+    pub offers: HashMap<Sha256Hash, MarketOffer>,
+    /// Leases in force, keyed by the leased triangle
+    #[serde(default)]
+    pub leases: HashMap<Sha256Hash, LeaseContract>,
+    /// Delegates approved to move a triangle for its owner, keyed by the triangle
+    #[serde(default)]
+    pub approvals: HashMap<Sha256Hash, Approval>,
+    /// Unspent triangles of each owner, kept in step with `utxo_set` by
+    /// `insert_triangle`, `remove_triangle` and `set_owner`
+    #[serde(skip)]
+    pub(crate) owner_index: HashMap<Address, HashSet<TriangleId>>,
+    /// Kept by the same methods, for the database to write only what changed
+    #[serde(skip)]
+    pub(crate) utxo_changes: UtxoChanges,
+    /// Unspent triangles by where they lie, kept by `insert_triangle` and
+    /// `remove_triangle`, for `query_region`
+    #[serde(skip)]
+    pub(crate) spatial_index: SpatialIndex,
 }
 
 impl TriangleState {
     pub fn new() -> Self {
         TriangleState {
             utxo_set: HashMap::new(),
+            nonces: HashMap::new(),
+            htlcs: HashMap::new(),
+            escrows: HashMap::new(),
+            offers: HashMap::new(),
+            leases: HashMap::new(),
+            approvals: HashMap::new(),
+            owner_index: HashMap::new(),
+            utxo_changes: UtxoChanges::All,
+            spatial_index: SpatialIndex::default(),
         }
     }
 
-    pub fn count(&self) -> usize {
-        self.utxo_set.len()
-    }
-
-    /// Apply a subdivision transaction to the state
-    pub fn apply_subdivision(&mut self, tx: &SubdivisionTx) -> Result<(), ChainError> {
-        if !self.utxo_set.contains_key(&tx.parent_hash) {
-            return Err(ChainError::TriangleNotFound(format!(
-                "Parent triangle {} not found",
-                hex::encode(tx.parent_hash)
-            )));
+    /// Add an unspent triangle
+    pub fn insert_triangle(&mut self, id: TriangleId, triangle: Triangle) {
+        self.touch(id);
+        self.owner_index.entry(triangle.owner.clone()).or_default().insert(id);
+        self.spatial_index.insert(id, &triangle);
+        if let Some(replaced) = self.utxo_set.insert(id, triangle) {
+            if replaced.owner != self.utxo_set[&id].owner {
+                self.unindex_owner(&replaced.owner, &id);
+            }
         }
+    }
 
-        self.utxo_set.remove(&tx.parent_hash);
+    /// Spend an unspent triangle, returning it
+    pub fn remove_triangle(&mut self, id: &TriangleId) -> Option<Triangle> {
+        let triangle = self.utxo_set.remove(id)?;
+        self.touch(*id);
+        self.unindex_owner(&triangle.owner, id);
+        self.spatial_index.remove(id, &triangle);
+        Some(triangle)
+    }
 
-        for child in &tx.children {
-            let child_hash = child.hash();
-            self.utxo_set.insert(child_hash, child.clone());
+    /// Hand an unspent triangle to a new owner
+    pub fn set_owner(&mut self, id: &TriangleId, owner: Address) -> Result<(), ChainError> {
+        let triangle = self.utxo_set.get_mut(id).ok_or(ChainError::UtxoMissing { hash: *id })?;
+        let previous = std::mem::replace(&mut triangle.owner, owner.clone());
+        if previous != owner {
+            self.touch(*id);
+            self.unindex_owner(&previous, id);
+            self.owner_index.entry(owner).or_default().insert(*id);
         }
-
         Ok(())
     }
 
-    /// Apply a coinbase transaction to the state, creating a new triangle as a reward.
-    pub fn apply_coinbase(
-        &mut self,
-        tx: &CoinbaseTx,
-        block_height: BlockHeight,
-    ) -> Result<(), ChainError> {
-        // Create a new triangle with a canonical shape based on the reward area
-        // The position is offset by the block height to ensure uniqueness
-        let side = (2.0 * tx.reward_area as f64).sqrt() as f64;
-        if !side.is_finite() || side <= 0.0 {
-            return Err(ChainError::InvalidTransaction(
-                "Invalid reward area for coinbase transaction".to_string(),
-            ));
+    fn unindex_owner(&mut self, owner: &str, id: &TriangleId) {
+        if let Some(ids) = self.owner_index.get_mut(owner) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.owner_index.remove(owner);
+            }
         }
-
-        // We'll create a right isosceles triangle at a location based on block height
-        // This ensures that reward triangles don't collide with each other
-        let offset = block_height as f64 * 1000.0; // Use a large offset
-        let new_triangle = Triangle::new(
-            Point { x: offset, y: 0.0 },
-            Point { x: offset + side, y: 0.0 },
-            Point { x: offset, y: side },
-            None,
-            tx.beneficiary_address.clone(),
-        );
-
-        let hash = new_triangle.hash();
-        self.utxo_set.insert(hash, new_triangle);
-
-        Ok(())
     }
-}
 
-/// Represents a block header with metadata
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct BlockHeader {
-    pub height: BlockHeight,
-    pub previous_hash: Sha256Hash,
-    pub timestamp: i64,
-    pub difficulty: u64,
-    pub nonce: u64,
-    pub merkle_root: Sha256Hash,
-}
+    fn touch(&mut self, id: TriangleId) {
+        if let UtxoChanges::Touched(ids) = &mut self.utxo_changes {
+            ids.insert(id);
+            if ids.len() > MAX_TRACKED_UTXO_CHANGES {
+                self.utxo_changes = UtxoChanges::All;
+            }
+        }
+    }
 
-impl BlockHeader {
-    pub fn calculate_hash(&self) -> Sha256Hash {
-        let mut hasher = Sha256::new();
-        hasher.update(self.height.to_le_bytes());
-        hasher.update(self.previous_hash);
-        hasher.update(self.timestamp.to_le_bytes());
-        hasher.update(self.difficulty.to_le_bytes());
-        hasher.update(self.nonce.to_le_bytes());
-        hasher.update(self.merkle_root);
-        hasher.finalize().into()
+    /// The unspent triangles changed since the state was last saved
+    pub fn utxo_changes(&self) -> &UtxoChanges {
+        &self.utxo_changes
     }
-}
 
-/// A block in the blockchain
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct Block {
-    pub header: BlockHeader,
-    pub hash: Sha256Hash,
-    pub transactions: Vec<Transaction>,
-}
+    /// Record that the state has been saved, so only later changes need to be
+    pub fn mark_utxo_saved(&mut self) {
+        self.utxo_changes = UtxoChanges::Touched(HashSet::new());
+    }
 
-impl Block {
-    pub fn new(
-        height: BlockHeight,
-        previous_hash: Sha256Hash,
-        difficulty: u64,
-        transactions: Vec<Transaction>,
-    ) -> Self {
-        let timestamp = Utc::now().timestamp();
-        let merkle_root = Self::calculate_merkle_root(&transactions);
+    /// Record that saving failed, so the next save writes the whole state
+    pub fn mark_utxo_unsaved(&mut self) {
+        self.utxo_changes = UtxoChanges::All;
+    }
 
-        let header = BlockHeader {
-            height,
-            previous_hash,
-            timestamp,
-            difficulty,
-            nonce: 0,
-            merkle_root,
+    /// Split off what saving the state needs and count it as saved: the
+    /// triangles changed since the last save (all of them if that wasn't
+    /// tracked), the nonces and the contracts. Saving the result leaves the
+    /// store as saving the state would, without keeping the state locked.
+    pub fn take_unsaved(&mut self) -> TriangleState {
+        let utxo_set = match &self.utxo_changes {
+            UtxoChanges::All => self.utxo_set.clone(),
+            UtxoChanges::Touched(ids) => ids.iter()
+                .filter_map(|id| self.utxo_set.get_key_value(id))
+                .map(|(id, triangle)| (*id, triangle.clone()))
+                .collect(),
         };
+        TriangleState {
+            utxo_set,
+            nonces: self.nonces.clone(),
+            htlcs: self.htlcs.clone(),
+            escrows: self.escrows.clone(),
+            offers: self.offers.clone(),
+            leases: self.leases.clone(),
+            approvals: self.approvals.clone(),
+            utxo_changes: std::mem::replace(&mut self.utxo_changes, UtxoChanges::Touched(HashSet::new())),
+            ..TriangleState::new()
+        }
+    }
 
-        Block {
-            header,
-            hash: [0; 32], // Will be calculated by the miner
-            transactions,
+    /// Rebuild the owner and spatial indexes from `utxo_set`, after loading a
+    /// state or editing `utxo_set` directly. Since the edits weren't tracked,
+    /// all of the state counts as changed.
+    pub fn rebuild_indexes(&mut self) {
+        self.utxo_changes = UtxoChanges::All;
+        self.owner_index.clear();
+        self.spatial_index.clear();
+        for (id, triangle) in &self.utxo_set {
+            self.owner_index.entry(triangle.owner.clone()).or_default().insert(*id);
+            self.spatial_index.insert(*id, triangle);
         }
     }
 
-    pub fn calculate_hash(&self) -> Sha256Hash {
-        let mut hasher = Sha256::new();
-        hasher.update(self.header.height.to_le_bytes());
-        hasher.update(self.header.previous_hash);
-        hasher.update(self.header.timestamp.to_le_bytes());
-        hasher.update(self.header.difficulty.to_le_bytes());
-        hasher.update(self.header.nonce.to_le_bytes());
-        hasher.update(self.header.merkle_root);
-        hasher.finalize().into()
+    /// The unspent triangles owned by `owner`, without scanning the whole set
+    pub fn by_owner<'a>(&'a self, owner: &str) -> impl Iterator<Item = (&'a TriangleId, &'a Triangle)> + 'a {
+        let owner = owner.to_string();
+        self.owner_index.get(&owner).into_iter().flatten()
+            .filter_map(|id| self.utxo_set.get_key_value(id))
+            // Skip entries left stale by direct edits to `utxo_set`
+            .filter(move |(_, triangle)| triangle.owner == owner)
     }
 
-    pub fn calculate_merkle_root(transactions: &[Transaction]) -> Sha256Hash {
-        if transactions.is_empty() {
-            return [0; 32];
+    /// The unspent triangle whose hex id starts with `prefix`. Fails if none
+    /// or several match, so a short prefix can't silently pick the wrong one.
+    pub fn find_by_prefix(&self, prefix: &str) -> Result<TriangleId, ChainError> {
+        let prefix = prefix.to_ascii_lowercase();
+        let mut matches = self.utxo_set.keys().filter(|id| hex::encode(id).starts_with(&prefix));
+        let found = *matches.next()
+            .ok_or_else(|| ChainError::TriangleNotFound(format!("No triangle with id prefix {}", prefix)))?;
+        if matches.next().is_some() {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Triangle id prefix {} is ambiguous", prefix
+            )));
         }
+        Ok(found)
+    }
 
-        let mut hashes: Vec<[u8; 32]> = transactions.iter().map(|tx| tx.hash()).collect();
-
-        while hashes.len() > 1 {
-            if hashes.len() % 2 != 0 {
-                hashes.push(*hashes.last().unwrap());
-            }
-
-            hashes = hashes
-                .chunks(2)
-                .map(|chunk| {
-                    let mut hasher = Sha256::new();
-                    hasher.update(chunk[0]);
-                    hasher.update(chunk[1]);
-                    hasher.finalize().into()
-                })
-                .collect();
+    /// The unspent triangle at `path`
+    pub fn find_by_path(&self, path: &TrianglePath) -> Option<(&TriangleId, &Triangle)> {
+        if let Some(id) = path.genesis_descendant_id() {
+            return self.utxo_set.get_key_value(&id);
         }
+        self.utxo_set.iter().find(|(_, triangle)| {
+            triangle.root_id() == path.root && triangle.path() == path.indices
+        })
+    }
 
-        hashes[0]
+    /// Path address of an unspent triangle
+    pub fn path_of(&self, id: &TriangleId) -> Option<TrianglePath> {
+        self.utxo_set.get(id).map(TrianglePath::of)
     }
 
-    pub fn verify_proof_of_work(&self) -> bool {
-        // Prevent DoS by limiting difficulty to a reasonable maximum (256 bits = 64 hex chars)
-        const MAX_DIFFICULTY: u64 = 64;
-        let difficulty = self.header.difficulty.min(MAX_DIFFICULTY);
+    /// Resolve what a user typed to refer to a triangle: a path such as `Δ/0/2`,
+    /// or a (prefix of a) hex id
+    pub fn resolve(&self, reference: &str) -> Result<TriangleId, ChainError> {
+        if reference.contains(['/', '.']) || reference == TrianglePath::GENESIS_ROOT {
+            let path: TrianglePath = reference.parse()?;
+            return self.find_by_path(&path)
+                .map(|(id, _)| *id)
+                .ok_or_else(|| ChainError::TriangleNotFound(format!("No unspent triangle at {}", path)));
+        }
+        self.find_by_prefix(reference)
+    }
 
-        let hash_hex = hex::encode(self.hash);
+    pub fn count(&self) -> usize {
+        self.utxo_set.len()
+    }
 
-        // Check if first 'difficulty' characters are '0'
-        hash_hex.chars().take(difficulty as usize).all(|c| c == '0')
+    /// Unspent triangles touching the unspent triangle `hash`, and how
+    pub fn neighbors(&self, hash: &TriangleId) -> Result<Vec<(TriangleId, Contact)>, ChainError> {
+        let triangle = self.utxo_set.get(hash)
+            .ok_or(ChainError::UtxoMissing { hash: *hash })?;
+        let mut neighbors: Vec<_> = self.utxo_set.iter()
+            .filter(|(id, _)| *id != hash)
+            .filter_map(|(id, other)| triangle.contact(other).map(|contact| (*id, contact)))
+            .collect();
+        neighbors.sort_by_key(|(id, _)| *id);
+        Ok(neighbors)
     }
-}
 
-/// Transaction pool for pending (unconfirmed) transactions
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct Mempool {
-    /// Pending transactions indexed by their hash
-    transactions: HashMap<Sha256Hash, Transaction>,
-}
+    /// Check that `nonce` is above the last nonce `address` used on chain.
+    /// Nonces must strictly increase, so a confirmed transaction can never be replayed.
+    pub fn check_nonce(&self, address: &Address, nonce: u64) -> Result<(), ChainError> {
+        match self.nonces.get(address) {
+            Some(&last) if nonce <= last => Err(ChainError::StaleNonce { address: address.clone(), nonce, last }),
+            _ => Ok(()),
+        }
+    }
 
-impl Mempool {
-    /// Maximum number of transactions in mempool (to prevent DoS)
-    const MAX_TRANSACTIONS: usize = 10000;
+    /// Whether a triangle is held by an open HTLC or escrow
+    pub fn is_locked(&self, hash: &Sha256Hash) -> bool {
+        self.htlcs.contains_key(hash) || self.escrows.contains_key(hash)
+    }
 
-    /// Maximum transactions per address to prevent spam
-    const MAX_PER_ADDRESS: usize = 100;
+    /// Whether a lease still holds the triangle in a block at `height`
+    pub fn is_leased(&self, hash: &Sha256Hash, height: BlockHeight) -> bool {
+        self.leases.get(hash).is_some_and(|lease| lease.is_active(height))
+    }
 
-    pub fn new() -> Self {
-        Mempool {
-            transactions: HashMap::new(),
+    /// Who may spend a triangle in a block at `height`. A lease ending at or
+    /// before `height` has already handed the triangle back to its owner, even
+    /// though that only shows in the UTXO set once the block is applied.
+    pub fn owner_at(&self, hash: &Sha256Hash, height: BlockHeight) -> Option<&Address> {
+        match self.leases.get(hash) {
+            Some(lease) if !lease.is_active(height) => Some(&lease.owner),
+            _ => self.utxo_set.get(hash).map(|triangle| &triangle.owner),
         }
     }
 
-    /// Add a transaction to the mempool with validation
-    pub fn add_transaction(&mut self, tx: Transaction) -> Result<(), ChainError> {
-        let tx_hash = tx.hash();
+    /// Whether `signer` is approved to move the triangle for whoever owns it at `height`
+    pub fn is_approved(&self, hash: &Sha256Hash, signer: &Address, height: BlockHeight) -> bool {
+        self.approvals.get(hash).is_some_and(|approval| {
+            approval.delegate == *signer && self.owner_at(hash, height) == Some(&approval.owner)
+        })
+    }
 
-        // Check if transaction already exists
-        if self.transactions.contains_key(&tx_hash) {
-            return Err(ChainError::InvalidTransaction(
-                "Transaction already in mempool".to_string()
-            ));
+    /// Return triangles whose lease ends at or before `height` to their owners
+    pub fn expire_leases(&mut self, height: BlockHeight) {
+        let expired: Vec<Sha256Hash> = self.leases.iter()
+            .filter(|(_, lease)| !lease.is_active(height))
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            if let Some(lease) = self.leases.remove(&hash) {
+                // The triangle may have been spent while leased
+                let _ = self.set_owner(&hash, lease.owner);
+            }
         }
+    }
 
-        // Validate transaction before adding to mempool
-        match &tx {
-            Transaction::Transfer(transfer_tx) => {
-                // Validate signature before adding
-                transfer_tx.validate()?;
-            },
-            Transaction::Coinbase(_) => {
-                return Err(ChainError::InvalidTransaction(
-                    "Coinbase transactions cannot be added to mempool".to_string()
-                ));
-            },
-            Transaction::Subdivision(sub_tx) => {
-                // We can still validate the signature without state access, which is a cheap
-                // way to discard obviously invalid transactions.
-                sub_tx.validate_signature()?;
+    /// Check `tx` against open HTLCs, escrows, offers and leases for a block at
+    /// `height`: redemptions, settlements and acceptances must satisfy their
+    /// contract; transfers, subdivisions, locks, offers, inscriptions, deep
+    /// subdivisions, split transfers, leases, approvals and swap inputs must come
+    /// from the triangle's owner at that height, or for transfers and subdivisions
+    /// an approved delegate; nothing else may spend a locked triangle; and a
+    /// leased triangle may only be transferred or inscribed. Inputs not yet in
+    /// the state must be among `created`, the triangles made earlier in the same
+    /// block or by pending transactions, which say who owns them.
+    pub fn check_contracts(
+        &self,
+        tx: &Transaction,
+        height: BlockHeight,
+        created: &HashMap<Sha256Hash, &Triangle>,
+    ) -> Result<(), ChainError> {
+        match tx {
+            Transaction::HtlcRedeem(redeem_tx) => {
+                let contract = self.htlcs.get(&redeem_tx.input_hash).ok_or_else(|| {
+                    ChainError::InvalidTransaction(format!(
+                        "Triangle {} is not locked in an HTLC",
+                        hex::encode(redeem_tx.input_hash)
+                    ))
+                })?;
+                redeem_tx.validate_against_contract(contract, height)
+            }
+            Transaction::EscrowSettle(settle_tx) => {
+                let contract = self.escrows.get(&settle_tx.input_hash).ok_or_else(|| {
+                    ChainError::InvalidTransaction(format!(
+                        "Triangle {} is not in escrow",
+                        hex::encode(settle_tx.input_hash)
+                    ))
+                })?;
+                settle_tx.validate_against_contract(contract)
+            }
+            _ => {
+                if let Some(locked) = tx.inputs().into_iter().find(|input| self.is_locked(input)) {
+                    return Err(ChainError::InvalidTransaction(format!(
+                        "Triangle {} is locked in a contract",
+                        hex::encode(locked)
+                    )));
+                }
+                if !matches!(tx, Transaction::Transfer(_) | Transaction::BatchTransfer(_) | Transaction::Inscription(_)) {
+                    if let Some(leased) = tx.inputs().into_iter().find(|input| self.is_leased(input, height)) {
+                        return Err(ChainError::InvalidTransaction(format!(
+                            "Triangle {} is leased until height {}",
+                            hex::encode(leased),
+                            self.leases[&leased].expires_at
+                        )));
+                    }
+                }
+                if let Transaction::Accept(accept_tx) = tx {
+                    return self.check_acceptance(accept_tx);
+                }
+                if let Transaction::Lease(lease_tx) = tx {
+                    lease_tx.validate_at_height(height)?;
+                }
+                let may_delegate = matches!(tx, Transaction::Transfer(_) | Transaction::Subdivision(_));
+                for (input_hash, signer) in tx.claimed_owners() {
+                    let owner = self.owner_at(input_hash, height)
+                        .or_else(|| created.get(input_hash).map(|triangle| &triangle.owner))
+                        .ok_or(ChainError::UtxoMissing { hash: *input_hash })?;
+                    if owner == signer {
+                        continue;
+                    }
+                    if !may_delegate || !self.is_approved(input_hash, signer, height) {
+                        return Err(ChainError::InvalidTransaction(format!(
+                            "{} does not own triangle {}",
+                            signer, hex::encode(input_hash)
+                        )));
+                    }
+                    // A delegate splits the triangle for its owner, not for themselves
+                    if let Transaction::Subdivision(sub_tx) = tx {
+                        if sub_tx.children.iter().any(|child| child.owner != *owner) {
+                            return Err(ChainError::InvalidTransaction(format!(
+                                "Delegated subdivision must leave the children with {}",
+                                owner
+                            )));
+                        }
+                    }
+                }
+                Ok(())
             }
         }
+    }
 
-        // Check per-address limit to prevent spam
-        let sender_address = match &tx {
-            Transaction::Transfer(t) => Some(&t.sender),
-            Transaction::Subdivision(s) => Some(&s.owner_address),
-            Transaction::Coinbase(_) => None,
-        };
-
-        if let Some(sender) = sender_address {
-            let count = self.transactions.values()
-                .filter(|t| match t {
-                    Transaction::Transfer(t) => &t.sender == sender,
-                    Transaction::Subdivision(s) => &s.owner_address == sender,
-                    _ => false,
-                })
-                .count();
+    /// An acceptance must name the open offer on the listed triangle, which the
+    /// seller must still own, and pay with triangles the buyer owns whose total
+    /// area covers the asking price
+    fn check_acceptance(&self, tx: &AcceptTx) -> Result<(), ChainError> {
+        let offer = self.offers.get(&tx.input_hash)
+            .filter(|offer| offer.id == tx.offer_id)
+            .ok_or_else(|| ChainError::InvalidTransaction(format!(
+                "No open offer {} on triangle {}",
+                hex::encode(tx.offer_id),
+                hex::encode(tx.input_hash)
+            )))?;
+
+        let owner_of = |hash: &Sha256Hash| self.utxo_set.get(hash).map(|t| &t.owner)
+            .ok_or(ChainError::UtxoMissing { hash: *hash });
+        if *owner_of(&tx.input_hash)? != offer.seller {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Offer seller {} no longer owns triangle {}",
+                offer.seller, hex::encode(tx.input_hash)
+            )));
+        }
 
-            if count >= Self::MAX_PER_ADDRESS {
-                return Err(ChainError::InvalidTransaction(
-                    format!("Address has reached maximum mempool limit of {}", Self::MAX_PER_ADDRESS)
-                ));
+        let mut paid = 0.0;
+        for input in &tx.payment {
+            if *owner_of(input)? != tx.buyer {
+                return Err(ChainError::InvalidTransaction(format!(
+                    "Buyer {} does not own payment triangle {}",
+                    tx.buyer, hex::encode(input)
+                )));
             }
+            paid += self.utxo_set[input].area();
         }
-
-        // If mempool is full, evict lowest fee transaction
-        if self.transactions.len() >= Self::MAX_TRANSACTIONS {
-            self.evict_lowest_fee_transaction()?;
+        if paid < offer.price_area && !areas_match(paid, offer.price_area) {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Payment area {} is below the asking price {}",
+                paid, offer.price_area
+            )));
         }
 
-        self.transactions.insert(tx_hash, tx);
         Ok(())
     }
 
-    /// Evict the transaction with the lowest fee to make room for new ones
-    fn evict_lowest_fee_transaction(&mut self) -> Result<(), ChainError> {
-        if self.transactions.is_empty() {
-            return Ok(());
-        }
-
-        // Find transaction with lowest fee
-        let mut lowest_fee = u64::MAX;
-        let mut lowest_hash: Option<Sha256Hash> = None;
-
-        for (hash, tx) in &self.transactions {
-            let fee = match tx {
-                Transaction::Transfer(t) => t.fee,
-                Transaction::Subdivision(_) => 0, // Subdivisions don't have fees
-                Transaction::Coinbase(_) => 0,
-            };
+    fn record_nonce(&mut self, address: &Address, nonce: u64) {
+        let last = self.nonces.entry(address.clone()).or_insert(nonce);
+        *last = (*last).max(nonce);
+    }
 
-            if fee < lowest_fee {
-                lowest_fee = fee;
-                lowest_hash = Some(*hash);
-            }
+    /// Apply a subdivision transaction to the state
+    pub fn apply_subdivision(&mut self, tx: &SubdivisionTx) -> Result<(), ChainError> {
+        let parent = self.utxo_set.get(&tx.parent_hash)
+            .ok_or(ChainError::UtxoMissing { hash: tx.parent_hash })?;
+        if !parent.can_subdivide(1) {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Triangle {} is too small to subdivide",
+                hex::encode(tx.parent_hash)
+            )));
         }
 
-        if let Some(hash) = lowest_hash {
-            self.transactions.remove(&hash);
+        self.remove_triangle(&tx.parent_hash);
+
+        for child in &tx.children {
+            self.insert_triangle(child.hash(), child.clone());
         }
+        self.record_nonce(&tx.owner_address, tx.nonce);
 
         Ok(())
     }
 
-    /// Remove a transaction from the mempool
-    pub fn remove_transaction(&mut self, tx_hash: &Sha256Hash) -> Option<Transaction> {
-        self.transactions.remove(tx_hash)
-    }
+    /// Apply a deep subdivision, replacing the parent with all of its leaves
+    pub fn apply_deep_subdivision(&mut self, tx: &DeepSubdivisionTx) -> Result<(), ChainError> {
+        let parent = self.utxo_set.get(&tx.parent_hash)
+            .ok_or(ChainError::UtxoMissing { hash: tx.parent_hash })?;
+        if !parent.can_subdivide(tx.depth as u32) {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Triangle {} is too small to subdivide to depth {}",
+                hex::encode(tx.parent_hash),
+                tx.depth
+            )));
+        }
 
-    /// Get all transactions currently in the mempool
-    pub fn get_all_transactions(&self) -> Vec<Transaction> {
-        self.transactions.values().cloned().collect()
-    }
+        let parent = self.remove_triangle(&tx.parent_hash).expect("parent checked above");
+        for leaf in tx.leaves(&parent) {
+            self.insert_triangle(leaf.hash(), leaf);
+        }
+        self.record_nonce(&tx.owner_address, tx.nonce);
 
-    /// Get transactions ordered by fee (highest first) for mining prioritization
-    /// Returns up to `limit` transactions with the highest fees
-    pub fn get_transactions_by_fee(&self, limit: usize) -> Vec<Transaction> {
-        let mut txs: Vec<Transaction> = self.transactions.values().cloned().collect();
+        Ok(())
+    }
 
-        // Sort by fee in descending order (highest fee first)
-        txs.sort_by(|a, b| b.fee().cmp(&a.fee()));
+    /// Apply a split transfer, replacing the parent with leaves for the recipient and change
+    pub fn apply_split_transfer(&mut self, tx: &SplitTransferTx) -> Result<(), ChainError> {
+        let parent = self.utxo_set.get(&tx.parent_hash)
+            .ok_or(ChainError::UtxoMissing { hash: tx.parent_hash })?;
+        if !parent.can_subdivide(tx.depth as u32) {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Triangle {} is too small to subdivide to depth {}",
+                hex::encode(tx.parent_hash),
+                tx.depth
+            )));
+        }
 
-        // Return up to limit transactions
-        txs.into_iter().take(limit).collect()
-    }
+        let parent = self.remove_triangle(&tx.parent_hash).expect("parent checked above");
+        for leaf in tx.outputs(&parent) {
+            self.insert_triangle(leaf.hash(), leaf);
+        }
+        self.record_nonce(&tx.sender, tx.nonce);
 
-    /// Get a specific transaction by hash
-    pub fn get_transaction(&self, tx_hash: &Sha256Hash) -> Option<&Transaction> {
-        self.transactions.get(tx_hash)
+        Ok(())
     }
 
-    /// Remove multiple transactions (e.g., after they're included in a block)
-    pub fn remove_transactions(&mut self, tx_hashes: &[Sha256Hash]) {
-        for hash in tx_hashes {
-            self.transactions.remove(hash);
+    /// Apply a coinbase transaction to the state, creating a new reward triangle
+    /// for each output. Placement is derived from the block hash (see
+    /// `CoinbaseTx::reward_triangles`), so replaying a block always recreates
+    /// the same triangles.
+    pub fn apply_coinbase(
+        &mut self,
+        tx: &CoinbaseTx,
+        block_hash: &Sha256Hash,
+    ) -> Result<(), ChainError> {
+        let rewards = self.check_coinbase_placement(tx, block_hash)?;
+        for triangle in rewards {
+            self.insert_triangle(triangle.hash(), triangle);
         }
+        Ok(())
     }
 
-    /// Clear all transactions from the mempool
-    pub fn clear(&mut self) {
-        self.transactions.clear();
-    }
+    /// The triangles `tx` mints in the block `block_hash`, checked to be valid
+    /// and disjoint from every unspent triangle and from each other: area is
+    /// supply, so new area must not be carved out of existing area.
+    ///
+    /// Placement follows from the block hash, so a miner whose reward lands on
+    /// existing geometry has to keep grinding; with rewards spread over a
+    /// 65536x65536 grid of cells that is vanishingly rare.
+    pub fn check_coinbase_placement(
+        &self,
+        tx: &CoinbaseTx,
+        block_hash: &Sha256Hash,
+    ) -> Result<Vec<Triangle>, ChainError> {
+        let rewards = tx.reward_triangles(block_hash);
+        for (index, triangle) in rewards.iter().enumerate() {
+            if !triangle.is_valid() {
+                return Err(ChainError::InvalidTransaction(
+                    "Invalid reward area for coinbase transaction".to_string(),
+                ));
+            }
 
-    /// Get the number of pending transactions
-    pub fn len(&self) -> usize {
-        self.transactions.len()
-    }
+            let hash = triangle.hash();
+            if self.utxo_set.contains_key(&hash) {
+                return Err(ChainError::InvalidTransaction(format!(
+                    "Coinbase reward triangle {} already exists",
+                    hex::encode(hash)
+                )));
+            }
 
-    /// Check if mempool is empty
-    pub fn is_empty(&self) -> bool {
-        self.transactions.is_empty()
+            let overlapping = Bounds::of([triangle]).into_iter()
+                .flat_map(|extent| self.query_region(&extent))
+                .find(|(_, existing)| existing.intersects(triangle))
+                .map(|(existing, _)| *existing)
+                .or_else(|| rewards[..index].iter().find(|earlier| earlier.intersects(triangle)).map(Triangle::hash));
+            if let Some(existing) = overlapping {
+                return Err(ChainError::TriangleOverlap { triangle: hash, existing });
+            }
+        }
+        Ok(rewards)
     }
 
-    /// Validate all transactions in mempool against current state
-    /// Removes invalid transactions and returns count of removed transactions
-    pub fn validate_and_prune(&mut self, state: &TriangleState) -> usize {
-        let mut to_remove = Vec::new();
+    /// Apply every transaction in a block to the state, in order
+    pub fn apply_block_transactions(&mut self, block: &Block) -> Result<(), ChainError> {
+        self.expire_leases(block.header.height);
 
-        for (hash, tx) in self.transactions.iter() {
-            let is_valid = match tx {
+        for tx in block.transactions.get()? {
+            match tx {
                 Transaction::Subdivision(sub_tx) => {
-                    // Check if parent exists in UTXO set
-                    state.utxo_set.contains_key(&sub_tx.parent_hash) &&
-                    sub_tx.validate(state).is_ok()
+                    self.apply_subdivision(sub_tx)?;
                 },
-                Transaction::Transfer(transfer_tx) => {
-                    // Check if input exists in UTXO set
-                    state.utxo_set.contains_key(&transfer_tx.input_hash) &&
-                    transfer_tx.validate().is_ok()
+                Transaction::DeepSubdivision(deep_tx) => {
+                    self.apply_deep_subdivision(deep_tx)?;
                 },
-                Transaction::Coinbase(_) => {
-                    // Coinbase transactions shouldn't be in mempool
-                    false
+                Transaction::SplitTransfer(split_tx) => {
+                    self.apply_split_transfer(split_tx)?;
+                },
+                Transaction::Lease(lease_tx) => {
+                    self.set_owner(&lease_tx.input_hash, lease_tx.tenant.clone())?;
+                    self.leases.insert(lease_tx.input_hash, lease_tx.contract());
+                    self.record_nonce(&lease_tx.owner, lease_tx.nonce);
+                },
+                Transaction::Approve(approve_tx) => {
+                    if !self.utxo_set.contains_key(&approve_tx.input_hash) {
+                        return Err(ChainError::UtxoMissing { hash: approve_tx.input_hash });
+                    }
+                    match approve_tx.approval() {
+                        Some(approval) => self.approvals.insert(approve_tx.input_hash, approval),
+                        None => self.approvals.remove(&approve_tx.input_hash),
+                    };
+                    self.record_nonce(&approve_tx.owner, approve_tx.nonce);
+                    // Approving doesn't move the triangle, so its offer stands
+                    continue;
+                },
+                Transaction::Swap(swap_tx) => {
+                    // Check every input first so a bad swap leaves the state untouched
+                    if let Some(missing) = swap_tx.inputs.iter().find(|i| !self.utxo_set.contains_key(&i.input_hash)) {
+                        return Err(ChainError::UtxoMissing { hash: missing.input_hash });
+                    }
+                    for output in &swap_tx.outputs {
+                        self.set_owner(&output.input_hash, output.new_owner.clone())?;
+                    }
+                },
+                Transaction::Coinbase(cb_tx) => {
+                    self.apply_coinbase(cb_tx, &block.hash)?;
+                },
+                Transaction::Transfer(transfer_tx) => {
+                    self.set_owner(&transfer_tx.input_hash, transfer_tx.new_owner.clone())?;
+                    self.record_nonce(&transfer_tx.sender, transfer_tx.nonce);
+                },
+                Transaction::BatchTransfer(batch_tx) => {
+                    // Check every input first so a bad batch leaves the state untouched
+                    if let Some(missing) = batch_tx.transfers.iter().find(|t| !self.utxo_set.contains_key(&t.input_hash)) {
+                        return Err(ChainError::UtxoMissing { hash: missing.input_hash });
+                    }
+                    for transfer in &batch_tx.transfers {
+                        self.set_owner(&transfer.input_hash, transfer.new_owner.clone())?;
+                    }
+                    self.record_nonce(&batch_tx.sender, batch_tx.nonce);
+                },
+                Transaction::HtlcLock(lock_tx) => {
+                    if !self.utxo_set.contains_key(&lock_tx.input_hash) {
+                        return Err(ChainError::UtxoMissing { hash: lock_tx.input_hash });
+                    }
+                    self.htlcs.insert(lock_tx.input_hash, lock_tx.contract());
+                    self.record_nonce(&lock_tx.sender, lock_tx.nonce);
+                },
+                Transaction::HtlcRedeem(redeem_tx) => {
+                    let contract = self.htlcs.remove(&redeem_tx.input_hash).ok_or_else(|| ChainError::TriangleNotFound(
+                        format!("No HTLC on triangle {}", hex::encode(redeem_tx.input_hash))
+                    ))?;
+                    self.set_owner(&redeem_tx.input_hash, redeem_tx.new_owner(&contract).clone())?;
+                    self.record_nonce(&redeem_tx.redeemer, redeem_tx.nonce);
+                },
+                Transaction::EscrowLock(lock_tx) => {
+                    if !self.utxo_set.contains_key(&lock_tx.input_hash) {
+                        return Err(ChainError::UtxoMissing { hash: lock_tx.input_hash });
+                    }
+                    self.escrows.insert(lock_tx.input_hash, lock_tx.contract());
+                    self.record_nonce(&lock_tx.seller, lock_tx.nonce);
+                },
+                Transaction::EscrowSettle(settle_tx) => {
+                    let contract = self.escrows.remove(&settle_tx.input_hash).ok_or_else(|| ChainError::TriangleNotFound(
+                        format!("No escrow on triangle {}", hex::encode(settle_tx.input_hash))
+                    ))?;
+                    self.set_owner(&settle_tx.input_hash, settle_tx.new_owner(&contract).clone())?;
+                },
+                Transaction::Offer(offer_tx) => {
+                    if !self.utxo_set.contains_key(&offer_tx.input_hash) {
+                        return Err(ChainError::UtxoMissing { hash: offer_tx.input_hash });
+                    }
+                    self.offers.insert(offer_tx.input_hash, offer_tx.offer());
+                    self.record_nonce(&offer_tx.seller, offer_tx.nonce);
+                    // A new offer replaces the old one rather than lapsing below
+                    continue;
+                },
+                Transaction::Accept(accept_tx) => {
+                    let offer = self.offers.remove(&accept_tx.input_hash).ok_or_else(|| ChainError::TriangleNotFound(
+                        format!("No offer on triangle {}", hex::encode(accept_tx.input_hash))
+                    ))?;
+                    let inputs = tx.inputs();
+                    if let Some(missing) = inputs.iter().find(|input| !self.utxo_set.contains_key(*input)) {
+                        return Err(ChainError::UtxoMissing { hash: *missing });
+                    }
+                    for input in &inputs {
+                        let owner = if *input == accept_tx.input_hash {
+                            accept_tx.buyer.clone()
+                        } else {
+                            offer.seller.clone()
+                        };
+                        self.set_owner(input, owner)?;
+                    }
+                    self.record_nonce(&accept_tx.buyer, accept_tx.nonce);
+                },
+                Transaction::Inscription(inscription_tx) => {
+                    if !self.utxo_set.contains_key(&inscription_tx.input_hash) {
+                        return Err(ChainError::UtxoMissing { hash: inscription_tx.input_hash });
+                    }
+                    self.record_nonce(&inscription_tx.owner, inscription_tx.nonce);
                 }
-            };
-
-            if !is_valid {
-                to_remove.push(*hash);
             }
-        }
 
-        let removed_count = to_remove.len();
-        for hash in to_remove {
-            self.transactions.remove(&hash);
+            // Using a listed triangle any other way, even inscribing it, withdraws its
+            // offer; moving or splitting it also ends any approval
+            for input in tx.inputs() {
+                self.offers.remove(&input);
+                if !matches!(tx, Transaction::Inscription(_)) {
+                    self.approvals.remove(&input);
+                }
+            }
         }
-
-        removed_count
+        Ok(())
     }
 }
 
-/// The blockchain itself
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct Blockchain {
-    pub blocks: Vec<Block>,
-    pub block_index: HashMap<Sha256Hash, Block>,
-    pub forks: HashMap<Sha256Hash, Block>,
-    pub state: TriangleState,
-    pub difficulty: u64,
-    pub mempool: Mempool,
+/// State containing only the genesis triangle
+fn genesis_state() -> TriangleState {
+    let mut state = TriangleState::new();
+    let genesis = genesis_triangle();
+    state.insert_triangle(genesis.hash(), genesis);
+    state
 }
 
-// Bitcoin-like parameters for Sierpinski Triangle Blockchain
-// Target: 1 block every 60 seconds = 1,440 blocks/day = ~525,600 blocks/year
-
-/// Difficulty adjusts every 2,016 blocks (like Bitcoin) ~1.4 days at 1 minute blocks
-const DIFFICULTY_ADJUSTMENT_WINDOW: BlockHeight = 2016;
-
-/// Target block time: 60 seconds (1 minute)
-const TARGET_BLOCK_TIME_SECONDS: i64 = 60;
-
-/// Initial mining reward (in area units) - represents triangle area
-const INITIAL_MINING_REWARD: u64 = 1000;
+fn merkle_parent(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
 
-/// Halving interval - reward halves every 210,000 blocks (~4 years at 1 minute blocks)
-/// This matches Bitcoin's ~4 year halving cycle
-const REWARD_HALVING_INTERVAL: BlockHeight = 210_000;
+/// Evidence that a transaction is in a block, checked against the block's
+/// header alone, so a light client needn't download the transactions
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MerkleProof {
+    pub txid: Sha256Hash,
+    /// Position of the transaction in the block
+    pub index: usize,
+    /// Sibling hashes from the leaf up to the root, as `Block::merkle_branch`
+    pub branch: Vec<Sha256Hash>,
+}
 
-/// Maximum number of halvings before reward becomes 0 (64 halvings)
-const MAX_HALVINGS: u64 = 64;
+/// Whether `proof` leads from its transaction to the header's merkle root
+pub fn verify_merkle_proof(header: &BlockHeader, proof: &MerkleProof) -> bool {
+    Block::merkle_root_from_branch(proof.txid, proof.index, &proof.branch) == header.merkle_root
+}
 
-/// Calculate maximum supply: sum of geometric series
-/// Max supply = INITIAL_REWARD * HALVING_INTERVAL * (1 + 1/2 + 1/4 + ... ≈ 2)
-/// = 1000 * 210,000 * 2 = 420,000,000 area units
-pub const MAX_SUPPLY: u64 = INITIAL_MINING_REWARD * REWARD_HALVING_INTERVAL * 2;
+/// Represents a block header with metadata
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BlockHeader {
+    pub height: BlockHeight,
+    pub previous_hash: Sha256Hash,
+    pub timestamp: i64,
+    /// Proof-of-work target in compact form (see `difficulty::compact_to_target`)
+    pub bits: u32,
+    pub nonce: u64,
+    pub merkle_root: Sha256Hash,
+}
 
-impl Blockchain {
-    pub fn new() -> Self {
-        let mut state = TriangleState::new();
-        let genesis = genesis_triangle();
-        let genesis_hash = genesis.hash();
-        state.utxo_set.insert(genesis_hash, genesis);
+impl BlockHeader {
+    /// Hash of the header's canonical encoding
+    pub fn calculate_hash(&self) -> Sha256Hash {
+        codec::hash(self)
+    }
 
-        let genesis_block = Block {
-            header: BlockHeader {
-                height: 0,
-                previous_hash: [0; 32],
-                timestamp: Utc::now().timestamp(),
-                difficulty: 2,
-                nonce: 0,
-                merkle_root: [0; 32],
-            },
-            hash: [0; 32],
-            transactions: vec![],
-        };
+    /// Checks that the header's own hash satisfies its declared target.
+    pub fn verify_proof_of_work(&self) -> bool {
+        Sha256Pow.meets_target(&self.calculate_hash(), self.bits)
+    }
+}
 
-        let mut block_index = HashMap::new();
-        block_index.insert(genesis_block.hash, genesis_block.clone());
+/// Most headers a `HeaderChain` keeps off its best chain. Past it the lowest
+/// are dropped, down to half as many, so stale and competing branches can't
+/// grow it without bound.
+const MAX_SIDE_HEADERS: usize = 10_000;
+
+/// Header-only view of the chain, used for headers-first synchronization.
+/// Headers are validated and stored independently of their full blocks, so a
+/// node can learn the shape of a peer's chain before downloading any transactions.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct HeaderChain {
+    headers: HashMap<Sha256Hash, BlockHeader>,
+    /// Work of the branch each header ends, from the first header stored
+    #[serde(default)]
+    work: HashMap<Sha256Hash, Work>,
+    /// Easiest target retargeting may reach: the genesis header's
+    #[serde(default)]
+    pow_limit_bits: u32,
+    best_hash: Sha256Hash,
+    best_height: BlockHeight,
+}
 
-        Blockchain {
-            blocks: vec![genesis_block],
-            block_index,
-            forks: HashMap::new(),
-            state,
-            difficulty: 2,
-            mempool: Mempool::new(),
-        }
+impl HeaderChain {
+    /// Create a header chain rooted at the given genesis header
+    pub fn new(genesis_hash: Sha256Hash, genesis: BlockHeader) -> Self {
+        let mut chain = HeaderChain::default();
+        chain.insert(genesis_hash, genesis);
+        chain
     }
 
-    /// Recalculate difficulty based on recent block times
-    /// This is useful when loading an old chain or after parameter changes
-    pub fn recalculate_difficulty(&mut self) {
-        // Need at least 10 blocks for a meaningful difficulty calculation
-        // (Too few blocks gives wildly inaccurate results)
-        if self.blocks.len() < 11 {
-            return;
+    /// Build a header chain from an already validated list of blocks
+    pub fn from_blocks(blocks: &[Block]) -> Self {
+        let mut chain = HeaderChain::default();
+        for block in blocks {
+            chain.insert(block.hash, block.header.clone());
         }
+        chain
+    }
 
-        // If we don't have enough blocks for a full window, use what we have (min 10 blocks)
-        let window_size = (self.blocks.len() - 1).min(DIFFICULTY_ADJUSTMENT_WINDOW as usize).max(10);
+    /// Check whether a header with this hash is known
+    pub fn contains(&self, hash: &Sha256Hash) -> bool {
+        self.headers.contains_key(hash)
+    }
 
-        let start_idx = self.blocks.len() - window_size - 1;
-        let window = &self.blocks[start_idx..];
+    /// Get a header by its hash
+    pub fn get(&self, hash: &Sha256Hash) -> Option<&BlockHeader> {
+        self.headers.get(hash)
+    }
 
-        let actual_time = window.last().unwrap().header.timestamp - window.first().unwrap().header.timestamp;
-        if actual_time <= 0 {
-            return;
-        }
+    /// Work of the branch ending at header `hash`, if it is known
+    pub fn chain_work(&self, hash: &Sha256Hash) -> Option<Work> {
+        self.work.get(hash).copied()
+    }
 
-        let expected_time = (window_size as i64) * TARGET_BLOCK_TIME_SECONDS;
-        let adjustment_factor = expected_time as f64 / actual_time as f64;
+    /// Hash of the known header with the most work behind it
+    pub fn best_hash(&self) -> Sha256Hash {
+        self.best_hash
+    }
 
-        const MIN_ADJUSTMENT: f64 = 0.25;
-        const MAX_ADJUSTMENT: f64 = 4.0;
-        let clamped_factor = adjustment_factor.max(MIN_ADJUSTMENT).min(MAX_ADJUSTMENT);
+    /// Height of the known header with the most work behind it
+    pub fn best_height(&self) -> BlockHeight {
+        self.best_height
+    }
 
-        let old_difficulty = self.difficulty;
-        let new_difficulty = ((self.difficulty as f64 * clamped_factor).round() as u64).max(1);
+    /// Number of known headers
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
 
-        if old_difficulty != new_difficulty {
-            self.difficulty = new_difficulty;
-            let avg_block_time = actual_time as f64 / window_size as f64;
-            println!("🔄 Recalculated difficulty: {} -> {} (avg: {:.1}s, target: {}s, window: {} blocks)",
-                     old_difficulty, new_difficulty, avg_block_time, TARGET_BLOCK_TIME_SECONDS, window_size);
-        }
+    /// Check if no headers are known
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
     }
 
-    pub fn validate_block(&self, block: &Block) -> Result<(), ChainError> {
-        if !self.block_index.contains_key(&block.header.previous_hash) {
+    /// Validate a header against its parent without touching any block data,
+    /// checking its work with `pow`
+    pub fn validate_header(&self, header: &BlockHeader, pow: &dyn PowEngine) -> Result<(), ChainError> {
+        let parent = self.headers.get(&header.previous_hash)
+            .ok_or(ChainError::InvalidBlockLinkage)?;
+
+        if header.height != parent.height + 1 {
             return Err(ChainError::InvalidBlockLinkage);
         }
 
-        let parent_block = self.block_index.get(&block.header.previous_hash).unwrap();
-
-        if block.header.height != parent_block.header.height + 1 {
-            return Err(ChainError::InvalidBlockLinkage);
+        let median_time_past = self.median_time_past(&header.previous_hash);
+        if header.timestamp <= median_time_past {
+            return Err(ChainError::TimestampTooOld { timestamp: header.timestamp, median_time_past });
         }
 
-        // Validate timestamp is greater than parent's timestamp
-        if block.header.timestamp <= parent_block.header.timestamp {
-            return Err(ChainError::InvalidTransaction(
-                "Block timestamp must be greater than parent timestamp".to_string()
-            ));
+        let drift = header.timestamp - (Utc::now().timestamp() + MAX_FUTURE_TIMESTAMP_DRIFT);
+        if drift > 0 {
+            return Err(ChainError::TimestampTooFarInFuture { drift });
         }
 
-        // Validate timestamp is not too far in the future (allow 2 hours of clock drift)
-        const MAX_FUTURE_TIMESTAMP_DRIFT: i64 = 2 * 3600; // 2 hours in seconds
-        let current_time = Utc::now().timestamp();
-        if block.header.timestamp > current_time + MAX_FUTURE_TIMESTAMP_DRIFT {
-            return Err(ChainError::InvalidTransaction(
-                "Block timestamp is too far in the future".to_string()
-            ));
+        let expected = self.next_bits(parent);
+        if header.bits != expected {
+            return Err(ChainError::UnexpectedDifficulty { expected, found: header.bits });
         }
 
-        if !block.verify_proof_of_work() {
+        if !pow.meets_target(&header.calculate_hash(), header.bits) {
             return Err(ChainError::InvalidProofOfWork);
         }
 
-        let calculated_merkle = Block::calculate_merkle_root(&block.transactions);
-        if block.header.merkle_root != calculated_merkle {
-            return Err(ChainError::InvalidMerkleRoot);
-        }
+        Ok(())
+    }
 
-        // Validate coinbase transaction rules
-        let mut coinbase_count = 0;
-        let mut coinbase_reward = 0u64;
-        for (i, tx) in block.transactions.iter().enumerate() {
-            if let Transaction::Coinbase(coinbase_tx) = tx {
-                coinbase_count += 1;
-                coinbase_reward = coinbase_tx.reward_area;
-                // Coinbase must be the first transaction
-                if i != 0 {
-                    return Err(ChainError::InvalidTransaction(
-                        "Coinbase transaction must be the first transaction in the block".to_string()
-                    ));
-                }
+    /// The bits a header extending `parent` must carry
+    pub fn next_bits(&self, parent: &BlockHeader) -> u32 {
+        next_bits(parent, self.pow_limit_bits, |hash| self.headers.get(hash))
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_PAST_WINDOW` headers ending at `tip`
+    pub fn median_time_past(&self, tip: &Sha256Hash) -> i64 {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_PAST_WINDOW);
+        let mut current = self.headers.get(tip);
+        while let Some(header) = current {
+            timestamps.push(header.timestamp);
+            if timestamps.len() == MEDIAN_TIME_PAST_WINDOW || header.height == 0 {
+                break;
             }
+            current = self.headers.get(&header.previous_hash);
         }
+        median_timestamp(timestamps)
+    }
 
-        // Exactly one coinbase transaction per block (or zero for genesis)
-        if block.header.height > 0 && coinbase_count != 1 {
-            return Err(ChainError::InvalidTransaction(
-                format!("Block must contain exactly one coinbase transaction, found {}", coinbase_count)
-            ));
+    /// Make `hash` the best tip again, such as after a heavier branch turned
+    /// out to be invalid
+    pub fn reset_best(&mut self, hash: Sha256Hash) {
+        if let Some(header) = self.headers.get(&hash) {
+            self.best_hash = hash;
+            self.best_height = header.height;
         }
+    }
 
-        // Validate coinbase reward doesn't exceed block reward + fees
-        if block.header.height > 0 {
-            let block_reward = Self::calculate_block_reward(block.header.height);
-            let total_fees = Self::calculate_total_fees(&block.transactions);
-
-            // Use saturating_add to prevent integer overflow
-            let max_reward = block_reward.saturating_add(total_fees);
+    /// Store a header, making it the best tip if its branch has more work than
+    /// the current one's. On a tie the branch seen first stays.
+    pub fn insert(&mut self, hash: Sha256Hash, header: BlockHeader) {
+        let parent_work = self.chain_work(&header.previous_hash).unwrap_or_default();
+        let work = add_work(&parent_work, &block_work(header.bits));
+        self.insert_with_work(hash, header, work);
+    }
 
-            if coinbase_reward > max_reward {
-                return Err(ChainError::InvalidTransaction(
-                    format!("Coinbase reward {} exceeds maximum allowed {} (block reward: {}, fees: {})",
-                        coinbase_reward, max_reward, block_reward, total_fees)
-                ));
-            }
+    /// Store a header whose branch's work is already known, such as from the
+    /// database, rather than adding it up again
+    pub fn insert_with_work(&mut self, hash: Sha256Hash, header: BlockHeader, work: Work) {
+        if header.height == 0 {
+            self.pow_limit_bits = header.bits;
         }
-
-        for tx in block.transactions.iter() {
-            match tx {
-                Transaction::Subdivision(tx) => {
-                    if !self.state.utxo_set.contains_key(&tx.parent_hash) {
-                        return Err(ChainError::InvalidTransaction(
-                            format!("Parent triangle {} not in UTXO set", hex::encode(tx.parent_hash))
-                        ));
-                    }
-                    tx.validate(&self.state)?;
-                },
-                Transaction::Coinbase(cb_tx) => {
-                    cb_tx.validate()?;
-                },
-                Transaction::Transfer(tx) => {
-                    if !self.state.utxo_set.contains_key(&tx.input_hash) {
-                        return Err(ChainError::InvalidTransaction(
-                            format!("Transfer input {} not in UTXO set", hex::encode(tx.input_hash))
-                        ));
-                    }
-                    tx.validate()?;
-                },
-            }
+        if self.headers.is_empty() || Some(work) > self.chain_work(&self.best_hash) {
+            self.best_hash = hash;
+            self.best_height = header.height;
         }
+        self.work.insert(hash, work);
+        self.headers.insert(hash, header);
 
-        Ok(())
+        // Every header from genesis to the best one is on the best chain
+        if self.headers.len().saturating_sub(self.best_height as usize + 1) > MAX_SIDE_HEADERS {
+            self.prune_side_branches();
+        }
     }
 
-    pub fn apply_block(&mut self, valid_block: Block) -> Result<(), ChainError> {
-        self.validate_block(&valid_block)?;
+    /// Drop the lowest headers off the best chain, keeping `MAX_SIDE_HEADERS / 2`
+    fn prune_side_branches(&mut self) {
+        let mut best_chain = HashSet::with_capacity(self.best_height as usize + 1);
+        let mut current = self.headers.get_key_value(&self.best_hash);
+        while let Some((hash, header)) = current {
+            best_chain.insert(*hash);
+            current = if header.height == 0 { None } else { self.headers.get_key_value(&header.previous_hash) };
+        }
 
-        let parent_hash = valid_block.header.previous_hash;
-        let last_block_hash = self.blocks.last().unwrap().hash;
+        let mut side: Vec<(BlockHeight, Sha256Hash)> = self.headers.iter()
+            .filter(|(hash, _)| !best_chain.contains(*hash))
+            .map(|(hash, header)| (header.height, *hash))
+            .collect();
+        side.sort_unstable();
+        for (_, hash) in side.iter().take(side.len().saturating_sub(MAX_SIDE_HEADERS / 2)) {
+            self.headers.remove(hash);
+            self.work.remove(hash);
+        }
+    }
+}
 
-        // Case 1: The new block extends the main chain
-        if parent_hash == last_block_hash {
-            // Collect transaction hashes before applying
-            let tx_hashes: Vec<Sha256Hash> = valid_block.transactions.iter()
-                .map(|tx| tx.hash())
-                .collect();
+/// A block's transactions. Blocks loaded from the database keep them in
+/// their canonical encoding until first used, so loading a long chain doesn't
+/// decode every historical transaction up front.
+#[derive(Default)]
+pub struct BlockTransactions {
+    decoded: OnceLock<Vec<Transaction>>,
+    /// Each transaction's encoding, shared between clones until decoded
+    encoded: Arc<Vec<Vec<u8>>>,
+}
 
-            for tx in valid_block.transactions.iter() {
-                match tx {
-                    Transaction::Subdivision(sub_tx) => {
-                        self.state.apply_subdivision(sub_tx)?;
-                    },
-                    Transaction::Coinbase(cb_tx) => {
-                        self.state.apply_coinbase(cb_tx, valid_block.header.height)?;
-                    },
-                    Transaction::Transfer(tx) => {
-                        let triangle = self.state.utxo_set.get_mut(&tx.input_hash)
-                            .ok_or_else(|| ChainError::TriangleNotFound(
-                                format!("Transfer input {} missing from UTXO set", hex::encode(tx.input_hash))
-                            ))?;
-                        triangle.owner = tx.new_owner.clone();
-                    }
-                }
-            }
+impl BlockTransactions {
+    /// Transactions from their canonical encodings, decoded on first use
+    pub fn from_encoded(encoded: Vec<Vec<u8>>) -> Self {
+        BlockTransactions { decoded: OnceLock::new(), encoded: Arc::new(encoded) }
+    }
 
-            let block_height = valid_block.header.height;
-            self.blocks.push(valid_block.clone());
-            self.block_index.insert(valid_block.hash, valid_block.clone());
+    /// Whether the transactions have been decoded yet
+    pub fn is_decoded(&self) -> bool {
+        self.decoded.get().is_some()
+    }
 
-            // Only adjust difficulty every DIFFICULTY_ADJUSTMENT_WINDOW blocks to prevent oscillation
-            // Adjust after accumulating enough blocks (at multiples of the window)
-            if block_height > 0 && block_height % DIFFICULTY_ADJUSTMENT_WINDOW == 0 {
-                self.adjust_difficulty();
-            }
+    /// The transactions, decoding them if they haven't been yet. Fails if a
+    /// stored encoding has been corrupted.
+    pub fn get(&self) -> Result<&Vec<Transaction>, ChainError> {
+        if let Some(transactions) = self.decoded.get() {
+            return Ok(transactions);
+        }
+        let transactions = self.encoded.iter()
+            .map(|bytes| codec::decode(bytes))
+            .collect::<Result<Vec<Transaction>, ChainError>>()
+            .map_err(|e| ChainError::DatabaseError(format!("Stored transaction failed to decode: {}", e)))?;
+        Ok(self.decoded.get_or_init(|| transactions))
+    }
 
-            self.mempool.remove_transactions(&tx_hashes);
-            self.mempool.validate_and_prune(&self.state);
+    /// The transactions to change, decoding them first if needed
+    pub fn get_mut(&mut self) -> Result<&mut Vec<Transaction>, ChainError> {
+        self.get()?;
+        // Once changed, the stored encodings no longer match
+        self.encoded = Arc::default();
+        Ok(self.decoded.get_mut().expect("decoded just above"))
+    }
+}
 
-        } else if self.block_index.contains_key(&parent_hash) {
-            // Case 2: The new block creates a fork
-            println!("🍴 Fork detected at height {}", valid_block.header.height);
-            self.forks.insert(valid_block.hash, valid_block.clone());
-            self.block_index.insert(valid_block.hash, valid_block.clone());
+impl From<Vec<Transaction>> for BlockTransactions {
+    fn from(transactions: Vec<Transaction>) -> Self {
+        BlockTransactions { decoded: OnceLock::from(transactions), encoded: Arc::default() }
+    }
+}
 
-            // Check if the fork is longer than the main chain
-            let mut fork_chain = vec![valid_block.clone()];
-            let mut current_hash = valid_block.header.previous_hash;
-            while let Some(block) = self.forks.get(&current_hash) {
-                fork_chain.push(block.clone());
-                current_hash = block.header.previous_hash;
-            }
+impl Clone for BlockTransactions {
+    fn clone(&self) -> Self {
+        match self.decoded.get() {
+            Some(transactions) => transactions.clone().into(),
+            None => BlockTransactions { decoded: OnceLock::new(), encoded: self.encoded.clone() },
+        }
+    }
+}
 
-            if fork_chain.len() > self.blocks.len() {
-                println!("⚠️  Switching to a longer fork! Rebuilding state...");
+impl std::fmt::Debug for BlockTransactions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.get() {
+            Ok(transactions) => transactions.fmt(f),
+            Err(e) => write!(f, "<{}>", e),
+        }
+    }
+}
 
-                // Reorganize the chain - build complete chain from genesis
-                let mut new_blocks = Vec::new();
-                let mut current_block = valid_block.clone();
+impl serde::Serialize for BlockTransactions {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.get().map_err(serde::ser::Error::custom)?.serialize(serializer)
+    }
+}
 
-                while let Some(block) = self.block_index.get(&current_block.header.previous_hash) {
-                    new_blocks.push(current_block);
-                    current_block = block.clone();
-                    if current_block.header.height == 0 {
-                        new_blocks.push(current_block);
-                        break;
-                    }
-                }
-                new_blocks.reverse();
+impl<'de> serde::Deserialize<'de> for BlockTransactions {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::from)
+    }
+}
 
-                // CRITICAL: Rebuild the entire UTXO state from scratch
-                self.state = TriangleState::new();
-                let genesis = genesis_triangle();
-                let genesis_hash = genesis.hash();
-                self.state.utxo_set.insert(genesis_hash, genesis);
-
-                // Replay all transactions to rebuild state
-                for block in &new_blocks[1..] { // Skip genesis
-                    for tx in &block.transactions {
-                        match tx {
-                            Transaction::Subdivision(sub_tx) => {
-                                self.state.apply_subdivision(sub_tx)?;
-                            },
-                            Transaction::Coinbase(cb_tx) => {
-                                self.state.apply_coinbase(cb_tx, block.header.height)?;
-                            },
-                            Transaction::Transfer(transfer_tx) => {
-                                let triangle = self.state.utxo_set.get_mut(&transfer_tx.input_hash)
-                                    .ok_or_else(|| ChainError::TriangleNotFound(
-                                        format!("Transfer input {} missing from UTXO set", hex::encode(transfer_tx.input_hash))
-                                    ))?;
-                                triangle.owner = transfer_tx.new_owner.clone();
-                            }
-                        }
-                    }
-                }
+/// A block in the blockchain
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub hash: Sha256Hash,
+    pub transactions: BlockTransactions,
+}
 
-                self.blocks = new_blocks;
-                self.mempool.validate_and_prune(&self.state);
+impl Block {
+    pub fn new(
+        height: BlockHeight,
+        previous_hash: Sha256Hash,
+        bits: u32,
+        transactions: Vec<Transaction>,
+    ) -> Self {
+        let timestamp = Utc::now().timestamp();
+        let merkle_root = Self::calculate_merkle_root(&transactions);
 
-                println!("✅ Fork reorganization complete - state rebuilt");
+        let header = BlockHeader {
+            height,
+            previous_hash,
+            timestamp,
+            bits,
+            nonce: 0,
+            merkle_root,
+        };
+
+        Block {
+            header,
+            hash: [0; 32], // Will be calculated by the miner
+            transactions: transactions.into(),
+        }
+    }
+
+    pub fn calculate_hash(&self) -> Sha256Hash {
+        self.header.calculate_hash()
+    }
+
+    /// Bump the coinbase's extra nonce and recompute the merkle root, so the
+    /// miner can search the header nonces again. Fails if there's no coinbase.
+    pub fn roll_extra_nonce(&mut self) -> Result<(), ChainError> {
+        let transactions = self.transactions.get_mut()?;
+        let coinbase = transactions.iter_mut()
+            .find_map(|tx| match tx {
+                Transaction::Coinbase(cb_tx) => Some(cb_tx),
+                _ => None,
+            })
+            .ok_or(ChainError::InvalidProofOfWork)?;
+        coinbase.extra_nonce = coinbase.extra_nonce.wrapping_add(1);
+        self.header.merkle_root = Self::calculate_merkle_root(transactions);
+        Ok(())
+    }
+
+    pub fn calculate_merkle_root(transactions: &[Transaction]) -> Sha256Hash {
+        if transactions.is_empty() {
+            return [0; 32];
+        }
+
+        let mut hashes: Vec<[u8; 32]> = transactions.iter().map(|tx| tx.hash()).collect();
+
+        while hashes.len() > 1 {
+            if !hashes.len().is_multiple_of(2) {
+                hashes.push(*hashes.last().unwrap());
+            }
+
+            hashes = hashes
+                .chunks(2)
+                .map(|chunk| merkle_parent(&chunk[0], &chunk[1]))
+                .collect();
+        }
+
+        hashes[0]
+    }
+
+    /// The hashes needed to recompute the merkle root from the transaction at
+    /// `index`: its sibling at each level of the tree, bottom up
+    pub fn merkle_branch(transactions: &[Transaction], mut index: usize) -> Vec<Sha256Hash> {
+        let mut hashes: Vec<Sha256Hash> = transactions.iter().map(|tx| tx.hash()).collect();
+        let mut branch = Vec::new();
+
+        while hashes.len() > 1 {
+            if !hashes.len().is_multiple_of(2) {
+                hashes.push(*hashes.last().unwrap());
+            }
+            branch.push(hashes[index ^ 1]);
+
+            hashes = hashes
+                .chunks(2)
+                .map(|chunk| merkle_parent(&chunk[0], &chunk[1]))
+                .collect();
+            index /= 2;
+        }
+
+        branch
+    }
+
+    /// The merkle root of a tree whose leaf at `index` is `leaf`, given that
+    /// leaf's `merkle_branch`
+    pub fn merkle_root_from_branch(leaf: Sha256Hash, mut index: usize, branch: &[Sha256Hash]) -> Sha256Hash {
+        branch.iter().fold(leaf, |node, sibling| {
+            let parent = if index.is_multiple_of(2) {
+                merkle_parent(&node, sibling)
+            } else {
+                merkle_parent(sibling, &node)
+            };
+            index /= 2;
+            parent
+        })
+    }
+
+    /// Proof that the transaction `txid` is in the block, if it is
+    pub fn merkle_proof(&self, txid: &Sha256Hash) -> Result<Option<MerkleProof>, ChainError> {
+        let transactions = self.transactions.get()?;
+        Ok(transactions.iter().position(|tx| tx.hash() == *txid)
+            .map(|index| MerkleProof { txid: *txid, index, branch: Self::merkle_branch(transactions, index) }))
+    }
+
+    /// Serialized size of the block in bytes, used for the block size limit
+    pub fn serialized_size(&self) -> usize {
+        codec::encoded_len(self)
+    }
+
+    /// Checks the block's hash against its target under the real consensus
+    /// rule, `Sha256Pow`; a chain checks with its own engine instead
+    pub fn verify_proof_of_work(&self) -> bool {
+        Sha256Pow.meets_target(&self.hash, self.header.bits)
+    }
+}
+
+/// Why the mempool turned a transaction away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    /// Already in the mempool
+    Duplicate,
+    /// A transaction version this node doesn't know
+    UnsupportedVersion,
+    /// Bad signature or malformed fields
+    Invalid,
+    /// Coinbases only appear in blocks
+    Coinbase,
+    /// The lock time doesn't allow it in the next block
+    NonFinal,
+    /// Spends inputs of more than one pending transaction
+    TooManyConflicts,
+    /// Conflicts with a pending transaction without outbidding it
+    InsufficientFee,
+    /// The sender already has a pending transaction with this nonce
+    NonceInUse,
+    /// The nonce is not above the sender's last confirmed one
+    StaleNonce,
+    /// The sender has too many pending transactions
+    AddressLimit,
+    /// The mempool is full and the transaction pays no better than the
+    /// lowest fee rate in it
+    MempoolFull,
+    /// Breaks a lock, lease, approval or ownership rule of the current state
+    ContractViolation,
+}
+
+impl RejectReason {
+    /// Stable machine-readable code for callers
+    pub fn code(&self) -> &'static str {
+        match self {
+            RejectReason::Duplicate => "duplicate",
+            RejectReason::UnsupportedVersion => "unsupported-version",
+            RejectReason::Invalid => "invalid",
+            RejectReason::Coinbase => "coinbase",
+            RejectReason::NonFinal => "non-final",
+            RejectReason::TooManyConflicts => "too-many-conflicts",
+            RejectReason::InsufficientFee => "insufficient-fee",
+            RejectReason::NonceInUse => "nonce-in-use",
+            RejectReason::StaleNonce => "stale-nonce",
+            RejectReason::AddressLimit => "address-limit",
+            RejectReason::MempoolFull => "mempool-full",
+            RejectReason::ContractViolation => "contract-violation",
+        }
+    }
+}
+
+/// Outcome of offering a transaction to the mempool
+#[derive(Debug, Clone)]
+pub enum MempoolAcceptResult {
+    /// Added to the mempool
+    Accepted { tx_hash: Sha256Hash, fee_rate: FeeRate },
+    /// Added, replacing a pending transaction that spent the same input
+    Replaced { tx_hash: Sha256Hash, fee_rate: FeeRate, replaced: Sha256Hash },
+    /// Spends triangles that are neither confirmed nor created by a pending
+    /// transaction. Not kept; resubmit once the parents are known.
+    Orphaned { tx_hash: Sha256Hash, missing_inputs: Vec<Sha256Hash> },
+    /// Turned away; `conflicts` lists pending transactions it clashed with
+    Rejected { tx_hash: Sha256Hash, reason: RejectReason, error: ChainError, conflicts: Vec<Sha256Hash> },
+}
+
+impl MempoolAcceptResult {
+    fn rejected(tx_hash: Sha256Hash, reason: RejectReason, error: ChainError) -> Self {
+        MempoolAcceptResult::Rejected { tx_hash, reason, error, conflicts: Vec::new() }
+    }
+
+    pub fn tx_hash(&self) -> Sha256Hash {
+        match self {
+            MempoolAcceptResult::Accepted { tx_hash, .. }
+            | MempoolAcceptResult::Replaced { tx_hash, .. }
+            | MempoolAcceptResult::Orphaned { tx_hash, .. }
+            | MempoolAcceptResult::Rejected { tx_hash, .. } => *tx_hash,
+        }
+    }
+
+    /// Whether the transaction is now in the mempool
+    pub fn is_accepted(&self) -> bool {
+        matches!(self, MempoolAcceptResult::Accepted { .. } | MempoolAcceptResult::Replaced { .. })
+    }
+
+    /// The rejection reason, if the transaction was rejected
+    pub fn reason(&self) -> Option<RejectReason> {
+        match self {
+            MempoolAcceptResult::Rejected { reason, .. } => Some(*reason),
+            _ => None,
+        }
+    }
+
+    /// Collapse into the hash of any replaced transaction, or the error
+    pub fn into_result(self) -> Result<Option<Sha256Hash>, ChainError> {
+        match self {
+            MempoolAcceptResult::Accepted { .. } => Ok(None),
+            MempoolAcceptResult::Replaced { replaced, .. } => Ok(Some(replaced)),
+            MempoolAcceptResult::Orphaned { missing_inputs, .. } => Err(ChainError::UtxoMissing { hash: missing_inputs[0] }),
+            MempoolAcceptResult::Rejected { error, .. } => Err(error),
+        }
+    }
+}
+
+/// Transaction pool for pending (unconfirmed) transactions
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Mempool {
+    /// Pending transactions indexed by their hash
+    transactions: HashMap<Sha256Hash, Transaction>,
+    /// Hash of the pending transaction spending each input triangle
+    #[serde(default)]
+    spent_inputs: HashMap<Sha256Hash, Sha256Hash>,
+    /// Hash of the pending subdivision creating each not-yet-confirmed triangle
+    #[serde(default)]
+    created_outputs: HashMap<Sha256Hash, Sha256Hash>,
+    /// Height of the chain tip, used to check lock times against the next block
+    #[serde(default)]
+    tip_height: BlockHeight,
+    /// Median time past at the chain tip
+    #[serde(default)]
+    tip_median_time: i64,
+    /// Event channel for accepted and evicted transactions
+    #[serde(skip)]
+    events: EventBus,
+    /// Verified signatures, shared with the owning blockchain's block validation
+    #[serde(skip)]
+    sig_cache: SignatureCache,
+    /// Most transactions kept at once
+    #[serde(skip, default = "Mempool::default_max_transactions")]
+    max_transactions: usize,
+}
+
+impl Mempool {
+    /// Maximum number of transactions in mempool (to prevent DoS)
+    const MAX_TRANSACTIONS: usize = 10000;
+
+    /// Maximum transactions per address to prevent spam
+    const MAX_PER_ADDRESS: usize = 100;
+
+    /// A replacement must pay at least this much more than the transaction it replaces...
+    pub const MIN_RBF_FEE_INCREMENT: u64 = 1;
+
+    /// ...and at least this many percent more
+    pub const MIN_RBF_FEE_BUMP_PERCENT: u64 = 10;
+
+    pub fn new() -> Self {
+        Self::with_events(EventBus::new())
+    }
+
+    /// Create an empty mempool that publishes on an existing event bus
+    pub fn with_events(events: EventBus) -> Self {
+        Mempool {
+            transactions: HashMap::new(),
+            spent_inputs: HashMap::new(),
+            created_outputs: HashMap::new(),
+            tip_height: 0,
+            tip_median_time: 0,
+            events,
+            sig_cache: SignatureCache::default(),
+            max_transactions: Self::MAX_TRANSACTIONS,
+        }
+    }
+
+    fn default_max_transactions() -> usize {
+        Self::MAX_TRANSACTIONS
+    }
+
+    /// Keep at most `max_transactions` instead of `MAX_TRANSACTIONS`
+    pub fn with_max_transactions(mut self, max_transactions: usize) -> Self {
+        self.max_transactions = max_transactions;
+        self
+    }
+
+    /// Use a shared signature cache so block validation can reuse mempool checks
+    pub fn with_signature_cache(mut self, sig_cache: SignatureCache) -> Self {
+        self.sig_cache = sig_cache;
+        self
+    }
+
+    /// Record the chain tip, so time-locked transactions are only accepted once
+    /// they could go in the next block
+    pub fn set_tip(&mut self, height: BlockHeight, median_time_past: i64) {
+        self.tip_height = height;
+        self.tip_median_time = median_time_past;
+    }
+
+    /// Whether `tx`'s lock time allows it in the block after the current tip
+    fn is_final_for_next_block(&self, tx: &Transaction) -> bool {
+        tx.is_final(self.tip_height + 1, self.tip_median_time)
+    }
+
+    /// Add a transaction to the mempool with validation.
+    /// If it spends the same input as a pending transaction and pays a high enough fee,
+    /// the pending one is replaced (replace-by-fee) and its hash is returned.
+    pub fn add_transaction(&mut self, tx: Transaction, state: &TriangleState) -> Result<Option<Sha256Hash>, ChainError> {
+        self.accept_transaction(tx, state).into_result()
+    }
+
+    /// Offer a transaction to the mempool, reporting in detail what happened to it.
+    /// Each input must be unspent in `state`, the confirmed state, or created by
+    /// a pending transaction; one spending anything else is orphaned. Its owners
+    /// and contracts are checked for the block after the tip.
+    pub fn accept_transaction(&mut self, tx: Transaction, state: &TriangleState) -> MempoolAcceptResult {
+        let tx_hash = tx.hash();
+
+        // Check if transaction already exists
+        if self.transactions.contains_key(&tx_hash) {
+            return MempoolAcceptResult::rejected(tx_hash, RejectReason::Duplicate, ChainError::DuplicateTransaction { hash: tx_hash });
+        }
+
+        let missing_inputs: Vec<Sha256Hash> = tx.inputs().into_iter()
+            .filter(|input| !state.utxo_set.contains_key(input) && self.pending_output(input).is_none())
+            .collect();
+        if !missing_inputs.is_empty() {
+            return MempoolAcceptResult::Orphaned { tx_hash, missing_inputs };
+        }
+
+        if let Err(e) = tx.version().check() {
+            return MempoolAcceptResult::rejected(tx_hash, RejectReason::UnsupportedVersion, e);
+        }
+
+        if let Transaction::Coinbase(_) = tx {
+            return MempoolAcceptResult::rejected(tx_hash, RejectReason::Coinbase, ChainError::InvalidTransaction(
+                "Coinbase transactions cannot be added to mempool".to_string()
+            ));
+        }
+        if let Err(e) = self.check_signatures(&tx) {
+            return MempoolAcceptResult::rejected(tx_hash, RejectReason::Invalid, e);
+        }
+        if let Err(e) = state.check_contracts(&tx, self.tip_height + 1, &self.pending_inputs(&tx)) {
+            return MempoolAcceptResult::rejected(tx_hash, RejectReason::ContractViolation, e);
+        }
+        if let Err(e) = self.check_against_parent(&tx, state) {
+            return MempoolAcceptResult::rejected(tx_hash, RejectReason::Invalid, e);
+        }
+
+        if !self.is_final_for_next_block(&tx) {
+            return MempoolAcceptResult::rejected(tx_hash, RejectReason::NonFinal, ChainError::InvalidTransaction(format!(
+                "Transaction is locked until {}",
+                tx.lock_time().map(|lock| lock.to_string()).unwrap_or_default()
+            )));
+        }
+
+        // Replace-by-fee: a conflicting transaction must outbid the pending one.
+        // Replacing several pending transactions at once is not supported.
+        let mut conflicts: Vec<Sha256Hash> = tx.inputs().iter()
+            .filter_map(|input| self.find_conflict(input))
+            .collect();
+        conflicts.sort();
+        conflicts.dedup();
+        if conflicts.len() > 1 {
+            return MempoolAcceptResult::Rejected {
+                tx_hash,
+                reason: RejectReason::TooManyConflicts,
+                error: ChainError::InvalidTransaction(format!(
+                    "Conflicts with {} pending transactions; only one can be replaced",
+                    conflicts.len()
+                )),
+                conflicts,
+            };
+        }
+        let replaced = conflicts.first().copied();
+        if let Some(old_hash) = replaced {
+            let required_fee = Self::min_replacement_fee(self.transactions[&old_hash].fee());
+            if tx.fee() < required_fee {
+                return MempoolAcceptResult::Rejected {
+                    tx_hash,
+                    reason: RejectReason::InsufficientFee,
+                    error: ChainError::InsufficientFee { required: required_fee, provided: tx.fee() },
+                    conflicts,
+                };
+            }
+        }
+
+        // Check per-address limit to prevent spam, and that the nonce isn't already pending
+        if let Some(sender) = tx.sender() {
+            let pending: Vec<(&Sha256Hash, &Transaction)> = self.transactions.iter()
+                .filter(|(hash, _)| Some(**hash) != replaced)
+                .filter(|(_, t)| t.sender() == Some(sender))
+                .collect();
+
+            if let Some((hash, _)) = pending.iter().find(|(_, t)| t.nonce() == tx.nonce()) {
+                return MempoolAcceptResult::Rejected {
+                    tx_hash,
+                    reason: RejectReason::NonceInUse,
+                    error: ChainError::InvalidTransaction(format!(
+                        "Nonce {} for {} is already used by pending transaction {}",
+                        tx.nonce().unwrap_or_default(), sender, hex::encode(hash)
+                    )),
+                    conflicts: vec![**hash],
+                };
+            }
+
+            let count = pending.len();
+            if count >= Self::MAX_PER_ADDRESS {
+                return MempoolAcceptResult::rejected(tx_hash, RejectReason::AddressLimit, ChainError::InvalidTransaction(
+                    format!("Address has reached maximum mempool limit of {}", Self::MAX_PER_ADDRESS)
+                ));
+            }
+        }
+
+        if let Some(old_hash) = replaced {
+            self.evict_with_descendants(&old_hash);
+        } else if self.transactions.len() >= self.max_transactions && !self.evict_lowest_fee_transaction(tx.fee_rate()) {
+            return MempoolAcceptResult::rejected(tx_hash, RejectReason::MempoolFull, ChainError::InvalidTransaction(
+                format!("Mempool is full and the fee rate doesn't beat its lowest of {} transactions", self.max_transactions)
+            ));
+        }
+
+        for input in tx.inputs() {
+            self.spent_inputs.insert(input, tx_hash);
+        }
+        if let Transaction::Subdivision(sub_tx) = &tx {
+            for child in &sub_tx.children {
+                self.created_outputs.insert(child.hash(), tx_hash);
+            }
+        }
+        let fee_rate = tx.fee_rate();
+        self.transactions.insert(tx_hash, tx);
+        self.events.publish(ChainEvent::TxAccepted(tx_hash));
+        match replaced {
+            Some(replaced) => MempoolAcceptResult::Replaced { tx_hash, fee_rate, replaced },
+            None => MempoolAcceptResult::Accepted { tx_hash, fee_rate },
+        }
+    }
+
+    /// Check a transaction's signatures and stateless rules
+    fn check_signatures(&self, tx: &Transaction) -> Result<(), ChainError> {
+        // Validate transaction before adding to mempool
+        match tx {
+            Transaction::Transfer(transfer_tx) => {
+                // Validate signature before adding
+                transfer_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::BatchTransfer(batch_tx) => {
+                batch_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::HtlcLock(lock_tx) => {
+                lock_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::HtlcRedeem(redeem_tx) => {
+                redeem_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::EscrowLock(lock_tx) => {
+                lock_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::EscrowSettle(settle_tx) => {
+                settle_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::Offer(offer_tx) => {
+                offer_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::Accept(accept_tx) => {
+                accept_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::Inscription(inscription_tx) => {
+                inscription_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::DeepSubdivision(deep_tx) => {
+                deep_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::SplitTransfer(split_tx) => {
+                split_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::Swap(swap_tx) => {
+                swap_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::Lease(lease_tx) => {
+                lease_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::Approve(approve_tx) => {
+                approve_tx.validate_cached(&self.sig_cache)?;
+            },
+            Transaction::Coinbase(_) => {
+                return Err(ChainError::InvalidTransaction(
+                    "Coinbase transactions cannot be added to mempool".to_string()
+                ));
+            },
+            Transaction::Subdivision(sub_tx) => {
+                // We can still validate the signature without state access, which is a cheap
+                // way to discard obviously invalid transactions.
+                sub_tx.validate_signature_cached(&self.sig_cache)?;
             }
-        } else {
-            // Case 3: Orphan block
-            return Err(ChainError::OrphanBlock);
         }
 
         Ok(())
     }
 
-    /// Calculate the block reward for a given block height (with halving)
-    pub fn calculate_block_reward(height: BlockHeight) -> u64 {
-        let halvings = height / REWARD_HALVING_INTERVAL;
-        if halvings >= MAX_HALVINGS {
-            // After 64 halvings, reward is 0
-            return 0;
+    /// The inputs of `tx` that pending transactions create
+    fn pending_inputs(&self, tx: &Transaction) -> HashMap<Sha256Hash, &Triangle> {
+        tx.inputs().into_iter()
+            .filter_map(|input| Some((input, self.pending_output(&input)?)))
+            .collect()
+    }
+
+    /// Check the triangles a subdivision, deep subdivision or split transfer
+    /// makes against the parent it spends, found in `state` or among the
+    /// outputs of pending transactions
+    fn check_against_parent(&self, tx: &Transaction, state: &TriangleState) -> Result<(), ChainError> {
+        let parent = |hash: &Sha256Hash| state.utxo_set.get(hash).or_else(|| self.pending_output(hash));
+        match tx {
+            Transaction::Subdivision(sub_tx) => parent(&sub_tx.parent_hash)
+                .map_or(Ok(()), |parent| sub_tx.validate_against_parent(parent)),
+            Transaction::DeepSubdivision(deep_tx) => parent(&deep_tx.parent_hash)
+                .map_or(Ok(()), |parent| deep_tx.validate_against_parent(parent)),
+            Transaction::SplitTransfer(split_tx) => parent(&split_tx.parent_hash)
+                .map_or(Ok(()), |parent| split_tx.validate_against_parent(parent)),
+            _ => Ok(()),
         }
-        INITIAL_MINING_REWARD >> halvings
     }
 
-    /// Calculate the total supply that has been mined up to a given block height
-    /// This accounts for all halvings that have occurred
-    pub fn calculate_current_supply(height: BlockHeight) -> u64 {
-        if height == 0 {
-            return 0;
+    /// Find a pending transaction spending the given input
+    fn find_conflict(&self, input: &Sha256Hash) -> Option<Sha256Hash> {
+        self.spent_inputs.get(input).copied()
+    }
+
+    /// A triangle created by a pending subdivision, not yet in the UTXO set
+    pub fn pending_output(&self, triangle_hash: &Sha256Hash) -> Option<&Triangle> {
+        let creator = self.created_outputs.get(triangle_hash)?;
+        match self.transactions.get(creator)? {
+            Transaction::Subdivision(sub_tx) => sub_tx.children.iter().find(|c| c.hash() == *triangle_hash),
+            _ => None,
         }
+    }
 
-        let mut total_supply = 0u64;
-        let mut current_height = 1u64; // Start from block 1 (first mined block)
+    /// Unconfirmed transactions this one depends on, ordered so that each comes
+    /// after the transactions it depends on
+    pub fn ancestors(&self, tx_hash: &Sha256Hash) -> Vec<Sha256Hash> {
+        let mut ancestors = Vec::new();
+        self.collect_ancestors(tx_hash, &mut ancestors);
+        ancestors
+    }
+
+    fn collect_ancestors(&self, tx_hash: &Sha256Hash, ancestors: &mut Vec<Sha256Hash>) {
+        let inputs = match self.transactions.get(tx_hash) {
+            Some(tx) => tx.inputs(),
+            None => return,
+        };
+
+        for input in inputs {
+            if let Some(parent_hash) = self.created_outputs.get(&input) {
+                if !ancestors.contains(parent_hash) {
+                    self.collect_ancestors(parent_hash, ancestors);
+                    ancestors.push(*parent_hash);
+                }
+            }
+        }
+    }
 
-        while current_height <= height {
-            let reward = Self::calculate_block_reward(current_height);
-            total_supply = total_supply.saturating_add(reward);
-            current_height += 1;
+    /// Pending transactions that spend outputs of this one, directly or indirectly
+    pub fn descendants(&self, tx_hash: &Sha256Hash) -> Vec<Sha256Hash> {
+        let mut descendants = Vec::new();
+        let mut queue = vec![*tx_hash];
+
+        while let Some(hash) = queue.pop() {
+            if let Some(Transaction::Subdivision(sub_tx)) = self.transactions.get(&hash) {
+                for child in &sub_tx.children {
+                    if let Some(spender) = self.spent_inputs.get(&child.hash()) {
+                        if !descendants.contains(spender) {
+                            descendants.push(*spender);
+                            queue.push(*spender);
+                        }
+                    }
+                }
+            }
         }
 
-        total_supply
+        descendants
     }
 
-    /// Calculate remaining supply that can still be mined
-    pub fn calculate_remaining_supply(&self) -> u64 {
-        let current = Self::calculate_current_supply(self.blocks.last().unwrap().header.height);
-        MAX_SUPPLY.saturating_sub(current)
+    /// Remove a transaction and everything depending on it, publishing an eviction
+    /// event for each. Returns the number of transactions removed.
+    fn evict_with_descendants(&mut self, tx_hash: &Sha256Hash) -> usize {
+        let mut evicted = vec![*tx_hash];
+        evicted.extend(self.descendants(tx_hash));
+
+        let mut removed = 0;
+        for hash in evicted {
+            if self.remove_transaction(&hash).is_some() {
+                self.events.publish(ChainEvent::TxEvicted(hash));
+                removed += 1;
+            }
+        }
+        removed
     }
 
-    /// Get percentage of total supply mined
-    pub fn supply_percentage(&self) -> f64 {
-        let current = Self::calculate_current_supply(self.blocks.last().unwrap().header.height);
-        (current as f64 / MAX_SUPPLY as f64) * 100.0
+    /// A pending transaction that spends one of the same inputs as `tx`, if any
+    pub fn conflicting_transaction(&self, tx: &Transaction) -> Option<&Transaction> {
+        tx.inputs().iter()
+            .find_map(|input| self.find_conflict(input))
+            .and_then(|hash| self.transactions.get(&hash))
+    }
+
+    /// Lowest fee a transaction needs to replace one paying `old_fee`
+    pub fn min_replacement_fee(old_fee: u64) -> u64 {
+        let bump = (old_fee.saturating_mul(Self::MIN_RBF_FEE_BUMP_PERCENT) / 100)
+            .max(Self::MIN_RBF_FEE_INCREMENT);
+        old_fee.saturating_add(bump)
+    }
+
+    /// Evict the transaction with the lowest fee rate, and its descendants, to
+    /// make room for one paying `fee_rate`. Nothing is evicted, and false
+    /// returned, unless the newcomer pays a better rate.
+    fn evict_lowest_fee_transaction(&mut self, fee_rate: FeeRate) -> bool {
+        let lowest = self.transactions.iter()
+            .min_by(|(hash_a, tx_a), (hash_b, tx_b)| {
+                tx_a.fee_rate().cmp(&tx_b.fee_rate()).then_with(|| hash_a.cmp(hash_b))
+            })
+            .map(|(hash, tx)| (*hash, tx.fee_rate()));
+
+        match lowest {
+            Some((hash, lowest_rate)) if lowest_rate < fee_rate => {
+                self.evict_with_descendants(&hash);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove a transaction from the mempool
+    pub fn remove_transaction(&mut self, tx_hash: &Sha256Hash) -> Option<Transaction> {
+        let tx = self.transactions.remove(tx_hash)?;
+        for input in tx.inputs() {
+            if self.spent_inputs.get(&input) == Some(tx_hash) {
+                self.spent_inputs.remove(&input);
+            }
+        }
+        if let Transaction::Subdivision(sub_tx) = &tx {
+            for child in &sub_tx.children {
+                let child_hash = child.hash();
+                if self.created_outputs.get(&child_hash) == Some(tx_hash) {
+                    self.created_outputs.remove(&child_hash);
+                }
+            }
+        }
+        Some(tx)
+    }
+
+    /// Get all transactions currently in the mempool
+    pub fn get_all_transactions(&self) -> Vec<Transaction> {
+        self.transactions.values().cloned().collect()
+    }
+
+    /// Get transactions ordered by fee rate (highest first) for mining prioritization
+    /// Returns up to `limit` transactions paying the most per serialized byte
+    pub fn get_transactions_by_fee(&self, limit: usize) -> Vec<Transaction> {
+        let mut txs: Vec<Transaction> = self.transactions.values().cloned().collect();
+
+        // Sort by fee rate in descending order (highest fee per byte first)
+        txs.sort_by_cached_key(|tx| std::cmp::Reverse(tx.fee_rate()));
+
+        // Return up to limit transactions
+        txs.into_iter().take(limit).collect()
+    }
+
+    /// Select transactions for a new block, highest fee rate first, staying within the
+    /// block transaction count and size limits. `reserved_bytes` and `reserved_count`
+    /// account for the header and coinbase the miner adds on top of these.
+    pub fn get_transactions_for_block(&self, reserved_count: usize, reserved_bytes: usize) -> Vec<Transaction> {
+        let max_count = MAX_BLOCK_TRANSACTIONS.saturating_sub(reserved_count);
+        let mut remaining_bytes = MAX_BLOCK_SIZE.saturating_sub(reserved_bytes);
+        let mut selected = Vec::new();
+
+        for tx in self.get_transactions_by_fee(self.transactions.len()) {
+            if selected.len() >= max_count {
+                break;
+            }
+
+            let tx_size = tx.size();
+            if tx_size > remaining_bytes {
+                continue;
+            }
+
+            remaining_bytes -= tx_size;
+            selected.push(tx);
+        }
+
+        selected
+    }
+
+    /// Get a specific transaction by hash
+    pub fn get_transaction(&self, tx_hash: &Sha256Hash) -> Option<&Transaction> {
+        self.transactions.get(tx_hash)
+    }
+
+    /// Remove multiple transactions (e.g., after they're included in a block)
+    pub fn remove_transactions(&mut self, tx_hashes: &[Sha256Hash]) {
+        for hash in tx_hashes {
+            self.remove_transaction(hash);
+        }
+    }
+
+    /// Clear all transactions from the mempool
+    pub fn clear(&mut self) {
+        self.transactions.clear();
+        self.spent_inputs.clear();
+        self.created_outputs.clear();
+    }
+
+    /// Get the number of pending transactions
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Check if mempool is empty
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Validate all transactions in mempool against current state
+    /// Removes invalid transactions and returns count of removed transactions
+    pub fn validate_and_prune(&mut self, state: &TriangleState) -> usize {
+        let mut to_remove = Vec::new();
+
+        for (hash, tx) in self.transactions.iter() {
+            let is_valid = match tx {
+                Transaction::Subdivision(sub_tx) => {
+                    // The parent must be in the UTXO set or created by a pending transaction
+                    let parent = state.utxo_set.get(&sub_tx.parent_hash)
+                        .or_else(|| self.pending_output(&sub_tx.parent_hash));
+                    match parent {
+                        Some(parent) => sub_tx.validate_signature_cached(&self.sig_cache).is_ok()
+                            && sub_tx.validate_against_parent(parent).is_ok(),
+                        None => false,
+                    }
+                },
+                Transaction::DeepSubdivision(deep_tx) => {
+                    let parent = state.utxo_set.get(&deep_tx.parent_hash)
+                        .or_else(|| self.pending_output(&deep_tx.parent_hash));
+                    match parent {
+                        Some(parent) => deep_tx.validate_cached(&self.sig_cache).is_ok()
+                            && deep_tx.validate_against_parent(parent).is_ok(),
+                        None => false,
+                    }
+                },
+                Transaction::SplitTransfer(split_tx) => {
+                    let parent = state.utxo_set.get(&split_tx.parent_hash)
+                        .or_else(|| self.pending_output(&split_tx.parent_hash));
+                    match parent {
+                        Some(parent) => split_tx.validate_cached(&self.sig_cache).is_ok()
+                            && split_tx.validate_against_parent(parent).is_ok(),
+                        None => false,
+                    }
+                },
+                Transaction::Transfer(transfer_tx) => {
+                    // Check if input exists in UTXO set or is created by a pending transaction
+                    (state.utxo_set.contains_key(&transfer_tx.input_hash)
+                        || self.created_outputs.contains_key(&transfer_tx.input_hash)) &&
+                    transfer_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::BatchTransfer(batch_tx) => {
+                    batch_tx.transfers.iter().all(|t| {
+                        state.utxo_set.contains_key(&t.input_hash)
+                            || self.created_outputs.contains_key(&t.input_hash)
+                    }) && batch_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::Swap(swap_tx) => {
+                    swap_tx.inputs.iter().all(|i| {
+                        state.utxo_set.contains_key(&i.input_hash)
+                            || self.created_outputs.contains_key(&i.input_hash)
+                    }) && swap_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::Lease(lease_tx) => {
+                    (state.utxo_set.contains_key(&lease_tx.input_hash)
+                        || self.created_outputs.contains_key(&lease_tx.input_hash)) &&
+                    lease_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::Approve(approve_tx) => {
+                    (state.utxo_set.contains_key(&approve_tx.input_hash)
+                        || self.created_outputs.contains_key(&approve_tx.input_hash)) &&
+                    approve_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::HtlcLock(lock_tx) => {
+                    (state.utxo_set.contains_key(&lock_tx.input_hash)
+                        || self.created_outputs.contains_key(&lock_tx.input_hash)) &&
+                    lock_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::HtlcRedeem(redeem_tx) => {
+                    // The contract itself is checked by `check_contracts` below
+                    redeem_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::EscrowLock(lock_tx) => {
+                    (state.utxo_set.contains_key(&lock_tx.input_hash)
+                        || self.created_outputs.contains_key(&lock_tx.input_hash)) &&
+                    lock_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::EscrowSettle(settle_tx) => {
+                    // Like redemptions, the escrow itself is checked by `check_contracts`
+                    settle_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::Offer(offer_tx) => {
+                    (state.utxo_set.contains_key(&offer_tx.input_hash)
+                        || self.created_outputs.contains_key(&offer_tx.input_hash)) &&
+                    offer_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::Accept(accept_tx) => {
+                    // The offer and payment are checked by `check_contracts`
+                    accept_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::Inscription(inscription_tx) => {
+                    (state.utxo_set.contains_key(&inscription_tx.input_hash)
+                        || self.created_outputs.contains_key(&inscription_tx.input_hash)) &&
+                    inscription_tx.validate_cached(&self.sig_cache).is_ok()
+                },
+                Transaction::Coinbase(_) => {
+                    // Coinbase transactions shouldn't be in mempool
+                    false
+                }
+            };
+
+            let nonce_ok = match (tx.sender(), tx.nonce()) {
+                (Some(sender), Some(nonce)) => state.check_nonce(sender, nonce).is_ok(),
+                _ => true,
+            };
+
+            let contracts_ok = state.check_contracts(tx, self.tip_height + 1, &self.pending_inputs(tx)).is_ok();
+
+            if !is_valid || !nonce_ok || !contracts_ok || !self.is_final_for_next_block(tx) {
+                to_remove.push(*hash);
+            }
+        }
+
+        let mut removed_count = 0;
+        for hash in to_remove {
+            removed_count += self.evict_with_descendants(&hash);
+        }
+
+        removed_count
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A single inconsistency found by `Blockchain::verify_chain`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainProblem {
+    pub height: BlockHeight,
+    pub hash: Sha256Hash,
+    pub description: String,
+}
+
+/// Result of re-validating the most recent blocks of the chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainVerificationReport {
+    /// First block that was checked (pruned blocks are never checked)
+    pub start_height: BlockHeight,
+    pub tip_height: BlockHeight,
+    pub blocks_checked: usize,
+    /// Whether replaying the blocks reproduced the current UTXO set
+    pub state_matches: bool,
+    pub problems: Vec<ChainProblem>,
+}
+
+impl ChainVerificationReport {
+    pub fn is_ok(&self) -> bool {
+        self.state_matches && self.problems.is_empty()
+    }
+}
+
+/// Result of reconciling issued rewards and triangle area against the issuance schedule
+#[derive(Debug, Clone, PartialEq)]
+pub struct SupplyAudit {
+    /// First block included in the audit (pruned blocks cannot be audited)
+    pub start_height: BlockHeight,
+    pub tip_height: BlockHeight,
+    /// Sum of coinbase rewards actually paid out
+    pub issued_rewards: u64,
+    /// Block subsidies scheduled for the audited heights
+    pub scheduled_rewards: u64,
+    /// Transaction fees miners were allowed to collect on top of the subsidy
+    pub fees_collected: u64,
+    /// Total area of the UTXO set implied by replaying the audited blocks
+    pub expected_utxo_area: f64,
+    /// Total area of the current UTXO set
+    pub utxo_area: f64,
+    pub problems: Vec<String>,
+}
+
+impl SupplyAudit {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// One triangle's history, as reconstructed by `Blockchain::triangle_ancestry`
+/// and `Blockchain::triangle_descendants`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TriangleRecord {
+    pub id: TriangleId,
+    /// The triangle as last seen, before it was spent
+    pub triangle: Triangle,
+    /// Height of the block that created it; triangles from before the pruning
+    /// point (or genesis) report that height
+    pub created_height: BlockHeight,
+    /// Transaction that created it, `None` for triangles from before the
+    /// pruning point (or genesis)
+    #[serde(default)]
+    pub created_by: Option<Sha256Hash>,
+    /// Each owner with the height it took over, oldest first
+    pub owners: Vec<(BlockHeight, Address)>,
+    /// Height and transaction that spent it, `None` while unspent
+    pub spent: Option<(BlockHeight, Sha256Hash)>,
+}
+
+/// A triangle and everything subdivided out of it
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FamilyTree {
+    pub record: TriangleRecord,
+    pub children: Vec<FamilyTree>,
+}
+
+impl FamilyTree {
+    /// Number of triangles in the tree, including its root
+    pub fn size(&self) -> usize {
+        1 + self.children.iter().map(FamilyTree::size).sum::<usize>()
+    }
+}
+
+/// Relative tolerance used when comparing floating-point triangle areas
+const AREA_TOLERANCE: f64 = 1e-9;
+
+pub(crate) fn areas_match(a: f64, b: f64) -> bool {
+    (a - b).abs() <= AREA_TOLERANCE * a.abs().max(b.abs()).max(1.0)
+}
+
+/// The blockchain itself
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Blockchain {
+    pub blocks: Vec<Block>,
+    /// Height of each main-chain block by hash; fork blocks live in `forks`
+    pub block_index: HashMap<Sha256Hash, BlockHeight>,
+    pub forks: HashMap<Sha256Hash, Block>,
+    pub header_chain: HeaderChain,
+    pub state: TriangleState,
+    /// Compact proof-of-work target for the next block
+    pub bits: u32,
+    pub mempool: Mempool,
+    /// Event channel shared with the mempool
+    #[serde(skip)]
+    pub events: EventBus,
+    /// Verified signatures shared with the mempool
+    #[serde(skip)]
+    pub sig_cache: SignatureCache,
+    /// Scheduled supply at the current tip, kept up to date as blocks connect and disconnect
+    #[serde(default)]
+    pub supply_at_tip: u64,
+    /// Blocks that failed validation, and their known descendants
+    #[serde(default)]
+    pub invalid_blocks: HashSet<Sha256Hash>,
+    /// Keep transactions only for this many recent blocks; `None` keeps everything
+    #[serde(default)]
+    pub prune_depth: Option<BlockHeight>,
+    /// Highest main-chain block whose transactions have been dropped (0 = none)
+    #[serde(default)]
+    pub pruned_height: BlockHeight,
+    /// UTXO set as of `pruned_height`, used as the starting point when replaying a reorg
+    #[serde(default)]
+    pub prune_base: Option<TriangleState>,
+    /// Judges the proof of work of every header and block; `Sha256Pow` unless
+    /// swapped out with `with_pow`
+    #[serde(skip, default = "pow::sha256")]
+    pub pow: Arc<dyn PowEngine>,
+}
+
+// Bitcoin-like parameters for Sierpinski Triangle Blockchain
+// Target: 1 block every 60 seconds = 1,440 blocks/day = ~525,600 blocks/year
+
+/// Difficulty adjusts every 2,016 blocks (like Bitcoin) ~1.4 days at 1 minute blocks
+const DIFFICULTY_ADJUSTMENT_WINDOW: BlockHeight = 2016;
+
+/// Target block time: 60 seconds (1 minute)
+const TARGET_BLOCK_TIME_SECONDS: i64 = 60;
+
+/// The bits a block extending `parent` must carry: the parent's own, except
+/// after every `DIFFICULTY_ADJUSTMENT_WINDOW`th block, when the target is
+/// retargeted over the window ending at the parent, up to `pow_limit`.
+/// `header` looks up the parent's ancestors by hash.
+fn next_bits<'a>(parent: &'a BlockHeader, pow_limit: u32, header: impl Fn(&Sha256Hash) -> Option<&'a BlockHeader>) -> u32 {
+    if parent.height == 0 || !parent.height.is_multiple_of(DIFFICULTY_ADJUSTMENT_WINDOW) {
+        return parent.bits;
+    }
+
+    let mut first = parent;
+    for _ in 1..DIFFICULTY_ADJUSTMENT_WINDOW {
+        match header(&first.previous_hash) {
+            Some(ancestor) => first = ancestor,
+            None => return parent.bits,
+        }
+    }
+
+    // Timestamps should always increase; don't adjust with invalid data
+    let actual_time = parent.timestamp - first.timestamp;
+    if actual_time <= 0 {
+        return parent.bits;
+    }
+    let expected_time = (DIFFICULTY_ADJUSTMENT_WINDOW as i64 - 1) * TARGET_BLOCK_TIME_SECONDS;
+    difficulty::retarget(parent.bits, actual_time, expected_time, pow_limit)
+}
+
+/// Initial mining reward (in area units) - represents triangle area
+const INITIAL_MINING_REWARD: u64 = 1000;
+
+/// Halving interval - reward halves every 210,000 blocks (~4 years at 1 minute blocks)
+/// This matches Bitcoin's ~4 year halving cycle
+const REWARD_HALVING_INTERVAL: BlockHeight = 210_000;
+
+/// Maximum number of halvings before reward becomes 0 (64 halvings)
+const MAX_HALVINGS: u64 = 64;
+
+/// Calculate maximum supply: sum of geometric series
+/// Max supply = INITIAL_REWARD * HALVING_INTERVAL * (1 + 1/2 + 1/4 + ... ≈ 2)
+/// = 1000 * 210,000 * 2 = 420,000,000 area units
+pub const MAX_SUPPLY: u64 = INITIAL_MINING_REWARD * REWARD_HALVING_INTERVAL * 2;
+
+/// Number of previous blocks used for the median-time-past timestamp rule
+pub const MEDIAN_TIME_PAST_WINDOW: usize = 11;
+
+/// Maximum clock drift allowed for block timestamps ahead of local time
+pub const MAX_FUTURE_TIMESTAMP_DRIFT: i64 = 2 * 3600; // 2 hours in seconds
+
+/// Median of a set of block timestamps (the lower middle value for even counts)
+fn median_timestamp(mut timestamps: Vec<i64>) -> i64 {
+    if timestamps.is_empty() {
+        return i64::MIN;
+    }
+    timestamps.sort_unstable();
+    timestamps[(timestamps.len() - 1) / 2]
+}
+
+/// Number of leading blocks two chains have in common
+fn common_prefix_len(a: &[Block], b: &[Block]) -> usize {
+    a.iter()
+        .zip(b.iter())
+        .take_while(|(x, y)| x.hash == y.hash)
+        .count()
+}
+
+/// Maximum number of transactions (including the coinbase) allowed in a single block
+pub const MAX_BLOCK_TRANSACTIONS: usize = 5_000;
+
+/// Maximum serialized size of a block in bytes (1 MB, like Bitcoin's original limit)
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+impl Blockchain {
+    /// A fresh chain on the main network
+    pub fn new() -> Self {
+        Self::with_params(&ChainParams::MAINNET)
+    }
+
+    /// A fresh chain on the regression test network, whose blocks take a
+    /// hash or two to mine
+    pub fn regtest() -> Self {
+        Self::with_params(&ChainParams::REGTEST)
+    }
+
+    /// A fresh chain on the network `params` describes, its genesis block
+    /// carrying the network's proof-of-work limit
+    pub fn with_params(params: &ChainParams) -> Self {
+        let state = genesis_state();
+
+        let genesis_block = Block {
+            header: BlockHeader {
+                height: 0,
+                previous_hash: [0; 32],
+                timestamp: Utc::now().timestamp(),
+                bits: params.pow_limit_bits,
+                nonce: 0,
+                merkle_root: [0; 32],
+            },
+            hash: [0; 32],
+            transactions: BlockTransactions::default(),
+        };
+
+        let mut block_index = HashMap::new();
+        block_index.insert(genesis_block.hash, 0);
+        let header_chain = HeaderChain::new(genesis_block.hash, genesis_block.header.clone());
+        let events = EventBus::new();
+        let sig_cache = SignatureCache::default();
+
+        let mut chain = Blockchain {
+            blocks: vec![genesis_block],
+            block_index,
+            forks: HashMap::new(),
+            header_chain,
+            state,
+            bits: params.pow_limit_bits,
+            mempool: Mempool::with_events(events.clone()).with_signature_cache(sig_cache.clone()),
+            events,
+            sig_cache,
+            supply_at_tip: 0,
+            invalid_blocks: HashSet::new(),
+            prune_depth: None,
+            pruned_height: 0,
+            prune_base: None,
+            pow: pow::sha256(),
+        };
+        chain.sync_mempool_tip();
+        chain
+    }
+
+    /// Judge proof of work with `pow` from now on, e.g. `pow::TestPow` so
+    /// tests can mine without grinding hashes
+    pub fn with_pow(mut self, pow: Arc<dyn PowEngine>) -> Self {
+        self.pow = pow;
+        self
+    }
+
+    /// A copy of this chain with its own event channel, as a separate node
+    /// loading it would have. A plain `clone` shares this chain's channel.
+    pub fn detached_clone(&self) -> Self {
+        let mut chain = self.clone();
+        chain.events = EventBus::new();
+        chain.mempool.events = chain.events.clone();
+        chain
+    }
+
+    /// Tell the mempool about the current tip so it can check lock times
+    pub(crate) fn sync_mempool_tip(&mut self) {
+        let tip = self.blocks.last().unwrap();
+        let median_time_past = self.median_time_past(&tip.hash);
+        self.mempool.set_tip(tip.header.height, median_time_past);
+    }
+
+    /// Check a block against the chain it extends and the current state
+    pub fn validate_block(&self, block: &Block) -> Result<(), ChainError> {
+        self.validate_block_structure(block)?;
+        self.validate_block_transactions(block, &self.state)
+    }
+
+    /// Check everything about a block that doesn't depend on the state it
+    /// applies to: its place in the chain, timestamp, difficulty, proof of
+    /// work, merkle root, size and coinbase reward
+    fn validate_block_structure(&self, block: &Block) -> Result<(), ChainError> {
+        let parent_block = self.get_block(&block.header.previous_hash)
+            .ok_or(ChainError::InvalidBlockLinkage)?;
+
+        if block.header.height != parent_block.header.height + 1 {
+            return Err(ChainError::InvalidBlockLinkage);
+        }
+
+        // Validate timestamp is greater than the median of the last 11 blocks.
+        // A single miner can't drag this median around, which protects difficulty
+        // adjustment against time-warp attacks.
+        let median_time_past = self.median_time_past(&block.header.previous_hash);
+        if block.header.timestamp <= median_time_past {
+            return Err(ChainError::TimestampTooOld { timestamp: block.header.timestamp, median_time_past });
+        }
+
+        // Validate timestamp is not too far in the future (allow 2 hours of clock drift)
+        let current_time = Utc::now().timestamp();
+        let drift = block.header.timestamp - (current_time + MAX_FUTURE_TIMESTAMP_DRIFT);
+        if drift > 0 {
+            return Err(ChainError::TimestampTooFarInFuture { drift });
+        }
+
+        // Without this a miner could pick the easiest target and keep it forever
+        let expected = self.next_bits(&parent_block.header);
+        if block.header.bits != expected {
+            return Err(ChainError::UnexpectedDifficulty { expected, found: block.header.bits });
+        }
+
+        // The claimed hash must be the real one: coinbase rewards are placed by it
+        if block.calculate_hash() != block.hash || !self.pow.meets_target(&block.hash, block.header.bits) {
+            return Err(ChainError::InvalidProofOfWork);
+        }
+
+        let transactions = block.transactions.get()?;
+        let calculated_merkle = Block::calculate_merkle_root(transactions);
+        if block.header.merkle_root != calculated_merkle {
+            return Err(ChainError::InvalidMerkleRoot);
+        }
+
+        // Enforce block size limits before doing any per-transaction work
+        if transactions.len() > MAX_BLOCK_TRANSACTIONS {
+            return Err(ChainError::InvalidTransaction(
+                format!("Block contains {} transactions, maximum is {}",
+                    transactions.len(), MAX_BLOCK_TRANSACTIONS)
+            ));
+        }
+
+        let block_size = block.serialized_size();
+        if block_size > MAX_BLOCK_SIZE {
+            return Err(ChainError::InvalidTransaction(
+                format!("Block size {} bytes exceeds maximum of {} bytes", block_size, MAX_BLOCK_SIZE)
+            ));
+        }
+
+        // Validate coinbase transaction rules
+        let mut coinbase_count = 0;
+        let mut coinbase_reward = 0u64;
+        for (i, tx) in transactions.iter().enumerate() {
+            if let Transaction::Coinbase(coinbase_tx) = tx {
+                coinbase_count += 1;
+                coinbase_reward = coinbase_tx.reward_area();
+                // Coinbase must be the first transaction
+                if i != 0 {
+                    return Err(ChainError::InvalidTransaction(
+                        "Coinbase transaction must be the first transaction in the block".to_string()
+                    ));
+                }
+            }
+        }
+
+        // Exactly one coinbase transaction per block (or zero for genesis)
+        if block.header.height > 0 && coinbase_count != 1 {
+            return Err(ChainError::InvalidTransaction(
+                format!("Block must contain exactly one coinbase transaction, found {}", coinbase_count)
+            ));
+        }
+
+        // Validate coinbase reward doesn't exceed block reward + fees
+        if block.header.height > 0 {
+            let block_reward = Self::calculate_block_reward(block.header.height);
+            let total_fees = Self::calculate_total_fees(transactions);
+
+            // Use saturating_add to prevent integer overflow
+            let max_reward = block_reward.saturating_add(total_fees);
+
+            if coinbase_reward > max_reward {
+                return Err(ChainError::CoinbaseRewardTooHigh { claimed: coinbase_reward, allowed: max_reward });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check a block's transactions against `state`, the state at its parent
+    fn validate_block_transactions(&self, block: &Block, state: &TriangleState) -> Result<(), ChainError> {
+        let median_time_past = self.median_time_past(&block.header.previous_hash);
+
+        // Validate transactions in order. A transaction may spend a triangle created
+        // earlier in the same block (child-pays-for-parent), but not one already spent.
+        let mut spent: HashSet<Sha256Hash> = HashSet::new();
+        let mut created: HashMap<Sha256Hash, &Triangle> = HashMap::new();
+        let mut block_nonces: HashMap<&Address, u64> = HashMap::new();
+        // Every input used so far in the block; contract transactions need theirs untouched
+        let mut touched: HashSet<Sha256Hash> = HashSet::new();
+        let available = |hash: &Sha256Hash, spent: &HashSet<Sha256Hash>, created: &HashMap<Sha256Hash, &Triangle>| {
+            !spent.contains(hash) && (created.contains_key(hash) || state.utxo_set.contains_key(hash))
+        };
+        // Contract transactions only apply to triangles in the UTXO set that nothing
+        // else in this block touches
+        let contract_input_available = |hash: &Sha256Hash, touched: &HashSet<Sha256Hash>| {
+            if !state.utxo_set.contains_key(hash) || touched.contains(hash) {
+                return Err(ChainError::InvalidTransaction(
+                    format!("Contract input {} not available", hex::encode(hash))
+                ));
+            }
+            Ok(())
+        };
+
+        for tx in block.transactions.get()? {
+            tx.version().check()?;
+
+            // Each sender's nonces must increase through the block, starting above the chain's
+            if let (Some(sender), Some(nonce)) = (tx.sender(), tx.nonce()) {
+                match block_nonces.get(sender) {
+                    Some(&last) if nonce <= last => {
+                        return Err(ChainError::InvalidTransaction(format!(
+                            "Nonce {} for {} is not above nonce {} used earlier in the block",
+                            nonce, sender, last
+                        )));
+                    }
+                    Some(_) => {}
+                    None => state.check_nonce(sender, nonce)?,
+                }
+                block_nonces.insert(sender, nonce);
+            }
+
+            if !tx.is_final(block.header.height, median_time_past) {
+                return Err(ChainError::InvalidTransaction(format!(
+                    "Transaction {} is locked until {}",
+                    tx.hash_str(),
+                    tx.lock_time().map(|lock| lock.to_string()).unwrap_or_default()
+                )));
+            }
+
+            state.check_contracts(tx, block.header.height, &created)?;
+            let inputs = tx.inputs();
+
+            match tx {
+                Transaction::Subdivision(tx) => {
+                    if !available(&tx.parent_hash, &spent, &created) {
+                        return Err(ChainError::InvalidTransaction(
+                            format!("Parent triangle {} not in UTXO set", hex::encode(tx.parent_hash))
+                        ));
+                    }
+                    let parent = match created.get(&tx.parent_hash) {
+                        Some(parent) => *parent,
+                        None => &state.utxo_set[&tx.parent_hash],
+                    };
+                    tx.validate_signature_cached(&self.sig_cache)?;
+                    tx.validate_against_parent(parent)?;
+
+                    spent.insert(tx.parent_hash);
+                    for child in &tx.children {
+                        created.insert(child.hash(), child);
+                    }
+                },
+                Transaction::DeepSubdivision(tx) => {
+                    if !available(&tx.parent_hash, &spent, &created) {
+                        return Err(ChainError::InvalidTransaction(
+                            format!("Parent triangle {} not in UTXO set", hex::encode(tx.parent_hash))
+                        ));
+                    }
+                    let parent = match created.get(&tx.parent_hash) {
+                        Some(parent) => *parent,
+                        None => &state.utxo_set[&tx.parent_hash],
+                    };
+                    tx.validate_cached(&self.sig_cache)?;
+                    tx.validate_against_parent(parent)?;
+
+                    // The leaves only exist once the block is applied, so they can't
+                    // be spent later in the same block
+                    spent.insert(tx.parent_hash);
+                },
+                Transaction::SplitTransfer(tx) => {
+                    if !available(&tx.parent_hash, &spent, &created) {
+                        return Err(ChainError::InvalidTransaction(
+                            format!("Parent triangle {} not in UTXO set", hex::encode(tx.parent_hash))
+                        ));
+                    }
+                    let parent = match created.get(&tx.parent_hash) {
+                        Some(parent) => *parent,
+                        None => &state.utxo_set[&tx.parent_hash],
+                    };
+                    tx.validate_cached(&self.sig_cache)?;
+                    tx.validate_against_parent(parent)?;
+
+                    // Like deep subdivision leaves, the outputs can't be spent in this block
+                    spent.insert(tx.parent_hash);
+                },
+                Transaction::Coinbase(cb_tx) => {
+                    cb_tx.validate()?;
+                    // The coinbase comes first, so the state is still the one it mints into
+                    state.check_coinbase_placement(cb_tx, &block.hash)?;
+                },
+                Transaction::Transfer(tx) => {
+                    if !available(&tx.input_hash, &spent, &created) {
+                        return Err(ChainError::InvalidTransaction(
+                            format!("Transfer input {} not in UTXO set", hex::encode(tx.input_hash))
+                        ));
+                    }
+                    tx.validate_cached(&self.sig_cache)?;
+                    // Its owner was checked before the move, so it can't move again in this block
+                    spent.insert(tx.input_hash);
+                },
+                Transaction::BatchTransfer(tx) => {
+                    if let Some(missing) = tx.transfers.iter().find(|t| !available(&t.input_hash, &spent, &created)) {
+                        return Err(ChainError::InvalidTransaction(
+                            format!("Batch transfer input {} not in UTXO set", hex::encode(missing.input_hash))
+                        ));
+                    }
+                    tx.validate_cached(&self.sig_cache)?;
+                    spent.extend(inputs.iter().copied());
+                },
+                Transaction::Swap(tx) => {
+                    if let Some(missing) = tx.inputs.iter().find(|i| !available(&i.input_hash, &spent, &created)) {
+                        return Err(ChainError::InvalidTransaction(
+                            format!("Swap input {} not in UTXO set", hex::encode(missing.input_hash))
+                        ));
+                    }
+                    tx.validate_cached(&self.sig_cache)?;
+                    spent.extend(inputs.iter().copied());
+                },
+                Transaction::HtlcLock(tx) => {
+                    contract_input_available(&tx.input_hash, &touched)?;
+                    tx.validate_cached(&self.sig_cache)?;
+                    spent.insert(tx.input_hash);
+                },
+                Transaction::HtlcRedeem(tx) => {
+                    contract_input_available(&tx.input_hash, &touched)?;
+                    tx.validate_cached(&self.sig_cache)?;
+                    spent.insert(tx.input_hash);
+                },
+                Transaction::EscrowLock(tx) => {
+                    contract_input_available(&tx.input_hash, &touched)?;
+                    tx.validate_cached(&self.sig_cache)?;
+                    spent.insert(tx.input_hash);
+                },
+                Transaction::Lease(tx) => {
+                    contract_input_available(&tx.input_hash, &touched)?;
+                    tx.validate_cached(&self.sig_cache)?;
+                    spent.insert(tx.input_hash);
+                },
+                Transaction::EscrowSettle(tx) => {
+                    contract_input_available(&tx.input_hash, &touched)?;
+                    tx.validate_cached(&self.sig_cache)?;
+                    spent.insert(tx.input_hash);
+                },
+                Transaction::Offer(tx) => {
+                    contract_input_available(&tx.input_hash, &touched)?;
+                    tx.validate_cached(&self.sig_cache)?;
+                    spent.insert(tx.input_hash);
+                },
+                Transaction::Accept(tx) => {
+                    for input in &inputs {
+                        contract_input_available(input, &touched)?;
+                    }
+                    tx.validate_cached(&self.sig_cache)?;
+                    spent.extend(inputs.iter().copied());
+                },
+                Transaction::Inscription(tx) => {
+                    // Inscribing doesn't spend the triangle, but the owner check is
+                    // against the UTXO set, so it can't follow a move in the same block
+                    contract_input_available(&tx.input_hash, &touched)?;
+                    tx.validate_cached(&self.sig_cache)?;
+                },
+                Transaction::Approve(tx) => {
+                    // Like an inscription, approving doesn't spend the triangle
+                    contract_input_available(&tx.input_hash, &touched)?;
+                    tx.validate_cached(&self.sig_cache)?;
+                },
+            }
+            touched.extend(inputs);
+        }
+
+        Ok(())
+    }
+
+    pub fn apply_block(&mut self, valid_block: Block) -> Result<(), ChainError> {
+        self.check_not_known_invalid(&valid_block.hash, &valid_block.header)?;
+
+        let parent_hash = valid_block.header.previous_hash;
+        let last_block_hash = self.blocks.last().unwrap().hash;
+
+        // A fork block's transactions can only be checked against the state of
+        // its own branch, which is only rebuilt to switch to it
+        let validation = if parent_hash == last_block_hash {
+            self.validate_block(&valid_block)
+        } else {
+            self.validate_block_structure(&valid_block)
+        };
+        if let Err(e) = validation {
+            if self.is_permanently_invalid(&valid_block, &e) {
+                self.invalid_blocks.insert(valid_block.hash);
+            }
+            return Err(e);
+        }
+
+        // Case 1: The new block extends the main chain
+        if parent_hash == last_block_hash {
+            // Collect transaction hashes before applying
+            let tx_hashes: Vec<Sha256Hash> = valid_block.transactions.get()?.iter()
+                .map(|tx| tx.hash())
+                .collect();
+
+            self.state.apply_block_transactions(&valid_block)?;
+
+            let block_height = valid_block.header.height;
+            self.header_chain.insert(valid_block.hash, valid_block.header.clone());
+            self.blocks.push(valid_block.clone());
+            self.block_index.insert(valid_block.hash, block_height);
+            self.supply_at_tip += Self::calculate_block_reward(block_height);
+
+            // Changes only every DIFFICULTY_ADJUSTMENT_WINDOW blocks, to prevent oscillation
+            self.adjust_difficulty();
+
+            self.events.publish(ChainEvent::BlockConnected {
+                hash: valid_block.hash,
+                height: block_height,
+            });
+
+            self.mempool.remove_transactions(&tx_hashes);
+            self.sync_mempool_tip();
+            self.mempool.validate_and_prune(&self.state);
+            self.prune()?;
+
+        } else if let Some(parent) = self.get_block(&parent_hash) {
+            // Case 2: The new block creates a fork
+            // A pruned node cannot replay a branch that leaves the chain below its prune point
+            if parent.header.height < self.pruned_height {
+                return Err(ChainError::PrunedData(format!(
+                    "Fork at height {} is below pruned height {}",
+                    parent.header.height, self.pruned_height
+                )));
+            }
+
+            println!("🍴 Fork detected at height {}", valid_block.header.height);
+            self.forks.insert(valid_block.hash, valid_block.clone());
+            self.header_chain.insert(valid_block.hash, valid_block.header.clone());
+            self.events.publish(ChainEvent::ForkBlockAccepted {
+                hash: valid_block.hash,
+                height: valid_block.header.height,
+            });
+
+            // Switch once the fork has more work behind it than the main chain;
+            // on a tie the branch seen first stays
+            let tip_hash = self.blocks.last().unwrap().hash;
+            if self.header_chain.chain_work(&valid_block.hash) > self.header_chain.chain_work(&tip_hash) {
+                println!("⚠️  Switching to a heavier fork! Rebuilding state...");
+
+                // Reorganize the chain - build complete chain from genesis
+                let mut new_blocks = Vec::new();
+                let mut current_block = valid_block.clone();
+
+                while let Some(block) = self.get_block(&current_block.header.previous_hash) {
+                    new_blocks.push(current_block);
+                    current_block = block.clone();
+                    if current_block.header.height == 0 {
+                        new_blocks.push(current_block);
+                        break;
+                    }
+                }
+                new_blocks.reverse();
+
+                // The branch must reach back to genesis; pruning may have dropped its ancestors
+                if new_blocks.first().map(|b| b.header.height) != Some(0) {
+                    return Err(ChainError::PrunedData(
+                        "Fork branch is disconnected from genesis".to_string()
+                    ));
+                }
+
+                // CRITICAL: Rebuild the UTXO state from genesis, or from the prune
+                // point on a pruned node, by replaying every block after it
+                let mut state = self.prune_base.clone().unwrap_or_else(genesis_state);
+                for block in &new_blocks[(self.pruned_height as usize + 1)..] {
+                    // Main-chain blocks were checked when they were connected, but
+                    // the branch's only now; one that is invalid on it sinks it
+                    let checked = if self.block_index.contains_key(&block.hash) {
+                        Ok(())
+                    } else {
+                        self.validate_block_transactions(block, &state)
+                    };
+                    if let Err(e) = checked.and_then(|_| state.apply_block_transactions(block)) {
+                        self.reject_branch(block.hash, valid_block.hash);
+                        return Err(e);
+                    }
+                }
+                // Nothing the database holds can be assumed to still match
+                state.utxo_changes = UtxoChanges::All;
+                self.state = state;
+
+                let old_blocks = std::mem::replace(&mut self.blocks, new_blocks);
+                self.adjust_difficulty();
+                self.publish_reorg_events(&old_blocks);
+                self.reindex_after_reorg(old_blocks);
+                self.sync_mempool_tip();
+                self.mempool.validate_and_prune(&self.state);
+
+                println!("✅ Fork reorganization complete - state rebuilt");
+            }
+        } else {
+            // Case 3: Orphan block
+            return Err(ChainError::OrphanBlock);
+        }
+
+        Ok(())
+    }
+
+    /// Enable pruning, keeping full transactions only for the most recent `depth` blocks
+    pub fn enable_pruning(&mut self, depth: BlockHeight) -> Result<usize, ChainError> {
+        self.prune_depth = Some(depth);
+        self.prune()
+    }
+
+    /// Whether a block's transactions have been dropped by pruning
+    pub fn is_pruned(&self, block: &Block) -> bool {
+        block.header.height > 0 && block.header.height <= self.pruned_height
+    }
+
+    /// Drop transaction data for main-chain blocks deeper than `prune_depth`.
+    /// Headers are kept so the chain can still be validated and served as headers.
+    /// Returns the number of blocks pruned.
+    pub fn prune(&mut self) -> Result<usize, ChainError> {
+        let depth = match self.prune_depth {
+            Some(depth) => depth,
+            None => return Ok(0),
+        };
+
+        let tip_height = self.blocks.last().unwrap().header.height;
+        let target = tip_height.saturating_sub(depth);
+        if target <= self.pruned_height {
+            return Ok(0);
+        }
+
+        // Advance the replay base over the blocks being pruned before dropping them
+        let mut base = self.prune_base.take().unwrap_or_else(genesis_state);
+        let start = self.pruned_height as usize + 1;
+        for block in &mut self.blocks[start..=target as usize] {
+            base.apply_block_transactions(block)?;
+            block.transactions = BlockTransactions::default();
+        }
+        self.prune_base = Some(base);
+        self.pruned_height = target;
+
+        // Forks below the prune point can never be reorganized onto
+        self.forks.retain(|_, block| block.header.height > target);
+
+        Ok(target as usize + 1 - start)
+    }
+
+    /// The lowest nonce `address` can use for a new transaction: above both its last
+    /// confirmed nonce and any nonce it has pending in the mempool
+    pub fn next_nonce(&self, address: &Address) -> u64 {
+        let confirmed = self.state.nonces.get(address).copied();
+        let pending = self.mempool.transactions.values()
+            .filter(|tx| tx.sender() == Some(address))
+            .filter_map(|tx| tx.nonce())
+            .max();
+        confirmed.max(pending).map_or(0, |last| last + 1)
+    }
+
+    /// Add a transaction to the mempool after checking its nonce, and its inputs
+    /// against the current state. Returns the hash of any transaction it replaced by fee.
+    pub fn submit_transaction(&mut self, tx: Transaction) -> Result<Option<Sha256Hash>, ChainError> {
+        self.accept_transaction(tx).into_result()
+    }
+
+    /// Check a transaction against the current state and offer it to the
+    /// mempool, reporting in detail what happened to it
+    pub fn accept_transaction(&mut self, tx: Transaction) -> MempoolAcceptResult {
+        let tx_hash = tx.hash();
+        if let (Some(sender), Some(nonce)) = (tx.sender(), tx.nonce()) {
+            if let Err(e) = self.state.check_nonce(sender, nonce) {
+                return MempoolAcceptResult::rejected(tx_hash, RejectReason::StaleNonce, e);
+            }
+        }
+        self.mempool.accept_transaction(tx, &self.state)
+    }
+
+    /// Mark the fork blocks from `first` up to `tip` invalid and drop them,
+    /// pointing the header chain back at the main chain's tip
+    fn reject_branch(&mut self, first: Sha256Hash, tip: Sha256Hash) {
+        let mut current = tip;
+        while let Some(block) = self.forks.remove(&current) {
+            self.invalid_blocks.insert(current);
+            if current == first {
+                break;
+            }
+            current = block.header.previous_hash;
+        }
+        self.header_chain.reset_best(self.blocks.last().unwrap().hash);
+    }
+
+    /// Whether a block hash has been marked invalid
+    pub fn is_known_invalid(&self, hash: &Sha256Hash) -> bool {
+        self.invalid_blocks.contains(hash)
+    }
+
+    /// Reject blocks (or headers) already marked invalid. A block building on an
+    /// invalid parent is marked invalid too, without re-running validation.
+    fn check_not_known_invalid(&mut self, hash: &Sha256Hash, header: &BlockHeader) -> Result<(), ChainError> {
+        if self.invalid_blocks.contains(hash) {
+            return Err(ChainError::KnownInvalidBlock(hex::encode(hash)));
+        }
+
+        if self.invalid_blocks.contains(&header.previous_hash) {
+            // Only remember the hash if it really belongs to this header
+            if header.calculate_hash() == *hash {
+                self.invalid_blocks.insert(*hash);
+            }
+            return Err(ChainError::KnownInvalidBlock(format!(
+                "{} descends from invalid block {}",
+                hex::encode(hash), hex::encode(header.previous_hash)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Whether a validation failure will never go away, so the block can be
+    /// remembered as invalid. Missing parents, pruned data and clock drift may
+    /// all succeed later.
+    fn is_permanently_invalid(&self, block: &Block, error: &ChainError) -> bool {
+        if block.calculate_hash() != block.hash {
+            return false;
+        }
+
+        if block.header.timestamp > Utc::now().timestamp() + MAX_FUTURE_TIMESTAMP_DRIFT {
+            return false;
+        }
+
+        match error {
+            ChainError::OrphanBlock | ChainError::PrunedData(_) => false,
+            ChainError::InvalidBlockLinkage => self.contains_block(&block.header.previous_hash),
+            _ => true,
+        }
+    }
+
+    /// Look up a main-chain or fork block by hash
+    pub fn get_block(&self, hash: &Sha256Hash) -> Option<&Block> {
+        match self.block_index.get(hash) {
+            Some(height) => self.blocks.get(*height as usize),
+            None => self.forks.get(hash),
+        }
+    }
+
+    /// Inscriptions on a triangle in main-chain order, with the height of each.
+    /// Inscriptions in pruned blocks are no longer available.
+    pub fn inscriptions(&self, triangle: &Sha256Hash) -> Result<Vec<(BlockHeight, &InscriptionTx)>, ChainError> {
+        let mut inscriptions = Vec::new();
+        for block in &self.blocks {
+            for tx in block.transactions.get()? {
+                match tx {
+                    Transaction::Inscription(inscription) if inscription.input_hash == *triangle => {
+                        inscriptions.push((block.header.height, inscription));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(inscriptions)
+    }
+
+    /// Whether a block is known, either on the main chain or a fork
+    pub fn contains_block(&self, hash: &Sha256Hash) -> bool {
+        self.block_index.contains_key(hash) || self.forks.contains_key(hash)
+    }
+
+    /// After switching to a new main chain, move blocks that left it into `forks`,
+    /// index the blocks that joined it by height, and update the supply at tip
+    fn reindex_after_reorg(&mut self, old_blocks: Vec<Block>) {
+        let fork_index = common_prefix_len(&old_blocks, &self.blocks);
+
+        for block in old_blocks.into_iter().skip(fork_index) {
+            self.supply_at_tip -= Self::calculate_block_reward(block.header.height);
+            self.block_index.remove(&block.hash);
+            self.forks.insert(block.hash, block);
+        }
+        for block in &self.blocks[fork_index..] {
+            self.supply_at_tip += Self::calculate_block_reward(block.header.height);
+            self.forks.remove(&block.hash);
+            self.block_index.insert(block.hash, block.header.height);
+        }
+    }
+
+    /// Subscribe to block, transaction, and reorganization events
+    pub fn subscribe(&self) -> broadcast::Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+
+    /// Publish disconnect/connect events for a switch from `old_blocks` to the current main chain
+    fn publish_reorg_events(&self, old_blocks: &[Block]) {
+        let fork_index = common_prefix_len(old_blocks, &self.blocks);
+
+        for block in old_blocks[fork_index..].iter().rev() {
+            self.events.publish(ChainEvent::BlockDisconnected {
+                hash: block.hash,
+                height: block.header.height,
+            });
+        }
+        for block in &self.blocks[fork_index..] {
+            self.events.publish(ChainEvent::BlockConnected {
+                hash: block.hash,
+                height: block.header.height,
+            });
+        }
+
+        if let (Some(old_tip), Some(new_tip)) = (old_blocks.last(), self.blocks.last()) {
+            self.events.publish(ChainEvent::ReorgCompleted {
+                old_tip: old_tip.hash,
+                new_tip: new_tip.hash,
+                fork_height: fork_index.saturating_sub(1) as BlockHeight,
+            });
+        }
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_PAST_WINDOW` blocks ending at `tip`
+    pub fn median_time_past(&self, tip: &Sha256Hash) -> i64 {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_PAST_WINDOW);
+        let mut current = self.get_block(tip);
+        while let Some(block) = current {
+            timestamps.push(block.header.timestamp);
+            if timestamps.len() == MEDIAN_TIME_PAST_WINDOW || block.header.height == 0 {
+                break;
+            }
+            current = self.get_block(&block.header.previous_hash);
+        }
+        median_timestamp(timestamps)
+    }
+
+    /// Validate and store a block header without its transactions.
+    /// Returns the header hash. Headers that are already known are accepted as-is.
+    pub fn accept_header(&mut self, header: BlockHeader) -> Result<Sha256Hash, ChainError> {
+        let hash = header.calculate_hash();
+        if self.header_chain.contains(&hash) {
+            return Ok(hash);
+        }
+
+        self.check_not_known_invalid(&hash, &header)?;
+        self.header_chain.validate_header(&header, &*self.pow)?;
+        self.header_chain.insert(hash, header);
+        Ok(hash)
+    }
+
+    /// Connect a full block whose header was previously accepted via `accept_header`.
+    pub fn connect_block_for_header(&mut self, block: Block) -> Result<(), ChainError> {
+        if !self.header_chain.contains(&block.hash) {
+            return Err(ChainError::HeaderNotFound(hex::encode(block.hash)));
+        }
+
+        if block.calculate_hash() != block.hash {
+            return Err(ChainError::InvalidProofOfWork);
+        }
+
+        self.apply_block(block)
+    }
+
+    /// Hashes of headers on the best header chain whose full blocks have not been
+    /// connected yet, ordered from lowest to highest height.
+    pub fn missing_block_hashes(&self, limit: usize) -> Vec<Sha256Hash> {
+        let mut missing = Vec::new();
+        let mut current = self.header_chain.best_hash();
+
+        while !self.contains_block(&current) {
+            let header = match self.header_chain.get(&current) {
+                Some(header) => header,
+                None => break,
+            };
+            missing.push(current);
+            current = header.previous_hash;
+        }
+
+        missing.reverse();
+        missing.truncate(limit);
+        missing
+    }
+
+    /// Main-chain hashes from the tip back to genesis, one per block for the
+    /// last ten and then twice as far apart each step. A peer finds the
+    /// newest one it also has and sends the headers after it.
+    pub fn block_locator(&self) -> Vec<Sha256Hash> {
+        let mut locator = Vec::new();
+        let mut height = self.blocks.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(self.blocks[height].hash);
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// Headers of up to `limit` main-chain blocks after the newest block in
+    /// `locator` that is on the main chain, or after genesis if none are
+    pub fn headers_after_locator(&self, locator: &[Sha256Hash], limit: usize) -> Vec<BlockHeader> {
+        let fork_height = locator.iter()
+            .find_map(|hash| self.block_index.get(hash))
+            .copied()
+            .unwrap_or(0);
+        self.blocks.iter()
+            .skip(fork_height as usize + 1)
+            .take(limit)
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    /// The state the blocks since the pruning point (or genesis) lead to,
+    /// rebuilt by replaying them without re-validating
+    pub fn replay_state(&self) -> Result<TriangleState, ChainError> {
+        let mut state = self.prune_base.clone().unwrap_or_else(genesis_state);
+        for block in self.blocks.iter().skip(self.pruned_height as usize + 1) {
+            state.apply_block_transactions(block).map_err(|e| ChainError::InvalidTransaction(format!(
+                "Block {} fails to replay: {}", block.header.height, e
+            )))?;
+        }
+        Ok(state)
+    }
+
+    /// Height at which each unspent triangle was created, found by replaying
+    /// the chain. Triangles that already existed at the pruning point (or
+    /// genesis) are reported at that height.
+    pub fn creation_heights(&self) -> HashMap<TriangleId, BlockHeight> {
+        let mut state = self.prune_base.clone().unwrap_or_else(genesis_state);
+        let mut heights: HashMap<TriangleId, BlockHeight> = state.utxo_set.keys()
+            .map(|id| (*id, self.pruned_height))
+            .collect();
+
+        for block in self.blocks.iter().skip(self.pruned_height as usize + 1) {
+            if state.apply_block_transactions(block).is_err() {
+                break;
+            }
+            for id in state.utxo_set.keys() {
+                heights.entry(*id).or_insert(block.header.height);
+            }
+        }
+
+        heights.retain(|id, _| self.state.utxo_set.contains_key(id));
+        heights
+    }
+
+    /// History of every triangle that existed since the pruning point (or
+    /// genesis), found by replaying the chain and diffing the UTXO set after
+    /// each block
+    fn triangle_records(&self) -> HashMap<TriangleId, TriangleRecord> {
+        let mut state = self.prune_base.clone().unwrap_or_else(genesis_state);
+        let mut records: HashMap<TriangleId, TriangleRecord> = state.utxo_set.iter()
+            .map(|(id, triangle)| (*id, TriangleRecord {
+                id: *id,
+                triangle: triangle.clone(),
+                created_height: self.pruned_height,
+                created_by: None,
+                owners: vec![(self.pruned_height, triangle.owner.clone())],
+                spent: None,
+            }))
+            .collect();
+
+        for block in self.blocks.iter().skip(self.pruned_height as usize + 1) {
+            let height = block.header.height;
+            let before = state.utxo_set.clone();
+            let Ok(transactions) = block.transactions.get() else {
+                break;
+            };
+            if state.apply_block_transactions(block).is_err() {
+                break;
+            }
+
+            for (id, triangle) in &before {
+                if state.utxo_set.contains_key(id) {
+                    continue;
+                }
+                let spender = transactions.iter()
+                    .find(|tx| tx.inputs().contains(id))
+                    .map(|tx| tx.hash())
+                    .unwrap_or_default();
+                if let Some(record) = records.get_mut(id) {
+                    record.triangle = triangle.clone();
+                    record.spent = Some((height, spender));
+                }
+            }
+            for (id, triangle) in &state.utxo_set {
+                let record = records.entry(*id).or_insert_with(|| TriangleRecord {
+                    id: *id,
+                    triangle: triangle.clone(),
+                    created_height: height,
+                    // Children come from the transaction spending their parent,
+                    // roots from the coinbase
+                    created_by: transactions.iter()
+                        .find(|tx| match triangle.parent_hash {
+                            Some(parent) => tx.inputs().contains(&parent),
+                            None => matches!(tx, Transaction::Coinbase(_)),
+                        })
+                        .map(|tx| tx.hash()),
+                    owners: Vec::new(),
+                    spent: None,
+                });
+                if record.owners.last().is_none_or(|(_, owner)| *owner != triangle.owner) {
+                    record.owners.push((height, triangle.owner.clone()));
+                }
+                record.triangle = triangle.clone();
+            }
+        }
+        records
+    }
+
+    /// The triangle `hash` followed by its parent, grandparent and so on up to
+    /// its root, with the block that created and spent each one. The walk stops
+    /// early at the pruning point, past which history is gone.
+    pub fn triangle_ancestry(&self, hash: &TriangleId) -> Result<Vec<TriangleRecord>, ChainError> {
+        let mut records = self.triangle_records();
+        let mut ancestry = vec![records.remove(hash)
+            .ok_or_else(|| ChainError::TriangleNotFound(hex::encode(hash)))?];
+        while let Some(parent) = ancestry.last().unwrap().triangle.parent_hash.and_then(|p| records.remove(&p)) {
+            ancestry.push(parent);
+        }
+        Ok(ancestry)
+    }
+
+    /// Every triangle subdivided out of `hash`, spent or not, as a tree with
+    /// children in subdivision order
+    pub fn triangle_descendants(&self, hash: &TriangleId) -> Result<FamilyTree, ChainError> {
+        let mut records = self.triangle_records();
+        let mut children: HashMap<TriangleId, Vec<TriangleId>> = HashMap::new();
+        for record in records.values() {
+            if let Some(parent) = record.triangle.parent_hash {
+                children.entry(parent).or_default().push(record.id);
+            }
+        }
+
+        fn build(
+            id: TriangleId,
+            records: &mut HashMap<TriangleId, TriangleRecord>,
+            children: &HashMap<TriangleId, Vec<TriangleId>>,
+        ) -> Option<FamilyTree> {
+            let record = records.remove(&id)?;
+            let mut kids = children.get(&id).cloned().unwrap_or_default();
+            kids.sort_by_key(|kid| (records.get(kid).map(|r| r.triangle.lineage), *kid));
+            let children = kids.into_iter().filter_map(|kid| build(kid, records, children)).collect();
+            Some(FamilyTree { record, children })
+        }
+
+        build(*hash, &mut records, &children)
+            .ok_or_else(|| ChainError::TriangleNotFound(hex::encode(hash)))
+    }
+
+    /// Re-validate proof of work, linkage, merkle roots, and transactions for the
+    /// last `depth` blocks, replaying the UTXO set and comparing it against `state`.
+    /// Blocks whose transactions were pruned are skipped.
+    pub fn verify_chain(&self, depth: usize) -> ChainVerificationReport {
+        let tip_height = self.blocks.last().unwrap().header.height;
+        let start_height = (tip_height + 1)
+            .saturating_sub(depth as BlockHeight)
+            .max(self.pruned_height + 1);
+
+        let mut problems = Vec::new();
+        let mut state = self.prune_base.clone().unwrap_or_else(genesis_state);
+        let mut replay_ok = true;
+
+        // Bring the state up to the first checked block without re-validating
+        for block in self.blocks.iter().skip(self.pruned_height as usize + 1).take_while(|b| b.header.height < start_height) {
+            if let Err(e) = state.apply_block_transactions(block) {
+                problems.push(ChainProblem {
+                    height: block.header.height,
+                    hash: block.hash,
+                    description: format!("Failed to replay transactions: {}", e),
+                });
+                replay_ok = false;
+                break;
+            }
+        }
+
+        let mut blocks_checked = 0;
+        for height in start_height..=tip_height {
+            let block = &self.blocks[height as usize];
+            let parent = &self.blocks[height as usize - 1];
+            blocks_checked += 1;
+
+            let mut problem = |description: String| problems.push(ChainProblem {
+                height,
+                hash: block.hash,
+                description,
+            });
+
+            if block.header.height != height || block.header.previous_hash != parent.hash {
+                problem("Block does not link to its parent".to_string());
+            }
+            if block.calculate_hash() != block.hash {
+                problem("Stored hash does not match header".to_string());
+            }
+            if !self.pow.meets_target(&block.hash, block.header.bits) {
+                problem("Invalid proof of work".to_string());
+            }
+            let transactions = match block.transactions.get() {
+                Ok(transactions) => transactions,
+                Err(e) => {
+                    problem(e.to_string());
+                    replay_ok = false;
+                    continue;
+                }
+            };
+            if block.header.merkle_root != Block::calculate_merkle_root(transactions) {
+                problem("Merkle root does not match transactions".to_string());
+            }
+
+            if !replay_ok {
+                continue;
+            }
+
+            for tx in transactions {
+                let result = match tx {
+                    Transaction::Subdivision(sub_tx) => sub_tx.validate_cached(&state, &self.sig_cache),
+                    Transaction::Coinbase(cb_tx) => cb_tx.validate(),
+                    Transaction::Transfer(transfer_tx) => transfer_tx.validate_cached(&self.sig_cache),
+                    Transaction::BatchTransfer(batch_tx) => batch_tx.validate_cached(&self.sig_cache),
+                    Transaction::Swap(swap_tx) => swap_tx.validate_cached(&self.sig_cache),
+                    Transaction::Lease(lease_tx) => lease_tx.validate_cached(&self.sig_cache),
+                    Transaction::Approve(approve_tx) => approve_tx.validate_cached(&self.sig_cache),
+                    Transaction::HtlcLock(lock_tx) => lock_tx.validate_cached(&self.sig_cache),
+                    Transaction::HtlcRedeem(redeem_tx) => redeem_tx.validate_cached(&self.sig_cache),
+                    Transaction::EscrowLock(lock_tx) => lock_tx.validate_cached(&self.sig_cache),
+                    Transaction::EscrowSettle(settle_tx) => settle_tx.validate_cached(&self.sig_cache),
+                    Transaction::Offer(offer_tx) => offer_tx.validate_cached(&self.sig_cache),
+                    Transaction::Accept(accept_tx) => accept_tx.validate_cached(&self.sig_cache),
+                    Transaction::Inscription(inscription_tx) => inscription_tx.validate_cached(&self.sig_cache),
+                    Transaction::DeepSubdivision(deep_tx) => deep_tx.validate_cached(&self.sig_cache).and_then(|_| {
+                        match state.utxo_set.get(&deep_tx.parent_hash) {
+                            Some(parent) => deep_tx.validate_against_parent(parent),
+                            None => Err(ChainError::UtxoMissing { hash: deep_tx.parent_hash }),
+                        }
+                    }),
+                    Transaction::SplitTransfer(split_tx) => split_tx.validate_cached(&self.sig_cache).and_then(|_| {
+                        match state.utxo_set.get(&split_tx.parent_hash) {
+                            Some(parent) => split_tx.validate_against_parent(parent),
+                            None => Err(ChainError::UtxoMissing { hash: split_tx.parent_hash }),
+                        }
+                    }),
+                };
+                if let Err(e) = result {
+                    problem(format!("Invalid transaction {}: {}", tx.hash_str(), e));
+                }
+            }
+
+            if let Err(e) = state.apply_block_transactions(block) {
+                problem(format!("Failed to replay transactions: {}", e));
+                replay_ok = false;
+            }
+        }
+
+        ChainVerificationReport {
+            start_height,
+            tip_height,
+            blocks_checked,
+            state_matches: replay_ok && state.utxo_set == self.state.utxo_set,
+            problems,
+        }
+    }
+
+    /// Check that coinbase issuance stays within the halving schedule (plus fees)
+    /// and that subdivisions conserve area: each one keeps exactly 3/4 of its
+    /// parent, and the UTXO set adds up to what the blocks imply.
+    pub fn audit_supply(&self) -> SupplyAudit {
+        let tip_height = self.blocks.last().unwrap().header.height;
+        let start_height = self.pruned_height + 1;
+
+        let mut problems = Vec::new();
+        let mut state = self.prune_base.clone().unwrap_or_else(genesis_state);
+        let mut expected_utxo_area: f64 = state.utxo_set.values().map(|t| t.area()).sum();
+        let mut issued_rewards = 0u64;
+        let mut fees_collected = 0u64;
+
+        for block in self.blocks.iter().skip(start_height as usize) {
+            let height = block.header.height;
+            let transactions = match block.transactions.get() {
+                Ok(transactions) => transactions,
+                Err(e) => {
+                    problems.push(format!("Block {}: {}", height, e));
+                    break;
+                }
+            };
+            fees_collected = fees_collected.saturating_add(Self::calculate_total_fees(transactions));
+
+            for tx in transactions {
+                match tx {
+                    Transaction::Coinbase(cb_tx) => {
+                        issued_rewards = issued_rewards.saturating_add(cb_tx.reward_area());
+                        expected_utxo_area += cb_tx.reward_area() as f64;
+                    }
+                    Transaction::Subdivision(sub_tx) => {
+                        if let Some(parent) = state.utxo_set.get(&sub_tx.parent_hash) {
+                            let kept = parent.area() * 0.75;
+                            let children: f64 = sub_tx.children.iter().map(|c| c.area()).sum();
+                            if !areas_match(kept, children) {
+                                problems.push(format!(
+                                    "Block {}: subdivision {} children have area {} but should keep {}",
+                                    height, tx.hash_str(), children, kept
+                                ));
+                            }
+                            expected_utxo_area += children - parent.area();
+                        }
+                    }
+                    Transaction::DeepSubdivision(deep_tx) => {
+                        if let Some(parent) = state.utxo_set.get(&deep_tx.parent_hash) {
+                            let leaves: f64 = deep_tx.leaves(parent).iter().map(|t| t.area()).sum();
+                            expected_utxo_area += leaves - parent.area();
+                        }
+                    }
+                    Transaction::SplitTransfer(split_tx) => {
+                        if let Some(parent) = state.utxo_set.get(&split_tx.parent_hash) {
+                            let leaves: f64 = split_tx.outputs(parent).iter().map(|t| t.area()).sum();
+                            expected_utxo_area += leaves - parent.area();
+                        }
+                    }
+                    Transaction::Transfer(_)
+                    | Transaction::BatchTransfer(_)
+                    | Transaction::HtlcLock(_)
+                    | Transaction::HtlcRedeem(_)
+                    | Transaction::EscrowLock(_)
+                    | Transaction::EscrowSettle(_)
+                    | Transaction::Offer(_)
+                    | Transaction::Accept(_)
+                    | Transaction::Inscription(_)
+                    | Transaction::Swap(_)
+                    | Transaction::Lease(_)
+                    | Transaction::Approve(_) => {}
+                }
+            }
+
+            if let Err(e) = state.apply_block_transactions(block) {
+                problems.push(format!("Block {}: failed to replay transactions: {}", height, e));
+                break;
+            }
+        }
+
+        let scheduled_rewards = Self::calculate_current_supply(tip_height)
+            - Self::calculate_current_supply(self.pruned_height);
+        if issued_rewards > scheduled_rewards.saturating_add(fees_collected) {
+            problems.push(format!(
+                "Issued rewards {} exceed scheduled subsidy {} plus fees {}",
+                issued_rewards, scheduled_rewards, fees_collected
+            ));
+        }
+
+        let utxo_area: f64 = self.state.utxo_set.values().map(|t| t.area()).sum();
+        if !areas_match(expected_utxo_area, utxo_area) {
+            problems.push(format!(
+                "UTXO set area {} does not match expected area {}",
+                utxo_area, expected_utxo_area
+            ));
+        }
+
+        SupplyAudit {
+            start_height,
+            tip_height,
+            issued_rewards,
+            scheduled_rewards,
+            fees_collected,
+            expected_utxo_area,
+            utxo_area,
+            problems,
+        }
+    }
+
+    /// Calculate the block reward for a given block height (with halving)
+    pub fn calculate_block_reward(height: BlockHeight) -> u64 {
+        let halvings = height / REWARD_HALVING_INTERVAL;
+        if halvings >= MAX_HALVINGS {
+            // After 64 halvings, reward is 0
+            return 0;
+        }
+        INITIAL_MINING_REWARD >> halvings
+    }
+
+    /// Calculate the total supply that has been mined up to a given block height
+    /// This accounts for all halvings that have occurred
+    /// This sums whole halving eras, so it costs at most `MAX_HALVINGS` steps
+    pub fn calculate_current_supply(height: BlockHeight) -> u64 {
+        if height == 0 {
+            return 0;
+        }
+
+        let mut total_supply = 0u64;
+        let last_era = (height / REWARD_HALVING_INTERVAL).min(MAX_HALVINGS - 1);
+
+        for era in 0..=last_era {
+            // Block 0 (genesis) carries no reward
+            let era_start = (era * REWARD_HALVING_INTERVAL).max(1);
+            let era_end = ((era + 1) * REWARD_HALVING_INTERVAL - 1).min(height);
+            let blocks = era_end - era_start + 1;
+            let reward = INITIAL_MINING_REWARD >> era;
+            total_supply = total_supply.saturating_add(blocks.saturating_mul(reward));
+        }
+
+        total_supply
+    }
+
+    /// Calculate remaining supply that can still be mined
+    pub fn calculate_remaining_supply(&self) -> u64 {
+        MAX_SUPPLY.saturating_sub(self.supply_at_tip)
+    }
+
+    /// Get percentage of total supply mined
+    pub fn supply_percentage(&self) -> f64 {
+        (self.supply_at_tip as f64 / MAX_SUPPLY as f64) * 100.0
+    }
+
+    /// Get the current halving era (0 = first era, 1 = first halving, etc.)
+    pub fn current_halving_era(&self) -> u64 {
+        self.blocks.last().unwrap().header.height / REWARD_HALVING_INTERVAL
+    }
+
+    /// Blocks until next halving
+    pub fn blocks_until_next_halving(&self) -> u64 {
+        let current_height = self.blocks.last().unwrap().header.height;
+        let next_halving_height = (self.current_halving_era() + 1) * REWARD_HALVING_INTERVAL;
+        next_halving_height.saturating_sub(current_height)
+    }
+
+    /// Calculate total transaction fees in a block
+    pub fn calculate_total_fees(transactions: &[Transaction]) -> u64 {
+        transactions.iter()
+            .filter(|tx| !matches!(tx, Transaction::Coinbase(_)))
+            .map(|tx| tx.fee())
+            .fold(0u64, |acc, fee| acc.saturating_add(fee))
+    }
+
+    /// The bits a block extending `parent` must carry, found from the chain
+    /// `parent` is on
+    pub fn next_bits(&self, parent: &BlockHeader) -> u32 {
+        next_bits(parent, self.pow_limit_bits(), |hash| self.get_block(hash).map(|block| &block.header))
+    }
+
+    /// The easiest target this chain allows: its genesis block's
+    pub fn pow_limit_bits(&self) -> u32 {
+        self.blocks[0].header.bits
+    }
+
+    /// Set `bits` to what the next block on the main chain must carry.
+    /// Bitcoin-style clamping limits each retarget to 4x in either direction,
+    /// which prevents wild swings while still allowing quick convergence.
+    fn adjust_difficulty(&mut self) {
+        let old_bits = self.bits;
+        let new_bits = self.next_bits(&self.blocks.last().unwrap().header);
+        if new_bits == old_bits {
+            return;
+        }
+        self.bits = new_bits;
+
+        println!("⚙️  Difficulty adjusted: {:.2} -> {:.2} (bits: {:#010x}, target: {}s)",
+                 difficulty::difficulty(old_bits), difficulty::difficulty(new_bits), new_bits,
+                 TARGET_BLOCK_TIME_SECONDS);
+    }
+
+    /// Human-readable difficulty of the current target, relative to the proof-of-work limit
+    pub fn difficulty(&self) -> f64 {
+        difficulty::difficulty(self.bits)
+    }
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use crate::geometry::Point;
+    use crate::transaction::{SubdivisionTx, Transaction};
+    use crate::crypto::KeyPair;
+
+    #[test]
+    fn test_genesis_triangle_is_canonical() {
+        let genesis = genesis_triangle();
+        assert_eq!(genesis.a.x, 0.0);
+        assert_eq!(genesis.a.y, 0.0);
+        assert_eq!(genesis.b.x, 1.0);
+        assert_eq!(genesis.c.x, 0.5);
+        assert!((genesis.c.y - 0.866025403784).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_block_merkle_root_calculation() {
+        let coinbase = CoinbaseTx::new(1000, "test".to_string());
+        let transactions = vec![Transaction::Coinbase(coinbase)];
+        let merkle = Block::calculate_merkle_root(&transactions);
+        assert!(!merkle.is_empty());
+    }
+
+    #[test]
+    fn test_merkle_tree_empty() {
+        let root = Block::calculate_merkle_root(&[]);
+        assert_eq!(root, [0; 32]);
+    }
+
+    #[test]
+    fn test_merkle_tree_single() {
+        let coinbase = CoinbaseTx::new(1000, "miner".to_string());
+        let txs = vec![Transaction::Coinbase(coinbase)];
+        let root = Block::calculate_merkle_root(&txs);
+        assert_eq!(root.len(), 32);
+    }
+
+    #[test]
+    fn test_merkle_tree_even() {
+        let tx1 = Transaction::Coinbase(CoinbaseTx::new(1000, "miner1".to_string()));
+        let tx2 = Transaction::Coinbase(CoinbaseTx::new(2000, "miner2".to_string()));
+        let root = Block::calculate_merkle_root(&[tx1, tx2]);
+        assert_eq!(root.len(), 32);
+    }
+
+    #[test]
+    fn test_merkle_tree_odd() {
+        let tx1 = Transaction::Coinbase(CoinbaseTx::new(1000, "miner1".to_string()));
+        let tx2 = Transaction::Coinbase(CoinbaseTx::new(2000, "miner2".to_string()));
+        let tx3 = Transaction::Coinbase(CoinbaseTx::new(3000, "miner3".to_string()));
+        let root = Block::calculate_merkle_root(&[tx1, tx2, tx3]);
+        assert_eq!(root.len(), 32);
+    }
+
+    #[test]
+    fn test_merkle_branch() {
+        for count in 1..=7 {
+            let txs: Vec<Transaction> = (0..count)
+                .map(|i| Transaction::Coinbase(CoinbaseTx::new(1000 + i, "miner".to_string())))
+                .collect();
+            let root = Block::calculate_merkle_root(&txs);
+            for (index, tx) in txs.iter().enumerate() {
+                let branch = Block::merkle_branch(&txs, index);
+                assert_eq!(Block::merkle_root_from_branch(tx.hash(), index, &branch), root);
+            }
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let txs: Vec<Transaction> = (0..5)
+            .map(|i| Transaction::Coinbase(CoinbaseTx::new(1000 + i, "miner".to_string())))
+            .collect();
+        let block = Block::new(1, [0; 32], difficulty::REGTEST_POW_LIMIT_BITS, txs.clone());
+
+        let proof = block.merkle_proof(&txs[3].hash()).unwrap().unwrap();
+        assert_eq!(proof.index, 3);
+        assert!(verify_merkle_proof(&block.header, &proof));
+
+        // A proof for another transaction, or at another position, fails
+        let mut forged = proof.clone();
+        forged.txid = txs[2].hash();
+        assert!(!verify_merkle_proof(&block.header, &forged));
+        let mut moved = proof;
+        moved.index = 2;
+        assert!(!verify_merkle_proof(&block.header, &moved));
+
+        assert!(block.merkle_proof(&[9; 32]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_roll_extra_nonce() {
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+        let mut block = mine_block_on(&genesis, chain.bits, "miner");
+        let coinbase_hash = block.transactions.get().unwrap()[0].hash();
+        let merkle_root = block.header.merkle_root;
+
+        block.roll_extra_nonce().unwrap();
+        assert_ne!(block.transactions.get().unwrap()[0].hash(), coinbase_hash);
+        assert_ne!(block.header.merkle_root, merkle_root);
+        assert_eq!(block.header.merkle_root, Block::calculate_merkle_root(block.transactions.get().unwrap()));
+
+        // The rolled block is still a valid block once its header is re-mined
+        block.header.nonce = 0;
+        block.hash = block.calculate_hash();
+        while !block.verify_proof_of_work() {
+            block.header.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
+        chain.apply_block(block).unwrap();
+
+        let mut no_coinbase = Block::new(2, genesis.hash, chain.bits, vec![]);
+        assert!(no_coinbase.roll_extra_nonce().is_err());
+    }
+
+    #[test]
+    fn test_apply_block_updates_state() {
+        let mut chain = Blockchain::regtest();
+        let initial_count = chain.state.count();
+
+        let genesis_hash = *chain.state.utxo_set.keys().next().unwrap();
+        let genesis_tri = chain.state.utxo_set.get(&genesis_hash).unwrap().clone();
+        let children = genesis_tri.subdivide();
+
+        let keypair = KeyPair::generate().unwrap();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+        let address = keypair.address();
+
+        let mut tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address.clone(), 0, 1);
+        let message = tx.signable_message();
+        let signature = keypair.sign(&message).unwrap();
+        let public_key = keypair.public_key.serialize().to_vec();
+        tx.sign(signature, public_key);
+
+        let coinbase = CoinbaseTx::new(1000, address);
+
+        let transactions = vec![
+            Transaction::Coinbase(coinbase),
+            Transaction::Subdivision(tx),
+        ];
+
+        let last_block = chain.blocks.last().unwrap();
+        let mut new_block = Block::new(
+            last_block.header.height + 1,
+            last_block.hash,
+            chain.bits,
+            transactions,
+        );
+
+        // Ensure timestamp is greater than parent
+        new_block.header.timestamp = last_block.header.timestamp + 1;
+        new_block.hash = new_block.calculate_hash();
+
+        while !new_block.verify_proof_of_work() {
+            new_block.header.nonce += 1;
+            new_block.hash = new_block.calculate_hash();
+        }
+
+        chain.apply_block(new_block).unwrap();
+
+        // Initial state has 1 triangle (genesis).
+        // Subdivision tx consumes 1 and creates 3 (+2).
+        // Coinbase tx creates 1 (+1).
+        // Total should be 1 + 2 + 1 = 4.
+        assert_eq!(chain.state.count(), initial_count + 3);
+    }
+
+    #[test]
+    fn test_block_validation_success() {
+        let mut chain = Blockchain::regtest();
+        let genesis_hash = *chain.state.utxo_set.keys().next().unwrap();
+        let genesis_tri = chain.state.utxo_set.get(&genesis_hash).unwrap().clone();
+        let children = genesis_tri.subdivide();
+
+        let keypair = KeyPair::generate().unwrap();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+        let address = keypair.address();
+
+        let mut tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address.clone(), 0, 1);
+        let message = tx.signable_message();
+        let signature = keypair.sign(&message).unwrap();
+        let public_key = keypair.public_key.serialize().to_vec();
+        tx.sign(signature, public_key);
+
+        let coinbase = CoinbaseTx::new(1000, address);
+
+        let transactions = vec![
+            Transaction::Coinbase(coinbase),
+            Transaction::Subdivision(tx),
+        ];
+
+        let last_block = chain.blocks.last().unwrap();
+        let mut new_block = Block::new(
+            last_block.header.height + 1,
+            last_block.hash,
+            chain.bits,
+            transactions,
+        );
+
+        // Ensure timestamp is greater than parent
+        new_block.header.timestamp = last_block.header.timestamp + 1;
+        new_block.hash = new_block.calculate_hash();
+
+        while !new_block.verify_proof_of_work() {
+            new_block.header.nonce += 1;
+            new_block.hash = new_block.calculate_hash();
+        }
+
+        assert!(chain.validate_block(&new_block).is_ok());
+    }
+
+    #[test]
+    fn test_block_validation_failure_linkage() {
+        let chain = Blockchain::regtest();
+        let last_block = chain.blocks.last().unwrap();
+
+        let mut bad_block = Block::new(
+            last_block.header.height + 1,
+            [1; 32],
+            chain.bits,
+            vec![],
+        );
+
+        bad_block.hash = bad_block.calculate_hash();
+
+        while !bad_block.verify_proof_of_work() {
+            bad_block.header.nonce += 1;
+            bad_block.hash = bad_block.calculate_hash();
+        }
+
+        assert!(chain.validate_block(&bad_block).is_err());
+    }
+
+    #[test]
+    fn test_block_validation_failure_pow() {
+        let chain = Blockchain::regtest();
+        let last_block = chain.blocks.last().unwrap();
+
+        let bad_block = Block::new(
+            last_block.header.height + 1,
+            last_block.hash,
+            chain.bits,
+            vec![],
+        );
+
+        assert!(chain.validate_block(&bad_block).is_err());
+    }
+
+    #[test]
+    fn test_block_validation_double_spend_in_block() {
+        let mut chain = Blockchain::regtest();
+        let genesis_hash = *chain.state.utxo_set.keys().next().unwrap();
+        let genesis_tri = chain.state.utxo_set.get(&genesis_hash).unwrap().clone();
+        let children = genesis_tri.subdivide();
+
+        let keypair = KeyPair::generate().unwrap();
+        let address = keypair.address();
+
+        let mut tx1 = SubdivisionTx::new(genesis_hash, children.to_vec(), address.clone(), 0, 1);
+        let message1 = tx1.signable_message();
+        let signature1 = keypair.sign(&message1).unwrap();
+        let public_key1 = keypair.public_key.serialize().to_vec();
+        tx1.sign(signature1, public_key1);
+
+        let mut tx2 = SubdivisionTx::new(genesis_hash, children.to_vec(), address.clone(), 0, 2);
+        let message2 = tx2.signable_message();
+        let signature2 = keypair.sign(&message2).unwrap();
+        let public_key2 = keypair.public_key.serialize().to_vec();
+        tx2.sign(signature2, public_key2);
+
+        let coinbase = CoinbaseTx::new(1000, address);
+
+        let transactions = vec![
+            Transaction::Coinbase(coinbase),
+            Transaction::Subdivision(tx1),
+            Transaction::Subdivision(tx2),
+        ];
+
+        let last_block = chain.blocks.last().unwrap();
+        let mut new_block = Block::new(
+            last_block.header.height + 1,
+            last_block.hash,
+            chain.bits,
+            transactions,
+        );
+
+        new_block.hash = new_block.calculate_hash();
+
+        while !new_block.verify_proof_of_work() {
+            new_block.header.nonce += 1;
+            new_block.hash = new_block.calculate_hash();
+        }
+
+        assert!(chain.apply_block(new_block).is_err());
+    }
+
+    #[test]
+    fn test_difficulty_adjustment_increase() {
+        let mut chain = Blockchain::regtest();
+
+        for i in 1..=10 {
+            let block = Block {
+                header: BlockHeader {
+                    height: i,
+                    previous_hash: chain.blocks.last().unwrap().hash,
+                    timestamp: Utc::now().timestamp() + (i as i64 * 10),
+                    bits: chain.bits,
+                    nonce: 0,
+                    merkle_root: [0; 32],
+                },
+                hash: [i as u8; 32],
+                transactions: BlockTransactions::default(),
+            };
+
+            chain.blocks.push(block);
+            chain.adjust_difficulty();
+        }
+
+        assert!(difficulty::compact_to_target(chain.bits) <= difficulty::compact_to_target(chain.pow_limit_bits()));
+    }
+
+    #[test]
+    fn test_chains_start_at_their_networks_pow_limit() {
+        let main = Blockchain::new();
+        assert_eq!(main.bits, difficulty::POW_LIMIT_BITS);
+        assert_eq!(main.pow_limit_bits(), difficulty::POW_LIMIT_BITS);
+
+        let regtest = Blockchain::regtest();
+        assert_eq!(regtest.bits, difficulty::REGTEST_POW_LIMIT_BITS);
+        assert_eq!(regtest.header_chain.next_bits(&regtest.blocks[0].header), difficulty::REGTEST_POW_LIMIT_BITS);
+    }
+
+    #[test]
+    fn test_difficulty_adjustment_decrease() {
+        let mut chain = Blockchain::regtest();
+
+        for i in 1..=10 {
+            let block = Block {
+                header: BlockHeader {
+                    height: i,
+                    previous_hash: chain.blocks.last().unwrap().hash,
+                    timestamp: Utc::now().timestamp() + (i as i64 * 200),
+                    bits: chain.bits,
+                    nonce: 0,
+                    merkle_root: [0; 32],
+                },
+                hash: [i as u8; 32],
+                transactions: BlockTransactions::default(),
+            };
+
+            chain.blocks.push(block);
+            chain.adjust_difficulty();
+        }
+
+        assert!(difficulty::compact_to_target(chain.bits) >= difficulty::compact_to_target(chain.pow_limit_bits()));
+    }
+
+    #[test]
+    fn test_difficulty_adjustment_no_change() {
+        let mut chain = Blockchain::regtest();
+        let initial_bits = chain.bits;
+
+        for i in 1..=10 {
+            let block = Block {
+                header: BlockHeader {
+                    height: i,
+                    previous_hash: chain.blocks.last().unwrap().hash,
+                    timestamp: Utc::now().timestamp() + (i as i64 * 60),
+                    bits: chain.bits,
+                    nonce: 0,
+                    merkle_root: [0; 32],
+                },
+                hash: [i as u8; 32],
+                transactions: BlockTransactions::default(),
+            };
+
+            chain.blocks.push(block);
+            chain.adjust_difficulty();
+        }
+
+        assert_eq!(chain.bits, initial_bits);
+    }
+
+    #[test]
+    fn test_block_bits_must_follow_retarget() {
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+
+        // Valid work, but at a target other than the chain calls for
+        let other = mine_block_on(&genesis, 0x2000ffff, "miner");
+        assert!(matches!(
+            chain.validate_block(&other),
+            Err(ChainError::UnexpectedDifficulty { expected, found })
+                if expected == chain.bits && found == 0x2000ffff
+        ));
+        assert!(matches!(chain.accept_header(other.header), Err(ChainError::UnexpectedDifficulty { .. })));
+        chain.apply_block(mine_block_on(&genesis, chain.bits, "miner")).unwrap();
+
+        // A window of one-second blocks: the header closing it calls for a harder target
+        let mut headers = HeaderChain::new(genesis.hash, genesis.header.clone());
+        let mut parent = genesis.header.clone();
+        for height in 1..=DIFFICULTY_ADJUSTMENT_WINDOW {
+            let header = BlockHeader {
+                height,
+                previous_hash: parent.calculate_hash(),
+                timestamp: genesis.header.timestamp + height as i64,
+                bits: parent.bits,
+                nonce: 0,
+                merkle_root: [0; 32],
+            };
+            assert_eq!(headers.next_bits(&parent), parent.bits);
+            headers.insert(parent.calculate_hash(), parent);
+            parent = header;
+        }
+        headers.insert(parent.calculate_hash(), parent.clone());
+        let window = DIFFICULTY_ADJUSTMENT_WINDOW as i64 - 1;
+        let expected = difficulty::retarget(parent.bits, window, window * TARGET_BLOCK_TIME_SECONDS, genesis.header.bits);
+        assert_eq!(headers.next_bits(&parent), expected);
+        assert!(difficulty::compact_to_target(expected) < difficulty::compact_to_target(parent.bits));
+    }
+
+    #[test]
+    fn test_mempool_add_transaction() {
+        let mut mempool = Mempool::new();
+        let mut state = TriangleState::new();
+        let genesis = genesis_triangle();
+        let genesis_hash = genesis.hash();
+        state.utxo_set.insert(genesis_hash, genesis.clone());
+        let children = genesis.subdivide();
+        let keypair = KeyPair::generate().unwrap();
+        let address = keypair.address();
+        state.set_owner(&genesis_hash, address.clone()).unwrap();
+        let mut valid_tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address, 0, 1);
+        let message = valid_tx.signable_message();
+        let signature = keypair.sign(&message).unwrap();
+        let public_key = keypair.public_key.serialize().to_vec();
+        valid_tx.sign(signature, public_key);
+        let tx = Transaction::Subdivision(valid_tx);
+
+        // Its parent must be confirmed, or created by a pending transaction
+        let result = mempool.accept_transaction(tx.clone(), &TriangleState::new());
+        assert!(matches!(result, MempoolAcceptResult::Orphaned { ref missing_inputs, .. } if *missing_inputs == vec![genesis_hash]));
+        assert!(mempool.is_empty());
+
+        mempool.add_transaction(tx.clone(), &state).unwrap();
+        assert_eq!(mempool.len(), 1);
+        assert!(!mempool.is_empty());
+    }
+
+    #[test]
+    fn test_mempool_remove_transaction() {
+        let mut mempool = Mempool::new();
+        let mut state = TriangleState::new();
+        let genesis = genesis_triangle();
+        let genesis_hash = genesis.hash();
+        state.utxo_set.insert(genesis_hash, genesis.clone());
+        let children = genesis.subdivide();
+        let keypair = KeyPair::generate().unwrap();
+        let address = keypair.address();
+        state.set_owner(&genesis_hash, address.clone()).unwrap();
+        let mut valid_tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address, 0, 1);
+        let message = valid_tx.signable_message();
+        let signature = keypair.sign(&message).unwrap();
+        let public_key = keypair.public_key.serialize().to_vec();
+        valid_tx.sign(signature, public_key);
+        let tx = Transaction::Subdivision(valid_tx);
+        let tx_hash = tx.hash();
+
+        mempool.add_transaction(tx.clone(), &state).unwrap();
+        assert_eq!(mempool.len(), 1);
+
+        let removed = mempool.remove_transaction(&tx_hash);
+        assert!(removed.is_some());
+        assert_eq!(mempool.len(), 0);
+    }
+
+    #[test]
+    fn test_mempool_duplicate_transaction() {
+        let mut mempool = Mempool::new();
+        let mut state = TriangleState::new();
+        let genesis = genesis_triangle();
+        let genesis_hash = genesis.hash();
+        state.utxo_set.insert(genesis_hash, genesis.clone());
+        let children = genesis.subdivide();
+        let keypair = KeyPair::generate().unwrap();
+        let address = keypair.address();
+        state.set_owner(&genesis_hash, address.clone()).unwrap();
+        let mut valid_tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address, 0, 1);
+        let message = valid_tx.signable_message();
+        let signature = keypair.sign(&message).unwrap();
+        let public_key = keypair.public_key.serialize().to_vec();
+        valid_tx.sign(signature, public_key);
+        let tx = Transaction::Subdivision(valid_tx);
+
+        mempool.add_transaction(tx.clone(), &state).unwrap();
+        let result = mempool.add_transaction(tx.clone(), &state);
+
+        assert!(result.is_err());
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn test_mempool_replace_by_fee() {
+        let mut mempool = Mempool::new();
+        let genesis = genesis_triangle();
+        let keypair = KeyPair::generate().unwrap();
+        let mut state = Blockchain::regtest().state;
+        state.set_owner(&genesis.hash(), keypair.address()).unwrap();
+        let make_tx = |fee: u64, nonce: u64| {
+            let mut tx = SubdivisionTx::new(genesis.hash(), genesis.subdivide().to_vec(), keypair.address(), fee, nonce);
+            let signature = keypair.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, keypair.public_key.serialize().to_vec());
+            Transaction::Subdivision(tx)
+        };
+
+        let original = make_tx(100, 1);
+        assert_eq!(mempool.add_transaction(original.clone(), &state).unwrap(), None);
+
+        // Not enough of a fee bump: the conflicting transaction is rejected
+        assert_eq!(Mempool::min_replacement_fee(100), 110);
+        assert!(mempool.add_transaction(make_tx(109, 2), &state).is_err());
+        assert!(mempool.get_transaction(&original.hash()).is_some());
+
+        // A sufficient bump replaces the pending transaction
+        let replacement = make_tx(110, 3);
+        assert_eq!(mempool.add_transaction(replacement.clone(), &state).unwrap(), Some(original.hash()));
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get_transaction(&original.hash()).is_none());
+        assert!(mempool.get_transaction(&replacement.hash()).is_some());
+
+        assert_eq!(Mempool::min_replacement_fee(0), 1);
+    }
+
+    #[test]
+    fn test_mempool_rejects_transfer_double_spending_subdivision() {
+        let mut mempool = Mempool::new();
+        let genesis = genesis_triangle();
+        let keypair = KeyPair::generate().unwrap();
+        let mut state = Blockchain::regtest().state;
+        state.set_owner(&genesis.hash(), keypair.address()).unwrap();
+
+        let mut sub_tx = SubdivisionTx::new(genesis.hash(), genesis.subdivide().to_vec(), keypair.address(), 5, 1);
+        let signature = keypair.sign(&sub_tx.signable_message()).unwrap();
+        sub_tx.sign(signature, keypair.public_key.serialize().to_vec());
+        let subdivision = Transaction::Subdivision(sub_tx);
+        mempool.add_transaction(subdivision.clone(), &state).unwrap();
+
+        let mut transfer_tx = crate::transaction::TransferTx::new(
+            genesis.hash(), "bob".to_string(), keypair.address(), 5, 2,
+        );
+        let signature = keypair.sign(&transfer_tx.signable_message()).unwrap();
+        transfer_tx.sign(signature, keypair.public_key.serialize().to_vec());
+        let transfer = Transaction::Transfer(transfer_tx);
+
+        assert_eq!(mempool.conflicting_transaction(&transfer).unwrap().hash(), subdivision.hash());
+        assert!(mempool.add_transaction(transfer.clone(), &state).is_err());
+        assert_eq!(mempool.len(), 1);
+
+        // Once the subdivision leaves the pool, its input is free again
+        mempool.remove_transaction(&subdivision.hash());
+        assert!(mempool.conflicting_transaction(&transfer).is_none());
+        mempool.add_transaction(transfer, &state).unwrap();
+    }
+
+    #[test]
+    fn test_mempool_tracks_ancestors_and_descendants() {
+        let mut chain = Blockchain::regtest();
+        let keypair = KeyPair::generate().unwrap();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+        let genesis = chain.state.utxo_set[&genesis_triangle().hash()].clone();
+
+        let mut parent_tx = SubdivisionTx::new(genesis.hash(), genesis.subdivide().to_vec(), keypair.address(), 1, 1);
+        let signature = keypair.sign(&parent_tx.signable_message()).unwrap();
+        parent_tx.sign(signature, keypair.public_key.serialize().to_vec());
+        let pending = parent_tx.children[0].clone();
+        let parent = Transaction::Subdivision(parent_tx);
+
+        let mut child_tx = crate::transaction::TransferTx::new(
+            pending.hash(), "bob".to_string(), keypair.address(), 3, 2,
+        );
+        let signature = keypair.sign(&child_tx.signable_message()).unwrap();
+        child_tx.sign(signature, keypair.public_key.serialize().to_vec());
+        let child = Transaction::Transfer(child_tx);
+
+        // The child cannot enter the pool before the transaction creating its input
+        assert!(matches!(chain.submit_transaction(child.clone()), Err(ChainError::UtxoMissing { .. })));
+
+        chain.submit_transaction(parent.clone()).unwrap();
+        assert!(chain.mempool.pending_output(&pending.hash()).is_some());
+        chain.submit_transaction(child.clone()).unwrap();
+
+        assert_eq!(chain.mempool.ancestors(&child.hash()), vec![parent.hash()]);
+        assert_eq!(chain.mempool.descendants(&parent.hash()), vec![child.hash()]);
+        assert!(chain.mempool.ancestors(&parent.hash()).is_empty());
+
+        // The child survives pruning while its parent is pending
+        assert_eq!(chain.mempool.validate_and_prune(&chain.state), 0);
+
+        // Dropping the parent from the state's point of view evicts the child with it
+        let empty_state = TriangleState::new();
+        assert_eq!(chain.mempool.validate_and_prune(&empty_state), 2);
+        assert!(chain.mempool.is_empty());
+        assert!(chain.mempool.pending_output(&pending.hash()).is_none());
+    }
+
+    #[test]
+    fn test_nonces_must_increase_per_address() {
+        let mut chain = Blockchain::regtest();
+        let genesis = genesis_triangle();
+        let keypair = KeyPair::generate().unwrap();
+        let address = keypair.address();
+        chain.state.utxo_set.get_mut(&genesis.hash()).unwrap().owner = address.clone();
+        let genesis = chain.state.utxo_set[&genesis.hash()].clone();
+
+        let mut sub_tx = SubdivisionTx::new(genesis.hash(), genesis.subdivide().to_vec(), address.clone(), 0, 5);
+        let signature = keypair.sign(&sub_tx.signable_message()).unwrap();
+        sub_tx.sign(signature, keypair.public_key.serialize().to_vec());
+        let children = sub_tx.children.clone();
+        chain.submit_transaction(Transaction::Subdivision(sub_tx)).unwrap();
+
+        let template = crate::blockassembler::BlockTemplate::build(&chain, "miner");
+        chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+        assert_eq!(chain.state.nonces.get(&address), Some(&5));
+        assert_eq!(chain.next_nonce(&address), 6);
+
+        let transfer = |input: &Triangle, nonce: u64| {
+            let mut tx = crate::transaction::TransferTx::new(input.hash(), "bob".to_string(), address.clone(), 0, nonce);
+            let signature = keypair.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, keypair.public_key.serialize().to_vec());
+            Transaction::Transfer(tx)
+        };
+
+        // Reused and lower nonces are rejected by the mempool and by block validation
+        assert!(chain.submit_transaction(transfer(&children[0], 5)).is_err());
+        assert!(chain.submit_transaction(transfer(&children[0], 4)).is_err());
+
+        let tip = chain.blocks.last().unwrap().clone();
+        let mut replay = mine_block_on(&tip, chain.bits, "miner");
+        replay.transactions.get_mut().unwrap().push(transfer(&children[0], 5));
+        replay.header.merkle_root = Block::calculate_merkle_root(replay.transactions.get().unwrap());
+        let replay = crate::miner::mine_block(replay, &AtomicBool::new(false)).unwrap();
+        assert!(matches!(chain.apply_block(replay), Err(ChainError::StaleNonce { nonce: 5, .. })));
+
+        // A pending nonce can't be reused for a different input either
+        chain.submit_transaction(transfer(&children[0], 6)).unwrap();
+        assert!(chain.submit_transaction(transfer(&children[1], 6)).is_err());
+        chain.submit_transaction(transfer(&children[1], 7)).unwrap();
+        assert_eq!(chain.next_nonce(&address), 8);
+    }
+
+    #[test]
+    fn test_batch_transfer_moves_every_input() {
+        use crate::transaction::{BatchTransferEntry, BatchTransferTx, TransferTx};
+
+        let mut chain = Blockchain::regtest();
+        let genesis = genesis_triangle();
+        let keypair = KeyPair::generate().unwrap();
+        let address = keypair.address();
+        chain.state.utxo_set.get_mut(&genesis.hash()).unwrap().owner = address.clone();
+        let genesis = chain.state.utxo_set[&genesis.hash()].clone();
+
+        let mut sub_tx = SubdivisionTx::new(genesis.hash(), genesis.subdivide().to_vec(), address.clone(), 0, 1);
+        let signature = keypair.sign(&sub_tx.signable_message()).unwrap();
+        sub_tx.sign(signature, keypair.public_key.serialize().to_vec());
+        let children = sub_tx.children.clone();
+        chain.submit_transaction(Transaction::Subdivision(sub_tx)).unwrap();
+        let template = crate::blockassembler::BlockTemplate::build(&chain, "miner");
+        chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+
+        let batch = |owners: &[&str], fee: u64, nonce: u64| {
+            let transfers = children.iter().zip(owners)
+                .map(|(child, owner)| BatchTransferEntry { input_hash: child.hash(), new_owner: owner.to_string() })
+                .collect();
+            let mut tx = BatchTransferTx::new(transfers, address.clone(), fee, nonce);
+            let signature = keypair.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, keypair.public_key.serialize().to_vec());
+            Transaction::BatchTransfer(tx)
+        };
+        let single = |input: &Triangle, fee: u64, nonce: u64| {
+            let mut tx = TransferTx::new(input.hash(), "dave".to_string(), address.clone(), fee, nonce);
+            let signature = keypair.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, keypair.public_key.serialize().to_vec());
+            Transaction::Transfer(tx)
+        };
+
+        // A batch overlapping two pending transfers can't replace both
+        chain.submit_transaction(single(&children[0], 1, 2)).unwrap();
+        chain.submit_transaction(single(&children[1], 1, 3)).unwrap();
+        assert!(chain.submit_transaction(batch(&["bob", "carol", "bob"], 50, 4)).is_err());
+
+        // Once only one conflict remains, a higher fee replaces it
+        chain.mempool.remove_transaction(&single(&children[1], 1, 3).hash());
+        let batch_tx = batch(&["bob", "carol", "bob"], 50, 4);
+        let replaced = chain.submit_transaction(batch_tx.clone()).unwrap();
+        assert_eq!(replaced, Some(single(&children[0], 1, 2).hash()));
+        assert_eq!(chain.mempool.len(), 1);
+
+        let template = crate::blockassembler::BlockTemplate::build(&chain, "miner");
+        assert_eq!(template.total_fees, 50);
+        chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+
+        let owner = |t: &Triangle| chain.state.utxo_set[&t.hash()].owner.clone();
+        assert_eq!(owner(&children[0]), "bob");
+        assert_eq!(owner(&children[1]), "carol");
+        assert_eq!(owner(&children[2]), "bob");
+        assert_eq!(chain.state.nonces.get(&address), Some(&4));
+        assert!(chain.mempool.is_empty());
+    }
+
+    #[test]
+    fn test_lock_time_delays_transactions() {
+        use crate::transaction::LockTime;
+
+        let mut chain = Blockchain::regtest();
+        let genesis = genesis_triangle();
+        let keypair = KeyPair::generate().unwrap();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+
+        let locked = |lock: LockTime, nonce: u64| {
+            let mut tx = SubdivisionTx::new(genesis.hash(), genesis.subdivide().to_vec(), keypair.address(), 0, nonce)
+                .with_lock_time(lock);
+            let signature = keypair.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, keypair.public_key.serialize().to_vec());
+            Transaction::Subdivision(tx)
+        };
+
+        let far_future = Utc::now().timestamp() + 86_400;
+        assert!(chain.submit_transaction(locked(LockTime::Timestamp(far_future), 1)).is_err());
+
+        // Locked until height 2: too early for block 1, fine once block 1 is connected
+        let height_locked = locked(LockTime::Height(2), 2);
+        assert!(chain.submit_transaction(height_locked.clone()).is_err());
+
+        let tip = chain.blocks.last().unwrap().clone();
+        let mut early = mine_block_on(&tip, chain.bits, "miner");
+        early.transactions.get_mut().unwrap().push(height_locked.clone());
+        early.header.merkle_root = Block::calculate_merkle_root(early.transactions.get().unwrap());
+        let early = crate::miner::mine_block(early, &AtomicBool::new(false)).unwrap();
+        assert!(matches!(chain.validate_block(&early), Err(ChainError::InvalidTransaction(_))));
+
+        chain.apply_block(mine_block_on(&tip, chain.bits, "miner")).unwrap();
+        chain.submit_transaction(height_locked.clone()).unwrap();
+
+        let template = crate::blockassembler::BlockTemplate::build(&chain, "miner");
+        assert_eq!(template.block.transactions.get().unwrap()[1].hash(), height_locked.hash());
+        chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+        assert!(!chain.state.utxo_set.contains_key(&genesis.hash()));
+    }
+
+    #[test]
+    fn test_htlc_claim_and_refund() {
+        use crate::transaction::TransferTx;
+        use crate::wallet::{generate_htlc_preimage, Wallet};
+
+        let mut chain = Blockchain::regtest();
+        let alice = Wallet::new(None).unwrap();
+        let bob = Wallet::new(None).unwrap();
+        let genesis_hash = genesis_triangle().hash();
+        chain.state.utxo_set.get_mut(&genesis_hash).unwrap().owner = alice.address.clone();
+
+        let mine = |chain: &mut Blockchain| {
+            let template = crate::blockassembler::BlockTemplate::build(chain, "miner");
+            chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+        };
+
+        // Only the owner can lock
+        let (preimage, hash_lock) = generate_htlc_preimage();
+        let stolen = bob.create_htlc_lock(genesis_hash, alice.address.clone(), hash_lock, 5, 0, 1).unwrap();
+        assert!(chain.submit_transaction(stolen).is_err());
+
+        let lock = alice.create_htlc_lock(genesis_hash, bob.address.clone(), hash_lock, 5, 0, 1).unwrap();
+        chain.submit_transaction(lock).unwrap();
+        mine(&mut chain);
+        assert!(chain.state.htlcs.contains_key(&genesis_hash));
+
+        // The locked triangle can't be spent any other way
+        let mut transfer = TransferTx::new(genesis_hash, bob.address.clone(), alice.address.clone(), 0, 2);
+        let keypair = alice.get_keypair().unwrap();
+        let signature = keypair.sign(&transfer.signable_message()).unwrap();
+        transfer.sign(signature, keypair.public_key.serialize().to_vec());
+        assert!(chain.submit_transaction(Transaction::Transfer(transfer)).is_err());
+
+        // Refund is too early and a wrong preimage doesn't open the lock
+        assert!(chain.submit_transaction(alice.create_htlc_refund(genesis_hash, 0, 2).unwrap()).is_err());
+        assert!(chain.submit_transaction(bob.create_htlc_claim(genesis_hash, vec![0; 32], 0, 1).unwrap()).is_err());
+
+        chain.submit_transaction(bob.create_htlc_claim(genesis_hash, preimage.to_vec(), 0, 1).unwrap()).unwrap();
+        mine(&mut chain);
+        assert!(chain.state.htlcs.is_empty());
+        assert_eq!(chain.state.utxo_set[&genesis_hash].owner, bob.address);
+        assert_eq!(
+            crate::wallet::find_htlc_preimage(&chain.blocks, &hash_lock).unwrap(),
+            Some(preimage.to_vec())
+        );
+
+        // Bob locks it back to Alice; she never claims, so he refunds after the timeout
+        let (_, other_lock) = generate_htlc_preimage();
+        let timeout = chain.blocks.len() as BlockHeight + 1;
+        chain.submit_transaction(bob.create_htlc_lock(genesis_hash, alice.address.clone(), other_lock, timeout, 0, 2).unwrap()).unwrap();
+        mine(&mut chain);
+        chain.submit_transaction(bob.create_htlc_refund(genesis_hash, 0, 3).unwrap()).unwrap();
+        mine(&mut chain);
+        assert!(chain.state.htlcs.is_empty());
+        assert_eq!(chain.state.utxo_set[&genesis_hash].owner, bob.address);
+    }
+
+    #[test]
+    fn test_escrow_release_and_refund() {
+        use crate::transaction::{EscrowOutcome, EscrowSettleTx};
+        use crate::wallet::Wallet;
+
+        let mut chain = Blockchain::regtest();
+        let seller = Wallet::new(None).unwrap();
+        let buyer = Wallet::new(None).unwrap();
+        let arbiter = Wallet::new(None).unwrap();
+        let genesis_hash = genesis_triangle().hash();
+        chain.state.utxo_set.get_mut(&genesis_hash).unwrap().owner = seller.address.clone();
+
+        let mine = |chain: &mut Blockchain| {
+            let template = crate::blockassembler::BlockTemplate::build(chain, "miner");
+            chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+        };
+        let settlement = |chain: &Blockchain, outcome, signers: &[&Wallet]| {
+            let contract = &chain.state.escrows[&genesis_hash];
+            let mut tx = EscrowSettleTx::new(contract, genesis_hash, outcome, 0);
+            for signer in signers {
+                let signature = signer.sign_escrow_settlement(&tx).unwrap();
+                tx.add_signature(signature);
+            }
+            Transaction::EscrowSettle(tx)
+        };
+
+        let lock = |nonce| seller
+            .create_escrow_lock(genesis_hash, buyer.address.clone(), arbiter.address.clone(), 0, nonce)
+            .unwrap();
+        chain.submit_transaction(lock(1)).unwrap();
+        mine(&mut chain);
+        assert!(chain.state.escrows.contains_key(&genesis_hash));
+
+        // The seller can't take it back alone, and can't move it any other way
+        assert!(chain.submit_transaction(settlement(&chain, EscrowOutcome::Refund, &[&seller])).is_err());
+        assert!(chain.submit_transaction(lock(2)).is_err());
+
+        // Buyer and arbiter agree to release
+        let release = settlement(&chain, EscrowOutcome::Release, &[&buyer, &arbiter]);
+        chain.submit_transaction(release.clone()).unwrap();
+        mine(&mut chain);
+        assert!(chain.state.escrows.is_empty());
+        assert_eq!(chain.state.utxo_set[&genesis_hash].owner, buyer.address);
+
+        // Replaying the settlement fails once the escrow is closed
+        assert!(chain.submit_transaction(release).is_err());
+
+        // The buyer escrows it back; seller and arbiter refund it
+        let relock = buyer
+            .create_escrow_lock(genesis_hash, seller.address.clone(), arbiter.address.clone(), 0, 1)
+            .unwrap();
+        chain.submit_transaction(relock).unwrap();
+        mine(&mut chain);
+        chain.submit_transaction(settlement(&chain, EscrowOutcome::Refund, &[&arbiter, &buyer])).unwrap();
+        mine(&mut chain);
+        assert!(chain.state.escrows.is_empty());
+        assert_eq!(chain.state.utxo_set[&genesis_hash].owner, buyer.address);
+    }
+
+    #[test]
+    fn test_offer_accept_swaps_atomically() {
+        use crate::transaction::TransferTx;
+        use crate::wallet::Wallet;
+
+        let mut chain = Blockchain::regtest();
+        let seller = Wallet::new(None).unwrap();
+        let buyer = Wallet::new(None).unwrap();
+        let genesis = genesis_triangle();
+        chain.state.utxo_set.get_mut(&genesis.hash()).unwrap().owner = seller.address.clone();
+
+        // Give the buyer three quarter-area triangles to pay with
+        let mut payment = Vec::new();
+        for (i, child) in genesis.subdivide().iter().enumerate() {
+            let mut child = child.clone();
+            child.owner = buyer.address.clone();
+            child.parent_hash = Some([i as u8; 32]);
+            payment.push(child.hash());
+            chain.state.utxo_set.insert(child.hash(), child);
+        }
+
+        let mine = |chain: &mut Blockchain| {
+            let template = crate::blockassembler::BlockTemplate::build(chain, "miner");
+            chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+        };
+
+        // Only the owner can list
+        assert!(chain.submit_transaction(buyer.create_offer(genesis.hash(), 0.1, 0, 1).unwrap()).is_err());
+
+        let price = genesis.area() / 2.0;
+        let offer_tx = seller.create_offer(genesis.hash(), price, 0, 1).unwrap();
+        let offer = match &offer_tx {
+            Transaction::Offer(tx) => tx.offer(),
+            _ => unreachable!(),
+        };
+        chain.submit_transaction(offer_tx).unwrap();
+        mine(&mut chain);
+        assert_eq!(chain.state.offers[&genesis.hash()], offer);
+
+        // One child is a quarter of the area, below the asking price
+        let underpaid = buyer.create_accept(&offer, genesis.hash(), payment[..1].to_vec(), 0, 1).unwrap();
+        assert!(chain.submit_transaction(underpaid).is_err());
+
+        let accept = buyer.create_accept(&offer, genesis.hash(), payment[..2].to_vec(), 0, 1).unwrap();
+        chain.submit_transaction(accept.clone()).unwrap();
+        mine(&mut chain);
+
+        assert!(chain.state.offers.is_empty());
+        assert_eq!(chain.state.utxo_set[&genesis.hash()].owner, buyer.address);
+        assert_eq!(chain.state.utxo_set[&payment[0]].owner, seller.address);
+        assert_eq!(chain.state.utxo_set[&payment[1]].owner, seller.address);
+        assert_eq!(chain.state.utxo_set[&payment[2]].owner, buyer.address);
+        assert!(chain.submit_transaction(accept).is_err());
+
+        // Moving a listed triangle withdraws its offer
+        let relist = buyer.create_offer(genesis.hash(), price, 0, 2).unwrap();
+        chain.submit_transaction(relist).unwrap();
+        mine(&mut chain);
+        let mut transfer = TransferTx::new(genesis.hash(), seller.address.clone(), buyer.address.clone(), 0, 3);
+        let keypair = buyer.get_keypair().unwrap();
+        let signature = keypair.sign(&transfer.signable_message()).unwrap();
+        transfer.sign(signature, keypair.public_key.serialize().to_vec());
+        chain.submit_transaction(Transaction::Transfer(transfer)).unwrap();
+        mine(&mut chain);
+        assert!(chain.state.offers.is_empty());
+    }
+
+    #[test]
+    fn test_inscriptions_bound_to_owned_triangle() {
+        use crate::wallet::Wallet;
+
+        let mut chain = Blockchain::regtest();
+        let owner = Wallet::new(None).unwrap();
+        let stranger = Wallet::new(None).unwrap();
+        let genesis_hash = genesis_triangle().hash();
+        chain.state.utxo_set.get_mut(&genesis_hash).unwrap().owner = owner.address.clone();
+
+        let data = b"ipfs://triangle-art".to_vec();
+        assert!(chain.submit_transaction(stranger.create_inscription(genesis_hash, data.clone(), 0, 1).unwrap()).is_err());
+        let too_long = vec![0; InscriptionTx::MAX_DATA_LENGTH + 1];
+        assert!(chain.submit_transaction(owner.create_inscription(genesis_hash, too_long, 0, 1).unwrap()).is_err());
+
+        chain.submit_transaction(owner.create_inscription(genesis_hash, data.clone(), 0, 1).unwrap()).unwrap();
+        let template = crate::blockassembler::BlockTemplate::build(&chain, "miner");
+        chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+
+        // The triangle is unchanged and the data is in history
+        assert_eq!(chain.state.utxo_set[&genesis_hash].owner, owner.address);
+        let inscriptions = chain.inscriptions(&genesis_hash).unwrap();
+        assert_eq!(inscriptions.len(), 1);
+        assert_eq!(inscriptions[0].0, 1);
+        assert_eq!(inscriptions[0].1.data, data);
+        assert!(chain.inscriptions(&[9; 32]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deep_subdivision_in_one_block() {
+        let mut chain = Blockchain::regtest();
+        let keypair = KeyPair::generate().unwrap();
+        let genesis = genesis_triangle();
+        chain.state.utxo_set.get_mut(&genesis.hash()).unwrap().owner = keypair.address();
+
+        let signed = |keypair: &KeyPair, nonce: u64| {
+            let mut tx = DeepSubdivisionTx::new(genesis.hash(), 2, keypair.address(), 0, nonce);
+            tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            Transaction::DeepSubdivision(tx)
+        };
+        assert!(chain.submit_transaction(signed(&KeyPair::generate().unwrap(), 1)).is_err());
+
+        chain.submit_transaction(signed(&keypair, 1)).unwrap();
+        let template = crate::blockassembler::BlockTemplate::build(&chain, "miner");
+        chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+
+        assert!(!chain.state.utxo_set.contains_key(&genesis.hash()));
+        let leaves = chain.state.utxo_set.values().filter(|t| t.owner == keypair.address()).count();
+        assert_eq!(leaves, 9);
+        assert!(chain.audit_supply().problems.is_empty());
+    }
+
+    #[test]
+    fn test_split_transfer_sends_part_of_a_triangle() {
+        let mut chain = Blockchain::regtest();
+        let keypair = KeyPair::generate().unwrap();
+        let genesis = genesis_triangle();
+        chain.state.utxo_set.get_mut(&genesis.hash()).unwrap().owner = keypair.address();
+
+        let signed = |keypair: &KeyPair| {
+            let mut tx = SplitTransferTx::new(genesis.hash(), 1, vec![2], "bob".to_string(), keypair.address(), 0, 1);
+            tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            Transaction::SplitTransfer(tx)
+        };
+        assert!(chain.submit_transaction(signed(&KeyPair::generate().unwrap())).is_err());
+
+        chain.submit_transaction(signed(&keypair)).unwrap();
+        let template = crate::blockassembler::BlockTemplate::build(&chain, "miner");
+        chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+
+        assert!(!chain.state.utxo_set.contains_key(&genesis.hash()));
+        let owned_by = |owner: &str| chain.state.utxo_set.values().filter(|t| t.owner == owner).count();
+        assert_eq!(owned_by("bob"), 1);
+        assert_eq!(owned_by(&keypair.address()), 2);
+        assert_eq!(chain.state.nonces.get(&keypair.address()), Some(&1));
+        assert!(chain.audit_supply().problems.is_empty());
+    }
+
+    #[test]
+    fn test_swap_built_by_two_parties() {
+        use crate::transaction::{SigHashType, SwapTx};
+        use crate::wallet::Wallet;
+
+        let mut chain = Blockchain::regtest();
+        let seller = Wallet::new(None).unwrap();
+        let buyer = Wallet::new(None).unwrap();
+        let genesis = genesis_triangle();
+        let children = genesis.subdivide();
+        chain.state.utxo_set.remove(&genesis.hash());
+        let (listed, payment) = (children[0].hash(), children[1].hash());
+        for (child, owner) in children.iter().zip([&seller.address, &buyer.address, &buyer.address]) {
+            let mut child = child.clone();
+            child.owner = owner.clone();
+            chain.state.utxo_set.insert(child.hash(), child);
+        }
+
+        let mut tx = SwapTx::new(0);
+        tx.add_input(listed, seller.address.clone(), SigHashType::SingleAnyoneCanPay);
+        tx.add_output(payment, seller.address.clone());
+        seller.sign_swap_input(&mut tx, 0).unwrap();
+        assert!(buyer.sign_swap_input(&mut tx, 0).is_err());
+
+        // Claiming a triangle the signer doesn't own is rejected on submit
+        let mut stolen = SwapTx::new(0);
+        stolen.add_input(children[2].hash(), seller.address.clone(), SigHashType::All);
+        stolen.add_output(children[2].hash(), "thief".to_string());
+        seller.sign_swap_input(&mut stolen, 0).unwrap();
+        assert!(stolen.validate().is_ok());
+        assert!(chain.submit_transaction(Transaction::Swap(stolen)).is_err());
+
+        let index = tx.add_input(payment, buyer.address.clone(), SigHashType::All);
+        tx.add_output(listed, buyer.address.clone());
+        buyer.sign_swap_input(&mut tx, index).unwrap();
+        chain.submit_transaction(Transaction::Swap(tx)).unwrap();
+
+        let template = crate::blockassembler::BlockTemplate::build(&chain, "miner");
+        chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+        assert_eq!(chain.state.utxo_set[&listed].owner, buyer.address);
+        assert_eq!(chain.state.utxo_set[&payment].owner, seller.address);
+    }
+
+    #[test]
+    fn test_lease_reverts_to_owner_at_expiry() {
+        use crate::transaction::TransferTx;
+        use crate::wallet::Wallet;
+
+        let mut chain = Blockchain::regtest();
+        let owner = Wallet::new(None).unwrap();
+        let tenant = Wallet::new(None).unwrap();
+        let genesis = genesis_triangle();
+        chain.state.utxo_set.get_mut(&genesis.hash()).unwrap().owner = owner.address.clone();
+
+        let transfer = |wallet: &Wallet, new_owner: &str, nonce: u64| {
+            let keypair = wallet.get_keypair().unwrap();
+            let mut tx = TransferTx::new(genesis.hash(), new_owner.to_string(), wallet.address.clone(), 0, nonce);
+            tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            Transaction::Transfer(tx)
+        };
+        let mine = |chain: &mut Blockchain| {
+            let template = crate::blockassembler::BlockTemplate::build(chain, "miner");
+            chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+        };
+
+        // A lease must outlast the block it lands in
+        assert!(chain.submit_transaction(owner.create_lease(genesis.hash(), tenant.address.clone(), 1, 0, 1).unwrap()).is_err());
+        chain.submit_transaction(owner.create_lease(genesis.hash(), tenant.address.clone(), 3, 0, 1).unwrap()).unwrap();
+        mine(&mut chain);
+        assert_eq!(chain.state.utxo_set[&genesis.hash()].owner, tenant.address);
+
+        // During the lease the owner can't move it and the tenant can only pass it on
+        assert!(chain.submit_transaction(transfer(&owner, "dave", 2)).is_err());
+        let tenant_keypair = tenant.get_keypair().unwrap();
+        let mut sub_tx = SubdivisionTx::new(genesis.hash(), genesis.subdivide().to_vec(), tenant.address.clone(), 0, 1);
+        sub_tx.sign(tenant_keypair.sign(&sub_tx.signable_message()).unwrap(), tenant_keypair.public_key.serialize().to_vec());
+        assert!(chain.submit_transaction(Transaction::Subdivision(sub_tx)).is_err());
+        chain.submit_transaction(transfer(&tenant, "carol", 1)).unwrap();
+        mine(&mut chain);
+        assert_eq!(chain.state.utxo_set[&genesis.hash()].owner, "carol");
+
+        // The block at the expiry height already belongs to the owner
+        chain.submit_transaction(transfer(&owner, "dave", 2)).unwrap();
+        mine(&mut chain);
+        assert_eq!(chain.state.utxo_set[&genesis.hash()].owner, "dave");
+        assert!(chain.state.leases.is_empty());
+    }
+
+    #[test]
+    fn test_approved_delegate_moves_triangle() {
+        use crate::transaction::TransferTx;
+        use crate::wallet::Wallet;
+
+        let mut chain = Blockchain::regtest();
+        let owner = Wallet::new(None).unwrap();
+        let delegate = Wallet::new(None).unwrap();
+        let genesis = genesis_triangle();
+        chain.state.utxo_set.get_mut(&genesis.hash()).unwrap().owner = owner.address.clone();
+        let owned = chain.state.utxo_set[&genesis.hash()].clone();
+
+        let signed_by = |wallet: &Wallet| {
+            let keypair = wallet.get_keypair().unwrap();
+            let mut transfer = TransferTx::new(genesis.hash(), "carol".to_string(), wallet.address.clone(), 0, 1);
+            transfer.sign(keypair.sign(&transfer.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            let mut stolen = SubdivisionTx::new(genesis.hash(), genesis.subdivide().to_vec(), wallet.address.clone(), 0, 2);
+            stolen.sign(keypair.sign(&stolen.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            (Transaction::Transfer(transfer), Transaction::Subdivision(stolen))
+        };
+        let mine = |chain: &mut Blockchain| {
+            let template = crate::blockassembler::BlockTemplate::build(chain, "miner");
+            chain.apply_block(crate::miner::mine_block(template.block, &AtomicBool::new(false)).unwrap()).unwrap();
+        };
+
+        // Without an approval the delegate is a stranger
+        let (transfer, _) = signed_by(&delegate);
+        assert!(chain.submit_transaction(transfer.clone()).is_err());
+
+        // Nor can it skip the approval by naming the owner as the sender: the
+        // ownership check passes, but the signature isn't the owner's
+        let keypair = delegate.get_keypair().unwrap();
+        let mut impersonating = TransferTx::new(genesis.hash(), "carol".to_string(), owner.address.clone(), 0, 1);
+        impersonating.sign(keypair.sign(&impersonating.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+        assert!(impersonating.validate().is_err());
+        let impersonating = Transaction::Transfer(impersonating);
+        assert!(chain.state.check_contracts(&impersonating, 1, &HashMap::new()).is_ok());
+        assert!(chain.submit_transaction(impersonating).is_err());
+
+        chain.submit_transaction(owner.create_approval(genesis.hash(), Some(delegate.address.clone()), 0, 1).unwrap()).unwrap();
+        mine(&mut chain);
+        assert_eq!(chain.state.approvals[&genesis.hash()].delegate, delegate.address);
+
+        // A delegate can't subdivide the triangle into children for themselves
+        let (_, stolen) = signed_by(&delegate);
+        assert!(chain.submit_transaction(stolen).is_err());
+        let keypair = delegate.get_keypair().unwrap();
+        let mut split = SubdivisionTx::new(genesis.hash(), owned.subdivide().to_vec(), delegate.address.clone(), 0, 2);
+        split.sign(keypair.sign(&split.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+        assert!(chain.state.check_contracts(&Transaction::Subdivision(split), 2, &HashMap::new()).is_ok());
+
+        // Revoking ends the approval
+        chain.submit_transaction(owner.create_approval(genesis.hash(), None, 0, 2).unwrap()).unwrap();
+        mine(&mut chain);
+        assert!(chain.state.approvals.is_empty());
+        assert!(chain.submit_transaction(transfer.clone()).is_err());
+
+        // Approved again, the delegate transfers it, which uses up the approval
+        chain.submit_transaction(owner.create_approval(genesis.hash(), Some(delegate.address.clone()), 0, 3).unwrap()).unwrap();
+        mine(&mut chain);
+        chain.submit_transaction(transfer).unwrap();
+        mine(&mut chain);
+        assert_eq!(chain.state.utxo_set[&genesis.hash()].owner, "carol");
+        assert!(chain.state.approvals.is_empty());
+    }
+
+    #[test]
+    fn test_spend_signed_for_another_address_is_rejected() {
+        use crate::transaction::TransferTx;
+
+        let mut chain = Blockchain::regtest();
+        let alice = KeyPair::generate().unwrap();
+        let mallory = KeyPair::generate().unwrap();
+        let genesis = genesis_triangle();
+        chain.state.utxo_set.get_mut(&genesis.hash()).unwrap().owner = alice.address();
+
+        // Mallory signs with her own key but names Alice as the sender
+        let mut transfer = TransferTx::new(genesis.hash(), mallory.address(), alice.address(), 0, 1);
+        transfer.sign(mallory.sign(&transfer.signable_message()).unwrap(), mallory.public_key.serialize().to_vec());
+        let transfer = Transaction::Transfer(transfer);
+        assert!(chain.submit_transaction(transfer.clone()).is_err());
+
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let block = mine_next_block(&chain, vec![coinbase, transfer]);
+        assert!(chain.apply_block(block).is_err());
+        assert_eq!(chain.state.utxo_set[&genesis.hash()].owner, alice.address());
     }
 
-    /// Get the current halving era (0 = first era, 1 = first halving, etc.)
-    pub fn current_halving_era(&self) -> u64 {
-        self.blocks.last().unwrap().header.height / REWARD_HALVING_INTERVAL
-    }
+    #[test]
+    fn test_only_the_owner_spends_a_triangle_created_in_the_same_block() {
+        use crate::transaction::TransferTx;
 
-    /// Blocks until next halving
-    pub fn blocks_until_next_halving(&self) -> u64 {
-        let current_height = self.blocks.last().unwrap().header.height;
-        let next_halving_height = (self.current_halving_era() + 1) * REWARD_HALVING_INTERVAL;
-        next_halving_height.saturating_sub(current_height)
+        let mut chain = Blockchain::regtest();
+        let alice = KeyPair::generate().unwrap();
+        let mallory = KeyPair::generate().unwrap();
+        let genesis = genesis_triangle();
+        chain.state.utxo_set.get_mut(&genesis.hash()).unwrap().owner = alice.address();
+
+        let parent = chain.state.utxo_set[&genesis.hash()].clone();
+        let mut subdivision = SubdivisionTx::new(parent.hash(), parent.subdivide().to_vec(), alice.address(), 0, 1);
+        subdivision.sign(alice.sign(&subdivision.signable_message()).unwrap(), alice.public_key.serialize().to_vec());
+        let child = subdivision.children[0].clone();
+        let subdivision = Transaction::Subdivision(subdivision);
+        let transfer = |keypair: &KeyPair, to: &str, nonce: u64| {
+            let mut tx = TransferTx::new(child.hash(), to.to_string(), keypair.address(), 0, nonce);
+            tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            Transaction::Transfer(tx)
+        };
+        let coinbase = || Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+
+        // Mallory signs for herself, so the signature checks out, but the child is Alice's
+        let stolen = transfer(&mallory, &mallory.address(), 1);
+        assert!(matches!(chain.state.check_contracts(&stolen, 1, &HashMap::new()), Err(ChainError::UtxoMissing { .. })));
+        let created = HashMap::from([(child.hash(), &child)]);
+        assert!(chain.state.check_contracts(&stolen, 1, &created).is_err());
+        assert!(chain.state.check_contracts(&transfer(&alice, "bob", 2), 1, &created).is_ok());
+        chain.submit_transaction(subdivision.clone()).unwrap();
+        assert!(chain.submit_transaction(stolen.clone()).is_err());
+        let block = mine_next_block(&chain, vec![coinbase(), subdivision.clone(), stolen]);
+        assert!(chain.apply_block(block).is_err());
+
+        // Nor may Alice move the child twice
+        let twice = vec![coinbase(), subdivision.clone(), transfer(&alice, "bob", 2), transfer(&alice, "carol", 3)];
+        let block = mine_next_block(&chain, twice);
+        assert!(chain.apply_block(block).is_err());
+
+        let block = mine_next_block(&chain, vec![coinbase(), subdivision, transfer(&alice, "bob", 2)]);
+        chain.apply_block(block).unwrap();
+        assert_eq!(chain.state.utxo_set[&child.hash()].owner, "bob");
     }
 
-    /// Calculate total transaction fees in a block
-    pub fn calculate_total_fees(transactions: &[Transaction]) -> u64 {
-        transactions.iter()
-            .filter(|tx| !matches!(tx, Transaction::Coinbase(_)))
-            .map(|tx| tx.fee())
-            .fold(0u64, |acc, fee| acc.saturating_add(fee))
+    #[test]
+    fn test_eviction_counts_subdivision_fees() {
+        let mut mempool = Mempool::new();
+        let keypair = KeyPair::generate().unwrap();
+        let (chain, leaves) = chain_with_leaves(&keypair, 1);
+
+        let mut subdivision = SubdivisionTx::new(
+            leaves[0].hash(), leaves[0].subdivide().to_vec(), keypair.address(), 100, 2,
+        );
+        let signature = keypair.sign(&subdivision.signable_message()).unwrap();
+        subdivision.sign(signature, keypair.public_key.serialize().to_vec());
+        let subdivision = Transaction::Subdivision(subdivision);
+
+        let mut transfer = crate::transaction::TransferTx::new(leaves[1].hash(), "bob".to_string(), keypair.address(), 1, 3);
+        let signature = keypair.sign(&transfer.signable_message()).unwrap();
+        transfer.sign(signature, keypair.public_key.serialize().to_vec());
+        let transfer = Transaction::Transfer(transfer);
+
+        mempool.add_transaction(subdivision.clone(), &chain.state).unwrap();
+        mempool.add_transaction(transfer.clone(), &chain.state).unwrap();
+
+        // The subdivision pays more, so the transfer goes first
+        assert!(mempool.evict_lowest_fee_transaction(FeeRate::new(u64::MAX, 1)));
+        assert!(mempool.get_transaction(&subdivision.hash()).is_some());
+        assert!(mempool.get_transaction(&transfer.hash()).is_none());
+        assert_eq!(mempool.get_transactions_by_fee(1)[0].hash(), subdivision.hash());
     }
 
-    fn adjust_difficulty(&mut self) {
-        if self.blocks.len() < DIFFICULTY_ADJUSTMENT_WINDOW as usize {
-            return; // Not enough blocks to adjust
+    #[test]
+    fn test_full_mempool_only_makes_room_for_better_fee_rates() {
+        use crate::transaction::TransferTx;
+
+        let mut mempool = Mempool::new().with_max_transactions(2);
+        let sender = KeyPair::generate().unwrap();
+        let (chain, leaves) = chain_with_leaves(&sender, 1);
+        let state = chain.state;
+        let transfer = |input: usize, fee: u64| {
+            let mut tx = TransferTx::new(leaves[input].hash(), "bob".to_string(), sender.address(), fee, input as u64 + 2);
+            let signature = sender.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, sender.public_key.serialize().to_vec());
+            Transaction::Transfer(tx)
+        };
+        let cheap = transfer(0, 10);
+        let dear = transfer(1, 20);
+        mempool.add_transaction(cheap.clone(), &state).unwrap();
+        mempool.add_transaction(dear.clone(), &state).unwrap();
+
+        // Paying less than, or the same rate as, the cheapest is turned away
+        for fee in [5, 10] {
+            let tx = transfer(2, fee);
+            assert_eq!(tx.fee_rate(), FeeRate::new(fee, cheap.size()));
+            assert_eq!(mempool.accept_transaction(tx, &state).reason(), Some(RejectReason::MempoolFull));
         }
+        assert!(mempool.get_transaction(&cheap.hash()).is_some());
 
-        let window_start_index = self.blocks.len() - DIFFICULTY_ADJUSTMENT_WINDOW as usize;
-        let window = &self.blocks[window_start_index..];
+        // A better rate evicts the cheapest
+        let better = transfer(2, 11);
+        mempool.add_transaction(better.clone(), &state).unwrap();
+        assert_eq!(mempool.len(), 2);
+        assert!(mempool.get_transaction(&cheap.hash()).is_none());
+        assert!(mempool.get_transaction(&better.hash()).is_some());
+        assert!(mempool.get_transaction(&dear.hash()).is_some());
+    }
 
-        // Calculate the actual time taken for the last DIFFICULTY_ADJUSTMENT_WINDOW blocks
-        let actual_time = window.last().unwrap().header.timestamp - window.first().unwrap().header.timestamp;
+    #[test]
+    fn test_mempool_ranks_by_fee_rate() {
+        use crate::transaction::{BatchTransferEntry, BatchTransferTx, TransferTx};
 
-        // Timestamps should always increase; if they don't, there's a bug
-        if actual_time <= 0 {
-            eprintln!("⚠️  Warning: Invalid timestamp range detected in difficulty adjustment");
-            return; // Don't adjust with invalid data
-        }
+        let mut mempool = Mempool::new();
+        let keypair = KeyPair::generate().unwrap();
+        let (chain, leaves) = chain_with_leaves(&keypair, 4);
+
+        // An 80-input batch paying 10 is far larger than a transfer paying 9
+        let transfers = leaves[..80].iter()
+            .map(|leaf| BatchTransferEntry { input_hash: leaf.hash(), new_owner: "bob".to_string() })
+            .collect();
+        let mut batch = BatchTransferTx::new(transfers, keypair.address(), 10, 41);
+        let signature = keypair.sign(&batch.signable_message()).unwrap();
+        batch.sign(signature, keypair.public_key.serialize().to_vec());
+        let batch = Transaction::BatchTransfer(batch);
+
+        let mut transfer = TransferTx::new(leaves[80].hash(), "carol".to_string(), keypair.address(), 9, 42);
+        let signature = keypair.sign(&transfer.signable_message()).unwrap();
+        transfer.sign(signature, keypair.public_key.serialize().to_vec());
+        let transfer = Transaction::Transfer(transfer);
+
+        assert!(batch.fee() > transfer.fee());
+        assert!(batch.fee_rate() < transfer.fee_rate());
+
+        mempool.add_transaction(batch.clone(), &chain.state).unwrap();
+        mempool.add_transaction(transfer.clone(), &chain.state).unwrap();
+        assert_eq!(mempool.get_transactions_by_fee(1)[0].hash(), transfer.hash());
+
+        assert!(mempool.evict_lowest_fee_transaction(FeeRate::new(u64::MAX, 1)));
+        assert!(mempool.get_transaction(&batch.hash()).is_none());
+        assert!(mempool.get_transaction(&transfer.hash()).is_some());
+    }
 
-        // Expected time for the window
-        let expected_time = (DIFFICULTY_ADJUSTMENT_WINDOW as i64 - 1) * TARGET_BLOCK_TIME_SECONDS;
+    #[test]
+    fn test_accept_transaction_reports_outcome() {
+        use crate::transaction::TransferTx;
 
-        // Calculate adjustment factor - how much faster/slower than target
-        let adjustment_factor = expected_time as f64 / actual_time as f64;
+        let mut chain = Blockchain::regtest();
+        let keypair = KeyPair::generate().unwrap();
+        let genesis_hash = genesis_triangle().hash();
+        chain.state.utxo_set.get_mut(&genesis_hash).unwrap().owner = keypair.address();
+
+        let transfer = |input_hash, fee| {
+            let mut tx = TransferTx::new(input_hash, "bob".to_string(), keypair.address(), fee, 1);
+            let signature = keypair.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, keypair.public_key.serialize().to_vec());
+            Transaction::Transfer(tx)
+        };
 
-        // Bitcoin-style clamping: limit adjustment to 4x in either direction per period
-        // This prevents wild swings while still allowing quick convergence
-        const MIN_ADJUSTMENT: f64 = 0.25; // Can decrease by up to 4x
-        const MAX_ADJUSTMENT: f64 = 4.0;  // Can increase by up to 4x
+        let first = transfer(genesis_hash, 100);
+        let result = chain.accept_transaction(first.clone());
+        assert!(matches!(result, MempoolAcceptResult::Accepted { tx_hash, .. } if tx_hash == first.hash()));
 
-        let clamped_factor = adjustment_factor.max(MIN_ADJUSTMENT).min(MAX_ADJUSTMENT);
+        let result = chain.accept_transaction(first.clone());
+        assert_eq!(result.reason(), Some(RejectReason::Duplicate));
 
-        let old_difficulty = self.difficulty;
-        let new_difficulty = ((self.difficulty as f64 * clamped_factor).round() as u64).max(1);
-        self.difficulty = new_difficulty;
+        // A conflicting spend must outbid the pending one
+        let result = chain.accept_transaction(transfer(genesis_hash, 101));
+        assert_eq!(result.reason(), Some(RejectReason::InsufficientFee));
+        assert!(matches!(
+            result,
+            MempoolAcceptResult::Rejected { error: ChainError::InsufficientFee { required: 110, provided: 101 }, .. }
+        ));
+        assert!(matches!(result, MempoolAcceptResult::Rejected { ref conflicts, .. } if *conflicts == vec![first.hash()]));
 
-        let avg_block_time = actual_time as f64 / (DIFFICULTY_ADJUSTMENT_WINDOW as f64 - 1.0);
-        println!("⚙️  Difficulty adjusted: {} -> {} (avg block time: {:.1}s, target: {}s)",
-                 old_difficulty, new_difficulty, avg_block_time, TARGET_BLOCK_TIME_SECONDS);
-    }
-}
+        let result = chain.accept_transaction(transfer(genesis_hash, 1000));
+        assert!(matches!(result, MempoolAcceptResult::Replaced { replaced, .. } if replaced == first.hash()));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::transaction::{SubdivisionTx, Transaction};
-    use crate::crypto::KeyPair;
+        let result = chain.accept_transaction(transfer([9; 32], 100));
+        assert!(matches!(result, MempoolAcceptResult::Orphaned { ref missing_inputs, .. } if *missing_inputs == vec![[9; 32]]));
+        assert!(!result.is_accepted());
 
-    #[test]
-    fn test_genesis_triangle_is_canonical() {
-        let genesis = genesis_triangle();
-        assert_eq!(genesis.a.x, 0.0);
-        assert_eq!(genesis.a.y, 0.0);
-        assert_eq!(genesis.b.x, 1.0);
-        assert_eq!(genesis.c.x, 0.5);
-        assert!((genesis.c.y - 0.866025403784).abs() < 1e-10);
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        assert_eq!(chain.mempool.accept_transaction(coinbase, &chain.state).reason(), Some(RejectReason::Coinbase));
     }
 
     #[test]
-    fn test_block_merkle_root_calculation() {
-        let coinbase = CoinbaseTx {
-            reward_area: 1000,
-            beneficiary_address: "test".to_string(),
+    fn test_mempool_checks_subdivision_geometry_on_entry() {
+        let mut chain = Blockchain::regtest();
+        let keypair = KeyPair::generate().unwrap();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+        let parent = chain.state.utxo_set[&genesis_triangle().hash()].clone();
+        let children = parent.subdivide();
+        let subdivide = |parent: &Triangle, children: Vec<Triangle>, nonce| {
+            let mut tx = SubdivisionTx::new(parent.hash(), children, keypair.address(), 0, nonce);
+            tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            Transaction::Subdivision(tx)
         };
-        let transactions = vec![Transaction::Coinbase(coinbase)];
-        let merkle = Block::calculate_merkle_root(&transactions);
-        assert!(!merkle.is_empty());
-    }
 
-    #[test]
-    fn test_merkle_tree_empty() {
-        let root = Block::calculate_merkle_root(&[]);
-        assert_eq!(root, [0; 32]);
+        // Signed, but the children aren't the parent's
+        let wrong = subdivide(&parent, children[0].subdivide().to_vec(), 1);
+        assert_eq!(chain.accept_transaction(wrong).reason(), Some(RejectReason::Invalid));
+        chain.submit_transaction(subdivide(&parent, children.to_vec(), 1)).unwrap();
+
+        // The same goes for a parent that's still pending
+        let wrong = subdivide(&children[0], children[1].subdivide().to_vec(), 2);
+        assert_eq!(chain.accept_transaction(wrong).reason(), Some(RejectReason::Invalid));
+        chain.submit_transaction(subdivide(&children[0], children[0].subdivide().to_vec(), 2)).unwrap();
     }
 
     #[test]
-    fn test_merkle_tree_single() {
-        let coinbase = CoinbaseTx {
-            reward_area: 1000,
-            beneficiary_address: "miner".to_string(),
-        };
-        let txs = vec![Transaction::Coinbase(coinbase)];
-        let root = Block::calculate_merkle_root(&txs);
-        assert_eq!(root.len(), 32);
+    fn test_triangle_ids_parse_and_resolve_prefixes() {
+        let chain = Blockchain::regtest();
+        let genesis_id = genesis_triangle().hash();
+        let hex_id = hex::encode(genesis_id);
+
+        assert_eq!(parse_hash(&hex_id).unwrap(), genesis_id);
+        assert!(matches!(parse_hash(&hex_id[..10]), Err(ChainError::CodecError(_))));
+        assert!(matches!(parse_hash("not hex"), Err(ChainError::CodecError(_))));
+
+        assert_eq!(chain.state.find_by_prefix(&hex_id[..8]).unwrap(), genesis_id);
+        assert_eq!(chain.state.find_by_prefix(&hex_id[..8].to_uppercase()).unwrap(), genesis_id);
+
+        let mut state = chain.state.clone();
+        for child in genesis_triangle().subdivide() {
+            state.utxo_set.insert(child.hash(), child);
+        }
+        // The empty prefix matches every triangle
+        assert!(matches!(state.find_by_prefix(""), Err(ChainError::InvalidTransaction(_))));
+        assert!(matches!(TriangleState::new().find_by_prefix("ab"), Err(ChainError::TriangleNotFound(_))));
     }
 
     #[test]
-    fn test_merkle_tree_even() {
-        let tx1 = Transaction::Coinbase(CoinbaseTx {
-            reward_area: 1000,
-            beneficiary_address: "miner1".to_string(),
-        });
-        let tx2 = Transaction::Coinbase(CoinbaseTx {
-            reward_area: 2000,
-            beneficiary_address: "miner2".to_string(),
-        });
-        let root = Block::calculate_merkle_root(&[tx1, tx2]);
-        assert_eq!(root.len(), 32);
+    fn test_triangle_path_addressing() {
+        let mut state = genesis_state();
+        let genesis = genesis_triangle();
+        let grandchild = genesis.subdivide()[0].subdivide()[2].clone();
+        state.utxo_set.insert(grandchild.hash(), grandchild.clone());
+
+        let path = TrianglePath::of(&grandchild);
+        assert_eq!(path.to_string(), "Δ/0/2");
+        assert_eq!("Δ.0.2".parse::<TrianglePath>().unwrap(), path);
+        assert_eq!(path.genesis_descendant_id(), Some(grandchild.hash()));
+        assert_eq!(state.path_of(&grandchild.hash()), Some(path.clone()));
+        assert_eq!(state.resolve("Δ/0/2").unwrap(), grandchild.hash());
+        assert_eq!(state.resolve("Δ").unwrap(), genesis.hash());
+        assert!(matches!(state.resolve("Δ/1"), Err(ChainError::TriangleNotFound(_))));
+        assert!("Δ/3".parse::<TrianglePath>().is_err());
+        assert!("Δ/x".parse::<TrianglePath>().is_err());
+
+        // Other roots are written by their id and looked up in the state
+        let reward = Triangle::equilateral(Point::new(64.0, 64.0), 1000.0, "miner".to_string());
+        let leaf = reward.subdivide()[1].clone();
+        state.utxo_set.insert(leaf.hash(), leaf.clone());
+        let text = format!("{}/1", hex::encode(reward.hash()));
+        assert_eq!(TrianglePath::of(&leaf).to_string(), text);
+        assert_eq!(state.resolve(&text).unwrap(), leaf.hash());
+        assert_eq!(TrianglePath::of(&leaf).genesis_descendant_id(), None);
     }
 
     #[test]
-    fn test_merkle_tree_odd() {
-        let tx1 = Transaction::Coinbase(CoinbaseTx {
-            reward_area: 1000,
-            beneficiary_address: "miner1".to_string(),
-        });
-        let tx2 = Transaction::Coinbase(CoinbaseTx {
-            reward_area: 2000,
-            beneficiary_address: "miner2".to_string(),
-        });
-        let tx3 = Transaction::Coinbase(CoinbaseTx {
-            reward_area: 3000,
-            beneficiary_address: "miner3".to_string(),
-        });
-        let root = Block::calculate_merkle_root(&[tx1, tx2, tx3]);
-        assert_eq!(root.len(), 32);
+    fn test_mempool_validate_and_prune() {
+        let mut mempool = Mempool::new();
+        let keypair = KeyPair::generate().unwrap();
+        let (mut chain, leaves) = chain_with_leaves(&keypair, 1);
+        let subdivide = |parent: &Triangle, fee: u64, nonce: u64| {
+            let mut tx = SubdivisionTx::new(parent.hash(), parent.subdivide().to_vec(), keypair.address(), fee, nonce);
+            let signature = keypair.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, keypair.public_key.serialize().to_vec());
+            Transaction::Subdivision(tx)
+        };
+
+        // Both parents are unspent when the transactions arrive
+        mempool.add_transaction(subdivide(&leaves[1], 0, 3), &chain.state).unwrap();
+        mempool.add_transaction(subdivide(&leaves[0], 0, 4), &chain.state).unwrap();
+        assert_eq!(mempool.len(), 2);
+
+        // A mined block then spends one of them another way
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let block = mine_next_block(&chain, vec![coinbase, subdivide(&leaves[0], 1, 2)]);
+        chain.apply_block(block).unwrap();
+
+        // Validate and prune - should remove 1 invalid transaction
+        let removed = mempool.validate_and_prune(&chain.state);
+        assert_eq!(removed, 1);
+        assert_eq!(mempool.len(), 1);
     }
 
     #[test]
-    fn test_apply_block_updates_state() {
-        let mut chain = Blockchain::new();
-        let initial_count = chain.state.count();
-
-        let genesis_hash = *chain.state.utxo_set.keys().next().unwrap();
-        let genesis_tri = chain.state.utxo_set.get(&genesis_hash).unwrap().clone();
-        let children = genesis_tri.subdivide();
+    fn test_blockchain_with_mempool() {
+        let mut chain = Blockchain::regtest();
+        assert!(chain.mempool.is_empty());
 
+        // Add a transaction to mempool
+        let genesis = genesis_triangle();
+        let genesis_hash = genesis.hash();
+        let children = genesis.subdivide();
         let keypair = KeyPair::generate().unwrap();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
         let address = keypair.address();
-
-        let mut tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address.clone(), 0, 1);
-        let message = tx.signable_message();
+        let mut valid_tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address, 0, 1);
+        let message = valid_tx.signable_message();
         let signature = keypair.sign(&message).unwrap();
         let public_key = keypair.public_key.serialize().to_vec();
-        tx.sign(signature, public_key);
-
-        let coinbase = CoinbaseTx {
-            reward_area: 1000,
-            beneficiary_address: address,
-        };
-
-        let transactions = vec![
-            Transaction::Coinbase(coinbase),
-            Transaction::Subdivision(tx),
-        ];
+        valid_tx.sign(signature, public_key);
+        let tx = Transaction::Subdivision(valid_tx);
+        chain.mempool.add_transaction(tx.clone(), &chain.state).unwrap();
+        assert_eq!(chain.mempool.len(), 1);
 
+        // Create and apply a block with that transaction
         let last_block = chain.blocks.last().unwrap();
+        let coinbase = CoinbaseTx::new(1000, "miner_address".to_string());
         let mut new_block = Block::new(
             last_block.header.height + 1,
             last_block.hash,
-            chain.difficulty,
-            transactions,
+            chain.bits,
+            vec![Transaction::Coinbase(coinbase), tx],
         );
 
         // Ensure timestamp is greater than parent
         new_block.header.timestamp = last_block.header.timestamp + 1;
-        new_block.hash = new_block.calculate_hash();
 
-        while !new_block.verify_proof_of_work() {
-            new_block.header.nonce += 1;
-            new_block.hash = new_block.calculate_hash();
+        // Before applying, mempool has 1 transaction
+        assert_eq!(chain.mempool.len(), 1);
+
+        // Apply block (this should remove the transaction from mempool)
+        let mut mined_block = new_block.clone();
+        loop {
+            mined_block.hash = mined_block.calculate_hash();
+            if mined_block.verify_proof_of_work() {
+                break;
+            }
+            mined_block.header.nonce += 1;
         }
 
-        chain.apply_block(new_block).unwrap();
+        chain.apply_block(mined_block).unwrap();
 
-        // Initial state has 1 triangle (genesis).
-        // Subdivision tx consumes 1 and creates 3 (+2).
-        // Coinbase tx creates 1 (+1).
-        // Total should be 1 + 2 + 1 = 4.
-        assert_eq!(chain.state.count(), initial_count + 3);
+        // After applying, mempool should be empty
+        assert_eq!(chain.mempool.len(), 0);
     }
 
-    #[test]
-    fn test_block_validation_success() {
-        let chain = Blockchain::new();
-        let genesis_hash = *chain.state.utxo_set.keys().next().unwrap();
-        let genesis_tri = chain.state.utxo_set.get(&genesis_hash).unwrap().clone();
-        let children = genesis_tri.subdivide();
-
-        let keypair = KeyPair::generate().unwrap();
-        let address = keypair.address();
-
-        let mut tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address.clone(), 0, 1);
-        let message = tx.signable_message();
-        let signature = keypair.sign(&message).unwrap();
-        let public_key = keypair.public_key.serialize().to_vec();
-        tx.sign(signature, public_key);
-
-        let coinbase = CoinbaseTx {
-            reward_area: 1000,
-            beneficiary_address: address,
-        };
-
-        let transactions = vec![
-            Transaction::Coinbase(coinbase),
-            Transaction::Subdivision(tx),
-        ];
+    /// A chain on which `keypair` subdivided the genesis triangle `depth`
+    /// generations deep, one mined block per generation, with the leaves it now owns
+    fn chain_with_leaves(keypair: &KeyPair, depth: usize) -> (Blockchain, Vec<Triangle>) {
+        let mut chain = Blockchain::regtest();
+        let genesis = genesis_triangle();
+        chain.state.utxo_set.get_mut(&genesis.hash()).unwrap().owner = keypair.address();
+
+        let mut leaves = vec![chain.state.utxo_set[&genesis.hash()].clone()];
+        let mut nonce = 0;
+        for _ in 0..depth {
+            let mut transactions = vec![Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()))];
+            let mut children = Vec::new();
+            for parent in &leaves {
+                nonce += 1;
+                let generation = parent.subdivide();
+                let mut tx = SubdivisionTx::new(parent.hash(), generation.to_vec(), keypair.address(), 0, nonce);
+                tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+                transactions.push(Transaction::Subdivision(tx));
+                children.extend(generation);
+            }
+            let block = mine_next_block(&chain, transactions);
+            chain.apply_block(block).unwrap();
+            leaves = children;
+        }
+        (chain, leaves)
+    }
 
+    fn mine_next_block(chain: &Blockchain, transactions: Vec<Transaction>) -> Block {
         let last_block = chain.blocks.last().unwrap();
-        let mut new_block = Block::new(
+        let mut block = Block::new(
             last_block.header.height + 1,
             last_block.hash,
-            chain.difficulty,
+            chain.bits,
             transactions,
         );
+        block.header.timestamp = last_block.header.timestamp + 1;
+        block.hash = block.calculate_hash();
+        while !block.verify_proof_of_work() {
+            block.header.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
+        block
+    }
 
-        // Ensure timestamp is greater than parent
-        new_block.header.timestamp = last_block.header.timestamp + 1;
-        new_block.hash = new_block.calculate_hash();
+    fn mine_block_on(parent: &Block, bits: u32, beneficiary: &str) -> Block {
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, beneficiary.to_string()));
+        let mut block = Block::new(parent.header.height + 1, parent.hash, bits, vec![coinbase]);
+        block.header.timestamp = parent.header.timestamp + 1;
+        block.hash = block.calculate_hash();
+        while !block.verify_proof_of_work() {
+            block.header.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
+        block
+    }
 
-        while !new_block.verify_proof_of_work() {
-            new_block.header.nonce += 1;
-            new_block.hash = new_block.calculate_hash();
+    #[test]
+    fn test_block_locator() {
+        let mut chain = Blockchain::regtest().with_pow(Arc::new(crate::pow::TestPow));
+        for _ in 0..30 {
+            let block = mine_block_on(chain.blocks.last().unwrap(), chain.bits, "miner");
+            chain.apply_block(block).unwrap();
         }
 
-        assert!(chain.validate_block(&new_block).is_ok());
+        let locator = chain.block_locator();
+        let heights: Vec<_> = locator.iter().map(|hash| chain.get_block(hash).unwrap().header.height).collect();
+        assert_eq!(heights, vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]);
+
+        // A peer twenty blocks behind gets the rest, capped at the limit
+        let behind = Blockchain {
+            blocks: chain.blocks[..11].to_vec(),
+            ..chain.clone()
+        };
+        let headers = chain.headers_after_locator(&behind.block_locator(), 15);
+        assert_eq!(headers.first().unwrap().height, 11);
+        assert_eq!(headers.len(), 15);
+
+        // Nothing in common but genesis
+        assert_eq!(chain.headers_after_locator(&[[9; 32]], 100).len(), 30);
+        assert!(chain.headers_after_locator(&locator, 100).is_empty());
     }
 
     #[test]
-    fn test_block_validation_failure_linkage() {
-        let chain = Blockchain::new();
-        let last_block = chain.blocks.last().unwrap();
+    fn test_reorg_moves_old_blocks_to_forks() {
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+
+        let main_block = mine_block_on(&genesis, chain.bits, "alice");
+        chain.apply_block(main_block.clone()).unwrap();
+
+        let mut parent = genesis;
+        let mut fork_hashes = Vec::new();
+        for _ in 0..3 {
+            let block = mine_block_on(&parent, chain.bits, "bob");
+            fork_hashes.push(block.hash);
+            chain.apply_block(block.clone()).unwrap();
+            parent = block;
+        }
 
-        let mut bad_block = Block::new(
-            last_block.header.height + 1,
-            [1; 32],
-            chain.difficulty,
-            vec![],
-        );
+        assert_eq!(chain.blocks.last().unwrap().hash, fork_hashes[2]);
+        assert!(!chain.block_index.contains_key(&main_block.hash));
+        assert!(chain.forks.contains_key(&main_block.hash));
+        for (i, hash) in fork_hashes.iter().enumerate() {
+            assert_eq!(chain.block_index[hash], i as BlockHeight + 1);
+            assert!(!chain.forks.contains_key(hash));
+            assert_eq!(chain.get_block(hash).unwrap().hash, *hash);
+        }
+        assert!(chain.get_block(&main_block.hash).is_some());
+    }
 
-        bad_block.hash = bad_block.calculate_hash();
+    #[test]
+    fn test_heavier_fork_wins_and_ties_keep_the_first_seen() {
+        let mut chain = Blockchain::regtest();
+        let a1 = mine_block_on(&chain.blocks[0].clone(), chain.bits, "alice");
+        chain.apply_block(a1.clone()).unwrap();
+        let a2 = mine_block_on(&a1, chain.bits, "alice");
+        chain.apply_block(a2.clone()).unwrap();
+
+        // A rival at the same height waits as a fork
+        let b2 = mine_block_on(&a1, chain.bits, "bob");
+        chain.apply_block(b2.clone()).unwrap();
+        assert_eq!(chain.blocks.last().unwrap().hash, a2.hash);
+
+        // Growing past the main chain switches to it, though it branches above genesis
+        let b3 = mine_block_on(&b2, chain.bits, "bob");
+        chain.apply_block(b3.clone()).unwrap();
+        let main: Vec<Sha256Hash> = chain.blocks.iter().map(|block| block.hash).collect();
+        assert_eq!(main[1..], [a1.hash, b2.hash, b3.hash]);
+        assert!(chain.forks.contains_key(&a2.hash));
+    }
 
-        while !bad_block.verify_proof_of_work() {
-            bad_block.header.nonce += 1;
-            bad_block.hash = bad_block.calculate_hash();
+    #[test]
+    fn test_header_chain_prefers_the_most_work_over_the_most_headers() {
+        let genesis = Blockchain::regtest().blocks[0].clone();
+        let mut headers = HeaderChain::new(genesis.hash, genesis.header.clone());
+        let extend = |headers: &mut HeaderChain, parent: &BlockHeader, bits| {
+            let header = BlockHeader {
+                height: parent.height + 1,
+                previous_hash: parent.calculate_hash(),
+                timestamp: parent.timestamp + 1,
+                bits,
+                nonce: 0,
+                merkle_root: [0; 32],
+            };
+            headers.insert(header.calculate_hash(), header.clone());
+            header
+        };
+
+        // Three easy headers, then a single one 256 times harder
+        let mut long = genesis.header.clone();
+        for _ in 0..3 {
+            long = extend(&mut headers, &long, 0x2000ffff);
+        }
+        assert_eq!(headers.best_height(), 3);
+        let heavy = extend(&mut headers, &genesis.header, 0x1f00ffff);
+        assert_eq!(headers.best_hash(), heavy.calculate_hash());
+        assert_eq!(headers.best_height(), 1);
+
+        let work = |hash| headers.chain_work(&hash).unwrap();
+        assert!(work(heavy.calculate_hash()) > work(long.calculate_hash()));
+        assert_eq!(headers.chain_work(&[7; 32]), None);
+    }
+
+    #[test]
+    fn test_header_chain_drops_the_lowest_side_headers_past_the_cap() {
+        let genesis = Blockchain::regtest().blocks[0].clone();
+        let mut headers = HeaderChain::new(genesis.hash, genesis.header.clone());
+        let child = |parent_hash, parent: &BlockHeader, bits| BlockHeader {
+            height: parent.height + 1,
+            previous_hash: parent_hash,
+            timestamp: parent.timestamp + 1,
+            bits,
+            nonce: 0,
+            merkle_root: [0; 32],
+        };
+
+        // One mainnet-hard header outweighs the whole easy side chain below
+        let best = child(genesis.hash, &genesis.header, 0x1d00ffff);
+        headers.insert(best.calculate_hash(), best.clone());
+        let mut side_hashes = Vec::new();
+        let (mut parent_hash, mut parent) = (genesis.hash, genesis.header.clone());
+        for _ in 0..=MAX_SIDE_HEADERS {
+            let header = child(parent_hash, &parent, 0x207fffff);
+            parent_hash = header.calculate_hash();
+            headers.insert(parent_hash, header.clone());
+            side_hashes.push(parent_hash);
+            parent = header;
         }
 
-        assert!(chain.validate_block(&bad_block).is_err());
+        assert_eq!(headers.best_hash(), best.calculate_hash());
+        assert_eq!(headers.headers.len(), 2 + MAX_SIDE_HEADERS / 2);
+        assert_eq!(headers.headers.len(), headers.work.len());
+        assert!(headers.get(&genesis.hash).is_some());
+        let kept = side_hashes.len() - MAX_SIDE_HEADERS / 2;
+        assert!(side_hashes[..kept].iter().all(|hash| headers.get(hash).is_none()));
+        assert!(side_hashes[kept..].iter().all(|hash| headers.get(hash).is_some()));
     }
 
     #[test]
-    fn test_block_validation_failure_pow() {
-        let chain = Blockchain::new();
-        let last_block = chain.blocks.last().unwrap();
+    fn test_fork_invalid_on_its_own_branch_is_rejected() {
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+        let keypair = KeyPair::generate().unwrap();
+        let address = keypair.address();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = address.clone();
+        let children_of = |parent: &Triangle| -> Vec<Triangle> {
+            parent.subdivide().into_iter().map(|mut child| { child.owner = address.clone(); child }).collect()
+        };
+        let subdivide = |parent: &Triangle, nonce| {
+            let mut tx = SubdivisionTx::new(parent.hash(), children_of(parent), address.clone(), 0, nonce);
+            tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            Transaction::Subdivision(tx)
+        };
+        let coinbase = || Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let bits = chain.bits;
+        let mine_on = |parent: &Block, transactions| {
+            let mut block = Block::new(parent.header.height + 1, parent.hash, bits, transactions);
+            block.header.timestamp = parent.header.timestamp + 1;
+            block.hash = block.calculate_hash();
+            while !block.verify_proof_of_work() {
+                block.header.nonce += 1;
+                block.hash = block.calculate_hash();
+            }
+            block
+        };
 
-        let bad_block = Block::new(
-            last_block.header.height + 1,
-            last_block.hash,
-            chain.difficulty,
-            vec![],
-        );
+        // The main chain subdivides the genesis triangle
+        let a1 = mine_on(&genesis, vec![coinbase(), subdivide(&genesis_triangle(), 1)]);
+        chain.apply_block(a1.clone()).unwrap();
+
+        // A branch from genesis spends one of the children, which only exist
+        // on the main chain, so it can't be switched to
+        let child = children_of(&genesis_triangle())[0].clone();
+        let b1 = mine_on(&genesis, vec![coinbase()]);
+        chain.apply_block(b1.clone()).unwrap();
+        let b2 = mine_on(&b1, vec![coinbase(), subdivide(&child, 2)]);
+        assert!(chain.apply_block(b2.clone()).is_err());
+
+        assert_eq!(chain.blocks.last().unwrap().hash, a1.hash);
+        assert!(chain.state.utxo_set.contains_key(&child.hash()));
+        assert!(chain.is_known_invalid(&b2.hash) && !chain.contains_block(&b2.hash));
+        assert!(chain.forks.contains_key(&b1.hash));
+        assert_eq!(chain.header_chain.best_hash(), a1.hash);
+        assert!(chain.missing_block_hashes(10).is_empty());
+    }
 
-        assert!(chain.validate_block(&bad_block).is_err());
+    #[test]
+    fn test_competing_branches_may_share_a_transaction() {
+        let mut chain = Blockchain::regtest();
+        let keypair = KeyPair::generate().unwrap();
+        // Both branches build on a block paying the keypair a triangle
+        let base = mine_block_on(&chain.blocks[0], chain.bits, &keypair.address());
+        chain.apply_block(base.clone()).unwrap();
+        let Transaction::Coinbase(coinbase) = &base.transactions.get().unwrap()[0] else { unreachable!() };
+        let reward = coinbase.reward_triangles(&base.hash)[0].clone();
+        let mut tx = SubdivisionTx::new(reward.hash(), reward.subdivide().to_vec(), keypair.address(), 0, 1);
+        tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+        let subdivision = Transaction::Subdivision(tx);
+        let mine_on = |parent: &Block, bits, beneficiary: &str| {
+            let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, beneficiary.to_string()));
+            let mut block = Block::new(parent.header.height + 1, parent.hash, bits, vec![coinbase, subdivision.clone()]);
+            block.header.timestamp = parent.header.timestamp + 1;
+            block.hash = block.calculate_hash();
+            while !block.verify_proof_of_work() {
+                block.header.nonce += 1;
+                block.hash = block.calculate_hash();
+            }
+            block
+        };
+
+        let a1 = mine_on(&base, chain.bits, "alice");
+        chain.apply_block(a1.clone()).unwrap();
+
+        // The rival carries the same transaction, so its nonce is stale
+        // against the main chain, but not on its own branch
+        let b1 = mine_on(&base, chain.bits, "bob");
+        chain.apply_block(b1.clone()).unwrap();
+        assert!(chain.forks.contains_key(&b1.hash));
+
+        let b2 = mine_block_on(&b1, chain.bits, "miner");
+        chain.apply_block(b2.clone()).unwrap();
+        assert!(!chain.is_known_invalid(&b1.hash) && !chain.is_known_invalid(&b2.hash));
+        assert_eq!(chain.blocks.last().unwrap().hash, b2.hash);
+        assert!(chain.forks.contains_key(&a1.hash));
     }
 
     #[test]
-    fn test_block_validation_double_spend_in_block() {
-        let mut chain = Blockchain::new();
-        let genesis_hash = *chain.state.utxo_set.keys().next().unwrap();
-        let genesis_tri = chain.state.utxo_set.get(&genesis_hash).unwrap().clone();
-        let children = genesis_tri.subdivide();
+    fn test_reorg_validates_branch_blocks_against_their_branch() {
+        use crate::transaction::TransferTx;
+
+        let mut chain = Blockchain::regtest();
+        let alice = KeyPair::generate().unwrap();
+        let base = mine_block_on(&chain.blocks[0], chain.bits, &alice.address());
+        chain.apply_block(base.clone()).unwrap();
+        let Transaction::Coinbase(coinbase) = &base.transactions.get().unwrap()[0] else { unreachable!() };
+        let reward = coinbase.reward_triangles(&base.hash)[0].clone();
+        let children = reward.subdivide();
+
+        let coinbase = || Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let transfer = |input: &Triangle, to: &str, nonce| {
+            let mut tx = TransferTx::new(input.hash(), to.to_string(), alice.address(), 0, nonce);
+            tx.sign(alice.sign(&tx.signable_message()).unwrap(), alice.public_key.serialize().to_vec());
+            Transaction::Transfer(tx)
+        };
+        let bits = chain.bits;
+        let mine_on = |parent: &Block, transactions| {
+            let mut block = Block::new(parent.header.height + 1, parent.hash, bits, transactions);
+            block.header.timestamp = parent.header.timestamp + 1;
+            block.hash = block.calculate_hash();
+            while !block.verify_proof_of_work() {
+                block.header.nonce += 1;
+                block.hash = block.calculate_hash();
+            }
+            block
+        };
+        let a1 = mine_on(&base, vec![coinbase()]);
+        chain.apply_block(a1.clone()).unwrap();
+
+        // Alice sends the same triangle twice down a branch
+        let f1 = mine_on(&base, vec![coinbase(), transfer(&reward, "bob", 1)]);
+        let f2 = mine_on(&f1, vec![coinbase(), transfer(&reward, "mallory", 1)]);
+        chain.apply_block(f1.clone()).unwrap();
+        assert!(chain.apply_block(f2.clone()).is_err());
+        assert_eq!(chain.blocks.last().unwrap().hash, a1.hash);
+        assert!(chain.is_known_invalid(&f2.hash));
+        assert_eq!(chain.state.utxo_set[&reward.hash()].owner, alice.address());
+
+        // A branch may spend what only it created
+        let mut subdivision = SubdivisionTx::new(reward.hash(), children.to_vec(), alice.address(), 0, 1);
+        subdivision.sign(alice.sign(&subdivision.signable_message()).unwrap(), alice.public_key.serialize().to_vec());
+        let g1 = mine_on(&base, vec![coinbase(), Transaction::Subdivision(subdivision)]);
+        let g2 = mine_on(&g1, vec![coinbase(), transfer(&children[0], "bob", 2)]);
+        chain.apply_block(g1).unwrap();
+        chain.apply_block(g2.clone()).unwrap();
+        assert_eq!(chain.blocks.last().unwrap().hash, g2.hash);
+        assert_eq!(chain.state.utxo_set[&children[0].hash()].owner, "bob");
+    }
+
+    #[test]
+    fn test_invalid_block_and_descendants_are_remembered() {
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+
+        // Valid proof of work, but the coinbase claims more than the block reward
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let mut bad = Block::new(1, genesis.hash, chain.bits, vec![coinbase.clone(), coinbase]);
+        bad.header.timestamp = genesis.header.timestamp + 1;
+        bad.hash = bad.calculate_hash();
+        while !bad.verify_proof_of_work() {
+            bad.header.nonce += 1;
+            bad.hash = bad.calculate_hash();
+        }
+
+        assert!(chain.apply_block(bad.clone()).is_err());
+        assert!(chain.is_known_invalid(&bad.hash));
+        assert!(matches!(chain.apply_block(bad.clone()), Err(ChainError::KnownInvalidBlock(_))));
+
+        // A child of the invalid block is rejected and marked without full validation
+        let child = mine_block_on(&bad, chain.bits, "miner");
+        assert!(matches!(chain.apply_block(child.clone()), Err(ChainError::KnownInvalidBlock(_))));
+        assert!(chain.is_known_invalid(&child.hash));
+
+        // Headers building on it are rejected as well
+        let grandchild = mine_block_on(&child, chain.bits, "miner");
+        assert!(matches!(chain.accept_header(grandchild.header), Err(ChainError::KnownInvalidBlock(_))));
+    }
+
+    #[test]
+    fn test_orphan_block_is_not_marked_invalid() {
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+        let parent = mine_block_on(&genesis, chain.bits, "miner");
+        let orphan = mine_block_on(&parent, chain.bits, "miner");
+
+        assert!(chain.apply_block(orphan.clone()).is_err());
+        assert!(!chain.is_known_invalid(&orphan.hash));
+
+        chain.apply_block(parent).unwrap();
+        chain.apply_block(orphan).unwrap();
+    }
 
-        let keypair = KeyPair::generate().unwrap();
-        let address = keypair.address();
+    #[test]
+    fn test_verify_chain_detects_corruption() {
+        let mut chain = Blockchain::regtest();
+        for _ in 0..4 {
+            let block = mine_block_on(chain.blocks.last().unwrap(), chain.bits, "miner");
+            chain.apply_block(block).unwrap();
+        }
 
-        let mut tx1 = SubdivisionTx::new(genesis_hash, children.to_vec(), address.clone(), 0, 1);
-        let message1 = tx1.signable_message();
-        let signature1 = keypair.sign(&message1).unwrap();
-        let public_key1 = keypair.public_key.serialize().to_vec();
-        tx1.sign(signature1, public_key1);
+        let report = chain.verify_chain(10);
+        assert!(report.is_ok());
+        assert_eq!(report.start_height, 1);
+        assert_eq!(report.blocks_checked, 4);
 
-        let mut tx2 = SubdivisionTx::new(genesis_hash, children.to_vec(), address.clone(), 0, 2);
-        let message2 = tx2.signable_message();
-        let signature2 = keypair.sign(&message2).unwrap();
-        let public_key2 = keypair.public_key.serialize().to_vec();
-        tx2.sign(signature2, public_key2);
+        let report = chain.verify_chain(2);
+        assert!(report.is_ok());
+        assert_eq!(report.start_height, 3);
 
-        let coinbase = CoinbaseTx {
-            reward_area: 1000,
-            beneficiary_address: address,
-        };
+        // Tamper with a stored block and the UTXO set
+        chain.blocks[3].transactions.get_mut().unwrap().clear();
+        chain.state.utxo_set.clear();
 
-        let transactions = vec![
-            Transaction::Coinbase(coinbase),
-            Transaction::Subdivision(tx1),
-            Transaction::Subdivision(tx2),
-        ];
+        let report = chain.verify_chain(2);
+        assert!(!report.is_ok());
+        assert!(!report.state_matches);
+        assert!(report.problems.iter().any(|p| p.height == 3 && p.description.contains("Merkle")));
+    }
 
-        let last_block = chain.blocks.last().unwrap();
-        let mut new_block = Block::new(
-            last_block.header.height + 1,
-            last_block.hash,
-            chain.difficulty,
-            transactions,
+    #[test]
+    fn test_audit_supply() {
+        let mut chain = Blockchain::regtest();
+        let block = mine_block_on(&chain.blocks[0], chain.bits, "miner");
+        chain.apply_block(block).unwrap();
+
+        let genesis_hash = genesis_triangle().hash();
+        let children = genesis_triangle().subdivide();
+        let keypair = KeyPair::generate().unwrap();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+        let mut tx = SubdivisionTx::new(genesis_hash, children.to_vec(), keypair.address(), 0, 1);
+        let signature = keypair.sign(&tx.signable_message()).unwrap();
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let block = mine_next_block(&chain, vec![coinbase, Transaction::Subdivision(tx)]);
+        chain.apply_block(block).unwrap();
+
+        let audit = chain.audit_supply();
+        assert!(audit.is_ok(), "{:?}", audit.problems);
+        assert_eq!(audit.issued_rewards, 2000);
+        assert_eq!(audit.scheduled_rewards, 2000);
+
+        // Area appearing out of nowhere is caught
+        let extra = Triangle::new(
+            Point { x: 0.0, y: 0.0 },
+            Point { x: 10.0, y: 0.0 },
+            Point { x: 0.0, y: 10.0 },
+            None,
+            "thief".to_string(),
         );
+        chain.state.utxo_set.insert(extra.hash(), extra);
+        assert!(!chain.audit_supply().is_ok());
+    }
 
-        new_block.hash = new_block.calculate_hash();
+    #[test]
+    fn test_accept_header_then_connect_block() {
+        let mut chain = Blockchain::regtest();
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let block = mine_next_block(&chain, vec![coinbase]);
+
+        let hash = chain.accept_header(block.header.clone()).unwrap();
+        assert_eq!(hash, block.hash);
+        assert_eq!(chain.header_chain.best_height(), 1);
+        assert_eq!(chain.missing_block_hashes(10), vec![block.hash]);
+
+        chain.connect_block_for_header(block).unwrap();
+        assert_eq!(chain.blocks.len(), 2);
+        assert!(chain.missing_block_hashes(10).is_empty());
+    }
 
-        while !new_block.verify_proof_of_work() {
-            new_block.header.nonce += 1;
-            new_block.hash = new_block.calculate_hash();
+    #[test]
+    fn test_pow_engine_judges_blocks_and_headers() {
+        let real = Blockchain::regtest();
+        let test = real.clone().with_pow(Arc::new(crate::pow::TestPow));
+
+        // A block without enough work, as TestPow would mine it
+        let last = real.blocks.last().unwrap();
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let mut block = Block::new(1, last.hash, real.bits, vec![coinbase]);
+        block.header.timestamp = last.header.timestamp + 1;
+        block.hash = block.calculate_hash();
+        while block.verify_proof_of_work() {
+            block.header.nonce += 1;
+            block.hash = block.calculate_hash();
         }
 
-        assert!(chain.apply_block(new_block).is_err());
+        assert!(matches!(real.clone().apply_block(block.clone()), Err(ChainError::InvalidProofOfWork)));
+        assert!(matches!(real.clone().accept_header(block.header.clone()), Err(ChainError::InvalidProofOfWork)));
+
+        let mut test_chain = test.clone();
+        test_chain.accept_header(block.header.clone()).unwrap();
+        test_chain.connect_block_for_header(block.clone()).unwrap();
+        assert!(test_chain.verify_chain(10).is_ok());
+
+        // The hash must still be the header's own
+        let mut forged = block;
+        forged.hash = [0; 32];
+        assert!(matches!(test.clone().apply_block(forged), Err(ChainError::InvalidProofOfWork)));
     }
 
     #[test]
-    fn test_difficulty_adjustment_increase() {
-        let mut chain = Blockchain::new();
+    fn test_accept_header_rejects_unknown_parent() {
+        let mut chain = Blockchain::regtest();
+        let mut block = mine_next_block(&chain, vec![]);
+        block.header.previous_hash = [7; 32];
 
-        for i in 1..=10 {
-            let block = Block {
-                header: BlockHeader {
-                    height: i,
-                    previous_hash: chain.blocks.last().unwrap().hash,
-                    timestamp: Utc::now().timestamp() + (i as i64 * 10),
-                    difficulty: chain.difficulty,
-                    nonce: 0,
-                    merkle_root: [0; 32],
-                },
-                hash: [i as u8; 32],
-                transactions: vec![],
-            };
+        assert!(matches!(chain.accept_header(block.header), Err(ChainError::InvalidBlockLinkage)));
+    }
 
-            chain.blocks.push(block);
-            chain.adjust_difficulty();
-        }
+    #[test]
+    fn test_connect_block_without_header_fails() {
+        let mut chain = Blockchain::regtest();
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let block = mine_next_block(&chain, vec![coinbase]);
 
-        assert!(chain.difficulty >= 2);
+        assert!(matches!(chain.connect_block_for_header(block), Err(ChainError::HeaderNotFound(_))));
     }
 
     #[test]
-    fn test_difficulty_adjustment_decrease() {
-        let mut chain = Blockchain::new();
+    fn test_block_transaction_count_limit() {
+        let chain = Blockchain::regtest();
+        let mut transactions = vec![Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()))];
+        let filler = Transaction::Transfer(crate::transaction::TransferTx::new(
+            [1; 32], "to".to_string(), "from".to_string(), 0, 0,
+        ));
+        transactions.extend(std::iter::repeat_n(filler, MAX_BLOCK_TRANSACTIONS));
+
+        let block = mine_next_block(&chain, transactions);
+        let err = chain.validate_block(&block).unwrap_err();
+        assert!(err.to_string().contains("maximum is"));
+    }
 
-        for i in 1..=10 {
-            let block = Block {
-                header: BlockHeader {
-                    height: i,
-                    previous_hash: chain.blocks.last().unwrap().hash,
-                    timestamp: Utc::now().timestamp() + (i as i64 * 200),
-                    difficulty: chain.difficulty,
-                    nonce: 0,
-                    merkle_root: [0; 32],
-                },
-                hash: [i as u8; 32],
-                transactions: vec![],
-            };
+    #[test]
+    fn test_mempool_block_selection_respects_size() {
+        let mut mempool = Mempool::new();
+        let keypair = KeyPair::generate().unwrap();
+        let (chain, leaves) = chain_with_leaves(&keypair, 1);
 
-            chain.blocks.push(block);
-            chain.adjust_difficulty();
+        for (nonce, parent) in (0..3).zip(&leaves) {
+            let mut tx = SubdivisionTx::new(parent.hash(), parent.subdivide().to_vec(), keypair.address(), nonce, nonce + 2);
+            let signature = keypair.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, keypair.public_key.serialize().to_vec());
+            mempool.add_transaction(Transaction::Subdivision(tx), &chain.state).unwrap();
         }
 
-        assert!(chain.difficulty <= 2);
+        let tx_size = codec::encoded_len(&mempool.get_all_transactions()[0]);
+        let selected = mempool.get_transactions_for_block(1, MAX_BLOCK_SIZE - tx_size * 2);
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[0].fee(), 2);
     }
 
     #[test]
-    fn test_difficulty_adjustment_no_change() {
-        let mut chain = Blockchain::new();
-        let initial_difficulty = chain.difficulty;
-
-        for i in 1..=10 {
-            let block = Block {
-                header: BlockHeader {
-                    height: i,
-                    previous_hash: chain.blocks.last().unwrap().hash,
-                    timestamp: Utc::now().timestamp() + (i as i64 * 60),
-                    difficulty: chain.difficulty,
-                    nonce: 0,
-                    merkle_root: [0; 32],
-                },
-                hash: [i as u8; 32],
-                transactions: vec![],
-            };
-
-            chain.blocks.push(block);
-            chain.adjust_difficulty();
+    fn test_median_time_past_rule() {
+        let mut chain = Blockchain::regtest();
+        let base = chain.blocks[0].header.timestamp;
+
+        for i in 1..=MEDIAN_TIME_PAST_WINDOW as i64 {
+            let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, format!("miner{}", i)));
+            let block = mine_next_block(&chain, vec![coinbase]);
+            chain.apply_block(block).unwrap();
         }
 
-        assert_eq!(chain.difficulty, initial_difficulty);
+        // Timestamps are base..base+11, so the median of the last 11 is base+6
+        let tip = chain.blocks.last().unwrap().hash;
+        assert_eq!(chain.median_time_past(&tip), base + 6);
+
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "late_miner".to_string()));
+        let mut block = mine_next_block(&chain, vec![coinbase]);
+        block.header.timestamp = base + 6;
+        block.hash = block.calculate_hash();
+        while !block.verify_proof_of_work() {
+            block.header.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
+        assert!(chain.validate_block(&block).is_err());
+
+        // Older than the parent but newer than the median is allowed
+        block.header.timestamp = base + 7;
+        block.hash = block.calculate_hash();
+        while !block.verify_proof_of_work() {
+            block.header.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
+        assert!(chain.validate_block(&block).is_ok());
     }
 
     #[test]
-    fn test_mempool_add_transaction() {
-        let mut mempool = Mempool::new();
-        let mut state = TriangleState::new();
-        let genesis = genesis_triangle();
-        let genesis_hash = genesis.hash();
-        state.utxo_set.insert(genesis_hash, genesis.clone());
-        let children = genesis.subdivide();
+    fn test_events_for_connected_block_and_mempool() {
+        let mut chain = Blockchain::regtest();
+        let mut events = chain.subscribe();
+
+        let genesis_hash = *chain.state.utxo_set.keys().next().unwrap();
+        let children = genesis_triangle().subdivide();
         let keypair = KeyPair::generate().unwrap();
-        let address = keypair.address();
-        let mut valid_tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address, 0, 1);
-        let message = valid_tx.signable_message();
-        let signature = keypair.sign(&message).unwrap();
-        let public_key = keypair.public_key.serialize().to_vec();
-        valid_tx.sign(signature, public_key);
-        let tx = Transaction::Subdivision(valid_tx);
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+        let mut tx = SubdivisionTx::new(genesis_hash, children.to_vec(), keypair.address(), 0, 1);
+        let signature = keypair.sign(&tx.signable_message()).unwrap();
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        let tx = Transaction::Subdivision(tx);
+        let tx_hash = tx.hash();
 
-        mempool.add_transaction(tx.clone()).unwrap();
-        assert_eq!(mempool.len(), 1);
-        assert!(!mempool.is_empty());
+        chain.mempool.add_transaction(tx.clone(), &chain.state).unwrap();
+        assert_eq!(events.try_recv().unwrap(), ChainEvent::TxAccepted(tx_hash));
+
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let block = mine_next_block(&chain, vec![coinbase, tx]);
+        let block_hash = block.hash;
+        chain.apply_block(block).unwrap();
+
+        assert_eq!(events.try_recv().unwrap(), ChainEvent::BlockConnected { hash: block_hash, height: 1 });
     }
 
     #[test]
-    fn test_mempool_remove_transaction() {
-        let mut mempool = Mempool::new();
-        let mut state = TriangleState::new();
-        let genesis = genesis_triangle();
-        let genesis_hash = genesis.hash();
-        state.utxo_set.insert(genesis_hash, genesis.clone());
-        let children = genesis.subdivide();
+    fn test_signature_cache_shared_with_mempool() {
+        let mut chain = Blockchain::regtest();
+        let genesis_hash = *chain.state.utxo_set.keys().next().unwrap();
+        let children = genesis_triangle().subdivide();
         let keypair = KeyPair::generate().unwrap();
-        let address = keypair.address();
-        let mut valid_tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address, 0, 1);
-        let message = valid_tx.signable_message();
-        let signature = keypair.sign(&message).unwrap();
-        let public_key = keypair.public_key.serialize().to_vec();
-        valid_tx.sign(signature, public_key);
-        let tx = Transaction::Subdivision(valid_tx);
-        let tx_hash = tx.hash();
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+        let mut tx = SubdivisionTx::new(genesis_hash, children.to_vec(), keypair.address(), 0, 1);
+        let signature = keypair.sign(&tx.signable_message()).unwrap();
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        let tx = Transaction::Subdivision(tx);
+
+        assert!(chain.sig_cache.is_empty());
+        chain.mempool.add_transaction(tx.clone(), &chain.state).unwrap();
+        assert_eq!(chain.sig_cache.len(), 1);
+
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let block = mine_next_block(&chain, vec![coinbase, tx]);
+        chain.apply_block(block).unwrap();
+        assert_eq!(chain.sig_cache.len(), 1);
+    }
 
-        mempool.add_transaction(tx.clone()).unwrap();
-        assert_eq!(mempool.len(), 1);
+    #[test]
+    fn test_pruning_drops_old_transactions() {
+        let mut chain = Blockchain::regtest();
+        for _ in 0..5 {
+            let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+            let block = mine_next_block(&chain, vec![coinbase]);
+            chain.apply_block(block).unwrap();
+        }
 
-        let removed = mempool.remove_transaction(&tx_hash);
-        assert!(removed.is_some());
-        assert_eq!(mempool.len(), 0);
+        assert_eq!(chain.enable_pruning(2).unwrap(), 3);
+        assert_eq!(chain.pruned_height, 3);
+        assert!(chain.is_pruned(&chain.blocks[3]));
+        assert!(chain.blocks[3].transactions.get().unwrap().is_empty());
+        assert!(chain.get_block(&chain.blocks[2].hash).unwrap().transactions.get().unwrap().is_empty());
+        assert_eq!(chain.blocks[4].transactions.get().unwrap().len(), 1);
+        assert_eq!(chain.prune_base.as_ref().unwrap().count(), 4);
+
+        // New blocks still validate and push the prune point forward
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let block = mine_next_block(&chain, vec![coinbase]);
+        chain.apply_block(block).unwrap();
+        assert_eq!(chain.pruned_height, 4);
+        assert_eq!(chain.state.count(), 7);
+
+        // A fork from below the prune point can no longer be replayed
+        let parent = chain.blocks[2].clone();
+        let coinbase = Transaction::Coinbase(CoinbaseTx::new(1000, "other".to_string()));
+        let mut fork = Block::new(3, parent.hash, chain.bits, vec![coinbase]);
+        fork.header.timestamp = chain.blocks[3].header.timestamp;
+        fork.hash = fork.calculate_hash();
+        while !fork.verify_proof_of_work() {
+            fork.header.nonce += 1;
+            fork.hash = fork.calculate_hash();
+        }
+        assert!(matches!(chain.apply_block(fork), Err(ChainError::PrunedData(_))));
     }
 
     #[test]
-    fn test_mempool_duplicate_transaction() {
-        let mut mempool = Mempool::new();
-        let mut state = TriangleState::new();
-        let genesis = genesis_triangle();
-        let genesis_hash = genesis.hash();
-        state.utxo_set.insert(genesis_hash, genesis.clone());
-        let children = genesis.subdivide();
+    fn test_coinbase_may_claim_the_fees() {
+        let mut chain = Blockchain::regtest();
         let keypair = KeyPair::generate().unwrap();
-        let address = keypair.address();
-        let mut valid_tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address, 0, 1);
-        let message = valid_tx.signable_message();
-        let signature = keypair.sign(&message).unwrap();
-        let public_key = keypair.public_key.serialize().to_vec();
-        valid_tx.sign(signature, public_key);
-        let tx = Transaction::Subdivision(valid_tx);
+        chain.state.utxo_set.get_mut(&genesis_triangle().hash()).unwrap().owner = keypair.address();
+        let children = genesis_triangle().subdivide().to_vec();
+        let mut tx = SubdivisionTx::new(genesis_triangle().hash(), children, keypair.address(), 100, 1);
+        tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+        let subdivision = Transaction::Subdivision(tx);
+        let claiming = |reward| vec![Transaction::Coinbase(CoinbaseTx::new(reward, "miner".to_string())), subdivision.clone()];
+
+        let greedy = mine_next_block(&chain, claiming(1101));
+        assert!(matches!(
+            chain.validate_block(&greedy),
+            Err(ChainError::CoinbaseRewardTooHigh { claimed: 1101, allowed: 1100 })
+        ));
+
+        chain.apply_block(mine_next_block(&chain, claiming(1100))).unwrap();
+        let reward = chain.state.by_owner("miner").next().unwrap().1;
+        assert!((reward.area() - 1100.0).abs() < 1e-6);
+        assert!(chain.audit_supply().problems.is_empty());
+    }
+
+    #[test]
+    fn test_multi_output_coinbase_pays_each_beneficiary() {
+        use crate::transaction::CoinbaseOutput;
 
-        mempool.add_transaction(tx.clone()).unwrap();
-        let result = mempool.add_transaction(tx.clone());
+        let mut chain = Blockchain::regtest();
+        let output = |address: &str, reward_area| CoinbaseOutput {
+            beneficiary_address: address.to_string(),
+            reward_area,
+        };
 
-        assert!(result.is_err());
-        assert_eq!(mempool.len(), 1);
+        // Outputs summing to more than subsidy + fees are rejected
+        let greedy = CoinbaseTx::with_outputs(vec![output("alice", 600), output("bob", 600)]);
+        let block = mine_next_block(&chain, vec![Transaction::Coinbase(greedy)]);
+        assert!(matches!(
+            chain.validate_block(&block),
+            Err(ChainError::CoinbaseRewardTooHigh { claimed: 1200, allowed: 1000 })
+        ));
+
+        let coinbase = CoinbaseTx::with_outputs(vec![output("alice", 600), output("bob", 400)]);
+        let block = mine_next_block(&chain, vec![Transaction::Coinbase(coinbase)]);
+        chain.apply_block(block).unwrap();
+
+        for (owner, area) in [("alice", 600.0), ("bob", 400.0)] {
+            let rewards: Vec<_> = chain.state.utxo_set.values()
+                .filter(|t| t.owner == owner)
+                .collect();
+            assert_eq!(rewards.len(), 1);
+            assert!((rewards[0].area() - area).abs() < 1e-6);
+        }
+        assert!(chain.audit_supply().problems.is_empty());
     }
 
     #[test]
-    fn test_mempool_validate_and_prune() {
-        let mut mempool = Mempool::new();
-        let mut state = TriangleState::new();
+    fn test_coinbase_placement_follows_block_hash() {
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+
+        // Competing blocks at the same height with the same coinbase
+        let first = mine_block_on(&genesis, chain.bits, "alice");
+        let mut second = first.clone();
+        second.header.timestamp += 1;
+        second.hash = second.calculate_hash();
+        while !second.verify_proof_of_work() {
+            second.header.nonce += 1;
+            second.hash = second.calculate_hash();
+        }
 
-        // Add genesis triangle to state
-        let genesis = genesis_triangle();
-        let genesis_hash = genesis.hash();
-        state.utxo_set.insert(genesis_hash, genesis.clone());
+        let Transaction::Coinbase(coinbase) = &first.transactions.get().unwrap()[0] else { unreachable!() };
+        let first_rewards = coinbase.reward_triangles(&first.hash);
+        assert_eq!(first_rewards, coinbase.reward_triangles(&first.hash));
+        assert_ne!(first_rewards[0].hash(), coinbase.reward_triangles(&second.hash)[0].hash());
 
-        // Create valid subdivision transaction
-        let children = genesis.subdivide();
-        let keypair = KeyPair::generate().unwrap();
-        let address = keypair.address();
-        let mut valid_tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address, 0, 1);
-        let message = valid_tx.signable_message();
-        let signature = keypair.sign(&message).unwrap();
-        let public_key = keypair.public_key.serialize().to_vec();
-        valid_tx.sign(signature, public_key);
+        chain.apply_block(first).unwrap();
+        assert!(chain.state.utxo_set.contains_key(&first_rewards[0].hash()));
 
-        mempool.add_transaction(Transaction::Subdivision(valid_tx)).unwrap();
+        // Reward triangles have the genesis shape
+        let reward = &first_rewards[0];
+        assert!((reward.area() - 1000.0).abs() < 1e-6);
+        let side = |p: &Point, q: &Point| ((p.x - q.x).powi(2) + (p.y - q.y).powi(2)).sqrt();
+        assert!((side(&reward.a, &reward.b) - side(&reward.b, &reward.c)).abs() < 1e-6);
+        assert!((side(&reward.a, &reward.b) - side(&reward.c, &reward.a)).abs() < 1e-6);
+    }
 
-        // Create invalid subdivision (non-existent parent), but with a valid signature
-        let invalid_parent_hash = [1; 32];
-        let keypair2 = KeyPair::generate().unwrap();
-        let address2 = keypair2.address();
-        let mut invalid_tx = SubdivisionTx::new(invalid_parent_hash, children.to_vec(), address2, 0, 1);
-        let message2 = invalid_tx.signable_message();
-        let signature2 = keypair2.sign(&message2).unwrap();
-        let public_key2 = keypair2.public_key.serialize().to_vec();
-        invalid_tx.sign(signature2, public_key2);
+    #[test]
+    fn test_coinbase_must_not_overlap_existing_triangles() {
+        let mut chain = Blockchain::regtest();
+        let block = mine_block_on(&chain.blocks[0].clone(), chain.bits, "alice");
+        let Transaction::Coinbase(coinbase) = &block.transactions.get().unwrap()[0] else { unreachable!() };
+        let reward = coinbase.reward_triangles(&block.hash)[0].clone();
+
+        // Someone already holds a triangle straddling the reward's corner
+        let squatter = Triangle::equilateral(
+            Point::new(reward.a.x - 1.0, reward.a.y - 1.0),
+            10.0,
+            "bob".to_string(),
+        );
+        let squatter_hash = squatter.hash();
+        chain.state.insert_triangle(squatter_hash, squatter);
+
+        assert!(matches!(
+            chain.validate_block(&block),
+            Err(ChainError::TriangleOverlap { existing, .. }) if existing == squatter_hash
+        ));
+        assert!(chain.clone().apply_block(block.clone()).is_err());
+
+        // Touching at a vertex is not overlapping
+        chain.state.remove_triangle(&squatter_hash);
+        let neighbour = Triangle::equilateral(
+            Point::new(reward.a.x - 10.0, reward.a.y),
+            10.0 * 10.0 * 3f64.sqrt() / 4.0,
+            "bob".to_string(),
+        );
+        chain.state.insert_triangle(neighbour.hash(), neighbour);
+        chain.validate_block(&block).unwrap();
+        chain.apply_block(block).unwrap();
+    }
 
-        // This should succeed because the signature is valid, even if the state is not.
-        mempool.add_transaction(Transaction::Subdivision(invalid_tx)).unwrap();
+    #[test]
+    fn test_fork_overlapping_only_the_main_chain_is_not_blacklisted() {
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+        chain.apply_block(mine_block_on(&genesis, chain.bits, "alice")).unwrap();
+
+        // The rival's reward lands on a triangle only the main chain holds
+        let rival = mine_block_on(&genesis, chain.bits, "bob");
+        let Transaction::Coinbase(coinbase) = &rival.transactions.get().unwrap()[0] else { unreachable!() };
+        let reward = coinbase.reward_triangles(&rival.hash)[0].clone();
+        let squatter = Triangle::equilateral(reward.centroid(), 10.0, "carol".to_string());
+        chain.state.insert_triangle(squatter.hash(), squatter);
+
+        chain.apply_block(rival.clone()).unwrap();
+        assert!(chain.forks.contains_key(&rival.hash));
+        assert!(!chain.is_known_invalid(&rival.hash));
+    }
 
-        // Should have 2 transactions
-        assert_eq!(mempool.len(), 2);
+    #[test]
+    fn test_block_timestamp_errors_are_typed() {
+        let chain = Blockchain::regtest();
+        let coinbase = || vec![Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()))];
+        let regrind = |block: &mut Block| {
+            block.hash = block.calculate_hash();
+            while !block.verify_proof_of_work() {
+                block.header.nonce += 1;
+                block.hash = block.calculate_hash();
+            }
+        };
 
-        // Validate and prune - should remove 1 invalid transaction
-        let removed = mempool.validate_and_prune(&state);
-        assert_eq!(removed, 1);
-        assert_eq!(mempool.len(), 1);
+        let mut future = mine_next_block(&chain, coinbase());
+        future.header.timestamp = Utc::now().timestamp() + MAX_FUTURE_TIMESTAMP_DRIFT + 600;
+        regrind(&mut future);
+        assert!(matches!(
+            chain.validate_block(&future),
+            Err(ChainError::TimestampTooFarInFuture { drift }) if (599..=600).contains(&drift)
+        ));
+
+        let mut stale = mine_next_block(&chain, coinbase());
+        stale.header.timestamp = chain.blocks[0].header.timestamp;
+        regrind(&mut stale);
+        assert!(matches!(chain.validate_block(&stale), Err(ChainError::TimestampTooOld { .. })));
     }
 
     #[test]
-    fn test_blockchain_with_mempool() {
-        let mut chain = Blockchain::new();
-        assert!(chain.mempool.is_empty());
+    fn test_creation_heights() {
+        let mut chain = Blockchain::regtest();
+        let genesis_hash = genesis_triangle().hash();
+
+        let block = mine_block_on(&chain.blocks[0].clone(), chain.bits, "alice");
+        let Transaction::Coinbase(coinbase) = &block.transactions.get().unwrap()[0] else { unreachable!() };
+        let reward = coinbase.reward_triangles(&block.hash)[0].hash();
+        chain.apply_block(block).unwrap();
+
+        let heights = chain.creation_heights();
+        assert_eq!(heights.len(), 2);
+        assert_eq!(heights[&genesis_hash], 0);
+        assert_eq!(heights[&reward], 1);
+    }
 
-        // Add a transaction to mempool
+    #[test]
+    fn test_neighbors() {
+        let mut state = TriangleState::new();
         let genesis = genesis_triangle();
-        let genesis_hash = genesis.hash();
         let children = genesis.subdivide();
-        let keypair = KeyPair::generate().unwrap();
-        let address = keypair.address();
-        let mut valid_tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address, 0, 1);
-        let message = valid_tx.signable_message();
-        let signature = keypair.sign(&message).unwrap();
-        let public_key = keypair.public_key.serialize().to_vec();
-        valid_tx.sign(signature, public_key);
-        let tx = Transaction::Subdivision(valid_tx);
-        chain.mempool.add_transaction(tx.clone()).unwrap();
-        assert_eq!(chain.mempool.len(), 1);
+        for child in children.iter().cloned() {
+            state.utxo_set.insert(child.hash(), child);
+        }
+        let far = Triangle::equilateral(Point::new(100.0, 100.0), 1.0, "bob".to_string());
+        state.utxo_set.insert(far.hash(), far.clone());
 
-        // Create and apply a block with that transaction
-        let last_block = chain.blocks.last().unwrap();
-        let coinbase = CoinbaseTx {
-            reward_area: 1000,
-            beneficiary_address: "miner_address".to_string(),
-        };
-        let mut new_block = Block::new(
-            last_block.header.height + 1,
-            last_block.hash,
-            chain.difficulty,
-            vec![Transaction::Coinbase(coinbase), tx],
-        );
+        let neighbors = state.neighbors(&children[0].hash()).unwrap();
+        let mut expected = vec![(children[1].hash(), Contact::Vertex), (children[2].hash(), Contact::Vertex)];
+        expected.sort_by_key(|(id, _)| *id);
+        assert_eq!(neighbors, expected);
 
-        // Ensure timestamp is greater than parent
-        new_block.header.timestamp = last_block.header.timestamp + 1;
+        assert!(state.neighbors(&far.hash()).unwrap().is_empty());
+        assert!(matches!(state.neighbors(&genesis.hash()), Err(ChainError::UtxoMissing { .. })));
+    }
 
-        // Before applying, mempool has 1 transaction
-        assert_eq!(chain.mempool.len(), 1);
+    #[test]
+    fn test_owner_index() {
+        let owned = |state: &TriangleState, owner: &str| {
+            let mut ids: Vec<TriangleId> = state.by_owner(owner).map(|(id, _)| *id).collect();
+            ids.sort();
+            ids
+        };
+        let mut state = genesis_state();
+        let genesis = genesis_triangle();
+        assert_eq!(owned(&state, "genesis_owner"), vec![genesis.hash()]);
 
-        // Apply block (this should remove the transaction from mempool)
-        let mut mined_block = new_block.clone();
-        loop {
-            mined_block.hash = mined_block.calculate_hash();
-            if mined_block.verify_proof_of_work() {
-                break;
-            }
-            mined_block.header.nonce += 1;
+        state.remove_triangle(&genesis.hash());
+        let children = genesis.subdivide();
+        for child in children.iter().cloned() {
+            state.insert_triangle(child.hash(), child);
         }
+        state.set_owner(&children[1].hash(), "bob".to_string()).unwrap();
+        let mut expected = vec![children[0].hash(), children[2].hash()];
+        expected.sort();
+        assert_eq!(owned(&state, "genesis_owner"), expected);
+        assert_eq!(owned(&state, "bob"), vec![children[1].hash()]);
+        assert!(owned(&state, "carol").is_empty());
+        assert!(state.set_owner(&genesis.hash(), "bob".to_string()).is_err());
+
+        // A direct edit leaves the index stale until it is rebuilt, but never wrong
+        state.utxo_set.get_mut(&children[0].hash()).unwrap().owner = "carol".to_string();
+        assert_eq!(owned(&state, "genesis_owner"), vec![children[2].hash()]);
+        assert!(owned(&state, "carol").is_empty());
+        state.rebuild_indexes();
+        assert_eq!(owned(&state, "carol"), vec![children[0].hash()]);
+    }
 
-        chain.apply_block(mined_block).unwrap();
-
-        // After applying, mempool should be empty
-        assert_eq!(chain.mempool.len(), 0);
+    #[test]
+    fn test_triangle_genealogy() {
+        let mut chain = Blockchain::regtest();
+        let genesis = genesis_triangle();
+        let keypair = KeyPair::generate().unwrap();
+        let alice = keypair.address();
+        chain.state.utxo_set.get_mut(&genesis.hash()).unwrap().owner = alice.clone();
+        chain.prune_base = Some(chain.state.clone());
+
+        // Block 1 splits genesis, block 2 splits its middle child
+        let split = |parent: &Triangle, nonce| {
+            let mut children = parent.subdivide().to_vec();
+            for child in &mut children {
+                child.owner = alice.clone();
+            }
+            let mut tx = SubdivisionTx::new(parent.hash(), children, alice.clone(), 0, nonce);
+            let signature = keypair.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, keypair.public_key.serialize().to_vec());
+            Transaction::Subdivision(tx)
+        };
+        let coinbase = || Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let first = split(&chain.state.utxo_set[&genesis.hash()].clone(), 1);
+        let block = mine_next_block(&chain, vec![coinbase(), first.clone()]);
+        chain.apply_block(block).unwrap();
+
+        let middle = chain.state.utxo_set.values().find(|t| t.child_index() == Some(1)).unwrap().clone();
+        let block = mine_next_block(&chain, vec![coinbase(), split(&middle, 2)]);
+        chain.apply_block(block).unwrap();
+
+        let grandchild = chain.state.utxo_set.values().find(|t| t.depth == 2 && t.child_index() == Some(0)).unwrap().clone();
+        let ancestry = chain.triangle_ancestry(&grandchild.hash()).unwrap();
+        let ids: Vec<_> = ancestry.iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![grandchild.hash(), middle.hash(), genesis.hash()]);
+        assert_eq!(ancestry[0].created_height, 2);
+        assert_eq!(ancestry[0].spent, None);
+        assert_eq!(ancestry[1].created_height, 1);
+        assert_eq!(ancestry[1].created_by, Some(first.hash()));
+        assert_eq!(ancestry[2].created_by, None);
+        assert_eq!(ancestry[1].spent.unwrap().0, 2);
+        assert_eq!(ancestry[2].created_height, 0);
+        assert_eq!(ancestry[2].spent, Some((1, first.hash())));
+        assert_eq!(ancestry[2].owners, vec![(0, alice.clone())]);
+
+        let tree = chain.triangle_descendants(&genesis.hash()).unwrap();
+        assert_eq!(tree.size(), 7);
+        assert_eq!(tree.children.len(), 3);
+        assert_eq!(tree.children[1].record.id, middle.hash());
+        assert_eq!(tree.children[1].children.len(), 3);
+        assert!(tree.children[0].children.is_empty());
+
+        assert!(matches!(chain.triangle_ancestry(&[7; 32]), Err(ChainError::TriangleNotFound(_))));
     }
 
     #[test]
@@ -1350,6 +5927,41 @@ mod tests {
         assert_eq!(Blockchain::calculate_block_reward(210_000 * 10), 0); // After 10 halvings, reward is <1
     }
 
+    #[test]
+    fn test_current_supply_closed_form() {
+        // Compare against summing rewards block by block around era boundaries
+        let naive = |height: BlockHeight| -> u64 {
+            (1..=height).map(Blockchain::calculate_block_reward).sum()
+        };
+        for height in [0, 1, 2, REWARD_HALVING_INTERVAL - 1, REWARD_HALVING_INTERVAL,
+                       REWARD_HALVING_INTERVAL + 1, 3 * REWARD_HALVING_INTERVAL + 17] {
+            assert_eq!(Blockchain::calculate_current_supply(height), naive(height), "height {}", height);
+        }
+
+        // Rewards run out long before the last halving, so supply stays under the cap
+        let final_supply = Blockchain::calculate_current_supply(u64::MAX / 2);
+        assert!(final_supply <= MAX_SUPPLY);
+        assert_eq!(final_supply, Blockchain::calculate_current_supply(100 * REWARD_HALVING_INTERVAL));
+    }
+
+    #[test]
+    fn test_supply_at_tip_tracks_connects_and_reorgs() {
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+        let block = mine_block_on(&genesis, chain.bits, "alice");
+        chain.apply_block(block).unwrap();
+        assert_eq!(chain.supply_at_tip, Blockchain::calculate_current_supply(1));
+
+        let mut parent = genesis;
+        for _ in 0..3 {
+            let block = mine_block_on(&parent, chain.bits, "bob");
+            chain.apply_block(block.clone()).unwrap();
+            parent = block;
+        }
+        assert_eq!(chain.blocks.len(), 4);
+        assert_eq!(chain.supply_at_tip, Blockchain::calculate_current_supply(3));
+    }
+
     #[test]
     fn test_transaction_fee_calculation() {
         use crate::transaction::{SubdivisionTx, TransferTx};
@@ -1365,6 +5977,7 @@ mod tests {
 
         // Test transfer transaction with fee
         let transfer_tx = TransferTx {
+            version: crate::transaction::TxVersion::CURRENT,
             input_hash: genesis.hash(),
             new_owner: "new_owner".to_string(),
             sender: address,
@@ -1373,6 +5986,7 @@ mod tests {
             memo: None,
             signature: None,
             public_key: None,
+            lock_time: None,
         };
         let tx2 = Transaction::Transfer(transfer_tx);
         assert_eq!(tx2.fee(), 50);
@@ -1387,21 +6001,19 @@ mod tests {
     fn test_mempool_fee_prioritization() {
         use crate::transaction::SubdivisionTx;
 
-        let mut chain = Blockchain::new();
-        let genesis = genesis_triangle();
-        let genesis_hash = genesis.hash();
-        let children = genesis.subdivide();
         let keypair = KeyPair::generate().unwrap();
         let address = keypair.address();
+        let (mut chain, leaves) = chain_with_leaves(&keypair, 2);
 
-        // Create transactions with different fees
+        // Create non-conflicting transactions with different fees
         for (i, fee) in [10u64, 50, 25, 100, 5].iter().enumerate() {
-            let mut tx = SubdivisionTx::new(genesis_hash, children.to_vec(), address.clone(), *fee, i as u64);
+            let parent = &leaves[i];
+            let mut tx = SubdivisionTx::new(parent.hash(), parent.subdivide().to_vec(), address.clone(), *fee, i as u64 + 5);
             let message = tx.signable_message();
             let signature = keypair.sign(&message).unwrap();
             let public_key = keypair.public_key.serialize().to_vec();
             tx.sign(signature, public_key);
-            chain.mempool.add_transaction(Transaction::Subdivision(tx)).unwrap();
+            chain.mempool.add_transaction(Transaction::Subdivision(tx), &chain.state).unwrap();
         }
 
         assert_eq!(chain.mempool.len(), 5);
@@ -0,0 +1,137 @@
+//! Canonical binary encoding for siertrichain
+//!
+//! Every byte string that is hashed, signed, sent to a peer or written to the
+//! database goes through this module, so two nodes always agree on the bytes
+//! behind a value. The encoding is bincode with fixed-width little-endian
+//! integers, length-prefixed sequences and struct fields in declaration order,
+//! preceded by a single version byte.
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::blockchain::Sha256Hash;
+use crate::error::ChainError;
+use crate::transaction::UNSUPPORTED_VERSION_ERROR;
+
+/// Version byte written in front of every encoding
+pub const CODEC_VERSION: u8 = 1;
+
+/// Largest payload `decode` will accept, so a corrupt length prefix can't
+/// make us allocate unbounded memory
+pub const MAX_DECODE_SIZE: u64 = 64 * 1024 * 1024;
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .reject_trailing_bytes()
+}
+
+/// Encode a value canonically, prefixed with the codec version.
+///
+/// Panics only if `T`'s `Serialize` impl itself fails, which never happens for
+/// the derived impls of chain types.
+pub fn encode<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![CODEC_VERSION];
+    options()
+        .serialize_into(&mut bytes, value)
+        .expect("chain types always encode");
+    bytes
+}
+
+/// Decode a value written by `encode`, rejecting unknown versions and trailing
+/// bytes. A transaction with an unknown version fails with `UnsupportedTxVersion`.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ChainError> {
+    let (version, body) = bytes
+        .split_first()
+        .ok_or_else(|| ChainError::CodecError("Empty input".to_string()))?;
+    if *version != CODEC_VERSION {
+        return Err(ChainError::CodecError(format!(
+            "Unsupported encoding version {} (expected {})",
+            version, CODEC_VERSION
+        )));
+    }
+
+    options()
+        .with_limit(MAX_DECODE_SIZE)
+        .deserialize(body)
+        .map_err(|e| match *e {
+            bincode::ErrorKind::Custom(ref msg) => msg
+                .strip_prefix(UNSUPPORTED_VERSION_ERROR)
+                .and_then(|version| version.parse().ok())
+                .map(ChainError::UnsupportedTxVersion)
+                .unwrap_or_else(|| ChainError::CodecError(e.to_string())),
+            _ => ChainError::CodecError(e.to_string()),
+        })
+}
+
+/// Length in bytes of `encode(value)`, without building the encoding
+pub fn encoded_len<T: Serialize + ?Sized>(value: &T) -> usize {
+    options()
+        .serialized_size(value)
+        .map(|size| size as usize + 1)
+        .unwrap_or(usize::MAX)
+}
+
+/// SHA-256 of the canonical encoding
+pub fn hash<T: Serialize + ?Sized>(value: &T) -> Sha256Hash {
+    Sha256::digest(encode(value)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::transaction::{CoinbaseTx, Transaction};
+
+    #[test]
+    fn test_round_trip_and_length() {
+        let block = Blockchain::regtest().blocks[0].clone();
+        let bytes = encode(&block);
+
+        assert_eq!(bytes[0], CODEC_VERSION);
+        assert_eq!(encoded_len(&block), bytes.len());
+
+        let decoded: crate::blockchain::Block = decode(&bytes).unwrap();
+        assert_eq!(decoded.hash, block.hash);
+        assert_eq!(encode(&decoded), bytes);
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_input() {
+        let tx = Transaction::Coinbase(CoinbaseTx::new(1000, "miner".to_string()));
+        let mut bytes = encode(&tx);
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(decode::<Transaction>(&trailing).is_err());
+        assert!(decode::<Transaction>(&[]).is_err());
+
+        bytes[0] = CODEC_VERSION + 1;
+        assert!(matches!(decode::<Transaction>(&bytes), Err(ChainError::CodecError(_))));
+    }
+
+    #[test]
+    fn test_decode_rejects_unknown_tx_version() {
+        use crate::transaction::TxVersion;
+
+        let mut coinbase = CoinbaseTx::new(1000, "miner".to_string());
+        let tx = Transaction::Coinbase(coinbase.clone());
+        let decoded: Transaction = decode(&encode(&tx)).unwrap();
+        assert_eq!(decoded.version(), TxVersion::CURRENT);
+
+        // The version is part of the hash
+        coinbase.version = TxVersion(TxVersion::CURRENT.0 + 1);
+        let future = Transaction::Coinbase(coinbase);
+        assert_ne!(future.hash(), tx.hash());
+        assert!(matches!(
+            decode::<Transaction>(&encode(&future)),
+            Err(ChainError::UnsupportedTxVersion(v)) if v == TxVersion::CURRENT.0 + 1
+        ));
+        assert!(matches!(
+            future.validate(&crate::blockchain::TriangleState::new()),
+            Err(ChainError::UnsupportedTxVersion(_))
+        ));
+    }
+}
@@ -0,0 +1,128 @@
+//! Where a node keeps its data
+//!
+//! The database lives in the data directory: `~/.siertrichain/data` unless
+//! the `SIERTRI_DATADIR` environment variable names another, and a binary's
+//! `--datadir <dir>` flag overrides both. Wallets stay in `~/.siertrichain`.
+//! With `--regtest`, a node runs the regression test network instead, keeping
+//! its database in a `regtest` subdirectory so the two never share one.
+
+use crate::error::ChainError;
+use crate::params::ChainParams;
+use std::path::PathBuf;
+
+/// Environment variable naming the data directory
+pub const DATADIR_ENV: &str = "SIERTRI_DATADIR";
+
+/// File name of the database within the data directory
+pub const DB_FILE: &str = "siertrichain.db";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub datadir: PathBuf,
+    /// The network the node runs
+    pub params: ChainParams,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { datadir: default_datadir(), params: ChainParams::MAINNET }
+    }
+}
+
+impl Config {
+    /// The data directory in `SIERTRI_DATADIR`, or the default
+    pub fn from_env() -> Self {
+        match std::env::var_os(DATADIR_ENV) {
+            Some(datadir) if !datadir.is_empty() => Config { datadir: datadir.into(), ..Config::default() },
+            _ => Config::default(),
+        }
+    }
+
+    /// `from_env`, changed by `--datadir <dir>` and `--regtest` in `args`,
+    /// which are taken out so the rest parse as before
+    pub fn from_args(args: &mut Vec<String>) -> Result<Self, ChainError> {
+        let mut config = Self::from_env();
+        if let Some(flag) = args.iter().position(|arg| arg == "--regtest") {
+            args.remove(flag);
+            config = config.with_params(ChainParams::REGTEST);
+        }
+        let Some(flag) = args.iter().position(|arg| arg == "--datadir") else {
+            return Ok(config);
+        };
+        if flag + 1 >= args.len() {
+            return Err(ChainError::DatabaseError("--datadir needs a directory".to_string()));
+        }
+        let datadir = args.remove(flag + 1);
+        args.remove(flag);
+        Ok(config.with_datadir(datadir))
+    }
+
+    pub fn with_datadir(mut self, datadir: impl Into<PathBuf>) -> Self {
+        self.datadir = datadir.into();
+        self
+    }
+
+    pub fn with_params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Where the network's data is kept: the data directory itself for the
+    /// main network, a subdirectory named after any other
+    pub fn network_dir(&self) -> PathBuf {
+        if self.params.network == ChainParams::MAINNET.network {
+            self.datadir.clone()
+        } else {
+            self.datadir.join(self.params.network)
+        }
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        self.network_dir().join(DB_FILE)
+    }
+
+    /// Where scheduled backups of the database go
+    pub fn backup_dir(&self) -> PathBuf {
+        self.network_dir().join("backups")
+    }
+
+    /// Create the network's data directory if it doesn't exist
+    pub fn ensure_datadir(&self) -> Result<(), ChainError> {
+        let dir = self.network_dir();
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to create data directory {}: {}", dir.display(), e)))
+    }
+}
+
+/// `~/.siertrichain/data`
+pub fn default_datadir() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home).join(".siertrichain").join("data")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datadir_flag_is_taken_out_of_the_args() {
+        let mut args: Vec<String> = ["siertrid", "--port", "8333", "--datadir", "/tmp/chain"]
+            .iter().map(|arg| arg.to_string()).collect();
+        let config = Config::from_args(&mut args).unwrap();
+        assert_eq!(config.db_path(), PathBuf::from("/tmp/chain").join(DB_FILE));
+        assert_eq!(args, ["siertrid", "--port", "8333"]);
+
+        let mut args = vec!["siertrid".to_string(), "--datadir".to_string()];
+        assert!(Config::from_args(&mut args).is_err());
+    }
+
+    #[test]
+    fn test_regtest_keeps_its_own_database() {
+        let mut args: Vec<String> = ["siertri-verify", "--regtest", "--datadir", "/tmp/chain", "--full"]
+            .iter().map(|arg| arg.to_string()).collect();
+        let config = Config::from_args(&mut args).unwrap();
+        assert_eq!(config.params, ChainParams::REGTEST);
+        assert_eq!(config.db_path(), PathBuf::from("/tmp/chain").join("regtest").join(DB_FILE));
+        assert_eq!(args, ["siertri-verify", "--full"]);
+    }
+}
@@ -0,0 +1,187 @@
+//! Outbound connection management for siertrichain nodes
+//!
+//! A `ConnectionManager` keeps a node connected to a target number of peers
+//! it dialed itself. Every check it pings connected peers, drops the ones
+//! that stopped answering, and dials candidates from the peer list and seed
+//! nodes to make up the difference. A peer that fails to connect is not tried
+//! again until its backoff, doubling with each failure in a row, runs out.
+
+use crate::network::{Direction, NetworkNode, PeerState, PeerStatus};
+use crate::params::ChainParams;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Outbound connections a node keeps by default
+pub const DEFAULT_OUTBOUND_PEERS: usize = 8;
+
+/// How often connections are checked and topped up
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often connected peers are pinged
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a ping may go unanswered before the peer is dropped
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Wait after a peer's first failed connection
+const BASE_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Longest wait between attempts to connect to a peer
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// How long to wait before trying a peer again after `failures` failed attempts in a row
+pub fn backoff_delay(failures: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+    /// `retry_at` as Unix time, for reporting
+    retry_at_unix: i64,
+}
+
+pub struct ConnectionManager {
+    node: NetworkNode,
+    params: ChainParams,
+    target: usize,
+    backoff: HashMap<String, Backoff>,
+    last_ping: Option<Instant>,
+}
+
+impl ConnectionManager {
+    pub fn new(node: NetworkNode, params: ChainParams, target: usize) -> Self {
+        ConnectionManager {
+            node,
+            params,
+            target,
+            backoff: HashMap::new(),
+            last_ping: None,
+        }
+    }
+
+    /// Check connections every `CHECK_INTERVAL`, forever
+    pub async fn run(mut self) {
+        loop {
+            self.check().await;
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Ping peers if due, then connect to more until the target is reached
+    /// or no candidate is ready, and save the resulting peer statuses
+    pub async fn check(&mut self) {
+        if self.last_ping.is_none_or(|last| last.elapsed() >= PING_INTERVAL) {
+            self.node.drop_unresponsive_sessions(PEER_TIMEOUT);
+            self.node.ping_sessions();
+            self.last_ping = Some(Instant::now());
+        }
+
+        self.connect_more().await;
+
+        if let Err(e) = self.node.save_peer_statuses(&self.statuses()) {
+            eprintln!("❌ Failed to save peer statuses: {}", e);
+        }
+        if let Err(e) = self.node.save_sync_state(&self.node.sync_state().await) {
+            eprintln!("❌ Failed to save sync state: {}", e);
+        }
+    }
+
+    async fn connect_more(&mut self) {
+        let sessions = self.node.session_statuses();
+        let connected: HashSet<String> = sessions.iter().map(|peer| peer.address.clone()).collect();
+        let mut outbound = sessions.iter().filter(|peer| peer.direction == Direction::Outbound).count();
+
+        let now = Instant::now();
+        for peer in self.node.candidates(&self.params).await {
+            if outbound >= self.target {
+                break;
+            }
+            let addr = peer.addr();
+            if connected.contains(&addr) || self.backoff.get(&addr).is_some_and(|b| b.retry_at > now) {
+                continue;
+            }
+
+            match self.node.connect_peer(peer.host.clone(), peer.port).await {
+                Ok(()) => {
+                    self.backoff.remove(&addr);
+                    outbound += 1;
+                }
+                Err(e) => {
+                    let failures = self.backoff.get(&addr).map_or(1, |b| b.failures + 1);
+                    let delay = backoff_delay(failures);
+                    eprintln!("❌ Failed to connect to {} ({} in a row), retrying in {:?}: {}", addr, failures, delay, e);
+                    self.backoff.insert(addr, Backoff {
+                        failures,
+                        retry_at: Instant::now() + delay,
+                        retry_at_unix: chrono::Utc::now().timestamp() + delay.as_secs() as i64,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Connected peers, then the ones waiting out a backoff
+    pub fn statuses(&self) -> Vec<PeerStatus> {
+        let mut statuses = self.node.session_statuses();
+        let mut waiting: Vec<PeerStatus> = self.backoff.iter()
+            .filter(|(addr, _)| !statuses.iter().any(|peer| &peer.address == *addr))
+            .map(|(addr, backoff)| PeerStatus {
+                address: addr.clone(),
+                state: PeerState::Backoff,
+                direction: Direction::Outbound,
+                last_seen: None,
+                latency_ms: None,
+                failures: backoff.failures,
+                retry_at: Some(backoff.retry_at_unix),
+                encrypted: false,
+            })
+            .collect();
+        waiting.sort_by(|a, b| a.address.cmp(&b.address));
+        statuses.extend(waiting);
+        statuses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::network::Node;
+    use crate::persistence::Database;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff_delay(1), Duration::from_secs(5));
+        assert_eq!(backoff_delay(2), Duration::from_secs(10));
+        assert_eq!(backoff_delay(4), Duration::from_secs(40));
+        assert_eq!(backoff_delay(20), MAX_BACKOFF);
+        assert_eq!(backoff_delay(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_failed_peers_back_off() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let db = Arc::new(Mutex::new(Database::open(":memory:").unwrap()));
+        let node = NetworkNode::new(Blockchain::regtest(), String::new()).with_database(db.clone());
+        node.add_peer(Node::parse(&dead).unwrap()).await;
+        let mut manager = ConnectionManager::new(node, ChainParams::MAINNET, DEFAULT_OUTBOUND_PEERS);
+
+        manager.check().await;
+        // Still backing off, so the second check doesn't count another failure
+        manager.check().await;
+
+        let statuses = db.lock().unwrap().load_peer_statuses().unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].address, dead);
+        assert_eq!(statuses[0].state, PeerState::Backoff);
+        assert_eq!(statuses[0].failures, 1);
+        assert!(statuses[0].retry_at.is_some());
+    }
+}
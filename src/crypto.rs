@@ -4,6 +4,9 @@ use sha2::{Digest, Sha256};
 use secp256k1::{Secp256k1, SecretKey, PublicKey, Message, ecdsa::Signature};
 use rand::rngs::OsRng;
 use crate::error::ChainError;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct KeyPair {
@@ -39,10 +42,7 @@ impl KeyPair {
     }
     
     pub fn address(&self) -> String {
-        let pubkey_bytes = self.public_key.serialize();
-        let mut hasher = Sha256::new();
-        hasher.update(&pubkey_bytes);
-        format!("{:x}", hasher.finalize())
+        address_from_public_key(&self.public_key.serialize())
     }
 
     pub fn public_key_bytes(&self) -> Vec<u8> {
@@ -64,6 +64,13 @@ impl KeyPair {
     }
 }
 
+/// Address controlled by a serialized public key: the hex SHA-256 of its bytes
+pub fn address_from_public_key(public_key_bytes: &[u8]) -> Address {
+    let mut hasher = Sha256::new();
+    hasher.update(public_key_bytes);
+    format!("{:x}", hasher.finalize())
+}
+
 pub fn verify_signature(
     public_key_bytes: &[u8],
     message: &[u8],
@@ -87,6 +94,117 @@ pub fn verify_signature(
     Ok(secp.verify_ecdsa(&message, &signature, &public_key).is_ok())
 }
 
+/// Number of verified signatures remembered by a `SignatureCache`
+pub const SIGNATURE_CACHE_CAPACITY: usize = 50_000;
+
+/// Least-recently-used set of signatures that have already been verified.
+/// Clones share the same underlying cache, so the mempool and the block
+/// validator can skip checking a signature twice.
+#[derive(Debug, Clone)]
+pub struct SignatureCache {
+    inner: Arc<Mutex<SignatureCacheEntries>>,
+}
+
+#[derive(Debug)]
+struct SignatureCacheEntries {
+    capacity: usize,
+    next_tick: u64,
+    /// Cache key to the tick it was last used at
+    entries: HashMap<[u8; 32], u64>,
+    /// Tick to cache key, oldest first
+    order: BTreeMap<u64, [u8; 32]>,
+}
+
+impl SignatureCache {
+    pub fn new(capacity: usize) -> Self {
+        SignatureCache {
+            inner: Arc::new(Mutex::new(SignatureCacheEntries {
+                capacity,
+                next_tick: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Verify a transaction signature, consulting the cache first.
+    /// Only valid signatures are cached.
+    pub fn verify(
+        &self,
+        tx_hash: &[u8; 32],
+        public_key_bytes: &[u8],
+        message: &[u8],
+        signature_bytes: &[u8],
+    ) -> Result<bool, ChainError> {
+        let key = Self::cache_key(tx_hash, public_key_bytes, signature_bytes);
+        if self.inner.lock().touch(&key) {
+            return Ok(true);
+        }
+
+        let is_valid = verify_signature(public_key_bytes, message, signature_bytes)?;
+        if is_valid {
+            self.inner.lock().insert(key);
+        }
+        Ok(is_valid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn cache_key(tx_hash: &[u8; 32], public_key_bytes: &[u8], signature_bytes: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(tx_hash);
+        hasher.update((public_key_bytes.len() as u32).to_le_bytes());
+        hasher.update(public_key_bytes);
+        hasher.update(signature_bytes);
+        hasher.finalize().into()
+    }
+}
+
+impl Default for SignatureCache {
+    fn default() -> Self {
+        Self::new(SIGNATURE_CACHE_CAPACITY)
+    }
+}
+
+impl SignatureCacheEntries {
+    /// Mark a key as recently used. Returns false if it is not cached.
+    fn touch(&mut self, key: &[u8; 32]) -> bool {
+        let tick = self.next_tick;
+        match self.entries.get_mut(key) {
+            Some(last_used) => {
+                self.order.remove(last_used);
+                *last_used = tick;
+                self.order.insert(tick, *key);
+                self.next_tick += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, key: [u8; 32]) {
+        if self.capacity == 0 || self.touch(&key) {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(key, self.next_tick);
+        self.order.insert(self.next_tick, key);
+        self.next_tick += 1;
+    }
+}
+
 pub type Address = String;
 
 #[cfg(test)]
@@ -143,4 +261,40 @@ mod tests {
         let is_valid = verify_signature(&pubkey_bytes, tampered, &signature).unwrap();
         assert!(!is_valid);
     }
+
+    #[test]
+    fn test_signature_cache_hits_and_evicts() {
+        let keypair = KeyPair::generate().unwrap();
+        let pubkey_bytes = keypair.public_key.serialize();
+        let cache = SignatureCache::new(2);
+
+        let mut hashes = Vec::new();
+        for i in 0..3u8 {
+            let message = [i; 8];
+            let signature = keypair.sign(&message).unwrap();
+            let tx_hash = [i; 32];
+            assert!(cache.verify(&tx_hash, &pubkey_bytes, &message, &signature).unwrap());
+            hashes.push((tx_hash, message, signature));
+        }
+        assert_eq!(cache.len(), 2);
+
+        // A cached entry is trusted without re-verifying the message
+        let (tx_hash, _, signature) = &hashes[2];
+        assert!(cache.verify(tx_hash, &pubkey_bytes, b"ignored", signature).unwrap());
+
+        // The oldest entry was evicted, so it is verified again (and fails here)
+        let (tx_hash, _, signature) = &hashes[0];
+        assert!(!cache.verify(tx_hash, &pubkey_bytes, b"ignored", signature).unwrap());
+    }
+
+    #[test]
+    fn test_signature_cache_skips_invalid() {
+        let keypair = KeyPair::generate().unwrap();
+        let signature = keypair.sign(b"message").unwrap();
+        let cache = SignatureCache::default();
+
+        let pubkey_bytes = keypair.public_key.serialize();
+        assert!(!cache.verify(&[0; 32], &pubkey_bytes, b"other", &signature).unwrap());
+        assert!(cache.is_empty());
+    }
 }
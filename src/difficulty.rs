@@ -0,0 +1,323 @@
+//! Compact difficulty targets for siertrichain
+//!
+//! Proof-of-work targets are 256-bit numbers stored in block headers using
+//! Bitcoin's compact "bits" encoding: the top byte is the size of the target in
+//! bytes and the low three bytes are its most significant digits. A block hash,
+//! read as a big-endian number, is valid when it is less than or equal to the target.
+
+use crate::blockchain::Sha256Hash;
+
+/// A 256-bit proof-of-work target, big-endian
+pub type Target = [u8; 32];
+
+/// An exact amount of work, as a 256-bit big-endian number. Byte order makes
+/// comparing two of them compare their values.
+pub type Work = [u8; 32];
+
+/// Easiest target the main network allows, Bitcoin's: about 2^32 hashes a
+/// block. A chain's genesis block carries its network's limit.
+pub const POW_LIMIT_BITS: u32 = 0x1d00ffff;
+
+/// Easiest target on the regression test network, which nearly every hash
+/// meets so that local tests mine blocks instantly. Never for a real network.
+pub const REGTEST_POW_LIMIT_BITS: u32 = 0x207fffff;
+
+/// Decode compact bits into a full 256-bit target.
+/// Negative targets decode to zero; targets too large to fit in 256 bits saturate.
+pub fn compact_to_target(bits: u32) -> Target {
+    let size = (bits >> 24) as i64;
+    let mantissa = bits & 0x007f_ffff;
+    let mut target = [0u8; 32];
+
+    if bits & 0x0080_0000 != 0 {
+        return target;
+    }
+
+    let mantissa_bytes = [(mantissa >> 16) as u8, (mantissa >> 8) as u8, mantissa as u8];
+    for (i, byte) in mantissa_bytes.iter().enumerate() {
+        // Position of this byte counted from the least significant end
+        let position = size - 1 - i as i64;
+        if position < 0 {
+            continue;
+        }
+        if position >= 32 {
+            if *byte != 0 {
+                return [0xff; 32];
+            }
+            continue;
+        }
+        target[31 - position as usize] = *byte;
+    }
+
+    target
+}
+
+/// Encode a 256-bit target into compact bits, rounding down to 3 significant bytes
+pub fn target_to_compact(target: &Target) -> u32 {
+    let first_nonzero = match target.iter().position(|b| *b != 0) {
+        Some(index) => index,
+        None => return 0,
+    };
+
+    let mut size = (32 - first_nonzero) as u32;
+    let byte_at = |i: usize| target.get(i).copied().unwrap_or(0) as u32;
+    let mut mantissa = (byte_at(first_nonzero) << 16)
+        | (byte_at(first_nonzero + 1) << 8)
+        | byte_at(first_nonzero + 2);
+
+    // The high bit of the mantissa is a sign bit, so shift it out of the way
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+
+    (size << 24) | mantissa
+}
+
+/// Compact bits for the target requiring `leading_zeros` zero hex digits,
+/// which was the difficulty scheme used before compact targets.
+pub fn bits_from_leading_zeros(leading_zeros: u64) -> u32 {
+    let zero_bits = (leading_zeros.min(64) * 4) as usize;
+    let mut target = [0xffu8; 32];
+    for (i, byte) in target.iter_mut().enumerate() {
+        let bit_start = i * 8;
+        if bit_start + 8 <= zero_bits {
+            *byte = 0;
+        } else if bit_start < zero_bits {
+            *byte = 0x0f;
+        }
+    }
+    target_to_compact(&target)
+}
+
+/// Checks whether a hash satisfies the target encoded in `bits`
+pub fn hash_meets_target(hash: &Sha256Hash, bits: u32) -> bool {
+    let target = compact_to_target(bits);
+    target != [0u8; 32] && *hash <= target
+}
+
+/// Checks that `bits` is a well-formed target. How easy it may be is up to
+/// each chain, whose blocks must follow its retargeting.
+pub fn is_valid_bits(bits: u32) -> bool {
+    compact_to_target(bits) != [0u8; 32]
+}
+
+/// Scale the target by `actual_time / expected_time`, limiting the change to
+/// 4x in either direction and never exceeding `pow_limit`.
+pub fn retarget(bits: u32, actual_time: i64, expected_time: i64, pow_limit: u32) -> u32 {
+    if expected_time <= 0 {
+        return bits;
+    }
+
+    let actual_time = actual_time.clamp(expected_time / 4, expected_time * 4).max(1);
+    let target = to_limbs(&compact_to_target(bits));
+
+    let scaled = mul_small(&target, actual_time as u64);
+    let quotient = div_small(&scaled, expected_time as u64);
+
+    let limit = compact_to_target(pow_limit);
+    let new_target = match from_limbs(&quotient) {
+        Some(t) if t <= limit => t,
+        _ => limit,
+    };
+
+    target_to_compact(&new_target)
+}
+
+/// Expected number of hashes needed to find a block at this target
+pub fn work(bits: u32) -> f64 {
+    let target = target_to_f64(&compact_to_target(bits));
+    if target <= 0.0 {
+        return f64::INFINITY;
+    }
+    2f64.powi(256) / (target + 1.0)
+}
+
+/// Exact expected number of hashes needed to find a block at this target,
+/// 2^256 / (target + 1) rounded down. Summed over a chain, it picks the best one.
+pub fn block_work(bits: u32) -> Work {
+    let divisor = add_limbs(&to_limbs(&compact_to_target(bits)), &[1, 0, 0, 0, 0]);
+    // Only a zero target needs all of 2^256, which doesn't fit
+    from_limbs(&div_limbs(&[0, 0, 0, 0, 1], &divisor)).unwrap_or([0xff; 32])
+}
+
+/// The sum of two amounts of work, saturating at the largest 256-bit number
+pub fn add_work(a: &Work, b: &Work) -> Work {
+    from_limbs(&add_limbs(&to_limbs(a), &to_limbs(b))).unwrap_or([0xff; 32])
+}
+
+/// Human-readable difficulty: how many times harder than the main network's
+/// proof-of-work limit
+pub fn difficulty(bits: u32) -> f64 {
+    let target = target_to_f64(&compact_to_target(bits));
+    if target <= 0.0 {
+        return f64::INFINITY;
+    }
+    target_to_f64(&compact_to_target(POW_LIMIT_BITS)) / target
+}
+
+fn target_to_f64(target: &Target) -> f64 {
+    target.iter().fold(0.0, |acc, byte| acc * 256.0 + *byte as f64)
+}
+
+/// Big-endian bytes to little-endian 64-bit limbs, with one spare limb for overflow
+fn to_limbs(target: &Target) -> [u64; 5] {
+    let mut limbs = [0u64; 5];
+    for (i, limb) in limbs.iter_mut().take(4).enumerate() {
+        let start = 32 - (i + 1) * 8;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&target[start..start + 8]);
+        *limb = u64::from_be_bytes(bytes);
+    }
+    limbs
+}
+
+/// Little-endian limbs back to big-endian bytes, or `None` if the value overflows 256 bits
+fn from_limbs(limbs: &[u64; 5]) -> Option<Target> {
+    if limbs[4] != 0 {
+        return None;
+    }
+    let mut target = [0u8; 32];
+    for (i, limb) in limbs.iter().take(4).enumerate() {
+        let start = 32 - (i + 1) * 8;
+        target[start..start + 8].copy_from_slice(&limb.to_be_bytes());
+    }
+    Some(target)
+}
+
+fn mul_small(limbs: &[u64; 5], factor: u64) -> [u64; 5] {
+    let mut result = [0u64; 5];
+    let mut carry: u128 = 0;
+    for i in 0..5 {
+        let product = limbs[i] as u128 * factor as u128 + carry;
+        result[i] = product as u64;
+        carry = product >> 64;
+    }
+    if carry != 0 {
+        // Saturate: anything this large is clamped to the proof-of-work limit anyway
+        return [u64::MAX; 5];
+    }
+    result
+}
+
+fn add_limbs(a: &[u64; 5], b: &[u64; 5]) -> [u64; 5] {
+    let mut result = [0u64; 5];
+    let mut carry = false;
+    for i in 0..5 {
+        let (sum, overflow) = a[i].overflowing_add(b[i]);
+        let (sum, carried) = sum.overflowing_add(carry as u64);
+        result[i] = sum;
+        carry = overflow || carried;
+    }
+    result
+}
+
+/// Long division one bit at a time; `divisor` must not be zero
+fn div_limbs(numerator: &[u64; 5], divisor: &[u64; 5]) -> [u64; 5] {
+    let mut quotient = [0u64; 5];
+    let mut remainder = [0u64; 5];
+    for bit in (0..320).rev() {
+        // The remainder stays below the divisor, so shifting it can't overflow
+        for i in (1..5).rev() {
+            remainder[i] = (remainder[i] << 1) | (remainder[i - 1] >> 63);
+        }
+        remainder[0] = (remainder[0] << 1) | ((numerator[bit / 64] >> (bit % 64)) & 1);
+
+        if remainder.iter().rev().cmp(divisor.iter().rev()) != std::cmp::Ordering::Less {
+            let mut borrow = false;
+            for i in 0..5 {
+                let (difference, underflow) = remainder[i].overflowing_sub(divisor[i]);
+                let (difference, borrowed) = difference.overflowing_sub(borrow as u64);
+                remainder[i] = difference;
+                borrow = underflow || borrowed;
+            }
+            quotient[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+    quotient
+}
+
+fn div_small(limbs: &[u64; 5], divisor: u64) -> [u64; 5] {
+    let mut result = [0u64; 5];
+    let mut remainder: u128 = 0;
+    for i in (0..5).rev() {
+        let current = (remainder << 64) | limbs[i] as u128;
+        result[i] = (current / divisor as u128) as u64;
+        remainder = current % divisor as u128;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_two_leading_zeros() {
+        assert_eq!(bits_from_leading_zeros(2), 0x2000ffff);
+
+        let target = compact_to_target(0x2000ffff);
+        assert_eq!(target[0], 0x00);
+        assert_eq!(target[1], 0xff);
+    }
+
+    #[test]
+    fn test_compact_roundtrip() {
+        for bits in [0x2000ffff, POW_LIMIT_BITS, REGTEST_POW_LIMIT_BITS, 0x1b0404cb, 0x03123456] {
+            assert_eq!(target_to_compact(&compact_to_target(bits)), bits);
+        }
+    }
+
+    #[test]
+    fn test_hash_meets_target_is_numeric() {
+        // 0x2000ffff decodes to 0x00ffff00...00
+        let mut hash = [0xffu8; 32];
+        hash[0] = 0x00;
+        hash[2] = 0xfe;
+        assert!(hash_meets_target(&hash, 0x2000ffff));
+
+        // Two leading zero hex digits alone no longer guarantee a valid hash
+        hash[2] = 0xff;
+        assert!(!hash_meets_target(&hash, 0x2000ffff));
+
+        hash[0] = 0x01;
+        assert!(!hash_meets_target(&hash, 0x2000ffff));
+    }
+
+    #[test]
+    fn test_retarget_clamps_to_four_times() {
+        let start = compact_to_target(0x1f00ffff);
+        let retarget = |actual_time| retarget(0x1f00ffff, actual_time, 1000, REGTEST_POW_LIMIT_BITS);
+
+        let harder = compact_to_target(retarget(1));
+        assert!(harder < start);
+        assert_eq!(retarget(1), retarget(250));
+
+        let easier = compact_to_target(retarget(8000));
+        assert!(easier > start);
+        assert_eq!(retarget(8000), retarget(4000));
+
+        assert_eq!(retarget(1000), 0x1f00ffff);
+    }
+
+    #[test]
+    fn test_block_work_is_exact() {
+        let work = |value: u128| {
+            let mut work = [0u8; 32];
+            work[16..].copy_from_slice(&value.to_be_bytes());
+            work
+        };
+        // Bitcoin's genesis block, at 0x1d00ffff, counts 0x100010001 hashes
+        assert_eq!(block_work(0x1d00ffff), work(0x1_0001_0001));
+        assert_eq!(block_work(0x2000ffff), work(0x100));
+        assert!(block_work(0x1b0404cb) > block_work(0x1d00ffff));
+        assert_eq!(add_work(&work(5), &work(u64::MAX as u128)), work(u64::MAX as u128 + 5));
+        assert_eq!(add_work(&[0xff; 32], &work(1)), [0xff; 32]);
+    }
+
+    #[test]
+    fn test_retarget_never_exceeds_pow_limit() {
+        assert_eq!(retarget(POW_LIMIT_BITS, 4000, 1000, POW_LIMIT_BITS), POW_LIMIT_BITS);
+        assert_eq!(retarget(0x1c7fffff, 4000, 1000, POW_LIMIT_BITS), POW_LIMIT_BITS);
+    }
+}
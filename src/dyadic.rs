@@ -0,0 +1,163 @@
+//! Exact dyadic coordinates for siertrichain
+//!
+//! Subdivision only ever takes midpoints, and every `f64` is itself a dyadic
+//! fraction, so a triangle's vertices can be tracked exactly as
+//! `mantissa / 2^exponent`. `f64` midpoints round once the vertices need more
+//! than 53 bits, and after enough subdivisions neighbouring vertices collapse
+//! together; dyadic midpoints stay distinct until the 127-bit mantissa runs out.
+//! Geometry uses this backend when built with the `exact-geometry` feature.
+
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::geometry::{Coord, Point};
+
+/// The exact value `mantissa / 2^exponent`, kept normalized (the mantissa is
+/// odd unless the exponent is zero) so equal values compare and hash equal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Dyadic {
+    mantissa: i128,
+    exponent: u32,
+}
+
+impl Dyadic {
+    pub const ZERO: Dyadic = Dyadic { mantissa: 0, exponent: 0 };
+
+    fn normalized(mut mantissa: i128, mut exponent: u32) -> Self {
+        if mantissa == 0 {
+            return Dyadic::ZERO;
+        }
+        let shift = mantissa.trailing_zeros().min(exponent);
+        mantissa >>= shift;
+        exponent -= shift;
+        Dyadic { mantissa, exponent }
+    }
+
+    /// The exact value of a finite `f64`, or `None` for NaN, infinities and
+    /// integers too large for the mantissa
+    pub fn from_f64(value: Coord) -> Option<Self> {
+        if !value.is_finite() {
+            return None;
+        }
+        let bits = value.to_bits();
+        let negative = bits >> 63 == 1;
+        let biased = ((bits >> 52) & 0x7ff) as i32;
+        let fraction = (bits & ((1 << 52) - 1)) as i128;
+        // value = significand * 2^power
+        let (significand, power) = if biased == 0 {
+            (fraction, -1074)
+        } else {
+            (fraction | (1 << 52), biased - 1075)
+        };
+        let significand = if negative { -significand } else { significand };
+
+        if power >= 0 {
+            let mantissa = significand.checked_mul(1i128.checked_shl(power as u32)?)?;
+            Some(Dyadic::normalized(mantissa, 0))
+        } else {
+            Some(Dyadic::normalized(significand, power.unsigned_abs()))
+        }
+    }
+
+    /// The nearest `f64`
+    pub fn to_f64(&self) -> Coord {
+        let mut value = self.mantissa as Coord;
+        let mut exponent = self.exponent;
+        while exponent > 0 {
+            let step = exponent.min(1000);
+            value /= 2f64.powi(step as i32);
+            exponent -= step;
+        }
+        value
+    }
+
+    /// `(self + other) / 2`, or `None` if it needs more than 127 bits
+    pub fn midpoint(&self, other: &Dyadic) -> Option<Dyadic> {
+        let exponent = self.exponent.max(other.exponent);
+        let align = |d: &Dyadic| d.mantissa.checked_mul(1i128.checked_shl(exponent - d.exponent)?);
+        let sum = align(self)?.checked_add(align(other)?)?;
+        Some(Dyadic::normalized(sum, exponent.checked_add(1)?))
+    }
+}
+
+/// Canonical text form, `mantissa/2^exponent`, used when hashing
+impl fmt::Display for Dyadic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/2^{}", self.mantissa, self.exponent)
+    }
+}
+
+/// A point with exact dyadic coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ExactPoint {
+    pub x: Dyadic,
+    pub y: Dyadic,
+}
+
+impl ExactPoint {
+    /// The exact value of a point's coordinates, `None` if they aren't finite
+    pub fn from_point(point: &Point) -> Option<Self> {
+        Some(ExactPoint { x: Dyadic::from_f64(point.x)?, y: Dyadic::from_f64(point.y)? })
+    }
+
+    /// The nearest `f64` point, for area checks and rendering
+    pub fn to_point(&self) -> Point {
+        Point::new(self.x.to_f64(), self.y.to_f64())
+    }
+
+    pub fn midpoint(&self, other: &ExactPoint) -> Option<ExactPoint> {
+        Some(ExactPoint { x: self.x.midpoint(&other.x)?, y: self.y.midpoint(&other.y)? })
+    }
+}
+
+impl fmt::Display for ExactPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{}", self.x, self.y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_f64_round_trip() {
+        for value in [0.0, -0.0, 1.0, -2.5, 0.8660254037844386, 1e-300, 5e-324, 1e10] {
+            let exact = Dyadic::from_f64(value).unwrap();
+            assert_eq!(exact.to_f64(), value);
+        }
+        assert_eq!(Dyadic::from_f64(0.0), Dyadic::from_f64(-0.0));
+        assert_eq!(Dyadic::from_f64(3.0).unwrap().to_string(), "3/2^0");
+        assert_eq!(Dyadic::from_f64(0.75).unwrap().to_string(), "3/2^2");
+        assert!(Dyadic::from_f64(f64::NAN).is_none());
+        assert!(Dyadic::from_f64(f64::INFINITY).is_none());
+        assert!(Dyadic::from_f64(1e300).is_none());
+    }
+
+    #[test]
+    fn test_midpoints_stay_exact() {
+        let one = Dyadic::from_f64(1.0).unwrap();
+        let irrational = Dyadic::from_f64(0.8660254037844386).unwrap();
+
+        // Walk towards `one` far past where f64 midpoints collapse onto it
+        let (mut exact, mut float) = (irrational, 0.8660254037844386f64);
+        for _ in 0..60 {
+            exact = exact.midpoint(&one).unwrap();
+            float = (float + 1.0) / 2.0;
+        }
+        assert_eq!(float, 1.0);
+        assert_ne!(exact, one);
+        assert_eq!(exact.midpoint(&exact), Some(exact));
+
+        // Midpoints are symmetric and exact for short mantissas
+        let a = Dyadic::from_f64(1.5).unwrap();
+        let b = Dyadic::from_f64(-0.25).unwrap();
+        assert_eq!(a.midpoint(&b), b.midpoint(&a));
+        assert_eq!(a.midpoint(&b).unwrap().to_f64(), 0.625);
+    }
+
+    #[test]
+    fn test_midpoint_overflow_is_reported() {
+        let big = Dyadic::normalized(i128::MAX, 0);
+        assert!(big.midpoint(&big).is_none());
+    }
+}
@@ -1,6 +1,7 @@
 //! Error types for siertrichain
 
 use std::fmt;
+use crate::blockchain::Sha256Hash;
 
 #[derive(Debug, Clone)]
 pub enum ChainError {
@@ -14,8 +15,46 @@ pub enum ChainError {
     CryptoError(String),
     WalletError(String),
     OrphanBlock,
+    HeaderNotFound(String),
+    PrunedData(String),
+    KnownInvalidBlock(String),
+    CodecError(String),
     ApiError(String),
     AuthenticationError(String),
+    UnsupportedTxVersion(u32),
+    /// A triangle a transaction spends is not in the UTXO set
+    UtxoMissing { hash: Sha256Hash },
+    /// The transaction is already in the mempool
+    DuplicateTransaction { hash: Sha256Hash },
+    /// A replacement doesn't pay enough to outbid the transaction it conflicts with
+    InsufficientFee { required: u64, provided: u64 },
+    /// The nonce is not above the sender's last confirmed nonce
+    StaleNonce { address: String, nonce: u64, last: u64 },
+    /// The block's timestamp is `drift` seconds past the allowed clock drift
+    TimestampTooFarInFuture { drift: i64 },
+    /// The block's timestamp is not after the median of the blocks before it
+    TimestampTooOld { timestamp: i64, median_time_past: i64 },
+    /// The coinbase claims more than the block reward plus fees
+    CoinbaseRewardTooHigh { claimed: u64, allowed: u64 },
+    /// The block's difficulty bits are not the ones its chain calls for
+    UnexpectedDifficulty { expected: u32, found: u32 },
+    /// A map tile address is out of range
+    InvalidTile(String),
+    /// A newly minted triangle overlaps one that already exists
+    TriangleOverlap { triangle: Sha256Hash, existing: Sha256Hash },
+    /// Mining was stopped before a valid nonce was found
+    MiningCancelled,
+    /// A remote miner's share was rejected
+    InvalidShare(String),
+    /// A thread count or duty cycle out of range
+    InvalidMiningConfig(String),
+    /// A peer's handshake shows it is on another network or speaks an
+    /// unsupported protocol version
+    IncompatiblePeer(String),
+    /// A peer's frame fails its checksum or doesn't decode as the command it names
+    MalformedMessage(String),
+    /// A frame longer than the protocol allows
+    OversizedMessage { size: usize, max: usize },
 }
 
 impl fmt::Display for ChainError {
@@ -29,12 +68,114 @@ impl fmt::Display for ChainError {
             ChainError::CryptoError(msg) => write!(f, "Cryptographic error: {}", msg),
             ChainError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
             ChainError::NetworkError(msg) => write!(f, "Network error: {}", msg),
+            ChainError::IncompatiblePeer(msg) => write!(f, "Incompatible peer: {}", msg),
+            ChainError::MalformedMessage(msg) => write!(f, "Malformed message: {}", msg),
+            ChainError::OversizedMessage { size, max } => {
+                write!(f, "Message of {} bytes exceeds the {} byte limit", size, max)
+            }
             ChainError::WalletError(msg) => write!(f, "Wallet error: {}", msg),
             ChainError::OrphanBlock => write!(f, "Orphan block"),
+            ChainError::HeaderNotFound(msg) => write!(f, "Header not found: {}", msg),
+            ChainError::PrunedData(msg) => write!(f, "Pruned block data: {}", msg),
+            ChainError::KnownInvalidBlock(msg) => write!(f, "Block previously marked invalid: {}", msg),
+            ChainError::CodecError(msg) => write!(f, "Encoding error: {}", msg),
             ChainError::ApiError(msg) => write!(f, "API error: {}", msg),
             ChainError::AuthenticationError(msg) => write!(f, "Authentication error: {}", msg),
+            ChainError::UnsupportedTxVersion(version) => write!(f, "Unsupported transaction version {}", version),
+            ChainError::UtxoMissing { hash } => write!(f, "Triangle {} is not in the UTXO set", hex::encode(hash)),
+            ChainError::DuplicateTransaction { hash } => write!(f, "Transaction {} is already in the mempool", hex::encode(hash)),
+            ChainError::InsufficientFee { required, provided } => {
+                write!(f, "Insufficient fee: {} provided, at least {} required", provided, required)
+            }
+            ChainError::StaleNonce { address, nonce, last } => {
+                write!(f, "Nonce {} for {} is not above its last used nonce {}", nonce, address, last)
+            }
+            ChainError::TimestampTooFarInFuture { drift } => {
+                write!(f, "Block timestamp is {} seconds too far in the future", drift)
+            }
+            ChainError::TimestampTooOld { timestamp, median_time_past } => {
+                write!(f, "Block timestamp {} must be greater than median time past {}", timestamp, median_time_past)
+            }
+            ChainError::CoinbaseRewardTooHigh { claimed, allowed } => {
+                write!(f, "Coinbase reward {} exceeds maximum allowed {}", claimed, allowed)
+            }
+            ChainError::UnexpectedDifficulty { expected, found } => {
+                write!(f, "Block bits {:#010x} do not match the expected {:#010x}", found, expected)
+            }
+            ChainError::InvalidTile(msg) => write!(f, "Invalid tile: {}", msg),
+            ChainError::TriangleOverlap { triangle, existing } => {
+                write!(f, "Triangle {} overlaps existing triangle {}", hex::encode(triangle), hex::encode(existing))
+            }
+            ChainError::MiningCancelled => write!(f, "Mining cancelled"),
+            ChainError::InvalidShare(msg) => write!(f, "Invalid share: {}", msg),
+            ChainError::InvalidMiningConfig(msg) => write!(f, "Invalid mining config: {}", msg),
+        }
+    }
+}
+
+impl ChainError {
+    /// Stable machine-readable name of the error, e.g. `utxo-missing`
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChainError::InvalidBlockLinkage => "invalid-block-linkage",
+            ChainError::NetworkError(_) => "network-error",
+            ChainError::DatabaseError(_) => "database-error",
+            ChainError::InvalidProofOfWork => "invalid-proof-of-work",
+            ChainError::InvalidMerkleRoot => "invalid-merkle-root",
+            ChainError::InvalidTransaction(_) => "invalid-transaction",
+            ChainError::TriangleNotFound(_) => "triangle-not-found",
+            ChainError::CryptoError(_) => "crypto-error",
+            ChainError::WalletError(_) => "wallet-error",
+            ChainError::OrphanBlock => "orphan-block",
+            ChainError::HeaderNotFound(_) => "header-not-found",
+            ChainError::PrunedData(_) => "pruned-data",
+            ChainError::KnownInvalidBlock(_) => "known-invalid-block",
+            ChainError::CodecError(_) => "codec-error",
+            ChainError::ApiError(_) => "api-error",
+            ChainError::AuthenticationError(_) => "authentication-error",
+            ChainError::UnsupportedTxVersion(_) => "unsupported-version",
+            ChainError::UtxoMissing { .. } => "utxo-missing",
+            ChainError::DuplicateTransaction { .. } => "duplicate-transaction",
+            ChainError::InsufficientFee { .. } => "insufficient-fee",
+            ChainError::StaleNonce { .. } => "stale-nonce",
+            ChainError::TimestampTooFarInFuture { .. } => "timestamp-too-far-in-future",
+            ChainError::TimestampTooOld { .. } => "timestamp-too-old",
+            ChainError::CoinbaseRewardTooHigh { .. } => "coinbase-reward-too-high",
+            ChainError::UnexpectedDifficulty { .. } => "unexpected-difficulty",
+            ChainError::InvalidTile(_) => "invalid-tile",
+            ChainError::TriangleOverlap { .. } => "triangle-overlap",
+            ChainError::MiningCancelled => "mining-cancelled",
+            ChainError::InvalidShare(_) => "invalid-share",
+            ChainError::InvalidMiningConfig(_) => "invalid-mining-config",
+            ChainError::IncompatiblePeer(_) => "incompatible-peer",
+            ChainError::MalformedMessage(_) => "malformed-message",
+            ChainError::OversizedMessage { .. } => "oversized-message",
         }
     }
 }
 
 impl std::error::Error for ChainError {}
+
+impl From<rusqlite::Error> for ChainError {
+    fn from(e: rusqlite::Error) -> Self {
+        ChainError::DatabaseError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ChainError {
+    fn from(e: serde_json::Error) -> Self {
+        ChainError::CodecError(e.to_string())
+    }
+}
+
+impl From<bincode::Error> for ChainError {
+    fn from(e: bincode::Error) -> Self {
+        ChainError::CodecError(e.to_string())
+    }
+}
+
+impl From<secp256k1::Error> for ChainError {
+    fn from(e: secp256k1::Error) -> Self {
+        ChainError::CryptoError(e.to_string())
+    }
+}
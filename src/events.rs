@@ -0,0 +1,61 @@
+//! Chain event notifications for siertrichain
+//!
+//! The blockchain and mempool publish events on a broadcast channel so that the
+//! miner, API, and wallet can react to new blocks and transactions immediately
+//! instead of polling or reloading the database.
+
+use crate::blockchain::{BlockHeight, Sha256Hash};
+use tokio::sync::broadcast;
+
+/// Number of events buffered per subscriber before the slowest one starts lagging
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Something that happened to the chain or the mempool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainEvent {
+    /// A block became part of the main chain
+    BlockConnected { hash: Sha256Hash, height: BlockHeight },
+    /// A block was removed from the main chain during a reorganization
+    BlockDisconnected { hash: Sha256Hash, height: BlockHeight },
+    /// A block was accepted onto a branch other than the main chain
+    ForkBlockAccepted { hash: Sha256Hash, height: BlockHeight },
+    /// A transaction was accepted into the mempool
+    TxAccepted(Sha256Hash),
+    /// A transaction was dropped from the mempool without being mined
+    TxEvicted(Sha256Hash),
+    /// The main chain switched to a different branch
+    ReorgCompleted {
+        old_tip: Sha256Hash,
+        new_tip: Sha256Hash,
+        fork_height: BlockHeight,
+    },
+}
+
+/// Broadcast channel shared by a blockchain and its mempool
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ChainEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        EventBus { sender }
+    }
+
+    /// Subscribe to all events published after this call
+    pub fn subscribe(&self) -> broadcast::Receiver<ChainEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publish an event. Having no subscribers is not an error.
+    pub fn publish(&self, event: ChainEvent) {
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,178 @@
+//! GeoJSON import and export for siertrichain
+//!
+//! Each triangle becomes a GeoJSON `Feature` whose geometry is a closed
+//! polygon ring and whose properties carry its owner, area and lineage, so
+//! the UTXO set can be dropped straight into Leaflet, Mapbox or a GIS tool.
+//! Chain coordinates are written as-is; they are not longitude/latitude.
+
+use serde_json::{json, Value};
+use crate::blockchain::{parse_hash, TriangleState};
+use crate::error::ChainError;
+use crate::geometry::{Point, Triangle, TriangleId};
+
+fn invalid(msg: impl Into<String>) -> ChainError {
+    ChainError::CodecError(format!("Invalid GeoJSON: {}", msg.into()))
+}
+
+impl Triangle {
+    /// A GeoJSON `Feature` for this triangle, with its id and owner, area,
+    /// depth and lineage as properties
+    pub fn to_geojson(&self) -> Value {
+        let ring: Vec<Value> = [self.a, self.b, self.c, self.a]
+            .iter()
+            .map(|p| json!([p.x, p.y]))
+            .collect();
+        #[allow(unused_mut)]
+        let mut feature = json!({
+            "type": "Feature",
+            "id": self.hash_str(),
+            "geometry": { "type": "Polygon", "coordinates": [ring] },
+            "properties": {
+                "owner": self.owner,
+                "area": self.area(),
+                "depth": self.depth,
+                "lineage": self.lineage,
+                "parent_hash": self.parent_hash.map(hex::encode),
+                "root": self.root.map(hex::encode),
+            },
+        });
+        // The rounded ring alone would give a deep triangle a different id
+        #[cfg(feature = "exact-geometry")]
+        if let Some(exact) = &self.exact {
+            feature["properties"]["exact"] = json!(exact);
+        }
+        feature
+    }
+
+    /// Parse a `Feature` written by `to_geojson`. Only the polygon and the
+    /// owner are required; lineage properties default to a root triangle.
+    pub fn from_geojson(feature: &Value) -> Result<Triangle, ChainError> {
+        if feature["type"] != "Feature" {
+            return Err(invalid("expected a Feature"));
+        }
+        let geometry = &feature["geometry"];
+        if geometry["type"] != "Polygon" {
+            return Err(invalid("expected Polygon geometry"));
+        }
+        let ring = geometry["coordinates"][0].as_array()
+            .ok_or_else(|| invalid("polygon has no exterior ring"))?;
+        let points = ring.iter()
+            .map(|position| match (position[0].as_f64(), position[1].as_f64()) {
+                (Some(x), Some(y)) => Ok(Point::new(x, y)),
+                _ => Err(invalid("position is not a pair of numbers")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // Three vertices, optionally closed by repeating the first
+        let vertices = match points.as_slice() {
+            [a, b, c] => [*a, *b, *c],
+            [a, b, c, closing] if closing == a => [*a, *b, *c],
+            _ => return Err(invalid(format!("ring has {} positions, expected a triangle", points.len()))),
+        };
+
+        let properties = feature["properties"].as_object().cloned().unwrap_or_default();
+        let owner = properties.get("owner").and_then(Value::as_str)
+            .ok_or_else(|| invalid("feature has no owner"))?;
+        let hash_property = |name: &str| -> Result<Option<TriangleId>, ChainError> {
+            properties.get(name).and_then(Value::as_str).map(parse_hash).transpose()
+        };
+
+        let [a, b, c] = vertices;
+        let mut triangle = Triangle::new(a, b, c, hash_property("parent_hash")?, owner.to_string());
+        triangle.depth = properties.get("depth").and_then(Value::as_u64).unwrap_or(0) as u32;
+        triangle.lineage = properties.get("lineage").and_then(Value::as_u64).unwrap_or(0);
+        triangle.root = hash_property("root")?;
+        #[cfg(feature = "exact-geometry")]
+        if let Some(exact) = properties.get("exact") {
+            triangle.exact = serde_json::from_value(exact.clone())?;
+        }
+        if !triangle.is_valid() {
+            return Err(invalid("triangle is degenerate or out of bounds"));
+        }
+        Ok(triangle)
+    }
+}
+
+impl TriangleState {
+    /// A GeoJSON `FeatureCollection` of the unspent triangles, in id order
+    pub fn to_geojson(&self) -> Value {
+        let mut ids: Vec<_> = self.utxo_set.keys().collect();
+        ids.sort();
+        let features: Vec<Value> = ids.into_iter().map(|id| self.utxo_set[id].to_geojson()).collect();
+        json!({ "type": "FeatureCollection", "features": features })
+    }
+
+    /// A state holding the triangles of a `FeatureCollection`, keyed by their
+    /// recomputed ids. Contracts, leases and nonces aren't part of GeoJSON, so
+    /// they start empty.
+    pub fn from_geojson(collection: &Value) -> Result<TriangleState, ChainError> {
+        if collection["type"] != "FeatureCollection" {
+            return Err(invalid("expected a FeatureCollection"));
+        }
+        let features = collection["features"].as_array()
+            .ok_or_else(|| invalid("collection has no features"))?;
+
+        let mut state = TriangleState::new();
+        for feature in features {
+            let triangle = Triangle::from_geojson(feature)?;
+            state.insert_triangle(triangle.hash(), triangle);
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis_triangle;
+
+    #[test]
+    fn test_triangle_round_trip() {
+        let grandchild = genesis_triangle().subdivide()[2].subdivide()[1].clone();
+        let feature = grandchild.to_geojson();
+
+        assert_eq!(feature["id"], grandchild.hash_str());
+        assert_eq!(feature["geometry"]["coordinates"][0].as_array().unwrap().len(), 4);
+        assert_eq!(feature["properties"]["owner"], "genesis_owner");
+        assert_eq!(feature["properties"]["depth"], 2);
+
+        let parsed = Triangle::from_geojson(&feature).unwrap();
+        assert_eq!(parsed, grandchild);
+        assert_eq!(parsed.path(), vec![2, 1]);
+
+        // Text survives too
+        let text = serde_json::to_string(&feature).unwrap();
+        assert_eq!(Triangle::from_geojson(&serde_json::from_str(&text).unwrap()).unwrap(), grandchild);
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut state = TriangleState::new();
+        for child in genesis_triangle().subdivide() {
+            state.utxo_set.insert(child.hash(), child);
+        }
+
+        let collection = state.to_geojson();
+        assert_eq!(collection["features"].as_array().unwrap().len(), 3);
+        assert_eq!(TriangleState::from_geojson(&collection).unwrap().utxo_set, state.utxo_set);
+    }
+
+    #[test]
+    fn test_rejects_malformed_features() {
+        let mut feature = genesis_triangle().to_geojson();
+        feature["geometry"]["coordinates"][0].as_array_mut().unwrap().pop();
+        feature["geometry"]["coordinates"][0].as_array_mut().unwrap().pop();
+        assert!(matches!(Triangle::from_geojson(&feature), Err(ChainError::CodecError(_))));
+
+        let mut feature = genesis_triangle().to_geojson();
+        feature["properties"].as_object_mut().unwrap().remove("owner");
+        assert!(Triangle::from_geojson(&feature).is_err());
+
+        let collinear = json!({
+            "type": "Feature",
+            "geometry": { "type": "Polygon", "coordinates": [[[0.0, 0.0], [1.0, 1.0], [2.0, 2.0]]] },
+            "properties": { "owner": "alice" },
+        });
+        assert!(Triangle::from_geojson(&collinear).is_err());
+        assert!(TriangleState::from_geojson(&collinear).is_err());
+    }
+}
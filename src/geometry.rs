@@ -4,11 +4,24 @@
 use serde::{Serialize, Deserialize};
 use sha2::{Digest, Sha256};
 use crate::blockchain::Sha256Hash;
+#[cfg(feature = "exact-geometry")]
+use crate::dyadic::ExactPoint;
+
+/// Identity of a triangle: the SHA-256 of its vertices. The UTXO set, transaction
+/// inputs and `parent_hash` all use these raw bytes; hex is only for display.
+pub type TriangleId = Sha256Hash;
 
 /// Coordinate type for high-precision geometric calculations.
 pub type Coord = f64;
 /// Tolerance for floating point comparisons to check for degeneracy/equality.
 const GEOMETRIC_TOLERANCE: Coord = 1e-9; 
+/// Smallest area a triangle created by subdivision may have (consensus rule).
+/// A genesis-sized triangle can be subdivided about a dozen times before reaching
+/// it; without a floor, repeated subdivision would fill the UTXO set with dust.
+pub const MIN_TRIANGLE_AREA: Coord = 1e-7;
+/// Deepest a triangle can sit below its root: a lineage stores two bits per
+/// level in a `u64`. The area floor stops subdivision long before this.
+pub const MAX_DEPTH: u32 = 32;
 
 // ----------------------------------------------------------------------------
 // 1.4 Coordinate System: Point
@@ -58,6 +71,16 @@ impl Point {
         hex::encode(self.hash())
     }
 
+    /// Position of the point on a Z-order (Morton) curve: its x and y
+    /// coordinates as 32.32 fixed point, bits interleaved. Nearby points
+    /// mostly get nearby keys, so ranges of keys cover patches of the plane.
+    /// Coordinates outside ±2^31 are clamped.
+    pub fn zorder_key(&self) -> u128 {
+        // Fixed point with the sign bit flipped, so keys order like coordinates
+        let fixed = |c: Coord| ((c * ZORDER_SCALE) as i64 as u64) ^ (1 << 63);
+        spread_bits(fixed(self.x)) | (spread_bits(fixed(self.y)) << 1)
+    }
+
     /// Checks for equality with another point within a small tolerance
     /// to handle floating-point inaccuracies.
     pub fn equals(&self, other: &Point) -> bool {
@@ -66,6 +89,22 @@ impl Point {
     }
 }
 
+/// A circle, as returned by `Triangle::incircle` and `Triangle::circumcircle`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Circle {
+    pub center: Point,
+    pub radius: Coord,
+}
+
+/// How two triangles with disjoint interiors touch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Contact {
+    /// They share part of an edge
+    Edge,
+    /// They meet only at a point, like the children of one subdivision
+    Vertex,
+}
+
 // ----------------------------------------------------------------------------
 // 1.3 Triangle Data Structure & Core Methods
 // ----------------------------------------------------------------------------
@@ -76,17 +115,58 @@ pub struct Triangle {
     pub a: Point,
     pub b: Point,
     pub c: Point,
-    pub parent_hash: Option<Sha256Hash>,
+    pub parent_hash: Option<TriangleId>,
     pub owner: String,
+    /// Number of subdivisions between this triangle and its root
+    #[serde(default)]
+    pub depth: u32,
+    /// Child index (0-2) taken at each subdivision below the root, two bits per
+    /// level with the most recent level in the lowest bits
+    #[serde(default)]
+    pub lineage: u64,
+    /// The root (genesis or a coinbase reward) this triangle was subdivided
+    /// from; `None` for a root itself
+    #[serde(default)]
+    pub root: Option<TriangleId>,
+    /// Exact vertices of a subdivided triangle, which `a`, `b` and `c` round.
+    /// `None` for roots, whose `f64` vertices are already exact.
+    #[cfg(feature = "exact-geometry")]
+    #[serde(default)]
+    pub exact: Option<[ExactPoint; 3]>,
 }
 
 impl Triangle {
     /// Creates a new Triangle from three vertices.
-    pub fn new(a: Point, b: Point, c: Point, parent_hash: Option<Sha256Hash>, owner: String) -> Self {
-        Triangle { a, b, c, parent_hash, owner }
+    pub fn new(a: Point, b: Point, c: Point, parent_hash: Option<TriangleId>, owner: String) -> Self {
+        Triangle {
+            a, b, c, parent_hash, owner, depth: 0, lineage: 0, root: None,
+            #[cfg(feature = "exact-geometry")]
+            exact: None,
+        }
     }
 
-    /// Calculates the center point (centroid) of the triangle.
+    /// The exact vertices: the tracked ones after subdivision, otherwise the
+    /// exact values of the `f64` vertices
+    #[cfg(feature = "exact-geometry")]
+    pub fn exact_vertices(&self) -> Option<[ExactPoint; 3]> {
+        self.exact.or_else(|| {
+            Some([
+                ExactPoint::from_point(&self.a)?,
+                ExactPoint::from_point(&self.b)?,
+                ExactPoint::from_point(&self.c)?,
+            ])
+        })
+    }
+
+    /// Whether both triangles have the same vertices in the same order: within
+    /// `GEOMETRIC_TOLERANCE`, or exactly under `exact-geometry`
+    pub fn same_vertices(&self, other: &Triangle) -> bool {
+        #[cfg(feature = "exact-geometry")]
+        if let (Some(ours), Some(theirs)) = (self.exact_vertices(), other.exact_vertices()) {
+            return ours == theirs;
+        }
+        self.a.equals(&other.a) && self.b.equals(&other.b) && self.c.equals(&other.c)
+    }
 
     /// Calculates the area of the triangle using the Shoelace formula.
     pub fn area(&self) -> Coord {
@@ -97,9 +177,18 @@ impl Triangle {
         val / 2.0
     }
 
-    /// Calculates the unique cryptographic hash of the triangle.
-    pub fn hash(&self) -> Sha256Hash {
-        let mut hashes = vec![self.a.hash_str(), self.b.hash_str(), self.c.hash_str()];
+    /// Calculates the unique cryptographic hash of the triangle, its `TriangleId`.
+    /// Under `exact-geometry` a subdivided triangle hashes its exact vertices,
+    /// since the rounded ones stop telling deep triangles apart.
+    pub fn hash(&self) -> TriangleId {
+        #[cfg(feature = "exact-geometry")]
+        if let Some(exact) = &self.exact {
+            let mut points = exact.map(|p| p.to_string());
+            points.sort();
+            return Sha256::digest(points.join(";").as_bytes()).into();
+        }
+
+        let mut hashes = [self.a.hash_str(), self.b.hash_str(), self.c.hash_str()];
         hashes.sort(); 
         
         let data = hashes.join("");
@@ -112,25 +201,94 @@ impl Triangle {
         hex::encode(self.hash())
     }
 
+    /// Z-order key of the triangle's centroid, for storing nearby triangles
+    /// near each other (see `Point::zorder_key`)
+    pub fn zorder_key(&self) -> u128 {
+        self.centroid().zorder_key()
+    }
+
+    // ------------------------------------------------------------------------
+    // Metrics
+    // ------------------------------------------------------------------------
+
+    /// The average of the three vertices, the triangle's center of mass
+    pub fn centroid(&self) -> Point {
+        Point::new(
+            (self.a.x + self.b.x + self.c.x) / 3.0,
+            (self.a.y + self.b.y + self.c.y) / 3.0,
+        )
+    }
+
+    /// Lengths of the edges AB, BC and CA
+    pub fn edge_lengths(&self) -> [Coord; 3] {
+        [(self.a, self.b), (self.b, self.c), (self.c, self.a)]
+            .map(|(p, q)| ((q.x - p.x).powi(2) + (q.y - p.y).powi(2)).sqrt())
+    }
+
+    pub fn perimeter(&self) -> Coord {
+        self.edge_lengths().iter().sum()
+    }
+
+    /// The largest circle inside the triangle. Its center weights each vertex
+    /// by the length of the opposite edge.
+    pub fn incircle(&self) -> Option<Circle> {
+        let [ab, bc, ca] = self.edge_lengths();
+        let perimeter = ab + bc + ca;
+        if self.area() <= GEOMETRIC_TOLERANCE || perimeter <= GEOMETRIC_TOLERANCE {
+            return None;
+        }
+        Some(Circle {
+            center: Point::new(
+                (bc * self.a.x + ca * self.b.x + ab * self.c.x) / perimeter,
+                (bc * self.a.y + ca * self.b.y + ab * self.c.y) / perimeter,
+            ),
+            radius: 2.0 * self.area() / perimeter,
+        })
+    }
+
+    /// The circle through all three vertices; `None` for a degenerate triangle
+    pub fn circumcircle(&self) -> Option<Circle> {
+        if self.area() <= GEOMETRIC_TOLERANCE {
+            return None;
+        }
+        let d = 2.0 * orient(&self.a, &self.b, &self.c);
+        let norm = |p: &Point| p.x * p.x + p.y * p.y;
+        let (a, b, c) = (&self.a, &self.b, &self.c);
+        let center = Point::new(
+            (norm(a) * (b.y - c.y) + norm(b) * (c.y - a.y) + norm(c) * (a.y - b.y)) / d,
+            (norm(a) * (c.x - b.x) + norm(b) * (a.x - c.x) + norm(c) * (b.x - a.x)) / d,
+        );
+        let radius = ((a.x - center.x).powi(2) + (a.y - center.y).powi(2)).sqrt();
+        Some(Circle { center, radius })
+    }
+
     // ------------------------------------------------------------------------
     // 1.6 Genesis Triangle Implementation
     // ------------------------------------------------------------------------
 
     /// Defines the canonical Genesis Triangle for the siertrichain.
+    #[deprecated(note = "use `ChainParams::genesis_triangle`; this used to build a different, √3-sided triangle than the chain's genesis")]
     pub fn genesis() -> Self {
+        crate::params::ChainParams::MAINNET.genesis_triangle()
+    }
+    
+    /// An upright equilateral triangle of the given area with its lower-left
+    /// vertex at `anchor`: the same shape as the genesis triangle, so it
+    /// subdivides into the same fractal.
+    pub fn equilateral(anchor: Point, area: Coord, owner: String) -> Self {
         const SQRT3: Coord = 1.7320508075688772;
-        const HALF_SQRT3: Coord = 0.8660254037844386;
-        const ONE_POINT_FIVE: Coord = 1.5;
 
+        let side = (4.0 * area / SQRT3).sqrt();
+        let height = side * SQRT3 / 2.0;
         Triangle::new(
-            Point::new(0.0, 0.0),
-            Point::new(SQRT3, 0.0),
-            Point::new(HALF_SQRT3, ONE_POINT_FIVE),
+            anchor,
+            Point::new(anchor.x + side, anchor.y),
+            Point::new(anchor.x + side / 2.0, anchor.y + height),
             None,
-            "genesis_owner".to_string(),
+            owner,
         )
     }
-    
+
     // ------------------------------------------------------------------------
     // 1.7 Subdivision Algorithm
     // ------------------------------------------------------------------------
@@ -151,8 +309,167 @@ impl Triangle {
 
         // Child 3 (mid_ca-mid_bc-C)
         let t3 = Triangle::new(mid_ca, mid_bc, self.c, parent_hash, self.owner.clone());
-        
-        [t1, t2, t3]
+
+        let mut children = [t1, t2, t3];
+        for (index, child) in children.iter_mut().enumerate() {
+            child.depth = self.depth + 1;
+            child.lineage = (self.lineage << 2) | index as u64;
+            child.root = Some(self.root_id());
+        }
+
+        // Take the midpoints exactly and round them for the f64 vertices. Past
+        // the dyadic mantissa's range children fall back to their f64 vertices.
+        #[cfg(feature = "exact-geometry")]
+        if let Some([a, b, c]) = self.exact_vertices() {
+            if let (Some(ab), Some(bc), Some(ca)) = (a.midpoint(&b), b.midpoint(&c), c.midpoint(&a)) {
+                for (child, exact) in children.iter_mut().zip([[a, ab, ca], [ab, b, bc], [ca, bc, c]]) {
+                    child.a = exact[0].to_point();
+                    child.b = exact[1].to_point();
+                    child.c = exact[2].to_point();
+                    child.exact = Some(exact);
+                }
+            }
+        }
+        children
+    }
+
+    /// Whether subdividing `depth` times leaves triangles of at least
+    /// `MIN_TRIANGLE_AREA`, within `MAX_DEPTH` of the root. Each subdivision
+    /// keeps children a quarter of the parent's area.
+    pub fn can_subdivide(&self, depth: u32) -> bool {
+        self.depth.saturating_add(depth) <= MAX_DEPTH
+            && self.area() * 0.25f64.powi(depth as i32) >= MIN_TRIANGLE_AREA
+    }
+
+    // ------------------------------------------------------------------------
+    // Lineage
+    // ------------------------------------------------------------------------
+
+    /// The root this triangle descends from, or its own hash if it is a root
+    pub fn root_id(&self) -> TriangleId {
+        self.root.unwrap_or_else(|| self.hash())
+    }
+
+    /// Child indices from the root down to this triangle
+    pub fn path(&self) -> Vec<u8> {
+        (0..self.depth.min(MAX_DEPTH))
+            .rev()
+            .map(|level| ((self.lineage >> (2 * level)) & 0b11) as u8)
+            .collect()
+    }
+
+    /// Which child of its parent this triangle is, `None` for a root
+    pub fn child_index(&self) -> Option<u8> {
+        (self.depth > 0).then_some((self.lineage & 0b11) as u8)
+    }
+
+    /// Whether `other` was subdivided (at any depth) out of this triangle,
+    /// decided from lineage alone without walking the chain
+    pub fn is_ancestor_of(&self, other: &Triangle) -> bool {
+        other.depth > self.depth
+            && other.root_id() == self.root_id()
+            && other.lineage >> (2 * (other.depth - self.depth)) == self.lineage
+    }
+
+    /// Whether this triangle carries the parent link, depth and lineage of
+    /// `parent`'s child number `index`
+    pub fn is_child_of(&self, parent: &Triangle, index: usize) -> bool {
+        self.parent_hash == Some(parent.hash())
+            && self.depth == parent.depth + 1
+            && self.lineage == (parent.lineage << 2) | index as u64
+            && self.root == Some(parent.root_id())
+    }
+
+    // ------------------------------------------------------------------------
+    // Spatial Predicates
+    // ------------------------------------------------------------------------
+
+    /// Whether `point` lies inside the triangle or on its boundary, within
+    /// `GEOMETRIC_TOLERANCE`
+    pub fn contains_point(&self, point: &Point) -> bool {
+        // Signed distance from each edge, positive on the inside whichever way
+        // the vertices wind
+        let winding = orient(&self.a, &self.b, &self.c).signum();
+        [(&self.a, &self.b), (&self.b, &self.c), (&self.c, &self.a)]
+            .iter()
+            .all(|(p, q)| {
+                let length = ((q.x - p.x).powi(2) + (q.y - p.y).powi(2)).sqrt();
+                length < GEOMETRIC_TOLERANCE
+                    || winding * orient(p, q, point) / length >= -GEOMETRIC_TOLERANCE
+            })
+    }
+
+    /// Whether `other` lies entirely inside this triangle (sharing edges allowed)
+    pub fn contains(&self, other: &Triangle) -> bool {
+        [other.a, other.b, other.c].iter().all(|p| self.contains_point(p))
+    }
+
+    /// Whether the interiors of the two triangles overlap. Triangles that only
+    /// touch along an edge or at a vertex, like siblings from one subdivision,
+    /// don't intersect.
+    pub fn intersects(&self, other: &Triangle) -> bool {
+        // Separating axis test: two convex shapes are disjoint exactly when
+        // their projections onto some edge normal don't overlap
+        let vertices = |t: &Triangle| [t.a, t.b, t.c];
+        let edges = |t: &Triangle| [(t.a, t.b), (t.b, t.c), (t.c, t.a)];
+        let project = |t: &Triangle, (nx, ny): (Coord, Coord)| {
+            vertices(t).iter().map(|p| p.x * nx + p.y * ny).fold(
+                (Coord::INFINITY, Coord::NEG_INFINITY),
+                |(min, max), v| (min.min(v), max.max(v)),
+            )
+        };
+
+        !edges(self).into_iter().chain(edges(other)).any(|(p, q)| {
+            let (dx, dy) = (q.x - p.x, q.y - p.y);
+            let length = (dx * dx + dy * dy).sqrt();
+            if length < GEOMETRIC_TOLERANCE {
+                return false;
+            }
+            let normal = (-dy / length, dx / length);
+            let (min1, max1) = project(self, normal);
+            let (min2, max2) = project(other, normal);
+            max1 <= min2 + GEOMETRIC_TOLERANCE || max2 <= min1 + GEOMETRIC_TOLERANCE
+        })
+    }
+
+    /// The other children `parent` subdivides into alongside this triangle
+    pub fn siblings(&self, parent: &Triangle) -> Vec<Triangle> {
+        parent.subdivide()
+            .into_iter()
+            .filter(|child| !child.same_vertices(self))
+            .collect()
+    }
+
+    /// Whether the triangles share a stretch of edge of positive length
+    pub fn shares_edge(&self, other: &Triangle) -> bool {
+        let edges = |t: &Triangle| [(t.a, t.b), (t.b, t.c), (t.c, t.a)];
+        edges(self).iter().any(|(p, q)| {
+            let (dx, dy) = (q.x - p.x, q.y - p.y);
+            let length = (dx * dx + dy * dy).sqrt();
+            if length < GEOMETRIC_TOLERANCE {
+                return false;
+            }
+            edges(other).iter().any(|(r, s)| {
+                // Both ends on the line through p and q, and overlapping along it
+                let on_line = |point: &Point| (orient(p, q, point) / length).abs() < GEOMETRIC_TOLERANCE;
+                let along = |point: &Point| ((point.x - p.x) * dx + (point.y - p.y) * dy) / length;
+                let (start, end) = (along(r).min(along(s)), along(r).max(along(s)));
+                on_line(r) && on_line(s) && end.min(length) - start.max(0.0) > GEOMETRIC_TOLERANCE
+            })
+        })
+    }
+
+    /// How this triangle touches `other`, or `None` if they are apart or overlap
+    pub fn contact(&self, other: &Triangle) -> Option<Contact> {
+        if self.intersects(other) {
+            return None;
+        }
+        if self.shares_edge(other) {
+            return Some(Contact::Edge);
+        }
+        let touches = [other.a, other.b, other.c].iter().any(|p| self.contains_point(p))
+            || [self.a, self.b, self.c].iter().any(|p| other.contains_point(p));
+        touches.then_some(Contact::Vertex)
     }
 
     // ------------------------------------------------------------------------
@@ -174,6 +491,25 @@ impl Triangle {
     }
 }
 
+/// 2^32: Z-order keys keep 32 fractional bits per coordinate
+const ZORDER_SCALE: Coord = 4294967296.0;
+
+/// Spread the bits of `v` over the even bit positions of a `u128`
+fn spread_bits(v: u64) -> u128 {
+    let mut v = v as u128;
+    v = (v | (v << 32)) & 0x0000_0000_FFFF_FFFF_0000_0000_FFFF_FFFF;
+    v = (v | (v << 16)) & 0x0000_FFFF_0000_FFFF_0000_FFFF_0000_FFFF;
+    v = (v | (v << 8)) & 0x00FF_00FF_00FF_00FF_00FF_00FF_00FF_00FF;
+    v = (v | (v << 4)) & 0x0F0F_0F0F_0F0F_0F0F_0F0F_0F0F_0F0F_0F0F;
+    v = (v | (v << 2)) & 0x3333_3333_3333_3333_3333_3333_3333_3333;
+    (v | (v << 1)) & 0x5555_5555_5555_5555_5555_5555_5555_5555
+}
+
+/// Twice the signed area of `a`, `b`, `c`: positive when they wind
+/// counter-clockwise, negative clockwise, zero when collinear
+fn orient(a: &Point, b: &Point, c: &Point) -> Coord {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
 
 // ----------------------------------------------------------------------------
 // Testing
@@ -182,6 +518,11 @@ impl Triangle {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::params::ChainParams;
+
+    fn genesis() -> Triangle {
+        ChainParams::MAINNET.genesis_triangle()
+    }
 
     fn setup_test_triangle() -> Triangle {
         Triangle::new(
@@ -221,9 +562,14 @@ mod tests {
 
     #[test]
     fn test_genesis_triangle_is_canonical() {
-        let g1 = Triangle::genesis();
-        let expected_area = 1.299038105676658;
-        assert!((g1.area() - expected_area).abs() < 1e-15, "Genesis triangle area is incorrect.");
+        let g1 = genesis();
+        let expected_area = 0.433012701892;
+        assert!((g1.area() - expected_area).abs() < 1e-12, "Genesis triangle area is incorrect.");
+        assert_eq!(g1, crate::blockchain::genesis_triangle());
+
+        #[allow(deprecated)]
+        let legacy = Triangle::genesis();
+        assert_eq!(legacy.hash(), ChainParams::MAINNET.genesis_id());
     }
 
     #[test]
@@ -241,7 +587,7 @@ mod tests {
         let t = setup_test_triangle();
         assert!(t.is_valid(), "A normal triangle should be valid.");
         
-        let g = Triangle::genesis();
+        let g = genesis();
         assert!(g.is_valid(), "The genesis triangle must be valid.");
     }
 
@@ -257,4 +603,202 @@ mod tests {
         );
         assert!(!t_degenerate.is_valid(), "A degenerate (collinear) triangle should be invalid.");
     }
+
+    #[test]
+    fn test_subdivision_area_floor() {
+        let genesis = genesis();
+        assert!(genesis.can_subdivide(1));
+        assert!(!genesis.can_subdivide(20));
+
+        let tiny = Triangle::new(
+            Point::new(0.0, 0.0),
+            Point::new(0.0005, 0.0),
+            Point::new(0.0, 0.0005),
+            None,
+            "test_owner".to_string(),
+        );
+        assert!(tiny.area() >= MIN_TRIANGLE_AREA);
+        assert!(!tiny.can_subdivide(1));
+    }
+
+    #[test]
+    fn test_equilateral_matches_requested_area() {
+        let t = Triangle::equilateral(Point::new(64.0, 128.0), 1000.0, "owner".to_string());
+        assert!((t.area() - 1000.0).abs() < 1e-9);
+        assert_eq!(t.a, Point::new(64.0, 128.0));
+
+        // Same shape as genesis: subdividing keeps three quarters of the area
+        let children = t.subdivide();
+        let total: Coord = children.iter().map(|c| c.area()).sum();
+        assert!((total - 750.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_subdivision_tracks_depth_and_lineage() {
+        let root = genesis();
+        assert_eq!(root.depth, 0);
+        assert_eq!(root.root_id(), root.hash());
+        assert_eq!(root.child_index(), None);
+        assert!(root.path().is_empty());
+
+        let children = root.subdivide();
+        let grandchild = children[2].subdivide()[1].clone();
+        assert_eq!(grandchild.depth, 2);
+        assert_eq!(grandchild.path(), vec![2, 1]);
+        assert_eq!(grandchild.child_index(), Some(1));
+        assert_eq!(grandchild.root_id(), root.hash());
+
+        assert!(root.is_ancestor_of(&grandchild));
+        assert!(children[2].is_ancestor_of(&grandchild));
+        assert!(!children[1].is_ancestor_of(&grandchild));
+        assert!(!grandchild.is_ancestor_of(&root));
+        assert!(children[0].is_child_of(&root, 0));
+        assert!(!children[0].is_child_of(&root, 1));
+
+        // Lineage doesn't affect identity
+        let mut relabeled = grandchild.clone();
+        relabeled.depth = 0;
+        assert_eq!(relabeled.hash(), grandchild.hash());
+    }
+
+    #[cfg(feature = "exact-geometry")]
+    #[test]
+    fn test_exact_subdivision_stays_distinct() {
+        // Far deeper than consensus allows, to where f64 midpoints collapse
+        let mut triangle = genesis();
+        for _ in 0..60 {
+            triangle = triangle.subdivide()[1].clone();
+        }
+        let [a, b, c] = triangle.exact.unwrap();
+        assert!(a != b && b != c && c != a);
+        assert!(triangle.a.equals(&triangle.b));
+
+        assert!(triangle.same_vertices(&triangle.clone()));
+        let parent_children = genesis().subdivide();
+        assert!(!parent_children[0].same_vertices(&parent_children[1]));
+        assert_ne!(parent_children[0].hash(), parent_children[1].hash());
+    }
+
+    #[test]
+    fn test_contains_point() {
+        let t = setup_test_triangle();
+        assert!(t.contains_point(&Point::new(2.0, 2.0)));
+        assert!(t.contains_point(&Point::new(5.0, 5.0)), "points on an edge are inside");
+        assert!(t.contains_point(&Point::new(0.0, 0.0)), "vertices are inside");
+        assert!(!t.contains_point(&Point::new(6.0, 6.0)));
+        assert!(!t.contains_point(&Point::new(-0.1, 1.0)));
+
+        // Winding doesn't matter
+        let clockwise = Triangle::new(t.a, t.c, t.b, None, "owner".to_string());
+        assert!(clockwise.contains_point(&Point::new(2.0, 2.0)));
+        assert!(!clockwise.contains_point(&Point::new(6.0, 6.0)));
+    }
+
+    #[test]
+    fn test_contains_and_intersects() {
+        let genesis = genesis();
+        let children = genesis.subdivide();
+        let grandchild = children[2].subdivide()[0].clone();
+
+        assert!(genesis.contains(&children[0]));
+        assert!(genesis.contains(&grandchild));
+        assert!(!children[0].contains(&genesis));
+        assert!(!children[0].contains(&grandchild));
+
+        // Siblings share vertices but not area
+        assert!(!children[0].intersects(&children[1]));
+        assert!(!children[1].intersects(&children[2]));
+        assert!(genesis.intersects(&grandchild));
+        assert!(grandchild.intersects(&genesis));
+
+        let far = Triangle::equilateral(Point::new(100.0, 100.0), 1.0, "owner".to_string());
+        assert!(!genesis.intersects(&far));
+
+        // Overlapping without either containing a vertex of the other
+        let up = Triangle::new(Point::new(0.0, 0.0), Point::new(4.0, 0.0), Point::new(2.0, 3.0), None, "owner".to_string());
+        let down = Triangle::new(Point::new(0.0, 2.0), Point::new(4.0, 2.0), Point::new(2.0, -1.0), None, "owner".to_string());
+        assert!(up.intersects(&down));
+        assert!(!up.contains(&down));
+    }
+
+    #[test]
+    fn test_siblings_and_contact() {
+        let genesis = genesis();
+        let children = genesis.subdivide();
+
+        let siblings = children[1].siblings(&genesis);
+        assert_eq!(siblings.len(), 2);
+        assert_eq!(siblings[0].hash(), children[0].hash());
+        assert_eq!(siblings[1].hash(), children[2].hash());
+
+        // Subdivision children meet only at the parent's edge midpoints
+        assert_eq!(children[0].contact(&children[1]), Some(Contact::Vertex));
+        assert!(!children[0].shares_edge(&children[1]));
+        assert_eq!(children[0].contact(&genesis), None, "overlapping is not touching");
+
+        // Mirrored across the base, genesis shares its whole bottom edge
+        let below = Triangle::new(genesis.a, genesis.b, Point::new(genesis.c.x, -genesis.c.y), None, "owner".to_string());
+        assert_eq!(genesis.contact(&below), Some(Contact::Edge));
+        // A child along the base shares only part of it
+        assert_eq!(children[0].contact(&below), Some(Contact::Edge));
+        assert_eq!(children[2].contact(&below), None);
+    }
+
+    #[test]
+    fn test_zorder_key() {
+        // Bits interleave x (even positions) and y (odd), offset by the sign flip
+        let origin = Point::new(0.0, 0.0).zorder_key();
+        assert_eq!(origin, 0b11 << 126);
+        assert_eq!(Point::new(ZORDER_SCALE.recip(), 0.0).zorder_key(), origin | 1);
+        assert_eq!(Point::new(0.0, ZORDER_SCALE.recip()).zorder_key(), origin | 2);
+
+        // Quadrants order bottom-left, bottom-right, top-left, top-right
+        let keys: Vec<u128> = [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)]
+            .iter()
+            .map(|&(x, y)| Point::new(x, y).zorder_key())
+            .collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Children of one subdivision sit within their parent's patch of the curve
+        let children = genesis().subdivide();
+        let grandchildren = children[0].subdivide();
+        let (low, high) = (
+            grandchildren.iter().map(Triangle::zorder_key).min().unwrap(),
+            grandchildren.iter().map(Triangle::zorder_key).max().unwrap(),
+        );
+        assert!(children[1].zorder_key() > high || children[1].zorder_key() < low);
+        assert_eq!(genesis().zorder_key(), genesis().zorder_key());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let t = setup_test_triangle();
+        assert_eq!(t.centroid(), Point::new(10.0 / 3.0, 10.0 / 3.0));
+        let [ab, bc, ca] = t.edge_lengths();
+        assert_eq!((ab, ca), (10.0, 10.0));
+        assert!((bc - 200f64.sqrt()).abs() < 1e-12);
+        assert!((t.perimeter() - (20.0 + 200f64.sqrt())).abs() < 1e-12);
+
+        // Right triangle: the circumcenter is the hypotenuse's midpoint
+        let circumcircle = t.circumcircle().unwrap();
+        assert!(circumcircle.center.equals(&Point::new(5.0, 5.0)));
+        assert!((circumcircle.radius - 50f64.sqrt()).abs() < 1e-9);
+
+        // The incircle of a right triangle with legs a, b has radius (a + b - c) / 2
+        let incircle = t.incircle().unwrap();
+        let r = (20.0 - 200f64.sqrt()) / 2.0;
+        assert!((incircle.radius - r).abs() < 1e-9);
+        assert!(incircle.center.equals(&Point::new(r, r)));
+        assert!(t.contains_point(&incircle.center));
+
+        // Equilateral: every center coincides
+        let g = genesis();
+        let (inc, circ) = (g.incircle().unwrap(), g.circumcircle().unwrap());
+        assert!(inc.center.equals(&g.centroid()) && circ.center.equals(&g.centroid()));
+        assert!((circ.radius - 2.0 * inc.radius).abs() < 1e-9);
+
+        let degenerate = Triangle::new(Point::new(0.0, 0.0), Point::new(1.0, 1.0), Point::new(2.0, 2.0), None, "owner".to_string());
+        assert!(degenerate.incircle().is_none());
+        assert!(degenerate.circumcircle().is_none());
+    }
 }
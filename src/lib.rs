@@ -1,12 +1,29 @@
 pub mod geometry;
+pub mod dyadic;
+pub mod render;
+pub mod geojson;
+pub mod params;
+pub mod config;
 pub mod blockchain;
 pub mod transaction;
 pub mod error;
 pub mod miner;
+pub mod pow;
+pub mod stratum;
+pub mod blockassembler;
+pub mod difficulty;
+pub mod events;
 pub mod crypto;
+pub mod codec;
 pub mod persistence;
+pub mod migrations;
 pub mod network;
+pub mod noise;
+pub mod connmgr;
+pub mod peerstore;
 pub mod wallet;
 pub mod addressbook;
 pub mod api;
+pub mod rpc;
 pub mod security;
+pub mod testkit;
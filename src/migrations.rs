@@ -0,0 +1,420 @@
+//! Versioned changes to the database schema
+//!
+//! A database records its schema version in the metadata table, under
+//! `schema_version`. `Database::open` applies every migration above it, in
+//! order, each in one transaction with the version it leads to, and refuses a
+//! database from a newer build instead of misreading it. Changing the schema
+//! means appending a migration here; released ones are never edited.
+
+use rusqlite::{params, Connection};
+use crate::error::ChainError;
+use crate::params::ChainParams;
+use crate::persistence::{decode_stored, index_transaction, record_chain_identity, stored_bits_to_compact};
+use crate::difficulty;
+use std::collections::HashMap;
+use crate::transaction::Transaction;
+use crate::codec;
+
+pub struct Migration {
+    /// Schema version the database is at once this has run
+    pub version: u32,
+    pub description: &'static str,
+    apply: fn(&Connection) -> Result<(), ChainError>,
+}
+
+/// Every migration, oldest first, with consecutive versions from 1
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Tables as they stood before schema versioning",
+        apply: baseline,
+    },
+    Migration {
+        version: 2,
+        description: "Move transactions stored inline with their blocks to the transactions table",
+        apply: move_inline_transactions,
+    },
+    Migration {
+        version: 3,
+        description: "Key blocks and their transactions by block hash, with the main chain in its own table",
+        apply: blocks_by_hash,
+    },
+    Migration {
+        version: 4,
+        description: "Record the network, genesis block and tip of the stored chain",
+        apply: record_identity,
+    },
+    Migration {
+        version: 5,
+        description: "Record each stored block's chain work",
+        apply: chain_work,
+    },
+];
+
+/// Schema version this build creates and understands
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// A database's schema version: 0 if it's empty or from before versioning
+pub fn schema_version(conn: &Connection) -> Result<u32, ChainError> {
+    let has_metadata: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'metadata'",
+        [],
+        |row| row.get(0),
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to inspect schema: {}", e)))?;
+    if !has_metadata {
+        return Ok(0);
+    }
+
+    let version: Option<String> = conn.query_row(
+        "SELECT value FROM metadata WHERE key = 'schema_version'",
+        [],
+        |row| row.get(0),
+    ).map(Some).or_else(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => Ok(None),
+        e => Err(ChainError::DatabaseError(format!("Failed to read schema version: {}", e))),
+    })?;
+    version.map_or(Ok(0), |version| version.parse()
+        .map_err(|_| ChainError::DatabaseError(format!("Invalid schema version {}", version))))
+}
+
+/// Bring a database up to `latest_version`
+pub fn migrate(conn: &mut Connection) -> Result<(), ChainError> {
+    let current = schema_version(conn)?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(newer_than_supported(current, latest));
+    }
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        let tx = conn.transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+        (migration.apply)(&tx).map_err(|e| ChainError::DatabaseError(format!(
+            "Migration to schema version {} ({}) failed: {}",
+            migration.version, migration.description, e
+        )))?;
+        tx.execute(
+            "INSERT OR REPLACE INTO metadata (key, value) VALUES ('schema_version', ?1)",
+            params![migration.version.to_string()],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to save schema version: {}", e)))?;
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+    }
+
+    Ok(())
+}
+
+/// Check that a database is at `latest_version` without migrating it, for
+/// connections that can't write
+pub fn require_latest(conn: &Connection) -> Result<(), ChainError> {
+    let current = schema_version(conn)?;
+    let latest = latest_version();
+    match current.cmp(&latest) {
+        std::cmp::Ordering::Equal => Ok(()),
+        std::cmp::Ordering::Greater => Err(newer_than_supported(current, latest)),
+        std::cmp::Ordering::Less => Err(ChainError::DatabaseError(format!(
+            "Database schema version {} is older than this build's ({}); open it for writing once to upgrade it",
+            current, latest
+        ))),
+    }
+}
+
+fn newer_than_supported(current: u32, latest: u32) -> ChainError {
+    ChainError::DatabaseError(format!(
+        "Database schema version {} is newer than this build supports ({}); upgrade siertrichain to open it",
+        current, latest
+    ))
+}
+
+/// Create whatever tables are missing. Databases from before versioning
+/// already have some, possibly without columns added later.
+fn baseline(conn: &Connection) -> Result<(), ChainError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS blocks (
+            height INTEGER PRIMARY KEY,
+            hash BLOB NOT NULL,
+            previous_hash BLOB NOT NULL,
+            timestamp INTEGER NOT NULL,
+            difficulty INTEGER NOT NULL, -- compact target bits
+            nonce INTEGER NOT NULL,
+            merkle_root BLOB NOT NULL,
+            transactions BLOB NOT NULL -- empty if in the transactions table
+        )",
+        [],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to create blocks table: {}", e)))?;
+
+    // Each block's transactions in their canonical encoding, found by txid.
+    // Coinbases paying the same reward to the same address share a txid,
+    // so rows are keyed by their place in the chain.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS transactions (
+            height INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            txid BLOB NOT NULL,
+            data BLOB NOT NULL,
+            PRIMARY KEY (height, position)
+        )",
+        [],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to create transactions table: {}", e)))?;
+    conn.execute("CREATE INDEX IF NOT EXISTS transactions_txid ON transactions (txid)", [])
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to create txid index: {}", e)))?;
+
+    // The transactions naming each address, for history without scanning
+    // the chain. Empty unless `set_address_index` turns it on, since it
+    // grows the database.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS address_index (
+            address TEXT NOT NULL,
+            height INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            txid BLOB NOT NULL,
+            role TEXT NOT NULL,
+            PRIMARY KEY (address, height, position, role)
+        )",
+        [],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to create address_index table: {}", e)))?;
+    conn.execute("CREATE INDEX IF NOT EXISTS address_index_height ON address_index (height)", [])
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to create address index height index: {}", e)))?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS utxo_set (
+            hash BLOB PRIMARY KEY,
+            triangle_data BLOB NOT NULL
+        )",
+        [],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to create utxo_set table: {}", e)))?;
+
+    // Secondary index on the Z-order key of each unspent triangle, so nearby
+    // triangles can be range-scanned. Databases from before it get the column
+    // added, filled in the next time the UTXO set is saved.
+    let has_zorder: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('utxo_set') WHERE name = 'zorder'",
+        [],
+        |row| row.get(0),
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to inspect utxo_set table: {}", e)))?;
+    if !has_zorder {
+        conn.execute("ALTER TABLE utxo_set ADD COLUMN zorder BLOB", [])
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to add zorder column: {}", e)))?;
+    }
+    conn.execute("CREATE INDEX IF NOT EXISTS utxo_set_zorder ON utxo_set (zorder)", [])
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to create zorder index: {}", e)))?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS metadata (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to create metadata table: {}", e)))?;
+
+    // UTXO set as of the pruned height, so a pruned node can still replay reorgs
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS prune_base_utxo_set (
+            hash BLOB PRIMARY KEY,
+            triangle_data BLOB NOT NULL
+        )",
+        [],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to create prune_base_utxo_set table: {}", e)))?;
+
+    // A stratum pool's recently credited shares, oldest first
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS pool_shares (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            address TEXT NOT NULL,
+            work REAL NOT NULL
+        )",
+        [],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to create pool_shares table: {}", e)))?;
+
+    Ok(())
+}
+
+/// Blocks saved before the transactions table held their transactions in
+/// their own row, as one encoded list or JSON text
+fn move_inline_transactions(conn: &Connection) -> Result<(), ChainError> {
+    let address_index: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM metadata WHERE key = 'address_index'",
+        [],
+        |row| row.get(0),
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to read address index setting: {}", e)))?;
+
+    let mut stmt = conn.prepare("SELECT height, transactions FROM blocks WHERE length(transactions) > 0")
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, decode_stored::<Vec<Transaction>>(row.get_ref(1)?))))
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to query blocks: {}", e)))?;
+    for row in rows {
+        let (height, transactions) = row
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to read block: {}", e)))?;
+        for (position, transaction) in transactions?.iter().enumerate() {
+            conn.execute(
+                "INSERT OR REPLACE INTO transactions (height, position, txid, data) VALUES (?1, ?2, ?3, ?4)",
+                params![height, position as i64, transaction.hash().to_vec(), codec::encode(transaction)],
+            ).map_err(|e| ChainError::DatabaseError(format!("Failed to save transaction: {}", e)))?;
+            if address_index {
+                index_transaction(conn, height, position as i64, transaction)?;
+            }
+        }
+    }
+    conn.execute("UPDATE blocks SET transactions = X'' WHERE length(transactions) > 0", [])
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to clear inline transactions: {}", e)))?;
+
+    Ok(())
+}
+
+/// Blocks were keyed by height, so a block from another branch overwrote
+/// the one the main chain had there. Now any number are kept per height, and
+/// `chain` lists the main chain's.
+fn blocks_by_hash(conn: &Connection) -> Result<(), ChainError> {
+    conn.execute_batch(
+        "CREATE TABLE blocks_by_hash (
+            hash BLOB PRIMARY KEY,
+            height INTEGER NOT NULL,
+            previous_hash BLOB NOT NULL,
+            timestamp INTEGER NOT NULL,
+            difficulty INTEGER NOT NULL, -- compact target bits
+            nonce INTEGER NOT NULL,
+            merkle_root BLOB NOT NULL,
+            transactions BLOB NOT NULL -- empty if in the transactions table
+        );
+        INSERT INTO blocks_by_hash (hash, height, previous_hash, timestamp, difficulty, nonce, merkle_root, transactions)
+            SELECT hash, height, previous_hash, timestamp, difficulty, nonce, merkle_root, transactions FROM blocks;
+
+        CREATE TABLE chain (
+            height INTEGER PRIMARY KEY,
+            hash BLOB NOT NULL
+        );
+        INSERT INTO chain (height, hash) SELECT height, hash FROM blocks;
+
+        CREATE TABLE transactions_by_block (
+            block_hash BLOB NOT NULL,
+            position INTEGER NOT NULL,
+            txid BLOB NOT NULL,
+            data BLOB NOT NULL,
+            PRIMARY KEY (block_hash, position)
+        );
+        INSERT INTO transactions_by_block (block_hash, position, txid, data)
+            SELECT b.hash, t.position, t.txid, t.data FROM transactions t JOIN blocks b ON b.height = t.height;
+
+        DROP TABLE transactions;
+        ALTER TABLE transactions_by_block RENAME TO transactions;
+        CREATE INDEX transactions_txid ON transactions (txid);
+        DROP TABLE blocks;
+        ALTER TABLE blocks_by_hash RENAME TO blocks;
+        CREATE INDEX blocks_height ON blocks (height);"
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to rekey blocks: {}", e)))
+}
+
+/// Loading checks these from now on. Only the main network existed before,
+/// so chains already stored are its; an empty database is left for the first
+/// node to open it to claim.
+fn record_identity(conn: &Connection) -> Result<(), ChainError> {
+    let has_blocks: bool = conn.query_row("SELECT COUNT(*) > 0 FROM chain", [], |row| row.get(0))
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to inspect the main chain: {}", e)))?;
+    if has_blocks {
+        record_chain_identity(conn, ChainParams::MAINNET.network)?;
+    }
+    Ok(())
+}
+
+/// The work of the branch each block ends, from genesis, as 32 big-endian
+/// bytes, so stored side branches can be weighed against the main chain
+fn chain_work(conn: &Connection) -> Result<(), ChainError> {
+    conn.execute("ALTER TABLE blocks ADD COLUMN chain_work BLOB", [])
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to add chain work column: {}", e)))?;
+
+    let mut stmt = conn.prepare("SELECT hash, previous_hash, difficulty FROM blocks ORDER BY height")
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, i64>(2)?)))
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to query blocks: {}", e)))?;
+    let mut work: HashMap<Vec<u8>, difficulty::Work> = HashMap::new();
+    for row in rows {
+        let (hash, previous_hash, bits) = row
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to read block: {}", e)))?;
+        let parent_work = work.get(&previous_hash).copied().unwrap_or_default();
+        let total = difficulty::add_work(&parent_work, &difficulty::block_work(stored_bits_to_compact(bits)));
+        conn.execute("UPDATE blocks SET chain_work = ?1 WHERE hash = ?2", params![total.to_vec(), hash])
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to save chain work: {}", e)))?;
+        work.insert(hash, total);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::persistence::Database;
+
+    #[test]
+    fn test_new_databases_are_at_the_latest_version() {
+        let path = std::env::temp_dir().join(format!("siertrichain-migrate-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        drop(Database::open(path.to_str().unwrap()).unwrap());
+
+        let mut conn = Connection::open(&path).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        // Already current, so nothing runs again
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_newer_databases_are_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute("UPDATE metadata SET value = '999' WHERE key = 'schema_version'", []).unwrap();
+
+        let error = migrate(&mut conn).unwrap_err();
+        assert!(matches!(error, ChainError::DatabaseError(e) if e.contains("999") && e.contains("upgrade")));
+    }
+
+    #[test]
+    fn test_inline_transactions_are_moved_out() {
+        let path = std::env::temp_dir().join(format!("siertrichain-inline-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let chain = Blockchain::regtest();
+        let genesis = &chain.blocks[0];
+        let coinbase = Transaction::Coinbase(crate::transaction::CoinbaseTx::new(1000, "miner".to_string()));
+        {
+            // An unversioned database, its block holding its own transactions
+            let conn = Connection::open(&path).unwrap();
+            baseline(&conn).unwrap();
+            conn.execute(
+                "INSERT INTO blocks (height, hash, previous_hash, timestamp, difficulty, nonce, merkle_root, transactions)
+                 VALUES (0, ?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    genesis.hash.to_vec(),
+                    genesis.header.previous_hash.to_vec(),
+                    genesis.header.timestamp,
+                    genesis.header.bits as i64,
+                    genesis.header.nonce as i64,
+                    genesis.header.merkle_root.to_vec(),
+                    codec::encode(&vec![coinbase.clone()]),
+                ],
+            ).unwrap();
+            assert_eq!(schema_version(&conn).unwrap(), 0);
+        }
+
+        let db = Database::open(path.to_str().unwrap()).unwrap();
+        let txid = coinbase.hash();
+        assert_eq!(db.load_transaction(&txid).unwrap().map(|(height, tx)| (height, tx.hash())), Some((0, txid)));
+        assert_eq!(db.load_blockchain().unwrap().blocks[0].transactions.get().unwrap().len(), 1);
+        drop(db);
+
+        let conn = Connection::open(&path).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        let inline: i64 = conn.query_row("SELECT COUNT(*) FROM blocks WHERE length(transactions) > 0", [], |row| row.get(0)).unwrap();
+        assert_eq!(inline, 0);
+        let tip: String = conn.query_row("SELECT value FROM metadata WHERE key = 'tip_hash'", [], |row| row.get(0)).unwrap();
+        assert_eq!(tip, hex::encode(genesis.hash));
+        let network: String = conn.query_row("SELECT value FROM metadata WHERE key = 'network'", [], |row| row.get(0)).unwrap();
+        assert_eq!(network, "main");
+        let work: Vec<u8> = conn.query_row("SELECT chain_work FROM blocks", [], |row| row.get(0)).unwrap();
+        assert_eq!(work, difficulty::block_work(genesis.header.bits).to_vec());
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
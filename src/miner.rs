@@ -1,29 +1,633 @@
 //! Proof-of-Work (PoW) implementation for siertrichain.
 
-use crate::blockchain::{Block, Sha256Hash};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tokio::sync::{watch, RwLock};
+use crate::blockassembler::BlockTemplate;
+use crate::blockchain::{Block, Blockchain, Sha256Hash};
+use crate::difficulty;
 use crate::error::ChainError;
+use crate::events::ChainEvent;
+use crate::persistence::Database;
+use crate::pow::{PowEngine, Sha256Pow};
+use crate::transaction::Address;
 
-/// Checks if a hash meets the required difficulty target.
-/// The difficulty is the required number of leading zeros in the hash.
-pub fn is_hash_valid(hash: &Sha256Hash, difficulty: u64) -> bool {
-    let required_prefix = "0".repeat(difficulty as usize);
-    hex::encode(hash).starts_with(&required_prefix)
+/// Number of nonces `mine_block` tries between checks of its stop flag
+pub const STOP_CHECK_INTERVAL: u64 = 4096;
+
+/// How often `mine_block` moves the header timestamp up to the current time
+pub const TIMESTAMP_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How old a template must be before mempool changes alone rebuild it, so a
+/// stream of new transactions doesn't keep restarting the search
+pub const MEMPOOL_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Span of recent hashing that `HashrateTracker` averages over
+pub const HASHRATE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Period a throttled miner's duty cycle repeats over: at 20%, each thread
+/// hashes for 200ms and then sleeps for 800ms
+pub const DUTY_CYCLE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Most hashing threads a `MiningConfig` may ask for
+pub const MAX_MINING_THREADS: usize = 256;
+
+/// How hard to mine: how many threads hash, and what share of the time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MiningConfig {
+    /// Hashing threads, each searching its own share of the nonces
+    pub threads: usize,
+    /// Percentage of each `DUTY_CYCLE_PERIOD` spent hashing, from 1 to 100.
+    /// Threads sleep for the rest, so a laptop can mine without pegging its cores.
+    pub duty_cycle: u8,
+}
+
+impl Default for MiningConfig {
+    /// One thread hashing flat out
+    fn default() -> Self {
+        MiningConfig { threads: 1, duty_cycle: 100 }
+    }
+}
+
+impl MiningConfig {
+    pub fn new(threads: usize, duty_cycle: u8) -> Result<Self, ChainError> {
+        if threads == 0 || threads > MAX_MINING_THREADS {
+            return Err(ChainError::InvalidMiningConfig(format!(
+                "Thread count must be between 1 and {}, got {}", MAX_MINING_THREADS, threads
+            )));
+        }
+        if duty_cycle == 0 || duty_cycle > 100 {
+            return Err(ChainError::InvalidMiningConfig(format!(
+                "Duty cycle must be between 1 and 100 percent, got {}", duty_cycle
+            )));
+        }
+        Ok(MiningConfig { threads, duty_cycle })
+    }
+}
+
+/// Sleeps out the rest of each `DUTY_CYCLE_PERIOD` once its hashing share is used up
+struct Throttle {
+    active: Duration,
+    period_start: Instant,
+}
+
+impl Throttle {
+    fn new(duty_cycle: u8) -> Self {
+        Throttle {
+            active: DUTY_CYCLE_PERIOD * duty_cycle.min(100) as u32 / 100,
+            period_start: Instant::now(),
+        }
+    }
+
+    fn pause(&mut self) {
+        if self.active >= DUTY_CYCLE_PERIOD {
+            return;
+        }
+        let elapsed = self.period_start.elapsed();
+        if elapsed >= self.active {
+            if let Some(rest) = DUTY_CYCLE_PERIOD.checked_sub(elapsed) {
+                std::thread::sleep(rest);
+            }
+            self.period_start = Instant::now();
+        }
+    }
+}
+
+/// Checks if a hash meets the target encoded in the compact `bits`.
+pub fn is_hash_valid(hash: &Sha256Hash, bits: u32) -> bool {
+    difficulty::hash_meets_target(hash, bits)
+}
+
+/// Mines a new block by searching for a nonce that satisfies the current target.
+/// Gives up with `ChainError::MiningCancelled` within `STOP_CHECK_INTERVAL`
+/// hashes of `stop` being set.
+///
+/// The timestamp is kept current while mining, and once every header nonce
+/// has been tried the coinbase's extra nonce is rolled and the search starts
+/// over, so a block without a coinbase is the only one that can run out.
+pub fn mine_block(block: Block, stop: &AtomicBool) -> Result<Block, ChainError> {
+    mine_block_with(block, |_| stop.load(Ordering::Relaxed))
+}
+
+/// `mine_block`, asking `interrupt` every `STOP_CHECK_INTERVAL` hashes whether
+/// to give up. It is passed the number of hashes tried so far, and called once
+/// more with the final count when mining ends, its answer then ignored.
+pub fn mine_block_with(block: Block, interrupt: impl FnMut(u64) -> bool) -> Result<Block, ChainError> {
+    search(block, &Sha256Pow, 0, 1, 100, interrupt)
+}
+
+/// `mine_block_with` across `config.threads` threads, each trying every
+/// `threads`th nonce and hashing for `config.duty_cycle` percent of the time,
+/// until a hash satisfies `pow`. `interrupt` is passed the thread's index
+/// along with its hash count; the first thread to find a block stops the
+/// others.
+pub fn mine_block_threaded(
+    block: Block,
+    config: &MiningConfig,
+    pow: &dyn PowEngine,
+    interrupt: impl Fn(usize, u64) -> bool + Sync,
+) -> Result<Block, ChainError> {
+    let threads = config.threads.max(1);
+    let found = AtomicBool::new(false);
+    let results: Vec<Result<Block, ChainError>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads).map(|thread| {
+            let (block, found, interrupt) = (block.clone(), &found, &interrupt);
+            scope.spawn(move || {
+                let result = search(block, pow, thread as u64, threads as u64, config.duty_cycle, |hashes| {
+                    // Checked second so the hash count is always reported
+                    interrupt(thread, hashes) || found.load(Ordering::Relaxed)
+                });
+                if result.is_ok() {
+                    found.store(true, Ordering::Relaxed);
+                }
+                result
+            })
+        }).collect();
+        handles.into_iter().map(|handle| handle.join().expect("mining thread panicked")).collect()
+    });
+
+    let mut cancelled = Err(ChainError::MiningCancelled);
+    for result in results {
+        match result {
+            Ok(block) => return Ok(block),
+            Err(ChainError::MiningCancelled) => {}
+            Err(e) => cancelled = Err(e),
+        }
+    }
+    cancelled
 }
 
-/// Mines a new block by searching for a nonce that satisfies the current difficulty.
-pub fn mine_block(mut block: Block) -> Result<Block, ChainError> {
-    let difficulty = block.header.difficulty;
-    let mut nonce: u64 = 0;
+/// Try nonces `first`, `first + step`, ... on `block` until one satisfies
+/// `pow`, rolling the extra nonce and starting over from `first` if they run out
+fn search(
+    mut block: Block,
+    pow: &dyn PowEngine,
+    first: u64,
+    step: u64,
+    duty_cycle: u8,
+    mut interrupt: impl FnMut(u64) -> bool,
+) -> Result<Block, ChainError> {
+    let bits = block.header.bits;
+    let mut nonce = first;
+    let mut hashes: u64 = 0;
+    let mut last_refresh = Instant::now();
+    let mut throttle = Throttle::new(duty_cycle);
     
     loop {
+        if hashes.is_multiple_of(STOP_CHECK_INTERVAL) {
+            if interrupt(hashes) {
+                return Err(ChainError::MiningCancelled);
+            }
+            throttle.pause();
+            if last_refresh.elapsed() >= TIMESTAMP_REFRESH_INTERVAL {
+                block.header.timestamp = block.header.timestamp.max(Utc::now().timestamp());
+                last_refresh = Instant::now();
+            }
+        }
+
         block.header.nonce = nonce;
         let hash = block.calculate_hash();
+        hashes = hashes.saturating_add(1);
         
-        if is_hash_valid(&hash, difficulty) {
+        if pow.meets_target(&hash, bits) {
+            interrupt(hashes);
             block.hash = hash;
             return Ok(block);
         }
 
-        nonce = nonce.checked_add(1).ok_or(ChainError::InvalidProofOfWork)?; 
+        nonce = match nonce.checked_add(step) {
+            Some(next) => next,
+            None => {
+                block.roll_extra_nonce()?;
+                first
+            }
+        };
+    }
+}
+
+/// A snapshot of mining progress, as published by `HashrateTracker`
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MinerStats {
+    /// Hashes tried since mining started
+    pub hashes: u64,
+    /// Hashes per second over the last `HASHRATE_WINDOW`, across all threads
+    pub hashrate: f64,
+    /// Window hashrate of each mining thread, by thread index
+    pub thread_hashrates: Vec<f64>,
+}
+
+/// Turns the hash counts that mining threads report into `MinerStats`,
+/// publishing every update on a watch channel for whoever displays them
+#[derive(Debug)]
+pub struct HashrateTracker {
+    /// Per thread, `(when, hashes so far)` samples covering the window, plus
+    /// the last one before it as a baseline
+    samples: Vec<VecDeque<(Instant, u64)>>,
+    sender: watch::Sender<MinerStats>,
+}
+
+impl HashrateTracker {
+    pub fn new(threads: usize) -> Self {
+        let now = Instant::now();
+        let threads = threads.max(1);
+        let (sender, _) = watch::channel(MinerStats {
+            thread_hashrates: vec![0.0; threads],
+            ..MinerStats::default()
+        });
+        HashrateTracker {
+            samples: (0..threads).map(|_| VecDeque::from([(now, 0)])).collect(),
+            sender,
+        }
+    }
+
+    /// Receive the stats after every update
+    pub fn subscribe(&self) -> watch::Receiver<MinerStats> {
+        self.sender.subscribe()
+    }
+
+    pub fn stats(&self) -> MinerStats {
+        self.sender.borrow().clone()
+    }
+
+    /// Record that `thread` has tried `hashes` more hashes
+    pub fn record(&mut self, thread: usize, hashes: u64) {
+        self.record_at(thread, hashes, Instant::now());
+    }
+
+    fn record_at(&mut self, thread: usize, hashes: u64, now: Instant) {
+        let samples = &mut self.samples[thread];
+        let total = samples.back().map_or(0, |(_, total)| *total).saturating_add(hashes);
+        samples.push_back((now, total));
+        while samples.len() > 2 && now.duration_since(samples[1].0) >= HASHRATE_WINDOW {
+            samples.pop_front();
+        }
+
+        let thread_hashrates: Vec<f64> = self.samples.iter().map(|samples| {
+            let (start, first) = samples.front().expect("never empty");
+            let (end, last) = samples.back().expect("never empty");
+            let elapsed = end.duration_since(*start).as_secs_f64();
+            if elapsed > 0.0 { (last - first) as f64 / elapsed } else { 0.0 }
+        }).collect();
+        self.sender.send_replace(MinerStats {
+            hashes: self.samples.iter().map(|samples| samples.back().map_or(0, |(_, total)| *total)).sum(),
+            hashrate: thread_hashrates.iter().sum(),
+            thread_hashrates,
+        });
+    }
+}
+
+/// Watches chain events to tell when the template being mined has gone stale:
+/// at once when the tip moves, and on mempool changes once the template is
+/// older than `MEMPOOL_REFRESH_INTERVAL`
+#[derive(Debug)]
+pub struct TemplateWatcher {
+    events: broadcast::Receiver<ChainEvent>,
+    built_at: Instant,
+    tip_changed: bool,
+    mempool_changed: bool,
+}
+
+impl TemplateWatcher {
+    pub fn new(events: broadcast::Receiver<ChainEvent>) -> Self {
+        TemplateWatcher {
+            events,
+            built_at: Instant::now(),
+            tip_changed: false,
+            mempool_changed: false,
+        }
+    }
+
+    /// Record that a fresh template was just built, forgetting earlier events
+    pub fn template_built(&mut self) {
+        self.drain();
+        self.built_at = Instant::now();
+        self.tip_changed = false;
+        self.mempool_changed = false;
+    }
+
+    /// Whether a template built now would differ enough to restart mining
+    pub fn is_stale(&mut self) -> bool {
+        self.drain();
+        self.tip_changed || (self.mempool_changed && self.built_at.elapsed() >= MEMPOOL_REFRESH_INTERVAL)
+    }
+
+    fn drain(&mut self) {
+        loop {
+            match self.events.try_recv() {
+                Ok(ChainEvent::BlockConnected { .. })
+                | Ok(ChainEvent::BlockDisconnected { .. })
+                | Ok(ChainEvent::ReorgCompleted { .. }) => self.tip_changed = true,
+                Ok(ChainEvent::TxAccepted(_)) | Ok(ChainEvent::TxEvicted(_)) => self.mempool_changed = true,
+                // A side branch leaves the tip where it was
+                Ok(ChainEvent::ForkBlockAccepted { .. }) => {}
+                // Events were missed, so assume the worst
+                Err(TryRecvError::Lagged(_)) => self.tip_changed = true,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// Mines blocks on a shared chain, rebuilding the template whenever the
+/// `TemplateWatcher` finds it stale. Its `HashrateTracker` carries across
+/// templates. The chain is the one a `NetworkNode` syncs, so blocks from
+/// peers and mined blocks land on the same instance; the coordinator blocks
+/// on its lock, so use it outside async code, e.g. under `spawn_blocking`.
+pub struct MiningCoordinator {
+    chain: Arc<RwLock<Blockchain>>,
+    miner_address: Address,
+    config: MiningConfig,
+    watcher: TemplateWatcher,
+    tracker: HashrateTracker,
+    templates_built: u64,
+}
+
+impl MiningCoordinator {
+    pub fn new(chain: Arc<RwLock<Blockchain>>, miner_address: Address) -> Self {
+        let watcher = TemplateWatcher::new(chain.blocking_read().subscribe());
+        MiningCoordinator {
+            chain,
+            miner_address,
+            config: MiningConfig::default(),
+            watcher,
+            tracker: HashrateTracker::new(1),
+            templates_built: 0,
+        }
+    }
+
+    /// Mine with `config`'s threads and duty cycle instead of one thread flat out
+    pub fn with_config(mut self, config: MiningConfig) -> Self {
+        self.tracker = HashrateTracker::new(config.threads);
+        self.config = config;
+        self
+    }
+
+    /// Mine a block on the current tip, under the chain's `PowEngine`,
+    /// starting over on a fresh template whenever the tip moves or the mempool
+    /// changes. Fails with `ChainError::MiningCancelled` once `stop` is set.
+    pub fn mine_next(&mut self, stop: &AtomicBool) -> Result<Block, ChainError> {
+        loop {
+            let (template, pow) = {
+                let chain = self.chain.blocking_read();
+                (BlockTemplate::build(&chain, &self.miner_address), chain.pow.clone())
+            };
+            self.watcher.template_built();
+            self.templates_built += 1;
+
+            let progress = Mutex::new((&mut self.tracker, &mut self.watcher, vec![0u64; self.config.threads.max(1)]));
+            let stale = AtomicBool::new(false);
+            let result = mine_block_threaded(template.block, &self.config, &*pow, |thread, hashes| {
+                let mut progress = progress.lock().unwrap();
+                let (tracker, watcher, reported) = &mut *progress;
+                tracker.record(thread, hashes - reported[thread]);
+                reported[thread] = hashes;
+                if stop.load(Ordering::Relaxed) {
+                    return true;
+                }
+                if watcher.is_stale() {
+                    stale.store(true, Ordering::Relaxed);
+                }
+                stale.load(Ordering::Relaxed)
+            });
+            match result {
+                Ok(block) => return Ok(block),
+                Err(ChainError::MiningCancelled) if stale.load(Ordering::Relaxed) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Mine blocks onto the chain until `stop` is set, saving each with the
+    /// state after it to `db`, if given, and passing it to `on_block`. A
+    /// block the chain rejects, e.g. because a peer's block won the race, is
+    /// dropped and mining carries on; failing to save one stops it.
+    pub fn run(&mut self, db: Option<&Mutex<Database>>, stop: &AtomicBool, mut on_block: impl FnMut(&Block)) -> Result<(), ChainError> {
+        loop {
+            let block = match self.mine_next(stop) {
+                Ok(block) => block,
+                Err(ChainError::MiningCancelled) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            {
+                let mut chain = self.chain.blocking_write();
+                if let Err(e) = chain.apply_block(block.clone()) {
+                    eprintln!("Failed to apply mined block: {}", e);
+                    continue;
+                }
+                if let Some(db) = db {
+                    let db = db.lock().unwrap();
+                    let bits = chain.bits;
+                    db.save_blockchain_state(&block, &mut chain.state, bits)?;
+                    db.save_prune_state(&chain)?;
+                }
+            }
+            on_block(&block);
+        }
+    }
+
+    /// Receive hashrate figures as mining progresses
+    pub fn subscribe(&self) -> watch::Receiver<MinerStats> {
+        self.tracker.subscribe()
+    }
+
+    pub fn stats(&self) -> MinerStats {
+        self.tracker.stats()
+    }
+
+    /// Templates built so far, including ones abandoned as stale
+    pub fn templates_built(&self) -> u64 {
+        self.templates_built
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventBus;
+
+    #[test]
+    fn test_mine_block() {
+        let chain = Blockchain::regtest();
+        let block = mine_block(BlockTemplate::build(&chain, "miner").block, &AtomicBool::new(false)).unwrap();
+        assert!(is_hash_valid(&block.hash, block.header.bits));
+        assert_eq!(block.hash, block.calculate_hash());
+    }
+
+    #[test]
+    fn test_stopped_mining_is_cancelled() {
+        let chain = Blockchain::regtest();
+        let block = BlockTemplate::build(&chain, "miner").block;
+        assert!(matches!(mine_block(block, &AtomicBool::new(true)), Err(ChainError::MiningCancelled)));
+    }
+
+    #[test]
+    fn test_mining_config() {
+        assert_eq!(MiningConfig::new(4, 20).unwrap(), MiningConfig { threads: 4, duty_cycle: 20 });
+        assert!(MiningConfig::new(0, 50).is_err());
+        assert!(MiningConfig::new(MAX_MINING_THREADS + 1, 50).is_err());
+        assert!(MiningConfig::new(2, 0).is_err());
+        assert!(MiningConfig::new(2, 101).is_err());
+    }
+
+    #[test]
+    fn test_threaded_mining_splits_the_nonces() {
+        let chain = Blockchain::regtest();
+        let block = BlockTemplate::build(&chain, "miner").block;
+        let config = MiningConfig::new(3, 100).unwrap();
+        let hashes: Mutex<Vec<u64>> = Mutex::new(vec![0; 3]);
+        let mined = mine_block_threaded(block, &config, &Sha256Pow, |thread, count| {
+            hashes.lock().unwrap()[thread] = count;
+            false
+        }).unwrap();
+        assert!(is_hash_valid(&mined.hash, mined.header.bits));
+        assert_eq!(mined.hash, mined.calculate_hash());
+
+        // Each thread reported its final count; together they covered the nonces up to the winner
+        let total: u64 = hashes.lock().unwrap().iter().sum();
+        assert!(total > mined.header.nonce / 3);
+    }
+
+    #[test]
+    fn test_throttle_sleeps_out_the_period() {
+        let mut full = Throttle::new(100);
+        let started = Instant::now();
+        full.pause();
+        assert!(started.elapsed() < DUTY_CYCLE_PERIOD / 10);
+
+        // At 10% the hashing share is used up after 100ms, so the pause lasts the rest of the period
+        let mut throttled = Throttle::new(10);
+        std::thread::sleep(DUTY_CYCLE_PERIOD / 10);
+        throttled.pause();
+        assert!(started.elapsed() >= DUTY_CYCLE_PERIOD);
+    }
+
+    #[test]
+    fn test_template_watcher() {
+        let events = EventBus::new();
+        let mut watcher = TemplateWatcher::new(events.subscribe());
+        events.publish(ChainEvent::BlockConnected { hash: [1; 32], height: 1 });
+        watcher.template_built();
+        assert!(!watcher.is_stale());
+
+        // A young template survives mempool churn but not a new tip
+        events.publish(ChainEvent::TxAccepted([2; 32]));
+        assert!(!watcher.is_stale());
+        events.publish(ChainEvent::BlockConnected { hash: [3; 32], height: 2 });
+        assert!(watcher.is_stale());
+
+        watcher.template_built();
+        assert!(!watcher.is_stale());
+        watcher.built_at -= MEMPOOL_REFRESH_INTERVAL;
+        assert!(!watcher.is_stale());
+        events.publish(ChainEvent::TxEvicted([2; 32]));
+        assert!(watcher.is_stale());
+    }
+
+    #[test]
+    fn test_coordinator_follows_the_tip() {
+        let chain = Arc::new(RwLock::new(Blockchain::regtest()));
+        let mut coordinator = MiningCoordinator::new(chain.clone(), "miner".to_string());
+        let stop = AtomicBool::new(false);
+
+        let first = coordinator.mine_next(&stop).unwrap();
+        chain.blocking_write().apply_block(first.clone()).unwrap();
+        let second = coordinator.mine_next(&stop).unwrap();
+        assert_eq!(second.header.previous_hash, first.hash);
+        assert_eq!(coordinator.templates_built(), 2);
+        assert!(coordinator.stats().hashes >= 2);
+
+        stop.store(true, Ordering::Relaxed);
+        assert!(matches!(coordinator.mine_next(&stop), Err(ChainError::MiningCancelled)));
+    }
+
+    #[test]
+    fn test_coordinator_leaves_out_conflicting_transactions() {
+        use crate::blockchain::genesis_triangle;
+        use crate::crypto::KeyPair;
+        use crate::transaction::{SubdivisionTx, Transaction};
+
+        let mut chain = Blockchain::regtest().with_pow(Arc::new(crate::pow::TestPow));
+        let keypair = KeyPair::generate().unwrap();
+        chain.state.set_owner(&genesis_triangle().hash(), keypair.address()).unwrap();
+
+        // Two subdivisions of the genesis triangle; the second replaces the first
+        let subdivision = |fee, nonce| {
+            let genesis = genesis_triangle();
+            let mut tx = SubdivisionTx::new(genesis.hash(), genesis.subdivide().to_vec(), keypair.address(), fee, nonce);
+            let signature = keypair.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, keypair.public_key.serialize().to_vec());
+            Transaction::Subdivision(tx)
+        };
+        let (low, high) = (subdivision(1, 1), subdivision(50, 2));
+        chain.mempool.add_transaction(low, &chain.state).unwrap();
+        chain.mempool.add_transaction(high.clone(), &chain.state).unwrap();
+
+        let chain = Arc::new(RwLock::new(chain));
+        let mut coordinator = MiningCoordinator::new(chain.clone(), "miner".to_string());
+        let block = coordinator.mine_next(&AtomicBool::new(false)).unwrap();
+        assert_eq!(block.transactions.get().unwrap().len(), 2);
+        assert_eq!(block.transactions.get().unwrap()[1].hash(), high.hash());
+        chain.blocking_write().apply_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_test_pow_mines_on_the_first_nonce() {
+        let chain = Arc::new(RwLock::new(Blockchain::regtest().with_pow(Arc::new(crate::pow::TestPow))));
+        let mut coordinator = MiningCoordinator::new(chain.clone(), "miner".to_string());
+        let stop = AtomicBool::new(false);
+
+        for _ in 0..200 {
+            let block = coordinator.mine_next(&stop).unwrap();
+            assert_eq!(block.header.nonce, 0);
+            chain.blocking_write().apply_block(block).unwrap();
+        }
+        assert_eq!(chain.blocking_read().blocks.len(), 201);
+        assert_eq!(coordinator.stats().hashes, 200);
+    }
+
+    #[test]
+    fn test_coordinator_run_saves_blocks_until_stopped() {
+        let chain = Arc::new(RwLock::new(Blockchain::regtest().with_pow(Arc::new(crate::pow::TestPow))));
+        let db = Mutex::new(Database::open(":memory:").unwrap());
+        let mut coordinator = MiningCoordinator::new(chain.clone(), "miner".to_string());
+        let stop = AtomicBool::new(false);
+
+        let mut mined = 0;
+        coordinator.run(Some(&db), &stop, |_| {
+            mined += 1;
+            if mined == 3 {
+                stop.store(true, Ordering::Relaxed);
+            }
+        }).unwrap();
+        assert_eq!(mined, 3);
+        assert_eq!(chain.blocking_read().blocks.len(), 4);
+        let tip = chain.blocking_read().blocks.last().unwrap().hash;
+        assert_eq!(db.lock().unwrap().load_tip_hash().unwrap(), Some(tip));
+    }
+
+    #[test]
+    fn test_hashrate_tracker() {
+        let mut tracker = HashrateTracker::new(2);
+        let stats = tracker.subscribe();
+        let start = tracker.samples[0][0].0;
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        tracker.record_at(0, 1000, at(1));
+        tracker.record_at(1, 500, at(1));
+        assert_eq!(stats.borrow().hashes, 1500);
+        assert_eq!(stats.borrow().thread_hashrates, vec![1000.0, 500.0]);
+        assert_eq!(stats.borrow().hashrate, 1500.0);
+
+        // Only the window counts once a thread has been hashing longer than it
+        tracker.record_at(0, 600_000, at(HASHRATE_WINDOW.as_secs() + 1));
+        tracker.record_at(0, 3000, at(HASHRATE_WINDOW.as_secs() + 2));
+        tracker.record_at(0, 3000, at(HASHRATE_WINDOW.as_secs() + 3));
+        let rate = stats.borrow().thread_hashrates[0];
+        assert_eq!(rate, 606_000.0 / (HASHRATE_WINDOW.as_secs() + 2) as f64);
+        assert_eq!(tracker.stats().hashes, 607_500);
     }
 }
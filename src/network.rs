@@ -1,11 +1,136 @@
 //! P2P Networking for siertrichain
+//!
+//! A `NetworkNode` listens for and dials peers, handshakes with each, then
+//! keeps a gossip session open to it, announcing the blocks and transactions
+//! it learns of and fetching those its peers announce. Messages travel in
+//! checksummed frames, over encrypted connections between nodes with keys
+//! (see `noise`), and peers that misbehave are banned.
 
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use crate::blockchain::Blockchain;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::{broadcast, mpsc, watch, Notify, RwLock};
+use crate::blockchain::{Block, BlockHeight, Blockchain, MempoolAcceptResult, Sha256Hash, MAX_BLOCK_SIZE};
+use sha2::{Digest, Sha256};
 use crate::error::ChainError;
+use crate::events::ChainEvent;
+use crate::noise::PeerStream;
+use crate::transaction::Transaction;
+use crate::codec;
+use crate::params::ChainParams;
+use crate::peerstore::PeerStore;
+use crate::persistence::Database;
+use crate::security::{PeerAuthResponse, PeerChallenge, SecurityManager};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Inventory a session remembers exchanging with its peer, so it is not
+/// announced back; the record starts over once it grows past this
+const MAX_KNOWN_INVENTORY: usize = 50_000;
+
+/// Transactions a node remembers having received or broadcast, so copies
+/// arriving from other peers are dropped without being checked again
+const MAX_RECENT_TRANSACTIONS: usize = 10_000;
+
+/// Most blocks kept waiting for their ancestors; the oldest is dropped first
+const MAX_ORPHAN_BLOCKS: usize = 100;
+
+/// Inbound connections a node accepts by default, counting those still handshaking
+pub const DEFAULT_MAX_INBOUND_PEERS: usize = 117;
+
+/// Inbound peers spared from eviction for each reason: coming from distinct
+/// network groups, behaving best, and being connected longest
+const PROTECTED_PER_REASON: usize = 4;
+
+/// How far back blocks connected from peers count towards the sync rate
+const SYNC_RATE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Most headers sent in reply to one `GetHeaders`
+const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+
+/// Blocks asked for in each `GetBlocks` while syncing
+const BLOCKS_PER_REQUEST: usize = 50;
+
+/// Blocks connected while syncing that are saved together, in one database
+/// transaction. A node stopped part way downloads the unsaved ones again.
+const SYNC_SAVE_BATCH: usize = 500;
+
+/// Most addresses sent in, or taken from, one `Addr`
+const MAX_ADDR_PER_MESSAGE: usize = 1000;
+
+/// Most items a peer may list in one `Inv` or `GetData`
+const MAX_INV_ITEMS: usize = 50_000;
+
+/// Messages queued for a peer before replies wait for it to catch up and
+/// announcements to it are dropped
+const OUTBOX_CAPACITY: usize = 256;
+
+/// Most addresses a node keeps in its peer list
+const MAX_KNOWN_PEERS: usize = 5000;
+
+/// How long to wait for a peer to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest payload a frame may carry; enough for a full block with room to spare
+pub const MAX_MESSAGE_SIZE: usize = 4 * MAX_BLOCK_SIZE;
+
+/// Bytes of blocks or transactions sent in answer to one request, leaving a
+/// frame room for the block that goes over
+const REPLY_SIZE_BUDGET: usize = MAX_MESSAGE_SIZE - MAX_BLOCK_SIZE;
+
+/// Bytes of a frame's command name, NUL-padded
+const COMMAND_SIZE: usize = 12;
+
+/// Magic, command, length and checksum
+const FRAME_HEADER_SIZE: usize = 4 + COMMAND_SIZE + 4 + 4;
+
+/// Misbehavior score at which a peer is banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// How long a banned peer's connections are refused
+pub const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long a peer has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a session stops reading from a peer that went over its rate limits
+const THROTTLE_DELAY: Duration = Duration::from_secs(1);
+
+/// Misbehavior counted against a peer each time it is throttled, so one that
+/// keeps flooding is banned and disconnected
+const THROTTLE_PENALTY: u32 = BAN_THRESHOLD / 5;
+
+/// Version of this peer-to-peer protocol, sent in the handshake
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this node talks to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Capability bit: the node serves every block since genesis, not just the
+/// ones after its prune height
+pub const CAP_FULL_HISTORY: u64 = 1;
+
+/// Capability bit: the node only talks to peers that answer its
+/// authentication challenge
+pub const CAP_AUTH_REQUIRED: u64 = 2;
+
+/// What each side of a connection tells the other before anything else
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct VersionMessage {
+    pub version: u32,
+    /// The sender's network, from `ChainParams::magic`
+    pub magic: [u8; 4],
+    pub genesis_hash: Sha256Hash,
+    pub best_height: u64,
+    /// `CAP_*` bits
+    pub capabilities: u64,
+    /// Port the sender accepts connections on, or 0 if it doesn't
+    pub listen_port: u16,
+    pub user_agent: String,
+}
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Node {
@@ -17,44 +142,446 @@ impl Node {
     pub fn new(host: String, port: u16) -> Self {
         Node { host, port }
     }
-    
+
+    /// `host:port`, with an IPv6 host in brackets
     pub fn addr(&self) -> String {
-        format!("{}:{}", self.host, self.port)
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /// Parse a `host:port` peer address, with an IPv6 host in brackets
+    pub fn parse(addr: &str) -> Result<Self, ChainError> {
+        let (host, port) = addr.rsplit_once(':')
+            .ok_or_else(|| ChainError::NetworkError(format!("Peer address {} is not host:port", addr)))?;
+        let host = match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+            Some(ipv6) => ipv6,
+            None if host.contains(':') => return Err(ChainError::NetworkError(format!(
+                "Peer address {} needs its IPv6 host in brackets, e.g. [::1]:8333", addr
+            ))),
+            None => host,
+        };
+        let port = port.parse()
+            .map_err(|_| ChainError::NetworkError(format!("Invalid peer port in {}", addr)))?;
+        Ok(Node::new(host.to_string(), port))
+    }
+}
+
+/// Something a node announces by hash and a peer fetches if it lacks it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum InvItem {
+    Block(Sha256Hash),
+    Tx(Sha256Hash),
+}
+
+/// Which side opened a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerState {
+    Connected,
+    /// Recently failed; not retried until `retry_at`
+    Backoff,
+}
+
+/// A peer as reported to operators, connected or waiting to be retried
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PeerStatus {
+    pub address: String,
+    pub state: PeerState,
+    pub direction: Direction,
+    /// Unix time of the last message from the peer
+    pub last_seen: Option<i64>,
+    /// Round trip of the last answered ping
+    pub latency_ms: Option<u64>,
+    /// Connection attempts that failed in a row
+    pub failures: u32,
+    /// Unix time of the next connection attempt
+    pub retry_at: Option<i64>,
+    /// Whether the connection is encrypted
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// The block a node's chain ends at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainTip {
+    pub hash: Sha256Hash,
+    pub height: BlockHeight,
+}
+
+impl ChainTip {
+    pub fn of(chain: &Blockchain) -> Self {
+        let tip = chain.blocks.last().unwrap();
+        ChainTip { hash: tip.hash, height: tip.header.height }
+    }
+}
+
+/// How far a node has caught up with its peers, for UIs to show progress
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SyncState {
+    /// Height of the best validated header
+    pub headers_height: u64,
+    /// Height of the best connected block
+    pub blocks_height: u64,
+    /// Highest best height a peer has reported
+    pub peer_best_height: u64,
+    /// Share of the known blocks that are connected, from 0 to 100
+    pub percentage: f64,
+    /// Blocks connected from peers per second, over the last `SYNC_RATE_WINDOW`
+    pub blocks_per_sec: f64,
+    /// Whether blocks are known that haven't been connected yet
+    pub syncing: bool,
+}
+
+impl SyncState {
+    pub fn new(headers_height: u64, blocks_height: u64, peer_best_height: u64, blocks_per_sec: f64) -> Self {
+        let target = headers_height.max(peer_best_height).max(blocks_height);
+        let percentage = if target == 0 {
+            100.0
+        } else {
+            blocks_height as f64 * 100.0 / target as f64
+        };
+        SyncState {
+            headers_height,
+            blocks_height,
+            peer_best_height,
+            percentage,
+            blocks_per_sec,
+            syncing: blocks_height < target,
+        }
+    }
+}
+
+/// How badly a peer's address has behaved
+#[derive(Default)]
+struct Misbehavior {
+    score: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Changes to the chain not yet saved, while `sync` saves them in batches
+#[derive(Default)]
+struct UnsavedBlocks {
+    /// Blocks connected since the last save
+    connected: usize,
+    /// Lowest height one of them was connected at
+    lowest: Option<BlockHeight>,
+    /// Lowest fork height of the reorgs since the last save
+    fork_height: Option<BlockHeight>,
+    /// Blocks that joined a side branch, or were moved to one by a reorg
+    forks: Vec<Sha256Hash>,
+}
+
+/// Blocks whose parent hasn't arrived yet, oldest first. The node asks the
+/// peer that sent one for the headers after its own tip and then, with
+/// `GetBlocks`, the missing ancestors, connecting the orphan once they arrive.
+#[derive(Default)]
+struct OrphanBlocks {
+    blocks: HashMap<Sha256Hash, Block>,
+    order: VecDeque<Sha256Hash>,
+}
+
+impl OrphanBlocks {
+    fn insert(&mut self, block: Block) {
+        if self.blocks.contains_key(&block.hash) {
+            return;
+        }
+        self.order.push_back(block.hash);
+        self.blocks.insert(block.hash, block);
+        while self.order.len() > MAX_ORPHAN_BLOCKS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.blocks.remove(&oldest);
+            }
+        }
+    }
+
+    /// Remove and return the orphans whose parent is `parent`
+    fn take_children(&mut self, parent: &Sha256Hash) -> Vec<Block> {
+        let children: Vec<Sha256Hash> = self.blocks.values()
+            .filter(|block| block.header.previous_hash == *parent)
+            .map(|block| block.hash)
+            .collect();
+        self.order.retain(|hash| !children.contains(hash));
+        children.iter().filter_map(|hash| self.blocks.remove(hash)).collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// A connected peer, as the rest of the node sees it
+struct Session {
+    addr: String,
+    direction: Direction,
+    encrypted: bool,
+    connected_at: Instant,
+    outbox: mpsc::Sender<NetworkMessage>,
+    /// Inventory the peer announced or was sent
+    known: HashSet<InvItem>,
+    last_seen: i64,
+    /// When the oldest unanswered ping was sent
+    ping_sent: Option<Instant>,
+    latency: Option<Duration>,
+    /// Ends the session when notified
+    close: Arc<Notify>,
+}
+
+impl Session {
+    fn remember(&mut self, item: InvItem) {
+        if self.known.len() >= MAX_KNOWN_INVENTORY {
+            self.known.clear();
+        }
+        self.known.insert(item);
     }
 }
 
+#[derive(Clone)]
 pub struct NetworkNode {
     blockchain: Arc<RwLock<Blockchain>>,
     peers: Arc<RwLock<Vec<Node>>>,
+    sessions: Arc<Mutex<HashMap<u64, Session>>>,
+    next_session_id: Arc<AtomicU64>,
+    /// Set once the task announcing chain events to sessions is running
+    relaying: Arc<AtomicBool>,
+    /// The chain's tip, kept current by the relay task for `subscribe_tip`
+    tip: Arc<watch::Sender<ChainTip>>,
+    recent_transactions: Arc<Mutex<HashSet<Sha256Hash>>>,
+    /// Where blocks received from peers are saved, if anywhere
+    db: Option<Arc<Mutex<Database>>>,
+    /// Peers synced with, and the file they are saved to, if any
+    peer_store: Option<(PathBuf, Arc<Mutex<PeerStore>>)>,
+    params: ChainParams,
+    /// Set once `start_server` is listening
+    listen_port: Arc<AtomicU16>,
+    /// Addresses `start_server` or `listen` bound
+    listen_addrs: Arc<Mutex<Vec<SocketAddr>>>,
+    /// Addresses peers are told they can reach this node at, besides any
+    /// routable address it listens on
+    external_addrs: Vec<Node>,
+    misbehavior: Arc<Mutex<HashMap<std::net::IpAddr, Misbehavior>>>,
+    orphans: Arc<Mutex<OrphanBlocks>>,
+    unsaved: Arc<Mutex<UnsavedBlocks>>,
+    /// Highest best height a peer has reported
+    peer_best_height: Arc<AtomicU64>,
+    /// When blocks were connected from peers, and how many, within `SYNC_RATE_WINDOW`
+    synced: Arc<Mutex<VecDeque<(Instant, usize)>>>,
+    /// Firewall, rate limits and the key peers are authenticated with, if any
+    security: Option<Arc<SecurityManager>>,
+    max_inbound: usize,
+    /// Inbound connections open, handshaken or not
+    inbound: Arc<AtomicUsize>,
 }
 
 impl NetworkNode {
     pub fn new(blockchain: Blockchain, _db_path: String) -> Self {
+        let tip = ChainTip::of(&blockchain);
         NetworkNode {
             blockchain: Arc::new(RwLock::new(blockchain)),
             peers: Arc::new(RwLock::new(Vec::new())),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            next_session_id: Arc::new(AtomicU64::new(0)),
+            relaying: Arc::new(AtomicBool::new(false)),
+            tip: Arc::new(watch::channel(tip).0),
+            recent_transactions: Arc::new(Mutex::new(HashSet::new())),
+            db: None,
+            peer_store: None,
+            params: ChainParams::MAINNET,
+            listen_port: Arc::new(AtomicU16::new(0)),
+            listen_addrs: Arc::new(Mutex::new(Vec::new())),
+            external_addrs: Vec::new(),
+            misbehavior: Arc::new(Mutex::new(HashMap::new())),
+            orphans: Arc::new(Mutex::new(OrphanBlocks::default())),
+            unsaved: Arc::new(Mutex::new(UnsavedBlocks::default())),
+            peer_best_height: Arc::new(AtomicU64::new(0)),
+            synced: Arc::new(Mutex::new(VecDeque::new())),
+            security: None,
+            max_inbound: DEFAULT_MAX_INBOUND_PEERS,
+            inbound: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Join the network `params` describes instead of the main network
+    pub fn with_params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Start from the peers saved in `path`, and save each peer synced with there
+    pub fn with_peers_file(mut self, path: PathBuf) -> Result<Self, ChainError> {
+        let store = PeerStore::load(&path)?;
+        self.peers = Arc::new(RwLock::new(store.peers.clone()));
+        self.peer_store = Some((path, Arc::new(Mutex::new(store))));
+        Ok(self)
+    }
+
+    /// Apply `security`'s firewall rules and rate limits to every connection,
+    /// and authenticate peers with its key if its policy requires it
+    pub fn with_security(mut self, security: SecurityManager) -> Self {
+        self.security = Some(Arc::new(security));
+        self
+    }
+
+    /// Accept at most `max` inbound connections, evicting or refusing peers beyond that
+    pub fn with_max_inbound(mut self, max: usize) -> Self {
+        self.max_inbound = max;
+        self
+    }
+
+    /// Tell peers this node is reachable at `addr`, such as a public address
+    /// forwarded to it from behind NAT
+    pub fn with_external_address(mut self, addr: Node) -> Self {
+        if !self.external_addrs.iter().any(|known| known.addr() == addr.addr()) {
+            self.external_addrs.push(addr);
+        }
+        self
+    }
+
+    /// Whether peers must answer this node's authentication challenge
+    pub fn requires_auth(&self) -> bool {
+        self.security.as_ref().is_some_and(|security| security.network_policy().read().requires_auth())
+    }
+
+    /// Public key this node authenticates to peers with, if it has one
+    pub fn public_key(&self) -> Option<Vec<u8>> {
+        self.security.as_ref().map(|security| security.public_key())
+    }
+
+    /// Save every block this node connects from a peer, with the state after it
+    pub fn with_database(mut self, db: Arc<Mutex<Database>>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Remember a peer to broadcast to, without connecting to it yet
+    pub async fn add_peer(&self, peer: Node) {
+        let mut peers = self.peers.write().await;
+        if !peers.iter().any(|p| p.addr() == peer.addr()) && peers.len() < MAX_KNOWN_PEERS {
+            peers.push(peer);
+        }
+    }
+
+    /// Addresses of every peer this node knows of
+    pub async fn known_peers(&self) -> Vec<Node> {
+        self.peers.read().await.clone()
+    }
+
+    /// Peers worth connecting to: known peers, most recently learned first,
+    /// then the network's seed nodes
+    pub async fn candidates(&self, params: &ChainParams) -> Vec<Node> {
+        let mut candidates: Vec<Node> = self.known_peers().await.into_iter().rev().collect();
+        for seed in params.seed_nodes.iter().filter_map(|seed| Node::parse(seed).ok()) {
+            if !candidates.iter().any(|peer| peer.addr() == seed.addr()) {
+                candidates.push(seed);
+            }
+        }
+        candidates
+    }
+
+    /// Connect to known peers, then to the network's seed nodes if those run
+    /// out, until `target` peers are connected. Addresses learned from each
+    /// peer are tried before falling back further. Returns how many connected.
+    pub async fn bootstrap(&self, params: &ChainParams, target: usize) -> usize {
+        let mut tried = HashSet::new();
+        let mut connected = 0;
+        while connected < target {
+            let candidates = self.candidates(params).await;
+            let Some(peer) = candidates.into_iter().find(|peer| !tried.contains(&peer.addr())) else {
+                break;
+            };
+            tried.insert(peer.addr());
+            match self.connect_peer(peer.host.clone(), peer.port).await {
+                Ok(()) => connected += 1,
+                Err(e) => eprintln!("❌ Failed to connect to {}: {}", peer.addr(), e),
+            }
         }
+        connected
     }
-    
+
+    /// Accept peers on `port` on every interface, over IPv4 and, if the
+    /// host has it, IPv6
     pub async fn start_server(&self, port: u16) -> Result<(), ChainError> {
-        let addr = format!("0.0.0.0:{}", port);
-        let listener = TcpListener::bind(&addr).await
-            .map_err(|e| ChainError::NetworkError(format!("Failed to bind: {}", e)))?;
-        
-        println!("🌐 Node listening on {}", addr);
-        
+        let mut listeners = vec![bind_listener(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))?];
+        match bind_listener(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => println!("⚠️  Not listening on IPv6: {}", e),
+        }
+        self.serve(listeners).await
+    }
+
+    /// Accept peers on each of `addrs`, failing if any can't be bound
+    pub async fn listen(&self, addrs: &[SocketAddr]) -> Result<(), ChainError> {
+        let listeners = addrs.iter().map(|addr| bind_listener(*addr)).collect::<Result<Vec<_>, _>>()?;
+        self.serve(listeners).await
+    }
+
+    /// Addresses this node is listening on
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        self.listen_addrs.lock().unwrap().clone()
+    }
+
+    /// Addresses peers can reach this node at: the external ones it was
+    /// given, then those it listens on that aren't a wildcard or loopback.
+    /// Sent to each peer it connects to, and first in its answers to `GetAddr`.
+    pub fn advertised_addresses(&self) -> Vec<Node> {
+        let mut addresses = self.external_addrs.clone();
+        for addr in self.listen_addrs() {
+            let ip = addr.ip();
+            let node = Node::new(ip.to_string(), addr.port());
+            if !ip.is_unspecified() && !ip.is_loopback() && !addresses.iter().any(|known| known.addr() == node.addr()) {
+                addresses.push(node);
+            }
+        }
+        addresses
+    }
+
+    /// Accept peers on every listener for as long as the node runs
+    async fn serve(&self, listeners: Vec<TcpListener>) -> Result<(), ChainError> {
+        let mut accepting = tokio::task::JoinSet::new();
+        for listener in listeners {
+            let addr = listener.local_addr()
+                .map_err(|e| ChainError::NetworkError(format!("Failed to bind: {}", e)))?;
+            println!("🌐 Node listening on {}", addr);
+            // Peers dial back the port of the first address
+            let _ = self.listen_port.compare_exchange(0, addr.port(), Ordering::Relaxed, Ordering::Relaxed);
+            self.listen_addrs.lock().unwrap().push(addr);
+            let node = self.clone();
+            accepting.spawn(async move { node.accept(listener).await });
+        }
+        while accepting.join_next().await.is_some() {}
+        Ok(())
+    }
+
+    /// Start a session with each peer that connects to `listener`, refusing
+    /// banned peers, addresses the firewall rules refuse, and newcomers when
+    /// at the inbound limit with no peer to evict
+    async fn accept(&self, listener: TcpListener) {
         loop {
             match listener.accept().await {
                 Ok((socket, peer_addr)) => {
+                    if self.is_banned(&peer_addr.to_string()) {
+                        println!("🚫 Refused connection from banned peer {}", peer_addr);
+                        continue;
+                    }
+                    if let Err(e) = self.check_peer_allowed(&peer_addr.to_string()) {
+                        println!("🚫 Refused connection from {}: {}", peer_addr, e);
+                        continue;
+                    }
+                    if self.inbound.load(Ordering::Relaxed) >= self.max_inbound && !self.evict_inbound() {
+                        println!("🚫 Refused connection from {}: at the limit of {} inbound peers", peer_addr, self.max_inbound);
+                        continue;
+                    }
                     println!("📡 New connection from {}", peer_addr);
-                    let blockchain = self.blockchain.clone();
-                    let peers = self.peers.clone();
-                    
-                    tokio::spawn(async move {
-                        if let Err(e) = handle_connection(socket, blockchain, peers).await {
-                            eprintln!("❌ Connection error: {}", e);
-                        }
-                    });
+                    self.spawn_session(socket, peer_addr.to_string(), Direction::Inbound);
                 }
                 Err(e) => {
                     eprintln!("❌ Accept error: {}", e);
@@ -62,230 +589,635 @@ impl NetworkNode {
             }
         }
     }
-    
+
+    /// Sync with a peer, learn its peers, then keep a gossip session open to it
     pub async fn connect_peer(&self, host: String, port: u16) -> Result<(), ChainError> {
-        let addr = format!("{}:{}", host, port);
+        let addr = Node::new(host.clone(), port).addr();
+        if self.is_banned(&addr) {
+            return Err(ChainError::NetworkError(format!("Peer {} is banned", addr)));
+        }
         println!("🔗 Connecting to peer: {}", addr);
 
-        let mut stream = TcpStream::connect(&addr).await
-            .map_err(|e| ChainError::NetworkError(format!("Failed to connect: {}", e)))?;
-
-        // 1. Get remote headers
-        let local_height = self.get_height().await;
-        let request = NetworkMessage::GetBlockHeaders { after_height: local_height };
-        let data = bincode::serialize(&request)
-            .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
-
-        let len = data.len() as u32;
-        stream.write_all(&len.to_be_bytes()).await
-            .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-        stream.write_all(&data).await
-            .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-
-        let mut len_bytes = [0u8; 4];
-        stream.read_exact(&mut len_bytes).await
-            .map_err(|e| ChainError::NetworkError(format!("Read failed: {}", e)))?;
-        let len = u32::from_be_bytes(len_bytes) as usize;
-
-        let mut buffer = vec![0u8; len];
-        stream.read_exact(&mut buffer).await
-            .map_err(|e| ChainError::NetworkError(format!("Read failed: {}", e)))?;
-
-        let response: NetworkMessage = bincode::deserialize(&buffer)
-            .map_err(|e| ChainError::NetworkError(format!("Deserialization failed: {}", e)))?;
-
-        let remote_headers = match response {
-            NetworkMessage::BlockHeaders(headers) => headers,
-            _ => return Err(ChainError::NetworkError("Unexpected response".to_string())),
-        };
+        let stream = self.dial(&addr).await?;
+        let mut stream = self.secure(stream, Direction::Outbound).await.inspect_err(|e| self.punish(&addr, e))?;
+        let version = self.handshake(&mut stream, &addr).await.inspect_err(|e| self.punish(&addr, e))?;
 
-        if remote_headers.is_empty() {
-            println!("✅ Already up to date");
-            return Ok(());
+        match self.sync(&mut stream).await.inspect_err(|e| self.punish(&addr, e))? {
+            0 => println!("✅ Already up to date"),
+            connected => println!("✅ Synced {} blocks", connected),
         }
 
-        println!("📥 Found {} new block headers", remote_headers.len());
-
-        // 2. Request missing blocks in batches (50 blocks at a time for efficiency)
-        const BATCH_SIZE: usize = 50;
-        let block_hashes: Vec<_> = remote_headers.iter()
-            .map(|h| h.calculate_hash())
-            .collect();
-
-        for chunk in block_hashes.chunks(BATCH_SIZE) {
-            let mut stream = TcpStream::connect(&addr).await
-                .map_err(|e| ChainError::NetworkError(format!("Failed to connect: {}", e)))?;
+        // Tell the peer where to reach us, then learn its peers
+        let ours = self.advertised_addresses();
+        if !ours.is_empty() {
+            write_message(&mut stream, self.params.magic, &NetworkMessage::Addr(ours)).await?;
+        }
+        let new_peers = exchange(&mut stream, self.params.magic, NetworkMessage::GetAddr, |message| match message {
+            NetworkMessage::Addr(peers) => Some(peers),
+            _ => None,
+        }).await?;
+        self.learn_addresses(new_peers).await;
 
-            let request = NetworkMessage::GetBlocks(chunk.to_vec());
-            let data = bincode::serialize(&request)
-                .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
+        let peer = Node::new(host, port);
+        self.add_peer(peer.clone()).await;
+        if let Err(e) = self.save_good_peer(peer) {
+            eprintln!("❌ Failed to save peer: {}", e);
+        }
 
-            let len = data.len() as u32;
-            stream.write_all(&len.to_be_bytes()).await
-                .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-            stream.write_all(&data).await
-                .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
+        // Stay connected to hear about new blocks and transactions
+        self.spawn_handshaken_session(stream, addr, Direction::Outbound, version);
 
-            let mut len_bytes = [0u8; 4];
-            stream.read_exact(&mut len_bytes).await
-                .map_err(|e| ChainError::NetworkError(format!("Read failed: {}", e)))?;
-            let len = u32::from_be_bytes(len_bytes) as usize;
+        Ok(())
+    }
 
-            let mut buffer = vec![0u8; len];
-            stream.read_exact(&mut buffer).await
-                .map_err(|e| ChainError::NetworkError(format!("Read failed: {}", e)))?;
+    /// Download the blocks a peer has that this node lacks: headers after
+    /// this chain's block locator first, then the blocks for them in
+    /// batches, each connected as it arrives and saved `SYNC_SAVE_BATCH` at
+    /// a time. Returns how many were connected.
+    pub async fn sync<S>(&self, stream: &mut S) -> Result<usize, ChainError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let result = self.download_blocks(stream).await;
+        // Save the last batch, even if the peer failed part way through it
+        self.persisting(&mut *self.blockchain.write().await, |_| Ok(()))?;
+        result
+    }
 
-            let response: NetworkMessage = bincode::deserialize(&buffer)
-                .map_err(|e| ChainError::NetworkError(format!("Deserialization failed: {}", e)))?;
+    async fn download_blocks<S>(&self, stream: &mut S) -> Result<usize, ChainError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut connected = 0;
+        loop {
+            let locator = self.blockchain.read().await.block_locator();
+            let headers = exchange(stream, self.params.magic, NetworkMessage::GetHeaders { locator }, |message| match message {
+                NetworkMessage::BlockHeaders(headers) => Some(headers),
+                _ => None,
+            }).await?;
+            if headers.is_empty() {
+                return Ok(connected);
+            }
+            let more = headers.len() >= MAX_HEADERS_PER_MESSAGE;
+            println!("📥 Found {} new block headers", headers.len());
 
-            if let NetworkMessage::Blocks(blocks) = response {
+            // Validate the headers before downloading any block bodies
+            {
                 let mut chain = self.blockchain.write().await;
+                for header in headers {
+                    chain.accept_header(header)
+                        .map_err(|e| ChainError::NetworkError(format!("Rejected header: {}", e)))?;
+                }
+            }
 
-                println!("📥 Received batch of {} blocks", blocks.len());
+            loop {
+                let wanted = self.blockchain.read().await.missing_block_hashes(BLOCKS_PER_REQUEST);
+                if wanted.is_empty() {
+                    break;
+                }
+                let blocks = exchange(stream, self.params.magic, NetworkMessage::GetBlocks(wanted), |message| match message {
+                    NetworkMessage::Blocks(blocks) => Some(blocks),
+                    _ => None,
+                }).await?;
+                if blocks.is_empty() {
+                    return Err(ChainError::NetworkError("Peer has no blocks for the headers it sent".to_string()));
+                }
 
-                for block in blocks {
-                    chain.apply_block(block)
-                        .map_err(|e| ChainError::NetworkError(format!("Failed to apply block: {}", e)))?;
+                let count = blocks.len();
+                connected += count;
+                let mut chain = self.blockchain.write().await;
+                self.persisting_in_batches(&mut chain, SYNC_SAVE_BATCH, |chain| {
+                    for block in blocks {
+                        chain.connect_block_for_header(block)
+                            .map_err(|e| ChainError::NetworkError(format!("Failed to apply block: {}", e)))?;
+                    }
+                    Ok(())
+                })?;
+                self.record_synced(count);
+                let state = self.sync_state_of(&chain);
+                println!("📥 Received batch of {} blocks ({:.1}% synced)", count, state.percentage);
+                if let Err(e) = self.save_sync_state(&state) {
+                    eprintln!("❌ Failed to save sync state: {}", e);
                 }
+            }
 
-                println!("✅ Applied batch successfully");
+            if !more {
+                return Ok(connected);
             }
         }
+    }
+
+    /// Encrypt the connection to the peer on `stream` if this node can,
+    /// handshake, then run a gossip session with it in the background
+    pub fn spawn_session<S>(&self, stream: S, addr: String, direction: Direction)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let node = self.clone();
+        if direction == Direction::Inbound {
+            node.inbound.fetch_add(1, Ordering::Relaxed);
+        }
+        tokio::spawn(async move {
+            let result = async {
+                let mut stream = node.secure(stream, direction).await?;
+                let version = node.handshake(&mut stream, &addr).await?;
+                node.run_session(stream, addr.clone(), direction, version).await
+            }.await;
+            if direction == Direction::Inbound {
+                node.inbound.fetch_sub(1, Ordering::Relaxed);
+            }
+            if let Err(e) = result {
+                eprintln!("❌ Session with {} ended: {}", addr, e);
+                node.punish(&addr, &e);
+            }
+        });
+    }
 
-        // 3. Get peers from remote
-        let mut stream = TcpStream::connect(&addr).await
-            .map_err(|e| ChainError::NetworkError(format!("Failed to connect: {}", e)))?;
+    /// Run a gossip session in the background with a peer already handshaken with
+    pub(crate) fn spawn_handshaken_session<S>(&self, stream: PeerStream<S>, addr: String, direction: Direction, version: VersionMessage)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let node = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = node.run_session(stream, addr.clone(), direction, version).await {
+                eprintln!("❌ Session with {} ended: {}", addr, e);
+                node.punish(&addr, &e);
+            }
+        });
+    }
+
+    /// Count `score` against the peer at `addr`, banning its IP address for
+    /// `BAN_DURATION` once its total reaches `BAN_THRESHOLD`
+    pub fn penalize(&self, addr: &str, score: u32) {
+        let Some(ip) = peer_ip(addr) else {
+            return;
+        };
+        let mut misbehavior = self.misbehavior.lock().unwrap();
+        let record = misbehavior.entry(ip).or_default();
+        record.score = record.score.saturating_add(score);
+        if record.score >= BAN_THRESHOLD && record.banned_until.is_none() {
+            println!("🚫 Banning {} for misbehaving", ip);
+            record.banned_until = Some(Instant::now() + BAN_DURATION);
+        }
+    }
 
-        let request = NetworkMessage::GetPeers;
-        let data = bincode::serialize(&request)
-            .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
+    /// Close the inbound session least worth keeping to make room for
+    /// another. False if every inbound peer is spared (see `select_eviction`).
+    fn evict_inbound(&self) -> bool {
+        let sessions: Vec<(u64, String, Instant, Option<Duration>)> = self.sessions.lock().unwrap().iter()
+            .filter(|(_, session)| session.direction == Direction::Inbound)
+            .map(|(&id, session)| (id, session.addr.clone(), session.connected_at, session.latency))
+            .collect();
+        let candidates = {
+            let misbehavior = self.misbehavior.lock().unwrap();
+            sessions.into_iter().map(|(id, addr, connected_at, latency)| {
+                let ip = peer_ip(&addr);
+                EvictionCandidate {
+                    id,
+                    connected_at,
+                    latency,
+                    misbehavior: ip.and_then(|ip| misbehavior.get(&ip)).map_or(0, |record| record.score),
+                    netgroup: ip.map(netgroup).unwrap_or_default(),
+                }
+            }).collect()
+        };
 
-        let len = data.len() as u32;
-        stream.write_all(&len.to_be_bytes()).await
-            .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-        stream.write_all(&data).await
-            .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
+        let Some(id) = select_eviction(candidates) else {
+            return false;
+        };
+        if let Some(session) = self.sessions.lock().unwrap().get(&id) {
+            println!("👋 Evicting peer {} to make room", session.addr);
+            session.close.notify_one();
+        }
+        true
+    }
 
-        let mut len_bytes = [0u8; 4];
-        stream.read_exact(&mut len_bytes).await
-            .map_err(|e| ChainError::NetworkError(format!("Read failed: {}", e)))?;
-        let len = u32::from_be_bytes(len_bytes) as usize;
+    /// Whether connections to or from `addr` are refused
+    pub fn is_banned(&self, addr: &str) -> bool {
+        let Some(ip) = peer_ip(addr) else {
+            return false;
+        };
+        let mut misbehavior = self.misbehavior.lock().unwrap();
+        match misbehavior.get(&ip).and_then(|record| record.banned_until) {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                misbehavior.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
 
-        let mut buffer = vec![0u8; len];
-        stream.read_exact(&mut buffer).await
-            .map_err(|e| ChainError::NetworkError(format!("Read failed: {}", e)))?;
+    /// The SOCKS5 proxy outbound connections go through, if any
+    pub fn socks5_proxy(&self) -> Option<String> {
+        let security = self.security.as_ref()?;
+        let proxy = security.network_policy().read().get_socks5_proxy().map(str::to_string);
+        proxy
+    }
 
-        let response: NetworkMessage = bincode::deserialize(&buffer)
-            .map_err(|e| ChainError::NetworkError(format!("Deserialization failed: {}", e)))?;
+    /// Save the proxy outbound connections go through, for other processes
+    /// such as the API to report
+    pub fn save_proxy_status(&self) -> Result<(), ChainError> {
+        match &self.db {
+            Some(db) => db.lock().unwrap().save_socks5_proxy(self.socks5_proxy().as_deref()),
+            None => Ok(()),
+        }
+    }
 
-        if let NetworkMessage::Peers(new_peers) = response {
-            let mut local_peers = self.peers.write().await;
-            for peer in new_peers {
-                if !local_peers.iter().any(|p| p.addr() == peer.addr()) {
-                    println!("Discovered new peer: {}", peer.addr());
-                    local_peers.push(peer);
+    /// Open a TCP connection to `addr`, through the SOCKS5 proxy if there is
+    /// one, and check the firewall allows the peer. Behind a proxy only peers
+    /// given by IP address can be checked. The proxy resolves host names
+    /// itself, so `.onion` peers are reachable through Tor.
+    async fn dial(&self, addr: &str) -> Result<TcpStream, ChainError> {
+        let connect = async {
+            match self.socks5_proxy() {
+                Some(proxy) => {
+                    if addr.parse::<std::net::SocketAddr>().is_ok() {
+                        self.check_peer_allowed(addr)?;
+                    }
+                    let peer = Node::parse(addr)?;
+                    tokio_socks::tcp::Socks5Stream::connect(proxy.as_str(), (peer.host.as_str(), peer.port)).await
+                        .map(tokio_socks::tcp::Socks5Stream::into_inner)
+                        .map_err(|e| ChainError::NetworkError(format!("Failed to connect through proxy {}: {}", proxy, e)))
+                }
+                None if Node::parse(addr)?.host.ends_with(".onion") => Err(ChainError::NetworkError(format!(
+                    "{} is only reachable through Tor; set SIERTRI_SOCKS5_PROXY", addr
+                ))),
+                None => {
+                    let stream = TcpStream::connect(addr).await
+                        .map_err(|e| ChainError::NetworkError(format!("Failed to connect: {}", e)))?;
+                    if let Ok(remote) = stream.peer_addr() {
+                        self.check_peer_allowed(&remote.to_string())?;
+                    }
+                    Ok(stream)
                 }
             }
-        }
+        };
+        tokio::time::timeout(CONNECT_TIMEOUT, connect).await
+            .map_err(|_| ChainError::NetworkError("Timed out connecting".to_string()))?
+    }
 
-        let mut peers = self.peers.write().await;
-        let peer = Node::new(host, port);
-        if !peers.iter().any(|p| p.addr() == peer.addr()) {
-            peers.push(peer);
+    /// Whether the firewall rules and rate limits let this node talk to the
+    /// peer at `addr`, an `ip:port` address
+    fn check_peer_allowed(&self, addr: &str) -> Result<(), ChainError> {
+        match &self.security {
+            Some(security) => security.check_peer_allowed(addr),
+            None => Ok(()),
         }
+    }
 
+    /// Hold off reading from the peer at `addr` for a while if a message of
+    /// `bytes` puts it over its message rate or bandwidth limit, penalizing
+    /// it each time. Fails once that gets the peer banned.
+    async fn throttle(&self, addr: &str, bytes: usize) -> Result<(), ChainError> {
+        let Some(security) = &self.security else {
+            return Ok(());
+        };
+        let Err(e) = security.check_peer_traffic(addr, bytes) else {
+            return Ok(());
+        };
+        self.penalize(addr, THROTTLE_PENALTY);
+        if self.is_banned(addr) {
+            return Err(ChainError::NetworkError(format!("Disconnected for flooding: {}", e)));
+        }
+        println!("🐢 Throttling {}: {}", addr, e);
+        tokio::time::sleep(THROTTLE_DELAY).await;
         Ok(())
     }
-    
-    pub async fn broadcast_transaction(&self, tx: &crate::transaction::Transaction) -> Result<(), ChainError> {
-        let peers = self.peers.read().await;
-        let message = NetworkMessage::NewTransaction(Box::new(tx.clone()));
-        let data = bincode::serialize(&message)
-            .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
 
-        for peer in peers.iter() {
-            let mut stream = match TcpStream::connect(peer.addr()).await {
-                Ok(stream) => stream,
-                Err(e) => {
-                    eprintln!("❌ Failed to connect to peer {}: {}", peer.addr(), e);
-                    continue;
-                }
-            };
+    /// Penalize a peer whose connection ended with `error`, if it was the
+    /// peer's fault: sending a frame longer than `MAX_MESSAGE_SIZE`, failing
+    /// its checksum or not decoding as the command it names, or failing
+    /// authentication. A frame from another network just ends the connection.
+    fn punish(&self, addr: &str, error: &ChainError) {
+        let score = match error {
+            ChainError::OversizedMessage { .. } => BAN_THRESHOLD,
+            ChainError::MalformedMessage(_) => BAN_THRESHOLD / 2,
+            // A third failed authentication gets the peer banned
+            ChainError::AuthenticationError(_) => BAN_THRESHOLD.div_ceil(3),
+            _ => 0,
+        };
+        if score > 0 {
+            self.penalize(addr, score);
+        }
+    }
 
-            let len = data.len() as u32;
-            if let Err(e) = stream.write_all(&len.to_be_bytes()).await {
-                eprintln!("❌ Failed to write to peer {}: {}", peer.addr(), e);
-                continue;
-            }
-            if let Err(e) = stream.write_all(&data).await {
-                eprintln!("❌ Failed to write to peer {}: {}", peer.addr(), e);
-                continue;
+    /// Encrypt a connection this node opened if it has a key, or one a peer
+    /// opened if the peer asks to. Plaintext from a peer is refused if this
+    /// node's policy requires encryption.
+    async fn secure<S>(&self, stream: S, direction: Direction) -> Result<PeerStream<S>, ChainError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Some(security) = &self.security else {
+            return Ok(PeerStream::plain(stream));
+        };
+        let key = security.noise_static_key();
+        let magic = self.params.magic;
+        let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+            match direction {
+                Direction::Outbound => PeerStream::connect(stream, &key, &magic).await,
+                Direction::Inbound => PeerStream::accept(stream, &key, &magic).await,
             }
-            println!("📢 Broadcasted transaction to {}", peer.addr());
+        }).await.map_err(|_| ChainError::NetworkError("Timed out waiting for the encryption handshake".to_string()))??;
+        if !stream.is_encrypted() && security.network_policy().read().requires_encryption() {
+            return Err(ChainError::IncompatiblePeer("did not encrypt the connection".to_string()));
         }
-
-        Ok(())
+        Ok(stream)
     }
 
-    pub async fn broadcast_block(&self, block: &crate::blockchain::Block) -> Result<(), ChainError> {
-        let peers = self.peers.read().await;
-        let message = NetworkMessage::NewBlock(Box::new(block.clone()));
-        let data = bincode::serialize(&message)
-            .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
+    /// The version this node announces. Its capabilities say whether the node
+    /// keeps every block's transactions and whether it requires authentication.
+    pub async fn version_message(&self) -> VersionMessage {
+        let chain = self.blockchain.read().await;
+        VersionMessage {
+            version: PROTOCOL_VERSION,
+            magic: self.params.magic,
+            genesis_hash: chain.blocks[0].hash,
+            best_height: chain.blocks.last().unwrap().header.height,
+            capabilities: if chain.pruned_height == 0 { CAP_FULL_HISTORY } else { 0 }
+                | if self.requires_auth() { CAP_AUTH_REQUIRED } else { 0 },
+            listen_port: self.listen_port.load(Ordering::Relaxed),
+            user_agent: format!("siertrichain/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
 
-        for peer in peers.iter() {
-            let mut stream = match TcpStream::connect(peer.addr()).await {
-                Ok(stream) => stream,
-                Err(e) => {
-                    eprintln!("❌ Failed to connect to peer {}: {}", peer.addr(), e);
-                    continue;
-                }
+    /// Exchange `Version` and `Verack` with the peer at `addr` on `stream`,
+    /// then authenticate each other if either side requires it, returning
+    /// the peer's version. Fails, before any other message is exchanged, if
+    /// the peer is on another network or chain, speaks too old a protocol, or
+    /// fails authentication.
+    pub async fn handshake<S>(&self, stream: &mut PeerStream<S>, addr: &str) -> Result<VersionMessage, ChainError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let ours = self.version_message().await;
+        let magic = self.params.magic;
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+            write_message(stream, magic, &NetworkMessage::Version(ours.clone())).await?;
+            let theirs = match read_message(stream, magic).await? {
+                Some(NetworkMessage::Version(theirs)) => theirs,
+                Some(_) => return Err(ChainError::IncompatiblePeer("spoke before sending its version".to_string())),
+                None => return Err(ChainError::NetworkError("Peer closed the connection".to_string())),
             };
+            check_version(&ours, &theirs)?;
+            self.peer_best_height.fetch_max(theirs.best_height, Ordering::Relaxed);
+            write_message(stream, magic, &NetworkMessage::Verack).await?;
+            match read_message(stream, magic).await? {
+                Some(NetworkMessage::Verack) => {}
+                Some(_) => return Err(ChainError::IncompatiblePeer("sent data before acknowledging our version".to_string())),
+                None => return Err(ChainError::NetworkError("Peer closed the connection".to_string())),
+            }
+            self.authenticate(stream, addr, theirs.capabilities & CAP_AUTH_REQUIRED != 0).await?;
+            Ok(theirs)
+        }).await.map_err(|_| ChainError::NetworkError("Timed out waiting for the handshake".to_string()))?
+    }
 
-            let len = data.len() as u32;
-            if let Err(e) = stream.write_all(&len.to_be_bytes()).await {
-                eprintln!("❌ Failed to write to peer {}: {}", peer.addr(), e);
-                continue;
+    /// Challenge the peer if this node requires authentication and answer its
+    /// challenge if `they_require` it. Both challenges are sent before either
+    /// is answered, so neither side waits on the other. Responses sign the
+    /// connection's handshake hash, so authenticating needs encryption.
+    async fn authenticate<S>(&self, stream: &mut PeerStream<S>, addr: &str, they_require: bool) -> Result<(), ChainError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if !self.requires_auth() && !they_require {
+            return Ok(());
+        }
+        let handshake_hash = stream.handshake_hash().map(<[u8]>::to_vec).ok_or_else(|| {
+            ChainError::IncompatiblePeer("did not encrypt the connection, so can't authenticate".to_string())
+        })?;
+        let magic = self.params.magic;
+        let ours = match &self.security {
+            Some(security) if self.requires_auth() => {
+                let challenge = security.create_challenge()?;
+                write_message(stream, magic, &NetworkMessage::AuthChallenge(challenge.clone())).await?;
+                Some((security, challenge))
             }
-            if let Err(e) = stream.write_all(&data).await {
-                eprintln!("❌ Failed to write to peer {}: {}", peer.addr(), e);
+            _ => None,
+        };
+
+        if they_require {
+            let security = self.security.as_ref().ok_or_else(|| {
+                ChainError::NetworkError("Peer requires authentication, but this node has no key".to_string())
+            })?;
+            let challenge = match read_message(stream, magic).await? {
+                Some(NetworkMessage::AuthChallenge(challenge)) => challenge,
+                Some(_) => return Err(ChainError::IncompatiblePeer("required authentication without a challenge".to_string())),
+                None => return Err(ChainError::NetworkError("Peer closed the connection".to_string())),
+            };
+            let response = security.create_auth_response(&challenge, &handshake_hash)?;
+            write_message(stream, magic, &NetworkMessage::AuthResponse(response)).await?;
+        }
+
+        if let Some((security, challenge)) = ours {
+            let response = match read_message(stream, magic).await? {
+                Some(NetworkMessage::AuthResponse(response)) => response,
+                Some(_) => return Err(ChainError::AuthenticationError("peer did not answer our challenge".to_string())),
+                None => return Err(ChainError::NetworkError("Peer closed the connection".to_string())),
+            };
+            security.verify_auth_response(addr, &challenge, &response, &handshake_hash)
+                .map_err(|e| ChainError::AuthenticationError(format!("{} failed our challenge: {}", addr, e)))?;
+            println!("🔐 Authenticated peer {}", addr);
+        }
+        Ok(())
+    }
+
+    /// Announce an item to every session whose peer doesn't already have it
+    pub fn announce(&self, item: InvItem) {
+        let mut sessions = self.sessions.lock().unwrap();
+        for session in sessions.values_mut() {
+            if session.known.contains(&item) {
                 continue;
             }
-            println!("📢 Broadcasted block {} to {}", block.header.height, peer.addr());
+            session.remember(item);
+            let _ = session.outbox.try_send(NetworkMessage::Inv(vec![item]));
         }
+    }
 
-        Ok(())
+    /// Addresses of the peers with an open session
+    pub fn session_addrs(&self) -> Vec<String> {
+        self.sessions.lock().unwrap().values().map(|session| session.addr.clone()).collect()
     }
 
-    pub async fn get_height(&self) -> u64 {
-        let chain = self.blockchain.read().await;
-        chain.blocks.last().map(|b| b.header.height).unwrap_or(0)
+    /// The connected peers
+    pub fn session_statuses(&self) -> Vec<PeerStatus> {
+        self.sessions.lock().unwrap().values().map(|session| PeerStatus {
+            address: session.addr.clone(),
+            state: PeerState::Connected,
+            direction: session.direction,
+            last_seen: Some(session.last_seen),
+            latency_ms: session.latency.map(|latency| latency.as_millis() as u64),
+            failures: 0,
+            retry_at: None,
+            encrypted: session.encrypted,
+        }).collect()
     }
 
-    /// Validates an entire blockchain by checking all blocks
-    #[allow(dead_code)]
-    fn validate_chain(chain: &Blockchain) -> bool {
-        if chain.blocks.is_empty() {
-            return false;
+    /// Ping every connected peer, keeping the time of any ping still unanswered
+    pub fn ping_sessions(&self) {
+        for session in self.sessions.lock().unwrap().values_mut() {
+            session.ping_sent.get_or_insert_with(Instant::now);
+            let _ = session.outbox.try_send(NetworkMessage::Ping);
         }
+    }
 
-        // Validate each block's proof of work and merkle root
-        for block in &chain.blocks {
-            if !block.verify_proof_of_work() {
-                println!("❌ Block {} has invalid proof of work", block.header.height);
-                return false;
+    /// Disconnect peers that left a ping unanswered for longer than `timeout`
+    pub fn drop_unresponsive_sessions(&self, timeout: Duration) {
+        for session in self.sessions.lock().unwrap().values() {
+            if session.ping_sent.is_some_and(|sent| sent.elapsed() > timeout) {
+                println!("⏱️  Peer {} stopped answering pings", session.addr);
+                session.close.notify_one();
             }
+        }
+    }
 
-            let calculated_merkle = crate::blockchain::Block::calculate_merkle_root(&block.transactions);
-            if block.header.merkle_root != calculated_merkle {
-                println!("❌ Block {} has invalid merkle root", block.header.height);
-                return false;
-            }
+    /// Save peer statuses where other processes, such as the API, can read them
+    pub fn save_peer_statuses(&self, statuses: &[PeerStatus]) -> Result<(), ChainError> {
+        match &self.db {
+            Some(db) => db.lock().unwrap().save_peer_statuses(statuses),
+            None => Ok(()),
         }
+    }
 
-        // Validate block linkage
-        for i in 1..chain.blocks.len() {
-            let prev = &chain.blocks[i - 1];
+    /// How far this node has caught up with its peers
+    pub async fn sync_state(&self) -> SyncState {
+        self.sync_state_of(&*self.blockchain.read().await)
+    }
+
+    fn sync_state_of(&self, chain: &Blockchain) -> SyncState {
+        let blocks_height = chain.blocks.last().map_or(0, |block| block.header.height);
+        SyncState::new(
+            chain.header_chain.best_height(),
+            blocks_height,
+            self.peer_best_height.load(Ordering::Relaxed),
+            self.blocks_per_sec(),
+        )
+    }
+
+    /// Count blocks just connected from peers towards the sync rate
+    fn record_synced(&self, count: usize) {
+        let mut synced = self.synced.lock().unwrap();
+        synced.push_back((Instant::now(), count));
+        while synced.front().is_some_and(|(at, _)| at.elapsed() > SYNC_RATE_WINDOW) {
+            synced.pop_front();
+        }
+    }
+
+    fn blocks_per_sec(&self) -> f64 {
+        let synced = self.synced.lock().unwrap();
+        let blocks: usize = synced.iter()
+            .filter(|(at, _)| at.elapsed() <= SYNC_RATE_WINDOW)
+            .map(|(_, count)| count)
+            .sum();
+        blocks as f64 / SYNC_RATE_WINDOW.as_secs_f64()
+    }
+
+    /// Save sync progress where other processes, such as the API, can read it
+    pub fn save_sync_state(&self, state: &SyncState) -> Result<(), ChainError> {
+        match &self.db {
+            Some(db) => db.lock().unwrap().save_sync_state(state),
+            None => Ok(()),
+        }
+    }
+
+    /// The chain this node syncs and gossips
+    pub fn blockchain(&self) -> Arc<RwLock<Blockchain>> {
+        self.blockchain.clone()
+    }
+
+    /// Push a transaction to every connected peer that doesn't have it yet,
+    /// and to each known peer without a session over a one-off connection
+    pub async fn broadcast_transaction(&self, tx: &crate::transaction::Transaction) -> Result<(), ChainError> {
+        let hash = tx.hash();
+        self.first_sighting(hash);
+        for addr in self.push(InvItem::Tx(hash), NetworkMessage::NewTransaction(Box::new(tx.clone()))).await {
+            println!("📢 Broadcasted transaction to {}", addr);
+        }
+        Ok(())
+    }
+
+    /// Push a block the same way as `broadcast_transaction`. Peers apply it,
+    /// save it and announce it to their own peers.
+    pub async fn broadcast_block(&self, block: &crate::blockchain::Block) -> Result<(), ChainError> {
+        for addr in self.push(InvItem::Block(block.hash), NetworkMessage::NewBlock(Box::new(block.clone()))).await {
+            println!("📢 Broadcasted block {} to {}", block.header.height, addr);
+        }
+        Ok(())
+    }
+
+    /// Send `message`, which carries `item`, to every peer that doesn't have
+    /// it yet, returning the peers reached over one-off connections
+    async fn push(&self, item: InvItem, message: NetworkMessage) -> Vec<String> {
+        let connected = {
+            let mut sessions = self.sessions.lock().unwrap();
+            for session in sessions.values_mut() {
+                if !session.known.contains(&item) {
+                    session.remember(item);
+                    let _ = session.outbox.try_send(message.clone());
+                }
+            }
+            sessions.values().map(|session| session.addr.clone()).collect::<HashSet<_>>()
+        };
+
+        let peers = self.peers.read().await;
+        let mut reached = Vec::new();
+        for peer in peers.iter().filter(|peer| !connected.contains(&peer.addr())) {
+            match self.send_once(&peer.addr(), &message).await {
+                Ok(()) => reached.push(peer.addr()),
+                Err(e) => eprintln!("❌ Failed to send to peer {}: {}", peer.addr(), e),
+            }
+        }
+        reached
+    }
+
+    /// Send one message on a fresh connection and close it
+    async fn send_once(&self, addr: &str, message: &NetworkMessage) -> Result<(), ChainError> {
+        let stream = self.dial(addr).await?;
+        let mut stream = self.secure(stream, Direction::Outbound).await?;
+        self.handshake(&mut stream, addr).await?;
+        write_message(&mut stream, self.params.magic, message).await?;
+        stream.shutdown().await
+            .map_err(|e| ChainError::NetworkError(format!("Failed to close connection: {}", e)))
+    }
+
+    pub async fn get_height(&self) -> u64 {
+        let chain = self.blockchain.read().await;
+        chain.blocks.last().map(|b| b.header.height).unwrap_or(0)
+    }
+
+    /// Validates an entire blockchain by checking all blocks
+    #[allow(dead_code)]
+    fn validate_chain(chain: &Blockchain) -> bool {
+        if chain.blocks.is_empty() {
+            return false;
+        }
+
+        // Validate each block's proof of work and merkle root
+        for block in &chain.blocks {
+            if !chain.pow.meets_target(&block.hash, block.header.bits) {
+                println!("❌ Block {} has invalid proof of work", block.header.height);
+                return false;
+            }
+
+            let transactions = match block.transactions.get() {
+                Ok(transactions) => transactions,
+                Err(e) => {
+                    println!("❌ Block {} has unreadable transactions: {}", block.header.height, e);
+                    return false;
+                }
+            };
+            let calculated_merkle = crate::blockchain::Block::calculate_merkle_root(transactions);
+            if block.header.merkle_root != calculated_merkle {
+                println!("❌ Block {} has invalid merkle root", block.header.height);
+                return false;
+            }
+        }
+
+        // Validate block linkage
+        for i in 1..chain.blocks.len() {
+            let prev = &chain.blocks[i - 1];
             let curr = &chain.blocks[i];
 
             if curr.header.height != prev.header.height + 1 {
@@ -301,11 +1233,473 @@ impl NetworkNode {
 
         true
     }
+
+    /// Hear of each new tip of this node's chain, whether its blocks came
+    /// from peers or were mined on it, without polling the chain or database
+    pub async fn subscribe_tip(&self) -> watch::Receiver<ChainTip> {
+        self.ensure_relay().await;
+        self.tip.subscribe()
+    }
+
+    /// Start announcing connected blocks and accepted transactions to every
+    /// session, and new tips to `subscribe_tip` receivers, if not already
+    async fn ensure_relay(&self) {
+        if self.relaying.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let mut events = {
+            let chain = self.blockchain.read().await;
+            self.set_tip(ChainTip::of(&chain));
+            chain.subscribe()
+        };
+        let node = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let item = match events.recv().await {
+                    Ok(ChainEvent::BlockConnected { hash, height }) => {
+                        // The last block connected in a reorganization is the new tip
+                        node.set_tip(ChainTip { hash, height });
+                        InvItem::Block(hash)
+                    }
+                    Ok(ChainEvent::TxAccepted(hash)) => InvItem::Tx(hash),
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        node.set_tip(ChainTip::of(&*node.blockchain.read().await));
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                node.announce(item);
+            }
+        });
+    }
+
+    /// Tell tip subscribers about `tip`, unless they already know
+    fn set_tip(&self, tip: ChainTip) {
+        self.tip.send_if_modified(|current| std::mem::replace(current, tip) != tip);
+    }
+
+    /// Answer a peer's messages until it disconnects. Replies and
+    /// announcements go out through the session's outbox, in order.
+    async fn run_session<S>(&self, stream: PeerStream<S>, addr: String, direction: Direction, version: VersionMessage) -> Result<(), ChainError>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        self.ensure_relay().await;
+
+        // A peer that dialed us and listens itself can be dialed back
+        if direction == Direction::Inbound && version.listen_port != 0 {
+            if let Ok(socket) = addr.parse::<std::net::SocketAddr>() {
+                self.add_peer(Node::new(socket.ip().to_string(), version.listen_port)).await;
+            }
+        }
+
+        let encrypted = stream.is_encrypted();
+        let peer_addr = addr.clone();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (outbox, mut inbox) = mpsc::channel(OUTBOX_CAPACITY);
+        let close = Arc::new(Notify::new());
+        let id = self.next_session_id.fetch_add(1, Ordering::Relaxed);
+        self.sessions.lock().unwrap().insert(id, Session {
+            addr,
+            direction,
+            encrypted,
+            connected_at: Instant::now(),
+            outbox: outbox.clone(),
+            known: HashSet::new(),
+            last_seen: chrono::Utc::now().timestamp(),
+            ping_sent: None,
+            latency: None,
+            close: close.clone(),
+        });
+
+        let magic = self.params.magic;
+        let writer_task = tokio::spawn(async move {
+            while let Some(message) = inbox.recv().await {
+                if write_message(&mut writer, magic, &message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let result = loop {
+            let read = tokio::select! {
+                read = read_frame(&mut reader, magic) => read,
+                _ = close.notified() => break Ok(()),
+            };
+            match read {
+                Ok(Some((message, size))) => {
+                    if let Err(e) = self.throttle(&peer_addr, size).await {
+                        break Err(e);
+                    }
+                    if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+                        session.last_seen = chrono::Utc::now().timestamp();
+                    }
+                    // Replies wait for room in the outbox, so a peer that stops reading can still be closed
+                    let handled = tokio::select! {
+                        handled = self.handle_message(id, message, &outbox) => handled,
+                        _ = close.notified() => break Ok(()),
+                    };
+                    if let Err(e) = handled {
+                        break Err(e);
+                    }
+                }
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+
+        // Let the writer flush what is queued, then stop once every outbox is gone
+        self.sessions.lock().unwrap().remove(&id);
+        if let Some(security) = &self.security {
+            security.forget_peer_traffic(&peer_addr);
+        }
+        drop(outbox);
+        let _ = writer_task.await;
+        result
+    }
+
+    /// Run `update` on the chain, then save the blocks it connected, along
+    /// with any left unsaved before, and the state they leave behind, in one
+    /// transaction. A reorg is saved in one go, its branch replacing the
+    /// blocks it displaced. Blocks it put on side branches are saved too.
+    fn persisting<T>(
+        &self,
+        chain: &mut Blockchain,
+        update: impl FnOnce(&mut Blockchain) -> Result<T, ChainError>,
+    ) -> Result<T, ChainError> {
+        self.persisting_in_batches(chain, 1, update)
+    }
+
+    /// `persisting`, but leaving what `update` connected unsaved until at
+    /// least `batch` blocks are
+    fn persisting_in_batches<T>(
+        &self,
+        chain: &mut Blockchain,
+        batch: usize,
+        update: impl FnOnce(&mut Blockchain) -> Result<T, ChainError>,
+    ) -> Result<T, ChainError> {
+        let Some(db) = &self.db else {
+            return update(chain);
+        };
+        // Nothing else can publish while the chain is borrowed mutably here
+        let mut events = chain.subscribe();
+        let result = update(chain);
+
+        let mut unsaved = self.unsaved.lock().unwrap();
+        while let Ok(event) = events.try_recv() {
+            match event {
+                ChainEvent::BlockConnected { height, .. } => {
+                    unsaved.connected += 1;
+                    unsaved.lowest = Some(unsaved.lowest.map_or(height, |lowest| lowest.min(height)));
+                }
+                ChainEvent::ReorgCompleted { fork_height, .. } => {
+                    unsaved.fork_height = Some(unsaved.fork_height.map_or(fork_height, |lowest| lowest.min(fork_height)));
+                }
+                ChainEvent::ForkBlockAccepted { hash, .. } | ChainEvent::BlockDisconnected { hash, .. } => {
+                    unsaved.forks.push(hash);
+                }
+                _ => {}
+            }
+        }
+        if unsaved.connected + unsaved.forks.len() < batch.max(1) {
+            return result;
+        }
+
+        let db = db.lock().unwrap();
+        // Every block from the lowest connected up is the main chain's now
+        if let Some(lowest) = unsaved.lowest {
+            match unsaved.fork_height {
+                Some(fork_height) => {
+                    let fork_height = fork_height.min(lowest.saturating_sub(1));
+                    db.save_reorg(fork_height, &chain.blocks[fork_height as usize + 1..], &mut chain.state, chain.bits)?;
+                }
+                None => db.save_blocks_batch(&chain.blocks[lowest as usize..], &mut chain.state, chain.bits)?,
+            }
+            db.save_prune_state(chain)?;
+        }
+        // Those still off the main chain, unless rejected since
+        let mut fork_blocks: Vec<&Block> = unsaved.forks.iter().filter_map(|hash| chain.forks.get(hash)).collect();
+        fork_blocks.sort_by_key(|block| (block.header.height, block.hash));
+        fork_blocks.dedup_by_key(|block| block.hash);
+        if !fork_blocks.is_empty() {
+            db.save_fork_blocks(&fork_blocks.into_iter().cloned().collect::<Vec<_>>())?;
+        }
+        *unsaved = UnsavedBlocks::default();
+        result
+    }
+
+    /// Apply blocks from a peer, each followed by the orphans waiting for it.
+    /// Blocks whose parent is unknown join the orphans. Returns how many
+    /// blocks connected and whether any were orphaned.
+    fn apply_blocks(&self, chain: &mut Blockchain, blocks: Vec<Block>) -> (usize, bool) {
+        let (mut connected, mut orphaned) = (0, false);
+        let mut queue = VecDeque::from(blocks);
+        while let Some(block) = queue.pop_front() {
+            if chain.contains_block(&block.hash) {
+                continue;
+            }
+            if !chain.contains_block(&block.header.previous_hash) {
+                self.orphans.lock().unwrap().insert(block);
+                orphaned = true;
+                continue;
+            }
+            let (hash, height) = (block.hash, block.header.height);
+            match chain.apply_block(block) {
+                Ok(()) => {
+                    println!("✅ Applied block {} from peer", height);
+                    self.peer_best_height.fetch_max(height, Ordering::Relaxed);
+                    connected += 1;
+                    queue.extend(self.orphans.lock().unwrap().take_children(&hash));
+                }
+                Err(e) => eprintln!("❌ Failed to apply block from peer: {}", e),
+            }
+        }
+        if connected > 0 {
+            self.record_synced(connected);
+        }
+        (connected, orphaned)
+    }
+
+    /// Add addresses a peer told us about to the peer list
+    async fn learn_addresses(&self, addresses: Vec<Node>) {
+        let ours = self.advertised_addresses();
+        for peer in addresses.into_iter().take(MAX_ADDR_PER_MESSAGE) {
+            if ours.iter().any(|own| own.addr() == peer.addr()) {
+                continue;
+            }
+            if !self.peers.read().await.iter().any(|p| p.addr() == peer.addr()) {
+                println!("Discovered new peer: {}", peer.addr());
+                self.add_peer(peer).await;
+            }
+        }
+    }
+
+    /// Record a peer this node synced with in the peers file
+    fn save_good_peer(&self, peer: Node) -> Result<(), ChainError> {
+        let Some((path, store)) = &self.peer_store else {
+            return Ok(());
+        };
+        let mut store = store.lock().unwrap();
+        store.add(peer);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| ChainError::NetworkError(format!("Failed to create peers directory: {}", e)))?;
+        }
+        store.save(path)
+    }
+
+    /// Whether this is the first time the node has seen transaction `hash`
+    fn first_sighting(&self, hash: Sha256Hash) -> bool {
+        let mut recent = self.recent_transactions.lock().unwrap();
+        if recent.contains(&hash) {
+            return false;
+        }
+        if recent.len() >= MAX_RECENT_TRANSACTIONS {
+            recent.clear();
+        }
+        recent.insert(hash)
+    }
+
+    /// Offer a transaction from session `id`'s peer to the mempool. Once
+    /// accepted, the relay announces it to the other peers.
+    async fn receive_transaction(&self, id: u64, tx: Transaction) {
+        let hash = tx.hash();
+        self.remember(id, [InvItem::Tx(hash)]);
+        if !self.first_sighting(hash) {
+            return;
+        }
+        let mut chain = self.blockchain.write().await;
+        report_transaction(chain.accept_transaction(tx));
+    }
+
+    /// Record that session `id`'s peer has `items`
+    fn remember(&self, id: u64, items: impl IntoIterator<Item = InvItem>) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            for item in items {
+                session.remember(item);
+            }
+        }
+    }
+
+    /// Act on one message from session `id`'s peer. An `Inv` is answered with
+    /// a `GetData` for the items this node is missing, which arrive as `Block`s
+    /// and `Tx`s. A pushed `NewTransaction` is accepted and relayed unless it
+    /// was already seen, and a `NewBlock` applied, saved if the node has a
+    /// database, and announced onwards. Requests are answered through `outbox`.
+    async fn handle_message(
+        &self,
+        id: u64,
+        message: NetworkMessage,
+        outbox: &mpsc::Sender<NetworkMessage>,
+    ) -> Result<(), ChainError> {
+        // Sent once the chain is unlocked, waiting for room in the outbox
+        let mut replies = Vec::new();
+
+        match message {
+            NetworkMessage::GetHeaders { locator } => {
+                let chain = self.blockchain.read().await;
+                let headers = chain.headers_after_locator(&locator, MAX_HEADERS_PER_MESSAGE);
+
+                println!("📤 Sent {} block headers", headers.len());
+                replies.push(NetworkMessage::BlockHeaders(headers));
+            }
+            NetworkMessage::GetBlock(hash) => {
+                let chain = self.blockchain.read().await;
+                match chain.get_block(&hash).filter(|b| !chain.is_pruned(b)) {
+                    Some(block) => {
+                        replies.push(NetworkMessage::Block(Box::new(block.clone())));
+                        println!("📤 Sent block {}", hex::encode(hash));
+                    }
+                    None => replies.push(NetworkMessage::NotFound(vec![InvItem::Block(hash)])),
+                }
+            }
+            // Batch block requests for faster syncing
+            NetworkMessage::GetBlocks(hashes) => {
+                let chain = self.blockchain.read().await;
+                // Stop short of the frame limit; the peer asks again for the rest
+                let mut size = 0;
+                let blocks: Vec<Block> = hashes.iter()
+                    .filter_map(|hash| chain.get_block(hash).filter(|b| !chain.is_pruned(b)))
+                    .take_while(|block| {
+                        size += codec::encode(*block).len();
+                        size <= REPLY_SIZE_BUDGET
+                    })
+                    .cloned()
+                    .collect();
+
+                println!("📤 Sent {} blocks in batch", blocks.len());
+                replies.push(NetworkMessage::Blocks(blocks));
+            }
+            NetworkMessage::GetAddr => {
+                let peer_list = self.peers.read().await;
+                // Where to reach this node first, then the peers it knows
+                let addresses = self.advertised_addresses().into_iter()
+                    .chain(peer_list.iter().rev().cloned())
+                    .take(MAX_ADDR_PER_MESSAGE)
+                    .collect();
+                replies.push(NetworkMessage::Addr(addresses));
+                println!("📤 Sent peer list to peer");
+            }
+            NetworkMessage::Addr(addresses) => self.learn_addresses(addresses).await,
+            NetworkMessage::Inv(items) => {
+                check_inv_size("inv", &items)?;
+                self.remember(id, items.iter().copied());
+                let chain = self.blockchain.read().await;
+                let missing: Vec<InvItem> = items.into_iter()
+                    .filter(|item| match item {
+                        InvItem::Block(hash) => !chain.contains_block(hash) && !chain.is_known_invalid(hash),
+                        InvItem::Tx(hash) => chain.mempool.get_transaction(hash).is_none()
+                            && !self.recent_transactions.lock().unwrap().contains(hash),
+                    })
+                    .collect();
+                if !missing.is_empty() {
+                    replies.push(NetworkMessage::GetData(missing));
+                }
+            }
+            NetworkMessage::GetData(items) => {
+                check_inv_size("getdata", &items)?;
+                let mut not_found = Vec::new();
+                let mut sent = Vec::new();
+                {
+                    let chain = self.blockchain.read().await;
+                    // Like `GetBlocks`, stop at about a frame's worth; the peer asks again for the rest
+                    let mut size = 0;
+                    for item in items {
+                        if size > REPLY_SIZE_BUDGET {
+                            break;
+                        }
+                        let message = match item {
+                            InvItem::Block(hash) => chain.get_block(&hash)
+                                .filter(|b| !chain.is_pruned(b))
+                                .map(|block| NetworkMessage::Block(Box::new(block.clone()))),
+                            InvItem::Tx(hash) => chain.mempool.get_transaction(&hash)
+                                .map(|tx| NetworkMessage::Tx(Box::new(tx.clone()))),
+                        };
+                        match message {
+                            Some(message) => {
+                                size += codec::encode(&message).len();
+                                replies.push(message);
+                                sent.push(item);
+                            }
+                            None => not_found.push(item),
+                        }
+                    }
+                }
+                self.remember(id, sent);
+                if !not_found.is_empty() {
+                    replies.push(NetworkMessage::NotFound(not_found));
+                }
+            }
+            NetworkMessage::Block(block) | NetworkMessage::NewBlock(block) => {
+                self.remember(id, [InvItem::Block(block.hash)]);
+                let mut chain = self.blockchain.write().await;
+                let (_, orphaned) = self.persisting(&mut chain, |chain| Ok(self.apply_blocks(chain, vec![*block])))?;
+                if orphaned {
+                    println!("Orphan block received, requesting its ancestors");
+                    replies.push(NetworkMessage::GetHeaders { locator: chain.block_locator() });
+                }
+            }
+            // Headers, then the blocks for them, arrive in answer to an orphan
+            NetworkMessage::BlockHeaders(headers) => {
+                let mut chain = self.blockchain.write().await;
+                for header in headers {
+                    if let Err(e) = chain.accept_header(header) {
+                        eprintln!("❌ Rejected header from peer: {}", e);
+                        break;
+                    }
+                }
+                let wanted = chain.missing_block_hashes(BLOCKS_PER_REQUEST);
+                if !wanted.is_empty() {
+                    replies.push(NetworkMessage::GetBlocks(wanted));
+                }
+            }
+            NetworkMessage::Blocks(blocks) => {
+                self.remember(id, blocks.iter().map(|block| InvItem::Block(block.hash)));
+                let mut chain = self.blockchain.write().await;
+                let (connected, _) = self.persisting(&mut chain, |chain| Ok(self.apply_blocks(chain, blocks)))?;
+                // Keep asking while the peer has ancestors the orphans are waiting for
+                if connected > 0 {
+                    let wanted = chain.missing_block_hashes(BLOCKS_PER_REQUEST);
+                    if !wanted.is_empty() {
+                        replies.push(NetworkMessage::GetBlocks(wanted));
+                    } else if !self.orphans.lock().unwrap().is_empty() {
+                        replies.push(NetworkMessage::GetHeaders { locator: chain.block_locator() });
+                    }
+                }
+            }
+            NetworkMessage::Tx(tx) | NetworkMessage::NewTransaction(tx) => {
+                self.receive_transaction(id, *tx).await;
+            }
+            NetworkMessage::Version(_) | NetworkMessage::Verack
+            | NetworkMessage::AuthChallenge(_) | NetworkMessage::AuthResponse(_) => {
+                return Err(ChainError::IncompatiblePeer("repeated the handshake".to_string()));
+            }
+            NetworkMessage::Ping => replies.push(NetworkMessage::Pong),
+            NetworkMessage::Pong => {
+                if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+                    session.latency = session.ping_sent.take().map(|sent| sent.elapsed());
+                }
+            }
+            _ => {}
+        }
+
+        for message in replies {
+            outbox.send(message).await.map_err(|_| ChainError::NetworkError("Session closed".to_string()))?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 enum NetworkMessage {
-    GetBlockHeaders { after_height: u64 },
+    /// The first message each side sends
+    Version(VersionMessage),
+    /// Acknowledges the other side's `Version`; after it, anything may be sent
+    Verack,
+    /// Ask for main-chain headers after the newest block in a locator
+    /// (see `Blockchain::block_locator`) that the receiver also has
+    GetHeaders { locator: Vec<Sha256Hash> },
     BlockHeaders(Vec<crate::blockchain::BlockHeader>),
     GetBlock(crate::blockchain::Sha256Hash),
     Block(Box<crate::blockchain::Block>),
@@ -314,163 +1708,998 @@ enum NetworkMessage {
     Blocks(Vec<crate::blockchain::Block>),
     NewBlock(Box<crate::blockchain::Block>),
     NewTransaction(Box<crate::transaction::Transaction>),
-    GetPeers,
-    Peers(Vec<Node>),
-    GetBlockchain,
-    Blockchain(Blockchain),
+    /// Ask for the addresses of peers the receiver knows
+    GetAddr,
+    /// Peer addresses, in reply to `GetAddr` or unprompted
+    Addr(Vec<Node>),
     Ping,
     Pong,
+    /// Hashes of blocks and transactions the sender has
+    Inv(Vec<InvItem>),
+    /// Ask for announced items; answered with `Block`s, `Tx`s and a `NotFound`
+    GetData(Vec<InvItem>),
+    Tx(Box<Transaction>),
+    /// Requested items the sender doesn't have
+    NotFound(Vec<InvItem>),
+    /// A nonce for the receiver to sign, right after the `Verack`, from a
+    /// node that requires authentication
+    AuthChallenge(PeerChallenge),
+    /// The nonce of an `AuthChallenge` and the connection's handshake hash,
+    /// signed with the sender's node key
+    AuthResponse(PeerAuthResponse),
 }
 
-async fn handle_connection(
-    mut socket: TcpStream,
-    blockchain: Arc<RwLock<Blockchain>>,
-    peers: Arc<RwLock<Vec<Node>>>,
-) -> Result<(), ChainError> {
-    let mut len_bytes = [0u8; 4];
-    socket.read_exact(&mut len_bytes).await
-        .map_err(|e| ChainError::NetworkError(format!("Read failed: {}", e)))?;
-    let len = u32::from_be_bytes(len_bytes) as usize;
-    
-    let mut buffer = vec![0u8; len];
-    socket.read_exact(&mut buffer).await
+impl NetworkMessage {
+    /// The name a frame carries for this message
+    fn command(&self) -> &'static str {
+        match self {
+            NetworkMessage::Version(_) => "version",
+            NetworkMessage::Verack => "verack",
+            NetworkMessage::GetHeaders { .. } => "getheaders",
+            NetworkMessage::BlockHeaders(_) => "headers",
+            NetworkMessage::GetBlock(_) => "getblock",
+            NetworkMessage::Block(_) => "block",
+            NetworkMessage::GetBlocks(_) => "getblocks",
+            NetworkMessage::Blocks(_) => "blocks",
+            NetworkMessage::NewBlock(_) => "newblock",
+            NetworkMessage::NewTransaction(_) => "newtx",
+            NetworkMessage::GetAddr => "getaddr",
+            NetworkMessage::Addr(_) => "addr",
+            NetworkMessage::Ping => "ping",
+            NetworkMessage::Pong => "pong",
+            NetworkMessage::Inv(_) => "inv",
+            NetworkMessage::GetData(_) => "getdata",
+            NetworkMessage::Tx(_) => "tx",
+            NetworkMessage::NotFound(_) => "notfound",
+            NetworkMessage::AuthChallenge(_) => "authchal",
+            NetworkMessage::AuthResponse(_) => "authresp",
+        }
+    }
+}
+
+/// The IP address of a `host:port` or `ip:port` peer address, if it has one
+/// An inbound peer that might be evicted, and what counts in its favour
+struct EvictionCandidate {
+    id: u64,
+    connected_at: Instant,
+    latency: Option<Duration>,
+    misbehavior: u32,
+    netgroup: Vec<u8>,
+}
+
+/// Which inbound session to close to make room for a new peer. Peers from
+/// `PROTECTED_PER_REASON` distinct network groups, the best behaved, and
+/// the longest connected are spared, so an attacker can't take over a node's
+/// inbound slots by connecting from one range, misbehaving, or reconnecting.
+/// Of the rest, the newest peer from the best represented network group goes.
+fn select_eviction(mut candidates: Vec<EvictionCandidate>) -> Option<u64> {
+    candidates.sort_by_key(|candidate| candidate.connected_at);
+    let mut groups = HashSet::new();
+    let mut spared = 0;
+    candidates.retain(|candidate| {
+        if spared < PROTECTED_PER_REASON && groups.insert(candidate.netgroup.clone()) {
+            spared += 1;
+            return false;
+        }
+        true
+    });
+
+    candidates.sort_by_key(|candidate| (candidate.misbehavior, candidate.latency.unwrap_or(Duration::MAX)));
+    candidates.drain(..PROTECTED_PER_REASON.min(candidates.len()));
+
+    candidates.sort_by_key(|candidate| candidate.connected_at);
+    candidates.drain(..PROTECTED_PER_REASON.min(candidates.len()));
+
+    let mut by_group: HashMap<&[u8], Vec<&EvictionCandidate>> = HashMap::new();
+    for candidate in &candidates {
+        by_group.entry(&candidate.netgroup).or_default().push(candidate);
+    }
+    // Candidates are oldest first, so the last in a group is its newest
+    let group = by_group.into_values()
+        .max_by_key(|group| (group.len(), group.last().map(|candidate| candidate.connected_at)))?;
+    group.last().map(|candidate| candidate.id)
+}
+
+/// The network group an address belongs to: its /16 for IPv4, its /32 for
+/// IPv6. Peers in one group are likely run by one operator in one place.
+fn netgroup(ip: std::net::IpAddr) -> Vec<u8> {
+    match ip {
+        std::net::IpAddr::V4(v4) => v4.octets()[..2].to_vec(),
+        std::net::IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => v4.octets()[..2].to_vec(),
+            None => v6.octets()[..4].to_vec(),
+        },
+    }
+}
+
+/// A listener on `addr`. An IPv6 one accepts only IPv6, so it can share its
+/// port with an IPv4 listener.
+fn bind_listener(addr: SocketAddr) -> Result<TcpListener, ChainError> {
+    let bind = || -> std::io::Result<TcpListener> {
+        let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    };
+    bind().map_err(|e| ChainError::NetworkError(format!("Failed to bind {}: {}", addr, e)))
+}
+
+fn peer_ip(addr: &str) -> Option<std::net::IpAddr> {
+    addr.parse::<std::net::SocketAddr>().map(|socket| socket.ip()).ok()
+        .or_else(|| Node::parse(addr).ok()?.host.parse().ok())
+}
+
+/// Why a peer that sent `theirs` can't talk to a node that sent `ours`
+fn check_version(ours: &VersionMessage, theirs: &VersionMessage) -> Result<(), ChainError> {
+    if theirs.magic != ours.magic {
+        return Err(ChainError::IncompatiblePeer(format!(
+            "on network {}, not {}", hex::encode(theirs.magic), hex::encode(ours.magic)
+        )));
+    }
+    if theirs.genesis_hash != ours.genesis_hash {
+        return Err(ChainError::IncompatiblePeer(format!(
+            "has genesis block {}", hex::encode(theirs.genesis_hash)
+        )));
+    }
+    if theirs.version < MIN_PROTOCOL_VERSION {
+        return Err(ChainError::IncompatiblePeer(format!(
+            "speaks protocol version {}, older than {}", theirs.version, MIN_PROTOCOL_VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// Fail if a peer's `command` lists more than `MAX_INV_ITEMS`
+fn check_inv_size(command: &str, items: &[InvItem]) -> Result<(), ChainError> {
+    if items.len() > MAX_INV_ITEMS {
+        return Err(ChainError::MalformedMessage(format!(
+            "{} with {} items, more than {}", command, items.len(), MAX_INV_ITEMS
+        )));
+    }
+    Ok(())
+}
+
+fn report_transaction(result: MempoolAcceptResult) {
+    match result {
+        MempoolAcceptResult::Accepted { .. } => println!("✅ Added new transaction to mempool"),
+        MempoolAcceptResult::Replaced { replaced, .. } => {
+            println!("✅ Added new transaction to mempool, replacing {}", hex::encode(replaced));
+        }
+        MempoolAcceptResult::Orphaned { missing_inputs, .. } => {
+            eprintln!("❌ Transaction spends {} unknown input(s), not added", missing_inputs.len());
+        }
+        MempoolAcceptResult::Rejected { reason, error, .. } => {
+            eprintln!("❌ Rejected new transaction ({}): {}", reason.code(), error);
+        }
+    }
+}
+
+/// Frame `message`: the network's 4-byte magic, a NUL-padded command name
+/// of `COMMAND_SIZE` bytes, the payload length as a big-endian `u32`, the
+/// first 4 bytes of the payload's SHA-256, then the payload, which is the
+/// message's canonical encoding (see `codec`)
+fn encode_frame(magic: [u8; 4], message: &NetworkMessage) -> Result<Vec<u8>, ChainError> {
+    let payload = codec::encode(message);
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(ChainError::OversizedMessage { size: payload.len(), max: MAX_MESSAGE_SIZE });
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&magic);
+    let mut command = [0u8; COMMAND_SIZE];
+    command[..message.command().len()].copy_from_slice(message.command().as_bytes());
+    frame.extend_from_slice(&command);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// First four bytes of the payload's SHA-256
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(payload);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, magic: [u8; 4], message: &NetworkMessage) -> Result<(), ChainError> {
+    let frame = encode_frame(magic, message)?;
+    writer.write_all(&frame).await
+        .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
+    writer.flush().await
+        .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))
+}
+
+/// The next message, or `None` if the peer closed the connection between
+/// messages. The header is checked before the payload is read, so an
+/// oversized or foreign frame costs no allocation.
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R, magic: [u8; 4]) -> Result<Option<NetworkMessage>, ChainError> {
+    Ok(read_frame(reader, magic).await?.map(|(message, _)| message))
+}
+
+/// Like `read_message`, also returning the size of the frame the message came in
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, magic: [u8; 4]) -> Result<Option<(NetworkMessage, usize)>, ChainError> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(ChainError::NetworkError(format!("Read failed: {}", e))),
+    }
+    if header[..4] != magic {
+        return Err(ChainError::IncompatiblePeer(format!("on network {}", hex::encode(&header[..4]))));
+    }
+    let command = &header[4..4 + COMMAND_SIZE];
+    let len = u32::from_be_bytes(header[16..20].try_into().unwrap()) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(ChainError::OversizedMessage { size: len, max: MAX_MESSAGE_SIZE });
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await
         .map_err(|e| ChainError::NetworkError(format!("Read failed: {}", e)))?;
-    
-    let message: NetworkMessage = bincode::deserialize(&buffer)
-        .map_err(|e| ChainError::NetworkError(format!("Deserialization failed: {}", e)))?;
-    
-    match message {
-        NetworkMessage::GetBlockHeaders { after_height } => {
-            let chain = blockchain.read().await;
-            let headers = chain.blocks
-                .iter()
-                .filter(|b| b.header.height > after_height)
-                .map(|b| b.header.clone())
-                .collect::<Vec<_>>();
-
-            let response = NetworkMessage::BlockHeaders(headers);
-            let data = bincode::serialize(&response)
-                .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
-            
-            let len = data.len() as u32;
-            socket.write_all(&len.to_be_bytes()).await
-                .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-            socket.write_all(&data).await
-                .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-            
-            println!("📤 Sent {} block headers", chain.blocks.len());
-        }
-        NetworkMessage::GetBlock(hash) => {
-            let chain = blockchain.read().await;
-            if let Some(block) = chain.block_index.get(&hash) {
-                let response = NetworkMessage::Block(Box::new(block.clone()));
-                let data = bincode::serialize(&response)
-                    .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
-
-                let len = data.len() as u32;
-                socket.write_all(&len.to_be_bytes()).await
-                    .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-                socket.write_all(&data).await
-                    .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-
-                println!("📤 Sent block {}", hex::encode(hash));
-            }
-        }
-        // Batch block requests for faster syncing
-        NetworkMessage::GetBlocks(hashes) => {
-            let chain = blockchain.read().await;
-            let mut blocks = Vec::new();
-
-            for hash in hashes {
-                if let Some(block) = chain.block_index.get(&hash) {
-                    blocks.push(block.clone());
-                }
+    if checksum(&payload) != header[20..24] {
+        return Err(ChainError::MalformedMessage("checksum mismatch".to_string()));
+    }
+
+    let message: NetworkMessage = codec::decode(&payload)
+        .map_err(|e| ChainError::MalformedMessage(format!("undecodable payload: {}", e)))?;
+    let name = message.command().as_bytes();
+    if command[..name.len()] != *name || command[name.len()..].iter().any(|&b| b != 0) {
+        return Err(ChainError::MalformedMessage(format!(
+            "{} payload under command {}", message.command(), String::from_utf8_lossy(command).trim_end_matches('\0')
+        )));
+    }
+    Ok(Some((message, FRAME_HEADER_SIZE + len)))
+}
+
+/// Send a request and wait for the reply `expected` picks out, skipping
+/// announcements the peer sends in the meantime
+async fn exchange<S, T>(
+    stream: &mut S,
+    magic: [u8; 4],
+    message: NetworkMessage,
+    expected: impl Fn(NetworkMessage) -> Option<T>,
+) -> Result<T, ChainError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write_message(stream, magic, &message).await?;
+    loop {
+        let reply = read_message(stream, magic).await?
+            .ok_or_else(|| ChainError::NetworkError("Peer closed the connection".to_string()))?;
+        match reply {
+            NetworkMessage::Inv(_) | NetworkMessage::NewTransaction(_) | NetworkMessage::NewBlock(_) => continue,
+            reply => return expected(reply)
+                .ok_or_else(|| ChainError::NetworkError("Unexpected response".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockassembler::BlockTemplate;
+    use crate::blockchain::genesis_triangle;
+    use crate::crypto::KeyPair;
+    use crate::pow::TestPow;
+    use crate::security::{FirewallRule, RateLimitConfig};
+    use crate::transaction::SubdivisionTx;
+    use std::time::Duration;
+
+    const MAGIC: [u8; 4] = ChainParams::MAINNET.magic;
+
+    /// Two nodes on copies of one chain, joined by an in-memory session
+    async fn linked_nodes(chain: Blockchain) -> (NetworkNode, NetworkNode) {
+        let a = NetworkNode::new(chain.clone(), String::new());
+        let b = NetworkNode::new(chain, String::new());
+        link(&a, &b).await;
+        (a, b)
+    }
+
+    async fn link(a: &NetworkNode, b: &NetworkNode) {
+        let (a_sessions, b_sessions) = (a.session_addrs().len(), b.session_addrs().len());
+        let (a_end, b_end) = tokio::io::duplex(1 << 20);
+        a.spawn_session(a_end, "b".to_string(), Direction::Outbound);
+        b.spawn_session(b_end, "a".to_string(), Direction::Inbound);
+        for _ in 0..100 {
+            if a.session_addrs().len() > a_sessions && b.session_addrs().len() > b_sessions {
+                break;
             }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
 
-            if !blocks.is_empty() {
-                let response = NetworkMessage::Blocks(blocks.clone());
-                let data = bincode::serialize(&response)
-                    .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
+    /// A raw connection to `node`, past the handshake
+    async fn connect(node: &NetworkNode) -> PeerStream<tokio::io::DuplexStream> {
+        let (client, server) = tokio::io::duplex(1 << 20);
+        node.spawn_session(server, "client".to_string(), Direction::Inbound);
+        let mut client = PeerStream::plain(client);
+        let me = NetworkNode::new(node.blockchain().read().await.clone(), String::new());
+        me.handshake(&mut client, "node").await.unwrap();
+        client
+    }
 
-                let len = data.len() as u32;
-                socket.write_all(&len.to_be_bytes()).await
-                    .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-                socket.write_all(&data).await
-                    .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
+    /// A subdivision of the genesis triangle, which the chain hands to a fresh key
+    fn genesis_subdivision(chain: &mut Blockchain) -> Transaction {
+        let keypair = KeyPair::generate().unwrap();
+        let genesis = genesis_triangle();
+        chain.state.set_owner(&genesis.hash(), keypair.address()).unwrap();
+        let mut tx = SubdivisionTx::new(genesis.hash(), genesis.subdivide().to_vec(), keypair.address(), 1, 1);
+        let signature = keypair.sign(&tx.signable_message()).unwrap();
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        Transaction::Subdivision(tx)
+    }
 
-                println!("📤 Sent {} blocks in batch", blocks.len());
+    async fn eventually(mut condition: impl AsyncFnMut() -> bool) -> bool {
+        for _ in 0..200 {
+            if condition().await {
+                return true;
             }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
-        NetworkMessage::GetPeers => {
-            let peer_list = peers.read().await;
-            let response = NetworkMessage::Peers(peer_list.clone());
-            let data = bincode::serialize(&response)
-                .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
-            
-            let len = data.len() as u32;
-            socket.write_all(&len.to_be_bytes()).await
-                .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-            socket.write_all(&data).await
-                .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-            
-            println!("📤 Sent peer list to peer");
-        }
-        NetworkMessage::GetBlockchain => {
-            let chain = blockchain.read().await;
-            let response = NetworkMessage::Blockchain(chain.clone());
-            let data = bincode::serialize(&response)
-                .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
-            
-            let len = data.len() as u32;
-            socket.write_all(&len.to_be_bytes()).await
-                .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-            socket.write_all(&data).await
-                .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-            
-            println!("📤 Sent blockchain to peer");
-        }
-        NetworkMessage::NewTransaction(tx) => {
+        false
+    }
+
+    #[tokio::test]
+    async fn test_blocks_are_announced_and_fetched() {
+        let chain = Blockchain::regtest().with_pow(Arc::new(TestPow));
+        let (a, b) = linked_nodes(chain).await;
+
+        for _ in 0..3 {
+            let blockchain = a.blockchain();
             let mut chain = blockchain.write().await;
-            if let Err(e) = chain.mempool.add_transaction(*tx) {
-                eprintln!("❌ Failed to add new transaction to mempool: {}", e);
-            } else {
-                println!("✅ Added new transaction to mempool");
-            }
+            let block = BlockTemplate::build(&chain, "miner").block;
+            chain.apply_block(block).unwrap();
         }
-        NetworkMessage::NewBlock(block) => {
+
+        let a_tip = a.blockchain().read().await.blocks.last().unwrap().hash;
+        assert!(eventually(async || b.blockchain().read().await.blocks.last().unwrap().hash == a_tip).await);
+    }
+
+    #[tokio::test]
+    async fn test_tip_subscribers_hear_of_new_blocks() {
+        let chain = Blockchain::regtest().with_pow(Arc::new(TestPow));
+        let (a, b) = linked_nodes(chain).await;
+        let mut tips = b.subscribe_tip().await;
+        assert_eq!(tips.borrow_and_update().height, 0);
+
+        // Mined on a, relayed to b
+        let block = {
+            let blockchain = a.blockchain();
             let mut chain = blockchain.write().await;
-            if let Err(e) = chain.apply_block(*block.clone()) {
-                if let ChainError::OrphanBlock = e {
-                    println!("Orphan block received, requesting parent");
-                    let request = NetworkMessage::GetBlock(block.header.previous_hash);
-                    let data = bincode::serialize(&request)
-                        .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
-                    
-                    let len = data.len() as u32;
-                    socket.write_all(&len.to_be_bytes()).await
-                        .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-                    socket.write_all(&data).await
-                        .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-                } else {
-                    eprintln!("❌ Failed to apply new block: {}", e);
+            let block = BlockTemplate::build(&chain, "miner").block;
+            chain.apply_block(block.clone()).unwrap();
+            block
+        };
+        tokio::time::timeout(Duration::from_secs(2), tips.changed()).await.unwrap().unwrap();
+        assert_eq!(*tips.borrow_and_update(), ChainTip { hash: block.hash, height: 1 });
+
+        // Mined on b itself
+        let blockchain = b.blockchain();
+        let mut chain = blockchain.write().await;
+        let block = BlockTemplate::build(&chain, "miner").block;
+        chain.apply_block(block.clone()).unwrap();
+        drop(chain);
+        tokio::time::timeout(Duration::from_secs(2), tips.changed()).await.unwrap().unwrap();
+        assert_eq!(tips.borrow().hash, block.hash);
+    }
+
+    #[tokio::test]
+    async fn test_pings_measure_latency_and_drop_silent_peers() {
+        let node = NetworkNode::new(Blockchain::regtest(), String::new());
+        let mut client = connect(&node).await;
+        assert!(eventually(async || node.session_statuses().len() == 1).await);
+
+        node.ping_sessions();
+        assert!(matches!(read_message(&mut client, MAGIC).await.unwrap(), Some(NetworkMessage::Ping)));
+        write_message(&mut client, MAGIC, &NetworkMessage::Pong).await.unwrap();
+        assert!(eventually(async || node.session_statuses()[0].latency_ms.is_some()).await);
+
+        // Answered pings keep the peer; an unanswered one past the timeout drops it
+        node.drop_unresponsive_sessions(Duration::ZERO);
+        assert_eq!(node.session_statuses().len(), 1);
+        node.ping_sessions();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        node.drop_unresponsive_sessions(Duration::ZERO);
+        assert!(eventually(async || node.session_statuses().is_empty()).await);
+    }
+
+    #[tokio::test]
+    async fn test_frames_are_checked() {
+        let frame = encode_frame(MAGIC, &NetworkMessage::GetAddr).unwrap();
+        assert_eq!(&frame[4..11], b"getaddr");
+        assert!(matches!(read_message(&mut &frame[..], MAGIC).await.unwrap(), Some(NetworkMessage::GetAddr)));
+
+        assert!(matches!(read_message(&mut &frame[..], *b"TEST").await, Err(ChainError::IncompatiblePeer(_))));
+
+        let mut corrupt = encode_frame(MAGIC, &NetworkMessage::Addr(vec![Node::new("10.0.0.1".to_string(), 1)])).unwrap();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(matches!(read_message(&mut &corrupt[..], MAGIC).await, Err(ChainError::MalformedMessage(_))));
+
+        let mut renamed = frame.clone();
+        renamed[4..8].copy_from_slice(b"ping");
+        assert!(matches!(read_message(&mut &renamed[..], MAGIC).await, Err(ChainError::MalformedMessage(_))));
+
+        // A 4 GB length is refused from the header alone
+        let mut huge = frame[..FRAME_HEADER_SIZE].to_vec();
+        huge[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            read_message(&mut &huge[..], MAGIC).await,
+            Err(ChainError::OversizedMessage { size, .. }) if size == u32::MAX as usize
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_peers_sending_bad_frames_are_banned() {
+        let node = NetworkNode::new(Blockchain::regtest(), String::new());
+        let me = NetworkNode::new(Blockchain::regtest(), String::new());
+
+        for attempt in 0..2 {
+            assert!(!node.is_banned("10.0.0.9:4000"), "banned after {} bad frames", attempt);
+            let (client, server) = tokio::io::duplex(1 << 16);
+            node.spawn_session(server, format!("10.0.0.9:{}", 5000 + attempt), Direction::Inbound);
+            let mut client = PeerStream::plain(client);
+            me.handshake(&mut client, "node").await.unwrap();
+            let mut corrupt = encode_frame(MAGIC, &NetworkMessage::GetAddr).unwrap();
+            corrupt[21] ^= 1;
+            client.write_all(&corrupt).await.unwrap();
+            assert!(read_message(&mut client, MAGIC).await.unwrap().is_none());
+        }
+        assert!(eventually(async || node.is_banned("10.0.0.9:4000")).await);
+        assert!(!node.is_banned("10.0.0.10:4000"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_other_networks() {
+        let chain = Blockchain::regtest();
+        let node = NetworkNode::new(chain.clone(), String::new());
+
+        let testnet = ChainParams { magic: *b"TEST", ..ChainParams::MAINNET };
+        let stranger = NetworkNode::new(chain.clone(), String::new()).with_params(testnet);
+        let (client, server) = tokio::io::duplex(1 << 16);
+        node.spawn_session(server, "stranger".to_string(), Direction::Inbound);
+        let mut client = PeerStream::plain(client);
+        assert!(matches!(stranger.handshake(&mut client, "node").await, Err(ChainError::IncompatiblePeer(_))));
+
+        let mut forked = chain.clone();
+        forked.blocks[0].hash = [1; 32];
+        let forked = NetworkNode::new(forked, String::new());
+        let (client, server) = tokio::io::duplex(1 << 16);
+        node.spawn_session(server, "forked".to_string(), Direction::Inbound);
+        let mut client = PeerStream::plain(client);
+        assert!(matches!(forked.handshake(&mut client, "node").await, Err(ChainError::IncompatiblePeer(_))));
+
+        // Skipping the handshake gets the connection closed unanswered
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        node.spawn_session(server, "rude".to_string(), Direction::Inbound);
+        write_message(&mut client, MAGIC, &NetworkMessage::GetAddr).await.unwrap();
+        assert!(matches!(read_message(&mut client, MAGIC).await.unwrap(), Some(NetworkMessage::Version(_))));
+        assert!(read_message(&mut client, MAGIC).await.unwrap().is_none());
+        assert!(node.session_addrs().is_empty());
+    }
+
+    fn secure(node: NetworkNode) -> NetworkNode {
+        node.with_security(SecurityManager::with_generated_key().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_peers_must_authenticate() {
+        let chain = Blockchain::regtest();
+        let node = secure(NetworkNode::new(chain.clone(), String::new()));
+        let friend = secure(NetworkNode::new(chain.clone(), String::new()));
+        assert!(!node.requires_auth(), "no keys are trusted");
+        let policy = node.security.as_ref().unwrap().network_policy();
+        policy.write().trust_key(friend.public_key().unwrap());
+        policy.write().set_require_auth(true);
+        assert!(node.requires_auth());
+
+        let (client, server) = tokio::io::duplex(1 << 16);
+        node.spawn_session(server, "10.0.0.4:4000".to_string(), Direction::Inbound);
+        let mut client = friend.secure(client, Direction::Outbound).await.unwrap();
+        let theirs = friend.handshake(&mut client, "node").await.unwrap();
+        assert_ne!(theirs.capabilities & CAP_AUTH_REQUIRED, 0);
+        let security = node.security.clone().unwrap();
+        assert!(eventually(async || security.get_peers().get("10.0.0.4:4000").is_some_and(|peer| peer.is_trusted())).await);
+
+        // A node without a key can't answer the challenge
+        let keyless = NetworkNode::new(chain.clone(), String::new());
+        let (client, server) = tokio::io::duplex(1 << 16);
+        node.spawn_session(server, "10.0.0.5:4000".to_string(), Direction::Inbound);
+        assert!(keyless.handshake(&mut PeerStream::plain(client), "node").await.is_err());
+
+        // Signing the wrong nonce, with an untrusted key, or for another
+        // connection fails, and the third failure is a ban
+        let impostor = NetworkNode::new(chain, String::new());
+        let friend_key = friend.security.clone().unwrap();
+        let untrusted = SecurityManager::with_generated_key().unwrap();
+        for attempt in 0..3 {
+            let (client, server) = tokio::io::duplex(1 << 16);
+            node.spawn_session(server, "10.0.0.6:4000".to_string(), Direction::Inbound);
+            let mut client = PeerStream::connect(client, &untrusted.noise_static_key(), &MAGIC).await.unwrap();
+            write_message(&mut client, MAGIC, &NetworkMessage::Version(impostor.version_message().await)).await.unwrap();
+            write_message(&mut client, MAGIC, &NetworkMessage::Verack).await.unwrap();
+            assert!(matches!(read_message(&mut client, MAGIC).await.unwrap(), Some(NetworkMessage::Version(_))));
+            assert!(matches!(read_message(&mut client, MAGIC).await.unwrap(), Some(NetworkMessage::Verack)));
+            let Some(NetworkMessage::AuthChallenge(mut challenge)) = read_message(&mut client, MAGIC).await.unwrap() else {
+                panic!("expected a challenge");
+            };
+            let handshake_hash = client.handshake_hash().unwrap().to_vec();
+            let response = match attempt {
+                0 => {
+                    challenge.nonce = "00".repeat(32);
+                    friend_key.create_auth_response(&challenge, &handshake_hash)
                 }
-            } else {
-                println!("✅ Applied new block from peer");
+                1 => untrusted.create_auth_response(&challenge, &handshake_hash),
+                _ => friend_key.create_auth_response(&challenge, &[0; 32]),
+            }.unwrap();
+            write_message(&mut client, MAGIC, &NetworkMessage::AuthResponse(response)).await.unwrap();
+            assert!(read_message(&mut client, MAGIC).await.unwrap().is_none());
+        }
+        assert!(eventually(async || node.is_banned("10.0.0.6:4000")).await);
+        assert!(!node.is_banned("10.0.0.5:4000"));
+    }
+
+    #[tokio::test]
+    async fn test_flooding_peers_are_throttled_then_banned() {
+        let chain = Blockchain::regtest();
+        let limits = RateLimitConfig { peer_requests_per_sec: 3, ..RateLimitConfig::default() };
+        let node = NetworkNode::new(chain.clone(), String::new())
+            .with_security(SecurityManager::with_generated_key().unwrap().with_rate_limits(limits));
+        let addr = "10.0.0.9:5555";
+        let (client, server) = tokio::io::duplex(1 << 20);
+        node.spawn_session(server, addr.to_string(), Direction::Inbound);
+        let mut client = PeerStream::plain(client);
+        secure(NetworkNode::new(chain, String::new())).handshake(&mut client, "node").await.unwrap();
+        let score = || node.misbehavior.lock().unwrap().get(&peer_ip(addr).unwrap()).map_or(0, |record| record.score);
+
+        // Over the limit the peer is slowed down, but kept
+        for _ in 0..8 {
+            write_message(&mut client, MAGIC, &NetworkMessage::Ping).await.unwrap();
+        }
+        assert!(eventually(async || score() >= THROTTLE_PENALTY).await);
+        assert_eq!(node.session_addrs(), vec![addr]);
+
+        // ...until it has been throttled enough to be banned
+        node.penalize(addr, BAN_THRESHOLD - 1 - score());
+        for _ in 0..8 {
+            if write_message(&mut client, MAGIC, &NetworkMessage::Ping).await.is_err() {
+                break;
             }
         }
-        NetworkMessage::Ping => {
-            let response = NetworkMessage::Pong;
-            let data = bincode::serialize(&response)
-                .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
-            
-            let len = data.len() as u32;
-            socket.write_all(&len.to_be_bytes()).await
-                .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
-            socket.write_all(&data).await
-                .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
+        assert!(eventually(async || node.session_addrs().is_empty()).await);
+        assert!(node.is_banned(addr));
+    }
+
+    #[tokio::test]
+    async fn test_sessions_between_keyed_nodes_are_encrypted() {
+        let mut chain = Blockchain::regtest();
+        let tx = genesis_subdivision(&mut chain);
+        let a = secure(NetworkNode::new(chain.clone(), String::new()));
+        let b = secure(NetworkNode::new(chain.clone(), String::new()));
+        link(&a, &b).await;
+        let statuses = [a.session_statuses(), b.session_statuses()].concat();
+        assert_eq!(statuses.len(), 2);
+        assert!(statuses.iter().all(|peer| peer.encrypted));
+
+        // Gossip flows over the encrypted session
+        let hash = tx.hash();
+        a.blockchain().write().await.submit_transaction(tx).unwrap();
+        assert!(eventually(async || b.blockchain().read().await.mempool.get_transaction(&hash).is_some()).await);
+
+        // A node requiring encryption hangs up on plaintext
+        a.security.as_ref().unwrap().network_policy().write().set_require_encryption(true);
+        let (client, server) = tokio::io::duplex(1 << 16);
+        a.spawn_session(server, "10.0.0.8:4000".to_string(), Direction::Inbound);
+        let plain = NetworkNode::new(chain, String::new());
+        let mut client = PeerStream::plain(client);
+        assert!(plain.handshake(&mut client, "a").await.is_err());
+        assert_eq!(a.session_statuses().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_outbound_connections_use_the_proxy() {
+        let node = secure(NetworkNode::new(Blockchain::regtest(), String::new()));
+        let onion = "expyuzz4wqqyqhjn.onion";
+        let result = node.connect_peer(onion.to_string(), 8333).await;
+        assert!(matches!(result, Err(ChainError::NetworkError(e)) if e.contains("SIERTRI_SOCKS5_PROXY")));
+
+        // A proxy that notes where it was asked to connect, then refuses
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap().to_string();
+        let requested = tokio::spawn(async move {
+            let (mut socket, _) = proxy.accept().await.unwrap();
+            let mut greeting = [0u8; 2];
+            socket.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0u8; greeting[1] as usize];
+            socket.read_exact(&mut methods).await.unwrap();
+            socket.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0u8; 5];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[3], 3, "expected a domain name");
+            let mut host = vec![0u8; request[4] as usize];
+            socket.read_exact(&mut host).await.unwrap();
+            let port = socket.read_u16().await.unwrap();
+            socket.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            (String::from_utf8(host).unwrap(), port)
+        });
+
+        node.security.as_ref().unwrap().network_policy().write().set_socks5_proxy(Some(proxy_addr.clone()));
+        assert_eq!(node.socks5_proxy(), Some(proxy_addr));
+        assert!(node.connect_peer(onion.to_string(), 8333).await.is_err());
+        assert_eq!(requested.await.unwrap(), (onion.to_string(), 8333));
+    }
+
+    #[tokio::test]
+    async fn test_firewall_refuses_peers() {
+        let chain = Blockchain::regtest();
+        let server = NetworkNode::new(chain.clone(), String::new());
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        {
+            let server = server.clone();
+            tokio::spawn(async move { server.start_server(port).await });
         }
-        _ => {}
+
+        let dialer = secure(NetworkNode::new(chain, String::new()));
+        dialer.security.as_ref().unwrap().network_policy().write()
+            .add_rule(FirewallRule::Deny("127.0.0.0/8".parse().unwrap()));
+        assert!(eventually(async || matches!(
+            dialer.connect_peer("127.0.0.1".to_string(), port).await,
+            Err(ChainError::NetworkError(e)) if e.contains("firewall")
+        )).await);
+        assert!(server.session_addrs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_inbound_connections_are_capped() {
+        let chain = Blockchain::regtest();
+        let server = NetworkNode::new(chain.clone(), String::new()).with_max_inbound(1);
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        {
+            let server = server.clone();
+            tokio::spawn(async move { server.start_server(port).await });
+        }
+
+        let first = NetworkNode::new(chain.clone(), String::new());
+        assert!(eventually(async || first.connect_peer("127.0.0.1".to_string(), port).await.is_ok()).await);
+        assert!(eventually(async || server.session_addrs().len() == 1).await);
+
+        // The only inbound peer is spared, so the newcomer is refused
+        let second = NetworkNode::new(chain, String::new());
+        assert!(second.connect_peer("127.0.0.1".to_string(), port).await.is_err());
+        assert_eq!(server.session_addrs().len(), 1);
+        assert_eq!(first.session_addrs().len(), 1);
+    }
+
+    #[test]
+    fn test_eviction_spares_old_well_behaved_and_diverse_peers() {
+        let start = Instant::now();
+        let candidate = |id: u64, group: u8, latency_ms: Option<u64>| EvictionCandidate {
+            id,
+            connected_at: start + Duration::from_secs(id),
+            latency: latency_ms.map(Duration::from_millis),
+            misbehavior: 0,
+            netgroup: vec![group, 0],
+        };
+        // Four peers from distinct groups, then a crowd from one more
+        let mut candidates: Vec<EvictionCandidate> = (0..4).map(|id| candidate(id, id as u8, None)).collect();
+        candidates.extend((4..12).map(|id| candidate(id, 9, None)));
+        candidates.extend((12..16).map(|id| candidate(id, 9, Some(5))));
+        // Too few to evict any without sparing them all
+        let few = (0..12).map(|id| candidate(id, if id < 4 { id as u8 } else { 9 }, None)).collect();
+        assert_eq!(select_eviction(few), None);
+
+        // A lone newer peer from another group outlasts the crowd's newest
+        candidates.push(candidate(16, 7, None));
+        assert_eq!(select_eviction(candidates), Some(11));
+
+        // Misbehaving counts against a peer even if it answers pings fastest
+        let mut candidates: Vec<EvictionCandidate> = (0..4).map(|id| candidate(id, id as u8, None)).collect();
+        candidates.extend((4..12).map(|id| candidate(id, 9, Some(50))));
+        candidates.extend((12..16).map(|id| candidate(id, 9, Some(5))));
+        candidates[15].misbehavior = 10;
+        assert_eq!(select_eviction(candidates), Some(15));
+    }
+
+    #[test]
+    fn test_netgroups() {
+        assert_eq!(netgroup("10.1.2.3".parse().unwrap()), netgroup("10.1.200.7".parse().unwrap()));
+        assert_ne!(netgroup("10.1.2.3".parse().unwrap()), netgroup("10.2.2.3".parse().unwrap()));
+        assert_eq!(netgroup("::ffff:10.1.2.3".parse().unwrap()), netgroup("10.1.9.9".parse().unwrap()));
+        assert_eq!(netgroup("2001:db8::1".parse().unwrap()), netgroup("2001:db8:ffff::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ipv6_peer_addresses_are_bracketed() {
+        let peer = Node::parse("[2001:db8::1]:8333").unwrap();
+        assert_eq!((peer.host.as_str(), peer.port), ("2001:db8::1", 8333));
+        assert_eq!(peer.addr(), "[2001:db8::1]:8333");
+        assert_eq!(Node::parse("10.0.0.1:8333").unwrap().addr(), "10.0.0.1:8333");
+        assert!(Node::parse("2001:db8::1:8333").is_err());
+        assert_eq!(peer_ip(&peer.addr()), Some("2001:db8::1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_listens_on_several_addresses_and_advertises_them() {
+        let chain = Blockchain::regtest();
+        let server = NetworkNode::new(chain.clone(), String::new())
+            .with_external_address(Node::new("203.0.113.5".to_string(), 8333));
+        {
+            let server = server.clone();
+            let addrs = ["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()];
+            tokio::spawn(async move { server.listen(&addrs).await });
+        }
+        assert!(eventually(async || server.listen_addrs().len() == 2).await);
+        // Loopback addresses aren't worth telling peers about
+        assert_eq!(server.advertised_addresses().iter().map(Node::addr).collect::<Vec<_>>(), vec!["203.0.113.5:8333"]);
+
+        let dialer = NetworkNode::new(chain, String::new())
+            .with_external_address(Node::new("2001:db8::7".to_string(), 8333));
+        for addr in server.listen_addrs() {
+            dialer.connect_peer(addr.ip().to_string(), addr.port()).await.unwrap();
+        }
+        assert!(eventually(async || server.session_addrs().iter().any(|addr| addr.starts_with("[::1]:"))).await);
+
+        // Each side learns where the other can be reached
+        assert!(dialer.known_peers().await.iter().any(|peer| peer.addr() == "203.0.113.5:8333"));
+        assert!(eventually(async || server.known_peers().await.iter().any(|peer| peer.addr() == "[2001:db8::7]:8333")).await);
+        // ...but not itself
+        assert!(!server.known_peers().await.iter().any(|peer| peer.addr() == "203.0.113.5:8333"));
+    }
+
+    #[tokio::test]
+    async fn test_listening_peers_are_learned_from_the_handshake() {
+        let chain = Blockchain::regtest();
+        let node = NetworkNode::new(chain.clone(), String::new());
+        let dialer = NetworkNode::new(chain, String::new());
+        dialer.listen_port.store(8444, Ordering::Relaxed);
+
+        let (client, server) = tokio::io::duplex(1 << 16);
+        node.spawn_session(server, "10.0.0.7:51234".to_string(), Direction::Inbound);
+        let mut client = PeerStream::plain(client);
+        let theirs = dialer.handshake(&mut client, "node").await.unwrap();
+        assert_eq!(theirs.version, PROTOCOL_VERSION);
+        assert_eq!(theirs.capabilities, CAP_FULL_HISTORY);
+
+        assert!(eventually(async || node.known_peers().await.iter().any(|peer| peer.addr() == "10.0.0.7:8444")).await);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_reaches_peers_without_a_session() {
+        let mut chain = Blockchain::regtest();
+        let tx = genesis_subdivision(&mut chain);
+        let server = NetworkNode::new(chain.clone(), String::new());
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        {
+            let server = server.clone();
+            tokio::spawn(async move { server.start_server(port).await });
+        }
+
+        let sender = NetworkNode::new(chain, String::new());
+        sender.add_peer(Node::new("127.0.0.1".to_string(), port)).await;
+        assert!(eventually(async || {
+            sender.broadcast_transaction(&tx).await.unwrap();
+            server.blockchain().read().await.mempool.get_transaction(&tx.hash()).is_some()
+        }).await);
+    }
+
+    #[tokio::test]
+    async fn test_addresses_are_exchanged() {
+        let node = NetworkNode::new(Blockchain::regtest(), String::new());
+        node.add_peer(Node::new("10.0.0.1".to_string(), 8333)).await;
+        let mut client = connect(&node).await;
+
+        let told = vec![Node::new("10.0.0.2".to_string(), 8333), Node::new("10.0.0.1".to_string(), 8333)];
+        write_message(&mut client, MAGIC, &NetworkMessage::Addr(told)).await.unwrap();
+        let addrs = exchange(&mut client, MAGIC, NetworkMessage::GetAddr, |message| match message {
+            NetworkMessage::Addr(peers) => Some(peers.iter().map(Node::addr).collect::<Vec<_>>()),
+            _ => None,
+        }).await.unwrap();
+        assert_eq!(addrs, vec!["10.0.0.2:8333", "10.0.0.1:8333"]);
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_falls_back_to_seeds() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let seeds: &'static [&'static str] = Vec::leak(vec![String::leak(dead.clone()) as &str]);
+        let params = ChainParams { seed_nodes: seeds, ..ChainParams::MAINNET };
+        let node = NetworkNode::new(Blockchain::regtest(), String::new());
+        node.add_peer(Node::parse(&dead).unwrap()).await;
+
+        // Each address is tried once, so an unreachable network gives up
+        assert_eq!(node.bootstrap(&params, 8).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_sync_downloads_only_missing_blocks() {
+        let mut chain = Blockchain::regtest().with_pow(Arc::new(TestPow));
+        let db = Database::open(":memory:").unwrap();
+        let genesis = chain.blocks[0].clone();
+        db.save_blockchain_state(&genesis, &mut chain.state, chain.bits).unwrap();
+        let db = Arc::new(Mutex::new(db));
+        let behind = NetworkNode::new(chain.clone(), String::new()).with_database(db.clone());
+        let ahead = NetworkNode::new(chain, String::new());
+        for _ in 0..BLOCKS_PER_REQUEST + 10 {
+            let blockchain = ahead.blockchain();
+            let mut chain = blockchain.write().await;
+            let block = BlockTemplate::build(&chain, "miner").block;
+            chain.apply_block(block).unwrap();
+        }
+
+        let mut client = connect(&ahead).await;
+        assert_eq!(behind.sync_state().await, SyncState::new(0, 0, 0, 0.0));
+        assert_eq!(behind.sync(&mut client).await.unwrap(), BLOCKS_PER_REQUEST + 10);
+        assert_eq!(behind.get_height().await, ahead.get_height().await);
+        // Fewer than a batch, so saved together once the sync ended
+        let tip = ahead.blockchain().read().await.blocks.last().unwrap().hash;
+        assert_eq!(db.lock().unwrap().load_tip_hash().unwrap(), Some(tip));
+        assert_eq!(db.lock().unwrap().load_blockchain().unwrap().blocks.len(), BLOCKS_PER_REQUEST + 11);
+        assert_eq!(behind.sync(&mut client).await.unwrap(), 0);
+
+        let state = behind.sync_state().await;
+        let height = (BLOCKS_PER_REQUEST + 10) as u64;
+        assert_eq!((state.headers_height, state.blocks_height), (height, height));
+        assert!(!state.syncing && state.blocks_per_sec > 0.0);
+    }
+
+    #[test]
+    fn test_sync_state_reports_progress() {
+        let state = SyncState::new(100, 42, 80, 2.0);
+        assert_eq!(state.percentage, 42.0);
+        assert!(state.syncing);
+        // A peer further ahead than the headers sets the target
+        assert_eq!(SyncState::new(50, 50, 200, 0.0).percentage, 25.0);
+        assert!(!SyncState::new(0, 0, 0, 0.0).syncing);
+    }
+
+    #[tokio::test]
+    async fn test_orphans_wait_for_their_ancestors() {
+        let chain = Blockchain::regtest().with_pow(Arc::new(TestPow));
+        let ahead = NetworkNode::new(chain.clone(), String::new());
+        let db = Arc::new(Mutex::new(Database::open(":memory:").unwrap()));
+        let behind = NetworkNode::new(chain, String::new()).with_database(db.clone());
+        let mut tip = None;
+        for _ in 0..BLOCKS_PER_REQUEST + 10 {
+            let blockchain = ahead.blockchain();
+            let mut chain = blockchain.write().await;
+            let block = BlockTemplate::build(&chain, "miner").block;
+            chain.apply_block(block.clone()).unwrap();
+            tip = Some(block);
+        }
+        let tip = tip.unwrap();
+        link(&ahead, &behind).await;
+
+        // Only the tip is pushed; its ancestors are fetched to connect it
+        ahead.broadcast_block(&tip).await.unwrap();
+        assert!(eventually(async || behind.blockchain().read().await.contains_block(&tip.hash)).await);
+        assert_eq!(behind.get_height().await, ahead.get_height().await);
+        assert!(behind.orphans.lock().unwrap().is_empty());
+        assert_eq!(db.lock().unwrap().load_tip_hash().unwrap(), Some(tip.hash));
+    }
+
+    #[test]
+    fn test_orphan_pool_is_capped() {
+        let chain = Blockchain::regtest().with_pow(Arc::new(TestPow));
+        let mut orphans = OrphanBlocks::default();
+        let mut parent = chain.blocks[0].clone();
+        let mut first = None;
+        for i in 0..=MAX_ORPHAN_BLOCKS {
+            let mut block = parent.clone();
+            block.header.previous_hash = parent.hash;
+            block.header.nonce = i as u64;
+            block.hash = block.calculate_hash();
+            first.get_or_insert(block.hash);
+            orphans.insert(block.clone());
+            parent = block;
+        }
+        assert_eq!(orphans.blocks.len(), MAX_ORPHAN_BLOCKS);
+        assert!(!orphans.blocks.contains_key(&first.unwrap()));
+
+        let oldest = orphans.order[0];
+        assert_eq!(orphans.take_children(&oldest).len(), 1);
+        assert_eq!(orphans.order.len(), MAX_ORPHAN_BLOCKS - 1);
+    }
+
+    #[tokio::test]
+    async fn test_pushed_blocks_are_applied_and_saved() {
+        let chain = Blockchain::regtest().with_pow(Arc::new(TestPow));
+        let db = Arc::new(Mutex::new(Database::open(":memory:").unwrap()));
+        let a = NetworkNode::new(chain.clone(), String::new());
+        let b = NetworkNode::new(chain.clone(), String::new()).with_database(db.clone());
+        let c = NetworkNode::new(chain, String::new());
+        link(&a, &b).await;
+        link(&b, &c).await;
+
+        // Mined elsewhere and pushed by A without A connecting it first
+        let block = {
+            let mut chain = a.blockchain().read().await.clone();
+            let block = BlockTemplate::build(&chain, "miner").block;
+            chain.apply_block(block.clone()).unwrap();
+            block
+        };
+        a.broadcast_block(&block).await.unwrap();
+
+        assert!(eventually(async || c.blockchain().read().await.contains_block(&block.hash)).await);
+        assert!(b.blockchain().read().await.contains_block(&block.hash));
+        assert_eq!(db.lock().unwrap().load_tip_hash().unwrap(), Some(block.hash));
+    }
+
+    #[tokio::test]
+    async fn test_transactions_are_announced_and_fetched() {
+        let mut chain = Blockchain::regtest();
+        let tx = genesis_subdivision(&mut chain);
+        let (a, b) = linked_nodes(chain).await;
+
+        let hash = tx.hash();
+        a.blockchain().write().await.submit_transaction(tx).unwrap();
+
+        assert!(eventually(async || b.blockchain().read().await.mempool.get_transaction(&hash).is_some()).await);
+    }
+
+    #[tokio::test]
+    async fn test_pushed_transactions_are_relayed_once() {
+        let mut chain = Blockchain::regtest();
+        let tx = genesis_subdivision(&mut chain);
+        let hash = tx.hash();
+        let (a, b) = linked_nodes(chain.clone()).await;
+        let c = NetworkNode::new(chain, String::new());
+        link(&b, &c).await;
+        link(&c, &a).await;
+
+        let mut client = connect(&a).await;
+        for _ in 0..2 {
+            write_message(&mut client, MAGIC, &NetworkMessage::NewTransaction(Box::new(tx.clone()))).await.unwrap();
+        }
+
+        assert!(eventually(async || c.blockchain().read().await.mempool.get_transaction(&hash).is_some()).await);
+        assert!(b.blockchain().read().await.mempool.get_transaction(&hash).is_some());
+
+        // The client sent it, so it is never announced back
+        write_message(&mut client, MAGIC, &NetworkMessage::Ping).await.unwrap();
+        assert!(matches!(read_message(&mut client, MAGIC).await.unwrap(), Some(NetworkMessage::Pong)));
+        assert!(!a.first_sighting(hash));
+    }
+
+    #[tokio::test]
+    async fn test_one_shot_requests_are_answered() {
+        let chain = Blockchain::regtest();
+        let node = NetworkNode::new(chain.clone(), String::new());
+        let mut client = connect(&node).await;
+
+        write_message(&mut client, MAGIC, &NetworkMessage::GetBlocks(vec![[7; 32]])).await.unwrap();
+        assert!(matches!(read_message(&mut client, MAGIC).await.unwrap(), Some(NetworkMessage::Blocks(blocks)) if blocks.is_empty()));
+
+        let wanted = vec![InvItem::Block(chain.blocks[0].hash), InvItem::Tx([1; 32])];
+        write_message(&mut client, MAGIC, &NetworkMessage::GetData(wanted)).await.unwrap();
+        assert!(matches!(read_message(&mut client, MAGIC).await.unwrap(), Some(NetworkMessage::Block(_))));
+        assert!(matches!(
+            read_message(&mut client, MAGIC).await.unwrap(),
+            Some(NetworkMessage::NotFound(items)) if items == vec![InvItem::Tx([1; 32])]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_getdata_ends_the_session() {
+        let node = NetworkNode::new(Blockchain::regtest(), String::new());
+        let mut client = connect(&node).await;
+
+        let wanted = vec![InvItem::Tx([1; 32]); MAX_INV_ITEMS + 1];
+        write_message(&mut client, MAGIC, &NetworkMessage::GetData(wanted)).await.unwrap();
+        assert!(!matches!(read_message(&mut client, MAGIC).await, Ok(Some(_))));
     }
-    
-    Ok(())
 }
@@ -0,0 +1,294 @@
+//! Encrypted peer connections for siertrichain
+//!
+//! A node with a key opens each connection with `NOISE_MARKER` and a
+//! Noise_XX handshake, then carries the framed protocol (see `network`) in
+//! Noise transport messages: a big-endian `u16` length, then the ciphertext.
+//! Its static key is derived from the node key, and the handshake's prologue
+//! is the network magic, so peers of another network fail the handshake.
+//! A connection that starts with anything else is plaintext.
+
+use crate::error::ChainError;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+
+/// What an encrypted connection starts with, where a plaintext one has its
+/// first frame's network magic
+pub const NOISE_MARKER: [u8; 4] = *b"NOIS";
+
+/// Longest Noise message, handshake or transport
+const MAX_NOISE_MESSAGE: usize = u16::MAX as usize;
+
+/// Authentication tag on each transport message
+const TAG_SIZE: usize = 16;
+
+/// Most plaintext carried by one transport message
+const MAX_CHUNK: usize = MAX_NOISE_MESSAGE - TAG_SIZE;
+
+/// A connection to a peer, encrypted or not. Reads and writes pass through
+/// unchanged on a plaintext connection.
+pub struct PeerStream<S> {
+    inner: S,
+    /// Ciphers agreed in the handshake; `None` on a plaintext connection
+    transport: Option<Box<snow::TransportState>>,
+    /// Hash of the handshake's transcript, the same on both sides; `None` on
+    /// a plaintext connection
+    handshake_hash: Option<Vec<u8>>,
+    /// Bytes read but not yet returned: decrypted data, or the marker-sized
+    /// start of a plaintext connection
+    readable: Vec<u8>,
+    read_pos: usize,
+    /// A transport message still arriving
+    incoming: Vec<u8>,
+    /// Transport messages not yet written to `inner`
+    outgoing: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PeerStream<S> {
+    /// A plaintext connection
+    pub fn plain(inner: S) -> Self {
+        PeerStream {
+            inner,
+            transport: None,
+            handshake_hash: None,
+            readable: Vec::new(),
+            read_pos: 0,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+        }
+    }
+
+    /// Encrypt a connection this side opened, as the handshake's initiator
+    pub async fn connect(mut inner: S, static_key: &[u8], prologue: &[u8]) -> Result<Self, ChainError> {
+        let mut handshake = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(static_key)
+            .prologue(prologue)
+            .build_initiator()
+            .map_err(handshake_failed)?;
+
+        inner.write_all(&NOISE_MARKER).await.map_err(io_failed)?;
+        send_handshake(&mut inner, &mut handshake).await?;
+        receive_handshake(&mut inner, &mut handshake).await?;
+        send_handshake(&mut inner, &mut handshake).await?;
+        Self::encrypted(inner, handshake)
+    }
+
+    /// Encrypt a connection the peer opened, as the handshake's responder, if
+    /// it starts with `NOISE_MARKER`; otherwise carry on in plaintext
+    pub async fn accept(mut inner: S, static_key: &[u8], prologue: &[u8]) -> Result<Self, ChainError> {
+        let mut start = [0u8; NOISE_MARKER.len()];
+        inner.read_exact(&mut start).await.map_err(io_failed)?;
+        if start != NOISE_MARKER {
+            let mut stream = Self::plain(inner);
+            stream.readable = start.to_vec();
+            return Ok(stream);
+        }
+
+        let mut handshake = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(static_key)
+            .prologue(prologue)
+            .build_responder()
+            .map_err(handshake_failed)?;
+
+        receive_handshake(&mut inner, &mut handshake).await?;
+        send_handshake(&mut inner, &mut handshake).await?;
+        receive_handshake(&mut inner, &mut handshake).await?;
+        Self::encrypted(inner, handshake)
+    }
+
+    fn encrypted(inner: S, handshake: snow::HandshakeState) -> Result<Self, ChainError> {
+        let handshake_hash = handshake.get_handshake_hash().to_vec();
+        let transport = handshake.into_transport_mode().map_err(handshake_failed)?;
+        let mut stream = Self::plain(inner);
+        stream.transport = Some(Box::new(transport));
+        stream.handshake_hash = Some(handshake_hash);
+        Ok(stream)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.transport.is_some()
+    }
+
+    /// What a signature must cover to be bound to this connection: a
+    /// man-in-the-middle runs a handshake of its own with each side, so it
+    /// can't relay one. `None` on a plaintext connection.
+    pub fn handshake_hash(&self) -> Option<&[u8]> {
+        self.handshake_hash.as_deref()
+    }
+}
+
+/// Write the next handshake message, which carries no payload
+async fn send_handshake<S: AsyncWrite + Unpin>(inner: &mut S, handshake: &mut snow::HandshakeState) -> Result<(), ChainError> {
+    let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+    let len = handshake.write_message(&[], &mut message).map_err(handshake_failed)?;
+    inner.write_all(&(len as u16).to_be_bytes()).await.map_err(io_failed)?;
+    inner.write_all(&message[..len]).await.map_err(io_failed)?;
+    inner.flush().await.map_err(io_failed)
+}
+
+async fn receive_handshake<S: AsyncRead + Unpin>(inner: &mut S, handshake: &mut snow::HandshakeState) -> Result<(), ChainError> {
+    let mut len = [0u8; 2];
+    inner.read_exact(&mut len).await.map_err(io_failed)?;
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    inner.read_exact(&mut message).await.map_err(io_failed)?;
+    let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+    handshake.read_message(&message, &mut payload).map_err(handshake_failed)?;
+    Ok(())
+}
+
+fn handshake_failed(e: snow::Error) -> ChainError {
+    ChainError::IncompatiblePeer(format!("failed the encryption handshake: {}", e))
+}
+
+fn io_failed(e: io::Error) -> ChainError {
+    ChainError::NetworkError(format!("Encryption handshake failed: {}", e))
+}
+
+/// Length of the transport message at the start of `buffer`, if all of it has arrived
+fn complete_message(buffer: &[u8]) -> Option<usize> {
+    let len = u16::from_be_bytes(buffer.get(..2)?.try_into().unwrap()) as usize;
+    (buffer.len() >= 2 + len).then_some(len)
+}
+
+/// Write out all of `outgoing`
+fn poll_drain<S: AsyncWrite + Unpin>(inner: &mut S, outgoing: &mut Vec<u8>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    while !outgoing.is_empty() {
+        let written = ready!(Pin::new(&mut *inner).poll_write(cx, outgoing))?;
+        if written == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        outgoing.drain(..written);
+    }
+    Poll::Ready(Ok(()))
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PeerStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.readable.len() {
+                let n = buf.remaining().min(this.readable.len() - this.read_pos);
+                buf.put_slice(&this.readable[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                if this.read_pos == this.readable.len() {
+                    this.readable.clear();
+                    this.read_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            let Some(transport) = this.transport.as_mut() else {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            };
+            if let Some(len) = complete_message(&this.incoming) {
+                let mut plaintext = vec![0u8; len];
+                let n = transport.read_message(&this.incoming[2..2 + len], &mut plaintext)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                plaintext.truncate(n);
+                this.incoming.drain(..2 + len);
+                this.readable = plaintext;
+                continue;
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return Poll::Ready(if this.incoming.is_empty() {
+                    Ok(())
+                } else {
+                    Err(io::ErrorKind::UnexpectedEof.into())
+                });
+            }
+            this.incoming.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PeerStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(transport) = this.transport.as_mut() else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        // Don't buffer more than one message ahead of the connection
+        if !this.outgoing.is_empty() {
+            ready!(poll_drain(&mut this.inner, &mut this.outgoing, cx))?;
+        }
+
+        let n = buf.len().min(MAX_CHUNK);
+        let mut message = vec![0u8; n + TAG_SIZE];
+        let len = transport.write_message(&buf[..n], &mut message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        this.outgoing.extend_from_slice(&(len as u16).to_be_bytes());
+        this.outgoing.extend_from_slice(&message[..len]);
+
+        // Whatever doesn't go out now goes with the next write or flush
+        if let Poll::Ready(Err(e)) = poll_drain(&mut this.inner, &mut this.outgoing, cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(poll_drain(&mut this.inner, &mut this.outgoing, cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(poll_drain(&mut this.inner, &mut this.outgoing, cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_encrypted_round_trip() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let accept = tokio::spawn(PeerStream::accept(server, &[2; 32], b"STRI"));
+        let mut client = PeerStream::connect(client, &[1; 32], b"STRI").await.unwrap();
+        let mut server = accept.await.unwrap().unwrap();
+        assert!(client.is_encrypted() && server.is_encrypted());
+        assert!(client.handshake_hash().is_some());
+        assert_eq!(client.handshake_hash(), server.handshake_hash());
+
+        // Longer than one transport message
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let writer = tokio::spawn(async move {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+            data
+        });
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, writer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_and_foreign_peers() {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        client.write_all(b"STRI and the rest").await.unwrap();
+        drop(client);
+        let mut server = PeerStream::accept(server, &[2; 32], b"STRI").await.unwrap();
+        assert!(!server.is_encrypted());
+        assert!(server.handshake_hash().is_none());
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"STRI and the rest");
+
+        // Another network's prologue fails the handshake
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let accept = tokio::spawn(PeerStream::accept(server, &[2; 32], b"STRI"));
+        let connect = PeerStream::connect(client, &[1; 32], b"TEST").await;
+        assert!(matches!(connect, Err(ChainError::IncompatiblePeer(_))));
+        assert!(accept.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,75 @@
+//! Consensus parameters for siertrichain
+//!
+//! Values every node must agree on before the first block is mined. The
+//! genesis triangle is defined here and nowhere else: two definitions that
+//! drift apart would give nodes different roots for the whole fractal.
+
+use crate::difficulty;
+use crate::geometry::{Point, Triangle, TriangleId};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainParams {
+    /// Name of the network, recorded in its nodes' databases so one built
+    /// for another network is refused
+    pub network: &'static str,
+    /// Vertices of the genesis triangle, the root every `Δ/...` path starts from
+    pub genesis_vertices: [Point; 3],
+    /// Owner of the genesis triangle
+    pub genesis_owner: &'static str,
+    /// Sent in every handshake, so nodes of different networks refuse each other
+    pub magic: [u8; 4],
+    /// `host:port` of long-running nodes a node with no known peers asks for
+    /// addresses
+    pub seed_nodes: &'static [&'static str],
+    /// Easiest proof-of-work target, in compact bits, which the genesis block
+    /// carries and retargeting never goes past
+    pub pow_limit_bits: u32,
+}
+
+impl ChainParams {
+    /// The parameters of the main network: a unit-sided equilateral genesis
+    /// triangle. The apex height is the truncated value the first blocks were
+    /// mined with; changing it would change every triangle id below genesis.
+    pub const MAINNET: ChainParams = ChainParams {
+        network: "main",
+        genesis_vertices: [
+            Point { x: 0.0, y: 0.0 },
+            Point { x: 1.0, y: 0.0 },
+            Point { x: 0.5, y: 0.866025403784 },
+        ],
+        genesis_owner: "genesis_owner",
+        magic: *b"STRI",
+        // No public seed nodes run yet; peers are found through `--peer`
+        // and the peers file until some do
+        seed_nodes: &[],
+        pow_limit_bits: difficulty::POW_LIMIT_BITS,
+    };
+
+    /// A private network for testing, started with `--regtest`: the main
+    /// network's genesis triangle, but its own magic so its nodes never talk
+    /// to main network ones, no seed nodes, and a proof-of-work limit nearly
+    /// any hash meets
+    pub const REGTEST: ChainParams = ChainParams {
+        network: "regtest",
+        magic: *b"SRRT",
+        seed_nodes: &[],
+        pow_limit_bits: difficulty::REGTEST_POW_LIMIT_BITS,
+        ..ChainParams::MAINNET
+    };
+
+    /// The genesis triangle, unspent in the state of every new chain
+    pub fn genesis_triangle(&self) -> Triangle {
+        let [a, b, c] = self.genesis_vertices;
+        Triangle::new(a, b, c, None, self.genesis_owner.to_string())
+    }
+
+    pub fn genesis_id(&self) -> TriangleId {
+        self.genesis_triangle().hash()
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams::MAINNET
+    }
+}
@@ -0,0 +1,85 @@
+//! Known-good peer addresses, kept between runs so a restarted node can
+//! reconnect without being told where its peers are
+
+use crate::error::ChainError;
+use crate::network::Node;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Most addresses kept; the ones synced with longest ago are dropped first
+pub const MAX_STORED_PEERS: usize = 1000;
+
+/// Peers this node has synced with, most recent last
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct PeerStore {
+    pub peers: Vec<Node>,
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        PeerStore { peers: Vec::new() }
+    }
+
+    /// Record a peer as good, moving it to the end if already known
+    pub fn add(&mut self, peer: Node) {
+        self.peers.retain(|p| p.addr() != peer.addr());
+        self.peers.push(peer);
+        if self.peers.len() > MAX_STORED_PEERS {
+            let excess = self.peers.len() - MAX_STORED_PEERS;
+            self.peers.drain(..excess);
+        }
+    }
+
+    /// Save the store to a file
+    pub fn save(&self, path: &Path) -> Result<(), ChainError> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| ChainError::NetworkError(format!("Failed to serialize peers: {}", e)))?;
+
+        fs::write(path, json)
+            .map_err(|e| ChainError::NetworkError(format!("Failed to write peers: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Load the store from a file, or start empty if there is none yet
+    pub fn load(path: &Path) -> Result<Self, ChainError> {
+        if !path.exists() {
+            return Ok(PeerStore::new());
+        }
+
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ChainError::NetworkError(format!("Failed to read peers: {}", e)))?;
+
+        serde_json::from_str(&contents)
+            .map_err(|e| ChainError::NetworkError(format!("Failed to parse peers: {}", e)))
+    }
+}
+
+/// Get the default peers file path
+pub fn get_peers_path() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home).join(".siertrichain").join("peers.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_store_round_trip() {
+        let path = std::env::temp_dir().join(format!("siertrichain-peers-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(PeerStore::load(&path).unwrap().peers.is_empty());
+
+        let mut store = PeerStore::new();
+        store.add(Node::new("10.0.0.1".to_string(), 8333));
+        store.add(Node::new("10.0.0.2".to_string(), 8333));
+        store.add(Node::new("10.0.0.1".to_string(), 8333));
+        store.save(&path).unwrap();
+
+        let addrs: Vec<_> = PeerStore::load(&path).unwrap().peers.iter().map(Node::addr).collect();
+        assert_eq!(addrs, vec!["10.0.0.2:8333", "10.0.0.1:8333"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,93 +1,261 @@
 //! Database persistence layer for siertrichain
 
-use rusqlite::{Connection, params};
-use crate::blockchain::{Blockchain, Block, BlockHeader, TriangleState, Mempool};
-use crate::transaction::Transaction;
-use crate::geometry::Triangle;
+use rusqlite::{Connection, OpenFlags, params};
+use rusqlite::backup::Backup;
+use rusqlite::types::ValueRef;
+use serde::de::DeserializeOwned;
+use crate::blockchain::{Blockchain, Block, BlockHeader, BlockHeight, BlockTransactions, ChainVerificationReport, HeaderChain, Sha256Hash, TriangleState, Mempool, UtxoChanges, MAX_BLOCK_SIZE};
+use crate::transaction::{Address, AddressRole, Approval, EscrowContract, HtlcContract, LeaseContract, MarketOffer, Transaction};
+use crate::geometry::{Triangle, TriangleId};
 use crate::error::ChainError;
+use crate::codec;
+use crate::migrations;
+use crate::config::{Config, DB_FILE};
+use crate::params::ChainParams;
+use crate::difficulty::{self, Work};
+use crate::events::EventBus;
+use crate::crypto::SignatureCache;
+use crate::stratum::PoolShare;
+use crate::network::{PeerStatus, SyncState};
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long a statement waits for another process to release the database
+/// before failing as busy
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a `BackupSchedule` backs up the database by default
+pub const DEFAULT_BACKUP_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Backups a `BackupSchedule` keeps by default, the oldest being deleted first
+pub const DEFAULT_BACKUP_RETENTION: usize = 7;
+
+/// File names of scheduled backups are this, then the time taken, then `.db`
+const BACKUP_PREFIX: &str = "siertrichain-";
 
 pub struct Database {
     conn: Connection,
+    /// Whether blocks are written to the address index
+    address_index: bool,
+    /// Opened with `open_read_only`, so loading writes nothing back
+    read_only: bool,
+    /// The network the chain must belong to
+    params: ChainParams,
+}
+
+/// What `Database::verify` found
+#[derive(Debug, Clone)]
+pub struct IntegrityReport {
+    /// Re-validation of the stored chain, if it could be loaded
+    pub chain: Option<ChainVerificationReport>,
+    /// Inconsistencies between the tables themselves
+    pub problems: Vec<String>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty() && self.chain.as_ref().is_some_and(ChainVerificationReport::is_ok)
+    }
+}
+
+/// A transaction naming an address, as found in the address index
+#[derive(Debug, Clone)]
+pub struct AddressHistoryEntry {
+    pub txid: Sha256Hash,
+    pub height: BlockHeight,
+    pub timestamp: i64,
+    /// The parts the address plays in it
+    pub roles: Vec<AddressRole>,
+    pub transaction: Transaction,
 }
 
 impl Database {
-    pub fn open(path: &str) -> Result<Self, ChainError> {
-        let conn = Connection::open(path)
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ChainError> {
+        let mut conn = Connection::open(path)
             .map_err(|e| ChainError::DatabaseError(format!("Failed to open database: {}", e)))?;
+        conn.busy_timeout(BUSY_TIMEOUT)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to set busy timeout: {}", e)))?;
+        // With a write-ahead log, readers in other processes don't block the
+        // node saving blocks, nor it them. In-memory databases keep their own mode.
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to enable WAL mode: {}", e)))?;
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS blocks (
-                height INTEGER PRIMARY KEY,
-                hash BLOB NOT NULL,
-                previous_hash BLOB NOT NULL,
-                timestamp INTEGER NOT NULL,
-                difficulty INTEGER NOT NULL,
-                nonce INTEGER NOT NULL,
-                merkle_root BLOB NOT NULL,
-                transactions TEXT NOT NULL
-            )",
-            [],
-        ).map_err(|e| ChainError::DatabaseError(format!("Failed to create blocks table: {}", e)))?;
+        migrations::migrate(&mut conn)?;
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS utxo_set (
-                hash BLOB PRIMARY KEY,
-                triangle_data TEXT NOT NULL
-            )",
-            [],
-        ).map_err(|e| ChainError::DatabaseError(format!("Failed to create utxo_set table: {}", e)))?;
+        Self::with_connection(conn, false)
+    }
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS metadata (
-                key TEXT PRIMARY KEY,
-                value TEXT NOT NULL
-            )",
+    /// Open an existing database without writing to it, for tools that only
+    /// read the chain, even while a node saves to it. Its schema must be the
+    /// one this build writes, since it can't be migrated.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self, ChainError> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to open database: {}", e)))?;
+        conn.busy_timeout(BUSY_TIMEOUT)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to set busy timeout: {}", e)))?;
+
+        migrations::require_latest(&conn)?;
+
+        Self::with_connection(conn, true)
+    }
+
+    /// Open the database in the data directory `Config::from_env` names,
+    /// creating both if needed
+    pub fn open_default() -> Result<Self, ChainError> {
+        Self::open_in(&Config::from_env())
+    }
+
+    /// Open the database in `config`'s data directory, creating both if needed
+    pub fn open_in(config: &Config) -> Result<Self, ChainError> {
+        config.ensure_datadir()?;
+        warn_of_legacy_database(config);
+        Ok(Self::open(config.db_path())?.with_params(config.params.clone()))
+    }
+
+    /// `open_read_only` on the database in `config`'s data directory
+    pub fn open_read_only_in(config: &Config) -> Result<Self, ChainError> {
+        Ok(Self::open_read_only(config.db_path())?.with_params(config.params.clone()))
+    }
+
+    fn with_connection(conn: Connection, read_only: bool) -> Result<Self, ChainError> {
+        let address_index = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM metadata WHERE key = 'address_index'",
             [],
-        ).map_err(|e| ChainError::DatabaseError(format!("Failed to create metadata table: {}", e)))?;
+            |row| row.get(0),
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to read address index setting: {}", e)))?;
+
+        Ok(Database { conn, address_index, read_only, params: ChainParams::MAINNET })
+    }
+
+    /// Expect the chain of the network `params` describes rather than the
+    /// main network's. Loading a database of another network fails.
+    pub fn with_params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self
+    }
 
-        Ok(Database { conn })
+    /// Whether the database was opened with `open_read_only`
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
     }
 
+    /// Whether the address index is kept
+    pub fn has_address_index(&self) -> bool {
+        self.address_index
+    }
+
+    /// Turn the address index on, indexing the transactions already stored,
+    /// or off, dropping it. The setting is kept in the database, so every
+    /// process saving blocks to it keeps the index up to date.
+    pub fn set_address_index(&mut self, enabled: bool) -> Result<(), ChainError> {
+        if enabled == self.address_index {
+            return Ok(());
+        }
+        let tx = self.conn.unchecked_transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+
+        if enabled {
+            reindex_addresses(&tx)?;
+            tx.execute("INSERT OR REPLACE INTO metadata (key, value) VALUES ('address_index', '1')", [])
+        } else {
+            tx.execute("DELETE FROM address_index", [])
+                .map_err(|e| ChainError::DatabaseError(format!("Failed to clear address index: {}", e)))?;
+            tx.execute("DELETE FROM metadata WHERE key = 'address_index'", [])
+        }.map_err(|e| ChainError::DatabaseError(format!("Failed to save address index setting: {}", e)))?;
+
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+        self.address_index = enabled;
+
+        Ok(())
+    }
+
+    /// The transactions naming `address`, oldest first, from the address
+    /// index. Fails if the index is off.
+    pub fn load_address_history(&self, address: &str) -> Result<Vec<AddressHistoryEntry>, ChainError> {
+        if !self.address_index {
+            return Err(ChainError::DatabaseError("The address index is off".to_string()));
+        }
+        let mut stmt = self.conn.prepare(
+            "SELECT a.txid, a.height, b.timestamp, group_concat(a.role), t.data
+             FROM address_index a
+             JOIN chain c ON c.height = a.height
+             JOIN blocks b ON b.hash = c.hash
+             JOIN transactions t ON t.block_hash = c.hash AND t.position = a.position
+             WHERE a.address = ?1
+             GROUP BY a.height, a.position
+             ORDER BY a.height, a.position"
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
+
+        let rows = stmt.query_map(params![address], |row| Ok((
+            row.get::<_, Vec<u8>>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, i64>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Vec<u8>>(4)?,
+        ))).map_err(|e| ChainError::DatabaseError(format!("Failed to query address index: {}", e)))?;
+
+        rows.map(|row| {
+            let (txid, height, timestamp, roles, data) = row
+                .map_err(|e| ChainError::DatabaseError(format!("Failed to read row: {}", e)))?;
+            Ok(AddressHistoryEntry {
+                txid: txid.try_into()
+                    .map_err(|_| ChainError::DatabaseError("Stored txid is not 32 bytes".to_string()))?,
+                height: height as BlockHeight,
+                timestamp,
+                roles: roles.split(',').map(str::parse).collect::<Result<_, _>>()?,
+                transaction: codec::decode(&data)?,
+            })
+        }).collect()
+    }
+
+    /// Store a block as the main chain's at its height
     pub fn save_block(&self, block: &Block) -> Result<(), ChainError> {
-        let transactions_json = serde_json::to_string(&block.transactions)
-            .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize transactions: {}", e)))?;
+        let tx = self.conn.unchecked_transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
 
-        self.conn.execute(
-            "INSERT OR REPLACE INTO blocks (height, hash, previous_hash, timestamp, difficulty, nonce, merkle_root, transactions)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
-            params![
-                block.header.height as i64,
-                block.hash.to_vec(),
-                block.header.previous_hash.to_vec(),
-                block.header.timestamp,
-                block.header.difficulty as i64,
-                block.header.nonce as i64,
-                block.header.merkle_root.to_vec(),
-                transactions_json,
-            ],
-        ).map_err(|e| ChainError::DatabaseError(format!("Failed to save block: {}", e)))?;
+        insert_block(&tx, block, self.address_index)?;
+        record_chain_identity(&tx, self.params.network)?;
+
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
 
         Ok(())
     }
 
-    pub fn save_utxo_set(&self, state: &TriangleState) -> Result<(), ChainError> {
-        // Use a transaction for atomic UTXO set update
+    /// Take the blocks above `height` off the stored main chain, as a reorg
+    /// does. They stay stored by hash.
+    pub fn disconnect_blocks_above(&self, height: BlockHeight) -> Result<(), ChainError> {
         let tx = self.conn.unchecked_transaction()
             .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
 
-        tx.execute("DELETE FROM utxo_set", [])
-            .map_err(|e| ChainError::DatabaseError(format!("Failed to clear utxo_set: {}", e)))?;
+        disconnect_above(&tx, height)?;
+        record_chain_identity(&tx, self.params.network)?;
 
-        for (hash, triangle) in &state.utxo_set {
-            let triangle_json = serde_json::to_string(triangle)
-                .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize triangle: {}", e)))?;
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
 
-            tx.execute(
-                "INSERT INTO utxo_set (hash, triangle_data) VALUES (?1, ?2)",
-                params![hash.to_vec(), triangle_json],
-            ).map_err(|e| ChainError::DatabaseError(format!("Failed to save UTXO: {}", e)))?;
-        }
+        Ok(())
+    }
+
+    /// The transaction with `txid` and the height of the block holding it,
+    /// decoding nothing else. The highest wins if several blocks hold it.
+    pub fn load_transaction(&self, txid: &Sha256Hash) -> Result<Option<(BlockHeight, Transaction)>, ChainError> {
+        find_transaction(&self.conn, txid)
+    }
+
+    /// Replace the stored UTXO set with all of `state`
+    pub fn save_utxo_set(&self, state: &TriangleState) -> Result<(), ChainError> {
+        // Use a transaction for atomic UTXO set update
+        let tx = self.conn.unchecked_transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+
+        rewrite_utxo_set(&tx, state)?;
+        save_state_metadata(&tx, "utxo_set", state)?;
 
         tx.commit()
             .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
@@ -96,238 +264,1896 @@ impl Database {
     }
 
     pub fn load_utxo_set(&self) -> Result<TriangleState, ChainError> {
+        let mut state = self.load_triangle_table("utxo_set")?;
+        // The state matches what's stored, unless rows from before the zorder
+        // column still need it filled in by saving the whole set
+        let missing_zorder: bool = self.conn.query_row(
+            "SELECT COUNT(*) > 0 FROM utxo_set WHERE zorder IS NULL",
+            [],
+            |row| row.get(0),
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to inspect utxo_set table: {}", e)))?;
+        if !missing_zorder {
+            state.mark_utxo_saved();
+        }
+        Ok(state)
+    }
+
+    /// Unspent triangles whose `Triangle::zorder_key` lies in `range`, in key
+    /// order. Keys are stored big-endian, so byte order is key order.
+    pub fn load_utxos_by_zorder(&self, range: RangeInclusive<u128>) -> Result<Vec<(TriangleId, Triangle)>, ChainError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT hash, triangle_data FROM utxo_set WHERE zorder BETWEEN ?1 AND ?2 ORDER BY zorder"
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
+
+        let rows = stmt.query_map(
+            params![range.start().to_be_bytes().to_vec(), range.end().to_be_bytes().to_vec()],
+            |row| Ok((row.get::<_, Vec<u8>>(0)?, decode_stored::<Triangle>(row.get_ref(1)?))),
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to query UTXO set: {}", e)))?;
+
+        rows.map(|row| {
+            let (hash, triangle) = row.map_err(|e| ChainError::DatabaseError(format!("Failed to read row: {}", e)))?;
+            let hash: TriangleId = hash.try_into()
+                .map_err(|_| ChainError::DatabaseError("Stored triangle hash is not 32 bytes".to_string()))?;
+            let triangle = triangle
+                .map_err(|e| ChainError::DatabaseError(format!("Failed to deserialize triangle: {}", e)))?;
+            Ok((hash, triangle))
+        }).collect()
+    }
+
+    fn load_triangle_table(&self, table: &str) -> Result<TriangleState, ChainError> {
         let mut utxo_set = HashMap::new();
 
-        let mut stmt = self.conn.prepare("SELECT hash, triangle_data FROM utxo_set")
+        let mut stmt = self.conn.prepare(&format!("SELECT hash, triangle_data FROM {}", table))
             .map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
 
         let rows = stmt.query_map([], |row| {
             let hash_bytes: Vec<u8> = row.get(0)?;
-            let triangle_json: String = row.get(1)?;
-            Ok((hash_bytes, triangle_json))
+            let triangle = decode_stored::<Triangle>(row.get_ref(1)?);
+            Ok((hash_bytes, triangle))
         }).map_err(|e| ChainError::DatabaseError(format!("Failed to query UTXO set: {}", e)))?;
 
         for row_result in rows {
-            let (hash_bytes, triangle_json) = row_result
+            let (hash_bytes, triangle) = row_result
                 .map_err(|e| ChainError::DatabaseError(format!("Failed to read row: {}", e)))?;
 
             let mut hash = [0u8; 32];
             hash.copy_from_slice(&hash_bytes);
 
-            let triangle: Triangle = serde_json::from_str(&triangle_json)
+            let triangle = triangle
                 .map_err(|e| ChainError::DatabaseError(format!("Failed to deserialize triangle: {}", e)))?;
 
             utxo_set.insert(hash, triangle);
         }
 
-        Ok(TriangleState { utxo_set })
+        // Address nonces and open contracts are stored alongside the set they belong to
+        let nonces = self.load_metadata_json(&format!("{}_nonces", table))?.unwrap_or_default();
+        let htlcs: Vec<(Sha256Hash, HtlcContract)> =
+            self.load_metadata_json(&format!("{}_htlcs", table))?.unwrap_or_default();
+        let escrows: Vec<(Sha256Hash, EscrowContract)> =
+            self.load_metadata_json(&format!("{}_escrows", table))?.unwrap_or_default();
+        let offers: Vec<(Sha256Hash, MarketOffer)> =
+            self.load_metadata_json(&format!("{}_offers", table))?.unwrap_or_default();
+        let leases: Vec<(Sha256Hash, LeaseContract)> =
+            self.load_metadata_json(&format!("{}_leases", table))?.unwrap_or_default();
+        let approvals: Vec<(Sha256Hash, Approval)> =
+            self.load_metadata_json(&format!("{}_approvals", table))?.unwrap_or_default();
+
+        let mut state = TriangleState {
+            utxo_set,
+            nonces,
+            htlcs: htlcs.into_iter().collect(),
+            escrows: escrows.into_iter().collect(),
+            offers: offers.into_iter().collect(),
+            leases: leases.into_iter().collect(),
+            approvals: approvals.into_iter().collect(),
+            ..TriangleState::new()
+        };
+        state.rebuild_indexes();
+        Ok(state)
+    }
+
+    fn load_metadata_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, ChainError> {
+        let json = self.conn.query_row(
+            "SELECT value FROM metadata WHERE key = ?1",
+            params![key],
+            |row| row.get::<_, String>(0),
+        ).ok();
+
+        json.map(|json| serde_json::from_str(&json)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to deserialize {}: {}", key, e))))
+            .transpose()
     }
 
-    pub fn save_difficulty(&self, difficulty: u64) -> Result<(), ChainError> {
+    pub fn save_difficulty(&self, bits: u32) -> Result<(), ChainError> {
         self.conn.execute(
             "INSERT OR REPLACE INTO metadata (key, value) VALUES ('difficulty', ?1)",
-            params![difficulty.to_string()],
+            params![bits.to_string()],
         ).map_err(|e| ChainError::DatabaseError(format!("Failed to save difficulty: {}", e)))?;
 
         Ok(())
     }
 
-    /// Atomically saves a block and the associated blockchain state
-    /// This ensures database consistency by wrapping all operations in a transaction
-    pub fn save_blockchain_state(&self, block: &Block, state: &TriangleState, difficulty: u64) -> Result<(), ChainError> {
+    /// Set how many recent blocks keep their transactions. Takes effect the next
+    /// time the blockchain is loaded; `None` turns pruning off for new blocks.
+    pub fn set_prune_depth(&self, depth: Option<u64>) -> Result<(), ChainError> {
+        match depth {
+            Some(depth) => self.conn.execute(
+                "INSERT OR REPLACE INTO metadata (key, value) VALUES ('prune_depth', ?1)",
+                params![depth.to_string()],
+            ),
+            None => self.conn.execute("DELETE FROM metadata WHERE key = 'prune_depth'", []),
+        }.map_err(|e| ChainError::DatabaseError(format!("Failed to save prune depth: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Atomically drops stored transactions for blocks the chain has pruned and
+    /// saves the pruned height and replay base. Does nothing for an unpruned chain.
+    pub fn save_prune_state(&self, chain: &Blockchain) -> Result<(), ChainError> {
+        let base = match &chain.prune_base {
+            Some(base) if chain.pruned_height > 0 => base,
+            _ => return Ok(()),
+        };
+
         let tx = self.conn.unchecked_transaction()
             .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
 
-        // Save block
-        let transactions_json = serde_json::to_string(&block.transactions)
-            .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize transactions: {}", e)))?;
-
         tx.execute(
-            "INSERT OR REPLACE INTO blocks (height, hash, previous_hash, timestamp, difficulty, nonce, merkle_root, transactions)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
-            params![
-                block.header.height as i64,
-                block.hash.to_vec(),
-                block.header.previous_hash.to_vec(),
-                block.header.timestamp,
-                block.header.difficulty as i64,
-                block.header.nonce as i64,
-                block.header.merkle_root.to_vec(),
-                transactions_json,
-            ],
-        ).map_err(|e| ChainError::DatabaseError(format!("Failed to save block: {}", e)))?;
-
-        // Save UTXO set
-        tx.execute("DELETE FROM utxo_set", [])
-            .map_err(|e| ChainError::DatabaseError(format!("Failed to clear utxo_set: {}", e)))?;
+            "DELETE FROM transactions WHERE block_hash IN (SELECT hash FROM chain WHERE height > 0 AND height <= ?1)",
+            params![chain.pruned_height as i64],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prune transactions: {}", e)))?;
+        tx.execute(
+            "DELETE FROM address_index WHERE height > 0 AND height <= ?1",
+            params![chain.pruned_height as i64],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prune address index: {}", e)))?;
+        tx.execute(
+            "UPDATE blocks SET transactions = X'' WHERE hash IN (SELECT hash FROM chain WHERE height > 0 AND height <= ?1)",
+            params![chain.pruned_height as i64],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prune blocks: {}", e)))?;
 
-        for (hash, triangle) in &state.utxo_set {
-            let triangle_json = serde_json::to_string(triangle)
-                .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize triangle: {}", e)))?;
+        tx.execute("DELETE FROM prune_base_utxo_set", [])
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to clear prune_base_utxo_set: {}", e)))?;
 
+        for (hash, triangle) in &base.utxo_set {
             tx.execute(
-                "INSERT INTO utxo_set (hash, triangle_data) VALUES (?1, ?2)",
-                params![hash.to_vec(), triangle_json],
-            ).map_err(|e| ChainError::DatabaseError(format!("Failed to save UTXO: {}", e)))?;
+                "INSERT INTO prune_base_utxo_set (hash, triangle_data) VALUES (?1, ?2)",
+                params![hash.to_vec(), codec::encode(triangle)],
+            ).map_err(|e| ChainError::DatabaseError(format!("Failed to save prune base UTXO: {}", e)))?;
         }
+        save_state_metadata(&tx, "prune_base_utxo_set", base)?;
 
-        // Save difficulty
         tx.execute(
-            "INSERT OR REPLACE INTO metadata (key, value) VALUES ('difficulty', ?1)",
-            params![difficulty.to_string()],
-        ).map_err(|e| ChainError::DatabaseError(format!("Failed to save difficulty: {}", e)))?;
+            "INSERT OR REPLACE INTO metadata (key, value) VALUES ('pruned_height', ?1)",
+            params![chain.pruned_height.to_string()],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to save pruned height: {}", e)))?;
+
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Keep transactions for only the most recent `keep_depth` blocks, now
+    /// and as the chain grows. Older blocks keep their headers, and the UTXO
+    /// set is untouched, but side branches below them are dropped, as they
+    /// can never be switched to. The file is then vacuumed to give the space
+    /// back. Returns how many more blocks are pruned than before.
+    pub fn prune(&self, keep_depth: u64) -> Result<u64, ChainError> {
+        let before = self.load_metadata_u64("pruned_height")?.unwrap_or(0);
+        self.set_prune_depth(Some(keep_depth))?;
+        // Loading prunes the chain to the depth set and saves what that drops
+        let chain = self.load_blockchain()?;
+
+        let tx = self.conn.unchecked_transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+        tx.execute(
+            "DELETE FROM transactions WHERE block_hash IN (
+                SELECT b.hash FROM blocks b
+                WHERE b.height <= ?1 AND NOT EXISTS (SELECT 1 FROM chain c WHERE c.height = b.height AND c.hash = b.hash))",
+            params![chain.pruned_height as i64],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prune fork transactions: {}", e)))?;
+        tx.execute(
+            "DELETE FROM blocks AS b
+             WHERE b.height <= ?1 AND NOT EXISTS (SELECT 1 FROM chain c WHERE c.height = b.height AND c.hash = b.hash)",
+            params![chain.pruned_height as i64],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prune fork blocks: {}", e)))?;
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+
+        self.conn.execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to vacuum database: {}", e)))?;
+
+        Ok(chain.pruned_height.saturating_sub(before))
+    }
+
+    /// Copy the database to a new file at `path` with SQLite's online backup,
+    /// which reads one consistent snapshot without stopping other connections
+    /// from writing. The copy is renamed into place once whole, so an
+    /// interrupted backup never leaves a partial file under that name.
+    pub fn backup_to(&self, path: impl AsRef<Path>) -> Result<(), ChainError> {
+        let path = path.as_ref();
+        let partial = path.with_extension("partial");
+        let _ = std::fs::remove_file(&partial);
+
+        let mut copy = Connection::open(&partial)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to create backup file: {}", e)))?;
+        // Every page in one step, so the copy is of a single snapshot
+        Backup::new(&self.conn, &mut copy)
+            .and_then(|backup| backup.run_to_completion(i32::MAX, Duration::ZERO, None))
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to back up database: {}", e)))?;
+        drop(copy);
+
+        std::fs::rename(&partial, path)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to save backup file: {}", e)))
+    }
+
+    fn load_metadata_string(&self, key: &str) -> Result<Option<String>, ChainError> {
+        self.conn.query_row(
+            "SELECT value FROM metadata WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        ).map(Some).or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(ChainError::DatabaseError(format!("Failed to load {}: {}", key, e))),
+        })
+    }
+
+    fn load_metadata_u64(&self, key: &str) -> Result<Option<u64>, ChainError> {
+        self.load_metadata_string(key)?
+            .map(|value| value.parse().map_err(|e| ChainError::DatabaseError(format!("Invalid {}: {}", key, e))))
+            .transpose()
+    }
+
+    /// Atomically saves a block and the associated blockchain state
+    /// This ensures database consistency by wrapping all operations in a transaction.
+    /// Only the unspent triangles changed since `state` was last saved are
+    /// written, after which it counts as saved.
+    pub fn save_blockchain_state(&self, block: &Block, state: &mut TriangleState, bits: u32) -> Result<(), ChainError> {
+        let tx = self.conn.unchecked_transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+
+        insert_block(&tx, block, self.address_index)?;
+        record_chain_identity(&tx, self.params.network)?;
+        save_state(&tx, state, bits)?;
 
         // Commit all changes atomically
         tx.commit()
             .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+        state.mark_utxo_saved();
 
         Ok(())
     }
 
-    pub fn load_blockchain(&self) -> Result<Blockchain, ChainError> {
-        let mut stmt = self.conn.prepare(
-            "SELECT height, hash, previous_hash, timestamp, difficulty, nonce, merkle_root, transactions
-             FROM blocks ORDER BY height ASC"
-        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
+    /// Atomically save consecutive blocks extending the main chain and the
+    /// state the last leaves behind, in one transaction rather than one per
+    /// block, for a node connecting many at once as it syncs
+    pub fn save_blocks_batch(&self, blocks: &[Block], state: &mut TriangleState, bits: u32) -> Result<(), ChainError> {
+        let tx = self.conn.unchecked_transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
 
-        let blocks_iter = stmt.query_map([], |row| {
-            let transactions_json: String = row.get(7)?;
-            let transactions: Vec<Transaction> = serde_json::from_str(&transactions_json)
-                .map_err(|_e| rusqlite::Error::InvalidQuery)?;
+        for block in blocks {
+            insert_block(&tx, block, self.address_index)?;
+        }
+        record_chain_identity(&tx, self.params.network)?;
+        save_state(&tx, state, bits)?;
 
-            let height: i64 = row.get(0)?;
-            let timestamp: i64 = row.get(3)?;
-            let difficulty: i64 = row.get(4)?;
-            let nonce: i64 = row.get(5)?;
-            let hash_vec: Vec<u8> = row.get(1)?;
-            let previous_hash_vec: Vec<u8> = row.get(2)?;
-            let merkle_root_vec: Vec<u8> = row.get(6)?;
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+        state.mark_utxo_saved();
 
-            let mut hash = [0u8; 32];
-            hash.copy_from_slice(&hash_vec);
-            let mut previous_hash = [0u8; 32];
-            previous_hash.copy_from_slice(&previous_hash_vec);
-            let mut merkle_root = [0u8; 32];
-            merkle_root.copy_from_slice(&merkle_root_vec);
+        Ok(())
+    }
+
+    /// Store blocks of branches other than the main chain, parents before
+    /// children, so a restarted node still knows them when choosing a chain
+    pub fn save_fork_blocks(&self, blocks: &[Block]) -> Result<(), ChainError> {
+        let tx = self.conn.unchecked_transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+
+        for block in blocks {
+            store_block(&tx, block)?;
+        }
+
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// The work of the branch ending at each stored block, from genesis
+    fn load_chain_work(&self) -> Result<HashMap<Sha256Hash, Work>, ChainError> {
+        let mut stmt = self.conn.prepare("SELECT hash, chain_work FROM blocks WHERE chain_work IS NOT NULL")
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?)))
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to query chain work: {}", e)))?;
+
+        let mut chain_work = HashMap::new();
+        for row in rows {
+            let (hash, work) = row
+                .map_err(|e| ChainError::DatabaseError(format!("Failed to load chain work: {}", e)))?;
+            let hash = Sha256Hash::try_from(hash.as_slice())
+                .map_err(|_| ChainError::DatabaseError("Invalid block hash length".to_string()))?;
+            chain_work.insert(hash, work_from_blob(&work)?);
+        }
+        Ok(chain_work)
+    }
+
+    /// Stored blocks above `height` that aren't on the main chain, lowest first
+    fn load_fork_blocks(&self, height: BlockHeight) -> Result<Vec<Block>, ChainError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT b.hash, b.height, b.previous_hash, b.timestamp, b.difficulty, b.nonce, b.merkle_root
+             FROM blocks b
+             WHERE b.height > ?1 AND NOT EXISTS (SELECT 1 FROM chain c WHERE c.height = b.height AND c.hash = b.hash)
+             ORDER BY b.height"
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
+        let rows = stmt.query_map(params![height as i64], |row| Ok((
+            row.get::<_, Vec<u8>>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, Vec<u8>>(2)?,
+            row.get::<_, i64>(3)?,
+            row.get::<_, i64>(4)?,
+            row.get::<_, i64>(5)?,
+            row.get::<_, Vec<u8>>(6)?,
+        ))).map_err(|e| ChainError::DatabaseError(format!("Failed to query fork blocks: {}", e)))?;
+
+        let mut transactions = self.conn.prepare(
+            "SELECT data FROM transactions WHERE block_hash = ?1 ORDER BY position"
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
+        let hash_of = |bytes: Vec<u8>| -> Result<Sha256Hash, ChainError> {
+            bytes.try_into().map_err(|_| ChainError::DatabaseError("Stored block hash is not 32 bytes".to_string()))
+        };
 
+        rows.map(|row| {
+            let (hash, height, previous_hash, timestamp, difficulty, nonce, merkle_root) = row
+                .map_err(|e| ChainError::DatabaseError(format!("Failed to load fork block: {}", e)))?;
+            let encoded = transactions.query_map(params![hash], |row| row.get::<_, Vec<u8>>(0))
+                .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| ChainError::DatabaseError(format!("Failed to load fork block transactions: {}", e)))?;
             Ok(Block {
                 header: BlockHeader {
-                    height: height as u64,
-                    previous_hash,
+                    height: height as BlockHeight,
+                    previous_hash: hash_of(previous_hash)?,
                     timestamp,
-                    difficulty: difficulty as u64,
+                    bits: stored_bits_to_compact(difficulty),
                     nonce: nonce as u64,
-                    merkle_root,
+                    merkle_root: hash_of(merkle_root)?,
                 },
-                hash,
-                transactions,
+                hash: hash_of(hash)?,
+                transactions: BlockTransactions::from_encoded(encoded),
             })
-        }).map_err(|e| ChainError::DatabaseError(format!("Failed to query blocks: {}", e)))?;
+        }).collect()
+    }
 
-        let mut blocks = Vec::new();
-        for block_result in blocks_iter {
-            blocks.push(block_result.map_err(|e| ChainError::DatabaseError(format!("Failed to load block: {}", e)))?);
+    /// Write the main chain to a bootstrap file at `path`, from genesis to
+    /// the tip, and return how many blocks it holds. Each block is its
+    /// canonical encoding preceded by the network magic and its length as a
+    /// big-endian u32, as peers frame messages. A pruned chain can't be
+    /// exported, having lost the transactions of its early blocks.
+    pub fn export_chain(&self, path: impl AsRef<Path>) -> Result<u64, ChainError> {
+        let chain = self.load_blockchain()?;
+        if chain.pruned_height > 0 {
+            return Err(ChainError::PrunedData(format!(
+                "Blocks up to height {} have been pruned, so the chain can't be exported",
+                chain.pruned_height
+            )));
         }
 
-        if blocks.is_empty() {
-            return Ok(Blockchain::new());
+        let file = File::create(path)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to create bootstrap file: {}", e)))?;
+        let mut writer = BufWriter::new(file);
+        for block in &chain.blocks {
+            let encoded = codec::encode(block);
+            writer.write_all(&self.params.magic)
+                .and_then(|_| writer.write_all(&(encoded.len() as u32).to_be_bytes()))
+                .and_then(|_| writer.write_all(&encoded))
+                .map_err(|e| ChainError::DatabaseError(format!("Failed to write bootstrap file: {}", e)))?;
         }
+        writer.flush()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to write bootstrap file: {}", e)))?;
 
-        let mut utxo_set = HashMap::new();
-        let mut stmt = self.conn.prepare("SELECT hash, triangle_data FROM utxo_set")
-            .map_err(|e| ChainError::DatabaseError(format!("Failed to prepare UTXO query: {}", e)))?;
+        Ok(chain.blocks.len() as u64)
+    }
 
-        let utxo_iter = stmt.query_map([], |row| {
-            let hash_vec: Vec<u8> = row.get(0)?;
-            let mut hash = [0u8; 32];
-            hash.copy_from_slice(&hash_vec);
-            let triangle_json: String = row.get(1)?;
-            let triangle: Triangle = serde_json::from_str(&triangle_json)
-                .map_err(|_| rusqlite::Error::InvalidQuery)?;
-            Ok((hash, triangle))
-        }).map_err(|e| ChainError::DatabaseError(format!("Failed to query UTXOs: {}", e)))?;
+    /// Extend the stored chain with the blocks of a bootstrap file written by
+    /// `export_chain`, validating each as if a peer had sent it, and return
+    /// how many were new. Blocks already stored are skipped; the rest must
+    /// extend the tip. Nothing is saved unless the whole file is valid.
+    pub fn import_chain(&self, path: impl AsRef<Path>) -> Result<u64, ChainError> {
+        let mut chain = self.load_blockchain()?;
+        let file = File::open(path)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to open bootstrap file: {}", e)))?;
+        let mut reader = BufReader::new(file);
 
-        for utxo_result in utxo_iter {
-            let (hash, triangle) = utxo_result.map_err(|e| ChainError::DatabaseError(format!("Failed to load UTXO: {}", e)))?;
-            utxo_set.insert(hash, triangle);
-        }
+        let tx = self.conn.unchecked_transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+        // A new database holds no blocks yet, not even the genesis block
+        let mut needs_genesis = self.load_tip_hash()?.is_none();
 
-        // Load difficulty from metadata, but verify against actual blocks
-        let metadata_difficulty: u64 = self.conn.query_row(
-            "SELECT value FROM metadata WHERE key = 'difficulty'",
-            [],
-            |row| {
-                let val: String = row.get(0)?;
-                Ok(val.parse::<u64>().unwrap_or(2))
+        let mut imported = 0;
+        while let Some(encoded) = read_bootstrap_record(&mut reader, &self.params.magic)? {
+            let block: Block = codec::decode(&encoded)?;
+            if block.header.height == 0 && block.hash != chain.blocks[0].hash {
+                return Err(ChainError::DatabaseError("Bootstrap file starts from a different genesis block".to_string()));
+            }
+            if needs_genesis {
+                // Take the file's, whose timestamp the blocks after it were mined against
+                if block.header.height == 0 {
+                    chain.header_chain = HeaderChain::new(block.hash, block.header.clone());
+                    chain.blocks[0] = block.clone();
+                }
+                insert_block(&tx, &chain.blocks[0], self.address_index)?;
+                needs_genesis = false;
+            }
+            if chain.contains_block(&block.hash) {
+                continue;
             }
-        ).unwrap_or(2);
+            if block.header.previous_hash != chain.blocks.last().unwrap().hash {
+                return Err(ChainError::DatabaseError(format!(
+                    "Block {} at height {} in the bootstrap file doesn't extend the stored chain",
+                    hex::encode(block.hash), block.header.height
+                )));
+            }
+            chain.apply_block(block.clone()).map_err(|e| ChainError::DatabaseError(format!(
+                "Block {} at height {} in the bootstrap file is invalid: {}",
+                hex::encode(block.hash), block.header.height, e
+            )))?;
+            insert_block(&tx, &block, self.address_index)?;
+            imported += 1;
+        }
+        if needs_genesis {
+            insert_block(&tx, &chain.blocks[0], self.address_index)?;
+        }
+        record_chain_identity(&tx, self.params.network)?;
+        save_state(&tx, &chain.state, chain.bits)?;
 
-        // IMPORTANT: Use the difficulty from the most recent block as source of truth
-        // The metadata might be stale due to crashes or non-atomic writes
-        let actual_difficulty = blocks.last()
-            .map(|block| block.header.difficulty)
-            .unwrap_or(2);
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+        chain.state.mark_utxo_saved();
+        self.save_prune_state(&chain)?;
 
-        // If there's a mismatch, warn and use the actual block difficulty
-        let difficulty = if metadata_difficulty != actual_difficulty && !blocks.is_empty() {
-            eprintln!("⚠️  Warning: Metadata difficulty ({}) doesn't match last block difficulty ({}). Using block data.",
-                      metadata_difficulty, actual_difficulty);
-            eprintln!("   Updating metadata to match...");
-            // Fix the metadata
-            let _ = self.conn.execute(
-                "INSERT OR REPLACE INTO metadata (key, value) VALUES ('difficulty', ?1)",
-                params![actual_difficulty.to_string()],
-            );
-            actual_difficulty
+        Ok(imported)
+    }
+
+    /// Check the whole stored chain: that the main chain has a block at every
+    /// height, each linked to the one before, that every block since the
+    /// pruning point is valid, and that replaying them reproduces the stored
+    /// UTXO set. Also looks for rows the other tables derive from blocks that
+    /// no longer match them. Works on a read-only database.
+    pub fn verify(&self) -> Result<IntegrityReport, ChainError> {
+        let mut problems = Vec::new();
+        let count = |what: &str, sql: &str| -> Result<i64, ChainError> {
+            self.conn.query_row(sql, [], |row| row.get(0))
+                .map_err(|e| ChainError::DatabaseError(format!("Failed to check {}: {}", what, e)))
+        };
+
+        let heights = count("the main chain", "SELECT COUNT(*) FROM chain")?;
+        let top = count("the main chain", "SELECT COALESCE(MAX(height), -1) FROM chain")?;
+        let headless = count("the main chain", "SELECT COUNT(*) FROM chain c LEFT JOIN blocks b ON b.hash = c.hash WHERE b.hash IS NULL")?;
+        let stray = count("transactions", "SELECT COUNT(*) FROM transactions t LEFT JOIN blocks b ON b.hash = t.block_hash WHERE b.hash IS NULL")?;
+        let unkeyed = count("the UTXO set", "SELECT COUNT(*) FROM utxo_set WHERE zorder IS NULL")?;
+        let stale = if self.address_index {
+            count("the address index",
+                "SELECT COUNT(*) FROM address_index a
+                 LEFT JOIN chain c ON c.height = a.height
+                 LEFT JOIN transactions t ON t.block_hash = c.hash AND t.position = a.position
+                 WHERE t.txid IS NULL OR t.txid != a.txid")?
         } else {
-            actual_difficulty
+            0
         };
 
-        let block_index = blocks.iter().map(|b| (b.hash, b.clone())).collect();
+        if heights != top + 1 {
+            problems.push(format!("The main chain is missing {} of its heights up to {}", top + 1 - heights, top));
+        }
+        if headless > 0 {
+            problems.push(format!("{} main-chain blocks are missing from the blocks table", headless));
+        }
+        if stray > 0 {
+            problems.push(format!("{} stored transactions belong to no stored block", stray));
+        }
+        if unkeyed > 0 {
+            problems.push(format!("{} unspent triangles lack their Z-order key", unkeyed));
+        }
+        if stale > 0 {
+            problems.push(format!("{} address index entries don't match a main-chain transaction", stale));
+        }
 
-        let state = self.load_utxo_set()?;
-        let mempool = Mempool::new();
-        let blockchain = Blockchain {
-            blocks,
-            block_index,
-            forks: std::collections::HashMap::new(),
-            state,
-            difficulty,
-            mempool,
+        // Without a block at every height the chain can't be loaded in order
+        let chain = if heights == top + 1 && headless == 0 {
+            match self.load_blockchain() {
+                Ok(chain) => {
+                    // Pruned blocks keep their headers, so their links can still be checked
+                    for pair in chain.blocks[..=chain.pruned_height as usize].windows(2) {
+                        if pair[1].header.previous_hash != pair[0].hash {
+                            problems.push(format!("Pruned block {} does not link to its parent", pair[1].header.height));
+                        }
+                    }
+                    Some(chain.verify_chain(chain.blocks.len()))
+                }
+                Err(e) => {
+                    problems.push(format!("The chain fails to load: {}", e));
+                    None
+                }
+            }
+        } else {
+            None
         };
 
-        // NOTE: Recalculation disabled - it was causing difficulty to jump on every reload
-        // The normal adjustment every 2,016 blocks will handle difficulty changes
-        // blockchain.recalculate_difficulty();
-
-        Ok(blockchain)
+        Ok(IntegrityReport { chain, problems })
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::blockchain::Blockchain;
+    /// Rebuild what the database derives from its blocks: the UTXO set, by
+    /// replaying them, and the address index if kept, dropping transactions
+    /// of blocks no longer stored. Blocks themselves can't be repaired, so a
+    /// chain that fails to load or replay is an error.
+    pub fn repair(&self) -> Result<(), ChainError> {
+        // The recorded tip is derived from the main chain too, and loading checks it
+        record_chain_identity(&self.conn, self.params.network)?;
+        let chain = self.load_blockchain()?;
+        let state = chain.replay_state()?;
 
-    #[test]
-    fn test_database_open() {
-        let db = Database::open(":memory:").unwrap();
+        let tx = self.conn.unchecked_transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+
+        tx.execute("DELETE FROM transactions WHERE block_hash NOT IN (SELECT hash FROM blocks)", [])
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to remove stray transactions: {}", e)))?;
+        rewrite_utxo_set(&tx, &state)?;
+        save_state_metadata(&tx, "utxo_set", &state)?;
+        if self.address_index {
+            reindex_addresses(&tx)?;
+        }
+
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Atomically switch the stored main chain to another branch: the blocks
+    /// above `fork_height` are disconnected, `branch` takes their place and
+    /// the state the switch leaves behind is saved, as `save_blockchain_state`
+    /// would. A crash part way leaves the old branch in place.
+    pub fn save_reorg(&self, fork_height: BlockHeight, branch: &[Block], state: &mut TriangleState, bits: u32) -> Result<(), ChainError> {
+        let tx = self.conn.unchecked_transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+
+        disconnect_above(&tx, fork_height)?;
+        for block in branch {
+            insert_block(&tx, block, self.address_index)?;
+        }
+        record_chain_identity(&tx, self.params.network)?;
+        save_state(&tx, state, bits)?;
+
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+        state.mark_utxo_saved();
+
+        Ok(())
+    }
+
+    /// Check that the database was built on the genesis triangle of `params`,
+    /// recording it on first use so a later build with a different genesis
+    /// refuses the data instead of silently forking from it
+    pub fn check_genesis(&self, params: &ChainParams) -> Result<(), ChainError> {
+        let expected = hex::encode(params.genesis_id());
+        let stored = self.conn.query_row(
+            "SELECT value FROM metadata WHERE key = 'genesis_triangle'",
+            [],
+            |row| row.get::<_, String>(0),
+        ).ok();
+
+        match stored {
+            Some(stored) if stored != expected => Err(ChainError::DatabaseError(format!(
+                "Database was created with genesis triangle {} but this node uses {}",
+                stored, expected
+            ))),
+            Some(_) => Ok(()),
+            None if self.read_only => Ok(()),
+            None => {
+                self.conn.execute(
+                    "INSERT INTO metadata (key, value) VALUES ('genesis_triangle', ?1)",
+                    params![expected],
+                ).map_err(|e| ChainError::DatabaseError(format!("Failed to save genesis triangle: {}", e)))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Check that the database belongs to the network this node runs,
+    /// recording it on first use, so a regtest node never builds on a main
+    /// network database or the other way round
+    fn check_network(&self) -> Result<(), ChainError> {
+        match self.load_metadata_string("network")? {
+            Some(stored) if stored != self.params.network => Err(ChainError::DatabaseError(format!(
+                "Database belongs to the {} network but this node runs {}",
+                stored, self.params.network
+            ))),
+            Some(_) => Ok(()),
+            None if self.read_only => Ok(()),
+            None => record_chain_identity(&self.conn, self.params.network),
+        }
+    }
+
+    /// Check the loaded main chain against the genesis block and tip recorded
+    /// with it, so a chain table changed behind the node's back is noticed
+    /// instead of built on
+    fn check_chain_ends(&self, blocks: &[Block]) -> Result<(), ChainError> {
+        let loaded = |block: Option<&Block>| block.map_or("none".to_string(), |block| hex::encode(block.hash));
+
+        if let Some(recorded) = self.load_metadata_string("genesis_hash")? {
+            if recorded != loaded(blocks.first()) {
+                return Err(ChainError::DatabaseError(format!(
+                    "Database was created with genesis block {} but its main chain starts from {}",
+                    recorded, loaded(blocks.first())
+                )));
+            }
+        }
+        if let Some(recorded) = self.load_metadata_string("tip_hash")? {
+            if recorded != loaded(blocks.last()) {
+                return Err(ChainError::DatabaseError(format!(
+                    "Recorded tip {} doesn't match the main chain's tip {}; run siertri-verify --repair if its blocks are right",
+                    recorded, loaded(blocks.last())
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the recorded state of a node's peers, for processes that
+    /// don't run the node, such as the API, to report
+    pub fn save_peer_statuses(&self, statuses: &[PeerStatus]) -> Result<(), ChainError> {
+        let json = serde_json::to_string(statuses)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize peers: {}", e)))?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO metadata (key, value) VALUES ('peer_statuses', ?1)",
+            params![json],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to save peers: {}", e)))?;
+        Ok(())
+    }
+
+    /// The peer states last saved by a node, or none if no node has saved any
+    pub fn load_peer_statuses(&self) -> Result<Vec<PeerStatus>, ChainError> {
+        let json: Option<String> = self.conn.query_row(
+            "SELECT value FROM metadata WHERE key = 'peer_statuses'",
+            [],
+            |row| row.get(0),
+        ).map(Some).or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(ChainError::DatabaseError(format!("Failed to load peers: {}", e))),
+        })?;
+        json.map_or(Ok(Vec::new()), |json| serde_json::from_str(&json)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to parse peers: {}", e))))
+    }
+
+    /// Record how far a node has synced, for processes that don't run the node to report
+    pub fn save_sync_state(&self, state: &SyncState) -> Result<(), ChainError> {
+        let json = serde_json::to_string(state)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize sync state: {}", e)))?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO metadata (key, value) VALUES ('sync_state', ?1)",
+            params![json],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to save sync state: {}", e)))?;
+        Ok(())
+    }
+
+    /// The sync state last saved by a node, if any node has saved one
+    pub fn load_sync_state(&self) -> Result<Option<SyncState>, ChainError> {
+        let json: Option<String> = self.conn.query_row(
+            "SELECT value FROM metadata WHERE key = 'sync_state'",
+            [],
+            |row| row.get(0),
+        ).map(Some).or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(ChainError::DatabaseError(format!("Failed to load sync state: {}", e))),
+        })?;
+        json.map(|json| serde_json::from_str(&json)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to parse sync state: {}", e))))
+            .transpose()
+    }
+
+    /// Record the SOCKS5 proxy a node dials peers through, or that it dials
+    /// them directly, for processes that don't run the node to report
+    pub fn save_socks5_proxy(&self, proxy: Option<&str>) -> Result<(), ChainError> {
+        match proxy {
+            Some(proxy) => self.conn.execute(
+                "INSERT OR REPLACE INTO metadata (key, value) VALUES ('socks5_proxy', ?1)",
+                params![proxy],
+            ),
+            None => self.conn.execute("DELETE FROM metadata WHERE key = 'socks5_proxy'", []),
+        }.map_err(|e| ChainError::DatabaseError(format!("Failed to save proxy: {}", e)))?;
+        Ok(())
+    }
+
+    /// The SOCKS5 proxy last saved by a node, if it uses one
+    pub fn load_socks5_proxy(&self) -> Result<Option<String>, ChainError> {
+        self.conn.query_row(
+            "SELECT value FROM metadata WHERE key = 'socks5_proxy'",
+            [],
+            |row| row.get(0),
+        ).map(Some).or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(ChainError::DatabaseError(format!("Failed to load proxy: {}", e))),
+        })
+    }
+
+    /// Hash of the highest stored block, without loading the chain. Lets a
+    /// miner in another process notice that a node has saved a new tip.
+    pub fn load_tip_hash(&self) -> Result<Option<Sha256Hash>, ChainError> {
+        let hash: Option<Vec<u8>> = self.conn.query_row(
+            "SELECT hash FROM chain ORDER BY height DESC LIMIT 1",
+            [],
+            |row| row.get(0),
+        ).map(Some).or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(ChainError::DatabaseError(format!("Failed to load tip: {}", e))),
+        })?;
+        hash.map(|hash| hash.try_into()
+            .map_err(|_| ChainError::DatabaseError("Stored block hash is not 32 bytes".to_string())))
+            .transpose()
+    }
+
+    /// Append a credited pool share, dropping shares older than the last `window`
+    pub fn save_pool_share(&self, share: &PoolShare, window: usize) -> Result<(), ChainError> {
+        let tx = self.conn.unchecked_transaction()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to start transaction: {}", e)))?;
+
+        tx.execute(
+            "INSERT INTO pool_shares (address, work) VALUES (?1, ?2)",
+            params![share.address, share.work],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to save pool share: {}", e)))?;
+        tx.execute(
+            "DELETE FROM pool_shares WHERE id <= (SELECT MAX(id) FROM pool_shares) - ?1",
+            params![window as i64],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to trim pool shares: {}", e)))?;
+
+        tx.commit()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to commit transaction: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// The last `window` credited pool shares, oldest first
+    pub fn load_pool_shares(&self, window: usize) -> Result<Vec<PoolShare>, ChainError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT address, work FROM (SELECT id, address, work FROM pool_shares ORDER BY id DESC LIMIT ?1) ORDER BY id"
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prepare statement: {}", e)))?;
+
+        let shares = stmt.query_map(params![window as i64], |row| {
+            Ok(PoolShare { address: row.get(0)?, work: row.get(1)? })
+        }).map_err(|e| ChainError::DatabaseError(format!("Failed to query pool shares: {}", e)))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to read pool share: {}", e)))?;
+
+        Ok(shares)
+    }
+
+    pub fn load_blockchain(&self) -> Result<Blockchain, ChainError> {
+        self.check_network()?;
+        self.check_genesis(&self.params)?;
+
+        // Transactions stay encoded until the chain first uses them
+        let mut encoded_transactions: HashMap<i64, Vec<Vec<u8>>> = HashMap::new();
+        let mut stmt = self.conn.prepare(
+            "SELECT c.height, t.data FROM chain c JOIN transactions t ON t.block_hash = c.hash
+             ORDER BY c.height, t.position"
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to query transactions: {}", e)))?;
+        for row in rows {
+            let (height, data) = row.map_err(|e| ChainError::DatabaseError(format!("Failed to load transaction: {}", e)))?;
+            encoded_transactions.entry(height).or_default().push(data);
+        }
+
+        // Only the main chain's blocks; others stored by hash are left out
+        let mut stmt = self.conn.prepare(
+            "SELECT c.height, b.hash, b.previous_hash, b.timestamp, b.difficulty, b.nonce, b.merkle_root, b.transactions
+             FROM chain c JOIN blocks b ON b.hash = c.hash ORDER BY c.height ASC"
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
+
+        let blocks_iter = stmt.query_map([], |row| {
+            let height: i64 = row.get(0)?;
+            // Blocks saved before the transactions table hold theirs inline
+            let transactions = match row.get_ref(7)? {
+                ValueRef::Blob([]) => BlockTransactions::from_encoded(
+                    encoded_transactions.remove(&height).unwrap_or_default()),
+                inline => decode_stored::<Vec<Transaction>>(inline)
+                    .map_err(|_e| rusqlite::Error::InvalidQuery)?
+                    .into(),
+            };
+
+            let timestamp: i64 = row.get(3)?;
+            let difficulty: i64 = row.get(4)?;
+            let nonce: i64 = row.get(5)?;
+            let hash_vec: Vec<u8> = row.get(1)?;
+            let previous_hash_vec: Vec<u8> = row.get(2)?;
+            let merkle_root_vec: Vec<u8> = row.get(6)?;
+
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&hash_vec);
+            let mut previous_hash = [0u8; 32];
+            previous_hash.copy_from_slice(&previous_hash_vec);
+            let mut merkle_root = [0u8; 32];
+            merkle_root.copy_from_slice(&merkle_root_vec);
+
+            Ok(Block {
+                header: BlockHeader {
+                    height: height as u64,
+                    previous_hash,
+                    timestamp,
+                    bits: stored_bits_to_compact(difficulty),
+                    nonce: nonce as u64,
+                    merkle_root,
+                },
+                hash,
+                transactions,
+            })
+        }).map_err(|e| ChainError::DatabaseError(format!("Failed to query blocks: {}", e)))?;
+
+        let mut blocks = Vec::new();
+        for block_result in blocks_iter {
+            blocks.push(block_result.map_err(|e| ChainError::DatabaseError(format!("Failed to load block: {}", e)))?);
+        }
+        self.check_chain_ends(&blocks)?;
+
+        if blocks.is_empty() {
+            return Ok(Blockchain::with_params(&self.params));
+        }
+
+        // Load difficulty from metadata, but verify against actual blocks
+        let metadata_difficulty: u32 = self.conn.query_row(
+            "SELECT value FROM metadata WHERE key = 'difficulty'",
+            [],
+            |row| {
+                let val: String = row.get(0)?;
+                Ok(val.parse::<i64>().map(stored_bits_to_compact).unwrap_or(self.params.pow_limit_bits))
+            }
+        ).unwrap_or(self.params.pow_limit_bits);
+
+        // IMPORTANT: Use the difficulty from the most recent block as source of truth
+        // The metadata might be stale due to crashes or non-atomic writes
+        let actual_difficulty = blocks.last()
+            .map(|block| block.header.bits)
+            .unwrap_or(self.params.pow_limit_bits);
+
+        // If there's a mismatch, warn and use the actual block difficulty
+        let difficulty = if metadata_difficulty != actual_difficulty && !blocks.is_empty() {
+            eprintln!("⚠️  Warning: Metadata difficulty ({}) doesn't match last block difficulty ({}). Using block data.",
+                      metadata_difficulty, actual_difficulty);
+            if !self.read_only {
+                eprintln!("   Updating metadata to match...");
+                // Fix the metadata
+                let _ = self.conn.execute(
+                    "INSERT OR REPLACE INTO metadata (key, value) VALUES ('difficulty', ?1)",
+                    params![actual_difficulty.to_string()],
+                );
+            }
+            actual_difficulty
+        } else {
+            actual_difficulty
+        };
+
+        let block_index: HashMap<Sha256Hash, BlockHeight> = blocks.iter().map(|b| (b.hash, b.header.height)).collect();
+        // The stored chain work spares adding up every header's again
+        let chain_work = self.load_chain_work()?;
+        let mut header_chain = HeaderChain::default();
+        let insert_header = |header_chain: &mut HeaderChain, block: &Block| match chain_work.get(&block.hash) {
+            Some(work) => header_chain.insert_with_work(block.hash, block.header.clone(), *work),
+            None => header_chain.insert(block.hash, block.header.clone()),
+        };
+        for block in &blocks {
+            insert_header(&mut header_chain, block);
+        }
+
+        let state = self.load_utxo_set()?;
+        let pruned_height = self.load_metadata_u64("pruned_height")?.unwrap_or(0);
+
+        // Side branches, so fork choice carries on where it left off. Those
+        // below the pruning point can never be reorganized onto.
+        let mut forks = HashMap::new();
+        for block in self.load_fork_blocks(pruned_height)? {
+            let parent = block.header.previous_hash;
+            if block_index.contains_key(&parent) || forks.contains_key(&parent) {
+                insert_header(&mut header_chain, &block);
+                forks.insert(block.hash, block);
+            }
+        }
+        let prune_base = if pruned_height > 0 {
+            Some(self.load_triangle_table("prune_base_utxo_set")?)
+        } else {
+            None
+        };
+
+        let events = EventBus::new();
+        let sig_cache = SignatureCache::default();
+        let mempool = Mempool::with_events(events.clone()).with_signature_cache(sig_cache.clone());
+        let supply_at_tip = Blockchain::calculate_current_supply(blocks.last().unwrap().header.height);
+        let mut blockchain = Blockchain {
+            blocks,
+            block_index,
+            forks,
+            header_chain,
+            state,
+            bits: difficulty,
+            mempool,
+            events,
+            sig_cache,
+            supply_at_tip,
+            invalid_blocks: std::collections::HashSet::new(),
+            prune_depth: None,
+            pruned_height,
+            prune_base,
+            pow: crate::pow::sha256(),
+        };
+
+        // A tip that closes a difficulty window already calls for the next target
+        blockchain.bits = blockchain.next_bits(&blockchain.blocks.last().unwrap().header);
+
+        blockchain.sync_mempool_tip();
+
+        if let Some(depth) = self.load_metadata_u64("prune_depth")? {
+            blockchain.enable_pruning(depth)?;
+            if !self.read_only {
+                self.save_prune_state(&blockchain)?;
+            }
+        }
+
+        Ok(blockchain)
+    }
+}
+
+/// Backups of a database taken every `interval` into `dir`, each named for
+/// when it was taken, keeping only the newest `retention`
+#[derive(Debug, Clone)]
+pub struct BackupSchedule {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    pub retention: usize,
+}
+
+impl BackupSchedule {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        BackupSchedule {
+            dir: dir.into(),
+            interval: DEFAULT_BACKUP_INTERVAL,
+            retention: DEFAULT_BACKUP_RETENTION,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_retention(mut self, retention: usize) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Back up `db` into the backup directory now, then delete all but the
+    /// newest `retention` backups there. Returns the new backup's path.
+    pub fn run_once(&self, db: &Database) -> Result<PathBuf, ChainError> {
+        std::fs::create_dir_all(&self.dir)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to create backup directory {}: {}", self.dir.display(), e)))?;
+        let path = self.dir.join(format!("{}{}.db", BACKUP_PREFIX, chrono::Utc::now().format("%Y%m%d-%H%M%S")));
+        db.backup_to(&path)?;
+
+        // Names sort in the order the backups were taken
+        let mut backups: Vec<PathBuf> = std::fs::read_dir(&self.dir)
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to list backups: {}", e)))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.file_name().and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(BACKUP_PREFIX) && name.ends_with(".db")))
+            .collect();
+        backups.sort();
+        let expired = backups.len().saturating_sub(self.retention.max(1));
+        for old in &backups[..expired] {
+            std::fs::remove_file(old)
+                .map_err(|e| ChainError::DatabaseError(format!("Failed to delete old backup {}: {}", old.display(), e)))?;
+        }
+
+        Ok(path)
+    }
+
+    /// Back up the database at `db_path` every `interval`, from a read-only
+    /// connection of its own so the node keeps saving blocks meanwhile.
+    /// Failures are reported and retried at the next interval.
+    pub async fn run(self, db_path: PathBuf) {
+        let mut ticks = tokio::time::interval(self.interval);
+        // The first tick is immediate; the first backup is due an interval in
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let (schedule, db_path) = (self.clone(), db_path.clone());
+            let result = tokio::task::spawn_blocking(move || {
+                schedule.run_once(&Database::open_read_only(db_path)?)
+            }).await;
+            match result {
+                Ok(Ok(path)) => println!("💾 Backed up the database to {}", path.display()),
+                Ok(Err(e)) => eprintln!("❌ Database backup failed: {}", e),
+                Err(e) => eprintln!("❌ Database backup stopped: {}", e),
+            }
+        }
+    }
+}
+
+/// The next block encoding in a bootstrap file of the network with `magic`,
+/// or `None` at its end
+fn read_bootstrap_record(reader: &mut impl Read, magic: &[u8; 4]) -> Result<Option<Vec<u8>>, ChainError> {
+    let mut prefix = [0u8; 8];
+    let mut filled = 0;
+    while filled < prefix.len() {
+        match reader.read(&mut prefix[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(ChainError::DatabaseError("Bootstrap file is truncated".to_string())),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(ChainError::DatabaseError(format!("Failed to read bootstrap file: {}", e))),
+        }
+    }
+    if prefix[..4] != magic[..] {
+        return Err(ChainError::DatabaseError("Not a bootstrap file for this network".to_string()));
+    }
+    let len = u32::from_be_bytes(prefix[4..].try_into().unwrap()) as usize;
+    if len > MAX_BLOCK_SIZE {
+        return Err(ChainError::DatabaseError(format!("Bootstrap record of {} bytes is larger than a block can be", len)));
+    }
+
+    let mut encoded = vec![0u8; len];
+    reader.read_exact(&mut encoded).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => ChainError::DatabaseError("Bootstrap file is truncated".to_string()),
+        _ => ChainError::DatabaseError(format!("Failed to read bootstrap file: {}", e)),
+    })?;
+    Ok(Some(encoded))
+}
+
+/// Point out a database left in the working directory by builds from before
+/// the data directory, when the data directory has none yet
+fn warn_of_legacy_database(config: &Config) {
+    if !config.db_path().exists() && Path::new(DB_FILE).exists() {
+        eprintln!("⚠️  Found {} in the working directory, but the data directory is {}.", DB_FILE, config.datadir.display());
+        eprintln!("   Move it there, or pass --datadir . to keep using it.");
+    }
+}
+
+/// Store a block by hash, with its transactions in the transactions table,
+/// and make it the main chain's block at its height, indexing its addresses
+/// if `index_addresses`
+fn insert_block(conn: &Connection, block: &Block, index_addresses: bool) -> Result<(), ChainError> {
+    let height = block.header.height as i64;
+    store_block(conn, block)?;
+
+    conn.execute(
+        "INSERT OR REPLACE INTO chain (height, hash) VALUES (?1, ?2)",
+        params![height, block.hash.to_vec()],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to connect block: {}", e)))?;
+    conn.execute("DELETE FROM address_index WHERE height = ?1", params![height])
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to replace address index entries: {}", e)))?;
+    if index_addresses {
+        for (position, transaction) in block.transactions.get()?.iter().enumerate() {
+            index_transaction(conn, height, position as i64, transaction)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a chain work stored as 32 big-endian bytes
+fn work_from_blob(blob: &[u8]) -> Result<Work, ChainError> {
+    Work::try_from(blob)
+        .map_err(|_| ChainError::DatabaseError(format!("Chain work must be 32 bytes, found {}", blob.len())))
+}
+
+/// Store a block by hash, with its transactions in the transactions table
+/// and the work of its branch: its parent's, if stored, plus its own
+fn store_block(conn: &Connection, block: &Block) -> Result<(), ChainError> {
+    let parent_work: Option<Vec<u8>> = conn.query_row(
+        "SELECT chain_work FROM blocks WHERE hash = ?1",
+        params![block.header.previous_hash.to_vec()],
+        |row| row.get(0),
+    ).or_else(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => Ok(None),
+        e => Err(ChainError::DatabaseError(format!("Failed to load chain work: {}", e))),
+    })?;
+    let parent_work = parent_work.map(|work| work_from_blob(&work)).transpose()?.unwrap_or_default();
+    conn.execute(
+        "INSERT OR REPLACE INTO blocks (hash, height, previous_hash, timestamp, difficulty, nonce, merkle_root, transactions, chain_work)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, X'', ?8)",
+        params![
+            block.hash.to_vec(),
+            block.header.height as i64,
+            block.header.previous_hash.to_vec(),
+            block.header.timestamp,
+            block.header.bits as i64,
+            block.header.nonce as i64,
+            block.header.merkle_root.to_vec(),
+            difficulty::add_work(&parent_work, &difficulty::block_work(block.header.bits)).to_vec(),
+        ],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to save block: {}", e)))?;
+
+    conn.execute("DELETE FROM transactions WHERE block_hash = ?1", params![block.hash.to_vec()])
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to replace transactions: {}", e)))?;
+    for (position, transaction) in block.transactions.get()?.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO transactions (block_hash, position, txid, data) VALUES (?1, ?2, ?3, ?4)",
+            params![block.hash.to_vec(), position as i64, transaction.hash().to_vec(), codec::encode(transaction)],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to save transaction: {}", e)))?;
+    }
+
+    Ok(())
+}
+
+/// Record that the stored blocks belong to `network`, failing if they're
+/// another network's, along with the hashes of the main chain's genesis
+/// block and tip, for `load_blockchain` to check
+pub(crate) fn record_chain_identity(conn: &Connection, network: &str) -> Result<(), ChainError> {
+    let stored: Option<String> = conn.query_row(
+        "SELECT value FROM metadata WHERE key = 'network'",
+        [],
+        |row| row.get(0),
+    ).map(Some).or_else(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => Ok(None),
+        e => Err(ChainError::DatabaseError(format!("Failed to load network: {}", e))),
+    })?;
+    match stored {
+        Some(stored) if stored != network => return Err(ChainError::DatabaseError(format!(
+            "Database belongs to the {} network, not {}", stored, network
+        ))),
+        Some(_) => {}
+        None => {
+            conn.execute("INSERT INTO metadata (key, value) VALUES ('network', ?1)", params![network])
+                .map_err(|e| ChainError::DatabaseError(format!("Failed to save network: {}", e)))?;
+        }
+    }
+
+    // The genesis block is never replaced, so the first one recorded stays
+    conn.execute(
+        "INSERT OR IGNORE INTO metadata (key, value) SELECT 'genesis_hash', lower(hex(hash)) FROM chain WHERE height = 0",
+        [],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to save genesis hash: {}", e)))?;
+    conn.execute("DELETE FROM metadata WHERE key = 'tip_hash'", [])
+        .and_then(|_| conn.execute(
+            "INSERT INTO metadata (key, value) SELECT 'tip_hash', lower(hex(hash)) FROM chain ORDER BY height DESC LIMIT 1",
+            [],
+        ))
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to save tip hash: {}", e)))?;
+
+    Ok(())
+}
+
+/// Take the blocks above `height` off the main chain, and out of the address index
+fn disconnect_above(conn: &Connection, height: BlockHeight) -> Result<(), ChainError> {
+    conn.execute("DELETE FROM chain WHERE height > ?1", params![height as i64])
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to disconnect blocks: {}", e)))?;
+    conn.execute("DELETE FROM address_index WHERE height > ?1", params![height as i64])
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to remove address index entries: {}", e)))?;
+    Ok(())
+}
+
+/// Rebuild the address index from the main chain's stored transactions
+fn reindex_addresses(conn: &Connection) -> Result<(), ChainError> {
+    conn.execute("DELETE FROM address_index", [])
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to clear address index: {}", e)))?;
+    let mut stmt = conn.prepare(
+        "SELECT c.height, t.position, t.data FROM chain c JOIN transactions t ON t.block_hash = c.hash
+         ORDER BY c.height, t.position"
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to prepare query: {}", e)))?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, Vec<u8>>(2)?)))
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to query transactions: {}", e)))?;
+    for row in rows {
+        let (height, position, data) = row
+            .map_err(|e| ChainError::DatabaseError(format!("Failed to read transaction: {}", e)))?;
+        index_transaction(conn, height, position, &codec::decode(&data)?)?;
+    }
+    Ok(())
+}
+
+/// Add the addresses `transaction` names to the address index, along with
+/// the seller of an offer it accepts
+pub(crate) fn index_transaction(conn: &Connection, height: i64, position: i64, transaction: &Transaction) -> Result<(), ChainError> {
+    let mut addresses: Vec<(Address, AddressRole)> = transaction.addresses().into_iter()
+        .map(|(address, role)| (address.clone(), role))
+        .collect();
+    if let Transaction::Accept(accept) = transaction {
+        if let Some((_, Transaction::Offer(offer))) = find_transaction(conn, &accept.offer_id)? {
+            addresses.push((offer.seller, AddressRole::Sender));
+        }
+    }
+
+    for (address, role) in addresses {
+        conn.execute(
+            "INSERT OR IGNORE INTO address_index (address, height, position, txid, role) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![address, height, position, transaction.hash().to_vec(), role.as_str()],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to index address: {}", e)))?;
+    }
+
+    Ok(())
+}
+
+/// The transaction with `txid` on the stored main chain and its block's
+/// height, the highest if several blocks hold it
+fn find_transaction(conn: &Connection, txid: &Sha256Hash) -> Result<Option<(BlockHeight, Transaction)>, ChainError> {
+    let row: Option<(i64, Vec<u8>)> = conn.query_row(
+        "SELECT c.height, t.data FROM transactions t JOIN chain c ON c.hash = t.block_hash
+         WHERE t.txid = ?1 ORDER BY c.height DESC LIMIT 1",
+        params![txid.to_vec()],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).map(Some).or_else(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => Ok(None),
+        e => Err(ChainError::DatabaseError(format!("Failed to load transaction: {}", e))),
+    })?;
+    row.map(|(height, data)| Ok((height as BlockHeight, codec::decode(&data)?))).transpose()
+}
+
+/// Save the unspent triangles changed since `state` was last saved, and the
+/// difficulty, leaving `mark_utxo_saved` to the caller once committed
+fn save_state(conn: &Connection, state: &TriangleState, bits: u32) -> Result<(), ChainError> {
+    match state.utxo_changes() {
+        UtxoChanges::All => rewrite_utxo_set(conn, state)?,
+        UtxoChanges::Touched(ids) => for id in ids {
+            match state.utxo_set.get(id) {
+                Some(triangle) => insert_utxo(conn, "INSERT OR REPLACE", id, triangle)?,
+                None => {
+                    conn.execute("DELETE FROM utxo_set WHERE hash = ?1", params![id.to_vec()])
+                        .map_err(|e| ChainError::DatabaseError(format!("Failed to remove UTXO: {}", e)))?;
+                }
+            }
+        },
+    }
+    save_state_metadata(conn, "utxo_set", state)?;
+
+    // Save difficulty
+    conn.execute(
+        "INSERT OR REPLACE INTO metadata (key, value) VALUES ('difficulty', ?1)",
+        params![bits.to_string()],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to save difficulty: {}", e)))?;
+
+    Ok(())
+}
+
+/// Replace the stored UTXO set with all of `state`'s unspent triangles
+fn rewrite_utxo_set(conn: &Connection, state: &TriangleState) -> Result<(), ChainError> {
+    conn.execute("DELETE FROM utxo_set", [])
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to clear utxo_set: {}", e)))?;
+    for (hash, triangle) in &state.utxo_set {
+        insert_utxo(conn, "INSERT", hash, triangle)?;
+    }
+    Ok(())
+}
+
+/// Store an unspent triangle with its Z-order key, with `verb` being `INSERT`
+/// or `INSERT OR REPLACE`
+fn insert_utxo(conn: &Connection, verb: &str, hash: &TriangleId, triangle: &Triangle) -> Result<(), ChainError> {
+    conn.execute(
+        &format!("{} INTO utxo_set (hash, triangle_data, zorder) VALUES (?1, ?2, ?3)", verb),
+        params![hash.to_vec(), codec::encode(triangle), triangle.zorder_key().to_be_bytes().to_vec()],
+    ).map_err(|e| ChainError::DatabaseError(format!("Failed to save UTXO: {}", e)))?;
+    Ok(())
+}
+
+/// Decode a stored block body or triangle: the canonical binary encoding, or
+/// JSON text written by versions before it existed
+pub(crate) fn decode_stored<T: DeserializeOwned>(value: ValueRef<'_>) -> Result<T, ChainError> {
+    match value {
+        ValueRef::Blob(bytes) => codec::decode(bytes),
+        ValueRef::Text(text) => serde_json::from_slice(text)
+            .map_err(|e| ChainError::DatabaseError(format!("Invalid legacy JSON: {}", e))),
+        _ => Err(ChainError::DatabaseError("Unexpected column type".to_string())),
+    }
+}
+
+/// Store the non-UTXO parts of a triangle set in the metadata table: address
+/// nonces under `<table>_nonces`, and open HTLCs, escrows, offers, leases and
+/// approvals under `<table>_htlcs`, `<table>_escrows`, `<table>_offers`,
+/// `<table>_leases` and `<table>_approvals`
+fn save_state_metadata(conn: &Connection, table: &str, state: &TriangleState) -> Result<(), ChainError> {
+    let nonces_json = serde_json::to_string(&state.nonces)
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize nonces: {}", e)))?;
+    // JSON object keys must be strings, so store the contracts as (triangle, contract) pairs
+    let htlcs: Vec<(&Sha256Hash, &HtlcContract)> = state.htlcs.iter().collect();
+    let htlcs_json = serde_json::to_string(&htlcs)
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize HTLCs: {}", e)))?;
+    let escrows: Vec<(&Sha256Hash, &EscrowContract)> = state.escrows.iter().collect();
+    let escrows_json = serde_json::to_string(&escrows)
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize escrows: {}", e)))?;
+    let offers: Vec<(&Sha256Hash, &MarketOffer)> = state.offers.iter().collect();
+    let offers_json = serde_json::to_string(&offers)
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize offers: {}", e)))?;
+    let leases: Vec<(&Sha256Hash, &LeaseContract)> = state.leases.iter().collect();
+    let leases_json = serde_json::to_string(&leases)
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize leases: {}", e)))?;
+    let approvals: Vec<(&Sha256Hash, &Approval)> = state.approvals.iter().collect();
+    let approvals_json = serde_json::to_string(&approvals)
+        .map_err(|e| ChainError::DatabaseError(format!("Failed to serialize approvals: {}", e)))?;
+
+    for (suffix, value) in [
+        ("nonces", nonces_json),
+        ("htlcs", htlcs_json),
+        ("escrows", escrows_json),
+        ("offers", offers_json),
+        ("leases", leases_json),
+        ("approvals", approvals_json),
+    ] {
+        conn.execute(
+            "INSERT OR REPLACE INTO metadata (key, value) VALUES (?1, ?2)",
+            params![format!("{}_{}", table, suffix), value],
+        ).map_err(|e| ChainError::DatabaseError(format!("Failed to save {}: {}", suffix, e)))?;
+    }
+
+    Ok(())
+}
+
+/// Decode a stored difficulty value. Databases written before compact targets
+/// stored the number of leading zero hex digits, which is always a small number.
+pub(crate) fn stored_bits_to_compact(value: i64) -> u32 {
+    if (0..=64).contains(&value) {
+        difficulty::bits_from_leading_zeros(value as u64)
+    } else {
+        value as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+
+    #[test]
+    fn test_database_open() {
+        let db = Database::open(":memory:").unwrap();
         assert!(db.conn.is_autocommit());
     }
 
     #[test]
     fn test_save_and_load_blockchain() {
         let db = Database::open(":memory:").unwrap();
-        let chain = Blockchain::new();
+        let mut chain = Blockchain::regtest();
+        chain.state.nonces.insert("alice".to_string(), 7);
 
         db.save_block(&chain.blocks[0]).unwrap();
         db.save_utxo_set(&chain.state).unwrap();
-        db.save_difficulty(chain.difficulty).unwrap();
+        db.save_difficulty(chain.bits).unwrap();
 
         let loaded_chain = db.load_blockchain().unwrap();
 
         assert_eq!(loaded_chain.blocks.len(), 1);
         assert_eq!(loaded_chain.blocks[0].header.height, 0);
-        assert_eq!(loaded_chain.difficulty, chain.difficulty);
+        assert_eq!(loaded_chain.bits, chain.bits);
+        assert_eq!(loaded_chain.state.nonces.get("alice"), Some(&7));
+    }
+
+    #[test]
+    fn test_transactions_are_decoded_on_first_use() {
+        let db = Database::open(":memory:").unwrap();
+        let mut chain = Blockchain::regtest();
+        db.save_block(&chain.blocks[0]).unwrap();
+        for height in 1..=2 {
+            let coinbase = Transaction::Coinbase(crate::transaction::CoinbaseTx::new(1000, format!("miner-{}", height)));
+            let parent = chain.blocks.last().unwrap();
+            let mut block = Block::new(height, parent.hash, chain.bits, vec![coinbase]);
+            block.header.timestamp = parent.header.timestamp + 1;
+            block.hash = block.calculate_hash();
+            while !block.verify_proof_of_work() {
+                block.header.nonce += 1;
+                block.hash = block.calculate_hash();
+            }
+            chain.apply_block(block.clone()).unwrap();
+            db.save_blockchain_state(&block, &mut chain.state, chain.bits).unwrap();
+        }
+
+        let loaded = db.load_blockchain().unwrap();
+        assert!(!loaded.blocks[1].transactions.is_decoded());
+        assert_eq!(loaded.blocks[1].transactions.get().unwrap()[0].hash(), chain.blocks[1].transactions.get().unwrap()[0].hash());
+        assert!(loaded.blocks[1].transactions.is_decoded());
+        assert!(!loaded.blocks[2].transactions.is_decoded());
+
+        let txid = chain.blocks[2].transactions.get().unwrap()[0].hash();
+        let (height, tx) = db.load_transaction(&txid).unwrap().unwrap();
+        assert_eq!((height, tx.hash()), (2, txid));
+        assert!(db.load_transaction(&[0; 32]).unwrap().is_none());
+
+        // A corrupted encoding is an error once it's used, not a crash
+        db.conn.execute("UPDATE transactions SET data = X'ff' WHERE txid = ?1", params![txid.to_vec()]).unwrap();
+        let corrupted = db.load_blockchain().unwrap();
+        assert!(matches!(corrupted.blocks[2].transactions.get(), Err(ChainError::DatabaseError(_))));
+        assert!(corrupted.blocks[2].merkle_proof(&txid).is_err());
+    }
+
+    #[test]
+    fn test_saves_only_the_changed_utxos() {
+        let db = Database::open(":memory:").unwrap();
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+        let genesis_hash = crate::blockchain::genesis_triangle().hash();
+        db.save_blockchain_state(&genesis, &mut chain.state, chain.bits).unwrap();
+        assert_eq!(chain.state.utxo_changes(), &UtxoChanges::Touched(Default::default()));
+
+        // A row the state doesn't have survives saves that don't touch it
+        let stray = crate::blockchain::genesis_triangle().subdivide()[1].clone();
+        insert_utxo(&db.conn, "INSERT", &[9; 32], &stray).unwrap();
+
+        let child = crate::blockchain::genesis_triangle().subdivide()[0].clone();
+        chain.state.set_owner(&genesis_hash, "alice".to_string()).unwrap();
+        chain.state.insert_triangle(child.hash(), child.clone());
+        db.save_blockchain_state(&genesis, &mut chain.state, chain.bits).unwrap();
+        let stored = db.load_utxo_set().unwrap();
+        assert_eq!(stored.utxo_set[&genesis_hash].owner, "alice");
+        assert!(stored.utxo_set.contains_key(&child.hash()) && stored.utxo_set.contains_key(&[9; 32]));
+        assert_eq!(stored.utxo_changes(), &UtxoChanges::Touched(Default::default()));
+
+        chain.state.remove_triangle(&child.hash());
+        db.save_blockchain_state(&genesis, &mut chain.state, chain.bits).unwrap();
+        assert!(!db.load_utxo_set().unwrap().utxo_set.contains_key(&child.hash()));
+
+        // A state edited behind the tracking's back is saved whole
+        chain.state.rebuild_indexes();
+        db.save_blockchain_state(&genesis, &mut chain.state, chain.bits).unwrap();
+        assert_eq!(db.load_utxo_set().unwrap().utxo_set.len(), 1);
+    }
+
+    #[test]
+    fn test_address_index() {
+        use crate::transaction::{AcceptTx, OfferTx, TransferTx};
+        let mut db = Database::open(":memory:").unwrap();
+        let chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+        let block_at = |height: u64, transactions| {
+            let mut block = Block::new(height, genesis.hash, chain.bits, transactions);
+            block.hash = [height as u8; 32];
+            block
+        };
+        let offer = OfferTx::new([1; 32], "alice".to_string(), 1.0, 0, 1);
+        let accept = Transaction::Accept(AcceptTx::new(&offer.offer(), [1; 32], "bob".to_string(), vec![[2; 32]], 0, 1));
+        let offer = Transaction::Offer(offer);
+        let to_self = Transaction::Transfer(TransferTx::new([3; 32], "alice".to_string(), "alice".to_string(), 0, 2));
+        db.save_block(&genesis).unwrap();
+        db.save_block(&block_at(1, vec![offer.clone()])).unwrap();
+
+        // Turning the index on covers the blocks already stored, then the new ones
+        assert!(db.load_address_history("alice").is_err());
+        db.set_address_index(true).unwrap();
+        let block = block_at(2, vec![accept.clone(), to_self.clone()]);
+        db.save_block(&block).unwrap();
+
+        let alice = db.load_address_history("alice").unwrap();
+        let found: Vec<(BlockHeight, Sha256Hash)> = alice.iter().map(|entry| (entry.height, entry.txid)).collect();
+        assert_eq!(found, vec![(1, offer.hash()), (2, accept.hash()), (2, to_self.hash())]);
+        // The accepted offer's seller is found through the offer
+        assert_eq!(alice[1].roles, vec![AddressRole::Sender]);
+        assert!(alice[2].roles.len() == 2 && alice[2].roles.contains(&AddressRole::Recipient));
+        let bob = db.load_address_history("bob").unwrap();
+        assert_eq!((bob[0].timestamp, &bob[0].roles), (block.header.timestamp, &vec![AddressRole::Recipient]));
+        assert_eq!(bob[0].transaction.hash(), accept.hash());
+
+        // A block replacing another at its height replaces its entries
+        db.save_block(&block_at(2, Vec::new())).unwrap();
+        assert_eq!(db.load_address_history("alice").unwrap().len(), 1);
+        assert!(db.load_address_history("bob").unwrap().is_empty());
+
+        db.set_address_index(false).unwrap();
+        assert!(!db.has_address_index());
+        let rows: i64 = db.conn.query_row("SELECT COUNT(*) FROM address_index", [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 0);
+    }
+
+    #[test]
+    fn test_load_tip_hash() {
+        let db = Database::open(":memory:").unwrap();
+        assert_eq!(db.load_tip_hash().unwrap(), None);
+
+        let chain = Blockchain::regtest();
+        db.save_block(&chain.blocks[0]).unwrap();
+        assert_eq!(db.load_tip_hash().unwrap(), Some(chain.blocks[0].hash));
+    }
+
+    #[test]
+    fn test_reorg_replaces_the_stored_branch() {
+        let db = Database::open(":memory:").unwrap();
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+        db.save_blockchain_state(&genesis, &mut chain.state, chain.bits).unwrap();
+
+        // A branch from `parent` with coinbases paying `miner`, each to a
+        // different address so their txids differ
+        let branch = |parent: &Block, miner: &str, length: u64| {
+            let mut blocks: Vec<Block> = Vec::new();
+            for _ in 0..length {
+                let parent = blocks.last().unwrap_or(parent);
+                let address = format!("{}-{}", miner, parent.header.height + 1);
+                let coinbase = Transaction::Coinbase(crate::transaction::CoinbaseTx::new(1000, address));
+                blocks.push(Block::new(parent.header.height + 1, parent.hash, chain.bits, vec![coinbase]));
+            }
+            blocks
+        };
+        let ours = branch(&genesis, "ours", 2);
+        for block in &ours {
+            db.save_block(block).unwrap();
+        }
+        let theirs = branch(&ours[0], "theirs", 2);
+        db.save_reorg(1, &theirs, &mut chain.state, chain.bits).unwrap();
+
+        let loaded = db.load_blockchain().unwrap();
+        let hashes: Vec<Sha256Hash> = loaded.blocks.iter().map(|block| block.hash).collect();
+        assert_eq!(hashes, [genesis.hash, ours[0].hash, theirs[0].hash, theirs[1].hash]);
+        assert_eq!(loaded.blocks[3].transactions.get().unwrap()[0].hash(), theirs[1].transactions.get().unwrap()[0].hash());
+        assert_eq!(db.load_tip_hash().unwrap(), Some(theirs[1].hash));
+        // The displaced block's transactions are no longer found
+        assert!(db.load_transaction(&ours[1].transactions.get().unwrap()[0].hash()).unwrap().is_none());
+
+        db.disconnect_blocks_above(1).unwrap();
+        assert_eq!(db.load_blockchain().unwrap().blocks.len(), 2);
+        assert_eq!(db.load_tip_hash().unwrap(), Some(ours[0].hash));
+    }
+
+    #[test]
+    fn test_read_only_alongside_a_writer() {
+        let path = std::env::temp_dir().join(format!("siertrichain-readonly-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        assert!(Database::open_read_only(path).is_err());
+
+        let writer = Database::open(path).unwrap();
+        let mode: String = writer.conn.query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap();
+        assert_eq!(mode, "wal");
+        let mut chain = Blockchain::regtest();
+        let genesis = chain.blocks[0].clone();
+        writer.save_blockchain_state(&genesis, &mut chain.state, chain.bits).unwrap();
+
+        // A reader loads what the writer saved, without writing anything back
+        let reader = Database::open_read_only(path).unwrap();
+        assert!(reader.is_read_only());
+        assert_eq!(reader.load_blockchain().unwrap().blocks[0].hash, genesis.hash);
+        let genesis_recorded: bool = reader.conn.query_row(
+            "SELECT COUNT(*) > 0 FROM metadata WHERE key = 'genesis_triangle'", [], |row| row.get(0),
+        ).unwrap();
+        assert!(!genesis_recorded);
+        assert!(matches!(reader.save_block(&genesis), Err(ChainError::DatabaseError(_))));
+
+        // The writer is not blocked by the open reader
+        writer.save_difficulty(chain.bits).unwrap();
+
+        // A database at an older schema is refused, since a reader can't migrate it
+        writer.conn.execute("UPDATE metadata SET value = '1' WHERE key = 'schema_version'", []).unwrap();
+        assert!(matches!(Database::open_read_only(path), Err(ChainError::DatabaseError(e)) if e.contains("older")));
+
+        drop((reader, writer));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    /// A block extending `parent`, paying its coinbase to `miner`
+    fn mine_block(parent: &Block, bits: u32, miner: &str) -> Block {
+        let coinbase = Transaction::Coinbase(crate::transaction::CoinbaseTx::new(1000, miner.to_string()));
+        let mut block = Block::new(parent.header.height + 1, parent.hash, bits, vec![coinbase]);
+        block.header.timestamp = parent.header.timestamp + 1;
+        block.hash = block.calculate_hash();
+        while !block.verify_proof_of_work() {
+            block.header.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
+        block
+    }
+
+    /// A chain of `length` mined blocks paying one coinbase each, saved to `db`.
+    /// It starts an hour ago, so a fresh genesis block would postdate it
+    fn mine_saved_chain(db: &Database, length: u64) -> Blockchain {
+        let mut chain = Blockchain::regtest();
+        chain.blocks[0].header.timestamp -= 3600;
+        chain.header_chain = HeaderChain::new(chain.blocks[0].hash, chain.blocks[0].header.clone());
+        let genesis = chain.blocks[0].clone();
+        db.save_blockchain_state(&genesis, &mut chain.state, chain.bits).unwrap();
+        for _ in 1..=length {
+            let block = mine_block(chain.blocks.last().unwrap(), chain.bits, "miner");
+            chain.apply_block(block.clone()).unwrap();
+            db.save_blockchain_state(&block, &mut chain.state, chain.bits).unwrap();
+        }
+        chain
+    }
+
+    #[test]
+    fn test_fork_blocks_survive_a_reload() {
+        let db = Database::open(":memory:").unwrap();
+        let chain = mine_saved_chain(&db, 2);
+        let fork = mine_block(&chain.blocks[1], chain.bits, "rival");
+        db.save_fork_blocks(std::slice::from_ref(&fork)).unwrap();
+
+        let work = db.load_chain_work().unwrap();
+        assert_eq!(work[&fork.hash], work[&chain.blocks[2].hash]);
+        assert!(work[&fork.hash] > work[&chain.blocks[1].hash]);
+        assert_eq!(Some(work[&fork.hash]), chain.header_chain.chain_work(&chain.blocks[2].hash));
+
+        let mut reloaded = db.load_blockchain().unwrap();
+        assert_eq!(reloaded.blocks.len(), 3);
+        assert_eq!(reloaded.header_chain.chain_work(&fork.hash), Some(work[&fork.hash]));
+        assert_eq!(reloaded.forks.get(&fork.hash).map(|block| block.transactions.get().unwrap().len()), Some(1));
+
+        // The branch is still there to switch to once it's longer
+        let next = mine_block(&fork, reloaded.bits, "rival");
+        reloaded.apply_block(next.clone()).unwrap();
+        assert_eq!(reloaded.blocks[2].hash, fork.hash);
+        assert_eq!(reloaded.blocks.last().unwrap().hash, next.hash);
+    }
+
+    #[test]
+    fn test_backups_are_whole_and_rotated() {
+        let db = Database::open(":memory:").unwrap();
+        let chain = mine_saved_chain(&db, 2);
+        let dir = std::env::temp_dir().join(format!("siertrichain-backups-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Older backups, one more than are kept alongside the new one
+        for name in ["siertrichain-20240101-000000.db", "siertrichain-20240102-000000.db", "notes.txt"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let backup = BackupSchedule::new(&dir).with_retention(2).run_once(&db).unwrap();
+        let mut left: Vec<_> = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left.len(), 3);
+        assert_eq!(left[..2], ["notes.txt", "siertrichain-20240102-000000.db"]);
+
+        let restored = Database::open(&backup).unwrap().load_blockchain().unwrap();
+        assert_eq!(restored.blocks.last().unwrap().hash, chain.blocks.last().unwrap().hash);
+        assert_eq!(restored.state.count(), chain.state.count());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_and_repair() {
+        let mut db = Database::open(":memory:").unwrap();
+        db.set_address_index(true).unwrap();
+        let chain = mine_saved_chain(&db, 3);
+        let report = db.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.chain.unwrap().blocks_checked, 3);
+
+        // Derived rows out of step with the blocks, as a crash between
+        // separate writes could leave them
+        let (spent, _) = chain.state.utxo_set.iter().next().unwrap();
+        db.conn.execute("DELETE FROM utxo_set WHERE hash = ?1", params![spent.to_vec()]).unwrap();
+        db.conn.execute("INSERT INTO transactions (block_hash, position, txid, data) VALUES (X'00', 0, X'00', X'00')", []).unwrap();
+        db.conn.execute("UPDATE address_index SET txid = X'00'", []).unwrap();
+        let report = db.verify().unwrap();
+        assert_eq!(report.problems.len(), 2, "{:?}", report.problems);
+        assert!(!report.chain.unwrap().state_matches);
+
+        db.repair().unwrap();
+        let report = db.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(db.load_utxo_set().unwrap().count(), chain.state.count());
+
+        // A missing block can't be repaired, only reported
+        db.conn.execute("DELETE FROM chain WHERE height = 2", []).unwrap();
+        let report = db.verify().unwrap();
+        assert!(report.chain.is_none());
+        assert!(report.problems[0].contains("missing 1"));
+    }
+
+    #[test]
+    fn test_export_and_import_a_bootstrap_file() {
+        let source = Database::open(":memory:").unwrap();
+        let chain = mine_saved_chain(&source, 3);
+
+        let path = std::env::temp_dir().join(format!("siertrichain-bootstrap-{}.dat", std::process::id()));
+        assert_eq!(source.export_chain(&path).unwrap(), 4);
+
+        let target = Database::open(":memory:").unwrap();
+        assert_eq!(target.import_chain(&path).unwrap(), 3);
+        let imported = target.load_blockchain().unwrap();
+        assert_eq!(imported.blocks[0].header.timestamp, chain.blocks[0].header.timestamp);
+        assert_eq!(imported.blocks.last().unwrap().hash, chain.blocks.last().unwrap().hash);
+        assert_eq!(imported.state.count(), chain.state.count());
+        // Importing again finds nothing new
+        assert_eq!(target.import_chain(&path).unwrap(), 0);
+
+        // A tampered block fails validation, and none of the file is kept
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        let fresh = Database::open(":memory:").unwrap();
+        assert!(fresh.import_chain(&path).is_err());
+        assert_eq!(fresh.load_tip_hash().unwrap(), None);
+
+        // So does a file cut short
+        std::fs::write(&path, &bytes[..bytes.len() - 10]).unwrap();
+        assert!(matches!(fresh.import_chain(&path), Err(ChainError::DatabaseError(e)) if e.contains("truncated")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pool_shares_keep_the_window() {
+        let db = Database::open(":memory:").unwrap();
+        assert!(db.load_pool_shares(3).unwrap().is_empty());
+
+        for (i, address) in ["alice", "bob", "carol", "dave"].iter().enumerate() {
+            let share = PoolShare { address: address.to_string(), work: i as f64 + 1.0 };
+            db.save_pool_share(&share, 3).unwrap();
+        }
+        let shares = db.load_pool_shares(3).unwrap();
+        let addresses: Vec<&str> = shares.iter().map(|share| share.address.as_str()).collect();
+        assert_eq!(addresses, vec!["bob", "carol", "dave"]);
+        assert_eq!(shares[0].work, 2.0);
+        assert_eq!(db.load_pool_shares(2).unwrap()[0].address, "carol");
+    }
+
+    #[test]
+    fn test_loads_legacy_json_rows() {
+        let db = Database::open(":memory:").unwrap();
+        let chain = Blockchain::regtest();
+        let genesis = &chain.blocks[0];
+
+        // Rows written before the binary encoding stored JSON text
+        db.conn.execute(
+            "INSERT INTO blocks (height, hash, previous_hash, timestamp, difficulty, nonce, merkle_root, transactions)
+             VALUES (0, ?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                genesis.hash.to_vec(),
+                genesis.header.previous_hash.to_vec(),
+                genesis.header.timestamp,
+                genesis.header.bits as i64,
+                genesis.header.nonce as i64,
+                genesis.header.merkle_root.to_vec(),
+                serde_json::to_string(&genesis.transactions).unwrap(),
+            ],
+        ).unwrap();
+        db.conn.execute("INSERT INTO chain (height, hash) VALUES (0, ?1)", params![genesis.hash.to_vec()]).unwrap();
+        for (hash, triangle) in &chain.state.utxo_set {
+            db.conn.execute(
+                "INSERT INTO utxo_set (hash, triangle_data) VALUES (?1, ?2)",
+                params![hash.to_vec(), serde_json::to_string(triangle).unwrap()],
+            ).unwrap();
+        }
+
+        let mut loaded = db.load_blockchain().unwrap();
+        assert_eq!(loaded.blocks[0].hash, genesis.hash);
+        assert_eq!(loaded.state.utxo_set.len(), chain.state.utxo_set.len());
+
+        // Saving again writes the binary encoding, which loads the same way
+        let loaded_genesis = loaded.blocks[0].clone();
+        db.save_blockchain_state(&loaded_genesis, &mut loaded.state, loaded.bits).unwrap();
+        let reloaded = db.load_blockchain().unwrap();
+        assert_eq!(reloaded.state.utxo_set.len(), chain.state.utxo_set.len());
+    }
+
+    #[test]
+    fn test_pruned_chain_round_trip() {
+        let db = Database::open(":memory:").unwrap();
+        let mut chain = Blockchain::regtest();
+        db.save_block(&chain.blocks[0]).unwrap();
+
+        for height in 1..=4 {
+            let coinbase = Transaction::Coinbase(crate::transaction::CoinbaseTx::new(1000, "miner".to_string()));
+            let parent = chain.blocks.last().unwrap();
+            let mut block = Block::new(height, parent.hash, chain.bits, vec![coinbase]);
+            block.header.timestamp = parent.header.timestamp + 1;
+            block.hash = block.calculate_hash();
+            while !block.verify_proof_of_work() {
+                block.header.nonce += 1;
+                block.hash = block.calculate_hash();
+            }
+            chain.apply_block(block.clone()).unwrap();
+            db.save_blockchain_state(&block, &mut chain.state, chain.bits).unwrap();
+        }
+
+        db.set_prune_depth(Some(2)).unwrap();
+        let loaded = db.load_blockchain().unwrap();
+
+        assert_eq!(loaded.pruned_height, 2);
+        assert!(loaded.blocks[1].transactions.get().unwrap().is_empty());
+        assert!(loaded.blocks[2].transactions.get().unwrap().is_empty());
+        assert_eq!(loaded.blocks[3].transactions.get().unwrap().len(), 1);
+        assert_eq!(loaded.state.count(), chain.state.count());
+
+        // Reloading again keeps the pruned data and replay base
+        let reloaded = db.load_blockchain().unwrap();
+        assert_eq!(reloaded.pruned_height, 2);
+        assert_eq!(reloaded.prune_base.unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_prune_drops_old_transactions_and_forks() {
+        let db = Database::open(":memory:").unwrap();
+        let chain = mine_saved_chain(&db, 5);
+        let old_fork = mine_block(&chain.blocks[1], chain.bits, "rival");
+        let new_fork = mine_block(&chain.blocks[4], chain.bits, "rival");
+        db.save_fork_blocks(&[old_fork.clone(), new_fork.clone()]).unwrap();
+
+        assert_eq!(db.prune(2).unwrap(), 3);
+        let stored: i64 = db.conn.query_row("SELECT COUNT(*) FROM transactions", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored, 3);
+        assert!(!db.load_chain_work().unwrap().contains_key(&old_fork.hash));
+
+        let loaded = db.load_blockchain().unwrap();
+        assert_eq!(loaded.pruned_height, 3);
+        assert_eq!(loaded.blocks.len(), 6);
+        assert!(loaded.forks.contains_key(&new_fork.hash));
+        assert_eq!(loaded.state.count(), chain.state.count());
+
+        // Already pruned that deep
+        assert_eq!(db.prune(2).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_legacy_difficulty_is_converted_to_bits() {
+        assert_eq!(stored_bits_to_compact(2), 0x2000ffff);
+        assert_eq!(stored_bits_to_compact(0x1f00ffff), 0x1f00ffff);
+    }
+
+    #[test]
+    fn test_load_rejects_a_different_genesis() {
+        let db = Database::open(":memory:").unwrap();
+        db.load_blockchain().unwrap();
+        db.check_genesis(&ChainParams::MAINNET).unwrap();
+
+        let mut other = ChainParams::MAINNET;
+        other.genesis_vertices[2].y = 3f64.sqrt() / 2.0;
+        assert!(matches!(db.check_genesis(&other), Err(ChainError::DatabaseError(_))));
+
+        db.conn.execute(
+            "UPDATE metadata SET value = ?1 WHERE key = 'genesis_triangle'",
+            params![hex::encode(other.genesis_id())],
+        ).unwrap();
+        assert!(db.load_blockchain().is_err());
+    }
+
+    #[test]
+    fn test_load_checks_the_network_and_tip() {
+        let db = Database::open(":memory:").unwrap();
+        let chain = mine_saved_chain(&db, 2);
+
+        // A regtest node refuses the main network's chain, for reading or writing
+        let db = db.with_params(ChainParams::REGTEST);
+        assert!(matches!(db.load_blockchain(), Err(ChainError::DatabaseError(e)) if e.contains("main")));
+        assert!(db.save_block(&chain.blocks[2]).is_err());
+
+        // A main chain cut short behind the node's back doesn't load as if whole
+        let db = db.with_params(ChainParams::MAINNET);
+        db.conn.execute("DELETE FROM chain WHERE height = 2", []).unwrap();
+        assert!(matches!(db.load_blockchain(), Err(ChainError::DatabaseError(e)) if e.contains("tip")));
+
+        db.repair().unwrap();
+        assert_eq!(db.load_blockchain().unwrap().blocks.len(), 2);
+    }
+
+    #[test]
+    fn test_range_scan_by_zorder() {
+        let db = Database::open(":memory:").unwrap();
+        let mut state = TriangleState::new();
+        let children = crate::blockchain::genesis_triangle().subdivide();
+        for child in children.iter().cloned() {
+            state.utxo_set.insert(child.hash(), child);
+        }
+        let far = Triangle::equilateral(crate::geometry::Point::new(5000.0, 5000.0), 10.0, "bob".to_string());
+        state.utxo_set.insert(far.hash(), far.clone());
+        db.save_utxo_set(&state).unwrap();
+
+        let all = db.load_utxos_by_zorder(0..=u128::MAX).unwrap();
+        assert_eq!(all.len(), 4);
+        assert!(all.windows(2).all(|pair| pair[0].1.zorder_key() <= pair[1].1.zorder_key()));
+
+        // The patch of the curve around genesis leaves out the far triangle
+        let low = children.iter().map(Triangle::zorder_key).min().unwrap();
+        let high = children.iter().map(Triangle::zorder_key).max().unwrap();
+        let near = db.load_utxos_by_zorder(low..=high).unwrap();
+        assert_eq!(near.len(), 3);
+        assert!(near.iter().all(|(hash, _)| *hash != far.hash()));
+    }
+
+    #[test]
+    fn test_open_adds_zorder_to_old_databases() {
+        let path = std::env::temp_dir().join(format!("siertrichain-zorder-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute("CREATE TABLE utxo_set (hash BLOB PRIMARY KEY, triangle_data BLOB NOT NULL)", []).unwrap();
+        }
+
+        let db = Database::open(path.to_str().unwrap()).unwrap();
+        db.save_utxo_set(&Blockchain::regtest().state).unwrap();
+        assert_eq!(db.load_utxos_by_zorder(0..=u128::MAX).unwrap().len(), 1);
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,63 @@
+//! Proof-of-work engines for siertrichain
+//!
+//! A `Blockchain` asks its engine whether a block hash does enough work, and
+//! miners ask the same engine while searching nonces. Real chains use
+//! `Sha256Pow`. `TestPow` accepts any hash under a well-formed target, so the
+//! first nonce always wins and tests can mine hundreds of blocks in
+//! milliseconds. Either way the claimed hash must still be the header's own.
+
+use std::fmt;
+use std::sync::Arc;
+use crate::blockchain::Sha256Hash;
+use crate::difficulty;
+
+/// Decides whether a header hash satisfies the target in its compact `bits`
+pub trait PowEngine: fmt::Debug + Send + Sync {
+    fn meets_target(&self, hash: &Sha256Hash, bits: u32) -> bool;
+}
+
+/// The consensus rule: the hash, as a number, must not exceed the target.
+/// Which targets a block may use is the chain's retargeting rule.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256Pow;
+
+impl PowEngine for Sha256Pow {
+    fn meets_target(&self, hash: &Sha256Hash, bits: u32) -> bool {
+        difficulty::is_valid_bits(bits) && difficulty::hash_meets_target(hash, bits)
+    }
+}
+
+/// Every hash meets any well-formed target. Only for tests and local
+/// experiments; a node using it accepts blocks no real chain would.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TestPow;
+
+impl PowEngine for TestPow {
+    fn meets_target(&self, _hash: &Sha256Hash, bits: u32) -> bool {
+        difficulty::is_valid_bits(bits)
+    }
+}
+
+/// The engine a new or loaded `Blockchain` starts with
+pub fn sha256() -> Arc<dyn PowEngine> {
+    Arc::new(Sha256Pow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_engines() {
+        let easy = difficulty::REGTEST_POW_LIMIT_BITS;
+        let hard = difficulty::bits_from_leading_zeros(16);
+
+        assert!(Sha256Pow.meets_target(&[0; 32], easy));
+        assert!(!Sha256Pow.meets_target(&[0xff; 32], hard));
+        assert!(TestPow.meets_target(&[0xff; 32], hard));
+
+        // Malformed targets fail under both
+        assert!(!Sha256Pow.meets_target(&[0; 32], 0));
+        assert!(!TestPow.meets_target(&[0; 32], 0));
+    }
+}
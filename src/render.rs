@@ -0,0 +1,704 @@
+//! Rendering of the triangle space for siertrichain
+//!
+//! Draws the unspent triangles as an SVG, so the Sierpinski gasket the chain
+//! carves out of its root triangles can actually be seen. Triangles are filled
+//! by owner, subdivision depth, or age, and always drawn in id order so the same
+//! state renders to the same bytes.
+//!
+//! Deep subdivisions hold far more triangles than fit in one image, so the
+//! space can also be rasterized into PNG map tiles addressed `z/x/y` as in
+//! slippy maps: zoom 0 is a single tile covering everything, and each zoom
+//! level splits every tile into four.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::ops::RangeInclusive;
+use sha2::{Digest, Sha256};
+use crate::blockchain::{BlockHeight, TriangleState};
+use crate::error::ChainError;
+use crate::geometry::{Coord, Point, Triangle, TriangleId};
+use crate::transaction::CoinbaseTx;
+
+/// Width and height of a map tile in pixels
+pub const TILE_SIZE: u32 = 256;
+
+/// Deepest zoom level a tile can be requested at
+pub const MAX_ZOOM: u8 = 32;
+
+/// How triangles are filled
+#[derive(Debug, Clone, Default)]
+pub enum ColorBy {
+    /// One color for every triangle
+    #[default]
+    Uniform,
+    /// A hue per owner address
+    Owner,
+    /// Hue steps with subdivision depth
+    Depth,
+    /// Newer triangles are brighter. `heights` gives each triangle's creation
+    /// height (see `Blockchain::creation_heights`) and `tip` the current height;
+    /// triangles missing from `heights` are drawn as oldest.
+    Age { heights: HashMap<TriangleId, BlockHeight>, tip: BlockHeight },
+}
+
+/// Options for `TriangleState::to_svg`
+#[derive(Debug, Clone)]
+pub struct SvgOptions {
+    /// Width of the image in pixels; the height follows the drawing's aspect ratio
+    pub width: u32,
+    pub color_by: ColorBy,
+    /// Outline each triangle
+    pub stroke: bool,
+    pub background: String,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        SvgOptions {
+            width: 1024,
+            color_by: ColorBy::Uniform,
+            stroke: true,
+            background: "#ffffff".to_string(),
+        }
+    }
+}
+
+/// Axis-aligned bounding box of a set of triangles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min_x: Coord,
+    pub min_y: Coord,
+    pub max_x: Coord,
+    pub max_y: Coord,
+}
+
+impl Bounds {
+    pub fn of<'a>(triangles: impl IntoIterator<Item = &'a Triangle>) -> Option<Self> {
+        let mut points = triangles.into_iter().flat_map(|t| [t.a, t.b, t.c]);
+        let first = points.next()?;
+        Some(points.fold(
+            Bounds { min_x: first.x, min_y: first.y, max_x: first.x, max_y: first.y },
+            |b, p| Bounds {
+                min_x: b.min_x.min(p.x),
+                min_y: b.min_y.min(p.y),
+                max_x: b.max_x.max(p.x),
+                max_y: b.max_y.max(p.y),
+            },
+        ))
+    }
+
+    pub fn width(&self) -> Coord {
+        self.max_x - self.min_x
+    }
+
+    pub fn height(&self) -> Coord {
+        self.max_y - self.min_y
+    }
+
+    /// The smallest square with the same bottom-left corner that contains these bounds
+    pub fn square(&self) -> Self {
+        let side = self.width().max(self.height());
+        Bounds { max_x: self.min_x + side, max_y: self.min_y + side, ..*self }
+    }
+
+    pub fn intersects(&self, other: &Bounds) -> bool {
+        self.min_x <= other.max_x && other.min_x <= self.max_x
+            && self.min_y <= other.max_y && other.min_y <= self.max_y
+    }
+}
+
+impl std::str::FromStr for Bounds {
+    type Err = ChainError;
+
+    /// `min_x,min_y,max_x,max_y`, as in a `bbox` query parameter
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ChainError::CodecError(format!("Invalid bounding box '{}', expected min_x,min_y,max_x,max_y", s));
+        let coords = s.split(',')
+            .map(|part| part.trim().parse::<Coord>().ok().filter(|c| c.is_finite()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        let [min_x, min_y, max_x, max_y] = coords[..] else { return Err(invalid()) };
+        if min_x > max_x || min_y > max_y {
+            return Err(invalid());
+        }
+        Ok(Bounds { min_x, min_y, max_x, max_y })
+    }
+}
+
+/// Side of the grid cells `SpatialIndex` files triangles under: a coinbase
+/// reward cell, so checking where a reward lands looks at a cell or two
+const SPATIAL_CELL_SIZE: Coord = CoinbaseTx::REWARD_CELL_SIZE;
+
+/// Triangles whose bounding boxes cover more cells than this are kept aside
+/// and checked on every lookup, instead of being filed under each cell
+const MAX_SPATIAL_CELLS: i128 = 64;
+
+/// Unspent triangles by the grid cells their bounding boxes cover, so looking
+/// up an area only visits the triangles near it
+#[derive(Debug, Default, Clone)]
+pub struct SpatialIndex {
+    cells: HashMap<(i64, i64), HashSet<TriangleId>>,
+    large: HashSet<TriangleId>,
+}
+
+/// The columns and rows of cells `bounds` covers
+fn cell_range(bounds: &Bounds) -> (RangeInclusive<i64>, RangeInclusive<i64>) {
+    let cell = |c: Coord| (c / SPATIAL_CELL_SIZE).floor() as i64;
+    (cell(bounds.min_x)..=cell(bounds.max_x), cell(bounds.min_y)..=cell(bounds.max_y))
+}
+
+fn cell_count((columns, rows): &(RangeInclusive<i64>, RangeInclusive<i64>)) -> i128 {
+    let span = |range: &RangeInclusive<i64>| *range.end() as i128 - *range.start() as i128 + 1;
+    span(columns).saturating_mul(span(rows))
+}
+
+impl SpatialIndex {
+    pub fn insert(&mut self, id: TriangleId, triangle: &Triangle) {
+        let Some(extent) = Bounds::of([triangle]) else { return };
+        let range = cell_range(&extent);
+        if cell_count(&range) > MAX_SPATIAL_CELLS {
+            self.large.insert(id);
+            return;
+        }
+        let (columns, rows) = range;
+        for x in columns {
+            for y in rows.clone() {
+                self.cells.entry((x, y)).or_default().insert(id);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: &TriangleId, triangle: &Triangle) {
+        if self.large.remove(id) {
+            return;
+        }
+        let Some(extent) = Bounds::of([triangle]) else { return };
+        let (columns, rows) = cell_range(&extent);
+        for x in columns {
+            for y in rows.clone() {
+                if let Some(ids) = self.cells.get_mut(&(x, y)) {
+                    ids.remove(id);
+                    if ids.is_empty() {
+                        self.cells.remove(&(x, y));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.large.clear();
+    }
+
+    /// Ids of the triangles whose bounding boxes may touch `bounds`. Some may
+    /// not, so callers check the triangles themselves.
+    pub fn candidates(&self, bounds: &Bounds) -> HashSet<TriangleId> {
+        let range = cell_range(bounds);
+        let mut found = self.large.clone();
+        let (columns, rows) = &range;
+        // A box wider than the occupied cells is cheaper to match the other way round
+        if cell_count(&range) > self.cells.len() as i128 {
+            for ((x, y), ids) in &self.cells {
+                if columns.contains(x) && rows.contains(y) {
+                    found.extend(ids);
+                }
+            }
+        } else {
+            for x in columns.clone() {
+                for y in rows.clone() {
+                    found.extend(self.cells.get(&(x, y)).into_iter().flatten());
+                }
+            }
+        }
+        found
+    }
+}
+
+/// A tile address: `2^z` tiles per side at zoom `z`, counted from the top left
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileCoord {
+    pub z: u8,
+    pub x: u64,
+    pub y: u64,
+}
+
+impl TileCoord {
+    pub fn new(z: u8, x: u64, y: u64) -> Result<Self, ChainError> {
+        if z > MAX_ZOOM {
+            return Err(ChainError::InvalidTile(format!("Zoom {} is above the maximum of {}", z, MAX_ZOOM)));
+        }
+        let tiles = 1u64 << z;
+        if x >= tiles || y >= tiles {
+            return Err(ChainError::InvalidTile(format!("Tile {}/{}/{} is outside the {}x{} grid", z, x, y, tiles, tiles)));
+        }
+        Ok(TileCoord { z, x, y })
+    }
+
+    /// The part of `world` this tile covers. `world` should be square so tiles are too.
+    pub fn bounds(&self, world: &Bounds) -> Bounds {
+        let tiles = (1u64 << self.z) as f64;
+        let (w, h) = (world.width() / tiles, world.height() / tiles);
+        let min_x = world.min_x + self.x as f64 * w;
+        let max_y = world.max_y - self.y as f64 * h;
+        Bounds { min_x, min_y: max_y - h, max_x: min_x + w, max_y }
+    }
+}
+
+/// A region of chain space mapped onto a `width` x `height` pixel image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub bounds: Bounds,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// An RGB image, three bytes per pixel, rows from the top
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Raster {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Raster {
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let i = (y as usize * self.width as usize + x as usize) * 3;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2]]
+    }
+
+    fn set_pixel(&mut self, x: u32, y: u32, rgb: [u8; 3]) {
+        let i = (y as usize * self.width as usize + x as usize) * 3;
+        self.pixels[i..i + 3].copy_from_slice(&rgb);
+    }
+
+    pub fn to_png(&self) -> Result<Vec<u8>, ChainError> {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| ChainError::CodecError(e.to_string()))?;
+        writer.write_image_data(&self.pixels).map_err(|e| ChainError::CodecError(e.to_string()))?;
+        writer.finish().map_err(|e| ChainError::CodecError(e.to_string()))?;
+        Ok(bytes)
+    }
+}
+
+/// An HSL color, written the way SVG takes it and convertible to RGB for rasters
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Hsl {
+    hue: u32,
+    saturation: u32,
+    lightness: u32,
+}
+
+impl Hsl {
+    fn new(hue: u32, saturation: u32, lightness: u32) -> Self {
+        Hsl { hue: hue % 360, saturation, lightness }
+    }
+
+    fn to_rgb(self) -> [u8; 3] {
+        let s = self.saturation as f64 / 100.0;
+        let l = self.lightness as f64 / 100.0;
+        let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+        let h = self.hue as f64 / 60.0;
+        let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+        let (r, g, b) = match self.hue / 60 {
+            0 => (c, x, 0.0),
+            1 => (x, c, 0.0),
+            2 => (0.0, c, x),
+            3 => (0.0, x, c),
+            4 => (x, 0.0, c),
+            _ => (c, 0.0, x),
+        };
+        let m = l - c / 2.0;
+        [r, g, b].map(|v| ((v + m) * 255.0).round() as u8)
+    }
+}
+
+impl fmt::Display for Hsl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "hsl({},{}%,{}%)", self.hue, self.saturation, self.lightness)
+    }
+}
+
+/// Parse a `#rrggbb` color, falling back to white
+fn parse_rgb(color: &str) -> [u8; 3] {
+    color.strip_prefix('#')
+        .and_then(|hex| hex::decode(hex).ok())
+        .and_then(|bytes| <[u8; 3]>::try_from(bytes).ok())
+        .unwrap_or([0xff; 3])
+}
+
+/// Fill color for a triangle under `color_by`
+fn fill(triangle: &Triangle, id: &TriangleId, color_by: &ColorBy) -> Hsl {
+    match color_by {
+        ColorBy::Uniform => Hsl::new(211, 65, 57),
+        ColorBy::Owner => {
+            let digest = Sha256::digest(triangle.owner.as_bytes());
+            Hsl::new(u16::from_le_bytes([digest[0], digest[1]]) as u32, 65, 55)
+        }
+        ColorBy::Depth => Hsl::new(triangle.depth * 37, 70, 50),
+        ColorBy::Age { heights, tip } => {
+            let created = heights.get(id).copied().unwrap_or(0);
+            let freshness = if *tip == 0 { 1.0 } else { created.min(*tip) as f64 / *tip as f64 };
+            Hsl::new(210, 70, (20.0 + 60.0 * freshness).round() as u32)
+        }
+    }
+}
+
+/// Render `triangles` as an SVG document. The drawing is scaled to fit, with
+/// the y axis pointing up as in chain coordinates.
+pub fn to_svg<'a>(triangles: impl IntoIterator<Item = (&'a TriangleId, &'a Triangle)>, options: &SvgOptions) -> String {
+    let mut triangles: Vec<_> = triangles.into_iter().collect();
+    triangles.sort_by_key(|(id, _)| **id);
+
+    let bounds = Bounds::of(triangles.iter().map(|(_, t)| *t))
+        .unwrap_or(Bounds { min_x: 0.0, min_y: 0.0, max_x: 1.0, max_y: 1.0 });
+    let span_x = bounds.width().max(Coord::MIN_POSITIVE);
+    let span_y = bounds.height().max(Coord::MIN_POSITIVE);
+    let width = options.width.max(1) as f64;
+    let scale = width / span_x;
+    let height = (span_y * scale).ceil().max(1.0);
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+        w = width,
+        h = height
+    );
+    let _ = writeln!(svg, r#"<rect width="100%" height="100%" fill="{}"/>"#, options.background);
+    for (id, triangle) in triangles {
+        let points: Vec<String> = [triangle.a, triangle.b, triangle.c]
+            .iter()
+            .map(|p| format!("{:.3},{:.3}", (p.x - bounds.min_x) * scale, height - (p.y - bounds.min_y) * scale))
+            .collect();
+        let stroke = if options.stroke { r##" stroke="#222222" stroke-width="0.5""## } else { "" };
+        let _ = writeln!(
+            svg,
+            r#"<polygon id="t{}" points="{}" fill="{}"{}/>"#,
+            hex::encode(&id[..8]),
+            points.join(" "),
+            fill(triangle, id, &options.color_by),
+            stroke
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Rasterize the part of `triangles` inside `viewport`. A pixel is filled when
+/// its center lies in a triangle; triangles smaller than a pixel still mark the
+/// pixel under their centroid so deep subdivisions don't vanish when zoomed
+/// out. Outlines are only drawn in SVG output.
+pub fn rasterize<'a>(
+    triangles: impl IntoIterator<Item = (&'a TriangleId, &'a Triangle)>,
+    viewport: &Viewport,
+    options: &SvgOptions,
+) -> Raster {
+    let (width, height) = (viewport.width.max(1), viewport.height.max(1));
+    let mut raster = Raster {
+        width,
+        height,
+        pixels: parse_rgb(&options.background).repeat(width as usize * height as usize),
+    };
+
+    let view = viewport.bounds;
+    let scale_x = width as f64 / view.width().max(Coord::MIN_POSITIVE);
+    let scale_y = height as f64 / view.height().max(Coord::MIN_POSITIVE);
+    let to_pixel = |p: Point| ((p.x - view.min_x) * scale_x, (view.max_y - p.y) * scale_y);
+
+    let mut triangles: Vec<_> = triangles.into_iter()
+        .filter(|(_, t)| Bounds::of([*t]).is_some_and(|b| b.intersects(&view)))
+        .collect();
+    triangles.sort_by_key(|(id, _)| **id);
+
+    for (id, triangle) in triangles {
+        let rgb = fill(triangle, id, &options.color_by).to_rgb();
+        let [a, b, c] = [triangle.a, triangle.b, triangle.c].map(to_pixel);
+        let (min_x, max_x) = (a.0.min(b.0).min(c.0), a.0.max(b.0).max(c.0));
+        let (min_y, max_y) = (a.1.min(b.1).min(c.1), a.1.max(b.1).max(c.1));
+
+        if max_x - min_x < 1.0 && max_y - min_y < 1.0 {
+            let (cx, cy) = to_pixel(triangle.centroid());
+            if cx >= 0.0 && cy >= 0.0 && cx < width as f64 && cy < height as f64 {
+                raster.set_pixel(cx as u32, cy as u32, rgb);
+            }
+            continue;
+        }
+
+        let edge = |p: (f64, f64), q: (f64, f64), x: f64, y: f64| (q.0 - p.0) * (y - p.1) - (q.1 - p.1) * (x - p.0);
+        let x_range = (min_x.floor().max(0.0) as u32)..(max_x.ceil().min(width as f64) as u32);
+        for py in (min_y.floor().max(0.0) as u32)..(max_y.ceil().min(height as f64) as u32) {
+            for px in x_range.clone() {
+                let (x, y) = (px as f64 + 0.5, py as f64 + 0.5);
+                let (e0, e1, e2) = (edge(a, b, x, y), edge(b, c, x, y), edge(c, a, x, y));
+                if (e0 >= 0.0 && e1 >= 0.0 && e2 >= 0.0) || (e0 <= 0.0 && e1 <= 0.0 && e2 <= 0.0) {
+                    raster.set_pixel(px, py, rgb);
+                }
+            }
+        }
+    }
+    raster
+}
+
+/// One item of a level-of-detail query: a triangle big enough to draw on its
+/// own, or the small triangles under one ancestor merged together
+#[derive(Debug, Clone, PartialEq)]
+pub enum LodEntry<'a> {
+    Triangle(&'a TriangleId, &'a Triangle),
+    Cluster {
+        /// Root and lineage of the smallest ancestor at least `min_area` in size
+        root: TriangleId,
+        depth: u32,
+        lineage: u64,
+        /// Bounding box of the merged triangles
+        bounds: Bounds,
+        count: usize,
+        area: Coord,
+    },
+}
+
+impl TriangleState {
+    /// The unspent triangles whose bounding boxes touch `bounds`, in id order
+    pub fn query_region(&self, bounds: &Bounds) -> Vec<(&TriangleId, &Triangle)> {
+        let mut triangles: Vec<_> = self.spatial_index.candidates(bounds).into_iter()
+            .filter_map(|id| self.utxo_set.get_key_value(&id))
+            .filter(|(_, triangle)| Bounds::of([*triangle]).is_some_and(|extent| extent.intersects(bounds)))
+            .collect();
+        triangles.sort_by_key(|(id, _)| **id);
+        triangles
+    }
+
+    /// The unspent triangles touching `bounds`, at a level of detail for
+    /// drawing: triangles of at least `min_area` come back as they are, and
+    /// smaller ones are merged per ancestor of at least `min_area`. For a
+    /// viewport, `min_area` is the area of a pixel, so the result stays about
+    /// as large as the image however deep the subdivisions go.
+    pub fn query_lod(&self, bounds: &Bounds, min_area: Coord) -> Vec<LodEntry<'_>> {
+        let mut triangles = Vec::new();
+        let mut clusters: HashMap<(TriangleId, u32, u64), (Bounds, usize, Coord)> = HashMap::new();
+
+        for (id, triangle) in &self.utxo_set {
+            let Some(extent) = Bounds::of([triangle]) else { continue };
+            if !extent.intersects(bounds) {
+                continue;
+            }
+            let area = triangle.area();
+            if area >= min_area {
+                triangles.push(LodEntry::Triangle(id, triangle));
+                continue;
+            }
+
+            // Each level up quadruples the area
+            let mut levels = 0;
+            while levels < triangle.depth && area * 4f64.powi(levels as i32) < min_area {
+                levels += 1;
+            }
+            let key = (triangle.root_id(), triangle.depth - levels, triangle.lineage >> (2 * levels));
+            let cluster = clusters.entry(key).or_insert((extent, 0, 0.0));
+            cluster.0 = Bounds {
+                min_x: cluster.0.min_x.min(extent.min_x),
+                min_y: cluster.0.min_y.min(extent.min_y),
+                max_x: cluster.0.max_x.max(extent.max_x),
+                max_y: cluster.0.max_y.max(extent.max_y),
+            };
+            cluster.1 += 1;
+            cluster.2 += area;
+        }
+
+        triangles.sort_by_key(|entry| match entry {
+            LodEntry::Triangle(id, _) => **id,
+            LodEntry::Cluster { root, .. } => *root,
+        });
+        let mut clusters: Vec<_> = clusters.into_iter().collect();
+        clusters.sort_by_key(|(key, _)| *key);
+        triangles.extend(clusters.into_iter().map(|((root, depth, lineage), (bounds, count, area))| {
+            LodEntry::Cluster { root, depth, lineage, bounds, count, area }
+        }));
+        triangles
+    }
+
+    /// Render the unspent triangles as an SVG document
+    pub fn to_svg(&self, options: &SvgOptions) -> String {
+        to_svg(&self.utxo_set, options)
+    }
+
+    /// The square every tile pyramid of this state divides, or `None` when
+    /// there are no unspent triangles. It grows as coinbases add triangles
+    /// further out, so tiles are only stable for a given state.
+    pub fn tile_world(&self) -> Option<Bounds> {
+        Bounds::of(self.utxo_set.values()).map(|b| b.square())
+    }
+
+    /// Rasterize one `TILE_SIZE` map tile as a PNG
+    pub fn render_tile(&self, tile: TileCoord, options: &SvgOptions) -> Result<Vec<u8>, ChainError> {
+        let world = self.tile_world()
+            .unwrap_or(Bounds { min_x: 0.0, min_y: 0.0, max_x: 1.0, max_y: 1.0 });
+        let viewport = Viewport { bounds: tile.bounds(&world), width: TILE_SIZE, height: TILE_SIZE };
+        rasterize(&self.utxo_set, &viewport, options).to_png()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis_triangle;
+
+    fn subdivided_state() -> TriangleState {
+        let mut state = TriangleState::new();
+        for (i, mut child) in genesis_triangle().subdivide().into_iter().enumerate() {
+            child.owner = format!("owner{}", i % 2);
+            state.utxo_set.insert(child.hash(), child);
+        }
+        state
+    }
+
+    #[test]
+    fn test_svg_draws_every_triangle_deterministically() {
+        let state = subdivided_state();
+        let svg = state.to_svg(&SvgOptions::default());
+
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert_eq!(svg.matches("<polygon").count(), 3);
+        assert_eq!(svg, state.clone().to_svg(&SvgOptions::default()));
+
+        let empty = TriangleState::new().to_svg(&SvgOptions::default());
+        assert_eq!(empty.matches("<polygon").count(), 0);
+    }
+
+    #[test]
+    fn test_svg_colors() {
+        let state = subdivided_state();
+        let fills = |options: &SvgOptions| -> Vec<String> {
+            state.to_svg(options).lines()
+                .filter_map(|line| line.split("fill=\"").nth(1))
+                .map(|rest| rest.split('"').next().unwrap().to_string())
+                .skip(1) // background
+                .collect()
+        };
+
+        let by_owner = fills(&SvgOptions { color_by: ColorBy::Owner, ..SvgOptions::default() });
+        let mut distinct = by_owner.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), 2);
+
+        let by_depth = fills(&SvgOptions { color_by: ColorBy::Depth, ..SvgOptions::default() });
+        assert!(by_depth.iter().all(|fill| *fill == by_depth[0]));
+
+        let newest = *state.utxo_set.keys().next().unwrap();
+        let heights = HashMap::from([(newest, 10)]);
+        let by_age = fills(&SvgOptions { color_by: ColorBy::Age { heights, tip: 10 }, ..SvgOptions::default() });
+        assert!(by_age.contains(&"hsl(210,70%,80%)".to_string()));
+        assert!(by_age.contains(&"hsl(210,70%,20%)".to_string()));
+    }
+
+    #[test]
+    fn test_tile_bounds() {
+        let world = Bounds { min_x: 0.0, min_y: 0.0, max_x: 8.0, max_y: 8.0 };
+        assert_eq!(TileCoord::new(0, 0, 0).unwrap().bounds(&world), world);
+
+        // Rows count down from the top
+        let tile = TileCoord::new(2, 1, 0).unwrap().bounds(&world);
+        assert_eq!(tile, Bounds { min_x: 2.0, min_y: 6.0, max_x: 4.0, max_y: 8.0 });
+
+        assert!(matches!(TileCoord::new(1, 2, 0), Err(ChainError::InvalidTile(_))));
+        assert!(matches!(TileCoord::new(MAX_ZOOM + 1, 0, 0), Err(ChainError::InvalidTile(_))));
+    }
+
+    #[test]
+    fn test_parse_bounds() {
+        assert_eq!("-1,0.5, 2,3".parse::<Bounds>().unwrap(), Bounds { min_x: -1.0, min_y: 0.5, max_x: 2.0, max_y: 3.0 });
+        assert!("0,0,1".parse::<Bounds>().is_err());
+        assert!("0,0,1,x".parse::<Bounds>().is_err());
+        assert!("2,0,1,1".parse::<Bounds>().is_err());
+        assert!("0,0,inf,1".parse::<Bounds>().is_err());
+    }
+
+    #[test]
+    fn test_rasterize() {
+        assert_eq!(Hsl::new(211, 65, 57).to_rgb(), [74, 143, 217]);
+
+        let state = subdivided_state();
+        let world = state.tile_world().unwrap();
+        let options = SvgOptions { color_by: ColorBy::Owner, ..SvgOptions::default() };
+        let raster = rasterize(&state.utxo_set, &Viewport { bounds: world, width: 64, height: 64 }, &options);
+        assert_eq!(raster.pixels.len(), 64 * 64 * 3);
+
+        // The bottom-left child is drawn in its owner's color
+        let corner = state.utxo_set.values()
+            .find(|t| t.a.x == world.min_x && t.a.y == world.min_y).unwrap();
+        assert_eq!(raster.pixel(4, 62), fill(corner, &[0; 32], &ColorBy::Owner).to_rgb());
+
+        // A triangle far smaller than a pixel still shows up
+        let mut tiny = genesis_triangle();
+        for _ in 0..12 {
+            tiny = tiny.subdivide()[0].clone();
+        }
+        let tiny_state: HashMap<_, _> = [(tiny.hash(), tiny)].into_iter().collect();
+        let raster = rasterize(&tiny_state, &Viewport { bounds: world, width: 64, height: 64 }, &SvgOptions::default());
+        assert!(raster.pixels.chunks(3).any(|p| p != [0xff; 3]));
+
+        let png = state.render_tile(TileCoord::new(1, 0, 1).unwrap(), &options).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn test_spatial_index() {
+        let mut index = SpatialIndex::default();
+        let small = Triangle::equilateral(Point::new(100.0, 100.0), 10.0, "alice".to_string());
+        let far = Triangle::equilateral(Point::new(5000.0, 5000.0), 10.0, "bob".to_string());
+        let huge = Triangle::equilateral(Point::new(0.0, 0.0), 1e8, "carol".to_string());
+        for triangle in [&small, &far, &huge] {
+            index.insert(triangle.hash(), triangle);
+        }
+
+        // Triangles spanning many cells come back from every lookup
+        let near_small = Bounds::of([&small]).unwrap();
+        assert_eq!(index.candidates(&near_small), [small.hash(), huge.hash()].into_iter().collect());
+        let everywhere = Bounds { min_x: -1e9, min_y: -1e9, max_x: 1e9, max_y: 1e9 };
+        assert_eq!(index.candidates(&everywhere).len(), 3);
+
+        index.remove(&small.hash(), &small);
+        index.remove(&huge.hash(), &huge);
+        assert!(index.candidates(&near_small).is_empty());
+        assert_eq!(index.candidates(&everywhere), [far.hash()].into_iter().collect());
+    }
+
+    #[test]
+    fn test_query_lod() {
+        // Genesis split twice over: nine leaves of a sixteenth of its area each
+        let genesis = genesis_triangle();
+        let mut state = TriangleState::new();
+        for child in genesis.subdivide() {
+            for leaf in child.subdivide() {
+                state.insert_triangle(leaf.hash(), leaf);
+            }
+        }
+        let far = Triangle::equilateral(Point::new(100.0, 100.0), 1.0, "bob".to_string());
+        state.insert_triangle(far.hash(), far);
+        let near_genesis = Bounds::of([&genesis]).unwrap();
+
+        // Fine enough: every leaf on its own, nothing from outside the box
+        let entries = state.query_lod(&near_genesis, 0.0);
+        assert_eq!(entries.len(), 9);
+        assert_eq!(state.query_region(&near_genesis).len(), 9);
+        assert!(entries.iter().all(|entry| matches!(entry, LodEntry::Triangle(..))));
+
+        // A pixel nearly the size of a child merges the leaves into their three parents
+        let entries = state.query_lod(&near_genesis, genesis.area() / 5.0);
+        assert_eq!(entries.len(), 3);
+        for entry in &entries {
+            let LodEntry::Cluster { root, depth, count, area, .. } = entry else { panic!("expected a cluster") };
+            assert_eq!((*root, *depth, *count), (genesis.hash(), 1, 3));
+            assert!((area - genesis.area() * 3.0 / 16.0).abs() < 1e-12);
+        }
+
+        // Coarser than the root still stops at the root
+        let entries = state.query_lod(&near_genesis, genesis.area() * 100.0);
+        assert!(matches!(entries[..], [LodEntry::Cluster { depth: 0, count: 9, .. }]));
+    }
+}
@@ -0,0 +1,179 @@
+//! Client for the HTTP API of a running node
+//!
+//! `siertrid` serves the API over the chain its network node and miner
+//! share, so the wallet CLIs ask it instead of opening the database
+//! themselves: they see the node's mempool, and transactions they submit
+//! are relayed to peers by the node that holds them.
+
+use crate::api::{SubmitTransactionResponse, TriangleInfo, TrianglePathInfo, DEFAULT_API_PORT};
+use crate::blockchain::{parse_hash, TrianglePath};
+use crate::geometry::TriangleId;
+use crate::error::ChainError;
+use crate::network::SyncState;
+use crate::transaction::{Address, Transaction};
+use serde::de::DeserializeOwned;
+
+pub struct RpcClient {
+    base_url: String,
+    http: reqwest::Client,
+}
+
+impl RpcClient {
+    /// A client for the API at `base_url`, e.g. `http://127.0.0.1:3000`
+    pub fn new(base_url: impl Into<String>) -> Self {
+        RpcClient {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// A client for the API at `SIERTRI_RPC_URL`, or on localhost at `DEFAULT_API_PORT`
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("SIERTRI_RPC_URL")
+            .unwrap_or_else(|_| format!("http://127.0.0.1:{}", DEFAULT_API_PORT)))
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// How far the node has synced, including the height of its tip
+    pub async fn sync_state(&self) -> Result<SyncState, ChainError> {
+        self.get("/network/sync").await
+    }
+
+    /// Unspent triangles `address` owns
+    pub async fn triangles(&self, address: &Address) -> Result<Vec<TriangleInfo>, ChainError> {
+        self.get(&format!("/address/{}/triangles", address)).await
+    }
+
+    /// The nonce `address`'s next transaction should use
+    pub async fn next_nonce(&self, address: &Address) -> Result<u64, ChainError> {
+        self.get(&format!("/address/{}/nonce", address)).await
+    }
+
+    /// The unspent triangle at `path`
+    pub async fn triangle_at(&self, path: &TrianglePath) -> Result<TrianglePathInfo, ChainError> {
+        // The API takes `.` between the parts, since `/` would split the URL
+        let url_path = format!("/triangle/path/{}", path.to_string().replace('/', "."));
+        let response = self.send_get(&url_path).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(ChainError::TriangleNotFound(format!("No unspent triangle at {}", path)));
+        }
+        Self::parse(&url_path, response).await
+    }
+
+    /// Resolve what a user typed to refer to one of `owner`'s triangles: a
+    /// path such as `Δ/0/2`, or a (prefix of a) hex id. Returns its id and area.
+    pub async fn resolve(&self, owner: &Address, reference: &str) -> Result<(TriangleId, f64), ChainError> {
+        if reference.contains(['/', '.']) || reference == TrianglePath::GENESIS_ROOT {
+            let info = self.triangle_at(&reference.parse()?).await?;
+            return Ok((parse_hash(&info.hash)?, info.area));
+        }
+        let prefix = reference.to_ascii_lowercase();
+        let triangles = self.triangles(owner).await?;
+        let mut matches = triangles.iter().filter(|triangle| triangle.hash.starts_with(&prefix));
+        let found = matches.next()
+            .ok_or_else(|| ChainError::TriangleNotFound(format!("No triangle of yours with id prefix {}", prefix)))?;
+        if matches.next().is_some() {
+            return Err(ChainError::InvalidTransaction(format!("Triangle id prefix {} is ambiguous", prefix)));
+        }
+        Ok((parse_hash(&found.hash)?, found.area))
+    }
+
+    /// Submit a transaction to the node's mempool. Rejections come back as a
+    /// response with the reason, not as an error.
+    pub async fn submit_transaction(&self, tx: &Transaction) -> Result<SubmitTransactionResponse, ChainError> {
+        let response = self.http.post(self.url("/transaction")).json(tx).send().await
+            .map_err(|e| self.unreachable(e))?;
+        let status = response.status();
+        let body = response.text().await.map_err(|e| self.unreachable(e))?;
+        serde_json::from_str(&body)
+            .map_err(|_| ChainError::NetworkError(format!("Node answered {}: {}", status, body)))
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, ChainError> {
+        let response = self.send_get(path).await?;
+        Self::parse(path, response).await
+    }
+
+    async fn send_get(&self, path: &str) -> Result<reqwest::Response, ChainError> {
+        self.http.get(self.url(path)).send().await.map_err(|e| self.unreachable(e))
+    }
+
+    async fn parse<T: DeserializeOwned>(path: &str, response: reqwest::Response) -> Result<T, ChainError> {
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(ChainError::NetworkError(format!("Node answered {} to {}: {}", status, path, body)));
+        }
+        response.json().await
+            .map_err(|e| ChainError::NetworkError(format!("Unexpected answer to {}: {}", path, e)))
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    fn unreachable(&self, error: reqwest::Error) -> ChainError {
+        ChainError::NetworkError(format!("Failed to reach the node at {} (is siertrid running?): {}", self.base_url, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::crypto::KeyPair;
+    use crate::persistence::Database;
+    use crate::transaction::TransferTx;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+    use tokio::sync::RwLock;
+
+    /// A client for the API served over `chain` on a free port
+    async fn serve(chain: Blockchain) -> RpcClient {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let db = Database::open(":memory:").unwrap();
+        tokio::spawn(crate::api::serve(listener, Arc::new(RwLock::new(chain)), Arc::new(Mutex::new(db))));
+        RpcClient::new(format!("http://{}/", addr))
+    }
+
+    #[tokio::test]
+    async fn test_wallet_requests_reach_the_node() {
+        let mut chain = Blockchain::regtest();
+        let keypair = KeyPair::generate().unwrap();
+        let address = keypair.address();
+        let genesis = *chain.state.utxo_set.keys().next().unwrap();
+        chain.state.set_owner(&genesis, address.clone()).unwrap();
+        let rpc = serve(chain).await;
+
+        assert_eq!(rpc.sync_state().await.unwrap().blocks_height, 0);
+        let (id, area) = rpc.resolve(&address, &hex::encode(genesis)[..8]).await.unwrap();
+        assert_eq!(id, genesis);
+        assert!(area > 0.0);
+        assert_eq!(rpc.resolve(&address, TrianglePath::GENESIS_ROOT).await.unwrap().0, genesis);
+        assert!(matches!(rpc.resolve(&address, "Δ/0").await, Err(ChainError::TriangleNotFound(_))));
+        assert!(matches!(rpc.resolve(&"someone".to_string(), &hex::encode(genesis)).await, Err(ChainError::TriangleNotFound(_))));
+
+        let nonce = rpc.next_nonce(&address).await.unwrap();
+        let mut tx = TransferTx::new(genesis, "bob".to_string(), address.clone(), 0, nonce);
+        let signature = keypair.sign(&tx.signable_message()).unwrap();
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        let tx = Transaction::Transfer(tx);
+        assert_eq!(rpc.submit_transaction(&tx).await.unwrap().status, "accepted");
+        assert_eq!(rpc.next_nonce(&address).await.unwrap(), nonce + 1);
+
+        // A rejection is an answer, not a failure
+        let rejected = rpc.submit_transaction(&tx).await.unwrap();
+        assert_eq!((rejected.status.as_str(), rejected.reason.as_deref()), ("rejected", Some("duplicate")));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_node() {
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let rpc = RpcClient::new(format!("http://{}", addr));
+        assert!(matches!(rpc.sync_state().await, Err(ChainError::NetworkError(e)) if e.contains("siertrid")));
+    }
+}
@@ -7,8 +7,9 @@ use crate::error::ChainError;
 use ipnetwork::IpNetwork;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::IpAddr;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -81,6 +82,10 @@ pub struct NetworkPolicy {
     rules: Vec<FirewallRule>,
     /// Require peer authentication
     require_auth: bool,
+    /// Public keys of the peers allowed to authenticate
+    trusted_keys: HashSet<Vec<u8>>,
+    /// Refuse peers that don't encrypt their connections
+    require_encryption: bool,
     /// VPN tunnel interface name (if using VPN)
     vpn_interface: Option<String>,
     /// SOCKS5 proxy for outbound connections
@@ -93,6 +98,8 @@ impl NetworkPolicy {
         Self {
             rules: Vec::new(),
             require_auth: true,
+            trusted_keys: HashSet::new(),
+            require_encryption: false,
             vpn_interface: None,
             socks5_proxy: None,
         }
@@ -112,9 +119,26 @@ impl NetworkPolicy {
             policy.socks5_proxy = Some(proxy);
         }
 
-        // Check if auth is required
-        if let Ok(auth) = std::env::var("SIERTRI_REQUIRE_AUTH") {
-            policy.require_auth = auth.to_lowercase() != "false";
+        // Trusted peer keys, comma-separated hex
+        if let Ok(keys) = std::env::var("SIERTRI_TRUSTED_PEERS") {
+            for key in keys.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+                match hex::decode(key) {
+                    Ok(key) => policy.trust_key(key),
+                    Err(e) => eprintln!("⚠️  Ignoring trusted peer key {}: {}", key, e),
+                }
+            }
+        }
+
+        // Check if auth is required. Without trusted keys every peer would
+        // fail it, so it's only on by default once some are configured.
+        policy.require_auth = match std::env::var("SIERTRI_REQUIRE_AUTH") {
+            Ok(auth) => auth.to_lowercase() != "false",
+            Err(_) => !policy.trusted_keys.is_empty(),
+        };
+
+        // Check if encryption is required
+        if let Ok(encryption) = std::env::var("SIERTRI_REQUIRE_ENCRYPTION") {
+            policy.require_encryption = encryption.to_lowercase() == "true";
         }
 
         policy
@@ -162,10 +186,46 @@ impl NetworkPolicy {
         self.socks5_proxy.as_deref()
     }
 
+    /// Route outbound connections through a SOCKS5 proxy, or dial directly
+    pub fn set_socks5_proxy(&mut self, proxy: Option<String>) {
+        self.socks5_proxy = proxy;
+    }
+
     /// Check if peer authentication is required
     pub fn requires_auth(&self) -> bool {
         self.require_auth
     }
+
+    /// Require, or stop requiring, peers to authenticate
+    pub fn set_require_auth(&mut self, required: bool) {
+        self.require_auth = required;
+    }
+
+    /// Let the peer holding `public_key` authenticate
+    pub fn trust_key(&mut self, public_key: Vec<u8>) {
+        self.trusted_keys.insert(public_key);
+    }
+
+    /// Check if the peer holding `public_key` may authenticate
+    pub fn is_trusted_key(&self, public_key: &[u8]) -> bool {
+        self.trusted_keys.contains(public_key)
+    }
+
+    /// Check if peers must encrypt their connections
+    pub fn requires_encryption(&self) -> bool {
+        self.require_encryption
+    }
+
+    /// Refuse, or again accept, peers that don't encrypt their connections
+    pub fn set_require_encryption(&mut self, required: bool) {
+        self.require_encryption = required;
+    }
+}
+
+impl Default for NetworkPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// Rate limiter for peer connections and API requests
@@ -177,6 +237,8 @@ pub struct RateLimitConfig {
     pub api_requests_per_sec: u32,
     /// Transaction submission rate (transactions per second)
     pub transactions_per_sec: u32,
+    /// Per-peer bandwidth limit (bytes per second)
+    pub peer_bytes_per_sec: u32,
 }
 
 impl Default for RateLimitConfig {
@@ -185,6 +247,7 @@ impl Default for RateLimitConfig {
             peer_requests_per_sec: 100,
             api_requests_per_sec: 50,
             transactions_per_sec: 10,
+            peer_bytes_per_sec: 8 * 1024 * 1024,
         }
     }
 }
@@ -207,6 +270,29 @@ impl TokenBucket {
     }
 
     fn try_consume(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take `amount` tokens unless the bucket is empty. Taking more than it
+    /// holds leaves it in debt, so a request bigger than the rate still gets
+    /// through but the average stays within it.
+    fn try_consume_amount(&mut self, amount: f64) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&mut self) {
         let now = current_timestamp();
         let elapsed = now.saturating_sub(self.last_refill);
 
@@ -218,13 +304,6 @@ impl TokenBucket {
         if self.tokens > self.rate as f64 {
             self.tokens = self.rate as f64;
         }
-
-        if self.tokens >= 1.0 {
-            self.tokens -= 1.0;
-            true
-        } else {
-            false
-        }
     }
 }
 
@@ -233,6 +312,8 @@ impl TokenBucket {
 pub struct RequestRateLimiter {
     // Map of entity ID to token bucket
     limiters: Arc<RwLock<HashMap<String, TokenBucket>>>,
+    // Map of peer ID to a bucket of bytes
+    bandwidth: Arc<RwLock<HashMap<String, TokenBucket>>>,
     config: RateLimitConfig,
 }
 
@@ -241,6 +322,7 @@ impl RequestRateLimiter {
     pub fn new(config: RateLimitConfig) -> Self {
         Self {
             limiters: Arc::new(RwLock::new(HashMap::new())),
+            bandwidth: Arc::new(RwLock::new(HashMap::new())),
             config,
         }
     }
@@ -254,6 +336,26 @@ impl RequestRateLimiter {
         )
     }
 
+    /// Check if a peer may send `bytes` more
+    pub fn check_peer_bandwidth(&self, peer_id: &str, bytes: usize) -> Result<(), ChainError> {
+        let mut buckets = self.bandwidth.write();
+        let bucket = buckets
+            .entry(peer_id.to_string())
+            .or_insert_with(|| TokenBucket::new(self.config.peer_bytes_per_sec));
+
+        if bucket.try_consume_amount(bytes as f64) {
+            Ok(())
+        } else {
+            Err(ChainError::NetworkError("peer bandwidth limit exceeded".to_string()))
+        }
+    }
+
+    /// Drop the buckets kept for an entity, such as a peer that disconnected
+    pub fn forget(&self, entity_id: &str) {
+        self.limiters.write().remove(entity_id);
+        self.bandwidth.write().remove(entity_id);
+    }
+
     /// Check if API request is allowed
     pub fn check_api_rate_limit(&self, client_ip: &str) -> Result<(), ChainError> {
         self.check_rate_limit(
@@ -319,6 +421,43 @@ impl SecurityManager {
         })
     }
 
+    /// Create a security manager with a freshly generated node key
+    pub fn with_generated_key() -> Result<Self, ChainError> {
+        Self::new(KeyPair::generate()?)
+    }
+
+    /// Create a security manager with the hex node key in `SIERTRI_NODE_KEY`,
+    /// so peers can trust it across restarts, or a freshly generated one
+    pub fn from_env() -> Result<Self, ChainError> {
+        match std::env::var("SIERTRI_NODE_KEY") {
+            Ok(key) => {
+                let bytes = hex::decode(key.trim())
+                    .map_err(|e| ChainError::CryptoError(format!("Invalid SIERTRI_NODE_KEY: {}", e)))?;
+                Self::new(KeyPair::from_secret_bytes(&bytes)?)
+            }
+            Err(_) => Self::with_generated_key(),
+        }
+    }
+
+    /// Public key this node authenticates with, for peers to trust
+    pub fn public_key(&self) -> Vec<u8> {
+        self.node_keypair.public_key_bytes()
+    }
+
+    /// Replace the default rate limits
+    pub fn with_rate_limits(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limiter = Arc::new(RequestRateLimiter::new(config));
+        self
+    }
+
+    /// Static key for encrypted peer connections, derived from the node key
+    pub fn noise_static_key(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"siertrichain noise static key");
+        hasher.update(self.node_keypair.secret_key.secret_bytes());
+        hasher.finalize().into()
+    }
+
     /// Create authentication challenge for peer
     pub fn create_challenge(&self) -> Result<PeerChallenge, ChainError> {
         let mut nonce_bytes = [0u8; 32];
@@ -326,18 +465,20 @@ impl SecurityManager {
         rand::rngs::OsRng.fill_bytes(&mut nonce_bytes);
 
         Ok(PeerChallenge {
-            nonce: hex::encode(&nonce_bytes),
+            nonce: hex::encode(nonce_bytes),
             public_key: hex::encode(self.node_keypair.public_key_bytes()),
             timestamp: current_timestamp(),
         })
     }
 
-    /// Respond to authentication challenge
+    /// Respond to authentication challenge on the connection whose Noise
+    /// handshake hashed to `handshake_hash`
     pub fn create_auth_response(
         &self,
         challenge: &PeerChallenge,
+        handshake_hash: &[u8],
     ) -> Result<PeerAuthResponse, ChainError> {
-        let signature = self.node_keypair.sign(challenge.nonce.as_bytes())?;
+        let signature = self.node_keypair.sign(&auth_message(challenge, handshake_hash))?;
 
         Ok(PeerAuthResponse {
             signature: hex::encode(&signature),
@@ -347,12 +488,15 @@ impl SecurityManager {
         })
     }
 
-    /// Verify peer authentication response
+    /// Verify peer authentication response, which must be signed on the
+    /// connection whose Noise handshake hashed to `handshake_hash` with a
+    /// trusted key
     pub fn verify_auth_response(
         &self,
         peer_id: &str,
         challenge: &PeerChallenge,
         response: &PeerAuthResponse,
+        handshake_hash: &[u8],
     ) -> Result<(), ChainError> {
         // Verify timestamp is recent (within 5 minutes)
         let now = current_timestamp();
@@ -369,11 +513,22 @@ impl SecurityManager {
         let signature = hex::decode(&response.signature)
             .map_err(|e| ChainError::AuthenticationError(format!("Invalid signature: {}", e)))?;
 
-        crate::crypto::verify_signature(
+        if !crate::crypto::verify_signature(
             &public_key_bytes,
-            challenge.nonce.as_bytes(),
+            &auth_message(challenge, handshake_hash),
             &signature,
-        )?;
+        )? {
+            return Err(ChainError::AuthenticationError(
+                "Challenge signature does not verify".to_string(),
+            ));
+        }
+
+        if !self.network_policy.read().is_trusted_key(&public_key_bytes) {
+            return Err(ChainError::AuthenticationError(format!(
+                "Key {} is not trusted",
+                response.public_key
+            )));
+        }
 
         // Mark peer as authenticated
         let mut peers = self.peers.write();
@@ -394,14 +549,13 @@ impl SecurityManager {
 
     /// Check if peer is allowed to connect
     pub fn check_peer_allowed(&self, peer_addr: &str) -> Result<(), ChainError> {
-        // Parse IP from address (format: "ip:port")
-        let ip_str = peer_addr.split(':').next().ok_or_else(|| {
-            ChainError::AuthenticationError("Invalid peer address format".to_string())
-        })?;
-
-        let ip: IpAddr = ip_str.parse().map_err(|_| {
-            ChainError::AuthenticationError(format!("Invalid IP address: {}", ip_str))
-        })?;
+        // Parse IP from address (format: "ip:port", or "[ip]:port" for IPv6)
+        let ip = match peer_addr.parse::<SocketAddr>() {
+            Ok(socket) => socket.ip(),
+            Err(_) => peer_addr.parse::<IpAddr>().map_err(|_| {
+                ChainError::AuthenticationError(format!("Invalid peer address: {}", peer_addr))
+            })?,
+        };
 
         // Check firewall rules
         let policy = self.network_policy.read();
@@ -417,6 +571,18 @@ impl SecurityManager {
         Ok(())
     }
 
+    /// Check a message of `bytes` from a connected peer against its message
+    /// rate and bandwidth limits
+    pub fn check_peer_traffic(&self, peer_addr: &str, bytes: usize) -> Result<(), ChainError> {
+        self.rate_limiter.check_peer_rate_limit(peer_addr)?;
+        self.rate_limiter.check_peer_bandwidth(peer_addr, bytes)
+    }
+
+    /// Forget the rate limits of a peer that disconnected
+    pub fn forget_peer_traffic(&self, peer_addr: &str) {
+        self.rate_limiter.forget(peer_addr);
+    }
+
     /// Check API request rate limit
     pub fn check_api_limit(&self, client_ip: &str) -> Result<(), ChainError> {
         self.rate_limiter.check_api_rate_limit(client_ip)
@@ -438,6 +604,15 @@ impl SecurityManager {
     }
 }
 
+/// What a response to `challenge` signs: the nonce and the hash of the
+/// handshake that encrypted the connection
+fn auth_message(challenge: &PeerChallenge, handshake_hash: &[u8]) -> Vec<u8> {
+    let mut message = b"siertrichain peer auth".to_vec();
+    message.extend_from_slice(challenge.nonce.as_bytes());
+    message.extend_from_slice(handshake_hash);
+    message
+}
+
 /// Get current Unix timestamp
 pub fn current_timestamp() -> u64 {
     SystemTime::now()
@@ -481,9 +656,74 @@ mod tests {
             peer_requests_per_sec: 10,
             api_requests_per_sec: 10,
             transactions_per_sec: 5,
+            peer_bytes_per_sec: 1000,
         });
 
         // Should allow first request
         assert!(limiter.check_peer_rate_limit("peer1").is_ok());
+
+        // One message bigger than the bandwidth limit gets through, leaving
+        // nothing for the next
+        assert!(limiter.check_peer_bandwidth("peer1", 5000).is_ok());
+        assert!(limiter.check_peer_bandwidth("peer1", 1).is_err());
+        assert!(limiter.check_peer_bandwidth("peer2", 1).is_ok());
+        limiter.forget("peer1");
+        assert!(limiter.check_peer_bandwidth("peer1", 1).is_ok());
+    }
+
+    #[test]
+    fn test_check_peer_allowed_parses_ipv6() {
+        let security = SecurityManager::with_generated_key().unwrap();
+        {
+            let policy = security.network_policy();
+            let mut policy = policy.write();
+            policy.add_rule(FirewallRule::Deny("2001:db8::/32".parse().unwrap()));
+            policy.add_rule(FirewallRule::Allow("::/0".parse().unwrap()));
+            policy.add_rule(FirewallRule::Allow("0.0.0.0/0".parse().unwrap()));
+        }
+
+        assert!(security.check_peer_allowed("[::1]:8333").is_ok());
+        assert!(security.check_peer_allowed("10.0.0.1:8333").is_ok());
+        assert!(security.check_peer_allowed("::1").is_ok());
+        assert!(security.check_peer_allowed("[2001:db8::1]:8333").is_err());
+        assert!(matches!(
+            security.check_peer_allowed("2001:db8::1:8333"),
+            Err(ChainError::NetworkError(_))
+        ));
+        assert!(matches!(security.check_peer_allowed("localhost:8333"), Err(ChainError::AuthenticationError(_))));
+    }
+
+    #[test]
+    fn test_auth_response_must_sign_the_challenge() {
+        let node = SecurityManager::with_generated_key().unwrap();
+        let peer = SecurityManager::with_generated_key().unwrap();
+        node.network_policy().write().trust_key(peer.public_key());
+        let challenge = node.create_challenge().unwrap();
+
+        let response = peer.create_auth_response(&challenge, &[1; 32]).unwrap();
+        assert!(node.verify_auth_response("peer", &challenge, &response, &[1; 32]).is_ok());
+        assert!(node.get_peers()["peer"].is_trusted());
+
+        let other = node.create_challenge().unwrap();
+        let response = peer.create_auth_response(&other, &[1; 32]).unwrap();
+        assert!(node.verify_auth_response("other", &challenge, &response, &[1; 32]).is_err());
+        assert!(!node.get_peers().contains_key("other"));
+    }
+
+    #[test]
+    fn test_auth_response_must_come_from_a_trusted_key_on_this_connection() {
+        let node = SecurityManager::with_generated_key().unwrap();
+        let peer = SecurityManager::with_generated_key().unwrap();
+        let challenge = node.create_challenge().unwrap();
+
+        // A valid signature from a key nobody trusted
+        let response = peer.create_auth_response(&challenge, &[1; 32]).unwrap();
+        assert!(node.verify_auth_response("peer", &challenge, &response, &[1; 32]).is_err());
+
+        // Relayed from another connection
+        node.network_policy().write().trust_key(peer.public_key());
+        let response = peer.create_auth_response(&challenge, &[2; 32]).unwrap();
+        assert!(node.verify_auth_response("peer", &challenge, &response, &[1; 32]).is_err());
+        assert!(!node.get_peers().contains_key("peer"));
     }
 }
@@ -0,0 +1,925 @@
+//! Stratum-style remote mining for siertrichain
+//!
+//! A pool node hands jobs to workers over TCP and checks the shares they send
+//! back. A job is a block header, the coinbase and the merkle branch above it,
+//! and a share target easier than the block's. Each worker gets its own range
+//! of coinbase extra nonces, so no two workers ever hash the same header. A
+//! share that also meets the block target is a block.
+//!
+//! Workers may name a payout address. The pool credits each accepted share to
+//! it and pays out pay-per-last-N-shares: every job's coinbase splits the
+//! reward between the addresses behind the last `PPLNS_WINDOW` shares, in
+//! proportion to the work those shares represent.
+//!
+//! Messages are JSON, one per line, so a worker can be written in any language.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{broadcast, mpsc};
+use crate::blockassembler::BlockTemplate;
+use crate::blockchain::{Block, BlockHeader, Blockchain, Sha256Hash, MAX_FUTURE_TIMESTAMP_DRIFT};
+use crate::difficulty;
+use crate::error::ChainError;
+use crate::miner::TemplateWatcher;
+use crate::persistence::Database;
+use crate::transaction::{Address, CoinbaseTx, Transaction};
+
+/// Share target handed to workers: four leading zero hex digits, about 65k
+/// hashes per share. Never harder than the block target.
+pub const DEFAULT_SHARE_BITS: u32 = 0x1f00ffff;
+
+/// Extra nonces given to each worker
+pub const EXTRA_NONCE_RANGE: u64 = 1 << 32;
+
+/// Jobs still accepting shares; older ones are stale
+pub const RECENT_JOBS: usize = 4;
+
+/// How often the server checks whether its job has gone stale
+pub const JOB_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Accepted shares the payouts are split over
+pub const PPLNS_WINDOW: usize = 1000;
+
+/// How long a job may keep paying out an outdated share window
+pub const PAYOUT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Longest line a worker may send, newline included
+pub const MAX_REQUEST_LINE: usize = 4096;
+
+/// A message from a worker
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Request {
+    /// Register under a display name, crediting shares to `address` if given.
+    /// Answered with `Subscribed`, then jobs.
+    Subscribe {
+        worker: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        address: Option<Address>,
+    },
+    Submit(Share),
+}
+
+/// A message from the pool
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Subscribed { worker_id: u64, extra_nonces: Range<u64> },
+    /// New work; shares for older jobs may be rejected as stale
+    Job(Job),
+    /// `block` is the hex hash of the block the share completed, if any
+    ShareAccepted { block: Option<String> },
+    ShareRejected { reason: String },
+}
+
+/// A unit of work: everything a worker needs to build and hash headers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub job_id: u64,
+    /// Header to mine, apart from the merkle root, nonce and timestamp
+    pub header: BlockHeader,
+    pub coinbase: CoinbaseTx,
+    /// Branch from the coinbase, the first transaction, up to the merkle root
+    pub merkle_branch: Vec<Sha256Hash>,
+    pub share_bits: u32,
+}
+
+/// A header a worker found meeting its job's share target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Share {
+    pub job_id: u64,
+    pub extra_nonce: u64,
+    pub nonce: u64,
+    pub timestamp: i64,
+}
+
+impl Job {
+    /// Merkle root once the coinbase carries `extra_nonce`
+    pub fn merkle_root(&self, extra_nonce: u64) -> Sha256Hash {
+        let mut coinbase = self.coinbase.clone();
+        coinbase.extra_nonce = extra_nonce;
+        Block::merkle_root_from_branch(Transaction::Coinbase(coinbase).hash(), 0, &self.merkle_branch)
+    }
+
+    /// The header a share describes
+    pub fn header_for(&self, share: &Share) -> BlockHeader {
+        BlockHeader {
+            merkle_root: self.merkle_root(share.extra_nonce),
+            nonce: share.nonce,
+            timestamp: share.timestamp,
+            ..self.header.clone()
+        }
+    }
+
+    /// Search `nonces` under `extra_nonce` for the first share, giving up if
+    /// `stop` is set
+    pub fn find_share(&self, extra_nonce: u64, nonces: Range<u64>, stop: &AtomicBool) -> Option<Share> {
+        let mut header = self.header.clone();
+        header.merkle_root = self.merkle_root(extra_nonce);
+        for nonce in nonces {
+            if nonce.is_multiple_of(crate::miner::STOP_CHECK_INTERVAL) && stop.load(Ordering::Relaxed) {
+                return None;
+            }
+            header.nonce = nonce;
+            if difficulty::hash_meets_target(&header.calculate_hash(), self.share_bits) {
+                return Some(Share { job_id: self.job_id, extra_nonce, nonce, timestamp: header.timestamp });
+            }
+        }
+        None
+    }
+}
+
+/// An accepted share credited to a payout address
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PoolShare {
+    pub address: Address,
+    /// `difficulty::work` of the share target the share met
+    pub work: f64,
+}
+
+/// Share counts and estimated hashrate of one worker
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkerStats {
+    pub worker_id: u64,
+    pub name: String,
+    pub address: Option<Address>,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub blocks: u64,
+    /// Accepted shares times the work each represents, per second connected
+    pub hashrate: f64,
+}
+
+#[derive(Debug)]
+struct Worker {
+    name: String,
+    address: Option<Address>,
+    extra_nonces: Range<u64>,
+    subscribed_at: Instant,
+    accepted: u64,
+    rejected: u64,
+    blocks: u64,
+    /// Sum of `difficulty::work` over accepted shares
+    work: f64,
+}
+
+#[derive(Debug)]
+struct OpenJob {
+    job: Job,
+    template: Block,
+    seen: HashSet<Share>,
+}
+
+/// The jobs and workers of a pool, and the rules a share must pass. Holds
+/// no connections, so the server can share it between them.
+#[derive(Debug)]
+pub struct Pool {
+    share_bits: u32,
+    jobs: VecDeque<OpenJob>,
+    next_job_id: u64,
+    workers: HashMap<u64, Worker>,
+    next_worker_id: u64,
+    /// The last `PPLNS_WINDOW` credited shares, oldest first
+    window: VecDeque<PoolShare>,
+    shares_since_job: u64,
+}
+
+impl Pool {
+    pub fn new(share_bits: u32) -> Self {
+        Pool {
+            share_bits,
+            jobs: VecDeque::new(),
+            next_job_id: 0,
+            workers: HashMap::new(),
+            next_worker_id: 0,
+            window: VecDeque::new(),
+            shares_since_job: 0,
+        }
+    }
+
+    /// Reload credited shares saved by an earlier run, oldest first
+    pub fn restore_shares(&mut self, shares: impl IntoIterator<Item = PoolShare>) {
+        for share in shares {
+            self.credit(share);
+        }
+        self.shares_since_job = 0;
+    }
+
+    fn credit(&mut self, share: PoolShare) {
+        self.window.push_back(share);
+        if self.window.len() > PPLNS_WINDOW {
+            self.window.pop_front();
+        }
+        self.shares_since_job += 1;
+    }
+
+    /// The most recently credited share
+    pub fn last_share(&self) -> Option<&PoolShare> {
+        self.window.back()
+    }
+
+    /// Work credited to each address over the share window, by address. A
+    /// job built from these pays each address its part of the reward.
+    pub fn payouts(&self) -> Vec<(Address, f64)> {
+        let mut payouts: HashMap<&str, f64> = HashMap::new();
+        for share in &self.window {
+            *payouts.entry(share.address.as_str()).or_default() += share.work;
+        }
+        let mut payouts: Vec<(Address, f64)> = payouts.into_iter()
+            .map(|(address, work)| (address.to_string(), work))
+            .collect();
+        payouts.sort_by(|(a, _), (b, _)| a.cmp(b));
+        payouts
+    }
+
+    /// Shares credited since the current job was built, so missing from its payouts
+    pub fn shares_since_job(&self) -> u64 {
+        self.shares_since_job
+    }
+
+    /// Turn a template into the current job. The oldest job stops accepting
+    /// shares once more than `RECENT_JOBS` are open.
+    pub fn new_job(&mut self, template: Block) -> Result<Job, ChainError> {
+        let Some(Transaction::Coinbase(coinbase)) = template.transactions.get()?.first() else {
+            return Err(ChainError::InvalidShare("Template has no coinbase".to_string()));
+        };
+        // A share must never be harder to find than the block itself
+        let share_bits = if difficulty::compact_to_target(self.share_bits) < difficulty::compact_to_target(template.header.bits) {
+            template.header.bits
+        } else {
+            self.share_bits
+        };
+
+        self.next_job_id += 1;
+        let job = Job {
+            job_id: self.next_job_id,
+            header: template.header.clone(),
+            coinbase: coinbase.clone(),
+            merkle_branch: Block::merkle_branch(template.transactions.get()?, 0),
+            share_bits,
+        };
+        self.jobs.push_back(OpenJob { job: job.clone(), template, seen: HashSet::new() });
+        self.shares_since_job = 0;
+        if self.jobs.len() > RECENT_JOBS {
+            self.jobs.pop_front();
+        }
+        Ok(job)
+    }
+
+    pub fn current_job(&self) -> Option<&Job> {
+        self.jobs.back().map(|open| &open.job)
+    }
+
+    /// Register a worker, giving it the next unused range of extra nonces.
+    /// Its accepted shares are credited to `address`, if any.
+    pub fn subscribe(&mut self, name: String, address: Option<Address>) -> Response {
+        let worker_id = self.next_worker_id;
+        self.next_worker_id += 1;
+        let start = worker_id.wrapping_mul(EXTRA_NONCE_RANGE);
+        let extra_nonces = start..start + EXTRA_NONCE_RANGE;
+        self.workers.insert(worker_id, Worker {
+            name,
+            address: address.filter(|address| !address.is_empty()),
+            extra_nonces: extra_nonces.clone(),
+            subscribed_at: Instant::now(),
+            accepted: 0,
+            rejected: 0,
+            blocks: 0,
+            work: 0.0,
+        });
+        Response::Subscribed { worker_id, extra_nonces }
+    }
+
+    pub fn unsubscribe(&mut self, worker_id: u64) {
+        self.workers.remove(&worker_id);
+    }
+
+    /// Check a worker's share, crediting it to the worker's address if
+    /// accepted. Returns the finished block if the share also meets the block
+    /// target.
+    pub fn submit(&mut self, worker_id: u64, share: &Share) -> Result<Option<Block>, ChainError> {
+        let result = self.check_share(worker_id, share);
+        let share_bits = self.jobs.iter()
+            .find(|open| open.job.job_id == share.job_id)
+            .map_or(self.share_bits, |open| open.job.share_bits);
+        let mut credited = None;
+        if let Some(worker) = self.workers.get_mut(&worker_id) {
+            match &result {
+                Ok(block) => {
+                    let work = difficulty::work(share_bits);
+                    worker.accepted += 1;
+                    worker.blocks += block.is_some() as u64;
+                    worker.work += work;
+                    credited = worker.address.clone().map(|address| PoolShare { address, work });
+                }
+                Err(_) => worker.rejected += 1,
+            }
+        }
+        if let Some(share) = credited {
+            self.credit(share);
+        }
+        result
+    }
+
+    fn check_share(&mut self, worker_id: u64, share: &Share) -> Result<Option<Block>, ChainError> {
+        let worker = self.workers.get(&worker_id)
+            .ok_or_else(|| ChainError::InvalidShare("Not subscribed".to_string()))?;
+        if !worker.extra_nonces.contains(&share.extra_nonce) {
+            return Err(ChainError::InvalidShare(format!(
+                "Extra nonce {} is outside the worker's range", share.extra_nonce
+            )));
+        }
+        let open = self.jobs.iter_mut().find(|open| open.job.job_id == share.job_id)
+            .ok_or_else(|| ChainError::InvalidShare(format!("Job {} is stale", share.job_id)))?;
+        if share.timestamp < open.job.header.timestamp
+            || share.timestamp > Utc::now().timestamp() + MAX_FUTURE_TIMESTAMP_DRIFT
+        {
+            return Err(ChainError::InvalidShare(format!("Timestamp {} is out of range", share.timestamp)));
+        }
+        if open.seen.contains(share) {
+            return Err(ChainError::InvalidShare("Duplicate share".to_string()));
+        }
+
+        let header = open.job.header_for(share);
+        let hash = header.calculate_hash();
+        if !difficulty::hash_meets_target(&hash, open.job.share_bits) {
+            return Err(ChainError::InvalidShare("Share is above the share target".to_string()));
+        }
+        open.seen.insert(*share);
+
+        if !difficulty::hash_meets_target(&hash, header.bits) {
+            return Ok(None);
+        }
+        let mut block = open.template.clone();
+        if let Some(Transaction::Coinbase(coinbase)) = block.transactions.get_mut()?.first_mut() {
+            coinbase.extra_nonce = share.extra_nonce;
+        }
+        block.header = header;
+        block.hash = hash;
+        Ok(Some(block))
+    }
+
+    /// Per-worker figures, by worker id
+    pub fn worker_stats(&self) -> Vec<WorkerStats> {
+        let mut stats: Vec<WorkerStats> = self.workers.iter().map(|(worker_id, worker)| {
+            let elapsed = worker.subscribed_at.elapsed().as_secs_f64();
+            WorkerStats {
+                worker_id: *worker_id,
+                name: worker.name.clone(),
+                address: worker.address.clone(),
+                accepted: worker.accepted,
+                rejected: worker.rejected,
+                blocks: worker.blocks,
+                hashrate: if elapsed > 0.0 { worker.work / elapsed } else { 0.0 },
+            }
+        }).collect();
+        stats.sort_by_key(|stats| stats.worker_id);
+        stats
+    }
+}
+
+/// Serves a `Pool` over TCP, building jobs from a shared chain and connecting
+/// the blocks workers find
+pub struct StratumServer {
+    chain: Arc<Mutex<Blockchain>>,
+    db: Option<Arc<Mutex<Database>>>,
+    beneficiary: Address,
+    pool: Arc<Mutex<Pool>>,
+    jobs: broadcast::Sender<Job>,
+}
+
+impl StratumServer {
+    pub fn new(chain: Arc<Mutex<Blockchain>>, beneficiary: Address, share_bits: u32) -> Self {
+        let (jobs, _) = broadcast::channel(16);
+        StratumServer {
+            chain,
+            db: None,
+            beneficiary,
+            pool: Arc::new(Mutex::new(Pool::new(share_bits))),
+            jobs,
+        }
+    }
+
+    /// Save the blocks workers find, and the state after them, and keep the
+    /// share window across restarts
+    pub fn with_database(mut self, db: Arc<Mutex<Database>>) -> Result<Self, ChainError> {
+        let shares = db.lock().unwrap().load_pool_shares(PPLNS_WINDOW)?;
+        self.pool.lock().unwrap().restore_shares(shares);
+        self.db = Some(db);
+        Ok(self)
+    }
+
+    pub fn pool(&self) -> Arc<Mutex<Pool>> {
+        self.pool.clone()
+    }
+
+    /// Build a job on the current tip, paying out the share window, and send
+    /// it to every worker. With no credited shares the beneficiary takes the
+    /// whole reward.
+    pub fn refresh_job(&self) -> Result<Job, ChainError> {
+        let payouts = self.pool.lock().unwrap().payouts();
+        let template = {
+            let chain = self.chain.lock().unwrap();
+            if payouts.is_empty() {
+                BlockTemplate::build(&chain, &self.beneficiary).block
+            } else {
+                BlockTemplate::build_split(&chain, &payouts).block
+            }
+        };
+        let job = self.pool.lock().unwrap().new_job(template)?;
+        let _ = self.jobs.send(job.clone());
+        Ok(job)
+    }
+
+    /// Accept workers on `port`, refreshing the job whenever the chain moves
+    pub async fn run(self: Arc<Self>, port: u16) -> Result<(), ChainError> {
+        let addr = format!("0.0.0.0:{}", port);
+        let listener = TcpListener::bind(&addr).await
+            .map_err(|e| ChainError::NetworkError(format!("Failed to bind: {}", e)))?;
+        println!("⛏️  Stratum server listening on {}", addr);
+
+        let mut watcher = TemplateWatcher::new(self.chain.lock().unwrap().subscribe());
+        self.refresh_job()?;
+        watcher.template_built();
+        let server = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(JOB_REFRESH_INTERVAL);
+            let mut built_at = Instant::now();
+            loop {
+                interval.tick().await;
+                let payouts_outdated = built_at.elapsed() >= PAYOUT_REFRESH_INTERVAL
+                    && server.pool.lock().unwrap().shares_since_job() > 0;
+                if watcher.is_stale() || payouts_outdated {
+                    if let Err(e) = server.refresh_job() {
+                        eprintln!("❌ Failed to refresh stratum job: {}", e);
+                    }
+                    watcher.template_built();
+                    built_at = Instant::now();
+                }
+            }
+        });
+
+        loop {
+            match listener.accept().await {
+                Ok((socket, peer_addr)) => {
+                    println!("👷 Worker connected from {}", peer_addr);
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = server.handle_worker(socket).await {
+                            eprintln!("❌ Worker error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    eprintln!("❌ Accept error: {}", e);
+                }
+            }
+        }
+    }
+
+    async fn handle_worker(&self, socket: TcpStream) -> Result<(), ChainError> {
+        let mut worker_id = None;
+        let result = self.serve_worker(socket, &mut worker_id).await;
+        if let Some(worker_id) = worker_id {
+            self.pool.lock().unwrap().unsubscribe(worker_id);
+        }
+        result
+    }
+
+    async fn serve_worker(&self, socket: TcpStream, worker_id: &mut Option<u64>) -> Result<(), ChainError> {
+        let (reader, mut writer) = socket.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        let mut jobs = self.jobs.subscribe();
+
+        loop {
+            tokio::select! {
+                more = read_request_line(&mut reader, &mut line) => {
+                    if !more? {
+                        return Ok(());
+                    }
+                    let response = match serde_json::from_slice::<Request>(&std::mem::take(&mut line)) {
+                        Ok(Request::Subscribe { worker, address }) => {
+                            let (subscribed, job) = self.subscribe(worker_id, worker, address);
+                            send(&mut writer, &subscribed).await?;
+                            match job {
+                                Some(job) => Response::Job(job),
+                                None => continue,
+                            }
+                        }
+                        Ok(Request::Submit(share)) => self.submit(*worker_id, &share).await,
+                        Err(e) => Response::ShareRejected { reason: format!("Malformed request: {}", e) },
+                    };
+                    send(&mut writer, &response).await?;
+                }
+                job = jobs.recv(), if worker_id.is_some() => {
+                    match job {
+                        Ok(job) => send(&mut writer, &Response::Job(job)).await?,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Register the connection's worker, replacing the one it registered
+    /// before, if any. Returns the reply and the job to start on.
+    fn subscribe(&self, worker_id: &mut Option<u64>, name: String, address: Option<Address>) -> (Response, Option<Job>) {
+        let mut pool = self.pool.lock().unwrap();
+        if let Some(earlier) = worker_id.take() {
+            pool.unsubscribe(earlier);
+        }
+        let subscribed = pool.subscribe(name, address);
+        if let Response::Subscribed { worker_id: id, .. } = subscribed {
+            *worker_id = Some(id);
+        }
+        (subscribed, pool.current_job().cloned())
+    }
+
+    async fn submit(&self, worker_id: Option<u64>, share: &Share) -> Response {
+        let outcome = match worker_id {
+            Some(worker_id) => self.submit_to_pool(worker_id, share),
+            None => Err(ChainError::InvalidShare("Not subscribed".to_string())),
+        };
+        match outcome {
+            Ok(None) => Response::ShareAccepted { block: None },
+            Ok(Some(block)) => match self.connect_block(block).await {
+                Ok(hash) => Response::ShareAccepted { block: Some(hex::encode(hash)) },
+                Err(e) => Response::ShareRejected { reason: e.to_string() },
+            },
+            Err(e) => Response::ShareRejected { reason: e.to_string() },
+        }
+    }
+
+    /// Submit to the pool, saving the share if it was credited
+    fn submit_to_pool(&self, worker_id: u64, share: &Share) -> Result<Option<Block>, ChainError> {
+        let mut pool = self.pool.lock().unwrap();
+        let credited_before = pool.shares_since_job();
+        let outcome = pool.submit(worker_id, share);
+        if let Some(db) = &self.db {
+            if pool.shares_since_job() > credited_before {
+                if let Some(credited) = pool.last_share() {
+                    db.lock().unwrap().save_pool_share(credited, PPLNS_WINDOW)?;
+                }
+            }
+        }
+        outcome
+    }
+
+    /// Connect a block a worker found and save it, on a blocking thread
+    async fn connect_block(&self, block: Block) -> Result<Sha256Hash, ChainError> {
+        let (hash, height) = (block.hash, block.header.height);
+        let (chain, db) = (self.chain.clone(), self.db.clone());
+        tokio::task::spawn_blocking(move || connect_and_save(&chain, db.as_deref(), &block)).await
+            .map_err(|e| ChainError::NetworkError(format!("Connecting the block stopped: {}", e)))??;
+        println!("✅ Worker found block {} at height {}", hex::encode(hash), height);
+        self.refresh_job()?;
+        Ok(hash)
+    }
+}
+
+/// Apply `block` to the chain and save it. The chain stays locked only while
+/// the block is applied, unless that pruned it; the database is taken before
+/// the chain is let go, so blocks are saved in the order they connect.
+fn connect_and_save(chain: &Mutex<Blockchain>, db: Option<&Mutex<Database>>, block: &Block) -> Result<(), ChainError> {
+    let mut locked = chain.lock().unwrap();
+    let pruned_height = locked.pruned_height;
+    locked.apply_block(block.clone())?;
+    let Some(db) = db else {
+        return Ok(());
+    };
+    let db = db.lock().unwrap();
+    let bits = locked.bits;
+    if locked.pruned_height != pruned_height {
+        db.save_blockchain_state(block, &mut locked.state, bits)?;
+        return db.save_prune_state(&locked);
+    }
+
+    let mut unsaved = locked.state.take_unsaved();
+    drop(locked);
+    let saved = db.save_blockchain_state(block, &mut unsaved, bits);
+    drop(db);
+    if saved.is_err() {
+        chain.lock().unwrap().state.mark_utxo_unsaved();
+    }
+    saved
+}
+
+/// Read the next line from a worker into `line`, newline included, failing
+/// once it runs past `MAX_REQUEST_LINE`. False at the end of the stream.
+/// What was read stays in `line`, so a read cut short by `select!` resumes.
+async fn read_request_line(reader: &mut BufReader<OwnedReadHalf>, line: &mut Vec<u8>) -> Result<bool, ChainError> {
+    loop {
+        let room = (MAX_REQUEST_LINE + 1).saturating_sub(line.len()) as u64;
+        let read = (&mut *reader).take(room).read_until(b'\n', line).await
+            .map_err(|e| ChainError::NetworkError(format!("Read failed: {}", e)))?;
+        if line.len() > MAX_REQUEST_LINE {
+            return Err(ChainError::NetworkError(format!("Request longer than {} bytes", MAX_REQUEST_LINE)));
+        }
+        if read == 0 || line.ends_with(b"\n") {
+            return Ok(!line.is_empty());
+        }
+    }
+}
+
+async fn send(writer: &mut OwnedWriteHalf, response: &Response) -> Result<(), ChainError> {
+    let mut line = serde_json::to_string(response)
+        .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+        .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))
+}
+
+/// Connect to a pool as `worker` and mine its jobs until `stop` is set,
+/// calling `on_response` with each reply to a share. Shares are credited to
+/// `address`, if given.
+pub async fn run_worker(
+    addr: &str,
+    worker: &str,
+    address: Option<&str>,
+    stop: Arc<AtomicBool>,
+    mut on_response: impl FnMut(&Response),
+) -> Result<(), ChainError> {
+    let stream = TcpStream::connect(addr).await
+        .map_err(|e| ChainError::NetworkError(format!("Failed to connect: {}", e)))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let subscribe = serde_json::to_string(&Request::Subscribe {
+        worker: worker.to_string(),
+        address: address.map(str::to_string),
+    })
+        .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
+    writer.write_all(format!("{}\n", subscribe).as_bytes()).await
+        .map_err(|e| ChainError::NetworkError(format!("Write failed: {}", e)))?;
+
+    let (shares_tx, mut shares) = mpsc::unbounded_channel::<Share>();
+    let mut extra_nonces = 0..0;
+    // Set to abandon the job being searched when a new one arrives
+    let mut current: Option<Arc<AtomicBool>> = None;
+
+    let result = loop {
+        if stop.load(Ordering::Relaxed) {
+            break Ok(());
+        }
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break Err(ChainError::NetworkError("Pool closed the connection".to_string())),
+                    Err(e) => break Err(ChainError::NetworkError(format!("Read failed: {}", e))),
+                };
+                let response: Response = serde_json::from_str(&line)
+                    .map_err(|e| ChainError::NetworkError(format!("Malformed response: {}", e)))?;
+                match response {
+                    Response::Subscribed { extra_nonces: range, .. } => extra_nonces = range,
+                    Response::Job(job) => {
+                        if let Some(abandon) = current.take() {
+                            abandon.store(true, Ordering::Relaxed);
+                        }
+                        let abandon = Arc::new(AtomicBool::new(false));
+                        current = Some(abandon.clone());
+                        let (shares_tx, extra_nonces) = (shares_tx.clone(), extra_nonces.clone());
+                        tokio::task::spawn_blocking(move || {
+                            for extra_nonce in extra_nonces {
+                                let mut nonces = 0..u64::MAX;
+                                while let Some(share) = job.find_share(extra_nonce, nonces.clone(), &abandon) {
+                                    if shares_tx.send(share).is_err() {
+                                        return;
+                                    }
+                                    nonces.start = share.nonce + 1;
+                                }
+                                if abandon.load(Ordering::Relaxed) {
+                                    return;
+                                }
+                            }
+                        });
+                    }
+                    other => on_response(&other),
+                }
+            }
+            Some(share) = shares.recv() => {
+                let submit = serde_json::to_string(&Request::Submit(share))
+                    .map_err(|e| ChainError::NetworkError(format!("Serialization failed: {}", e)))?;
+                if let Err(e) = writer.write_all(format!("{}\n", submit).as_bytes()).await {
+                    break Err(ChainError::NetworkError(format!("Write failed: {}", e)));
+                }
+            }
+            _ = tokio::time::sleep(JOB_REFRESH_INTERVAL) => {}
+        }
+    };
+
+    if let Some(abandon) = current {
+        abandon.store(true, Ordering::Relaxed);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A share target every hash meets
+    const ANY_HASH_BITS: u32 = 0x2100ffff;
+
+    fn pool_with_job(chain: &Blockchain, share_bits: u32) -> (Pool, Job, u64, Range<u64>) {
+        let mut pool = Pool::new(share_bits);
+        let job = pool.new_job(BlockTemplate::build(chain, "pool").block).unwrap();
+        let Response::Subscribed { worker_id, extra_nonces } = pool.subscribe("rig".to_string(), None) else {
+            panic!("expected Subscribed");
+        };
+        (pool, job, worker_id, extra_nonces)
+    }
+
+    #[test]
+    fn test_job_rebuilds_the_template_header() {
+        let chain = Blockchain::regtest();
+        let template = BlockTemplate::build(&chain, "pool").block;
+        let job = Pool::new(DEFAULT_SHARE_BITS).new_job(template.clone()).unwrap();
+        assert_eq!(job.merkle_root(0), template.header.merkle_root);
+        assert_ne!(job.merkle_root(1), template.header.merkle_root);
+    }
+
+    #[test]
+    fn test_shares_and_blocks() {
+        // Every hash is a share, and about every other one a block
+        let mut chain = Blockchain::regtest();
+        let (mut pool, job, worker_id, extra_nonces) = pool_with_job(&chain, ANY_HASH_BITS);
+        let stop = AtomicBool::new(false);
+        let extra_nonce = extra_nonces.start;
+
+        // Keep submitting until a share completes a block, which then connects
+        let mut nonce = 0;
+        let block = loop {
+            let share = job.find_share(extra_nonce, nonce..u64::MAX, &stop).unwrap();
+            nonce = share.nonce + 1;
+            if let Some(block) = pool.submit(worker_id, &share).unwrap() {
+                assert!(matches!(pool.submit(worker_id, &share), Err(ChainError::InvalidShare(_))));
+                break block;
+            }
+        };
+        chain.apply_block(block).unwrap();
+
+        let stats = &pool.worker_stats()[0];
+        assert_eq!(stats.name, "rig");
+        assert_eq!(stats.rejected, 1);
+        assert!(stats.accepted >= 1);
+        assert_eq!(stats.blocks, 1);
+    }
+
+    #[test]
+    fn test_resubscribing_replaces_the_worker() {
+        let server = StratumServer::new(Arc::new(Mutex::new(Blockchain::regtest())), "pool".to_string(), DEFAULT_SHARE_BITS);
+        let mut worker_id = None;
+        server.subscribe(&mut worker_id, "rig".to_string(), None);
+        let first = worker_id.unwrap();
+        server.subscribe(&mut worker_id, "rig2".to_string(), None);
+
+        let stats = server.pool.lock().unwrap().worker_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].name, "rig2");
+        assert_ne!(worker_id, Some(first));
+    }
+
+    #[tokio::test]
+    async fn test_found_blocks_are_saved() {
+        let chain = Arc::new(Mutex::new(Blockchain::regtest()));
+        let db = Arc::new(Mutex::new(Database::open(":memory:").unwrap()));
+        let server = StratumServer::new(chain.clone(), "pool".to_string(), DEFAULT_SHARE_BITS)
+            .with_database(db.clone())
+            .unwrap();
+
+        for _ in 0..2 {
+            let template = BlockTemplate::build(&chain.lock().unwrap(), "pool").block;
+            let block = crate::miner::mine_block(template, &AtomicBool::new(false)).unwrap();
+            server.connect_block(block).await.unwrap();
+        }
+
+        let saved = db.lock().unwrap().load_blockchain().unwrap();
+        let chain = chain.lock().unwrap();
+        assert_eq!(saved.blocks.last().unwrap().hash, chain.blocks.last().unwrap().hash);
+        assert_eq!(saved.state.utxo_set.len(), chain.state.utxo_set.len());
+        assert_eq!(chain.state.utxo_changes(), &crate::blockchain::UtxoChanges::Touched(HashSet::new()));
+    }
+
+    #[tokio::test]
+    async fn test_request_lines_are_capped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(socket.into_split().0);
+
+        client.write_all(b"{}\n").await.unwrap();
+        client.write_all(&vec![b'x'; MAX_REQUEST_LINE + 1]).await.unwrap();
+        let mut line = Vec::new();
+        assert!(read_request_line(&mut reader, &mut line).await.unwrap());
+        assert_eq!(line, b"{}\n");
+        line.clear();
+        assert!(read_request_line(&mut reader, &mut line).await.is_err());
+    }
+
+    #[test]
+    fn test_rejected_shares() {
+        let chain = Blockchain::regtest();
+        let (mut pool, job, worker_id, extra_nonces) = pool_with_job(&chain, ANY_HASH_BITS);
+        let share = job.find_share(extra_nonces.start, 0..u64::MAX, &AtomicBool::new(false)).unwrap();
+
+        let outside = Share { extra_nonce: extra_nonces.end, ..share };
+        assert!(pool.submit(worker_id, &outside).is_err());
+        let early = Share { timestamp: job.header.timestamp - 1, ..share };
+        assert!(pool.submit(worker_id, &early).is_err());
+        assert!(pool.submit(worker_id + 1, &share).is_err());
+
+        // Shares for a job pushed out by newer ones are stale
+        for _ in 0..RECENT_JOBS {
+            pool.new_job(BlockTemplate::build(&chain, "pool").block).unwrap();
+        }
+        assert!(pool.submit(worker_id, &share).is_err());
+        assert_eq!(pool.worker_stats()[0].rejected, 3);
+
+        // Workers get disjoint extra nonce ranges
+        let Response::Subscribed { extra_nonces: second, .. } = pool.subscribe("rig2".to_string(), None) else {
+            panic!("expected Subscribed");
+        };
+        assert_eq!(second.start, extra_nonces.end);
+    }
+
+    #[test]
+    fn test_pplns_payouts() {
+        let chain = Blockchain::regtest();
+        let mut pool = Pool::new(ANY_HASH_BITS);
+        let job = pool.new_job(BlockTemplate::build(&chain, "pool").block).unwrap();
+        let stop = AtomicBool::new(false);
+
+        let mut subscribe = |name: &str, address: Option<&str>| {
+            let Response::Subscribed { worker_id, extra_nonces } = pool.subscribe(name.to_string(), address.map(str::to_string)) else {
+                panic!("expected Subscribed");
+            };
+            (worker_id, extra_nonces.start)
+        };
+        let alice = subscribe("alice-rig", Some("alice"));
+        let bob = subscribe("bob-rig", Some("bob"));
+        let anonymous = subscribe("anon", None);
+
+        // Alice submits three shares for each of Bob's; anonymous shares earn nothing
+        let mut submit = |(worker_id, extra_nonce): (u64, u64), count: usize| {
+            let mut nonce = 0;
+            for _ in 0..count {
+                let share = job.find_share(extra_nonce, nonce..u64::MAX, &stop).unwrap();
+                nonce = share.nonce + 1;
+                pool.submit(worker_id, &share).unwrap();
+            }
+        };
+        submit(alice, 3);
+        submit(bob, 1);
+        submit(anonymous, 2);
+
+        let work = difficulty::work(ANY_HASH_BITS);
+        assert_eq!(pool.payouts(), vec![("alice".to_string(), 3.0 * work), ("bob".to_string(), work)]);
+        assert_eq!(pool.shares_since_job(), 4);
+
+        // The next job splits the reward along the window
+        let template = BlockTemplate::build_split(&chain, &pool.payouts()).block;
+        let job = pool.new_job(template).unwrap();
+        assert_eq!(pool.shares_since_job(), 0);
+        let reward = Blockchain::calculate_block_reward(1);
+        assert_eq!(job.coinbase.reward_to("alice"), reward * 3 / 4);
+        assert_eq!(job.coinbase.reward_to("bob"), reward / 4);
+
+        // Only the last PPLNS_WINDOW shares count
+        pool.restore_shares((0..PPLNS_WINDOW).map(|_| PoolShare { address: "carol".to_string(), work: 1.0 }));
+        assert_eq!(pool.payouts(), vec![("carol".to_string(), PPLNS_WINDOW as f64)]);
+    }
+
+    #[test]
+    fn test_share_target_is_never_harder_than_the_block() {
+        let (_, job, _, _) = pool_with_job(&Blockchain::regtest(), difficulty::bits_from_leading_zeros(16));
+        assert_eq!(job.share_bits, job.header.bits);
+    }
+
+    #[test]
+    fn test_messages_are_json_lines() {
+        let request = Request::Subscribe { worker: "rig".to_string(), address: None };
+        let json = serde_json::to_string(&request).unwrap();
+        assert_eq!(json, r#"{"method":"subscribe","worker":"rig"}"#);
+        assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), request);
+        let with_address = r#"{"method":"subscribe","worker":"rig","address":"alice"}"#;
+        assert_eq!(
+            serde_json::from_str::<Request>(with_address).unwrap(),
+            Request::Subscribe { worker: "rig".to_string(), address: Some("alice".to_string()) },
+        );
+
+        let share = Share { job_id: 1, extra_nonce: 2, nonce: 3, timestamp: 4 };
+        let json = serde_json::to_string(&Request::Submit(share)).unwrap();
+        assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), Request::Submit(share));
+    }
+}
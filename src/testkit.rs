@@ -0,0 +1,286 @@
+//! Simulated networks of in-process nodes, for tests
+//!
+//! A `SimNetwork` runs `NetworkNode`s on copies of one chain, joined by
+//! in-memory links instead of sockets. Each link delays what it carries by
+//! its latency and can be cut to partition the network, and `mine` extends
+//! a node's chain under `TestPow`, so fork resolution, reorgs and relay can
+//! be exercised in `cargo test`. Under a paused clock
+//! (`#[tokio::test(start_paused = true)]`) latencies are exact and a run
+//! repeats the same way every time.
+
+use crate::blockassembler::BlockTemplate;
+use crate::blockchain::{Block, Blockchain};
+use crate::error::ChainError;
+use crate::network::{ChainTip, Direction, NetworkNode};
+use crate::noise::PeerStream;
+use crate::pow::TestPow;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Bytes a link buffers at each end
+const LINK_BUFFER: usize = 1 << 20;
+
+/// How often `wait_for_convergence` compares tips
+const CONVERGENCE_POLL: Duration = Duration::from_millis(10);
+
+/// How long `connect` waits for both ends' sessions to start
+const SESSION_START_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The tasks carrying a link's bytes, stopped when it is cut
+struct Link {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+pub struct SimNetwork {
+    nodes: Vec<NetworkNode>,
+    /// Latency of each link asked for, by (dialing node, dialed node),
+    /// whether it is up or cut
+    topology: HashMap<(usize, usize), Duration>,
+    links: HashMap<(usize, usize), Link>,
+}
+
+impl SimNetwork {
+    /// `count` unconnected nodes on a fresh chain that accepts any proof of work
+    pub fn new(count: usize) -> Self {
+        Self::with_chain(Blockchain::new().with_pow(Arc::new(TestPow)), count)
+    }
+
+    /// `count` unconnected nodes, each on its own copy of `chain`
+    pub fn with_chain(chain: Blockchain, count: usize) -> Self {
+        SimNetwork {
+            nodes: (0..count).map(|_| NetworkNode::new(chain.detached_clone(), String::new())).collect(),
+            topology: HashMap::new(),
+            links: HashMap::new(),
+        }
+    }
+
+    pub fn node(&self, index: usize) -> &NetworkNode {
+        &self.nodes[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The address node `index` appears to its peers at
+    pub fn addr(index: usize) -> String {
+        SocketAddr::from(([10, 0, (index >> 8) as u8, index as u8], 8333)).to_string()
+    }
+
+    /// Link node `from` to node `to` with `latency` each way. `from` dials:
+    /// it syncs from `to` first, as `connect_peer` would, then both gossip.
+    pub async fn connect(&mut self, from: usize, to: usize, latency: Duration) -> Result<(), ChainError> {
+        self.topology.insert((from, to), latency);
+        self.open_link(from, to, latency).await
+    }
+
+    /// Cut the link between two nodes, ending their sessions
+    pub fn disconnect(&mut self, a: usize, b: usize) {
+        self.links.remove(&(a, b));
+        self.links.remove(&(b, a));
+    }
+
+    /// Cut every link between the nodes in `side` and the rest
+    pub fn partition(&mut self, side: &[usize]) {
+        self.links.retain(|(a, b), _| side.contains(a) == side.contains(b));
+    }
+
+    /// Restore every cut link, each dialer syncing from its peer again
+    pub async fn heal(&mut self) -> Result<(), ChainError> {
+        let mut cut: Vec<((usize, usize), Duration)> = self.topology.iter()
+            .filter(|(link, _)| !self.links.contains_key(*link))
+            .map(|(&link, &latency)| (link, latency))
+            .collect();
+        cut.sort_by_key(|(link, _)| *link);
+        for ((from, to), latency) in cut {
+            self.open_link(from, to, latency).await?;
+        }
+        Ok(())
+    }
+
+    /// Mine a block on node `index`'s tip, which it then announces to its peers
+    pub async fn mine(&self, index: usize) -> Result<Block, ChainError> {
+        let blockchain = self.nodes[index].blockchain();
+        let mut chain = blockchain.write().await;
+        // Each node pays its own address, so rival blocks at a height differ
+        let block = BlockTemplate::build(&chain, &format!("sim-miner-{}", index)).block;
+        chain.apply_block(block.clone())?;
+        Ok(block)
+    }
+
+    /// The block node `index`'s chain ends at
+    pub async fn tip(&self, index: usize) -> ChainTip {
+        ChainTip::of(&*self.nodes[index].blockchain().read().await)
+    }
+
+    /// Whether every node's chain ends at the same block
+    pub async fn converged(&self) -> bool {
+        let first = self.tip(0).await;
+        for index in 1..self.nodes.len() {
+            if self.tip(index).await != first {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Wait up to `timeout` for every node to reach the same tip, returning it
+    pub async fn wait_for_convergence(&self, timeout: Duration) -> Option<ChainTip> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.converged().await {
+                return Some(self.tip(0).await);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            tokio::time::sleep(CONVERGENCE_POLL).await;
+        }
+    }
+
+    async fn open_link(&mut self, from: usize, to: usize, latency: Duration) -> Result<(), ChainError> {
+        let (from_end, from_wire) = tokio::io::duplex(LINK_BUFFER);
+        let (to_end, to_wire) = tokio::io::duplex(LINK_BUFFER);
+        let (from_read, from_write) = tokio::io::split(from_wire);
+        let (to_read, to_write) = tokio::io::split(to_wire);
+        let mut tasks = carry(from_read, to_write, latency);
+        tasks.extend(carry(to_read, from_write, latency));
+        // Registered first, so a failed handshake below still leaves the link to cut
+        self.links.insert((from, to), Link { tasks });
+
+        let (dialer, dialed) = (&self.nodes[from], &self.nodes[to]);
+        dialed.spawn_session(to_end, Self::addr(from), Direction::Inbound);
+        let mut stream = PeerStream::plain(from_end);
+        let version = dialer.handshake(&mut stream, &Self::addr(to)).await?;
+        dialer.sync(&mut stream).await?;
+        dialer.spawn_handshaken_session(stream, Self::addr(to), Direction::Outbound, version);
+
+        // Blocks are only announced to sessions already running, so don't
+        // return before both ends can hear of the next one
+        let deadline = Instant::now() + SESSION_START_TIMEOUT;
+        while !(dialer.session_addrs().contains(&Self::addr(to)) && dialed.session_addrs().contains(&Self::addr(from))) {
+            if Instant::now() >= deadline {
+                return Err(ChainError::NetworkError(format!("Sessions between nodes {} and {} did not start", from, to)));
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        Ok(())
+    }
+}
+
+/// Carry bytes from `from` to `to`, each read delivered `latency` after it
+/// arrived, in order
+fn carry<R, W>(mut from: R, mut to: W, latency: Duration) -> Vec<JoinHandle<()>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+    let reader = tokio::spawn(async move {
+        let mut buffer = vec![0u8; 8192];
+        while let Ok(n @ 1..) = from.read(&mut buffer).await {
+            if sender.send((Instant::now() + latency, buffer[..n].to_vec())).is_err() {
+                break;
+            }
+        }
+    });
+    let writer = tokio::spawn(async move {
+        while let Some((due, bytes)) = receiver.recv().await {
+            tokio::time::sleep_until(due).await;
+            if to.write_all(&bytes).await.is_err() {
+                return;
+            }
+        }
+        let _ = to.shutdown().await;
+    });
+    vec![reader, writer]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LATENCY: Duration = Duration::from_millis(50);
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test(start_paused = true)]
+    async fn test_blocks_relay_along_a_line_of_nodes() {
+        let mut network = SimNetwork::new(4);
+        for index in 0..3 {
+            network.connect(index, index + 1, LATENCY).await.unwrap();
+        }
+
+        let sent = Instant::now();
+        network.mine(0).await.unwrap();
+        let block = network.mine(0).await.unwrap();
+        let tip = network.wait_for_convergence(TIMEOUT).await.unwrap();
+        assert_eq!((tip.hash, tip.height), (block.hash, 2));
+        // An announcement, a request and the block cross each of three links
+        assert!(sent.elapsed() >= 3 * 3 * LATENCY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partitioned_nodes_reorg_to_the_longer_side_once_healed() {
+        let mut network = SimNetwork::new(3);
+        network.connect(0, 1, LATENCY).await.unwrap();
+        network.connect(1, 2, LATENCY).await.unwrap();
+
+        // Node 0 alone mines one block while the other side mines three
+        network.partition(&[0]);
+        let lone = network.mine(0).await.unwrap();
+        for _ in 0..3 {
+            network.mine(2).await.unwrap();
+        }
+        assert!(network.wait_for_convergence(Duration::from_millis(500)).await.is_none());
+        assert_eq!(network.tip(0).await.hash, lone.hash);
+        assert_eq!(network.tip(1).await, network.tip(2).await);
+
+        network.heal().await.unwrap();
+        let tip = network.wait_for_convergence(TIMEOUT).await.unwrap();
+        assert_eq!((tip, tip.height), (network.tip(2).await, 3));
+        assert!(!network.node(0).blockchain().read().await.block_index.contains_key(&lone.hash));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tied_branches_hold_until_one_grows() {
+        let mut network = SimNetwork::new(2);
+        network.connect(0, 1, LATENCY).await.unwrap();
+        network.mine(0).await.unwrap();
+        network.wait_for_convergence(TIMEOUT).await.unwrap();
+
+        // Both sides build on the common block at once
+        network.partition(&[0]);
+        let ours = network.mine(0).await.unwrap();
+        let theirs = network.mine(1).await.unwrap();
+        network.heal().await.unwrap();
+
+        // Each keeps the branch it saw first, until the other grows past it
+        assert!(network.wait_for_convergence(Duration::from_millis(500)).await.is_none());
+        assert_eq!(network.tip(0).await.hash, ours.hash);
+        let next = network.mine(1).await.unwrap();
+        let tip = network.wait_for_convergence(TIMEOUT).await.unwrap();
+        assert_eq!((tip.hash, tip.height), (next.hash, 3));
+        let chain = network.node(0).blockchain();
+        let chain = chain.read().await;
+        assert_eq!(chain.blocks[2].hash, theirs.hash);
+        assert!(chain.forks.contains_key(&ours.hash));
+    }
+}
@@ -1,70 +1,444 @@
 //! Transaction types for siertrichain
 
 use sha2::{Digest, Sha256};
-use crate::blockchain::{Sha256Hash, TriangleState};
-use crate::geometry::Triangle;
+use crate::blockchain::{BlockHeight, Sha256Hash, TriangleState};
+use crate::geometry::{Coord, Point, Triangle, TriangleId};
 use crate::error::ChainError;
+use crate::crypto::SignatureCache;
+use crate::codec;
 
 pub type Address = String;
 
+/// Version of a transaction's format and hashing rules. Decoding rejects
+/// versions this node doesn't know, so future format changes can be rolled
+/// out without old nodes misreading new transactions. Version 1 already signs
+/// and hashes every field but the signature, optional ones like a transfer's
+/// memo and lock time included; a new optional field needs a new version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
+pub struct TxVersion(pub u32);
+
+impl TxVersion {
+    /// The version new transactions are created with
+    pub const CURRENT: TxVersion = TxVersion(1);
+
+    pub fn is_supported(self) -> bool {
+        (1..=Self::CURRENT.0).contains(&self.0)
+    }
+
+    pub fn check(self) -> Result<(), ChainError> {
+        if self.is_supported() {
+            Ok(())
+        } else {
+            Err(ChainError::UnsupportedTxVersion(self.0))
+        }
+    }
+}
+
+/// Transactions written before versioning are version 1
+impl Default for TxVersion {
+    fn default() -> Self {
+        TxVersion(1)
+    }
+}
+
+/// Prefix of the decode error for an unknown version, which `codec::decode`
+/// turns back into `ChainError::UnsupportedTxVersion`
+pub(crate) const UNSUPPORTED_VERSION_ERROR: &str = "unsupported transaction version ";
+
+impl<'de> serde::Deserialize<'de> for TxVersion {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = TxVersion(<u32 as serde::Deserialize>::deserialize(deserializer)?);
+        if !version.is_supported() {
+            return Err(serde::de::Error::custom(format!("{}{}", UNSUPPORTED_VERSION_ERROR, version.0)));
+        }
+        Ok(version)
+    }
+}
+
 /// A transaction that can occur in a block
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum Transaction {
     Transfer(TransferTx),
     Subdivision(SubdivisionTx),
     Coinbase(CoinbaseTx),
+    BatchTransfer(BatchTransferTx),
+    HtlcLock(HtlcLockTx),
+    HtlcRedeem(HtlcRedeemTx),
+    EscrowLock(EscrowLockTx),
+    EscrowSettle(EscrowSettleTx),
+    Offer(OfferTx),
+    Accept(AcceptTx),
+    Inscription(InscriptionTx),
+    DeepSubdivision(DeepSubdivisionTx),
+    SplitTransfer(SplitTransferTx),
+    Swap(SwapTx),
+    Lease(LeaseTx),
+    Approve(ApproveTx),
+}
+
+/// How an address takes part in a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressRole {
+    /// Gives up or acts on a triangle it holds
+    Sender,
+    /// Receives a triangle, a reward or a claim on one
+    Recipient,
+    /// Takes part without either: an arbiter, delegate or settlement signer
+    Party,
+}
+
+impl AddressRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AddressRole::Sender => "sender",
+            AddressRole::Recipient => "recipient",
+            AddressRole::Party => "party",
+        }
+    }
+}
+
+impl std::str::FromStr for AddressRole {
+    type Err = ChainError;
+
+    fn from_str(s: &str) -> Result<Self, ChainError> {
+        match s {
+            "sender" => Ok(AddressRole::Sender),
+            "recipient" => Ok(AddressRole::Recipient),
+            "party" => Ok(AddressRole::Party),
+            _ => Err(ChainError::CodecError(format!("Unknown address role {}", s))),
+        }
+    }
+}
+
+/// Fee paid per byte of canonical encoding. Rates are compared exactly, by
+/// cross-multiplying, rather than through a rounded division.
+#[derive(Debug, Clone, Copy)]
+pub struct FeeRate {
+    pub fee: u64,
+    pub size: usize,
+}
+
+impl FeeRate {
+    pub fn new(fee: u64, size: usize) -> Self {
+        FeeRate { fee, size: size.max(1) }
+    }
+
+    /// Approximate fee per byte, for display
+    pub fn per_byte(&self) -> f64 {
+        self.fee as f64 / self.size as f64
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.fee as u128 * other.size as u128).cmp(&(other.fee as u128 * self.size as u128))
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
 }
 
+impl Eq for FeeRate {}
+
 impl Transaction {
     pub fn hash_str(&self) -> String {
         hex::encode(self.hash())
     }
 
+    /// Size of this transaction's canonical encoding in bytes
+    pub fn size(&self) -> usize {
+        codec::encoded_len(self)
+    }
+
+    /// Fee per serialized byte, the metric the mempool and block assembler rank by
+    pub fn fee_rate(&self) -> FeeRate {
+        FeeRate::new(self.fee(), self.size())
+    }
+
     /// Get the fee for this transaction
     pub fn fee(&self) -> u64 {
         match self {
             Transaction::Subdivision(tx) => tx.fee,
             Transaction::Transfer(tx) => tx.fee,
+            Transaction::BatchTransfer(tx) => tx.fee,
+            Transaction::HtlcLock(tx) => tx.fee,
+            Transaction::HtlcRedeem(tx) => tx.fee,
+            Transaction::EscrowLock(tx) => tx.fee,
+            Transaction::EscrowSettle(tx) => tx.fee,
+            Transaction::Offer(tx) => tx.fee,
+            Transaction::Accept(tx) => tx.fee,
+            Transaction::Inscription(tx) => tx.fee,
+            Transaction::DeepSubdivision(tx) => tx.fee,
+            Transaction::SplitTransfer(tx) => tx.fee,
+            Transaction::Swap(tx) => tx.fee,
+            Transaction::Lease(tx) => tx.fee,
+            Transaction::Approve(tx) => tx.fee,
             Transaction::Coinbase(_) => 0, // Coinbase has no fee
         }
     }
 
     /// Calculate the hash of this transaction
     pub fn hash(&self) -> [u8; 32] {
-        let mut hasher = Sha256::new();
         match self {
-            Transaction::Subdivision(tx) => {
-                hasher.update(tx.parent_hash);
-                for child in &tx.children {
-                    hasher.update(child.hash());
-                }
-                hasher.update(tx.owner_address.as_bytes());
-                hasher.update(tx.fee.to_le_bytes());
-                hasher.update(tx.nonce.to_le_bytes());
-            }
-            Transaction::Coinbase(tx) => {
-                hasher.update("coinbase".as_bytes());
-                hasher.update(tx.reward_area.to_le_bytes());
-                hasher.update(tx.beneficiary_address.as_bytes());
-            }
-            Transaction::Transfer(tx) => {
-                hasher.update("transfer".as_bytes());
-                hasher.update(tx.input_hash);
-                hasher.update(tx.new_owner.as_bytes());
-                hasher.update(tx.sender.as_bytes());
-                hasher.update(tx.fee.to_le_bytes());
-                hasher.update(tx.nonce.to_le_bytes());
-            }
+            Transaction::Subdivision(tx) => tx.hash(),
+            Transaction::Coinbase(tx) => tx.hash(),
+            Transaction::Transfer(tx) => tx.hash(),
+            Transaction::BatchTransfer(tx) => tx.hash(),
+            Transaction::HtlcLock(tx) => tx.hash(),
+            Transaction::HtlcRedeem(tx) => tx.hash(),
+            Transaction::EscrowLock(tx) => tx.hash(),
+            Transaction::EscrowSettle(tx) => tx.hash(),
+            Transaction::Offer(tx) => tx.hash(),
+            Transaction::Accept(tx) => tx.hash(),
+            Transaction::Inscription(tx) => tx.hash(),
+            Transaction::DeepSubdivision(tx) => tx.hash(),
+            Transaction::SplitTransfer(tx) => tx.hash(),
+            Transaction::Swap(tx) => tx.hash(),
+            Transaction::Lease(tx) => tx.hash(),
+            Transaction::Approve(tx) => tx.hash(),
+        }
+    }
+
+    /// The format version this transaction was created with
+    pub fn version(&self) -> TxVersion {
+        match self {
+            Transaction::Subdivision(tx) => tx.version,
+            Transaction::Coinbase(tx) => tx.version,
+            Transaction::Transfer(tx) => tx.version,
+            Transaction::BatchTransfer(tx) => tx.version,
+            Transaction::HtlcLock(tx) => tx.version,
+            Transaction::HtlcRedeem(tx) => tx.version,
+            Transaction::EscrowLock(tx) => tx.version,
+            Transaction::EscrowSettle(tx) => tx.version,
+            Transaction::Offer(tx) => tx.version,
+            Transaction::Accept(tx) => tx.version,
+            Transaction::Inscription(tx) => tx.version,
+            Transaction::DeepSubdivision(tx) => tx.version,
+            Transaction::SplitTransfer(tx) => tx.version,
+            Transaction::Swap(tx) => tx.version,
+            Transaction::Lease(tx) => tx.version,
+            Transaction::Approve(tx) => tx.version,
+        }
+    }
+
+    /// The triangles this transaction spends; empty for coinbase. Two pending
+    /// transactions sharing an input conflict: only one of them can ever confirm.
+    pub fn inputs(&self) -> Vec<TriangleId> {
+        match self {
+            Transaction::Subdivision(tx) => vec![tx.parent_hash],
+            Transaction::Transfer(tx) => vec![tx.input_hash],
+            Transaction::BatchTransfer(tx) => tx.transfers.iter().map(|t| t.input_hash).collect(),
+            Transaction::HtlcLock(tx) => vec![tx.input_hash],
+            Transaction::HtlcRedeem(tx) => vec![tx.input_hash],
+            Transaction::EscrowLock(tx) => vec![tx.input_hash],
+            Transaction::EscrowSettle(tx) => vec![tx.input_hash],
+            Transaction::Offer(tx) => vec![tx.input_hash],
+            Transaction::Accept(tx) => std::iter::once(tx.input_hash).chain(tx.payment.iter().copied()).collect(),
+            // Not spent, but inscribing conflicts with moving the triangle in the same block
+            Transaction::Inscription(tx) => vec![tx.input_hash],
+            Transaction::DeepSubdivision(tx) => vec![tx.parent_hash],
+            Transaction::SplitTransfer(tx) => vec![tx.parent_hash],
+            Transaction::Swap(tx) => tx.inputs.iter().map(|input| input.input_hash).collect(),
+            Transaction::Lease(tx) => vec![tx.input_hash],
+            // Not spent, but approving conflicts with moving the triangle in the same block
+            Transaction::Approve(tx) => vec![tx.input_hash],
+            Transaction::Coinbase(_) => Vec::new(),
+        }
+    }
+
+    /// The address that signed this transaction; coinbase and multi-party
+    /// escrow settlements and swaps have none
+    pub fn sender(&self) -> Option<&Address> {
+        match self {
+            Transaction::Subdivision(tx) => Some(&tx.owner_address),
+            Transaction::Transfer(tx) => Some(&tx.sender),
+            Transaction::BatchTransfer(tx) => Some(&tx.sender),
+            Transaction::HtlcLock(tx) => Some(&tx.sender),
+            Transaction::HtlcRedeem(tx) => Some(&tx.redeemer),
+            Transaction::EscrowLock(tx) => Some(&tx.seller),
+            Transaction::Offer(tx) => Some(&tx.seller),
+            Transaction::Accept(tx) => Some(&tx.buyer),
+            Transaction::Inscription(tx) => Some(&tx.owner),
+            Transaction::DeepSubdivision(tx) => Some(&tx.owner_address),
+            Transaction::SplitTransfer(tx) => Some(&tx.sender),
+            Transaction::Lease(tx) => Some(&tx.owner),
+            Transaction::Approve(tx) => Some(&tx.owner),
+            Transaction::EscrowSettle(_) | Transaction::Swap(_) | Transaction::Coinbase(_) => None,
+        }
+    }
+
+    /// Each input paired with the address that signs for it as its owner.
+    /// Contract redemptions, settlements and acceptances name none: their
+    /// contract says who may spend.
+    pub fn claimed_owners(&self) -> Vec<(&TriangleId, &Address)> {
+        match self {
+            Transaction::Transfer(tx) => vec![(&tx.input_hash, &tx.sender)],
+            Transaction::Subdivision(tx) => vec![(&tx.parent_hash, &tx.owner_address)],
+            Transaction::BatchTransfer(tx) => tx.transfers.iter().map(|t| (&t.input_hash, &tx.sender)).collect(),
+            Transaction::HtlcLock(tx) => vec![(&tx.input_hash, &tx.sender)],
+            Transaction::EscrowLock(tx) => vec![(&tx.input_hash, &tx.seller)],
+            Transaction::Offer(tx) => vec![(&tx.input_hash, &tx.seller)],
+            Transaction::Inscription(tx) => vec![(&tx.input_hash, &tx.owner)],
+            Transaction::DeepSubdivision(tx) => vec![(&tx.parent_hash, &tx.owner_address)],
+            Transaction::SplitTransfer(tx) => vec![(&tx.parent_hash, &tx.sender)],
+            Transaction::Swap(tx) => tx.inputs.iter().map(|i| (&i.input_hash, &i.owner)).collect(),
+            Transaction::Lease(tx) => vec![(&tx.input_hash, &tx.owner)],
+            Transaction::Approve(tx) => vec![(&tx.input_hash, &tx.owner)],
+            Transaction::HtlcRedeem(_) | Transaction::EscrowSettle(_) | Transaction::Accept(_)
+                | Transaction::Coinbase(_) => Vec::new(),
+        }
+    }
+
+    /// Every address named in this transaction, with the part it plays.
+    /// An accepted offer's seller isn't named, only the offer.
+    pub fn addresses(&self) -> Vec<(&Address, AddressRole)> {
+        use AddressRole::*;
+        let mut addresses = match self {
+            Transaction::Transfer(tx) => vec![(&tx.sender, Sender), (&tx.new_owner, Recipient)],
+            Transaction::Subdivision(tx) => vec![(&tx.owner_address, Sender)],
+            Transaction::Coinbase(tx) => tx.outputs.iter().map(|o| (&o.beneficiary_address, Recipient)).collect(),
+            Transaction::BatchTransfer(tx) => std::iter::once((&tx.sender, Sender))
+                .chain(tx.transfers.iter().map(|t| (&t.new_owner, Recipient)))
+                .collect(),
+            Transaction::HtlcLock(tx) => vec![(&tx.sender, Sender), (&tx.recipient, Recipient)],
+            Transaction::HtlcRedeem(tx) => vec![(&tx.redeemer, Recipient)],
+            Transaction::EscrowLock(tx) => vec![(&tx.seller, Sender), (&tx.buyer, Recipient), (&tx.arbiter, Party)],
+            Transaction::EscrowSettle(tx) => tx.signatures.iter().map(|s| (&s.signer, Party)).collect(),
+            Transaction::Offer(tx) => vec![(&tx.seller, Sender)],
+            Transaction::Accept(tx) => vec![(&tx.buyer, Recipient)],
+            Transaction::Inscription(tx) => vec![(&tx.owner, Sender)],
+            Transaction::DeepSubdivision(tx) => vec![(&tx.owner_address, Sender)],
+            Transaction::SplitTransfer(tx) => vec![(&tx.sender, Sender), (&tx.recipient, Recipient)],
+            Transaction::Swap(tx) => tx.inputs.iter().map(|i| (&i.owner, Sender))
+                .chain(tx.outputs.iter().map(|o| (&o.new_owner, Recipient)))
+                .collect(),
+            Transaction::Lease(tx) => vec![(&tx.owner, Sender), (&tx.tenant, Recipient)],
+            Transaction::Approve(tx) => std::iter::once((&tx.owner, Sender))
+                .chain(tx.delegate.iter().map(|delegate| (delegate, Party)))
+                .collect(),
         };
-        hasher.finalize().into()
+        let mut seen = std::collections::HashSet::new();
+        addresses.retain(|&(address, role)| seen.insert((address, role)));
+        addresses
+    }
+
+    /// The sender's nonce; transactions without a sender have none
+    pub fn nonce(&self) -> Option<u64> {
+        match self {
+            Transaction::Subdivision(tx) => Some(tx.nonce),
+            Transaction::Transfer(tx) => Some(tx.nonce),
+            Transaction::BatchTransfer(tx) => Some(tx.nonce),
+            Transaction::HtlcLock(tx) => Some(tx.nonce),
+            Transaction::HtlcRedeem(tx) => Some(tx.nonce),
+            Transaction::EscrowLock(tx) => Some(tx.nonce),
+            Transaction::Offer(tx) => Some(tx.nonce),
+            Transaction::Accept(tx) => Some(tx.nonce),
+            Transaction::Inscription(tx) => Some(tx.nonce),
+            Transaction::DeepSubdivision(tx) => Some(tx.nonce),
+            Transaction::SplitTransfer(tx) => Some(tx.nonce),
+            Transaction::Lease(tx) => Some(tx.nonce),
+            Transaction::Approve(tx) => Some(tx.nonce),
+            Transaction::EscrowSettle(_) | Transaction::Swap(_) | Transaction::Coinbase(_) => None,
+        }
+    }
+
+    /// The lock delaying this transaction, if any
+    pub fn lock_time(&self) -> Option<LockTime> {
+        match self {
+            Transaction::Subdivision(tx) => tx.lock_time,
+            Transaction::Transfer(tx) => tx.lock_time,
+            Transaction::BatchTransfer(_)
+            | Transaction::HtlcLock(_)
+            | Transaction::HtlcRedeem(_)
+            | Transaction::EscrowLock(_)
+            | Transaction::EscrowSettle(_)
+            | Transaction::Offer(_)
+            | Transaction::Accept(_)
+            | Transaction::Inscription(_)
+            | Transaction::DeepSubdivision(_)
+            | Transaction::SplitTransfer(_)
+            | Transaction::Swap(_)
+            | Transaction::Lease(_)
+            | Transaction::Approve(_)
+            | Transaction::Coinbase(_) => None,
+        }
+    }
+
+    /// Whether this transaction may be included in a block at `height`, given
+    /// the median time past of the blocks before it
+    pub fn is_final(&self, height: BlockHeight, median_time_past: i64) -> bool {
+        self.lock_time().is_none_or(|lock| lock.is_final(height, median_time_past))
     }
 
     /// Validate this transaction against the current UTXO state
     pub fn validate(&self, state: &TriangleState) -> Result<(), ChainError> {
+        self.version().check()?;
         match self {
             Transaction::Subdivision(tx) => tx.validate(state),
             Transaction::Coinbase(tx) => tx.validate(),
             Transaction::Transfer(tx) => tx.validate(),
+            Transaction::BatchTransfer(tx) => tx.validate(),
+            Transaction::HtlcLock(tx) => tx.validate(),
+            Transaction::HtlcRedeem(tx) => tx.validate(),
+            Transaction::EscrowLock(tx) => tx.validate(),
+            Transaction::EscrowSettle(tx) => tx.validate(),
+            Transaction::Offer(tx) => tx.validate(),
+            Transaction::Accept(tx) => tx.validate(),
+            Transaction::Inscription(tx) => tx.validate(),
+            Transaction::Swap(tx) => tx.validate(),
+            Transaction::Lease(tx) => tx.validate(),
+            Transaction::Approve(tx) => tx.validate(),
+            Transaction::DeepSubdivision(tx) => {
+                tx.validate()?;
+                let parent = state.utxo_set.get(&tx.parent_hash)
+            .ok_or(ChainError::UtxoMissing { hash: tx.parent_hash })?;
+                tx.validate_against_parent(parent)
+            }
+            Transaction::SplitTransfer(tx) => {
+                tx.validate()?;
+                let parent = state.utxo_set.get(&tx.parent_hash)
+            .ok_or(ChainError::UtxoMissing { hash: tx.parent_hash })?;
+                tx.validate_against_parent(parent)
+            }
+        }
+    }
+}
+
+/// Earliest point at which a transaction may be included in a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum LockTime {
+    /// Valid in blocks at or above this height
+    Height(BlockHeight),
+    /// Valid once the median time past of the preceding blocks reaches this Unix timestamp
+    Timestamp(i64),
+}
+
+impl LockTime {
+    /// Timestamp locks compare against median time past rather than the block's own
+    /// timestamp, so a miner can't unlock a transaction early by lying about the time.
+    pub fn is_final(&self, height: BlockHeight, median_time_past: i64) -> bool {
+        match *self {
+            LockTime::Height(lock_height) => height >= lock_height,
+            LockTime::Timestamp(lock_time) => median_time_past >= lock_time,
+        }
+    }
+}
+
+impl std::fmt::Display for LockTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LockTime::Height(height) => write!(f, "height {}", height),
+            LockTime::Timestamp(time) => write!(f, "time {}", time),
         }
     }
 }
@@ -72,234 +446,1853 @@ impl Transaction {
 /// Subdivision transaction: splits one parent triangle into three children
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct SubdivisionTx {
+    #[serde(default)]
+    pub version: TxVersion,
     pub parent_hash: Sha256Hash,
     pub children: Vec<Triangle>,
     pub owner_address: Address,
     pub fee: u64,
-    pub nonce: u64,
-    pub signature: Option<Vec<u8>>,
-    pub public_key: Option<Vec<u8>>,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+    #[serde(default)]
+    pub lock_time: Option<LockTime>,
+}
+
+impl SubdivisionTx {
+    pub fn new(
+        parent_hash: Sha256Hash,
+        children: Vec<Triangle>,
+        owner_address: Address,
+        fee: u64,
+        nonce: u64,
+    ) -> Self {
+        SubdivisionTx {
+            version: TxVersion::CURRENT,
+            parent_hash,
+            children,
+            owner_address,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+            lock_time: None,
+        }
+    }
+
+    /// Delay the transaction until `lock_time`; must be set before signing
+    pub fn with_lock_time(mut self, lock_time: LockTime) -> Self {
+        self.lock_time = Some(lock_time);
+        self
+    }
+
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key
+    pub fn signable_message(&self) -> Vec<u8> {
+        let child_hashes: Vec<Sha256Hash> = self.children.iter().map(|c| c.hash()).collect();
+        codec::encode(&(
+            "subdivision",
+            self.version,
+            &self.parent_hash,
+            &child_hashes,
+            &self.owner_address,
+            self.fee,
+            self.nonce,
+            &self.lock_time,
+        ))
+    }
+
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+
+    /// Validates just the signature of the transaction, without access to blockchain state.
+    /// This is useful for early validation in the mempool.
+    pub fn validate_signature(&self) -> Result<(), ChainError> {
+        self.check_signature(None)
+    }
+
+    /// Like `validate_signature`, but skips verification for signatures already in the cache
+    pub fn validate_signature_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check_signature(Some(cache))
+    }
+
+    fn check_signature(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        check_signed_by(&self.owner_address, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+
+    /// Performs a full validation of the transaction against the current blockchain state.
+    pub fn validate(&self, state: &TriangleState) -> Result<(), ChainError> {
+        // First, perform a stateless signature check.
+        self.validate_signature()?;
+
+        // Then, validate against the current state (UTXO set).
+        self.validate_against_state(state)
+    }
+
+    /// Full validation using a shared signature cache
+    pub fn validate_cached(&self, state: &TriangleState, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.validate_signature_cached(cache)?;
+        self.validate_against_state(state)
+    }
+
+    fn validate_against_state(&self, state: &TriangleState) -> Result<(), ChainError> {
+        let parent = state.utxo_set.get(&self.parent_hash)
+            .ok_or(ChainError::UtxoMissing { hash: self.parent_hash })?;
+
+        self.validate_against_parent(parent)
+    }
+
+    /// Checks that the children are exactly the subdivision of `parent`.
+    /// Used when the parent is not in the UTXO set yet, e.g. it is created by an
+    /// earlier transaction in the same block or mempool package.
+    pub fn validate_against_parent(&self, parent: &Triangle) -> Result<(), ChainError> {
+        if !parent.can_subdivide(1) {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Subdivision would create triangles below the minimum area of {}",
+                crate::geometry::MIN_TRIANGLE_AREA
+            )));
+        }
+
+        let expected_children = parent.subdivide();
+
+        if self.children.len() != 3 {
+            return Err(ChainError::InvalidTransaction(
+                "Subdivision must produce exactly 3 children".to_string(),
+            ));
+        }
+
+        for (i, child) in self.children.iter().enumerate() {
+            let expected = &expected_children[i];
+            if !child.same_vertices(expected) {
+                return Err(ChainError::InvalidTransaction(format!(
+                    "Child {} geometry does not match expected subdivision",
+                    i
+                )));
+            }
+        }
+
+        for (i, child) in self.children.iter().enumerate() {
+            if !child.is_child_of(parent, i) {
+                return Err(ChainError::InvalidTransaction(format!(
+                    "Child {} parent link, depth or lineage does not match its parent",
+                    i
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Deep subdivision: splits one parent triangle `depth` times over in a single
+/// transaction, producing all 3^depth leaves at once. The leaves follow from the
+/// parent, so the transaction doesn't carry them.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DeepSubdivisionTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub parent_hash: Sha256Hash,
+    pub depth: u8,
+    pub owner_address: Address,
+    pub fee: u64,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl DeepSubdivisionTx {
+    /// Deepest subdivision allowed in one transaction (3^4 = 81 leaves)
+    pub const MAX_DEPTH: u8 = 4;
+
+    pub fn new(parent_hash: Sha256Hash, depth: u8, owner_address: Address, fee: u64, nonce: u64) -> Self {
+        DeepSubdivisionTx {
+            version: TxVersion::CURRENT,
+            parent_hash,
+            depth,
+            owner_address,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+        }
+    }
+
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "deep_subdivision",
+            self.version,
+            &self.parent_hash,
+            self.depth,
+            &self.owner_address,
+            self.fee,
+            self.nonce,
+        ))
+    }
+
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if self.depth == 0 || self.depth > Self::MAX_DEPTH {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Subdivision depth must be between 1 and {}",
+                Self::MAX_DEPTH
+            )));
+        }
+        check_signed_by(&self.owner_address, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+
+    /// Checks that the signer owns `parent` and that its leaves aren't too small
+    pub fn validate_against_parent(&self, parent: &Triangle) -> Result<(), ChainError> {
+        if parent.owner != self.owner_address {
+            return Err(ChainError::InvalidTransaction(format!(
+                "{} does not own triangle {}",
+                self.owner_address,
+                hex::encode(self.parent_hash)
+            )));
+        }
+        if !parent.can_subdivide(self.depth as u32) {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Subdividing to depth {} would create triangles below the minimum area of {}",
+                self.depth,
+                crate::geometry::MIN_TRIANGLE_AREA
+            )));
+        }
+        Ok(())
+    }
+
+    /// The leaves this transaction creates from `parent`, owned by the signer
+    pub fn leaves(&self, parent: &Triangle) -> Vec<Triangle> {
+        let mut leaves = subdivide_to_depth(parent, self.depth);
+        for leaf in &mut leaves {
+            leaf.owner = self.owner_address.clone();
+        }
+        leaves
+    }
+}
+
+/// All leaves of subdividing `parent` `depth` times, in depth-first order (each
+/// triangle's children in `Triangle::subdivide` order)
+fn subdivide_to_depth(parent: &Triangle, depth: u8) -> Vec<Triangle> {
+    let mut level = vec![parent.clone()];
+    for _ in 0..depth {
+        level = level.iter().flat_map(|t| t.subdivide()).collect();
+    }
+    level
+}
+
+/// Split transfer: subdivides an owned triangle and sends some of the leaves to a
+/// recipient, keeping the rest as change, so less than a whole triangle can be sent
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SplitTransferTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub parent_hash: Sha256Hash,
+    /// How many times to subdivide; each leaf has 1/4^depth of the parent's area
+    pub depth: u8,
+    /// Indices into the depth-first leaf order, strictly increasing
+    pub recipient_leaves: Vec<u16>,
+    pub recipient: Address,
+    pub sender: Address,
+    pub fee: u64,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl SplitTransferTx {
+    pub fn new(
+        parent_hash: Sha256Hash,
+        depth: u8,
+        recipient_leaves: Vec<u16>,
+        recipient: Address,
+        sender: Address,
+        fee: u64,
+        nonce: u64,
+    ) -> Self {
+        SplitTransferTx {
+            version: TxVersion::CURRENT,
+            parent_hash,
+            depth,
+            recipient_leaves,
+            recipient,
+            sender,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+        }
+    }
+
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "split_transfer",
+            self.version,
+            &self.parent_hash,
+            self.depth,
+            &self.recipient_leaves,
+            &self.recipient,
+            &self.sender,
+            self.fee,
+            self.nonce,
+        ))
+    }
+
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if self.depth == 0 || self.depth > DeepSubdivisionTx::MAX_DEPTH {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Subdivision depth must be between 1 and {}",
+                DeepSubdivisionTx::MAX_DEPTH
+            )));
+        }
+        if self.recipient_leaves.is_empty() {
+            return Err(ChainError::InvalidTransaction("Split transfer sends no leaves".to_string()));
+        }
+        if self.recipient_leaves.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(ChainError::InvalidTransaction(
+                "Split transfer leaf indices must be strictly increasing".to_string()
+            ));
+        }
+        let leaf_count = 3usize.pow(self.depth as u32);
+        if self.recipient_leaves.iter().any(|&index| index as usize >= leaf_count) {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Split transfer leaf index out of range (depth {} has {} leaves)",
+                self.depth, leaf_count
+            )));
+        }
+        check_signed_by(&self.sender, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+
+    /// Checks that the sender owns `parent` and that its leaves aren't too small
+    pub fn validate_against_parent(&self, parent: &Triangle) -> Result<(), ChainError> {
+        if parent.owner != self.sender {
+            return Err(ChainError::InvalidTransaction(format!(
+                "{} does not own triangle {}",
+                self.sender,
+                hex::encode(self.parent_hash)
+            )));
+        }
+        if !parent.can_subdivide(self.depth as u32) {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Subdividing to depth {} would create triangles below the minimum area of {}",
+                self.depth,
+                crate::geometry::MIN_TRIANGLE_AREA
+            )));
+        }
+        Ok(())
+    }
+
+    /// The leaves this transaction creates from `parent`: the selected ones owned by
+    /// the recipient, the rest by the sender
+    pub fn outputs(&self, parent: &Triangle) -> Vec<Triangle> {
+        let mut leaves = subdivide_to_depth(parent, self.depth);
+        for (index, leaf) in leaves.iter_mut().enumerate() {
+            leaf.owner = if self.recipient_leaves.binary_search(&(index as u16)).is_ok() {
+                self.recipient.clone()
+            } else {
+                self.sender.clone()
+            };
+        }
+        leaves
+    }
+}
+
+/// One beneficiary's share of a coinbase reward
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CoinbaseOutput {
+    pub beneficiary_address: Address,
+    pub reward_area: u64,
+}
+
+/// Coinbase transaction: miner reward, optionally split between several
+/// beneficiaries (e.g. a pool's members), each getting their own triangle
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CoinbaseTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub outputs: Vec<CoinbaseOutput>,
+    /// Rolled by the miner once the header nonce space is used up, giving the
+    /// block a fresh merkle root and so a fresh set of header hashes
+    #[serde(default)]
+    pub extra_nonce: u64,
+}
+
+impl CoinbaseTx {
+    /// Maximum number of beneficiaries in one coinbase
+    pub const MAX_OUTPUTS: usize = 100;
+
+    /// Spacing of the grid reward triangles are placed on. An equilateral of
+    /// the initial subsidy is under 50 wide, so unless fees make them much
+    /// larger, triangles in different cells never overlap.
+    pub const REWARD_CELL_SIZE: Coord = 64.0;
+
+    /// A coinbase paying the whole reward to one beneficiary
+    pub fn new(reward_area: u64, beneficiary_address: Address) -> Self {
+        Self::with_outputs(vec![CoinbaseOutput { beneficiary_address, reward_area }])
+    }
+
+    pub fn with_outputs(outputs: Vec<CoinbaseOutput>) -> Self {
+        CoinbaseTx {
+            version: TxVersion::CURRENT,
+            outputs,
+            extra_nonce: 0,
+        }
+    }
+
+    /// Total area claimed across every output
+    pub fn reward_area(&self) -> u64 {
+        self.outputs.iter().fold(0u64, |total, output| total.saturating_add(output.reward_area))
+    }
+
+    /// Area paid to `address` by this coinbase
+    pub fn reward_to(&self, address: &str) -> u64 {
+        self.outputs.iter()
+            .filter(|output| output.beneficiary_address == address)
+            .fold(0u64, |total, output| total.saturating_add(output.reward_area))
+    }
+
+    pub fn hash(&self) -> Sha256Hash {
+        // A coinbase that never rolled its extra nonce hashes as it always has
+        if self.extra_nonce == 0 {
+            codec::hash(&("coinbase", self.version, &self.outputs))
+        } else {
+            codec::hash(&("coinbase", self.version, &self.outputs, self.extra_nonce))
+        }
+    }
+
+    /// The reward triangles this coinbase creates when mined in the block with
+    /// hash `block_hash`, one per output, in output order.
+    ///
+    /// Each triangle is an equilateral of the output's area, placed on a grid
+    /// cell chosen by hashing the block hash, output index and beneficiary, so
+    /// anyone can recompute it and the same coinbase in a competing block
+    /// lands elsewhere.
+    pub fn reward_triangles(&self, block_hash: &Sha256Hash) -> Vec<Triangle> {
+        self.outputs.iter().enumerate().map(|(index, output)| {
+            let seed = codec::hash(&("coinbase_reward", block_hash, index as u64, &output.beneficiary_address));
+            let cell = |bytes: &[u8]| u16::from_le_bytes([bytes[0], bytes[1]]) as Coord * Self::REWARD_CELL_SIZE;
+            Triangle::equilateral(
+                Point::new(cell(&seed[0..2]), cell(&seed[2..4])),
+                output.reward_area as Coord,
+                output.beneficiary_address.clone(),
+            )
+        }).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        if self.outputs.is_empty() {
+            return Err(ChainError::InvalidTransaction(
+                "Coinbase must have at least one output".to_string()
+            ));
+        }
+        if self.outputs.len() > Self::MAX_OUTPUTS {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Coinbase exceeds maximum of {} outputs",
+                Self::MAX_OUTPUTS
+            )));
+        }
+
+        for output in &self.outputs {
+            // Validate reward area is within acceptable bounds
+            if output.reward_area == 0 {
+                return Err(ChainError::InvalidTransaction(
+                    "Coinbase reward area must be greater than zero".to_string()
+                ));
+            }
+
+            // Validate beneficiary address is not empty
+            if output.beneficiary_address.is_empty() {
+                return Err(ChainError::InvalidTransaction(
+                    "Coinbase beneficiary address cannot be empty".to_string()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Transfer transaction - moves ownership of a triangle
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TransferTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub input_hash: Sha256Hash,
+    pub new_owner: Address,
+    pub sender: Address,
+    pub fee: u64,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+    #[serde(default)]
+    pub memo: Option<String>,
+    #[serde(default)]
+    pub lock_time: Option<LockTime>,
+}
+
+impl TransferTx {
+    /// Maximum memo length (256 characters)
+    pub const MAX_MEMO_LENGTH: usize = 256;
+
+    pub fn new(input_hash: Sha256Hash, new_owner: Address, sender: Address, fee: u64, nonce: u64) -> Self {
+        TransferTx {
+            version: TxVersion::CURRENT,
+            input_hash,
+            new_owner,
+            sender,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+            memo: None,
+            lock_time: None,
+        }
+    }
+
+    pub fn with_memo(mut self, memo: String) -> Result<Self, ChainError> {
+        if memo.len() > Self::MAX_MEMO_LENGTH {
+            return Err(ChainError::InvalidTransaction(
+                format!("Memo exceeds maximum length of {} characters", Self::MAX_MEMO_LENGTH)
+            ));
+        }
+        self.memo = Some(memo);
+        Ok(self)
+    }
+
+    /// Delay the transaction until `lock_time`; must be set before signing
+    pub fn with_lock_time(mut self, lock_time: LockTime) -> Self {
+        self.lock_time = Some(lock_time);
+        self
+    }
+    
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key,
+    /// including the memo so it can't be altered or stripped in transit
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "transfer",
+            self.version,
+            &self.input_hash,
+            &self.new_owner,
+            &self.sender,
+            self.fee,
+            self.nonce,
+            &self.memo,
+            &self.lock_time,
+        ))
+    }
+    
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+    
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        // Validate memo length to prevent DoS attacks
+        if let Some(ref memo) = self.memo {
+            if memo.len() > Self::MAX_MEMO_LENGTH {
+                return Err(ChainError::InvalidTransaction(
+                    format!("Memo exceeds maximum length of {} characters", Self::MAX_MEMO_LENGTH)
+                ));
+            }
+        }
+
+        check_signed_by(&self.sender, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+}
+
+/// One input of a batch transfer and the address receiving it
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BatchTransferEntry {
+    pub input_hash: Sha256Hash,
+    pub new_owner: Address,
+}
+
+/// Batch transfer: moves several triangles, each to its own recipient, under a
+/// single signature. One fee pays for every input in the batch.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BatchTransferTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub transfers: Vec<BatchTransferEntry>,
+    pub sender: Address,
+    pub fee: u64,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl BatchTransferTx {
+    /// Maximum number of inputs in one batch
+    pub const MAX_INPUTS: usize = 100;
+
+    pub fn new(transfers: Vec<BatchTransferEntry>, sender: Address, fee: u64, nonce: u64) -> Self {
+        BatchTransferTx {
+            version: TxVersion::CURRENT,
+            transfers,
+            sender,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+        }
+    }
+
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "batch_transfer",
+            self.version,
+            &self.transfers,
+            &self.sender,
+            self.fee,
+            self.nonce,
+        ))
+    }
+
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if self.transfers.is_empty() {
+            return Err(ChainError::InvalidTransaction("Batch transfer has no inputs".to_string()));
+        }
+        if self.transfers.len() > Self::MAX_INPUTS {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Batch transfer exceeds maximum of {} inputs",
+                Self::MAX_INPUTS
+            )));
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        for transfer in &self.transfers {
+            if !seen.insert(transfer.input_hash) {
+                return Err(ChainError::InvalidTransaction(format!(
+                    "Batch transfer spends {} more than once",
+                    hex::encode(transfer.input_hash)
+                )));
+            }
+        }
+
+        check_signed_by(&self.sender, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+}
+
+/// Check that a signature over `message` is valid and made by the key behind `signer`
+fn check_signed_by(
+    signer: &Address,
+    public_key: &Option<Vec<u8>>,
+    signature: &Option<Vec<u8>>,
+    tx_hash: &Sha256Hash,
+    message: &[u8],
+    cache: Option<&SignatureCache>,
+) -> Result<(), ChainError> {
+    let (public_key, signature) = match (public_key, signature) {
+        (Some(public_key), Some(signature)) => (public_key, signature),
+        _ => return Err(ChainError::InvalidTransaction("Transaction not signed".to_string())),
+    };
+
+    if crate::crypto::address_from_public_key(public_key) != *signer {
+        return Err(ChainError::InvalidTransaction(format!(
+            "Public key does not belong to {}",
+            signer
+        )));
+    }
+
+    let is_valid = match cache {
+        Some(cache) => cache.verify(tx_hash, public_key, message, signature)?,
+        None => crate::crypto::verify_signature(public_key, message, signature)?,
+    };
+    if !is_valid {
+        return Err(ChainError::InvalidTransaction("Invalid signature".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Terms of a hash time-locked contract holding a triangle
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct HtlcContract {
+    /// Owner who locked the triangle and can reclaim it after the timeout
+    pub sender: Address,
+    /// Party who can claim the triangle by revealing the preimage
+    pub recipient: Address,
+    /// SHA-256 of the secret preimage
+    pub hash_lock: Sha256Hash,
+    /// First block height at which the sender may refund
+    pub timeout_height: BlockHeight,
+}
+
+/// HTLC lock: places an owned triangle under a hash time-locked contract.
+/// Until it is redeemed, the triangle can't be spent by any other transaction.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct HtlcLockTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub input_hash: Sha256Hash,
+    pub sender: Address,
+    pub recipient: Address,
+    pub hash_lock: Sha256Hash,
+    pub timeout_height: BlockHeight,
+    pub fee: u64,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl HtlcLockTx {
+    pub fn new(input_hash: Sha256Hash, contract: HtlcContract, fee: u64, nonce: u64) -> Self {
+        HtlcLockTx {
+            version: TxVersion::CURRENT,
+            input_hash,
+            sender: contract.sender,
+            recipient: contract.recipient,
+            hash_lock: contract.hash_lock,
+            timeout_height: contract.timeout_height,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+        }
+    }
+
+    /// The contract this transaction creates
+    pub fn contract(&self) -> HtlcContract {
+        HtlcContract {
+            sender: self.sender.clone(),
+            recipient: self.recipient.clone(),
+            hash_lock: self.hash_lock,
+            timeout_height: self.timeout_height,
+        }
+    }
+
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "htlc_lock",
+            self.version,
+            &self.input_hash,
+            &self.contract(),
+            self.fee,
+            self.nonce,
+        ))
+    }
+
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if self.sender == self.recipient {
+            return Err(ChainError::InvalidTransaction(
+                "HTLC sender and recipient must differ".to_string()
+            ));
+        }
+        check_signed_by(&self.sender, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+}
+
+/// How an HTLC is redeemed
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum HtlcRedeemPath {
+    /// The recipient reveals the preimage before the timeout
+    Claim { preimage: Vec<u8> },
+    /// The sender takes the triangle back at or after the timeout
+    Refund,
+}
+
+/// HTLC redeem: settles a locked triangle by claim or refund
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct HtlcRedeemTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub input_hash: Sha256Hash,
+    pub path: HtlcRedeemPath,
+    pub redeemer: Address,
+    pub fee: u64,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl HtlcRedeemTx {
+    /// Longest preimage a claim may reveal
+    pub const MAX_PREIMAGE_LENGTH: usize = 64;
+
+    pub fn new(input_hash: Sha256Hash, path: HtlcRedeemPath, redeemer: Address, fee: u64, nonce: u64) -> Self {
+        HtlcRedeemTx {
+            version: TxVersion::CURRENT,
+            input_hash,
+            path,
+            redeemer,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+        }
+    }
+
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "htlc_redeem",
+            self.version,
+            &self.input_hash,
+            &self.path,
+            &self.redeemer,
+            self.fee,
+            self.nonce,
+        ))
+    }
+
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if let HtlcRedeemPath::Claim { preimage } = &self.path {
+            if preimage.len() > Self::MAX_PREIMAGE_LENGTH {
+                return Err(ChainError::InvalidTransaction(format!(
+                    "HTLC preimage exceeds maximum length of {} bytes",
+                    Self::MAX_PREIMAGE_LENGTH
+                )));
+            }
+        }
+        check_signed_by(&self.redeemer, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+
+    /// Checks this redemption against the contract it settles, for a block at `height`
+    pub fn validate_against_contract(&self, contract: &HtlcContract, height: BlockHeight) -> Result<(), ChainError> {
+        match &self.path {
+            HtlcRedeemPath::Claim { preimage } => {
+                if self.redeemer != contract.recipient {
+                    return Err(ChainError::InvalidTransaction("Only the HTLC recipient can claim".to_string()));
+                }
+                if height >= contract.timeout_height {
+                    return Err(ChainError::InvalidTransaction(format!(
+                        "HTLC claim window closed at height {}",
+                        contract.timeout_height
+                    )));
+                }
+                let digest: Sha256Hash = Sha256::digest(preimage).into();
+                if digest != contract.hash_lock {
+                    return Err(ChainError::InvalidTransaction("Preimage does not match HTLC hash lock".to_string()));
+                }
+            }
+            HtlcRedeemPath::Refund => {
+                if self.redeemer != contract.sender {
+                    return Err(ChainError::InvalidTransaction("Only the HTLC sender can refund".to_string()));
+                }
+                if height < contract.timeout_height {
+                    return Err(ChainError::InvalidTransaction(format!(
+                        "HTLC can't be refunded before height {}",
+                        contract.timeout_height
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The address that owns the triangle once this redemption confirms
+    pub fn new_owner<'a>(&self, contract: &'a HtlcContract) -> &'a Address {
+        match self.path {
+            HtlcRedeemPath::Claim { .. } => &contract.recipient,
+            HtlcRedeemPath::Refund => &contract.sender,
+        }
+    }
+}
+
+/// Parties to a 2-of-3 escrow holding a triangle
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct EscrowContract {
+    /// Hash of the lock transaction, so settlement signatures can't be replayed
+    /// against a later escrow of the same triangle
+    pub id: Sha256Hash,
+    pub seller: Address,
+    pub buyer: Address,
+    pub arbiter: Address,
+}
+
+impl EscrowContract {
+    /// Signatures from distinct parties needed to settle
+    pub const REQUIRED_SIGNATURES: usize = 2;
+
+    pub fn is_party(&self, address: &Address) -> bool {
+        *address == self.seller || *address == self.buyer || *address == self.arbiter
+    }
+}
+
+/// Escrow lock: the seller places an owned triangle in escrow for a buyer, with an
+/// arbiter to break ties. Until settled, the triangle can't be spent any other way.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct EscrowLockTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub input_hash: Sha256Hash,
+    pub seller: Address,
+    pub buyer: Address,
+    pub arbiter: Address,
+    pub fee: u64,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl EscrowLockTx {
+    pub fn new(input_hash: Sha256Hash, seller: Address, buyer: Address, arbiter: Address, fee: u64, nonce: u64) -> Self {
+        EscrowLockTx {
+            version: TxVersion::CURRENT,
+            input_hash,
+            seller,
+            buyer,
+            arbiter,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+        }
+    }
+
+    /// The contract this transaction creates
+    pub fn contract(&self) -> EscrowContract {
+        EscrowContract {
+            id: self.hash(),
+            seller: self.seller.clone(),
+            buyer: self.buyer.clone(),
+            arbiter: self.arbiter.clone(),
+        }
+    }
+
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "escrow_lock",
+            self.version,
+            &self.input_hash,
+            &self.seller,
+            &self.buyer,
+            &self.arbiter,
+            self.fee,
+            self.nonce,
+        ))
+    }
+
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if self.seller == self.buyer || self.seller == self.arbiter || self.buyer == self.arbiter {
+            return Err(ChainError::InvalidTransaction(
+                "Escrow seller, buyer and arbiter must all differ".to_string()
+            ));
+        }
+        check_signed_by(&self.seller, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+}
+
+/// Where an escrowed triangle goes when it is settled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum EscrowOutcome {
+    /// The triangle goes to the buyer
+    Release,
+    /// The triangle goes back to the seller
+    Refund,
+}
+
+/// One party's signature over an escrow settlement
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct EscrowSignature {
+    pub signer: Address,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Escrow settlement: releases or refunds an escrowed triangle once two of the
+/// three parties have signed. It carries no nonce; the escrow id stops replays.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct EscrowSettleTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub input_hash: Sha256Hash,
+    pub escrow_id: Sha256Hash,
+    pub outcome: EscrowOutcome,
+    pub fee: u64,
+    pub signatures: Vec<EscrowSignature>,
+}
+
+impl EscrowSettleTx {
+    pub fn new(contract: &EscrowContract, input_hash: Sha256Hash, outcome: EscrowOutcome, fee: u64) -> Self {
+        EscrowSettleTx {
+            version: TxVersion::CURRENT,
+            input_hash,
+            escrow_id: contract.id,
+            outcome,
+            fee,
+            signatures: Vec::new(),
+        }
+    }
+
+    /// Hash of the signed fields. Every party signs the same message, so the txid
+    /// doesn't depend on which two of them signed.
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signatures
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "escrow_settle",
+            self.version,
+            &self.input_hash,
+            &self.escrow_id,
+            self.outcome,
+            self.fee,
+        ))
+    }
+
+    /// Add a party's signature, replacing any earlier one from the same signer
+    pub fn add_signature(&mut self, signature: EscrowSignature) {
+        self.signatures.retain(|s| s.signer != signature.signer);
+        self.signatures.push(signature);
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if self.signatures.len() < EscrowContract::REQUIRED_SIGNATURES || self.signatures.len() > 3 {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Escrow settlement needs {} to 3 signatures, got {}",
+                EscrowContract::REQUIRED_SIGNATURES,
+                self.signatures.len()
+            )));
+        }
+
+        let tx_hash = self.hash();
+        let message = self.signable_message();
+        let mut signers = std::collections::HashSet::new();
+        for sig in &self.signatures {
+            if !signers.insert(&sig.signer) {
+                return Err(ChainError::InvalidTransaction(format!(
+                    "Duplicate escrow signature from {}",
+                    sig.signer
+                )));
+            }
+            check_signed_by(
+                &sig.signer,
+                &Some(sig.public_key.clone()),
+                &Some(sig.signature.clone()),
+                &tx_hash,
+                &message,
+                cache,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Checks this settlement against the escrow it settles: it must name that
+    /// escrow and be signed only by its parties
+    pub fn validate_against_contract(&self, contract: &EscrowContract) -> Result<(), ChainError> {
+        if self.escrow_id != contract.id {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Settlement is for escrow {}, not {}",
+                hex::encode(self.escrow_id),
+                hex::encode(contract.id)
+            )));
+        }
+        if let Some(outsider) = self.signatures.iter().find(|s| !contract.is_party(&s.signer)) {
+            return Err(ChainError::InvalidTransaction(format!(
+                "{} is not a party to the escrow",
+                outsider.signer
+            )));
+        }
+        Ok(())
+    }
+
+    /// The address that owns the triangle once this settlement confirms
+    pub fn new_owner<'a>(&self, contract: &'a EscrowContract) -> &'a Address {
+        match self.outcome {
+            EscrowOutcome::Release => &contract.buyer,
+            EscrowOutcome::Refund => &contract.seller,
+        }
+    }
+}
+
+/// Terms of a lease: the tenant holds the triangle until `expires_at`, when it
+/// goes back to the owner
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct LeaseContract {
+    /// Hash of the lease transaction
+    pub id: Sha256Hash,
+    pub owner: Address,
+    pub tenant: Address,
+    /// First block height at which the triangle belongs to the owner again
+    pub expires_at: BlockHeight,
+}
+
+impl LeaseContract {
+    /// Whether the tenant still holds the triangle in a block at `height`
+    pub fn is_active(&self, height: BlockHeight) -> bool {
+        height < self.expires_at
+    }
+}
+
+/// Lease: hands an owned triangle to a tenant until a block height. The tenant
+/// may pass it on with a transfer, but whoever holds it at expiry loses it back
+/// to the owner, so it can't be subdivided or put under another contract.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct LeaseTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub input_hash: Sha256Hash,
+    pub owner: Address,
+    pub tenant: Address,
+    pub expires_at: BlockHeight,
+    pub fee: u64,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl LeaseTx {
+    pub fn new(input_hash: Sha256Hash, owner: Address, tenant: Address, expires_at: BlockHeight, fee: u64, nonce: u64) -> Self {
+        LeaseTx {
+            version: TxVersion::CURRENT,
+            input_hash,
+            owner,
+            tenant,
+            expires_at,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+        }
+    }
+
+    /// The lease this transaction starts
+    pub fn contract(&self) -> LeaseContract {
+        LeaseContract {
+            id: self.hash(),
+            owner: self.owner.clone(),
+            tenant: self.tenant.clone(),
+            expires_at: self.expires_at,
+        }
+    }
+
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "lease",
+            self.version,
+            &self.input_hash,
+            &self.owner,
+            &self.tenant,
+            self.expires_at,
+            self.fee,
+            self.nonce,
+        ))
+    }
+
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if self.owner == self.tenant {
+            return Err(ChainError::InvalidTransaction(
+                "Lease owner and tenant must differ".to_string()
+            ));
+        }
+        check_signed_by(&self.owner, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+
+    /// A lease must end after the block it is included in
+    pub fn validate_at_height(&self, height: BlockHeight) -> Result<(), ChainError> {
+        if self.expires_at <= height {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Lease expires at height {}, not after block {}",
+                self.expires_at, height
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Permission for a delegate to transfer or subdivide a triangle for its owner.
+/// It only holds while `owner` still owns the triangle.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Approval {
+    pub owner: Address,
+    pub delegate: Address,
+}
+
+/// Approve: lets a delegate transfer or subdivide one of the owner's triangles
+/// with the delegate's own key, or revokes that permission when `delegate` is
+/// `None`. The approval ends once the triangle is used.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ApproveTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub input_hash: Sha256Hash,
+    pub owner: Address,
+    pub delegate: Option<Address>,
+    pub fee: u64,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl ApproveTx {
+    pub fn new(input_hash: Sha256Hash, owner: Address, delegate: Option<Address>, fee: u64, nonce: u64) -> Self {
+        ApproveTx {
+            version: TxVersion::CURRENT,
+            input_hash,
+            owner,
+            delegate,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+        }
+    }
+
+    /// The approval this transaction grants, or `None` for a revocation
+    pub fn approval(&self) -> Option<Approval> {
+        self.delegate.as_ref().map(|delegate| Approval {
+            owner: self.owner.clone(),
+            delegate: delegate.clone(),
+        })
+    }
+
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "approve",
+            self.version,
+            &self.input_hash,
+            &self.owner,
+            &self.delegate,
+            self.fee,
+            self.nonce,
+        ))
+    }
+
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if self.delegate.as_ref() == Some(&self.owner) {
+            return Err(ChainError::InvalidTransaction(
+                "An owner can't approve themselves".to_string()
+            ));
+        }
+        check_signed_by(&self.owner, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+}
+
+/// An open marketplace listing: the seller's triangle is for sale for `price_area`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MarketOffer {
+    /// Hash of the offer transaction, which an acceptance must name
+    pub id: Sha256Hash,
+    pub seller: Address,
+    /// Total area of triangles the buyer must hand over
+    pub price_area: f64,
+}
+
+/// Marketplace offer: the owner lists a triangle for sale. The listing lapses as
+/// soon as the triangle is spent by anything other than an acceptance.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct OfferTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub input_hash: Sha256Hash,
+    pub seller: Address,
+    pub price_area: f64,
+    pub fee: u64,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl OfferTx {
+    pub fn new(input_hash: Sha256Hash, seller: Address, price_area: f64, fee: u64, nonce: u64) -> Self {
+        OfferTx {
+            version: TxVersion::CURRENT,
+            input_hash,
+            seller,
+            price_area,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+        }
+    }
+
+    /// The listing this transaction opens
+    pub fn offer(&self) -> MarketOffer {
+        MarketOffer {
+            id: self.hash(),
+            seller: self.seller.clone(),
+            price_area: self.price_area,
+        }
+    }
+
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "offer",
+            self.version,
+            &self.input_hash,
+            &self.seller,
+            self.price_area,
+            self.fee,
+            self.nonce,
+        ))
+    }
+
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if !self.price_area.is_finite() || self.price_area <= 0.0 {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Offer price {} must be a positive area",
+                self.price_area
+            )));
+        }
+        check_signed_by(&self.seller, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+}
+
+/// Marketplace acceptance: in one transaction the buyer takes the listed triangle
+/// and hands the seller payment triangles worth at least the asking price
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AcceptTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub offer_id: Sha256Hash,
+    /// The listed triangle
+    pub input_hash: Sha256Hash,
+    pub buyer: Address,
+    /// Triangles the buyer pays with; each moves to the seller
+    pub payment: Vec<Sha256Hash>,
+    pub fee: u64,
+    pub nonce: u64,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl AcceptTx {
+    /// Maximum number of payment triangles
+    pub const MAX_PAYMENT_INPUTS: usize = 100;
+
+    pub fn new(
+        offer: &MarketOffer,
+        input_hash: Sha256Hash,
+        buyer: Address,
+        payment: Vec<Sha256Hash>,
+        fee: u64,
+        nonce: u64,
+    ) -> Self {
+        AcceptTx {
+            version: TxVersion::CURRENT,
+            offer_id: offer.id,
+            input_hash,
+            buyer,
+            payment,
+            fee,
+            nonce,
+            signature: None,
+            public_key: None,
+        }
+    }
+
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
+    }
+
+    /// Canonical encoding of every field except the signature and public key
+    pub fn signable_message(&self) -> Vec<u8> {
+        codec::encode(&(
+            "accept",
+            self.version,
+            &self.offer_id,
+            &self.input_hash,
+            &self.buyer,
+            &self.payment,
+            self.fee,
+            self.nonce,
+        ))
+    }
+
+    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
+        self.signature = Some(signature);
+        self.public_key = Some(public_key);
+    }
+
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
+
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
+
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if self.payment.is_empty() {
+            return Err(ChainError::InvalidTransaction("Acceptance has no payment".to_string()));
+        }
+        if self.payment.len() > Self::MAX_PAYMENT_INPUTS {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Acceptance exceeds maximum of {} payment triangles",
+                Self::MAX_PAYMENT_INPUTS
+            )));
+        }
+
+        let mut seen = std::collections::HashSet::from([self.input_hash]);
+        for input in &self.payment {
+            if !seen.insert(*input) {
+                return Err(ChainError::InvalidTransaction(format!(
+                    "Acceptance spends {} more than once",
+                    hex::encode(input)
+                )));
+            }
+        }
+
+        check_signed_by(&self.buyer, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
+    }
+}
+
+/// Which parts of a swap one input's signature commits to, so a swap can be
+/// built up by several parties signing in turn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum SigHashType {
+    /// Every input, every output and the fee; nothing may change after signing
+    All,
+    /// Every input and the fee, but only the output at the signer's index
+    Single,
+    /// Only the signer's input, but every output; others may add inputs
+    AllAnyoneCanPay,
+    /// Only the signer's input and the output at its index; others may add
+    /// inputs and outputs and set the fee
+    SingleAnyoneCanPay,
+}
+
+impl SigHashType {
+    pub fn commits_to_all_inputs(self) -> bool {
+        matches!(self, SigHashType::All | SigHashType::Single)
+    }
+
+    pub fn commits_to_all_outputs(self) -> bool {
+        matches!(self, SigHashType::All | SigHashType::AllAnyoneCanPay)
+    }
+}
+
+/// A triangle going into a swap, signed by its owner
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SwapInput {
+    pub input_hash: Sha256Hash,
+    pub owner: Address,
+    pub sighash: SigHashType,
+    pub signature: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+/// Where one of the swapped triangles ends up
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SwapOutput {
+    pub input_hash: Sha256Hash,
+    pub new_owner: Address,
+}
+
+/// Swap: reassigns triangles from several owners at once. Each owner signs their
+/// own input with a `SigHashType`, so e.g. a seller can sign their triangle and
+/// the payment they expect, leaving a buyer to attach the payment later. It
+/// carries no nonce; moving the inputs to new owners stops replays.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SwapTx {
+    #[serde(default)]
+    pub version: TxVersion,
+    pub inputs: Vec<SwapInput>,
+    /// Every input appears in exactly one output
+    pub outputs: Vec<SwapOutput>,
+    pub fee: u64,
 }
 
-impl SubdivisionTx {
-    pub fn new(
-        parent_hash: Sha256Hash,
-        children: Vec<Triangle>,
-        owner_address: Address,
-        fee: u64,
-        nonce: u64,
-    ) -> Self {
-        SubdivisionTx {
-            parent_hash,
-            children,
-            owner_address,
+impl SwapTx {
+    /// Maximum number of inputs in one swap
+    pub const MAX_INPUTS: usize = 100;
+
+    pub fn new(fee: u64) -> Self {
+        SwapTx {
+            version: TxVersion::CURRENT,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
             fee,
-            nonce,
+        }
+    }
+
+    /// Add an unsigned input, returning its index
+    pub fn add_input(&mut self, input_hash: Sha256Hash, owner: Address, sighash: SigHashType) -> usize {
+        self.inputs.push(SwapInput {
+            input_hash,
+            owner,
+            sighash,
             signature: None,
             public_key: None,
-        }
+        });
+        self.inputs.len() - 1
     }
 
-    pub fn signable_message(&self) -> Vec<u8> {
-        let mut message = Vec::new();
-        message.extend_from_slice(&self.parent_hash);
-        for child in &self.children {
-            message.extend_from_slice(&child.hash());
-        }
-        message.extend_from_slice(self.owner_address.as_bytes());
-        message.extend_from_slice(&self.fee.to_le_bytes());
-        message.extend_from_slice(&self.nonce.to_le_bytes());
-        message
+    /// Add an output, returning its index
+    pub fn add_output(&mut self, input_hash: Sha256Hash, new_owner: Address) -> usize {
+        self.outputs.push(SwapOutput { input_hash, new_owner });
+        self.outputs.len() - 1
     }
 
-    pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
-        self.signature = Some(signature);
-        self.public_key = Some(public_key);
+    /// Hash of every field except the signatures
+    pub fn hash(&self) -> Sha256Hash {
+        let inputs: Vec<_> = self.inputs.iter().map(|i| (&i.input_hash, &i.owner, i.sighash)).collect();
+        codec::hash(&("swap", self.version, inputs, &self.outputs, self.fee))
     }
 
-    /// Validates just the signature of the transaction, without access to blockchain state.
-    /// This is useful for early validation in the mempool.
-    pub fn validate_signature(&self) -> Result<(), ChainError> {
-        if self.signature.is_none() || self.public_key.is_none() {
-            return Err(ChainError::InvalidTransaction(
-                "Transaction not signed".to_string(),
-            ));
-        }
+    /// Canonical encoding of what the input at `index` signs, as chosen by its `SigHashType`
+    pub fn signable_message(&self, index: usize) -> Result<Vec<u8>, ChainError> {
+        let input = self.inputs.get(index).ok_or_else(|| {
+            ChainError::InvalidTransaction(format!("Swap has no input {}", index))
+        })?;
+        let sighash = input.sighash;
 
-        let message = self.signable_message();
-        let is_valid = crate::crypto::verify_signature(
-            self.public_key.as_ref().unwrap(),
-            &message,
-            self.signature.as_ref().unwrap(),
-        )?;
+        let inputs: Vec<_> = if sighash.commits_to_all_inputs() {
+            self.inputs.iter().map(|i| (&i.input_hash, &i.owner, i.sighash)).collect()
+        } else {
+            vec![(&input.input_hash, &input.owner, input.sighash)]
+        };
+        let outputs: Vec<&SwapOutput> = if sighash.commits_to_all_outputs() {
+            self.outputs.iter().collect()
+        } else {
+            vec![self.outputs.get(index).ok_or_else(|| {
+                ChainError::InvalidTransaction(format!("Swap has no output {} for input {} to sign", index, index))
+            })?]
+        };
+        // Whoever completes the swap sets the fee
+        let fee = sighash.commits_to_all_inputs().then_some(self.fee);
 
-        if !is_valid {
-            return Err(ChainError::InvalidTransaction(
-                "Invalid signature".to_string(),
-            ));
+        Ok(codec::encode(&("swap", self.version, sighash, inputs, outputs, fee)))
+    }
+
+    pub fn sign_input(&mut self, index: usize, signature: Vec<u8>, public_key: Vec<u8>) {
+        if let Some(input) = self.inputs.get_mut(index) {
+            input.signature = Some(signature);
+            input.public_key = Some(public_key);
         }
+    }
 
-        Ok(())
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
     }
 
-    /// Performs a full validation of the transaction against the current blockchain state.
-    pub fn validate(&self, state: &TriangleState) -> Result<(), ChainError> {
-        // First, perform a stateless signature check.
-        self.validate_signature()?;
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
 
-        // Then, validate against the current state (UTXO set).
-        if !state.utxo_set.contains_key(&self.parent_hash) {
-            return Err(ChainError::TriangleNotFound(format!(
-                "Parent triangle {} not found in UTXO set",
-                hex::encode(self.parent_hash)
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if self.inputs.is_empty() {
+            return Err(ChainError::InvalidTransaction("Swap has no inputs".to_string()));
+        }
+        if self.inputs.len() > Self::MAX_INPUTS {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Swap exceeds maximum of {} inputs",
+                Self::MAX_INPUTS
             )));
         }
-
-        let parent = state.utxo_set.get(&self.parent_hash).unwrap();
-        let expected_children = parent.subdivide();
-
-        if self.children.len() != 3 {
-            return Err(ChainError::InvalidTransaction(
-                "Subdivision must produce exactly 3 children".to_string(),
-            ));
+        if self.outputs.len() != self.inputs.len() {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Swap has {} inputs but {} outputs",
+                self.inputs.len(),
+                self.outputs.len()
+            )));
         }
 
-        for (i, child) in self.children.iter().enumerate() {
-            let expected = &expected_children[i];
-            if !child.a.equals(&expected.a) ||
-               !child.b.equals(&expected.b) ||
-               !child.c.equals(&expected.c) {
+        let owners: std::collections::HashMap<_, _> =
+            self.inputs.iter().map(|i| (i.input_hash, &i.owner)).collect();
+        if owners.len() != self.inputs.len() {
+            return Err(ChainError::InvalidTransaction("Swap spends a triangle more than once".to_string()));
+        }
+        let mut assigned = std::collections::HashSet::new();
+        for output in &self.outputs {
+            if !owners.contains_key(&output.input_hash) || !assigned.insert(output.input_hash) {
                 return Err(ChainError::InvalidTransaction(format!(
-                    "Child {} geometry does not match expected subdivision",
-                    i
+                    "Swap output {} must assign exactly one of its inputs",
+                    hex::encode(output.input_hash)
                 )));
             }
         }
-
-        Ok(())
-    }
-}
-
-/// Coinbase transaction: miner reward
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct CoinbaseTx {
-    pub reward_area: u64,
-    pub beneficiary_address: Address,
-}
-
-impl CoinbaseTx {
-    /// Maximum reward area that can be claimed in a coinbase transaction
-    pub const MAX_REWARD_AREA: u64 = 1000;
-
-    pub fn validate(&self) -> Result<(), ChainError> {
-        // Validate reward area is within acceptable bounds
-        if self.reward_area == 0 {
-            return Err(ChainError::InvalidTransaction(
-                "Coinbase reward area must be greater than zero".to_string()
-            ));
+        if self.outputs.iter().all(|o| *owners[&o.input_hash] == o.new_owner) {
+            return Err(ChainError::InvalidTransaction("Swap changes no owners".to_string()));
         }
 
-        if self.reward_area > Self::MAX_REWARD_AREA {
-            return Err(ChainError::InvalidTransaction(
-                format!("Coinbase reward area {} exceeds maximum {}",
-                    self.reward_area, Self::MAX_REWARD_AREA)
-            ));
-        }
-
-        // Validate beneficiary address is not empty
-        if self.beneficiary_address.is_empty() {
-            return Err(ChainError::InvalidTransaction(
-                "Coinbase beneficiary address cannot be empty".to_string()
-            ));
+        for (index, input) in self.inputs.iter().enumerate() {
+            let message = self.signable_message(index)?;
+            // Each input signs a different message, so cache by that rather than the txid
+            let message_hash: Sha256Hash = Sha256::digest(&message).into();
+            check_signed_by(&input.owner, &input.public_key, &input.signature, &message_hash, &message, cache)?;
         }
-
         Ok(())
     }
 }
 
-/// Transfer transaction - moves ownership of a triangle
+/// Inscription: attaches arbitrary data (art metadata, a URI, ...) to a triangle
+/// the signer owns. The data lives in chain history; the triangle is unchanged.
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct TransferTx {
+pub struct InscriptionTx {
+    #[serde(default)]
+    pub version: TxVersion,
     pub input_hash: Sha256Hash,
-    pub new_owner: Address,
-    pub sender: Address,
+    pub owner: Address,
+    pub data: Vec<u8>,
     pub fee: u64,
     pub nonce: u64,
     pub signature: Option<Vec<u8>>,
     pub public_key: Option<Vec<u8>>,
-    #[serde(default)]
-    pub memo: Option<String>,
 }
 
-impl TransferTx {
-    /// Maximum memo length (256 characters)
-    pub const MAX_MEMO_LENGTH: usize = 256;
+impl InscriptionTx {
+    /// Maximum number of bytes one inscription may carry
+    pub const MAX_DATA_LENGTH: usize = 1024;
 
-    pub fn new(input_hash: Sha256Hash, new_owner: Address, sender: Address, fee: u64, nonce: u64) -> Self {
-        TransferTx {
+    pub fn new(input_hash: Sha256Hash, owner: Address, data: Vec<u8>, fee: u64, nonce: u64) -> Self {
+        InscriptionTx {
+            version: TxVersion::CURRENT,
             input_hash,
-            new_owner,
-            sender,
+            owner,
+            data,
             fee,
             nonce,
             signature: None,
             public_key: None,
-            memo: None,
         }
     }
 
-    pub fn with_memo(mut self, memo: String) -> Result<Self, ChainError> {
-        if memo.len() > Self::MAX_MEMO_LENGTH {
-            return Err(ChainError::InvalidTransaction(
-                format!("Memo exceeds maximum length of {} characters", Self::MAX_MEMO_LENGTH)
-            ));
-        }
-        self.memo = Some(memo);
-        Ok(self)
+    /// Hash of the signed fields, so the txid commits to everything the signature covers
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256::digest(self.signable_message()).into()
     }
-    
+
+    /// Canonical encoding of every field except the signature and public key
     pub fn signable_message(&self) -> Vec<u8> {
-        let mut message = Vec::new();
-        message.extend_from_slice("TRANSFER:".as_bytes());
-        message.extend_from_slice(&self.input_hash);
-        message.extend_from_slice(self.new_owner.as_bytes());
-        message.extend_from_slice(self.sender.as_bytes());
-        message.extend_from_slice(&self.fee.to_le_bytes());
-        message.extend_from_slice(&self.nonce.to_le_bytes());
-        message
+        codec::encode(&(
+            "inscription",
+            self.version,
+            &self.input_hash,
+            &self.owner,
+            &self.data,
+            self.fee,
+            self.nonce,
+        ))
     }
-    
+
     pub fn sign(&mut self, signature: Vec<u8>, public_key: Vec<u8>) {
         self.signature = Some(signature);
         self.public_key = Some(public_key);
     }
-    
-    pub fn validate(&self) -> Result<(), ChainError> {
-        if self.signature.is_none() || self.public_key.is_none() {
-            return Err(ChainError::InvalidTransaction("Transfer not signed".to_string()));
-        }
 
-        // Validate memo length to prevent DoS attacks
-        if let Some(ref memo) = self.memo {
-            if memo.len() > Self::MAX_MEMO_LENGTH {
-                return Err(ChainError::InvalidTransaction(
-                    format!("Memo exceeds maximum length of {} characters", Self::MAX_MEMO_LENGTH)
-                ));
-            }
-        }
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check(None)
+    }
 
-        let message = self.signable_message();
-        let is_valid = crate::crypto::verify_signature(
-            self.public_key.as_ref().unwrap(),
-            &message,
-            self.signature.as_ref().unwrap(),
-        )?;
+    /// Like `validate`, but skips verification for signatures already in the cache
+    pub fn validate_cached(&self, cache: &SignatureCache) -> Result<(), ChainError> {
+        self.check(Some(cache))
+    }
 
-        if !is_valid {
-            return Err(ChainError::InvalidTransaction("Invalid signature".to_string()));
+    fn check(&self, cache: Option<&SignatureCache>) -> Result<(), ChainError> {
+        if self.data.is_empty() {
+            return Err(ChainError::InvalidTransaction("Inscription has no data".to_string()));
         }
-
-        Ok(())
+        if self.data.len() > Self::MAX_DATA_LENGTH {
+            return Err(ChainError::InvalidTransaction(format!(
+                "Inscription exceeds maximum length of {} bytes",
+                Self::MAX_DATA_LENGTH
+            )));
+        }
+        check_signed_by(&self.owner, &self.public_key, &self.signature, &self.hash(), &self.signable_message(), cache)
     }
 }
 
@@ -429,4 +2422,418 @@ mod tests {
 
         assert!(tx.validate(&state).is_err());
     }
+
+    #[test]
+    fn test_transfer_memo_is_signed() {
+        let keypair = KeyPair::generate().unwrap();
+        let mut tx = TransferTx::new([7u8; 32], "bob".to_string(), keypair.address(), 1, 1)
+            .with_memo("rent".to_string())
+            .unwrap();
+        let signature = keypair.sign(&tx.signable_message()).unwrap();
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        assert!(tx.validate().is_ok());
+
+        // Altering or stripping the memo changes the txid and breaks the signature
+        let original_hash = tx.hash();
+        let mut tampered = tx.clone();
+        tampered.memo = Some("gift".to_string());
+        assert_ne!(tampered.hash(), original_hash);
+        assert!(tampered.validate().is_err());
+
+        tampered.memo = None;
+        assert!(tampered.validate().is_err());
+
+        // The same goes for the other optional field
+        let mut tampered = tx.clone();
+        tampered.lock_time = Some(LockTime::Height(100));
+        assert_ne!(tampered.hash(), original_hash);
+        assert!(tampered.validate().is_err());
+    }
+
+    #[test]
+    fn test_batch_transfer_validation() {
+        let keypair = KeyPair::generate().unwrap();
+        let entry = |byte: u8, owner: &str| BatchTransferEntry {
+            input_hash: [byte; 32],
+            new_owner: owner.to_string(),
+        };
+        let signed = |transfers: Vec<BatchTransferEntry>| {
+            let mut tx = BatchTransferTx::new(transfers, keypair.address(), 3, 1);
+            let signature = keypair.sign(&tx.signable_message()).unwrap();
+            tx.sign(signature, keypair.public_key.serialize().to_vec());
+            tx
+        };
+
+        let tx = signed(vec![entry(1, "bob"), entry(2, "carol")]);
+        assert!(tx.validate().is_ok());
+        assert_eq!(Transaction::BatchTransfer(tx.clone()).inputs(), vec![[1u8; 32], [2u8; 32]]);
+
+        // Redirecting one input invalidates the single signature over the batch
+        let mut tampered = tx.clone();
+        tampered.transfers[1].new_owner = "mallory".to_string();
+        assert!(tampered.validate().is_err());
+
+        assert!(signed(vec![]).validate().is_err());
+        assert!(signed(vec![entry(1, "bob"), entry(1, "carol")]).validate().is_err());
+        let too_many = (0..=BatchTransferTx::MAX_INPUTS as u8).map(|i| entry(i, "bob")).collect();
+        assert!(signed(too_many).validate().is_err());
+    }
+
+    #[test]
+    fn test_transfer_signer_must_be_sender() {
+        let alice = KeyPair::generate().unwrap();
+        let mallory = KeyPair::generate().unwrap();
+
+        // A valid signature, but by a key that isn't behind the named sender
+        let mut transfer = TransferTx::new([1; 32], mallory.address(), alice.address(), 1, 1);
+        transfer.sign(mallory.sign(&transfer.signable_message()).unwrap(), mallory.public_key.serialize().to_vec());
+        assert!(transfer.validate().is_err());
+        transfer.sign(alice.sign(&transfer.signable_message()).unwrap(), alice.public_key.serialize().to_vec());
+        assert!(transfer.validate().is_ok());
+
+        let entry = BatchTransferEntry { input_hash: [1; 32], new_owner: mallory.address() };
+        let mut batch = BatchTransferTx::new(vec![entry], alice.address(), 1, 1);
+        batch.sign(mallory.sign(&batch.signable_message()).unwrap(), mallory.public_key.serialize().to_vec());
+        assert!(batch.validate().is_err());
+        batch.sign(alice.sign(&batch.signable_message()).unwrap(), alice.public_key.serialize().to_vec());
+        assert!(batch.validate().is_ok());
+    }
+
+    #[test]
+    fn test_subdivision_signer_must_be_owner() {
+        let alice = KeyPair::generate().unwrap();
+        let mallory = KeyPair::generate().unwrap();
+        let parent = Triangle::new(
+            Point { x: 0.0, y: 0.0 },
+            Point { x: 1.0, y: 0.0 },
+            Point { x: 0.5, y: 0.866 },
+            None,
+            alice.address(),
+        );
+
+        let mut tx = SubdivisionTx::new(parent.hash(), parent.subdivide().to_vec(), alice.address(), 0, 1);
+        tx.sign(mallory.sign(&tx.signable_message()).unwrap(), mallory.public_key.serialize().to_vec());
+        assert!(tx.validate_signature().is_err());
+        tx.sign(alice.sign(&tx.signable_message()).unwrap(), alice.public_key.serialize().to_vec());
+        assert!(tx.validate_signature().is_ok());
+    }
+
+    #[test]
+    fn test_htlc_signer_must_match_party() {
+        let sender = KeyPair::generate().unwrap();
+        let recipient = KeyPair::generate().unwrap();
+        let contract = HtlcContract {
+            sender: sender.address(),
+            recipient: recipient.address(),
+            hash_lock: Sha256::digest(b"secret").into(),
+            timeout_height: 10,
+        };
+
+        // Signed by the recipient's key on the sender's behalf
+        let mut lock = HtlcLockTx::new([1; 32], contract.clone(), 0, 1);
+        lock.sign(recipient.sign(&lock.signable_message()).unwrap(), recipient.public_key.serialize().to_vec());
+        assert!(lock.validate().is_err());
+        lock.sign(sender.sign(&lock.signable_message()).unwrap(), sender.public_key.serialize().to_vec());
+        assert!(lock.validate().is_ok());
+
+        let claim = HtlcRedeemTx::new([1; 32], HtlcRedeemPath::Claim { preimage: b"secret".to_vec() }, recipient.address(), 0, 1);
+        assert!(claim.validate_against_contract(&contract, 9).is_ok());
+        assert!(claim.validate_against_contract(&contract, 10).is_err());
+        assert_eq!(claim.new_owner(&contract), &contract.recipient);
+
+        let refund = HtlcRedeemTx::new([1; 32], HtlcRedeemPath::Refund, sender.address(), 0, 1);
+        assert!(refund.validate_against_contract(&contract, 9).is_err());
+        assert!(refund.validate_against_contract(&contract, 10).is_ok());
+        assert_eq!(refund.new_owner(&contract), &contract.sender);
+    }
+
+    #[test]
+    fn test_escrow_settlement_needs_two_parties() {
+        let seller = KeyPair::generate().unwrap();
+        let buyer = KeyPair::generate().unwrap();
+        let arbiter = KeyPair::generate().unwrap();
+        let outsider = KeyPair::generate().unwrap();
+
+        let mut lock = EscrowLockTx::new([1; 32], seller.address(), buyer.address(), arbiter.address(), 0, 1);
+        lock.sign(seller.sign(&lock.signable_message()).unwrap(), seller.public_key.serialize().to_vec());
+        assert!(lock.validate().is_ok());
+        let contract = lock.contract();
+
+        let signature = |tx: &EscrowSettleTx, keypair: &KeyPair| EscrowSignature {
+            signer: keypair.address(),
+            public_key: keypair.public_key.serialize().to_vec(),
+            signature: keypair.sign(&tx.signable_message()).unwrap(),
+        };
+
+        let mut release = EscrowSettleTx::new(&contract, [1; 32], EscrowOutcome::Release, 0);
+        release.add_signature(signature(&release, &buyer));
+        assert!(release.validate().is_err());
+
+        // Signing twice doesn't count twice
+        release.add_signature(signature(&release, &buyer));
+        assert!(release.validate().is_err());
+
+        release.add_signature(signature(&release, &arbiter));
+        assert!(release.validate().is_ok());
+        assert!(release.validate_against_contract(&contract).is_ok());
+        assert_eq!(release.new_owner(&contract), &contract.buyer);
+
+        // A signature claiming to be the seller's but made with another key
+        let mut forged = signature(&release, &outsider);
+        forged.signer = seller.address();
+        release.add_signature(forged);
+        assert!(release.validate().is_err());
+
+        let mut outside = EscrowSettleTx::new(&contract, [1; 32], EscrowOutcome::Refund, 0);
+        outside.add_signature(signature(&outside, &seller));
+        outside.add_signature(signature(&outside, &outsider));
+        assert!(outside.validate().is_ok());
+        assert!(outside.validate_against_contract(&contract).is_err());
+    }
+
+    #[test]
+    fn test_offer_and_accept_validation() {
+        let seller = KeyPair::generate().unwrap();
+        let buyer = KeyPair::generate().unwrap();
+
+        let mut offer = OfferTx::new([1; 32], seller.address(), 0.0, 0, 1);
+        offer.sign(seller.sign(&offer.signable_message()).unwrap(), seller.public_key.serialize().to_vec());
+        assert!(offer.validate().is_err());
+
+        offer.price_area = 0.5;
+        offer.sign(seller.sign(&offer.signable_message()).unwrap(), seller.public_key.serialize().to_vec());
+        assert!(offer.validate().is_ok());
+
+        let signed = |payment: Vec<Sha256Hash>| {
+            let mut tx = AcceptTx::new(&offer.offer(), [1; 32], buyer.address(), payment, 0, 1);
+            tx.sign(buyer.sign(&tx.signable_message()).unwrap(), buyer.public_key.serialize().to_vec());
+            tx
+        };
+        assert!(signed(vec![[2; 32]]).validate().is_ok());
+        assert!(signed(vec![]).validate().is_err());
+        assert!(signed(vec![[2; 32], [2; 32]]).validate().is_err());
+        // Paying for a triangle with itself
+        assert!(signed(vec![[1; 32]]).validate().is_err());
+    }
+
+    #[test]
+    fn test_deep_subdivision_leaves() {
+        let keypair = KeyPair::generate().unwrap();
+        let parent = crate::blockchain::genesis_triangle();
+        let signed = |depth: u8| {
+            let mut tx = DeepSubdivisionTx::new(parent.hash(), depth, keypair.address(), 0, 1);
+            tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            tx
+        };
+
+        assert!(signed(0).validate().is_err());
+        assert!(signed(DeepSubdivisionTx::MAX_DEPTH + 1).validate().is_err());
+
+        let tx = signed(3);
+        assert!(tx.validate().is_ok());
+        let leaves = tx.leaves(&parent);
+        assert_eq!(leaves.len(), 27);
+        assert!(leaves.iter().all(|leaf| leaf.owner == keypair.address()));
+        let hashes: std::collections::HashSet<_> = leaves.iter().map(|leaf| leaf.hash()).collect();
+        assert_eq!(hashes.len(), 27);
+
+        // Same leaves as three rounds of ordinary subdivision
+        let area: f64 = leaves.iter().map(|leaf| leaf.area()).sum();
+        assert!((area - parent.area() * 0.75f64.powi(3)).abs() < 1e-9);
+        let first_path = parent.subdivide()[0].subdivide()[0].subdivide()[0].hash();
+        assert_eq!(leaves[0].hash(), first_path);
+
+        // The parent must belong to the signer
+        assert!(tx.validate_against_parent(&parent).is_err());
+    }
+
+    #[test]
+    fn test_subdivision_below_minimum_area_fails() {
+        let parent = Triangle::new(
+            Point { x: 0.0, y: 0.0 },
+            Point { x: 0.0005, y: 0.0 },
+            Point { x: 0.0, y: 0.0005 },
+            None,
+            "owner".to_string(),
+        );
+        let tx = SubdivisionTx::new(parent.hash(), parent.subdivide().to_vec(), "owner".to_string(), 0, 1);
+        assert!(matches!(tx.validate_against_parent(&parent), Err(ChainError::InvalidTransaction(_))));
+
+        let mut state = TriangleState::new();
+        state.utxo_set.insert(parent.hash(), parent.clone());
+        assert!(state.apply_subdivision(&tx).is_err());
+        assert!(state.utxo_set.contains_key(&parent.hash()));
+    }
+
+    #[test]
+    fn test_split_transfer_outputs() {
+        let keypair = KeyPair::generate().unwrap();
+        let mut parent = crate::blockchain::genesis_triangle();
+        parent.owner = keypair.address();
+        let signed = |depth: u8, leaves: Vec<u16>| {
+            let mut tx = SplitTransferTx::new(parent.hash(), depth, leaves, "bob".to_string(), keypair.address(), 0, 1);
+            tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            tx
+        };
+
+        assert!(signed(0, vec![0]).validate().is_err());
+        assert!(signed(1, vec![]).validate().is_err());
+        assert!(signed(1, vec![1, 0]).validate().is_err());
+        assert!(signed(1, vec![0, 0]).validate().is_err());
+        assert!(signed(1, vec![3]).validate().is_err());
+
+        let tx = signed(2, vec![0, 4, 8]);
+        assert!(tx.validate().is_ok());
+        assert!(tx.validate_against_parent(&parent).is_ok());
+
+        let outputs = tx.outputs(&parent);
+        assert_eq!(outputs.len(), 9);
+        assert_eq!(outputs.iter().filter(|t| t.owner == "bob").count(), 3);
+        assert_eq!(outputs.iter().filter(|t| t.owner == keypair.address()).count(), 6);
+        assert_eq!(outputs[4].owner, "bob");
+
+        let mut stranger = parent.clone();
+        stranger.owner = "someone else".to_string();
+        assert!(tx.validate_against_parent(&stranger).is_err());
+    }
+
+    #[test]
+    fn test_swap_sighash_lets_buyer_attach_payment() {
+        let seller = KeyPair::generate().unwrap();
+        let buyer = KeyPair::generate().unwrap();
+        let (listed, payment) = ([1u8; 32], [2u8; 32]);
+        let sign = |tx: &mut SwapTx, index: usize, keypair: &KeyPair| {
+            let signature = keypair.sign(&tx.signable_message(index).unwrap()).unwrap();
+            tx.sign_input(index, signature, keypair.public_key.serialize().to_vec());
+        };
+
+        // The seller signs their triangle and the payment they expect, nothing else
+        let mut tx = SwapTx::new(0);
+        tx.add_input(listed, seller.address(), SigHashType::SingleAnyoneCanPay);
+        tx.add_output(payment, seller.address());
+        sign(&mut tx, 0, &seller);
+        let partial = tx.clone();
+
+        // The buyer attaches the payment, takes the listed triangle and sets the fee
+        tx.fee = 5;
+        tx.add_input(payment, buyer.address(), SigHashType::All);
+        tx.add_output(listed, buyer.address());
+        sign(&mut tx, 1, &buyer);
+        assert!(tx.validate().is_ok());
+        assert_eq!(Transaction::Swap(tx.clone()).inputs(), vec![listed, payment]);
+
+        // The seller's output can't be redirected
+        let mut redirected = tx.clone();
+        redirected.outputs[0].new_owner = buyer.address();
+        assert!(redirected.validate().is_err());
+
+        // A seller signing with ALL locks the swap as it was
+        let mut locked = partial.clone();
+        locked.inputs[0].sighash = SigHashType::All;
+        sign(&mut locked, 0, &seller);
+        locked.add_input(payment, buyer.address(), SigHashType::All);
+        locked.add_output(listed, buyer.address());
+        sign(&mut locked, 1, &buyer);
+        assert!(locked.validate().is_err());
+
+        // Every input needs its owner's signature
+        let mut unsigned = partial;
+        unsigned.add_input(payment, buyer.address(), SigHashType::All);
+        unsigned.add_output(listed, buyer.address());
+        assert!(unsigned.validate().is_err());
+    }
+
+    #[test]
+    fn test_lease_validation() {
+        let keypair = KeyPair::generate().unwrap();
+        let signed = |tenant: String| {
+            let mut tx = LeaseTx::new([3u8; 32], keypair.address(), tenant, 20, 0, 1);
+            tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            tx
+        };
+
+        assert!(signed(keypair.address()).validate().is_err());
+
+        let tx = signed("tenant".to_string());
+        assert!(tx.validate().is_ok());
+        assert!(tx.validate_at_height(19).is_ok());
+        assert!(tx.validate_at_height(20).is_err());
+
+        let contract = tx.contract();
+        assert_eq!(contract.id, tx.hash());
+        assert!(contract.is_active(19));
+        assert!(!contract.is_active(20));
+    }
+
+    #[test]
+    fn test_approval_validation() {
+        let keypair = KeyPair::generate().unwrap();
+        let signed = |delegate: Option<String>| {
+            let mut tx = ApproveTx::new([4u8; 32], keypair.address(), delegate, 0, 1);
+            tx.sign(keypair.sign(&tx.signable_message()).unwrap(), keypair.public_key.serialize().to_vec());
+            tx
+        };
+
+        assert!(signed(Some(keypair.address())).validate().is_err());
+
+        let approve = signed(Some("delegate".to_string()));
+        assert!(approve.validate().is_ok());
+        assert_eq!(approve.approval(), Some(Approval { owner: keypair.address(), delegate: "delegate".to_string() }));
+
+        let revoke = signed(None);
+        assert!(revoke.validate().is_ok());
+        assert_eq!(revoke.approval(), None);
+        assert_ne!(revoke.hash(), approve.hash());
+    }
+
+    #[test]
+    fn test_coinbase_outputs_validation() {
+        let output = |address: &str, reward_area| CoinbaseOutput {
+            beneficiary_address: address.to_string(),
+            reward_area,
+        };
+
+        let split = CoinbaseTx::with_outputs(vec![output("alice", 700), output("bob", 300)]);
+        assert!(split.validate().is_ok());
+        assert_eq!(split.reward_area(), 1000);
+        assert_eq!(split.reward_to("bob"), 300);
+        assert_ne!(split.hash(), CoinbaseTx::new(1000, "alice".to_string()).hash());
+
+        assert!(CoinbaseTx::with_outputs(vec![]).validate().is_err());
+        assert!(CoinbaseTx::with_outputs(vec![output("alice", 700), output("bob", 0)]).validate().is_err());
+        assert!(CoinbaseTx::with_outputs(vec![output("alice", 700), output("", 300)]).validate().is_err());
+        // How much a coinbase may claim depends on the block's fees, so it's checked with the block
+        assert!(CoinbaseTx::with_outputs(vec![output("alice", 700), output("bob", 301)]).validate().is_ok());
+    }
+
+    #[test]
+    fn test_fee_rate_ordering() {
+        assert!(FeeRate::new(9, 100) > FeeRate::new(10, 1000));
+        assert_eq!(FeeRate::new(2, 100), FeeRate::new(1, 50));
+        assert!(FeeRate::new(0, 10) < FeeRate::new(1, 10_000));
+        assert_eq!(FeeRate::new(5, 0).size, 1);
+
+        let tx = Transaction::Transfer(TransferTx::new([1; 32], "bob".to_string(), "alice".to_string(), 7, 1));
+        assert_eq!(tx.size(), codec::encode(&tx).len());
+        assert_eq!(tx.fee_rate(), FeeRate::new(7, tx.size()));
+    }
+
+    #[test]
+    fn test_subdivision_children_must_carry_lineage() {
+        let parent = crate::blockchain::genesis_triangle();
+        let children = parent.subdivide().to_vec();
+        let tx = SubdivisionTx::new(parent.hash(), children.clone(), "owner".to_string(), 0, 1);
+        assert!(tx.validate_against_parent(&parent).is_ok());
+
+        let mut tampered = children.clone();
+        tampered[1].depth = 5;
+        let tx = SubdivisionTx::new(parent.hash(), tampered, "owner".to_string(), 0, 1);
+        assert!(tx.validate_against_parent(&parent).is_err());
+
+        // Children listed out of order carry the wrong child index
+        let mut swapped = children;
+        swapped[0].lineage = 1;
+        let tx = SubdivisionTx::new(parent.hash(), swapped, "owner".to_string(), 0, 1);
+        assert!(tx.validate_against_parent(&parent).is_err());
+    }
 }
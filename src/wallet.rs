@@ -6,8 +6,16 @@
 // Suppress deprecation warnings from aes-gcm's generic-array dependency
 #![allow(deprecated)]
 
+use crate::blockchain::{areas_match, Block, BlockHeight, Sha256Hash};
 use crate::crypto::KeyPair;
 use crate::error::ChainError;
+use crate::geometry::Triangle;
+use crate::transaction::{
+    AcceptTx, ApproveTx, DeepSubdivisionTx, EscrowLockTx, EscrowSettleTx, EscrowSignature, HtlcContract,
+    HtlcLockTx, HtlcRedeemPath, HtlcRedeemTx, InscriptionTx, LeaseTx, MarketOffer, OfferTx, SplitTransferTx,
+    SwapTx, Transaction,
+};
+use sha2::{Digest, Sha256};
 use std::fs;
 use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
@@ -70,6 +78,294 @@ impl Wallet {
 
         KeyPair::from_secret_bytes(&secret_bytes)
     }
+
+    /// Build and sign an HTLC lock of `input_hash` in favour of `recipient`
+    pub fn create_htlc_lock(
+        &self,
+        input_hash: Sha256Hash,
+        recipient: String,
+        hash_lock: Sha256Hash,
+        timeout_height: BlockHeight,
+        fee: u64,
+        nonce: u64,
+    ) -> Result<Transaction, ChainError> {
+        let keypair = self.get_keypair()?;
+        let contract = HtlcContract {
+            sender: self.address.clone(),
+            recipient,
+            hash_lock,
+            timeout_height,
+        };
+        let mut tx = HtlcLockTx::new(input_hash, contract, fee, nonce);
+        let signature = keypair.sign(&tx.signable_message())?;
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        Ok(Transaction::HtlcLock(tx))
+    }
+
+    /// Build and sign a claim of a locked triangle by revealing its preimage
+    pub fn create_htlc_claim(
+        &self,
+        input_hash: Sha256Hash,
+        preimage: Vec<u8>,
+        fee: u64,
+        nonce: u64,
+    ) -> Result<Transaction, ChainError> {
+        self.create_htlc_redeem(input_hash, HtlcRedeemPath::Claim { preimage }, fee, nonce)
+    }
+
+    /// Build and sign a refund of a triangle this wallet locked, once it has timed out
+    pub fn create_htlc_refund(&self, input_hash: Sha256Hash, fee: u64, nonce: u64) -> Result<Transaction, ChainError> {
+        self.create_htlc_redeem(input_hash, HtlcRedeemPath::Refund, fee, nonce)
+    }
+
+    fn create_htlc_redeem(
+        &self,
+        input_hash: Sha256Hash,
+        path: HtlcRedeemPath,
+        fee: u64,
+        nonce: u64,
+    ) -> Result<Transaction, ChainError> {
+        let keypair = self.get_keypair()?;
+        let mut tx = HtlcRedeemTx::new(input_hash, path, self.address.clone(), fee, nonce);
+        let signature = keypair.sign(&tx.signable_message())?;
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        Ok(Transaction::HtlcRedeem(tx))
+    }
+
+    /// Build and sign an escrow of `input_hash` for `buyer`, with `arbiter` as the third key
+    pub fn create_escrow_lock(
+        &self,
+        input_hash: Sha256Hash,
+        buyer: String,
+        arbiter: String,
+        fee: u64,
+        nonce: u64,
+    ) -> Result<Transaction, ChainError> {
+        let keypair = self.get_keypair()?;
+        let mut tx = EscrowLockTx::new(input_hash, self.address.clone(), buyer, arbiter, fee, nonce);
+        let signature = keypair.sign(&tx.signable_message())?;
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        Ok(Transaction::EscrowLock(tx))
+    }
+
+    /// Sign an escrow settlement as one of its parties. Two parties' signatures
+    /// are needed before the settlement can be submitted.
+    pub fn sign_escrow_settlement(&self, tx: &EscrowSettleTx) -> Result<EscrowSignature, ChainError> {
+        let keypair = self.get_keypair()?;
+        Ok(EscrowSignature {
+            signer: self.address.clone(),
+            public_key: keypair.public_key.serialize().to_vec(),
+            signature: keypair.sign(&tx.signable_message())?,
+        })
+    }
+
+    /// Build and sign a marketplace offer of `input_hash` for `price_area`
+    pub fn create_offer(&self, input_hash: Sha256Hash, price_area: f64, fee: u64, nonce: u64) -> Result<Transaction, ChainError> {
+        let keypair = self.get_keypair()?;
+        let mut tx = OfferTx::new(input_hash, self.address.clone(), price_area, fee, nonce);
+        let signature = keypair.sign(&tx.signable_message())?;
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        Ok(Transaction::Offer(tx))
+    }
+
+    /// Build and sign an acceptance of `offer`, paying with the `payment` triangles
+    pub fn create_accept(
+        &self,
+        offer: &MarketOffer,
+        input_hash: Sha256Hash,
+        payment: Vec<Sha256Hash>,
+        fee: u64,
+        nonce: u64,
+    ) -> Result<Transaction, ChainError> {
+        let keypair = self.get_keypair()?;
+        let mut tx = AcceptTx::new(offer, input_hash, self.address.clone(), payment, fee, nonce);
+        let signature = keypair.sign(&tx.signable_message())?;
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        Ok(Transaction::Accept(tx))
+    }
+
+    /// Build and sign an inscription of `data` on a triangle this wallet owns
+    pub fn create_inscription(&self, input_hash: Sha256Hash, data: Vec<u8>, fee: u64, nonce: u64) -> Result<Transaction, ChainError> {
+        let keypair = self.get_keypair()?;
+        let mut tx = InscriptionTx::new(input_hash, self.address.clone(), data, fee, nonce);
+        let signature = keypair.sign(&tx.signable_message())?;
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        Ok(Transaction::Inscription(tx))
+    }
+
+    /// Lease an owned triangle to `tenant` until block height `expires_at`
+    pub fn create_lease(
+        &self,
+        input_hash: Sha256Hash,
+        tenant: String,
+        expires_at: BlockHeight,
+        fee: u64,
+        nonce: u64,
+    ) -> Result<Transaction, ChainError> {
+        let keypair = self.get_keypair()?;
+        let mut tx = LeaseTx::new(input_hash, self.address.clone(), tenant, expires_at, fee, nonce);
+        let signature = keypair.sign(&tx.signable_message())?;
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        Ok(Transaction::Lease(tx))
+    }
+
+    /// Approve `delegate` to transfer or subdivide an owned triangle, or revoke
+    /// the approval with `None`
+    pub fn create_approval(
+        &self,
+        input_hash: Sha256Hash,
+        delegate: Option<String>,
+        fee: u64,
+        nonce: u64,
+    ) -> Result<Transaction, ChainError> {
+        let keypair = self.get_keypair()?;
+        let mut tx = ApproveTx::new(input_hash, self.address.clone(), delegate, fee, nonce);
+        let signature = keypair.sign(&tx.signable_message())?;
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        Ok(Transaction::Approve(tx))
+    }
+
+    /// Send `recipient_leaves` of `parent_hash` subdivided to `depth`, keeping the rest as change
+    pub fn create_split_transfer(
+        &self,
+        parent_hash: Sha256Hash,
+        depth: u8,
+        recipient_leaves: Vec<u16>,
+        recipient: String,
+        fee: u64,
+        nonce: u64,
+    ) -> Result<Transaction, ChainError> {
+        let keypair = self.get_keypair()?;
+        let mut tx = SplitTransferTx::new(
+            parent_hash, depth, recipient_leaves, recipient, self.address.clone(), fee, nonce,
+        );
+        let signature = keypair.sign(&tx.signable_message())?;
+        tx.sign(signature, keypair.public_key.serialize().to_vec());
+        Ok(Transaction::SplitTransfer(tx))
+    }
+
+    /// Sign this wallet's input at `index` of a swap under the input's `SigHashType`
+    pub fn sign_swap_input(&self, tx: &mut SwapTx, index: usize) -> Result<(), ChainError> {
+        match tx.inputs.get(index) {
+            Some(input) if input.owner == self.address => {}
+            _ => {
+                return Err(ChainError::WalletError(format!(
+                    "Swap input {} does not belong to this wallet",
+                    index
+                )))
+            }
+        }
+        let keypair = self.get_keypair()?;
+        let signature = keypair.sign(&tx.signable_message(index)?)?;
+        tx.sign_input(index, signature, keypair.public_key.serialize().to_vec());
+        Ok(())
+    }
+}
+
+/// How to pay a target area out of the triangles a wallet owns
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoinSelection {
+    /// Send these triangles whole
+    Whole(Vec<Sha256Hash>),
+    /// Subdivide one triangle and send the first `leaf_count` leaves, keeping the rest
+    Split { parent_hash: Sha256Hash, depth: u8, leaf_count: usize },
+}
+
+impl CoinSelection {
+    /// Leaf indices for the recipient of a split selection
+    pub fn recipient_leaves(&self) -> Vec<u16> {
+        match self {
+            CoinSelection::Whole(_) => Vec::new(),
+            CoinSelection::Split { leaf_count, .. } => (0..*leaf_count as u16).collect(),
+        }
+    }
+}
+
+/// Choose triangles covering `target_area`, overpaying as little as possible.
+///
+/// A triangle of exactly the right size is sent whole. Otherwise the split with
+/// the smallest overshoot is preferred, falling back to sending the largest
+/// triangles whole. Returns `None` if everything owned can't cover the target.
+pub fn select_triangles(owned: &[Triangle], target_area: f64) -> Option<CoinSelection> {
+    if !target_area.is_finite() || target_area <= 0.0 {
+        return None;
+    }
+
+    if let Some(exact) = owned.iter().find(|t| areas_match(t.area(), target_area)) {
+        return Some(CoinSelection::Whole(vec![exact.hash()]));
+    }
+
+    let mut best_split: Option<(f64, CoinSelection)> = None;
+    for triangle in owned {
+        for depth in 1..=DeepSubdivisionTx::MAX_DEPTH {
+            if !triangle.can_subdivide(depth as u32) {
+                break;
+            }
+            let leaf_area = triangle.area() * 0.25f64.powi(depth as i32);
+            let mut leaf_count = (target_area / leaf_area).ceil() as usize;
+            // Don't round up a whole extra leaf over floating-point noise
+            if leaf_count > 1 && areas_match((leaf_count - 1) as f64 * leaf_area, target_area) {
+                leaf_count -= 1;
+            }
+            if leaf_count == 0 || leaf_count > 3usize.pow(depth as u32) {
+                continue;
+            }
+            let overshoot = leaf_count as f64 * leaf_area - target_area;
+            if best_split.as_ref().is_none_or(|(best, _)| overshoot < *best) {
+                best_split = Some((overshoot, CoinSelection::Split {
+                    parent_hash: triangle.hash(),
+                    depth,
+                    leaf_count,
+                }));
+            }
+        }
+    }
+    if let Some((_, selection)) = best_split {
+        return Some(selection);
+    }
+
+    let mut by_area: Vec<&Triangle> = owned.iter().collect();
+    by_area.sort_by(|a, b| b.area().total_cmp(&a.area()));
+    let mut selected = Vec::new();
+    let mut total = 0.0;
+    for triangle in by_area {
+        selected.push(triangle.hash());
+        total += triangle.area();
+        if total >= target_area || areas_match(total, target_area) {
+            return Some(CoinSelection::Whole(selected));
+        }
+    }
+    None
+}
+
+/// Generate a random HTLC preimage and the hash lock that commits to it.
+/// The preimage must stay secret until the counterparty has locked their side.
+pub fn generate_htlc_preimage() -> ([u8; 32], Sha256Hash) {
+    use rand::RngCore;
+    let mut preimage = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut preimage);
+    (preimage, Sha256::digest(preimage).into())
+}
+
+/// Find a preimage revealed on chain by a claim against `hash_lock`.
+/// The other side of a swap uses this to claim their half with the same secret.
+pub fn find_htlc_preimage(blocks: &[Block], hash_lock: &Sha256Hash) -> Result<Option<Vec<u8>>, ChainError> {
+    for block in blocks.iter().rev() {
+        let found = block.transactions.get()?.iter().find_map(|tx| match tx {
+            Transaction::HtlcRedeem(redeem) => match &redeem.path {
+                HtlcRedeemPath::Claim { preimage } => {
+                    let digest: Sha256Hash = Sha256::digest(preimage).into();
+                    (digest == *hash_lock).then(|| preimage.clone())
+                }
+                HtlcRedeemPath::Refund => None,
+            },
+            _ => None,
+        });
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(None)
 }
 
 /// Get the default wallet directory
@@ -243,7 +539,7 @@ impl EncryptedWallet {
         // Encrypt the secret key
         let secret_bytes = wallet.secret_key_hex.as_bytes();
         let ciphertext = cipher
-            .encrypt(&nonce, secret_bytes)
+            .encrypt(nonce, secret_bytes)
             .map_err(|e| ChainError::CryptoError(format!("Encryption failed: {}", e)))?;
 
         use base64::{Engine as _, engine::general_purpose};
@@ -253,7 +549,7 @@ impl EncryptedWallet {
             address: wallet.address.clone(),
             encrypted_secret_key: general_purpose::STANDARD.encode(&ciphertext),
             salt: salt.to_string(),
-            nonce: general_purpose::STANDARD.encode(&nonce_bytes),
+            nonce: general_purpose::STANDARD.encode(nonce_bytes),
             created: wallet.created.clone(),
         })
     }
@@ -292,7 +588,7 @@ impl EncryptedWallet {
 
         // Decrypt
         let plaintext = cipher
-            .decrypt(&nonce, ciphertext.as_ref())
+            .decrypt(nonce, ciphertext.as_ref())
             .map_err(|_| ChainError::CryptoError("Decryption failed - wrong password?".to_string()))?;
 
         let secret_key_hex = String::from_utf8(plaintext)
@@ -382,4 +678,61 @@ mod tests {
         // Cleanup
         fs::remove_file(&wallet_path).unwrap();
     }
+
+    #[test]
+    fn test_htlc_helpers() {
+        let alice = Wallet::new(None).unwrap();
+        let bob = Wallet::new(None).unwrap();
+        let (preimage, hash_lock) = generate_htlc_preimage();
+        let input = [7u8; 32];
+
+        let lock = alice
+            .create_htlc_lock(input, bob.address.clone(), hash_lock, 20, 0, 1)
+            .unwrap();
+        let contract = match &lock {
+            Transaction::HtlcLock(tx) => {
+                assert!(tx.validate().is_ok());
+                tx.contract()
+            }
+            _ => unreachable!(),
+        };
+
+        let claim = bob.create_htlc_claim(input, preimage.to_vec(), 0, 1).unwrap();
+        match &claim {
+            Transaction::HtlcRedeem(tx) => {
+                assert!(tx.validate().is_ok());
+                assert!(tx.validate_against_contract(&contract, 10).is_ok());
+            }
+            _ => unreachable!(),
+        }
+
+        let mut block = crate::blockchain::Blockchain::regtest().blocks[0].clone();
+        block.transactions.get_mut().unwrap().push(claim);
+        assert_eq!(find_htlc_preimage(&[block], &hash_lock).unwrap(), Some(preimage.to_vec()));
+    }
+
+    #[test]
+    fn test_select_triangles() {
+        use crate::geometry::Point;
+
+        let big = Triangle::new(Point::new(0.0, 0.0), Point::new(4.0, 0.0), Point::new(0.0, 4.0), None, "me".to_string());
+        let small = Triangle::new(Point::new(10.0, 0.0), Point::new(11.0, 0.0), Point::new(10.0, 1.0), None, "me".to_string());
+        let owned = vec![big.clone(), small.clone()];
+
+        // An exact match is sent whole
+        assert_eq!(select_triangles(&owned, small.area()), Some(CoinSelection::Whole(vec![small.hash()])));
+
+        // Two of big's depth-1 children cover half its area exactly
+        let selection = select_triangles(&owned, big.area() / 2.0).unwrap();
+        assert_eq!(selection, CoinSelection::Split { parent_hash: big.hash(), depth: 1, leaf_count: 2 });
+        assert_eq!(selection.recipient_leaves(), vec![0, 1]);
+
+        // More than any split can carry falls back to whole triangles
+        assert_eq!(
+            select_triangles(&owned, big.area() + 0.1),
+            Some(CoinSelection::Whole(vec![big.hash(), small.hash()]))
+        );
+        assert_eq!(select_triangles(&owned, 100.0), None);
+        assert_eq!(select_triangles(&owned, 0.0), None);
+    }
 }